@@ -0,0 +1,65 @@
+//! The per-request cost of the pipeline, for a trivial handler and for one
+//! sending a large body, answered with `FyreServer::handle` so no socket is
+//! involved. `handle` builds a Lua state per request, as a worker does for
+//! its first.
+//!
+//! ```text
+//! cargo bench --bench hello
+//! ```
+
+use criterion::{criterion_group, criterion_main, Criterion};
+use scriptable_server::{FyreServer, SyntheticRequest};
+use std::fs;
+use std::hint::black_box;
+
+const CONFIG: &str = r#"
+router.add("/hello", "hello.lua")
+router.add("/large", "large.lua")
+"#;
+
+const HELLO: &str = r#"
+return {
+  handler = function(request, response)
+    response.headers["Content-Type"] = "text/plain"
+    response.body = "hello"
+  end,
+}
+"#;
+
+const LARGE: &str = r#"
+local body = string.rep("x", 1024 * 1024)
+return {
+  handler = function(request, response)
+    response.headers["Content-Type"] = "application/octet-stream"
+    response.body = body
+  end,
+}
+"#;
+
+fn pipeline(c: &mut Criterion) {
+  let dir = std::env::temp_dir().join(format!("fyre-bench-{}", std::process::id()));
+  fs::create_dir_all(dir.join("scripts")).unwrap();
+  fs::write(dir.join("config.lua"), CONFIG).unwrap();
+  fs::write(dir.join("scripts").join("hello.lua"), HELLO).unwrap();
+  fs::write(dir.join("scripts").join("large.lua"), LARGE).unwrap();
+  let server = FyreServer::builder()
+    .config_file(dir.join("config.lua"))
+    .quiet(true)
+    .load()
+    .unwrap();
+
+  for (name, path) in [("hello", "/hello"), ("1 MB body", "/large")] {
+    c.bench_function(name, |b| {
+      b.iter(|| {
+        let response = server.handle(black_box(SyntheticRequest::get(path)));
+        assert_eq!(response.status, 200);
+        response
+      })
+    });
+  }
+
+  let _ = fs::remove_dir_all(&dir);
+}
+
+criterion_group!(benches, pipeline);
+criterion_main!(benches);
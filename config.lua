@@ -1,12 +1,261 @@
--- Set the server address here. 
--- The value below will be used unless a CLI argument overrides it.
-SERVER_ADDR = "localhost:9000"
+-- Server settings. Every key is optional; a misspelt key or a value of the
+-- wrong type stops the server with an error naming it.
+CONFIG = {
+  -- The value below will be used unless a CLI argument overrides it.
+  addr = "localhost:9000",
+  -- (router.set_addr("localhost:9000") does the same; CONFIG.addr wins if both are used.)
+  -- Or listen on a Unix domain socket, e.g. behind nginx on the same host:
+  -- addr = "unix:/run/fyre.sock",
+  -- Or listen on several addresses at once (instead of addr):
+  -- addrs = { "0.0.0.0:8000", "[::]:8000", "unix:/run/fyre.sock" },
+  -- bind_check = "lenient",   -- start even if some can't be bound (default "strict")
+
+  -- Serve HTTPS with a PEM certificate chain and key (optional).
+  -- tls = { cert = "certs/fullchain.pem", key = "certs/privkey.pem" },
+  -- tls = { cert = "certs/fullchain.pem", key = "certs/privkey.pem", redirect_http = "0.0.0.0:80" },
+  -- Client certificates: checked against client_ca, seen as request.tls.client_cert.
+  -- tls = { cert = "certs/fullchain.pem", key = "certs/privkey.pem",
+  --         client_ca = "certs/clients-ca.pem", require_client_cert = true },
+  -- Protocol versions ("1.2" or "1.3") and cipher suites (IANA names, preferred first).
+  -- tls = { cert = "certs/fullchain.pem", key = "certs/privkey.pem", min_version = "1.2",
+  --         cipher_suites = { "TLS13_AES_256_GCM_SHA384", "TLS_ECDHE_RSA_WITH_AES_256_GCM_SHA384" } },
+
+  -- The Server header sent with every response (default "fyre"; false sends none).
+  -- server_header = "fyre",
+
+  -- Hardening headers for handler responses (false leaves one out; HSTS only over HTTPS).
+  -- security_headers = {
+  --   hsts = { max_age = 31536000, include_subdomains = true },
+  --   frame_options = "DENY",                 -- default; or "SAMEORIGIN"
+  --   content_type_options = true,            -- default
+  --   referrer_policy = "strict-origin-when-cross-origin",   -- default
+  --   csp = "default-src 'self'",
+  -- },
+
+  -- Process id file for init scripts (optional).
+  -- pid_file = "/run/fyre.pid",
+
+  -- Switch to this account after binding ports below 1024 as root (Unix only).
+  -- run_as = { user = "fyre", group = "fyre" },
+
+  -- Threads handling requests (default: one per CPU).
+  -- workers = 4,
+
+  log = {
+    -- Where output goes (also used by --daemon).
+    -- file = "/var/log/fyre.log",
+    -- Rotate the file before it grows past max_size, keeping this many old ones (default 5).
+    -- rotate = { max_size = "50MB", keep = 5 },
+    -- One JSON object per line (ts, level, msg, route, script, request_id, ...) instead of plain text.
+    -- format = "json",
+    -- Which lines to write (default "info"); --log-level and FYRE_LOG override it.
+    -- level = "info,fyre::pipeline=debug",
+    -- Log handler requests slower than this, with a read/lua/write breakdown (default 0: off).
+    -- slow_request_ms = 500,
+    -- One line per request in Combined Log Format (default), "common", or false for none.
+    -- requests = "combined",
+    -- requests_exclude = { "/healthz", "/readyz" },   -- paths not logged; "/internal/*" for a prefix
+  },
+
+  lua = {
+    -- Requests each worker's Lua state serves before it is rebuilt (default 1000).
+    -- state_max_uses = 1000,
+    -- VM instructions one request's pipeline may run before it is stopped with a 500
+    -- (no limit by default); router.add(..., { instruction_limit = n }) overrides it.
+    -- instruction_limit = 100000000,
+    -- Handler scripts that fail to compile at startup stop the server (default "strict").
+    -- script_check = "lenient",   -- start anyway; their routes answer 503
+    -- Load handler scripts from compiled bytecode (recompiled when a script changes).
+    -- bytecode_cache = true,
+    -- bytecode_cache_dir = ".fyre-cache",    -- also keep the bytecode across restarts
+    -- cache_max_bytes = 67108864,            -- bytecode kept in memory (default 64 MB)
+  },
+
+  -- Listening socket tuning (unset options keep the OS defaults).
+  socket = {
+    -- nodelay = true,
+    -- backlog = 1024,
+    -- recv_buffer = 262144,
+    -- send_buffer = 262144,
+    -- unix_mode = "660",   -- permissions of a unix: socket
+  },
+
+  -- Retry a bind that fails with "Address already in use", e.g. right after
+  -- a crash, waiting delay_ms and then twice as long each time.
+  bind = {
+    -- retry = { attempts = 10, delay_ms = 500 },
+    -- reuse_addr = true,   -- SO_REUSEADDR (default: on for Unix)
+    -- reuse_port = true,   -- SO_REUSEPORT: several servers on one port
+  },
+
+  limits = {
+    -- Connection limits (defaults: 1024 connections, 5s idle timeout, no request limit).
+    -- connections = 1024,
+    -- keep_alive_timeout_ms = 5000,
+    -- requests_per_connection = 1000,
+    -- Request head limits; past them, 414 (URL) or 431 (headers).
+    -- url_bytes = 8192,
+    -- headers = 100,
+    -- header_bytes = 8192,
+    -- header_total_bytes = 65536,
+    -- Largest request body; past it, 413 (default 100 MB).
+    -- body = 104857600,
+    -- Requests running their handler at once; past it, 503 with Retry-After.
+    -- in_flight = 64,
+    -- in_flight_queue = 32,   -- wait for a slot instead (default 0)
+    -- in_flight_queue_timeout_ms = 1000,
+  },
+
+  http = {
+    -- keep_alive = false,     -- send Connection: close on every response
+    -- version_compat = true,  -- close HTTP/1.0 connections, no 100 Continue
+  },
+
+  timeouts = {
+    -- How long one read or write may stall before the connection is closed.
+    -- header_read_ms = 10000,   -- in a request's head (answered with 408)
+    -- header_deadline_ms = 10000,  -- for a whole head, however it trickles in
+    -- body_read_ms = 30000,     -- in a request's body
+    -- write_ms = 30000,         -- while writing the response
+    -- keep_alive_idle_ms = 5000,   -- same as limits.keep_alive_timeout_ms
+  },
+
+  -- Client addresses or CIDR ranges (IPv4 or IPv6); deny wins, others get 403.
+  -- access = {
+  --   allow = { "10.0.0.0/8", "192.168.1.5" },
+  --   deny = { "10.9.0.0/16" },
+  --   log = true,   -- also log requests an allow entry let in
+  -- },
+
+  -- Host names requests may be for; others get 421 (a missing Host on HTTP/1.1 gets 400).
+  -- allowed_hosts = { "example.com", "*.example.com", "localhost" },
+
+  -- Named auth strategies, required per route with router.add(..., { require_auth = "jwt" }).
+  -- auth = {
+  --   jwt = { secret = env("JWT_SECRET"), aud = "api" },
+  --   partners = { type = "api_key", keys = { acme = env("ACME_KEY_SHA256") } },
+  -- },
+  -- SHA-256 of each API key by id, for { require_auth = "api_key" } (429 after 10 bad keys/min).
+  -- api_keys = { ["svc-billing"] = env("BILLING_KEY_HASH") },
+
+  -- Per-client token bucket: requests per window seconds, up to burst at once (429 past it).
+  -- rate_limit = { requests = 100, window = 60, burst = 20 },
+
+  -- Request bodies larger than this are written to a temporary file (default 1 MB).
+  body = {
+    -- spill_bytes = 1048576,
+    -- spill_dir = "/var/tmp/fyre",
+  },
+
+  -- On SIGINT/SIGTERM, time for running requests to finish (default 30s), then a script's run().
+  shutdown = {
+    -- grace_ms = 30000,
+    -- script = "tasks/flush.lua",
+  },
+
+  -- Memory maps for router.static mounts with { mmap = true } (defaults: 256 files up to 1 MB).
+  static = {
+    -- mmap_entries = 256,
+    -- mmap_max_bytes = 1048576,
+  },
+
+  sandbox = {
+    -- Hosts handlers may contact with fyre.http.
+    -- http_allow = { "api.example.com", "*.partners.example.com" },
+    -- Let those hosts resolve to loopback or private addresses (refused by default).
+    -- http_allow_private = false,
+    -- Environment variables handlers may read with fyre.env (names or prefixes).
+    -- env_allow = { "FYRE_" },
+    -- Directories handlers may access with fyre.fs.
+    -- fs_allow = { "data/" },
+    -- Programs handlers may run with fyre.exec.
+    -- exec_allow = { "convert", "/usr/bin/git" },
+  },
+
+  -- Default Redis server for fyre.redis.connect() (optional).
+  -- redis = { url = "redis://127.0.0.1:6379/0" },
+
+  -- Outbound email for fyre.mail (optional). Credentials come from the environment.
+  -- smtp = {
+  --   host = "smtp.example.com",
+  --   security = "starttls",   -- or "tls" (port 465) or "none"
+  --   username = env("SMTP_USERNAME"),
+  --   password = fyre.secrets.get("smtp_password"),   -- see fyre.secrets
+  --   from = "Example <noreply@example.com>",
+  --   queue = "mail",          -- queue used by fyre.mail.send{ ..., async = true }
+  -- },
+
+  -- Keys for fyre.cookie.sign/verify; previous ones still verify, for rotation.
+  -- keys = {
+  --   current = fyre.secrets.get("cookie_key_v2"),
+  --   previous = { fyre.secrets.get("cookie_key_v1") },
+  -- },
+
+  -- Keep pending fyre.queue jobs across restarts.
+  -- queue = { dir = "data/queues" },
+
+  -- Label combinations kept per fyre.metrics metric (default 1000), and the Prometheus
+  -- endpoint (default "/metrics"; false turns it off, listener = "admin" moves it to admin.addr).
+  -- metrics = { max_series = 1000, path = "/metrics", listener = "main", count_self = false },
+
+  -- Export request spans to an OpenTelemetry collector (off unless endpoint is set).
+  -- tracing = {
+  --   endpoint = "http://otel-collector:4318/v1/traces",
+  --   protocol = "http/protobuf",   -- or "http/json"
+  --   sample_ratio = 1,             -- share of new traces exported
+  --   resource = { ["service.name"] = "fyre" },
+  -- },
+
+  -- HTTP endpoints to reload the config and flush caches (optional; see README).
+  -- admin = { token = env.require("FYRE_ADMIN_TOKEN"), addr = "127.0.0.1:9100" },
+  -- Where admin actions are recorded (default: the main log, when admin is set).
+  -- audit = { file = "/var/log/fyre/audit.log" },   -- or { syslog = "auth" }
+
+  -- Post handler failures to a webhook, at most once per throttle (see README).
+  -- error_reporting = { webhook = env("SLACK_WEBHOOK"), min_level = "error", throttle = "5m" },
+
+  -- Write the requests for these paths, and their responses, to files (see README).
+  -- debug = { dump_routes = { "/api/*" }, dump_dir = "dumps" },
+
+  -- Probe paths answered without a script (defaults /healthz and /readyz; false turns one off).
+  -- health = {
+  --   ready_path = "/readyz",
+  --   readiness = function() return fyre.kv.get("maintenance") == nil end,
+  -- },
+}
+
+-- Run more config files, e.g. local overrides, in sorted order (optional).
+-- include("conf.d/*.lua")
+
+-- config.<env>.lua (e.g. config.production.lua, for FYRE_ENV=production) runs
+-- after this file if it exists; fyre.env_name is the environment's name.
+
+-- Background job queues: queue.worker(name, worker_script, options).
+-- queue.worker("emails", "workers/email.lua", { concurrency = 2, max_attempts = 5 })
+
+-- Scheduled tasks: each script returns { run = function() ... end }.
+-- schedule.every("5m", "tasks/cleanup.lua")
+-- schedule.cron("0 3 * * *", "tasks/backup.lua")
 
 -- Maps incoming URL paths to specific handler script files.
--- router.add(path, handler_script_filename)
+-- router.add(path, handler_script_filename [, options])
+-- router.add("/export", "export.lua", { max_concurrent = 1, queue = 5 })   -- per-route limit
+-- router.add("/internal", "internal.lua", { allow = { "10.0.0.0/8" }, deny = { "10.9.0.0/16" } })
+-- router.add("/login", "login.lua", { rate_limit = { requests = 5, window = 60 } })
+-- router.add("/hooks/billing", "hook.lua", { accept_types = { "application/json" } })  -- else 415
+-- router.add("/billing", "billing.lua", { secrets = { "stripe_key" } })  -- fyre.secrets limit
+-- router.add("/account", "account.lua", { csrf = true })  -- fyre.csrf token, else 403
+-- router.add("/admin", "admin.lua",
+--   { auth = { type = "basic", users = { admin = env("ADMIN_PASS_HASH") } } })  -- bcrypt/argon2
+-- router.protect("/admin/*", { type = "basic", users = { admin = env("ADMIN_PASS_HASH") } })
+-- router.protect("/ops/*", { type = "basic", htpasswd = "conf/users.htpasswd" })  -- $2y$/$apr1$
+
+-- Serves files from a directory for paths no route matches (optional).
+-- router.static("/assets", "public", { mmap = true })
+-- router.static("/build", "dist", { follow_symlinks = true, serve_hidden = true })  -- off by default
 
 -- Public endpoint demo
 router.add("/", "default_api.lua")
 
 -- Protected endpoint demo
-router.add("/api/users", "user_api.lua")
\ No newline at end of file
+router.add("/api/users", "user_api.lua")
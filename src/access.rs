@@ -0,0 +1,282 @@
+//! # IP Access Lists
+//!
+//! Turns away clients by address before any Lua runs. `CONFIG.access = {
+//! allow = {...}, deny = {...} }` applies to every request, and a route's
+//! `allow` and `deny` options to that route as well; a request must pass
+//! both. Entries are IPv4 or IPv6 addresses or CIDR ranges
+//! (`"10.0.0.0/8"`, `"2001:db8::/32"`), parsed when the configuration is
+//! loaded.
+//!
+//! A client matching a `deny` entry is refused even if it also matches an
+//! `allow` entry. With an `allow` list, a client matching none of it is
+//! refused; without one, everyone not denied is let in. An IPv4 client
+//! reaching an IPv6 listener (`::ffff:10.1.2.3`) is matched as its IPv4
+//! address. Unix socket clients have no address, so they are refused by any
+//! `allow` list.
+
+use mlua::prelude::*;
+use std::fmt;
+use std::net::IpAddr;
+
+/// An address, or a range of them in CIDR notation.
+#[derive(Debug, Clone)]
+pub struct Cidr {
+  addr: IpAddr,
+  bits: u8,
+  /// The entry as written, for the logs.
+  text: String,
+}
+
+impl Cidr {
+  /// Parses `"192.168.1.5"`, `"10.0.0.0/8"`, or an IPv6 equivalent. Bits
+  /// past the prefix length are ignored, so `"10.1.2.3/8"` is `10.0.0.0/8`.
+  pub fn parse(text: &str) -> Result<Cidr, String> {
+    let (addr, bits) = match text.split_once('/') {
+      Some((addr, bits)) => (addr, Some(bits)),
+      None => (text, None),
+    };
+    let addr: IpAddr = addr
+      .parse()
+      .map_err(|_| format!("'{}' is not an IP address or CIDR range", text))?;
+    let max = if addr.is_ipv4() { 32 } else { 128 };
+    let bits = match bits {
+      Some(bits) => bits
+        .parse::<u8>()
+        .ok()
+        .filter(|&bits| bits <= max)
+        .ok_or_else(|| format!("'{}': the prefix length must be 0 to {}", text, max))?,
+      None => max,
+    };
+    Ok(Cidr {
+      addr,
+      bits,
+      text: text.to_string(),
+    })
+  }
+
+  /// Whether `ip` is in the range. Addresses of the other family never are.
+  pub fn contains(&self, ip: IpAddr) -> bool {
+    let (net, ip, width) = match (self.addr, ip.to_canonical()) {
+      (IpAddr::V4(net), IpAddr::V4(ip)) => (u32::from(net) as u128, u32::from(ip) as u128, 32),
+      (IpAddr::V6(net), IpAddr::V6(ip)) => (u128::from(net), u128::from(ip), 128),
+      _ => return false,
+    };
+    self.bits == 0 || (net ^ ip) >> (width - u32::from(self.bits)) == 0
+  }
+}
+
+impl fmt::Display for Cidr {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    f.write_str(&self.text)
+  }
+}
+
+/// Why a client was refused.
+#[derive(Debug)]
+pub enum Denied<'a> {
+  /// It matched this `deny` entry.
+  Rule(&'a Cidr),
+  /// There is an `allow` list and it matched none of it.
+  NotAllowed,
+}
+
+impl fmt::Display for Denied<'_> {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      Denied::Rule(rule) => write!(f, "denied by {}", rule),
+      Denied::NotAllowed => f.write_str("not in the allow list"),
+    }
+  }
+}
+
+/// An `allow` and a `deny` list.
+#[derive(Debug, Default, Clone)]
+pub struct AccessList {
+  allow: Vec<Cidr>,
+  deny: Vec<Cidr>,
+}
+
+impl AccessList {
+  /// Parses the entries of both lists. `what` names the declaration in
+  /// error messages.
+  ///
+  /// # Errors
+  ///
+  /// This function will return an error naming the first entry that is not
+  /// an address or CIDR range.
+  pub fn parse(what: &str, allow: &[String], deny: &[String]) -> Result<AccessList, String> {
+    let parse = |list: &[String], name: &str| {
+      list
+        .iter()
+        .map(|entry| Cidr::parse(entry).map_err(|e| format!("{} {}: {}", what, name, e)))
+        .collect::<Result<Vec<_>, _>>()
+    };
+    Ok(AccessList {
+      allow: parse(allow, "allow")?,
+      deny: parse(deny, "deny")?,
+    })
+  }
+
+  /// Reads a route's `allow` and `deny` options. Returns `None` when it
+  /// sets neither.
+  ///
+  /// # Errors
+  ///
+  /// This function will return a `LuaError` if an option is not a list of
+  /// strings or an entry is not an address or CIDR range.
+  pub fn from_route_options(path: &str, opts: &LuaTable) -> LuaResult<Option<AccessList>> {
+    let allow = opts.get::<Option<Vec<String>>>("allow")?;
+    let deny = opts.get::<Option<Vec<String>>>("deny")?;
+    if allow.is_none() && deny.is_none() {
+      return Ok(None);
+    }
+    AccessList::parse(
+      &format!("router.add('{}')", path),
+      &allow.unwrap_or_default(),
+      &deny.unwrap_or_default(),
+    )
+    .map(Some)
+    .map_err(LuaError::external)
+  }
+
+  /// Whether the list lets anyone through without looking.
+  pub fn is_empty(&self) -> bool {
+    self.allow.is_empty() && self.deny.is_empty()
+  }
+
+  /// Decides on a client with address `ip` (`None` for a Unix socket
+  /// client). Returns the `allow` entry it matched, if any, or why it is
+  /// refused.
+  pub fn check(&self, ip: Option<IpAddr>) -> Result<Option<&Cidr>, Denied<'_>> {
+    if let Some(rule) = ip.and_then(|ip| self.deny.iter().find(|rule| rule.contains(ip))) {
+      return Err(Denied::Rule(rule));
+    }
+    if self.allow.is_empty() {
+      return Ok(None);
+    }
+    ip.and_then(|ip| self.allow.iter().find(|rule| rule.contains(ip)))
+      .map(Some)
+      .ok_or(Denied::NotAllowed)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn ip(text: &str) -> IpAddr {
+    text.parse().unwrap()
+  }
+
+  fn list(allow: &[&str], deny: &[&str]) -> AccessList {
+    let strings = |entries: &[&str]| entries.iter().map(|e| e.to_string()).collect::<Vec<_>>();
+    AccessList::parse("CONFIG.access", &strings(allow), &strings(deny)).unwrap()
+  }
+
+  #[test]
+  fn ipv4_ranges_match_by_prefix() {
+    let range = Cidr::parse("10.1.2.3/8").unwrap();
+    assert!(range.contains(ip("10.0.0.0")));
+    assert!(range.contains(ip("10.255.255.255")));
+    assert!(!range.contains(ip("11.0.0.0")));
+    assert!(!range.contains(ip("9.255.255.255")));
+    // An IPv4 client on an IPv6 listener.
+    assert!(range.contains(ip("::ffff:10.1.2.3")));
+    assert!(!range.contains(ip("::a01:203")));
+
+    let host = Cidr::parse("192.168.1.5").unwrap();
+    assert!(host.contains(ip("192.168.1.5")));
+    assert!(!host.contains(ip("192.168.1.6")));
+    let odd = Cidr::parse("192.168.1.0/25").unwrap();
+    assert!(odd.contains(ip("192.168.1.127")));
+    assert!(!odd.contains(ip("192.168.1.128")));
+    let everyone = Cidr::parse("0.0.0.0/0").unwrap();
+    assert!(everyone.contains(ip("203.0.113.9")));
+    assert!(!everyone.contains(ip("2001:db8::1")));
+  }
+
+  #[test]
+  fn ipv6_ranges_match_by_prefix() {
+    let range = Cidr::parse("2001:db8::/32").unwrap();
+    assert!(range.contains(ip("2001:db8::1")));
+    assert!(range.contains(ip("2001:db8:ffff:ffff:ffff:ffff:ffff:ffff")));
+    assert!(!range.contains(ip("2001:db9::")));
+    assert!(!range.contains(ip("10.0.0.1")));
+
+    let host = Cidr::parse("::1").unwrap();
+    assert!(host.contains(ip("::1")));
+    assert!(!host.contains(ip("::2")));
+    let odd = Cidr::parse("2001:db8:0:80::/57").unwrap();
+    assert!(odd.contains(ip("2001:db8:0:ff::1")));
+    assert!(!odd.contains(ip("2001:db8:0:7f::1")));
+    assert!(Cidr::parse("::/0").unwrap().contains(ip("fe80::1")));
+  }
+
+  #[test]
+  fn bad_entries_are_refused() {
+    for (entry, expected) in [
+      (
+        "10.0.0.0/33",
+        "'10.0.0.0/33': the prefix length must be 0 to 32",
+      ),
+      ("::/129", "'::/129': the prefix length must be 0 to 128"),
+      ("10.0.0.0/-1", "the prefix length must be 0 to 32"),
+      (
+        "example.com",
+        "'example.com' is not an IP address or CIDR range",
+      ),
+      ("10.0.0/8", "is not an IP address or CIDR range"),
+    ] {
+      let error = AccessList::parse("CONFIG.access", &[], &[entry.to_string()]).unwrap_err();
+      assert!(error.starts_with("CONFIG.access deny: "), "{}", error);
+      assert!(error.contains(expected), "{}: {}", entry, error);
+    }
+  }
+
+  #[test]
+  fn deny_wins_over_allow() {
+    let access = list(
+      &["10.0.0.0/8", "2001:db8::/32"],
+      &["10.0.0.5", "2001:db8:bad::/48"],
+    );
+    assert_eq!(
+      access
+        .check(Some(ip("10.0.0.4")))
+        .unwrap()
+        .unwrap()
+        .to_string(),
+      "10.0.0.0/8"
+    );
+    assert_eq!(
+      access.check(Some(ip("10.0.0.5"))).unwrap_err().to_string(),
+      "denied by 10.0.0.5"
+    );
+    assert!(access.check(Some(ip("2001:db8:1::1"))).is_ok());
+    assert_eq!(
+      access
+        .check(Some(ip("2001:db8:bad::1")))
+        .unwrap_err()
+        .to_string(),
+      "denied by 2001:db8:bad::/48"
+    );
+    assert_eq!(
+      access.check(Some(ip("192.0.2.1"))).unwrap_err().to_string(),
+      "not in the allow list"
+    );
+    // A Unix socket client has no address to allow.
+    assert!(matches!(access.check(None), Err(Denied::NotAllowed)));
+  }
+
+  #[test]
+  fn without_an_allow_list_everyone_not_denied_is_let_in() {
+    let access = list(&[], &["203.0.113.0/24"]);
+    assert!(matches!(access.check(Some(ip("192.0.2.1"))), Ok(None)));
+    assert!(matches!(access.check(None), Ok(None)));
+    assert!(access.check(Some(ip("203.0.113.9"))).is_err());
+    assert!(list(&[], &[]).is_empty());
+    assert!(matches!(
+      list(&[], &[]).check(Some(ip("192.0.2.1"))),
+      Ok(None)
+    ));
+  }
+}
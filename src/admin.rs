@@ -0,0 +1,387 @@
+//! # Admin Endpoints
+//!
+//! Where signals can't easily be sent (e.g. in a container), the server can
+//! be managed over HTTP instead. Setting `CONFIG.admin.token` enables:
+//!
+//! - `POST /admin/reload`: runs `config.lua` again and swaps in its routes
+//!   and static directories. The old ones keep serving if it fails to load
+//!   or a handler script doesn't compile. Other settings only change on a
+//!   restart; the response says when they differ from the running ones.
+//! - `POST /admin/cache/flush`: empties `fyre.cache`, the static file maps,
+//!   and the compiled scripts kept in memory.
+//! - `GET /admin/config`: the server's version, the settings in effect,
+//!   with secrets left out, and the current routes.
+//! - `GET /admin/stats`: each route's request counts, latency percentiles,
+//!   body sizes, and last error (see `route_stats`).
+//! - `POST /admin/stats/reset`: starts those statistics again.
+//! - `GET /admin/log-level`: the log filter in effect (see `logger`).
+//! - `POST /admin/log-level`: replaces the log filter with the one in the
+//!   request body, such as `info,fyre::pipeline=debug`, until the next
+//!   change or restart.
+//!
+//! Every request must send `Authorization: Bearer <token>`, is limited to
+//! `CONFIG.rate_limit` per `RATE_WINDOW` per client, and is logged with the
+//! client's address, and recorded in the audit log (see `audit`), refused
+//! ones included. With `CONFIG.admin.addr` the endpoints are served on that
+//! address only (e.g. `127.0.0.1:9100` or a Unix socket); otherwise they
+//! are served under `/admin/` on the server's own addresses, ahead of the
+//! routes. Without a token none of these paths exist.
+
+use arc_swap::ArcSwap;
+use std::collections::HashMap;
+use std::io::{self, Cursor, Read};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tiny_http::{Header, Method, Response};
+
+use crate::fyre::crypto::constant_time_eq;
+use crate::net::Listener;
+use crate::{
+  audit, check_scripts, load_lua_config, locks, logger, paths, request_metrics, server, statics,
+  tls, AppState, RouteTable, RoutesMap,
+};
+
+/// The path prefix of the endpoints.
+pub const PREFIX: &str = "/admin/";
+/// The shortest token accepted, so it can't be guessed within the rate
+/// limit.
+pub const MIN_TOKEN_LEN: usize = 16;
+/// The requests one client may make per `RATE_WINDOW`.
+const RATE_LIMIT: u32 = 10;
+const RATE_WINDOW: Duration = Duration::from_secs(60);
+/// The longest log filter `POST /admin/log-level` reads.
+const MAX_LOG_LEVEL_BYTES: u64 = 4096;
+
+/// The `admin` settings from `config.lua`.
+#[derive(Debug, Clone)]
+pub struct AdminSettings {
+  /// The bearer token every request must send.
+  pub token: String,
+  /// The address the endpoints are served on, instead of under `/admin/`
+  /// on the server's own addresses.
+  pub addr: Option<String>,
+}
+
+/// The admin endpoints.
+pub struct Admin {
+  token: String,
+  /// Who a request with the token is recorded as in the audit log.
+  actor: String,
+  /// Whether the endpoints are served under `PREFIX` on the server's own
+  /// addresses.
+  on_main_listener: bool,
+  /// Where `config.lua` is, for reloads.
+  paths: paths::Paths,
+  /// The settings the server started with, from `settings::effective`.
+  settings: serde_json::Value,
+  workers: usize,
+  /// The requests per client in the current window.
+  clients: Mutex<HashMap<String, (Instant, u32)>>,
+  /// Held during a reload, so two can't run at once.
+  reloading: Mutex<()>,
+}
+
+type AdminResponse = Response<Cursor<Vec<u8>>>;
+
+impl Admin {
+  /// Creates the endpoints; `effective` and `workers` are the settings the
+  /// server started with, shown by `GET /admin/config`.
+  pub fn new(
+    settings: AdminSettings,
+    paths: paths::Paths,
+    effective: serde_json::Value,
+    workers: usize,
+  ) -> Self {
+    Admin {
+      actor: audit::token_actor(&settings.token),
+      token: settings.token,
+      on_main_listener: settings.addr.is_none(),
+      paths,
+      settings: effective,
+      workers,
+      clients: Mutex::new(HashMap::new()),
+      reloading: Mutex::new(()),
+    }
+  }
+
+  /// Whether `url` is an admin endpoint on the server's own addresses,
+  /// which `handle_request` passes to `handle` instead of the routes.
+  pub fn serves(&self, url: &str) -> bool {
+    self.on_main_listener && url.starts_with(PREFIX)
+  }
+
+  /// Authenticates, runs, logs, and answers one admin request.
+  pub fn handle(&self, mut request: server::Request, state: &AppState) {
+    let client = client(request.remote_addr());
+    let method = request.method().clone();
+    let path = request
+      .url()
+      .split('?')
+      .next()
+      .unwrap_or_default()
+      .to_string();
+
+    let (response, actor, outcome, result) = if !self.allow(&client) {
+      warn!("Admin: {} {} from {} rate limited", method, path, client);
+      let response = with_header(error(429, "Too many admin requests"), "Retry-After", "60");
+      (response, "unauthenticated", "rate_limited", None)
+    } else if !self.authorized(&request) {
+      warn!(
+        "Admin: {} {} from {} rejected: bad or missing token",
+        method, path, client
+      );
+      let response = with_header(error(401, "Unauthorized"), "WWW-Authenticate", "Bearer");
+      (response, "unauthenticated", "denied", None)
+    } else {
+      let (response, result) = self.dispatch(&method, &path, &mut request, state);
+      let status = response.status_code().0;
+      info!("Admin: {} {} from {} -> {}", method, path, client, status);
+      let outcome = if status < 400 { "ok" } else { "failed" };
+      (response, self.actor.as_str(), outcome, result)
+    };
+    if let Some(audit) = &state.audit {
+      let mut params = serde_json::json!({
+        "method": method.as_str(),
+        "path": path.as_str(),
+        "client": client.as_str(),
+        "status": response.status_code().0,
+      });
+      if let Some(result) = result {
+        params["result"] = result;
+      }
+      audit.record(actor, action(&path), params, outcome);
+    }
+    if let Err(e) = request.respond(response) {
+      error!("Admin: Error sending response to {}: {}", client, e);
+    }
+  }
+
+  /// Runs the endpoint, returning its response and, for one that changes
+  /// something, what it did, for the audit log.
+  fn dispatch(
+    &self,
+    method: &Method,
+    path: &str,
+    request: &mut server::Request,
+    state: &AppState,
+  ) -> (AdminResponse, Option<serde_json::Value>) {
+    let allowed: &[Method] = match path {
+      "/admin/reload" | "/admin/cache/flush" | "/admin/stats/reset" => &[Method::Post],
+      "/admin/config" | "/admin/stats" => &[Method::Get],
+      "/admin/log-level" => &[Method::Get, Method::Post],
+      _ => return (error(404, "No such admin endpoint"), None),
+    };
+    if !allowed.contains(method) {
+      let allow: Vec<&str> = allowed.iter().map(Method::as_str).collect();
+      let response = with_header(error(405, "Method Not Allowed"), "Allow", &allow.join(", "));
+      return (response, None);
+    }
+    match path {
+      "/admin/reload" => match self.reload(state) {
+        Ok(body) => (json(200, body.clone()), Some(body)),
+        Err(e) => {
+          error!("Admin: Reload failed: {}", e);
+          let message = format!("Reload failed: {}", e);
+          (error(500, &message), Some(serde_json::json!({ "error": message })))
+        }
+      },
+      "/admin/cache/flush" => {
+        let body = serde_json::json!({
+          "cache": state.cache.clear(),
+          "static_files": state.files.clear(),
+          "scripts": state.scripts.clear(),
+        });
+        (json(200, body.clone()), Some(body))
+      }
+      "/admin/stats" => (json(200, state.route_stats.to_json()), None),
+      "/admin/stats/reset" => {
+        state.route_stats.reset();
+        info!("Admin: Route statistics reset");
+        (json(200, serde_json::json!({ "reset": true })), None)
+      }
+      "/admin/log-level" if *method == Method::Post => match read_log_level(request) {
+        Ok(filter) => {
+          let previous = logger::filter();
+          logger::set_filter(filter.clone());
+          info!("Admin: Log level set to {} (was {})", filter, previous);
+          let body = serde_json::json!({
+            "level": filter.to_string(),
+            "previous": previous.to_string(),
+          });
+          (json(200, body.clone()), Some(body))
+        }
+        Err(message) => (error(400, &message), None),
+      },
+      "/admin/log-level" => {
+        let body = serde_json::json!({ "level": logger::filter().to_string() });
+        (json(200, body), None)
+      }
+      _ => (json(200, self.config(state)), None),
+    }
+  }
+
+  /// Loads `config.lua` into a new route table and swaps it in once every
+  /// handler script compiles.
+  fn reload(&self, state: &AppState) -> Result<serde_json::Value, String> {
+    let _reloading = locks::lock(&self.reloading, "admin reload");
+    let routes: RoutesMap = Arc::new(ArcSwap::from_pointee(RouteTable::default()));
+    let config = load_lua_config(routes.clone(), &self.paths).map_err(|e| e.to_string())?;
+    let table = routes.load_full();
+    check_scripts(&table, &state.scripts, config.script_check).map_err(|e| e.to_string())?;
+    state.routes.store(table.clone());
+    info!(
+      "Admin: Reloaded {} route(s) and {} static director{} from {}",
+      table.handlers.len(),
+      table.mounts.len(),
+      if table.mounts.len() == 1 { "y" } else { "ies" },
+      self.paths.config_file().display()
+    );
+
+    let restart_needed = config.effective != self.settings;
+    if restart_needed {
+      warn!("Admin: Settings other than routes changed; restart to apply them");
+    }
+    Ok(serde_json::json!({
+      "routes": table.handlers.len(),
+      "static_dirs": table.mounts.len(),
+      "warnings": config.warnings,
+      "restart_needed": restart_needed,
+    }))
+  }
+
+  fn config(&self, state: &AppState) -> serde_json::Value {
+    let table = state.routes.load();
+    let mut routes: Vec<serde_json::Value> = table
+      .handlers
+      .iter()
+      .map(|(path, route)| serde_json::json!({ "path": path, "script": route.script }))
+      .collect();
+    routes.sort_by(|a, b| a["path"].as_str().cmp(&b["path"].as_str()));
+    let static_dirs: Vec<String> = table.mounts.iter().map(statics::describe).collect();
+    serde_json::json!({
+      "version": crate::VERSION,
+      "config_file": self.paths.config_file().display().to_string(),
+      "env": self.paths.env(),
+      "settings": self.settings,
+      "listening": state.addrs.load().as_slice(),
+      "workers": self.workers,
+      "routes": routes,
+      "static_dirs": static_dirs,
+    })
+  }
+
+  /// Counts a request from `client`, returning `false` once it is over the
+  /// limit for the current window.
+  fn allow(&self, client: &str) -> bool {
+    let now = Instant::now();
+    let mut clients = locks::lock(&self.clients, "admin clients");
+    clients.retain(|_, (started, _)| now.duration_since(*started) < RATE_WINDOW);
+    let (_, count) = clients.entry(client.to_string()).or_insert((now, 0));
+    *count += 1;
+    *count <= RATE_LIMIT
+  }
+
+  fn authorized(&self, request: &server::Request) -> bool {
+    request
+      .headers()
+      .iter()
+      .filter(|h| h.field.equiv("Authorization"))
+      .filter_map(|h| h.value.as_str().strip_prefix("Bearer "))
+      .any(|token| constant_time_eq(token.trim().as_bytes(), self.token.as_bytes()))
+  }
+}
+
+/// Serves the endpoints on their own `listener`, from `CONFIG.admin.addr`, with
+/// a thread of their own so they answer even while every worker is busy. `tls`
+/// is used on a TCP listener; a Unix socket is always plain.
+///
+/// # Errors
+///
+/// This function will return an error if the server or its thread can't
+/// be started.
+pub fn start(
+  listener: Listener,
+  limits: server::Limits,
+  tls: Option<Arc<tls::ServerTls>>,
+  state: &Arc<AppState>,
+) -> io::Result<Arc<server::Server>> {
+  let tls = tls.filter(|_| listener.port().is_some());
+  let server = Arc::new(server::Server::start(
+    vec![listener],
+    limits,
+    Arc::new(server::ConnectionStats::default()),
+    tls,
+    None,
+    state.server_header.clone(),
+    None,
+  )?);
+  let admin_server = server.clone();
+  let state = state.clone();
+  std::thread::Builder::new()
+    .name("admin".to_string())
+    .spawn(move || {
+      while let Some(request) = admin_server.recv() {
+        if state.request_metrics.serves_admin(request.url()) {
+          request_metrics::serve(request, &state);
+        } else if let Some(admin) = &state.admin {
+          admin.handle(request, &state);
+        }
+      }
+    })?;
+  Ok(server)
+}
+
+/// Reads the log filter from the body of `POST /admin/log-level`.
+fn read_log_level(request: &mut server::Request) -> Result<logger::Filter, String> {
+  let mut body = String::new();
+  request
+    .as_reader()
+    .take(MAX_LOG_LEVEL_BYTES + 1)
+    .read_to_string(&mut body)
+    .map_err(|e| format!("Failed to read the log level: {}", e))?;
+  if body.len() as u64 > MAX_LOG_LEVEL_BYTES {
+    return Err(format!(
+      "The log level is longer than {} bytes",
+      MAX_LOG_LEVEL_BYTES
+    ));
+  }
+  body.trim().parse()
+}
+
+/// The audit log's name for the endpoint at `path`.
+fn action(path: &str) -> &str {
+  match path {
+    "/admin/reload" => "reload",
+    "/admin/cache/flush" => "cache.flush",
+    "/admin/config" => "config.read",
+    "/admin/stats" => "stats.read",
+    "/admin/stats/reset" => "stats.reset",
+    "/admin/log-level" => "log.level",
+    other => other,
+  }
+}
+
+/// The client a request is counted against: its IP, so that reconnecting
+/// doesn't reset the limit.
+fn client(addr: &server::RemoteAddr) -> String {
+  match addr {
+    server::RemoteAddr::Tcp(addr) => addr.ip().to_string(),
+    addr => addr.to_string(),
+  }
+}
+
+fn json(status: u16, body: serde_json::Value) -> AdminResponse {
+  let response = Response::from_string(format!("{}\n", body)).with_status_code(status);
+  with_header(response, "Content-Type", "application/json")
+}
+
+fn error(status: u16, message: &str) -> AdminResponse {
+  json(status, serde_json::json!({ "error": message }))
+}
+
+fn with_header(mut response: AdminResponse, name: &str, value: &str) -> AdminResponse {
+  if let Ok(header) = Header::from_bytes(name, value) {
+    response.add_header(header);
+  }
+  response
+}
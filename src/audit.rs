@@ -0,0 +1,356 @@
+//! # Audit Log
+//!
+//! Records the administrative actions taken on a running server: admin
+//! endpoint calls (reloads, which swap the routes, cache flushes, and
+//! config reads, including refused ones) and shutdowns by signal. Each
+//! entry is one JSON object with the `time`, the `actor` (`token:<id>`, the
+//! first 8 hex digits of the admin token's SHA-256, or `signal`), the
+//! `action`, its `params`, and the `outcome`.
+//!
+//! Entries go to `CONFIG.audit.file`, opened for appending only and fsynced
+//! after every entry, or to the syslog facility `CONFIG.audit.syslog` (e.g.
+//! `"auth"` or `"local0"`) through `/dev/log`. With neither, but admin
+//! endpoints enabled, they go to the main log as `AUDIT` lines, and `fyre
+//! check` warns that there is no separate destination.
+//!
+//! Entries are sent over a channel to one writer thread, so they never
+//! interleave, and one already sent is written even if the worker that sent
+//! it panics. `flush` waits for the ones sent so far, at shutdown.
+
+use serde_json::json;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::path::PathBuf;
+use std::sync::mpsc;
+use std::time::Duration;
+
+use mlua::prelude::*;
+
+use crate::logger::{self, Level};
+
+/// How long `flush` waits for the writer thread.
+const FLUSH_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Where audit entries are written.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Destination {
+  /// Appended to a file of their own.
+  File(PathBuf),
+  /// Sent to the local syslog daemon with this facility number.
+  Syslog(u8),
+  /// Written to the main log.
+  Log,
+}
+
+impl Destination {
+  /// Reads `CONFIG.audit.file` and `CONFIG.audit.syslog`. Returns `None` if
+  /// neither is set.
+  ///
+  /// # Errors
+  ///
+  /// Returns an error message if both are set, either is not a string, or
+  /// the facility is not a syslog facility name.
+  pub fn from_globals(globals: &LuaTable) -> Result<Option<Destination>, String> {
+    let file = globals
+      .get::<Option<String>>("AUDIT_FILE")
+      .map_err(|e| format!("CONFIG.audit.file must be a file path: {}", e))?;
+    let syslog = globals
+      .get::<Option<String>>("AUDIT_SYSLOG")
+      .map_err(|e| format!("CONFIG.audit.syslog must be a syslog facility name: {}", e))?;
+    match (file, syslog) {
+      (Some(_), Some(_)) => {
+        Err("CONFIG.audit.file and CONFIG.audit.syslog can't both be set".to_string())
+      }
+      (Some(file), None) => Ok(Some(Destination::File(PathBuf::from(file)))),
+      (None, Some(facility)) => facility_number(&facility)
+        .map(|facility| Some(Destination::Syslog(facility)))
+        .ok_or_else(|| {
+          format!(
+            "CONFIG.audit.syslog must be a syslog facility such as \"auth\" or \"local0\", \
+             got \"{}\"",
+            facility
+          )
+        }),
+      (None, None) => Ok(None),
+    }
+  }
+}
+
+fn facility_number(name: &str) -> Option<u8> {
+  let number = match name {
+    "kern" => 0,
+    "user" => 1,
+    "mail" => 2,
+    "daemon" => 3,
+    "auth" => 4,
+    "syslog" => 5,
+    "lpr" => 6,
+    "news" => 7,
+    "uucp" => 8,
+    "cron" => 9,
+    "authpriv" => 10,
+    "ftp" => 11,
+    _ => {
+      let n: u8 = name.strip_prefix("local")?.parse().ok()?;
+      return (n < 8).then_some(16 + n);
+    }
+  };
+  Some(number)
+}
+
+enum Message {
+  Entry(serde_json::Value),
+  Flush(mpsc::Sender<()>),
+}
+
+/// The audit log's sending end, in `AppState`.
+pub struct Audit {
+  sender: mpsc::Sender<Message>,
+}
+
+impl Audit {
+  /// Opens `destination` and starts the writer thread.
+  ///
+  /// # Errors
+  ///
+  /// Returns an error message if the file can't be opened, syslog can't be
+  /// reached, or the thread can't be started.
+  pub fn start(destination: Destination) -> Result<Audit, String> {
+    let mut sink = Sink::open(&destination)?;
+    let (sender, receiver) = mpsc::channel();
+    std::thread::Builder::new()
+      .name("audit".to_string())
+      .spawn(move || {
+        for message in receiver {
+          match message {
+            Message::Entry(entry) => {
+              if let Err(e) = sink.write(&entry) {
+                error!("Failed to write audit entry {}: {}", entry, e);
+              }
+            }
+            Message::Flush(done) => {
+              let _ = done.send(());
+            }
+          }
+        }
+      })
+      .map_err(|e| format!("Could not start the audit log thread: {}", e))?;
+    Ok(Audit { sender })
+  }
+
+  /// Records that `actor` took `action` with `params`, and how it ended.
+  pub fn record(&self, actor: &str, action: &str, params: serde_json::Value, outcome: &str) {
+    let entry = json!({
+      "time": chrono::Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Millis, true),
+      "actor": actor,
+      "action": action,
+      "params": params,
+      "outcome": outcome,
+    });
+    if let Err(mpsc::SendError(Message::Entry(entry))) = self.sender.send(Message::Entry(entry)) {
+      error!("Audit log writer has stopped; lost entry {}", entry);
+    }
+  }
+
+  /// Waits until the entries recorded so far are written, for up to
+  /// `FLUSH_TIMEOUT`.
+  pub fn flush(&self) {
+    let (done, written) = mpsc::channel();
+    if self.sender.send(Message::Flush(done)).is_ok()
+      && written.recv_timeout(FLUSH_TIMEOUT).is_err()
+    {
+      warn!("Timed out waiting for the audit log to be written");
+    }
+  }
+}
+
+/// The actor name of a holder of the admin token `token`: a short hash, so
+/// tokens can be told apart after rotation without the log revealing them.
+pub fn token_actor(token: &str) -> String {
+  use sha2::{Digest, Sha256};
+  format!("token:{}", &hex::encode(Sha256::digest(token.as_bytes()))[..8])
+}
+
+/// The open destination, owned by the writer thread.
+enum Sink {
+  File(File),
+  #[cfg(unix)]
+  Syslog {
+    socket: std::os::unix::net::UnixDatagram,
+    facility: u8,
+  },
+  Log,
+}
+
+impl Sink {
+  fn open(destination: &Destination) -> Result<Sink, String> {
+    match destination {
+      Destination::File(path) => {
+        let mut options = OpenOptions::new();
+        options.append(true).create(true);
+        #[cfg(unix)]
+        std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+        options
+          .open(path)
+          .map(Sink::File)
+          .map_err(|e| format!("Failed to open audit log {}: {}", path.display(), e))
+      }
+      #[cfg(unix)]
+      Destination::Syslog(facility) => {
+        let socket = std::os::unix::net::UnixDatagram::unbound()
+          .and_then(|socket| socket.connect("/dev/log").map(|()| socket))
+          .map_err(|e| format!("Failed to reach syslog at /dev/log for the audit log: {}", e))?;
+        Ok(Sink::Syslog {
+          socket,
+          facility: *facility,
+        })
+      }
+      #[cfg(not(unix))]
+      Destination::Syslog(_) => Err("CONFIG.audit.syslog is only supported on Unix".to_string()),
+      Destination::Log => Ok(Sink::Log),
+    }
+  }
+
+  fn write(&mut self, entry: &serde_json::Value) -> io::Result<()> {
+    match self {
+      Sink::File(file) => {
+        file.write_all(format!("{}\n", entry).as_bytes())?;
+        file.sync_data()
+      }
+      #[cfg(unix)]
+      Sink::Syslog { socket, facility } => {
+        // Informational, or a warning for anything that didn't succeed.
+        let severity = if entry["outcome"] == "ok" { 6 } else { 4 };
+        let line = format!(
+          "<{}>fyre[{}]: {}",
+          u16::from(*facility) * 8 + severity,
+          std::process::id(),
+          entry
+        );
+        socket.send(line.as_bytes()).map(|_| ())
+      }
+      Sink::Log => {
+        // Written whatever the log level, like the audit file.
+        logger::write_fields(Level::Info, format_args!("AUDIT {}", entry), &[]);
+        Ok(())
+      }
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::testing::{self, Fixture};
+  use serde_json::Value;
+
+  fn destination(config: &str) -> Result<Option<Destination>, String> {
+    let lua = Lua::new();
+    lua.load(config).exec().unwrap();
+    Destination::from_globals(&lua.globals())
+  }
+
+  #[test]
+  fn destinations_are_read_from_config() {
+    assert_eq!(destination("").unwrap(), None);
+    assert_eq!(
+      destination(r#"AUDIT_FILE = "/var/log/fyre-audit.log""#).unwrap(),
+      Some(Destination::File(PathBuf::from("/var/log/fyre-audit.log")))
+    );
+    for (facility, number) in [
+      ("auth", 4),
+      ("authpriv", 10),
+      ("local0", 16),
+      ("local7", 23),
+    ] {
+      assert_eq!(
+        destination(&format!("AUDIT_SYSLOG = {:?}", facility)).unwrap(),
+        Some(Destination::Syslog(number))
+      );
+    }
+    for config in [
+      r#"AUDIT_SYSLOG = "local8""#,
+      r#"AUDIT_SYSLOG = "security""#,
+      r#"AUDIT_FILE = "audit.log" AUDIT_SYSLOG = "auth""#,
+    ] {
+      assert!(destination(config).is_err(), "{}", config);
+    }
+  }
+
+  #[test]
+  fn token_actors_tell_tokens_apart_without_revealing_them() {
+    let actor = token_actor("secret-token");
+    assert_eq!(actor, token_actor("secret-token"));
+    assert_ne!(actor, token_actor("secret-token-2"));
+    assert!(actor
+      .strip_prefix("token:")
+      .is_some_and(|hash| hash.len() == 8));
+    assert!(!actor.contains("secret"));
+  }
+
+  #[test]
+  fn admin_requests_are_appended_to_the_file() {
+    let fixture = Fixture::new("", &[]);
+    let file = fixture.path().join("audit.log");
+    std::fs::write(
+      fixture.path().join("config.lua"),
+      format!(
+        "CONFIG = {{ admin = {{ token = \"admin-token-0123456789\" }}, audit = {{ file = {:?} }} }}",
+        file.display().to_string()
+      ),
+    )
+    .unwrap();
+    let server = fixture
+      .builder()
+      .addr("127.0.0.1:0")
+      .workers(1)
+      .load()
+      .unwrap();
+    let addr = server.serve().unwrap().remove(0);
+    let admin = |method: &str, path: &str, token: &str| {
+      testing::send(
+        &addr,
+        &format!(
+          "{} {} HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\
+           Authorization: Bearer {}\r\nContent-Length: 0\r\n\r\n",
+          method, path, token
+        ),
+      )
+    };
+    assert!(admin("GET", "/admin/config", "admin-token-0123456789").starts_with("HTTP/1.1 200"));
+    assert!(admin("POST", "/admin/cache/flush", "guess").starts_with("HTTP/1.1 401"));
+    server.shutdown();
+
+    let contents = std::fs::read_to_string(&file).unwrap();
+    let entries: Vec<Value> = contents
+      .lines()
+      .map(|line| serde_json::from_str(line).unwrap())
+      .collect();
+    let summary: Vec<_> = entries
+      .iter()
+      .map(|e| {
+        (
+          e["actor"].as_str().unwrap(),
+          e["action"].as_str().unwrap(),
+          e["outcome"].as_str().unwrap(),
+        )
+      })
+      .collect();
+    let actor = token_actor("admin-token-0123456789");
+    assert_eq!(
+      summary,
+      [
+        (actor.as_str(), "config.read", "ok"),
+        ("unauthenticated", "cache.flush", "denied")
+      ]
+    );
+    assert_eq!(entries[1]["params"]["status"], 401);
+    assert!(!contents.contains("admin-token-0123456789"));
+    #[cfg(unix)]
+    {
+      use std::os::unix::fs::PermissionsExt;
+      let mode = std::fs::metadata(&file).unwrap().permissions().mode();
+      assert_eq!(mode & 0o777, 0o600);
+    }
+  }
+}
@@ -0,0 +1,406 @@
+//! # API Key Strategy
+//!
+//! Authenticates a machine client by the key it sends in a header
+//! (`X-Api-Key` unless the strategy's `header` says otherwise). Keys are
+//! listed in `CONFIG.api_keys`, or a strategy's own `keys`, mapping an id
+//! for each key to the hex SHA-256 of the key, so `config.lua` never holds
+//! the keys themselves:
+//!
+//! ```lua
+//! CONFIG = { api_keys = { ["svc-billing"] = env("BILLING_KEY_HASH") } }
+//! router.add("/internal/invoices", "invoices.lua", { require_auth = "api_key" })
+//! ```
+//!
+//! A request's key is hashed and compared in constant time with every
+//! stored hash, and the matching id becomes the request's subject and
+//! `request.auth.key_id`. Removing a key and reloading the configuration
+//! revokes it. When each key was last used is kept in `Usage`, for
+//! `fyre.metrics.render()`.
+//!
+//! Failed attempts take a token from a bucket per client address
+//! (`failure_limit`, by default 10 a minute); a client whose bucket is
+//! empty gets `429` before its key is checked, so keys can't be guessed at
+//! speed.
+
+use mlua::prelude::*;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tiny_http::Header;
+
+use super::Identity;
+use crate::client_limit::{RateLimit, DEFAULT_IPV6_PREFIX};
+use crate::fyre::crypto::constant_time_eq;
+use crate::locks;
+
+/// The header keys are read from when `header` is not set.
+pub const DEFAULT_HEADER: &str = "X-Api-Key";
+
+/// The failed attempts a client may make when `failure_limit` is not set.
+pub const DEFAULT_FAILURE_LIMIT: RateLimit = RateLimit {
+  requests: 10,
+  window: Duration::from_secs(60),
+  burst: 10,
+  ipv6_prefix: DEFAULT_IPV6_PREFIX,
+};
+
+/// An `api_key` strategy.
+#[derive(Debug)]
+pub struct ApiKeys {
+  header: String,
+  /// Each key's id and SHA-256.
+  keys: Vec<(String, [u8; 32])>,
+  /// How many failed attempts a client may make.
+  pub failure_limit: RateLimit,
+}
+
+impl ApiKeys {
+  /// Reads an `api_key` strategy's table, taking its keys from `keys` or,
+  /// without that, from `api_keys` (`CONFIG.api_keys`). `what` names it in
+  /// error messages.
+  ///
+  /// # Errors
+  ///
+  /// This function will return a `LuaError` if there are no keys, a hash is
+  /// not 64 hex digits, `header` is not a header name, or `failure_limit`
+  /// is not a valid rate limit.
+  pub fn from_lua(
+    what: &str,
+    table: &LuaTable,
+    api_keys: Option<&LuaTable>,
+  ) -> LuaResult<ApiKeys> {
+    let header = table
+      .get::<Option<String>>("header")?
+      .unwrap_or_else(|| DEFAULT_HEADER.to_string());
+    if header.is_empty() || !header.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'-') {
+      return Err(LuaError::external(format!(
+        "{}: header '{}' is not a header name",
+        what, header
+      )));
+    }
+    let (source, entries) = match table.get::<Option<LuaTable>>("keys")? {
+      Some(entries) => (what.to_string(), Some(entries)),
+      None => ("CONFIG.api_keys".to_string(), api_keys.cloned()),
+    };
+    let mut keys = Vec::new();
+    if let Some(entries) = entries {
+      for pair in entries.pairs::<String, String>() {
+        let (id, hash) = pair?;
+        let hash = hex::decode(hash.trim())
+          .ok()
+          .and_then(|hash| <[u8; 32]>::try_from(hash).ok())
+          .ok_or_else(|| {
+            LuaError::external(format!(
+              "{}: key {}: expected the key's SHA-256 as 64 hex digits",
+              source, id
+            ))
+          })?;
+        keys.push((id, hash));
+      }
+    }
+    if keys.is_empty() {
+      return Err(LuaError::external(format!(
+        "{}: api_key needs at least one key in keys or CONFIG.api_keys (is a key hash \
+         variable unset?)",
+        what
+      )));
+    }
+    keys.sort_unstable_by(|a, b| a.0.cmp(&b.0));
+    let failure_limit = match table.get::<Option<LuaTable>>("failure_limit")? {
+      Some(limit) => RateLimit::from_lua(&format!("{}.failure_limit", what), &limit)?,
+      None => DEFAULT_FAILURE_LIMIT,
+    };
+    Ok(ApiKeys {
+      header,
+      keys,
+      failure_limit,
+    })
+  }
+
+  /// Checks a request's headers. Returns the id of the key it sent, if
+  /// that is one of the keys.
+  pub fn check(&self, strategy: &str, headers: &[Header]) -> Option<Identity> {
+    let key = headers
+      .iter()
+      .find(|h| h.field.as_str().as_str().eq_ignore_ascii_case(&self.header))?
+      .value
+      .as_str()
+      .trim();
+    let hash = Sha256::digest(key.as_bytes());
+    // Every hash is compared, so the time taken doesn't tell which matched.
+    let mut matched = None;
+    for (id, expected) in &self.keys {
+      if constant_time_eq(&hash, expected) {
+        matched = Some(id);
+      }
+    }
+    let id = matched?;
+    Some(Identity {
+      strategy: strategy.to_string(),
+      subject: id.clone(),
+      key_id: Some(id.clone()),
+      claims: None,
+    })
+  }
+
+  /// The `WWW-Authenticate` value sent with a `401`.
+  pub fn challenge(&self) -> String {
+    format!("ApiKey header=\"{}\"", self.header)
+  }
+}
+
+/// When each key was last used, by id, since startup. It outlives
+/// configuration reloads, so a revoked key's last use is still reported.
+#[derive(Default)]
+pub struct Usage {
+  last_used: Mutex<HashMap<String, SystemTime>>,
+}
+
+impl Usage {
+  /// Records that the key `id` was just used.
+  pub fn record(&self, id: &str) {
+    let mut last_used = locks::lock(&self.last_used, "api key usage");
+    match last_used.get_mut(id) {
+      Some(time) => *time = SystemTime::now(),
+      None => {
+        last_used.insert(id.to_string(), SystemTime::now());
+      }
+    }
+  }
+
+  /// Returns each key's id and when it was last used, in seconds since the
+  /// epoch, sorted by id.
+  pub fn snapshot(&self) -> Vec<(String, f64)> {
+    let last_used = locks::lock(&self.last_used, "api key usage");
+    let mut keys: Vec<(String, f64)> = last_used
+      .iter()
+      .map(|(id, time)| {
+        let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+        (id.clone(), since_epoch.as_secs_f64())
+      })
+      .collect();
+    keys.sort_by(|a, b| a.0.cmp(&b.0));
+    keys
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::fyre::ratelimit::RateLimiter;
+  use crate::testing::Fixture;
+  use std::io::{Read, Write};
+  use std::net::TcpStream;
+
+  fn key_hash(key: &str) -> String {
+    hex::encode(Sha256::digest(key.as_bytes()))
+  }
+
+  fn header(name: &str, value: &str) -> Header {
+    Header::from_bytes(name.as_bytes(), value.as_bytes()).unwrap()
+  }
+
+  /// Reads an `api_key` strategy table, with `ACME` and `BILLING` set to
+  /// the hashes of `"key-acme"` and `"key-billing"`.
+  fn strategy(table: &str) -> LuaResult<ApiKeys> {
+    let lua = Lua::new();
+    lua.globals().set("ACME", key_hash("key-acme"))?;
+    lua.globals().set("BILLING", key_hash("key-billing"))?;
+    let table: LuaTable = lua.load(table).eval()?;
+    ApiKeys::from_lua("CONFIG.auth.partners", &table, None)
+  }
+
+  #[test]
+  fn keys_are_matched_by_their_sha256() {
+    let keys =
+      strategy("{ keys = { acme = ACME, billing = ' ' .. BILLING:upper() .. '\\n' } }").unwrap();
+    for (sent, id) in [
+      ("key-acme", "acme"),
+      ("key-billing", "billing"),
+      (" key-acme ", "acme"),
+    ] {
+      let identity = keys
+        .check("partners", &[header("x-api-key", sent)])
+        .unwrap();
+      assert_eq!(identity.subject, id);
+      assert_eq!(identity.key_id.as_deref(), Some(id));
+    }
+    // The stored hash isn't itself a key, and a key must match exactly.
+    for refused in [key_hash("key-acme").as_str(), "key-acm", "key-acmee", ""] {
+      assert!(
+        keys
+          .check("partners", &[header("X-Api-Key", refused)])
+          .is_none(),
+        "{}",
+        refused
+      );
+    }
+  }
+
+  #[test]
+  fn hashes_are_compared_whole() {
+    let hash = Sha256::digest(b"key-acme");
+    let mut last = hash;
+    last[31] ^= 1;
+    let mut first = hash;
+    first[0] ^= 0x80;
+    assert!(constant_time_eq(&hash, &hash));
+    assert!(!constant_time_eq(&hash, &last));
+    assert!(!constant_time_eq(&hash, &first));
+    assert!(!constant_time_eq(&hash, &hash[..31]));
+    assert!(!constant_time_eq(&[], &hash));
+  }
+
+  #[test]
+  fn bad_hashes_are_refused() {
+    for (table, expected) in [
+      (
+        "{ keys = { acme = 'abc' } }",
+        "key acme: expected the key's SHA-256",
+      ),
+      (
+        "{ keys = { acme = ACME .. '00' } }",
+        "key acme: expected the key's SHA-256",
+      ),
+      (
+        "{ keys = { acme = ('z'):rep(64) } }",
+        "key acme: expected the key's SHA-256",
+      ),
+      ("{ keys = {} }", "needs at least one key"),
+      (
+        "{ keys = { acme = ACME }, header = 'X Key' }",
+        "is not a header name",
+      ),
+    ] {
+      let error = strategy(table).unwrap_err().to_string();
+      assert!(error.contains(expected), "{}: {}", table, error);
+    }
+  }
+
+  #[test]
+  fn each_keys_last_use_is_kept() {
+    let usage = Usage::default();
+    assert!(usage.snapshot().is_empty());
+    let before = SystemTime::now()
+      .duration_since(UNIX_EPOCH)
+      .unwrap()
+      .as_secs_f64();
+    usage.record("billing");
+    usage.record("acme");
+    let first = usage.snapshot();
+    let ids: Vec<&str> = first.iter().map(|(id, _)| id.as_str()).collect();
+    assert_eq!(ids, ["acme", "billing"]);
+    let after = SystemTime::now()
+      .duration_since(UNIX_EPOCH)
+      .unwrap()
+      .as_secs_f64();
+    assert!(
+      first.iter().all(|(_, at)| (before..=after).contains(at)),
+      "{:?}",
+      first
+    );
+
+    std::thread::sleep(Duration::from_millis(20));
+    usage.record("acme");
+    let second = usage.snapshot();
+    assert_eq!(second.len(), 2);
+    assert!(second[0].1 > first[0].1);
+    assert_eq!(second[1], first[1]);
+  }
+
+  #[test]
+  fn failures_take_from_a_bucket_per_client() {
+    let keys =
+      strategy("{ keys = { acme = ACME }, failure_limit = { requests = 2, window = 60 } }")
+        .unwrap();
+    let limit = &keys.failure_limit;
+    assert_eq!((limit.requests, limit.burst), (2, 2));
+    let store = RateLimiter::default();
+    let client = limit.client_key("192.0.2.7".parse().unwrap());
+    let other = limit.client_key("192.0.2.8".parse().unwrap());
+    // Peeking, as a request does before its key is checked, takes nothing.
+    assert!(limit.peek(&store, &client).allowed);
+    assert!(limit.peek(&store, &client).allowed);
+    assert!(limit.check(&store, &client).allowed);
+    assert!(limit.check(&store, &client).allowed);
+    let decision = limit.peek(&store, &client);
+    assert!(!decision.allowed);
+    assert!(decision.retry_after > 0);
+    assert!(limit.peek(&store, &other).allowed);
+
+    let default = strategy("{ keys = { acme = ACME } }")
+      .unwrap()
+      .failure_limit;
+    assert_eq!(
+      (default.requests, default.window),
+      (10, Duration::from_secs(60))
+    );
+  }
+
+  #[test]
+  fn a_client_sending_bad_keys_is_throttled() {
+    let fixture = Fixture::new(
+      &format!(
+        r#"
+          CONFIG = {{
+            auth = {{
+              partners = {{
+                type = "api_key",
+                keys = {{ acme = "{}" }},
+                failure_limit = {{ requests = 3, window = 60 }},
+              }},
+            }},
+          }}
+          router.add("/invoices", "invoices.lua", {{ require_auth = "partners" }})
+        "#,
+        key_hash("key-acme")
+      ),
+      &[(
+        "invoices.lua",
+        r#"
+          return {
+            handler = function(request, response)
+              response.body = request.auth.key_id
+            end,
+          }
+        "#,
+      )],
+    );
+    let server = fixture
+      .builder()
+      .addr("127.0.0.1:0")
+      .workers(1)
+      .load()
+      .unwrap();
+    let addr = server.serve().unwrap().remove(0);
+    let get = |key: &str| {
+      let mut stream = TcpStream::connect(&addr).unwrap();
+      write!(
+        stream,
+        "GET /invoices HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\nX-Api-Key: {}\r\n\r\n",
+        key
+      )
+      .unwrap();
+      let mut response = String::new();
+      stream.read_to_string(&mut response).unwrap();
+      response
+    };
+
+    // A good key doesn't use up the bucket.
+    let accepted = get("key-acme");
+    assert!(accepted.starts_with("HTTP/1.1 200"), "{}", accepted);
+    assert!(accepted.ends_with("\r\n\r\nacme"), "{}", accepted);
+    for attempt in 0..3 {
+      let refused = get(&format!("guess-{}", attempt));
+      assert!(refused.starts_with("HTTP/1.1 401"), "{}", refused);
+    }
+    // Once it is empty even the right key is turned away.
+    for key in ["guess-3", "key-acme"] {
+      let throttled = get(key).to_ascii_lowercase();
+      assert!(throttled.starts_with("http/1.1 429"), "{}", throttled);
+      assert!(throttled.contains("\r\nretry-after: "), "{}", throttled);
+    }
+    server.shutdown();
+  }
+}
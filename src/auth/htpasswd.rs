@@ -0,0 +1,398 @@
+//! # htpasswd Files
+//!
+//! A basic-auth declaration can take its users from an Apache htpasswd
+//! file instead of listing them in `config.lua`:
+//!
+//! ```lua
+//! router.add("/admin", "admin.lua", {
+//!   auth = { type = "basic", htpasswd = "conf/users.htpasswd" },
+//! })
+//! ```
+//!
+//! The path is relative to the configuration's directory. Each line is
+//! `user:hash`, with a bcrypt (`$2y$`, from `htpasswd -B`), argon2
+//! (`$argon2id$`), or apr1 MD5 (`$apr1$`, `htpasswd`'s default) hash;
+//! blank lines and lines starting with `#` are skipped. Entries hashed with `crypt`, SHA-1, or not at all
+//! are too weak to accept, and a line that isn't `user:hash` can't be read;
+//! each is skipped with a warning naming its line, and the rest of the file
+//! is used. The first entry for a user wins, as in Apache.
+//!
+//! The file is read when the configuration is loaded, and again when its
+//! modification time changes (checked on every request that needs it) or
+//! the server gets `SIGHUP`. A file that can't be read at startup, or has no
+//! usable entries, stops the server; one that breaks later is reported and
+//! the users read before are kept.
+
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+
+use argon2::PasswordHash;
+use md5::{Digest, Md5};
+
+use super::Hash;
+use crate::locks;
+use crate::logger;
+
+/// The prefix of an apr1 MD5 hash.
+const APR1_MAGIC: &str = "$apr1$";
+/// The characters apr1 encodes its digest with.
+const APR1_ALPHABET: &[u8; 64] =
+  b"./0123456789ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz";
+
+/// Set once a configuration uses an htpasswd file, so the server knows to
+/// handle `SIGHUP`.
+static IN_USE: AtomicBool = AtomicBool::new(false);
+
+/// Whether any configuration loaded so far uses an htpasswd file.
+pub fn in_use() -> bool {
+  IN_USE.load(Ordering::Relaxed)
+}
+
+/// The users read from the file, and what the file looked like then.
+#[derive(Debug)]
+struct Loaded {
+  modified: Option<SystemTime>,
+  /// `logger::hangups()` when the file was read.
+  hangups: u64,
+  users: Arc<HashMap<String, Hash>>,
+}
+
+/// An htpasswd file, read again when it changes.
+#[derive(Debug)]
+pub struct Htpasswd {
+  path: PathBuf,
+  loaded: Mutex<Loaded>,
+}
+
+impl Htpasswd {
+  /// Reads the file at `path`.
+  ///
+  /// # Errors
+  ///
+  /// Returns an error message if the file can't be read or has no usable
+  /// entries.
+  pub fn load(path: PathBuf) -> Result<Htpasswd, String> {
+    let modified = modified(&path);
+    let hangups = logger::hangups();
+    let users = read(&path)?;
+    if users.is_empty() {
+      return Err(format!("{} has no usable entries", path.display()));
+    }
+    IN_USE.store(true, Ordering::Relaxed);
+    Ok(Htpasswd {
+      path,
+      loaded: Mutex::new(Loaded {
+        modified,
+        hangups,
+        users: Arc::new(users),
+      }),
+    })
+  }
+
+  /// The users in the file, reading it again first if it has changed or
+  /// the server got `SIGHUP` since it was last read.
+  pub(super) fn users(&self) -> Arc<HashMap<String, Hash>> {
+    let modified = modified(&self.path);
+    let hangups = logger::hangups();
+    let mut loaded = locks::lock(&self.loaded, "htpasswd file");
+    if modified != loaded.modified || hangups != loaded.hangups {
+      // Recorded even if the read fails, so a broken file is reported
+      // once rather than on every request.
+      loaded.modified = modified;
+      loaded.hangups = hangups;
+      match read(&self.path) {
+        Ok(users) if !users.is_empty() => {
+          info!("Reloaded {} ({} users)", self.path.display(), users.len());
+          loaded.users = Arc::new(users);
+        }
+        Ok(_) => warn!(
+          "{} has no usable entries; keeping the {} users read before",
+          self.path.display(),
+          loaded.users.len()
+        ),
+        Err(e) => warn!("{}; keeping the {} users read before", e, loaded.users.len()),
+      }
+    }
+    loaded.users.clone()
+  }
+}
+
+/// The file's modification time, if it can be read.
+fn modified(path: &Path) -> Option<SystemTime> {
+  fs::metadata(path).and_then(|metadata| metadata.modified()).ok()
+}
+
+/// Reads the entries of the file at `path`, warning about and skipping
+/// each line that can't be used.
+fn read(path: &Path) -> Result<HashMap<String, Hash>, String> {
+  let contents = fs::read_to_string(path)
+    .map_err(|e| format!("Failed to read htpasswd file {}: {}", path.display(), e))?;
+  let mut users = HashMap::new();
+  for (index, line) in contents.lines().enumerate() {
+    let line = line.trim_end_matches('\r');
+    if line.trim().is_empty() || line.starts_with('#') {
+      continue;
+    }
+    match parse_line(line) {
+      Ok((user, hash)) => {
+        if users.contains_key(user) {
+          warn!(
+            "{}:{}: user {} is listed again; the first entry is used",
+            path.display(),
+            index + 1,
+            user
+          );
+        } else {
+          users.insert(user.to_string(), hash);
+        }
+      }
+      Err(e) => warn!("{}:{}: {}; skipped", path.display(), index + 1, e),
+    }
+  }
+  Ok(users)
+}
+
+/// Reads one `user:hash` line.
+fn parse_line(line: &str) -> Result<(&str, Hash), String> {
+  let (user, hash) = line
+    .split_once(':')
+    .filter(|(user, _)| !user.is_empty())
+    .ok_or_else(|| "not a user:hash entry".to_string())?;
+  if hash.starts_with("$2") {
+    return hash
+      .parse::<bcrypt::HashParts>()
+      .map(|_| (user, Hash::Bcrypt(hash.to_string())))
+      .map_err(|e| format!("bad bcrypt hash for {}: {}", user, e));
+  }
+  if hash.starts_with("$argon2") {
+    return PasswordHash::new(hash)
+      .map(|_| (user, Hash::Argon2(hash.to_string())))
+      .map_err(|e| format!("bad argon2 hash for {}: {}", user, e));
+  }
+  if hash.starts_with(APR1_MAGIC) {
+    return match apr1_parts(hash) {
+      Some(_) => Ok((user, Hash::Apr1(hash.to_string()))),
+      None => Err(format!("bad apr1 hash for {}", user)),
+    };
+  }
+  let kind = if hash.starts_with("{SHA}") {
+    "SHA-1"
+  } else if hash.len() == 13 && hash.bytes().all(|b| APR1_ALPHABET.contains(&b)) {
+    "crypt"
+  } else {
+    "plaintext"
+  };
+  Err(format!(
+    "user {} has a {} password, which is not accepted; rehash it with htpasswd -B",
+    user, kind
+  ))
+}
+
+/// Splits an apr1 hash into its salt and digest, if it is well formed.
+fn apr1_parts(hash: &str) -> Option<(&str, &str)> {
+  let (salt, digest) = hash.strip_prefix(APR1_MAGIC)?.split_once('$')?;
+  let encoded = |s: &str| s.bytes().all(|b| APR1_ALPHABET.contains(&b));
+  (salt.len() <= 8 && encoded(salt) && digest.len() == 22 && encoded(digest))
+    .then_some((salt, digest))
+}
+
+/// Checks `password` against an apr1 hash, in constant time.
+pub(super) fn verify_apr1(password: &str, hash: &str) -> bool {
+  match apr1_parts(hash) {
+    Some((salt, _)) => crate::fyre::crypto::constant_time_eq(
+      apr1(password.as_bytes(), salt.as_bytes()).as_bytes(),
+      hash.as_bytes(),
+    ),
+    None => false,
+  }
+}
+
+/// Hashes `password` with Apache's variant of the MD5-based `crypt`,
+/// returning the whole `$apr1$salt$digest` string.
+fn apr1(password: &[u8], salt: &[u8]) -> String {
+  let mut alternate = Md5::new();
+  alternate.update(password);
+  alternate.update(salt);
+  alternate.update(password);
+  let alternate = alternate.finalize();
+
+  let mut context = Md5::new();
+  context.update(password);
+  context.update(APR1_MAGIC.as_bytes());
+  context.update(salt);
+  for chunk in password.chunks(16) {
+    context.update(&alternate[..chunk.len()]);
+  }
+  let mut length = password.len();
+  while length > 0 {
+    if length & 1 == 1 {
+      context.update([0]);
+    } else {
+      context.update(&password[..1]);
+    }
+    length >>= 1;
+  }
+  let mut digest = context.finalize();
+
+  // A thousand rounds, to slow down guessing.
+  for round in 0..1000 {
+    let mut context = Md5::new();
+    if round & 1 == 1 {
+      context.update(password);
+    } else {
+      context.update(digest);
+    }
+    if round % 3 != 0 {
+      context.update(salt);
+    }
+    if round % 7 != 0 {
+      context.update(password);
+    }
+    if round & 1 == 1 {
+      context.update(digest);
+    } else {
+      context.update(password);
+    }
+    digest = context.finalize();
+  }
+
+  let mut encoded = String::with_capacity(APR1_MAGIC.len() + salt.len() + 23);
+  encoded.push_str(APR1_MAGIC);
+  encoded.push_str(&String::from_utf8_lossy(salt));
+  encoded.push('$');
+  let groups = [(0, 6, 12), (1, 7, 13), (2, 8, 14), (3, 9, 15), (4, 10, 5)];
+  for (a, b, c) in groups {
+    let value = (u32::from(digest[a]) << 16) | (u32::from(digest[b]) << 8) | u32::from(digest[c]);
+    push_base64(&mut encoded, value, 4);
+  }
+  push_base64(&mut encoded, u32::from(digest[11]), 2);
+  encoded
+}
+
+/// Appends the low `count` six-bit groups of `value`, least significant
+/// first, as `crypt` encodes them.
+fn push_base64(out: &mut String, mut value: u32, count: usize) {
+  for _ in 0..count {
+    out.push(char::from(APR1_ALPHABET[(value & 0x3f) as usize]));
+    value >>= 6;
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::testing::Fixture;
+
+  /// Lines from `htpasswd`, `openssl passwd -apr1`, OpenBSD's bcrypt
+  /// tests, and the argon2-cffi documentation, with the password each was
+  /// made from.
+  const KNOWN: &[(&str, &str)] = &[
+    ("ada:$apr1$r31.....$HqJZimcKQFAMYayBlzkrA/", "myPassword"),
+    ("ada:$apr1$Zf3/.$84c2jrKyod/xE8tsITv3A0", "a much longer password than sixteen"),
+    ("ada:$apr1$12345678$sHuPAw7VA9xjRbJz7zKV7/", ""),
+    ("ada:$2y$05$CCCCCCCCCCCCCCCCCCCCC.E5YPO9kmyuRGyh0XouQYb4YMJKvyOeW", "U*U"),
+    (
+      "ada:$argon2id$v=19$m=65536,t=3,p=4$MIIRqgvgQbgj220jfp0MPA$YfwJSVjtjSU0zzV/P3S9nnQ/USre2wvJMjfCIjrTQbg",
+      "correct horse battery staple",
+    ),
+  ];
+
+  #[test]
+  fn known_hashes_verify() {
+    for (line, password) in KNOWN {
+      let (user, hash) = parse_line(line).unwrap();
+      assert_eq!(user, "ada");
+      assert!(hash.verify(password), "{}", line);
+      assert!(!hash.verify(&format!("{}x", password)), "{}", line);
+    }
+    assert_eq!(
+      apr1(b"myPassword", b"r31....."),
+      "$apr1$r31.....$HqJZimcKQFAMYayBlzkrA/"
+    );
+  }
+
+  #[test]
+  fn weak_and_malformed_lines_are_refused() {
+    for (line, expected) in [
+      ("ada:hunter2", "ada has a plaintext password"),
+      ("ada:abJnggxhB/yWI", "ada has a crypt password"),
+      (
+        "ada:{SHA}W6ph5Mm5Pz8GgiULbPgzG37mj9g=",
+        "ada has a SHA-1 password",
+      ),
+      ("ada:$apr1$r31.....$short", "bad apr1 hash for ada"),
+      ("ada:$2y$05$tooshort", "bad bcrypt hash for ada"),
+      ("ada:$argon2id$v=19$m=65536,t=3,p=4$c29tZXNhbHQ$!!!", "bad argon2 hash for ada"),
+      ("ada", "not a user:hash entry"),
+      (
+        ":$apr1$r31.....$HqJZimcKQFAMYayBlzkrA/",
+        "not a user:hash entry",
+      ),
+    ] {
+      let error = parse_line(line).expect_err(line);
+      assert!(error.contains(expected), "{}: {}", line, error);
+    }
+  }
+
+  #[test]
+  fn refused_lines_are_skipped() {
+    let fixture = Fixture::new("", &[]);
+    let path = fixture.path().join("users.htpasswd");
+    fs::write(
+      &path,
+      "# admins\r\n\
+       ada:hunter2\r\n\
+       \n\
+       bob:$apr1$r31.....$HqJZimcKQFAMYayBlzkrA/\n\
+       cy:abJnggxhB/yWI\n\
+       bob:$apr1$12345678$sHuPAw7VA9xjRbJz7zKV7/\n",
+    )
+    .unwrap();
+    let users = Htpasswd::load(path.clone()).unwrap().users();
+    assert_eq!(users.len(), 1);
+    // The first entry for a user wins.
+    assert!(users["bob"].verify("myPassword"));
+    assert!(!users["bob"].verify(""));
+
+    fs::write(&path, "ada:hunter2\ncy:abJnggxhB/yWI\n").unwrap();
+    let error = Htpasswd::load(path).unwrap_err();
+    assert!(error.ends_with("has no usable entries"), "{}", error);
+  }
+
+  #[test]
+  #[cfg(unix)]
+  fn the_file_is_read_again_on_sighup() {
+    let _hanging_up = locks::lock(&crate::testing::HANGING_UP, "test");
+    let fixture = Fixture::new("", &[]);
+    let path = fixture.path().join("users.htpasswd");
+    fs::write(&path, format!("{}\n", KNOWN[0].0)).unwrap();
+    let file = Htpasswd::load(path.clone()).unwrap();
+    assert!(in_use());
+    assert!(file.users().contains_key("ada"));
+
+    // Rewritten with its old modification time, the file isn't noticed...
+    let modified = modified(&path).unwrap();
+    fs::write(&path, "bob:$apr1$r31.....$HqJZimcKQFAMYayBlzkrA/\n").unwrap();
+    fs::File::options()
+      .write(true)
+      .open(&path)
+      .unwrap()
+      .set_modified(modified)
+      .unwrap();
+    assert!(file.users().contains_key("ada"));
+
+    // ...until the server gets SIGHUP.
+    crate::testing::hang_up();
+    let users = file.users();
+    assert!(users.contains_key("bob") && !users.contains_key("ada"));
+
+    // A file broken afterwards keeps the users read before.
+    fs::write(&path, "bob:hunter2\n").unwrap();
+    crate::testing::hang_up();
+    assert!(file.users()["bob"].verify("myPassword"));
+  }
+}
@@ -0,0 +1,193 @@
+//! # JWT Strategy
+//!
+//! Authenticates a request by the HMAC JSON Web Token in its
+//! `Authorization: Bearer` header, checked as `fyre.jwt.verify` checks one:
+//! the `secret`, and the optional `alg`, `leeway`, `aud`, and `iss`, come
+//! from the strategy's `CONFIG.auth` entry. The token must carry a `sub`
+//! claim, which becomes the request's subject, and its claims are passed to
+//! the handler as `request.auth.claims`.
+
+use mlua::prelude::*;
+use serde_json::Value;
+
+use super::Identity;
+use crate::fyre::jwt::{self, VerifyOptions};
+
+/// A `jwt` strategy.
+pub struct JwtAuth {
+  secret: Vec<u8>,
+  options: VerifyOptions,
+  realm: String,
+}
+
+impl std::fmt::Debug for JwtAuth {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    f.debug_struct("JwtAuth")
+      .field("secret", &"[redacted]")
+      .field("realm", &self.realm)
+      .finish_non_exhaustive()
+  }
+}
+
+impl JwtAuth {
+  /// Reads a `jwt` strategy's table. `what` names it in error messages.
+  ///
+  /// # Errors
+  ///
+  /// This function will return a `LuaError` if `secret` is missing or
+  /// empty, or an option has the wrong type or an unsupported value.
+  pub fn from_lua(lua: &Lua, what: &str, table: &LuaTable) -> LuaResult<JwtAuth> {
+    let secret = table
+      .get::<Option<LuaString>>("secret")?
+      .map(|secret| secret.as_bytes().to_vec())
+      .unwrap_or_default();
+    if secret.is_empty() {
+      return Err(LuaError::external(format!(
+        "{}: jwt needs a secret (is the secret's variable unset?)",
+        what
+      )));
+    }
+    let options = VerifyOptions::from_table(lua, Some(table.clone()))
+      .map_err(|e| LuaError::external(format!("{}: {}", what, e)))?;
+    Ok(JwtAuth {
+      secret,
+      options,
+      realm: super::realm(what, table)?,
+    })
+  }
+
+  /// Checks an `Authorization` header. Returns who the token is for if it
+  /// holds a valid one with a `sub` claim.
+  pub fn check(&self, strategy: &str, authorization: Option<&str>) -> Option<Identity> {
+    let (scheme, token) = authorization?.trim().split_once(' ')?;
+    if !scheme.eq_ignore_ascii_case("Bearer") {
+      return None;
+    }
+    let claims = jwt::verify(token.trim(), &self.secret, &self.options).ok()?;
+    let subject = match claims.get("sub")? {
+      Value::String(sub) => sub.clone(),
+      Value::Number(sub) => sub.to_string(),
+      _ => return None,
+    };
+    Some(Identity {
+      strategy: strategy.to_string(),
+      subject,
+      key_id: None,
+      claims: Some(claims),
+    })
+  }
+
+  /// The `WWW-Authenticate` value sent with a `401`.
+  pub fn challenge(&self) -> String {
+    format!("Bearer realm=\"{}\"", self.realm)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  const SECRET: &str = "jwt-strategy-secret";
+
+  /// Signs `claims`, a Lua table, with `secret`.
+  fn token(lua: &Lua, claims: &str, secret: &str) -> String {
+    lua.globals().set("jwt", jwt::module(lua).unwrap()).unwrap();
+    lua
+      .load(format!("return jwt.sign({}, {:?})", claims, secret))
+      .eval()
+      .unwrap()
+  }
+
+  fn strategy(lua: &Lua, table: &str) -> LuaResult<JwtAuth> {
+    JwtAuth::from_lua(lua, "CONFIG.auth.users", &lua.load(table).eval()?)
+  }
+
+  #[test]
+  fn a_bearer_token_with_a_subject_is_accepted() {
+    let lua = Lua::new();
+    let auth = strategy(
+      &lua,
+      &format!(r#"{{ secret = {:?}, iss = "fyre" }}"#, SECRET),
+    )
+    .unwrap();
+    let valid = token(
+      &lua,
+      r#"{ sub = "ada", iss = "fyre", role = "admin" }"#,
+      SECRET,
+    );
+    let identity = auth
+      .check("users", Some(&format!("Bearer {}", valid)))
+      .unwrap();
+    assert_eq!(identity.strategy, "users");
+    assert_eq!(identity.subject, "ada");
+    assert_eq!(identity.claims.unwrap()["role"], "admin");
+
+    let numeric = token(&lua, r#"{ sub = 42, iss = "fyre" }"#, SECRET);
+    let identity = auth
+      .check("users", Some(&format!("bearer  {} ", numeric)))
+      .unwrap();
+    assert_eq!(identity.subject, "42");
+  }
+
+  #[test]
+  fn other_tokens_are_refused() {
+    let lua = Lua::new();
+    let auth = strategy(
+      &lua,
+      &format!(r#"{{ secret = {:?}, iss = "fyre" }}"#, SECRET),
+    )
+    .unwrap();
+    let valid = token(&lua, r#"{ sub = "ada", iss = "fyre" }"#, SECRET);
+    for authorization in [
+      None,
+      Some(format!("Basic {}", valid)),
+      Some(valid.clone()),
+      Some(format!(
+        "Bearer {}",
+        token(&lua, r#"{ sub = "ada", iss = "fyre" }"#, "other-secret")
+      )),
+      Some(format!(
+        "Bearer {}",
+        token(&lua, r#"{ iss = "fyre" }"#, SECRET)
+      )),
+      Some(format!(
+        "Bearer {}",
+        token(&lua, r#"{ sub = { "ada" }, iss = "fyre" }"#, SECRET)
+      )),
+      Some(format!(
+        "Bearer {}",
+        token(&lua, r#"{ sub = "ada", iss = "evil" }"#, SECRET)
+      )),
+      // Expired in 1970.
+      Some(format!(
+        "Bearer {}",
+        token(&lua, r#"{ sub = "ada", iss = "fyre", exp = 1 }"#, SECRET)
+      )),
+    ] {
+      assert!(
+        auth.check("users", authorization.as_deref()).is_none(),
+        "{:?}",
+        authorization
+      );
+    }
+  }
+
+  #[test]
+  fn a_strategy_needs_a_secret() {
+    let lua = Lua::new();
+    for table in ["{}", r#"{ secret = "" }"#] {
+      let error = strategy(&lua, table).unwrap_err().to_string();
+      assert!(
+        error.contains("CONFIG.auth.users: jwt needs a secret"),
+        "{}",
+        error
+      );
+    }
+    let error = strategy(&lua, r#"{ secret = "s", alg = "none" }"#)
+      .unwrap_err()
+      .to_string();
+    assert!(error.starts_with("CONFIG.auth.users: "), "{}", error);
+    let auth = strategy(&lua, r#"{ secret = "s", realm = "api" }"#).unwrap();
+    assert_eq!(auth.challenge(), "Bearer realm=\"api\"");
+  }
+}
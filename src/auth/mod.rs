@@ -0,0 +1,739 @@
+//! # Authentication
+//!
+//! Protects routes with credentials checked before the handler script
+//! runs. A route declares HTTP Basic authentication with
+//! `router.add(path, script, { auth = { type = "basic", users = {...} } })`,
+//! and `router.protect("/admin/*", {...})` applies the same to every path
+//! under a prefix, static files included.
+//!
+//! Strategies can instead be configured once, by name, in `CONFIG.auth`,
+//! and required by routes with `require_auth`:
+//!
+//! ```lua
+//! CONFIG = {
+//!   auth = {
+//!     jwt = { secret = env("JWT_SECRET"), aud = "api" },
+//!     partners = { type = "api_key", keys = { acme = env("ACME_KEY_SHA256") } },
+//!   },
+//! }
+//! router.add("/api/orders", "orders.lua", { require_auth = { "jwt", "partners" } })
+//! ```
+//!
+//! An entry's `type` is `"basic"` (see `Auth`), `"jwt"` (see `jwt`), or
+//! `"api_key"` (see `api_key`), and defaults to its name. A route listing
+//! several accepts any of them, tried in order.
+//!
+//! A request without valid credentials is answered with `401` and a
+//! `WWW-Authenticate` challenge for each accepted strategy. One with them
+//! runs the handler with `request.auth = { strategy = ..., subject = ...,
+//! claims = ... }`, and the subject (the user name, token `sub`, or key id)
+//! also in `request.user`.
+//!
+//! Passwords are given as bcrypt (`$2b$...`) or argon2 (`$argon2id$...`)
+//! hashes and checked with those libraries. Plaintext passwords are
+//! refused when the configuration is loaded. Instead of `users`, a basic
+//! declaration can name an htpasswd file with `htpasswd` (see `htpasswd`). Both are slow by design, so
+//! every authenticated request spends the hash's cost on a worker thread;
+//! pick a cost to match.
+
+use argon2::{Argon2, PasswordHash, PasswordVerifier};
+use mlua::prelude::*;
+use std::collections::HashMap;
+use std::path::Path;
+use tiny_http::Header;
+
+use crate::client_limit;
+
+pub mod api_key;
+pub mod htpasswd;
+pub mod jwt;
+
+/// The realm sent in the challenge when `realm` is not set.
+pub const DEFAULT_REALM: &str = "fyre";
+
+/// A password hash, in the format it was given in.
+#[derive(Debug)]
+enum Hash {
+  Bcrypt(String),
+  Argon2(String),
+  /// Apache's MD5 `crypt`, only accepted from an htpasswd file.
+  Apr1(String),
+}
+
+impl Hash {
+  /// Reads a hash, checking it parses so a typo fails at startup rather
+  /// than on every login.
+  fn parse(hash: &str) -> Result<Hash, String> {
+    if hash.starts_with("$2") {
+      hash
+        .parse::<bcrypt::HashParts>()
+        .map(|_| Hash::Bcrypt(hash.to_string()))
+        .map_err(|e| format!("bad bcrypt hash: {}", e))
+    } else if hash.starts_with("$argon2") {
+      PasswordHash::new(hash)
+        .map(|_| Hash::Argon2(hash.to_string()))
+        .map_err(|e| format!("bad argon2 hash: {}", e))
+    } else {
+      Err("not a bcrypt or argon2 hash; plaintext passwords are not accepted".to_string())
+    }
+  }
+
+  fn verify(&self, password: &str) -> bool {
+    match self {
+      Hash::Bcrypt(hash) => bcrypt::verify(password, hash).unwrap_or(false),
+      Hash::Argon2(hash) => PasswordHash::new(hash).is_ok_and(|hash| {
+        Argon2::default()
+          .verify_password(password.as_bytes(), &hash)
+          .is_ok()
+      }),
+      Hash::Apr1(hash) => htpasswd::verify_apr1(password, hash),
+    }
+  }
+}
+
+/// Where a basic declaration's users come from.
+#[derive(Debug)]
+enum Users {
+  /// Listed in `users`.
+  Listed(HashMap<String, Hash>),
+  /// Read from the file named by `htpasswd`.
+  File(htpasswd::Htpasswd),
+}
+
+/// The credentials a protected route accepts.
+#[derive(Debug)]
+pub struct Auth {
+  realm: String,
+  users: Users,
+}
+
+impl Auth {
+  /// Reads an `auth` table: `type` (only `"basic"`), `users` mapping names
+  /// to password hashes or `htpasswd` naming a file, resolved against
+  /// `base`, and an optional `realm`. `what` names the declaration in error
+  /// messages.
+  ///
+  /// # Errors
+  ///
+  /// This function will return a `LuaError` if `type` is not `"basic"`,
+  /// neither or both of `users` and `htpasswd` are given, `users` is empty,
+  /// a password is not a bcrypt or argon2 hash, the htpasswd file can't be
+  /// read or has no usable entries, or a field has the wrong type.
+  pub fn from_lua(what: &str, table: &LuaTable, base: &Path) -> LuaResult<Auth> {
+    let kind = table.get::<Option<String>>("type")?;
+    if kind.as_deref() != Some("basic") {
+      return Err(LuaError::external(format!(
+        "{}: auth type must be \"basic\", got {}",
+        what,
+        kind.as_deref().unwrap_or("nothing")
+      )));
+    }
+    Auth::from_fields(what, table, base)
+  }
+
+  /// Reads the `users` or `htpasswd`, and `realm`, of a table already known
+  /// to be for basic authentication.
+  fn from_fields(what: &str, table: &LuaTable, base: &Path) -> LuaResult<Auth> {
+    let realm = realm(what, table)?;
+    if let Some(file) = table.get::<Option<String>>("htpasswd")? {
+      if table.contains_key("users")? {
+        return Err(LuaError::external(format!(
+          "{}: auth can't have both users and htpasswd",
+          what
+        )));
+      }
+      let file = htpasswd::Htpasswd::load(base.join(file))
+        .map_err(|e| LuaError::external(format!("{}: {}", what, e)))?;
+      return Ok(Auth {
+        realm,
+        users: Users::File(file),
+      });
+    }
+    let mut users = HashMap::new();
+    if let Some(entries) = table.get::<Option<LuaTable>>("users")? {
+      for pair in entries.pairs::<String, String>() {
+        let (user, hash) = pair?;
+        let hash = Hash::parse(&hash)
+          .map_err(|e| LuaError::external(format!("{}: user {}: {}", what, user, e)))?;
+        users.insert(user, hash);
+      }
+    }
+    if users.is_empty() {
+      return Err(LuaError::external(format!(
+        "{}: auth needs at least one user (is a password hash variable unset?)",
+        what
+      )));
+    }
+    Ok(Auth {
+      realm,
+      users: Users::Listed(users),
+    })
+  }
+
+  /// Checks an `Authorization` header. Returns the user name if it holds
+  /// valid credentials.
+  pub fn check(&self, authorization: Option<&str>) -> Option<String> {
+    let (user, password) = authorization.and_then(crate::fyre::encoding::parse_basic_auth)?;
+    match &self.users {
+      Users::Listed(users) => verify(users, user, &password),
+      Users::File(file) => verify(&file.users(), user, &password),
+    }
+  }
+
+  /// The `WWW-Authenticate` value sent with a `401`.
+  pub fn challenge(&self) -> String {
+    format!("Basic realm=\"{}\", charset=\"UTF-8\"", self.realm)
+  }
+}
+
+/// Checks `user`'s `password` against `users`, returning the name if it
+/// matches.
+fn verify(users: &HashMap<String, Hash>, user: String, password: &str) -> Option<String> {
+  match users.get(&user) {
+    Some(hash) => hash.verify(password).then_some(user),
+    None => {
+      // Spend the same time on an unknown name as on a wrong password,
+      // so the response time doesn't tell which names exist.
+      if let Some(hash) = users.values().next() {
+        hash.verify(password);
+      }
+      None
+    }
+  }
+}
+
+/// Reads a table's `realm`, for the challenge.
+fn realm(what: &str, table: &LuaTable) -> LuaResult<String> {
+  let realm = table
+    .get::<Option<String>>("realm")?
+    .unwrap_or_else(|| DEFAULT_REALM.to_string());
+  if realm.contains(['"', '\\', '\r', '\n']) {
+    return Err(LuaError::external(format!(
+      "{}: auth realm can't contain quotes, backslashes, or line breaks",
+      what
+    )));
+  }
+  Ok(realm)
+}
+
+/// Who a request authenticated as.
+#[derive(Debug)]
+pub struct Identity {
+  /// The name of the strategy that accepted it, or `"basic"` for an `auth`
+  /// route option or `router.protect` rule.
+  pub strategy: String,
+  /// The user name, the token's `sub`, or the key's id.
+  pub subject: String,
+  /// The key's id, for an API key.
+  pub key_id: Option<String>,
+  /// The token's claims, for a JWT.
+  pub claims: Option<serde_json::Map<String, serde_json::Value>>,
+}
+
+impl Identity {
+  /// Builds the `request.auth` table.
+  pub fn to_lua(&self, lua: &Lua) -> LuaResult<LuaTable> {
+    let table = lua.create_table()?;
+    table.set("strategy", self.strategy.as_str())?;
+    table.set("subject", self.subject.as_str())?;
+    table.set("key_id", self.key_id.as_deref())?;
+    if let Some(claims) = &self.claims {
+      let claims = serde_json::Value::Object(claims.clone());
+      table.set("claims", crate::fyre::json::from_json(lua, &claims)?)?;
+    }
+    Ok(table)
+  }
+}
+
+/// A way of authenticating requests, configured by name in `CONFIG.auth`.
+#[derive(Debug)]
+pub enum Strategy {
+  Basic(Auth),
+  Jwt(jwt::JwtAuth),
+  ApiKey(api_key::ApiKeys),
+}
+
+impl Strategy {
+  /// Reads the `CONFIG.auth` entry `name`. An `api_key` entry without
+  /// `keys` takes them from `api_keys`, `CONFIG.api_keys`, and a `basic`
+  /// entry's `htpasswd` is resolved against `base`.
+  ///
+  /// # Errors
+  ///
+  /// This function will return a `LuaError` if `type` (or, without one,
+  /// the name) is not `"basic"`, `"jwt"`, or `"api_key"`, or the entry is
+  /// not valid for its type.
+  pub fn from_lua(
+    lua: &Lua,
+    name: &str,
+    table: &LuaTable,
+    api_keys: Option<&LuaTable>,
+    base: &Path,
+  ) -> LuaResult<Strategy> {
+    let what = format!("CONFIG.auth.{}", name);
+    let kind = table
+      .get::<Option<String>>("type")?
+      .unwrap_or_else(|| name.to_string());
+    match kind.as_str() {
+      "basic" => Ok(Strategy::Basic(Auth::from_fields(&what, table, base)?)),
+      "jwt" => Ok(Strategy::Jwt(jwt::JwtAuth::from_lua(lua, &what, table)?)),
+      "api_key" => Ok(Strategy::ApiKey(api_key::ApiKeys::from_lua(
+        &what, table, api_keys,
+      )?)),
+      other => Err(LuaError::external(format!(
+        "{}: type must be \"basic\", \"jwt\", or \"api_key\", got \"{}\"",
+        what, other
+      ))),
+    }
+  }
+
+  /// Checks a request's headers against the strategy named `name`.
+  pub fn authenticate(&self, name: &str, headers: &[Header]) -> Option<Identity> {
+    match self {
+      Strategy::Basic(auth) => Some(Identity {
+        strategy: name.to_string(),
+        subject: auth.check(authorization(headers))?,
+        key_id: None,
+        claims: None,
+      }),
+      Strategy::Jwt(auth) => auth.check(name, authorization(headers)),
+      Strategy::ApiKey(keys) => keys.check(name, headers),
+    }
+  }
+
+  /// The `WWW-Authenticate` value sent with a `401`.
+  pub fn challenge(&self) -> String {
+    match self {
+      Strategy::Basic(auth) => auth.challenge(),
+      Strategy::Jwt(auth) => auth.challenge(),
+      Strategy::ApiKey(keys) => keys.challenge(),
+    }
+  }
+}
+
+/// Reads `CONFIG.auth`, a table of strategies by name, and
+/// `CONFIG.api_keys`. With keys but no `api_key` entry, an `api_key`
+/// strategy with the default settings is added for them.
+///
+/// # Errors
+///
+/// This function will return a `LuaError` if an entry is not a table or
+/// not a valid strategy, or a key hash is not valid.
+pub fn strategies_from_lua(
+  lua: &Lua,
+  table: Option<&LuaTable>,
+  api_keys: Option<&LuaTable>,
+  base: &Path,
+) -> LuaResult<HashMap<String, Strategy>> {
+  let mut strategies = HashMap::new();
+  if let Some(table) = table {
+    for pair in table.pairs::<String, LuaTable>() {
+      let (name, entry) = pair?;
+      let strategy = Strategy::from_lua(lua, &name, &entry, api_keys, base)?;
+      strategies.insert(name, strategy);
+    }
+  }
+  if api_keys.is_some() && !strategies.contains_key("api_key") {
+    let defaults = lua.create_table()?;
+    let keys = api_key::ApiKeys::from_lua("CONFIG.auth.api_key", &defaults, api_keys)?;
+    strategies.insert("api_key".to_string(), Strategy::ApiKey(keys));
+  }
+  Ok(strategies)
+}
+
+/// What a request for a route must authenticate with.
+pub enum Required<'a> {
+  /// An `auth` route option or `router.protect` rule.
+  Basic(&'a Auth),
+  /// Any of a route's `require_auth` strategies, with their names.
+  AnyOf(Vec<(&'a str, &'a Strategy)>),
+}
+
+impl Required<'_> {
+  /// Checks a request's headers. Returns who it authenticated as, if it
+  /// did.
+  pub fn authenticate(&self, headers: &[Header]) -> Option<Identity> {
+    match self {
+      Required::Basic(auth) => Some(Identity {
+        strategy: "basic".to_string(),
+        subject: auth.check(authorization(headers))?,
+        key_id: None,
+        claims: None,
+      }),
+      Required::AnyOf(strategies) => strategies
+        .iter()
+        .find_map(|(name, strategy)| strategy.authenticate(name, headers)),
+    }
+  }
+
+  /// The limit on failed attempts, if an API key is accepted: the first
+  /// `api_key` strategy's `failure_limit`.
+  pub fn failure_limit(&self) -> Option<&client_limit::RateLimit> {
+    let Required::AnyOf(strategies) = self else {
+      return None;
+    };
+    strategies.iter().find_map(|(_, strategy)| match strategy {
+      Strategy::ApiKey(keys) => Some(&keys.failure_limit),
+      _ => None,
+    })
+  }
+
+  /// The `WWW-Authenticate` values sent with a `401`, one per strategy.
+  pub fn challenges(&self) -> Vec<String> {
+    match self {
+      Required::Basic(auth) => vec![auth.challenge()],
+      Required::AnyOf(strategies) => strategies
+        .iter()
+        .map(|(_, strategy)| strategy.challenge())
+        .collect(),
+    }
+  }
+}
+
+/// A request's `Authorization` header.
+fn authorization(headers: &[Header]) -> Option<&str> {
+  headers
+    .iter()
+    .find(|h| h.field.equiv("Authorization"))
+    .map(|h| h.value.as_str())
+}
+
+/// A `router.protect` declaration.
+#[derive(Debug)]
+pub struct Protected {
+  /// The path, or with `prefix` the path its matches are under.
+  pub path: String,
+  /// Whether the pattern ended in `/*`.
+  pub prefix: bool,
+  pub auth: Auth,
+}
+
+impl Protected {
+  /// Reads `router.protect(pattern, auth)`. The pattern is an exact path or
+  /// one ending in `/*`, which matches the path before it and everything
+  /// under it. An `htpasswd` file is resolved against `base`.
+  ///
+  /// # Errors
+  ///
+  /// This function will return a `LuaError` if the pattern doesn't start
+  /// with `/`, has a `*` anywhere but a trailing `/*`, or `auth` is not a
+  /// valid `auth` table.
+  pub fn from_lua(pattern: &str, auth: &LuaTable, base: &Path) -> LuaResult<Protected> {
+    let what = format!("router.protect('{}')", pattern);
+    let (path, prefix) = match pattern.strip_suffix("/*") {
+      Some(path) => (path, true),
+      None => (pattern, false),
+    };
+    if !pattern.starts_with('/') || path.contains('*') {
+      return Err(LuaError::external(format!(
+        "{}: the pattern must be a path starting with '/', optionally ending in '/*'",
+        what
+      )));
+    }
+    // Declared paths are normalized like request paths, so `/admin/` and
+    // `/admin` are the same rule; the root prefix becomes "".
+    let mut path = normalize(path);
+    if prefix {
+      path.truncate(path.trim_end_matches('/').len());
+    }
+    Ok(Protected {
+      path,
+      prefix,
+      auth: Auth::from_lua(&what, auth, base)?,
+    })
+  }
+
+  /// The pattern as declared.
+  pub fn pattern(&self) -> String {
+    if self.prefix {
+      format!("{}/*", self.path)
+    } else {
+      self.path.clone()
+    }
+  }
+}
+
+/// Reduces a request URL to the path its protection is decided on: the
+/// query is dropped, escapes are decoded, and empty and `.` segments are
+/// removed, so `/%61dmin//x` is covered by a rule for `/admin/*` just as it
+/// would be served from under `/admin`.
+pub fn normalize(url: &str) -> String {
+  let path = url.split(['?', '#']).next().unwrap_or(url);
+  let decoded = percent_encoding::percent_decode_str(path).decode_utf8_lossy();
+  let segments: Vec<&str> = decoded
+    .split('/')
+    .filter(|segment| !segment.is_empty() && *segment != ".")
+    .collect();
+  format!("/{}", segments.join("/"))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::testing::Fixture;
+  use sha2::{Digest, Sha256};
+  use std::io::{Read, Write};
+  use std::net::TcpStream;
+
+  /// The hex SHA-256 of `key`, as `CONFIG.api_keys` holds it.
+  fn key_hash(key: &str) -> String {
+    hex::encode(Sha256::digest(key.as_bytes()))
+  }
+
+  /// Reads the `CONFIG.auth` table `auth` and `CONFIG.api_keys` table
+  /// `api_keys`, with `HASH` set to a bcrypt hash of `"hunter2"` and `KEY`
+  /// to the hash of `"key-acme"`.
+  fn load(auth: &str, api_keys: Option<&str>) -> LuaResult<HashMap<String, Strategy>> {
+    let lua = Lua::new();
+    lua
+      .globals()
+      .set("HASH", bcrypt::hash("hunter2", 4).unwrap())?;
+    lua.globals().set("KEY", key_hash("key-acme"))?;
+    let auth: LuaTable = lua.load(auth).eval()?;
+    let api_keys: Option<LuaTable> = api_keys.map(|keys| lua.load(keys).eval()).transpose()?;
+    strategies_from_lua(&lua, Some(&auth), api_keys.as_ref(), Path::new("."))
+  }
+
+  fn header(name: &str, value: &str) -> Header {
+    Header::from_bytes(name.as_bytes(), value.as_bytes()).unwrap()
+  }
+
+  fn basic(user: &str, password: &str) -> Header {
+    let credentials = format!("{}:{}", user, password);
+    let value = format!(
+      "Basic {}",
+      crate::fyre::encoding::base64_encode(credentials.as_bytes())
+    );
+    header("Authorization", &value)
+  }
+
+  /// A token for `claims`, a Lua table, signed with `secret`.
+  fn token(claims: &str, secret: &str) -> Header {
+    let lua = Lua::new();
+    let sign: LuaFunction = crate::fyre::jwt::module(&lua).unwrap().get("sign").unwrap();
+    let claims: LuaTable = lua.load(claims).eval().unwrap();
+    let token: String = sign.call((claims, secret)).unwrap();
+    header("Authorization", &format!("Bearer {}", token))
+  }
+
+  #[test]
+  fn basic_accepts_a_listed_user() {
+    let strategies = load(
+      r#"{ admins = { type = "basic", users = { ada = HASH } } }"#,
+      None,
+    )
+    .unwrap();
+    let admins = &strategies["admins"];
+    let identity = admins
+      .authenticate("admins", &[basic("ada", "hunter2")])
+      .unwrap();
+    assert_eq!(identity.strategy, "admins");
+    assert_eq!(identity.subject, "ada");
+    assert!(identity.claims.is_none());
+    assert!(admins
+      .authenticate("admins", &[basic("ada", "hunter3")])
+      .is_none());
+    assert!(admins
+      .authenticate("admins", &[basic("bob", "hunter2")])
+      .is_none());
+    assert!(admins.authenticate("admins", &[]).is_none());
+    assert_eq!(
+      admins.challenge(),
+      "Basic realm=\"fyre\", charset=\"UTF-8\""
+    );
+  }
+
+  #[test]
+  fn jwt_accepts_a_signed_token_with_a_subject() {
+    let strategies = load(
+      r#"{ jwt = { secret = "s3cret", aud = "api", realm = "orders" } }"#,
+      None,
+    )
+    .unwrap();
+    let jwt = &strategies["jwt"];
+    let identity = jwt
+      .authenticate(
+        "jwt",
+        &[token(
+          r#"{ sub = "u-7", aud = "api", role = "admin" }"#,
+          "s3cret",
+        )],
+      )
+      .unwrap();
+    assert_eq!(identity.strategy, "jwt");
+    assert_eq!(identity.subject, "u-7");
+    assert_eq!(identity.claims.unwrap()["role"], "admin");
+
+    for refused in [
+      token(r#"{ sub = "u-7", aud = "api" }"#, "wrong"),
+      token(r#"{ sub = "u-7", aud = "web" }"#, "s3cret"),
+      token(r#"{ aud = "api" }"#, "s3cret"),
+      basic("u-7", "s3cret"),
+    ] {
+      assert!(jwt.authenticate("jwt", &[refused]).is_none());
+    }
+    assert_eq!(jwt.challenge(), "Bearer realm=\"orders\"");
+  }
+
+  #[test]
+  fn api_key_accepts_a_listed_key() {
+    // Keys from `CONFIG.api_keys`, for the strategy added for them.
+    let strategies = load("{}", Some("{ acme = KEY }")).unwrap();
+    let keys = &strategies["api_key"];
+    let identity = keys
+      .authenticate("api_key", &[header("x-api-key", "key-acme")])
+      .unwrap();
+    assert_eq!(identity.subject, "acme");
+    assert_eq!(identity.key_id.as_deref(), Some("acme"));
+    assert!(keys
+      .authenticate("api_key", &[header("X-Api-Key", "key-other")])
+      .is_none());
+    assert!(keys
+      .authenticate("api_key", &[header("X-Key", "key-acme")])
+      .is_none());
+    assert_eq!(keys.challenge(), "ApiKey header=\"X-Api-Key\"");
+
+    // A strategy's own keys and header.
+    let own = load(
+      r#"{ partners = { type = "api_key", header = "X-Partner-Key", keys = { acme = KEY } } }"#,
+      None,
+    )
+    .unwrap();
+    let partners = &own["partners"];
+    assert!(!own.contains_key("api_key"));
+    assert!(partners
+      .authenticate("partners", &[header("X-Partner-Key", "key-acme")])
+      .is_some());
+    assert!(partners
+      .authenticate("partners", &[header("X-Api-Key", "key-acme")])
+      .is_none());
+  }
+
+  #[test]
+  fn a_route_accepts_any_of_its_strategies() {
+    let strategies = load(
+      r#"{
+        jwt = { secret = "s3cret" },
+        partners = { type = "api_key", keys = { acme = KEY } },
+      }"#,
+      None,
+    )
+    .unwrap();
+    let required = Required::AnyOf(
+      ["jwt", "partners"]
+        .into_iter()
+        .map(|name| (name, &strategies[name]))
+        .collect(),
+    );
+    let by_token = required
+      .authenticate(&[token(r#"{ sub = "u-7" }"#, "s3cret")])
+      .unwrap();
+    assert_eq!(
+      (by_token.strategy.as_str(), by_token.subject.as_str()),
+      ("jwt", "u-7")
+    );
+    let by_key = required
+      .authenticate(&[header("X-Api-Key", "key-acme")])
+      .unwrap();
+    assert_eq!(
+      (by_key.strategy.as_str(), by_key.subject.as_str()),
+      ("partners", "acme")
+    );
+    assert!(required
+      .authenticate(&[token(r#"{ sub = "u-7" }"#, "wrong")])
+      .is_none());
+    assert_eq!(
+      required.challenges(),
+      ["Bearer realm=\"fyre\"", "ApiKey header=\"X-Api-Key\""]
+    );
+    assert!(required.failure_limit().is_some());
+  }
+
+  #[test]
+  fn bad_strategies_are_refused() {
+    for (auth, expected) in [
+      (
+        r#"{ ldap = {} }"#,
+        "type must be \"basic\", \"jwt\", or \"api_key\"",
+      ),
+      (r#"{ jwt = { secret = "" } }"#, "jwt needs a secret"),
+      (r#"{ api_key = {} }"#, "api_key needs at least one key"),
+      (
+        r#"{ api_key = { keys = { acme = "abc" } } }"#,
+        "64 hex digits",
+      ),
+      (
+        r#"{ basic = { users = { ada = "hunter2" } } }"#,
+        "plaintext passwords are not accepted",
+      ),
+    ] {
+      let error = load(auth, None).unwrap_err().to_string();
+      assert!(error.contains(expected), "{}: {}", auth, error);
+    }
+  }
+
+  #[test]
+  fn require_auth_answers_401_with_a_challenge_per_strategy() {
+    let fixture = Fixture::new(
+      &format!(
+        r#"
+          CONFIG = {{
+            auth = {{
+              jwt = {{ secret = "s3cret" }},
+              partners = {{ type = "api_key", keys = {{ acme = "{}" }} }},
+            }},
+          }}
+          router.add("/orders", "orders.lua", {{ require_auth = {{ "jwt", "partners" }} }})
+        "#,
+        key_hash("key-acme")
+      ),
+      &[(
+        "orders.lua",
+        r#"
+          return {
+            handler = function(request, response)
+              response.body = request.auth.strategy .. " " .. request.user
+            end,
+          }
+        "#,
+      )],
+    );
+    let server = fixture
+      .builder()
+      .addr("127.0.0.1:0")
+      .workers(1)
+      .load()
+      .unwrap();
+    let addr = server.serve().unwrap().remove(0);
+    let get = |headers: &str| {
+      let mut stream = TcpStream::connect(&addr).unwrap();
+      write!(
+        stream,
+        "GET /orders HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n{}\r\n",
+        headers
+      )
+      .unwrap();
+      let mut response = String::new();
+      stream.read_to_string(&mut response).unwrap();
+      response
+    };
+
+    // Header names are lowercased by hyper with the `async` feature.
+    let refused = get("").to_ascii_lowercase();
+    assert!(refused.starts_with("http/1.1 401"), "{}", refused);
+    assert!(
+      refused.contains("www-authenticate: bearer realm=\"fyre\"\r\n"),
+      "{}",
+      refused
+    );
+    assert!(
+      refused.contains("www-authenticate: apikey header=\"x-api-key\"\r\n"),
+      "{}",
+      refused
+    );
+    let accepted = get("X-Api-Key: key-acme\r\n");
+    assert!(accepted.starts_with("HTTP/1.1 200"), "{}", accepted);
+    assert!(accepted.ends_with("\r\n\r\npartners acme"), "{}", accepted);
+    server.shutdown();
+  }
+}
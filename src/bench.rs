@@ -0,0 +1,306 @@
+//! # `fyre bench`
+//!
+//! A small load generator for tuning `CONFIG.workers` and the caching settings
+//! without installing anything else on the box:
+//!
+//! ```text
+//! fyre bench http://localhost:8000/api/users --connections 64 --duration 10s
+//! fyre bench http://localhost:8000/orders -m POST --body order.json \
+//!   --headers headers.txt --max-error-rate 0.01
+//! ```
+//!
+//! Each connection is a thread with its own `ureq` agent (the client behind
+//! `fyre.http`), so it keeps one keep-alive connection open and sends
+//! requests back to back until the duration is up. The report gives
+//! requests per second, latency percentiles, and the non-2xx responses and
+//! failed requests. With `--max-error-rate`, the command fails when the
+//! share of non-2xx and failed requests is above that fraction.
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::Read;
+use std::time::{Duration, Instant};
+
+use crate::schedule::parse_interval;
+
+const DEFAULT_CONNECTIONS: usize = 16;
+/// How long one request may take before it counts as failed.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// The `fyre bench` command line.
+#[derive(Debug, clap::Args)]
+pub struct BenchArgs {
+  /// The URL to request.
+  url: String,
+  /// The number of connections kept busy at once.
+  #[arg(short, long, default_value_t = DEFAULT_CONNECTIONS, value_parser = parse_connections)]
+  connections: usize,
+  /// How long to run, e.g. "10s" or "1m".
+  #[arg(short, long, default_value = "10s", value_parser = parse_interval)]
+  duration: Duration,
+  /// The request method.
+  #[arg(short, long, default_value = "GET")]
+  method: String,
+  /// A file whose contents are sent as the request body.
+  #[arg(long, value_name = "FILE")]
+  body: Option<String>,
+  /// A file of "Name: value" header lines to send.
+  #[arg(long, value_name = "FILE")]
+  headers: Option<String>,
+  /// A header to send, as "Name: value"; may be repeated.
+  #[arg(short = 'H', long = "header", value_name = "HEADER", value_parser = parse_header)]
+  header: Vec<(String, String)>,
+  /// Fail when the share of non-2xx and failed requests is above this
+  /// fraction.
+  #[arg(long, value_name = "RATE", value_parser = parse_error_rate)]
+  max_error_rate: Option<f64>,
+}
+
+/// The benchmark settings, with the body and header files read.
+struct Options {
+  url: String,
+  connections: usize,
+  duration: Duration,
+  method: String,
+  headers: Vec<(String, String)>,
+  body: Vec<u8>,
+  max_error_rate: Option<f64>,
+}
+
+/// What one connection thread saw.
+#[derive(Default)]
+struct Results {
+  /// The latency of every completed request, in microseconds.
+  latencies: Vec<u64>,
+  /// Responses by status code.
+  statuses: BTreeMap<u16, u64>,
+  /// Requests that got no response, by error message.
+  errors: BTreeMap<String, u64>,
+}
+
+impl Results {
+  fn merge(&mut self, other: Results) {
+    self.latencies.extend(other.latencies);
+    for (status, count) in other.statuses {
+      *self.statuses.entry(status).or_default() += count;
+    }
+    for (error, count) in other.errors {
+      *self.errors.entry(error).or_default() += count;
+    }
+  }
+}
+
+/// Runs `fyre bench` with the arguments after `bench`.
+///
+/// # Errors
+///
+/// This function will return an error if the arguments are invalid, a body
+/// or headers file can't be read, or the error rate is above
+/// `--max-error-rate`.
+pub fn run(args: BenchArgs) -> Result<(), Box<dyn std::error::Error>> {
+  let options = Options::from_args(args)?;
+
+  println!(
+    "Running {} {} for {}s over {} connection(s)",
+    options.method,
+    options.url,
+    options.duration.as_secs(),
+    options.connections
+  );
+
+  let started = Instant::now();
+  let deadline = started + options.duration;
+  let results = std::thread::scope(|scope| {
+    let handles: Vec<_> = (0..options.connections)
+      .map(|_| scope.spawn(|| drive(&options, deadline)))
+      .collect();
+    let mut results = Results::default();
+    for handle in handles {
+      if let Ok(thread_results) = handle.join() {
+        results.merge(thread_results);
+      }
+    }
+    results
+  });
+  let elapsed = started.elapsed();
+
+  report(&results, elapsed);
+
+  let total = results.latencies.len() as u64 + results.errors.values().sum::<u64>();
+  let failed = results
+    .statuses
+    .iter()
+    .filter(|(status, _)| !(200..300).contains(*status))
+    .map(|(_, count)| count)
+    .sum::<u64>()
+    + results.errors.values().sum::<u64>();
+  let error_rate = if total == 0 {
+    1.0
+  } else {
+    failed as f64 / total as f64
+  };
+  if let Some(max) = options.max_error_rate {
+    if error_rate > max {
+      return Err(
+        format!(
+          "error rate {:.2}% is above --max-error-rate {:.2}%",
+          error_rate * 100.0,
+          max * 100.0
+        )
+        .into(),
+      );
+    }
+  }
+  Ok(())
+}
+
+impl Options {
+  /// Reads the files named in `args` and checks the URL.
+  fn from_args(args: BenchArgs) -> Result<Options, String> {
+    let mut headers = Vec::new();
+    if let Some(path) = &args.headers {
+      let text = fs::read_to_string(path)
+        .map_err(|e| format!("Failed to read headers file {}: {}", path, e))?;
+      for line in text.lines().filter(|line| !line.trim().is_empty()) {
+        headers.push(parse_header(line)?);
+      }
+    }
+    headers.extend(args.header);
+    let body = match &args.body {
+      Some(path) => {
+        fs::read(path).map_err(|e| format!("Failed to read body file {}: {}", path, e))?
+      }
+      None => Vec::new(),
+    };
+
+    let parsed =
+      url::Url::parse(&args.url).map_err(|e| format!("invalid url '{}': {}", args.url, e))?;
+    if parsed.scheme() != "http" && parsed.scheme() != "https" {
+      return Err(format!("unsupported url scheme: {}", parsed.scheme()));
+    }
+    Ok(Options {
+      url: args.url,
+      connections: args.connections,
+      duration: args.duration,
+      method: args.method.to_ascii_uppercase(),
+      headers,
+      body,
+      max_error_rate: args.max_error_rate,
+    })
+  }
+}
+
+fn parse_connections(value: &str) -> Result<usize, String> {
+  value
+    .parse()
+    .ok()
+    .filter(|&n| n > 0)
+    .ok_or_else(|| "must be a positive integer".to_string())
+}
+
+fn parse_error_rate(value: &str) -> Result<f64, String> {
+  value
+    .parse()
+    .ok()
+    .filter(|rate: &f64| (0.0..=1.0).contains(rate))
+    .ok_or_else(|| "must be between 0 and 1".to_string())
+}
+
+/// Parses a `Name: value` header line.
+fn parse_header(line: &str) -> Result<(String, String), String> {
+  let (name, value) = line
+    .split_once(':')
+    .ok_or_else(|| format!("invalid header '{}' (use 'Name: value')", line))?;
+  Ok((name.trim().to_string(), value.trim().to_string()))
+}
+
+/// Sends requests on one keep-alive connection until `deadline`.
+fn drive(options: &Options, deadline: Instant) -> Results {
+  let agent = ureq::AgentBuilder::new()
+    .timeout(REQUEST_TIMEOUT)
+    .max_idle_connections_per_host(1)
+    .redirects(0)
+    .build();
+  let mut results = Results::default();
+  let mut sink = Vec::new();
+
+  while Instant::now() < deadline {
+    let mut request = agent.request(&options.method, &options.url);
+    for (name, value) in &options.headers {
+      request = request.set(name, value);
+    }
+
+    let started = Instant::now();
+    let result = if options.body.is_empty() {
+      request.call()
+    } else {
+      request.send_bytes(&options.body)
+    };
+    let response = match result {
+      Ok(response) | Err(ureq::Error::Status(_, response)) => response,
+      Err(e) => {
+        *results.errors.entry(e.to_string()).or_default() += 1;
+        continue;
+      }
+    };
+    let status = response.status();
+    // The body has to be read to the end for the connection to be reused.
+    sink.clear();
+    if let Err(e) = response.into_reader().read_to_end(&mut sink) {
+      *results.errors.entry(e.to_string()).or_default() += 1;
+      continue;
+    }
+    results.latencies.push(started.elapsed().as_micros() as u64);
+    *results.statuses.entry(status).or_default() += 1;
+  }
+  results
+}
+
+fn report(results: &Results, elapsed: Duration) {
+  let completed = results.latencies.len();
+  let seconds = elapsed.as_secs_f64();
+  println!();
+  println!("Requests:      {}", completed);
+  println!("Duration:      {:.2}s", seconds);
+  println!("Requests/sec:  {:.1}", completed as f64 / seconds);
+
+  let mut latencies = results.latencies.clone();
+  latencies.sort_unstable();
+  if !latencies.is_empty() {
+    let percentile = |p: f64| {
+      let index = ((latencies.len() as f64 * p).ceil() as usize).clamp(1, latencies.len()) - 1;
+      format_latency(latencies[index])
+    };
+    println!("Latency:");
+    println!("  p50          {}", percentile(0.50));
+    println!("  p90          {}", percentile(0.90));
+    println!("  p99          {}", percentile(0.99));
+    println!(
+      "  max          {}",
+      format_latency(latencies[latencies.len() - 1])
+    );
+  }
+
+  let non_2xx: Vec<_> = results
+    .statuses
+    .iter()
+    .filter(|(status, _)| !(200..300).contains(*status))
+    .collect();
+  if !non_2xx.is_empty() {
+    println!("Non-2xx responses:");
+    for (status, count) in non_2xx {
+      println!("  {}          {}", status, count);
+    }
+  }
+  if !results.errors.is_empty() {
+    println!("Failed requests:");
+    for (error, count) in &results.errors {
+      println!("  {}  {}", count, error);
+    }
+  }
+}
+
+/// Formats microseconds as milliseconds.
+fn format_latency(micros: u64) -> String {
+  format!("{:.2}ms", micros as f64 / 1000.0)
+}
@@ -0,0 +1,448 @@
+//! # Request Bodies
+//!
+//! Reads request bodies for the handler pipeline. A body up to
+//! `CONFIG.body.spill_bytes` is kept in memory and passed to Lua as
+//! `request.body`. A larger one (by its `Content-Length`, or by the bytes
+//! read so far when it is chunked) is streamed into a temporary file
+//! instead, so concurrent uploads don't each hold a whole body in memory.
+//! Its path is `request.body_path`, and handlers read it in pieces with
+//! `request.read_body()`. The file is deleted when the `Body` is dropped at
+//! the end of the request, including when the handler fails.
+//!
+//! A body larger than `CONFIG.limits.body` is refused: by its
+//! `Content-Length` before any of it is read, or once a chunked one gets
+//! past the limit, with the part written so far deleted. `request.json()`
+//! and `request.validate()` load a spilled body back into memory, so they
+//! refuse one over `MAX_LOAD_BYTES`.
+//!
+//! A large `response.body` goes the other way without a copy: rather than
+//! copying the Lua string into a buffer, which holds the body in memory
+//! twice, the response reads it from the string in chunks as it is written.
+
+use mlua::prelude::*;
+use std::cell::RefCell;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+
+/// The largest body kept in memory when `CONFIG.body.spill_bytes` is not set.
+pub const DEFAULT_SPILL_BYTES: u64 = 1024 * 1024;
+/// The largest body accepted when `CONFIG.limits.body` is not set.
+pub const DEFAULT_MAX_BYTES: u64 = 100 * 1024 * 1024;
+/// The largest body `BodySource::load` reads into memory.
+pub const MAX_LOAD_BYTES: u64 = 16 * 1024 * 1024;
+/// The chunk size `request.read_body()` uses when none is given.
+const DEFAULT_CHUNK_BYTES: usize = 64 * 1024;
+/// The smallest `response.body` sent straight from the Lua string; smaller
+/// ones are copied out, which frees the string sooner.
+pub const STREAM_RESPONSE_BYTES: usize = 256 * 1024;
+
+/// Where request bodies go once they are too large for memory, and the
+/// largest accepted.
+#[derive(Debug, Clone)]
+pub struct SpillOptions {
+  pub threshold: u64,
+  pub dir: PathBuf,
+  pub max: u64,
+}
+
+impl Default for SpillOptions {
+  fn default() -> Self {
+    SpillOptions {
+      threshold: DEFAULT_SPILL_BYTES,
+      dir: std::env::temp_dir(),
+      max: DEFAULT_MAX_BYTES,
+    }
+  }
+}
+
+/// A temporary file holding a request body, deleted when dropped.
+pub struct TempFile {
+  path: PathBuf,
+}
+
+impl TempFile {
+  /// Creates an empty file that only the server's user can read.
+  fn create(dir: &Path) -> io::Result<(TempFile, File)> {
+    let path = dir.join(format!("fyre-body-{}", uuid::Uuid::new_v4().simple()));
+    let mut options = OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    {
+      use std::os::unix::fs::OpenOptionsExt;
+      options.mode(0o600);
+    }
+    let file = options.open(&path)?;
+    Ok((TempFile { path }, file))
+  }
+}
+
+impl Drop for TempFile {
+  fn drop(&mut self) {
+    if let Err(e) = fs::remove_file(&self.path) {
+      warn!(
+        "Failed to delete request body file {}: {}",
+        self.path.display(),
+        e
+      );
+    }
+  }
+}
+
+/// A request body, in memory or spilled to a temporary file.
+pub enum Body {
+  Memory(Vec<u8>),
+  File { file: TempFile, len: u64 },
+}
+
+impl Body {
+  /// Reads the whole body from `reader`. `length` is the `Content-Length`,
+  /// if the request had one.
+  ///
+  /// # Errors
+  ///
+  /// This function will return an error if the body can't be read or the
+  /// temporary file can't be written, or one of kind `FileTooLarge` if the
+  /// body is larger than `options.max`.
+  pub fn read(
+    reader: &mut dyn Read,
+    length: Option<u64>,
+    options: &SpillOptions,
+  ) -> io::Result<Body> {
+    let too_large = || {
+      io::Error::new(
+        io::ErrorKind::FileTooLarge,
+        format!("the body is larger than {} bytes", options.max),
+      )
+    };
+    if length.is_some_and(|length| length > options.max) {
+      return Err(too_large());
+    }
+
+    let mut buffer = Vec::new();
+    if length.is_none_or(|length| length <= options.threshold) {
+      Read::take(&mut *reader, options.threshold + 1).read_to_end(&mut buffer)?;
+      if buffer.len() as u64 > options.max {
+        return Err(too_large());
+      }
+      if buffer.len() as u64 <= options.threshold {
+        return Ok(Body::Memory(buffer));
+      }
+    }
+
+    // Dropping `temp` on an error deletes what was written.
+    let (temp, mut file) = TempFile::create(&options.dir)?;
+    file.write_all(&buffer)?;
+    let left = options.max - buffer.len() as u64;
+    let len = buffer.len() as u64 + io::copy(&mut Read::take(reader, left + 1), &mut file)?;
+    if len > options.max {
+      return Err(too_large());
+    }
+    file.flush()?;
+    Ok(Body::File { file: temp, len })
+  }
+
+  /// Returns the body size in bytes.
+  pub fn size(&self) -> u64 {
+    match self {
+      Body::Memory(bytes) => bytes.len() as u64,
+      Body::File { len, .. } => *len,
+    }
+  }
+
+  /// Returns the path of the temporary file, if the body was spilled.
+  pub fn path(&self) -> Option<&Path> {
+    match self {
+      Body::Memory(_) => None,
+      Body::File { file, .. } => Some(&file.path),
+    }
+  }
+}
+
+/// The body as seen from Lua: the in-memory string or the file's path.
+#[derive(Clone)]
+pub enum BodySource {
+  Memory(LuaString),
+  File(PathBuf),
+}
+
+impl BodySource {
+  /// Returns the whole body as a Lua string, reading the file if the body
+  /// was spilled, or `None` if it is larger than `MAX_LOAD_BYTES`.
+  ///
+  /// # Errors
+  ///
+  /// This function will return a `LuaError` if the file can't be read.
+  pub fn load(&self, lua: &Lua) -> LuaResult<Option<LuaString>> {
+    match self {
+      BodySource::Memory(body) if body.as_bytes().len() as u64 > MAX_LOAD_BYTES => Ok(None),
+      BodySource::Memory(body) => Ok(Some(body.clone())),
+      BodySource::File(path) => {
+        let read = || -> io::Result<Option<Vec<u8>>> {
+          let mut bytes = Vec::new();
+          File::open(path)?
+            .take(MAX_LOAD_BYTES + 1)
+            .read_to_end(&mut bytes)?;
+          Ok((bytes.len() as u64 <= MAX_LOAD_BYTES).then_some(bytes))
+        };
+        match read()
+          .map_err(|e| LuaError::external(format!("Failed to read request body file: {}", e)))?
+        {
+          Some(bytes) => Ok(Some(lua.create_string(bytes)?)),
+          None => Ok(None),
+        }
+      }
+    }
+  }
+}
+
+/// The read position of `request.read_body()`.
+enum Cursor {
+  Memory { body: LuaString, pos: usize },
+  File(Option<File>, PathBuf),
+}
+
+/// Builds `request.read_body([size])`, which returns the next piece of the
+/// body (at most `size` bytes, default 64 KiB) or `nil` at the end.
+///
+/// # Errors
+///
+/// This function will return a `LuaError` if the function cannot be created.
+pub fn reader_function(lua: &Lua, source: BodySource) -> LuaResult<LuaFunction> {
+  let cursor = RefCell::new(match source {
+    BodySource::Memory(body) => Cursor::Memory { body, pos: 0 },
+    BodySource::File(path) => Cursor::File(None, path),
+  });
+  lua.create_function(move |lua, size: Option<usize>| {
+    let size = size.unwrap_or(DEFAULT_CHUNK_BYTES);
+    if size == 0 {
+      return Err(LuaError::external("read_body: size must be positive"));
+    }
+    let mut cursor = cursor.borrow_mut();
+    let chunk = match &mut *cursor {
+      Cursor::Memory { body, pos } => {
+        let bytes = body.as_bytes();
+        let end = bytes.len().min(pos.saturating_add(size));
+        let chunk = bytes[*pos..end].to_vec();
+        *pos = end;
+        chunk
+      }
+      Cursor::File(file, path) => {
+        if file.is_none() {
+          *file =
+            Some(File::open(&*path).map_err(|e| {
+              LuaError::external(format!("Failed to open request body file: {}", e))
+            })?);
+        }
+        let mut chunk = Vec::new();
+        if let Some(file) = file {
+          file
+            .take(size as u64)
+            .read_to_end(&mut chunk)
+            .map_err(|e| LuaError::external(format!("Failed to read request body: {}", e)))?;
+        }
+        chunk
+      }
+    };
+    if chunk.is_empty() {
+      return Ok(None);
+    }
+    Ok(Some(lua.create_string(chunk)?))
+  })
+}
+
+/// Reads a `response.body` straight from its Lua string. It holds a handle
+/// to the Lua state, so the string stays valid even if the worker retires
+/// the state before the response has been written.
+pub struct LuaStringReader {
+  _lua: Lua,
+  body: LuaString,
+  pos: usize,
+}
+
+impl LuaStringReader {
+  pub fn new(lua: &Lua, body: LuaString) -> Self {
+    LuaStringReader {
+      _lua: lua.clone(),
+      body,
+      pos: 0,
+    }
+  }
+}
+
+impl Read for LuaStringReader {
+  fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+    let bytes = self.body.as_bytes();
+    let remaining = bytes.get(self.pos..).unwrap_or_default();
+    let n = remaining.len().min(buf.len());
+    buf[..n].copy_from_slice(&remaining[..n]);
+    self.pos += n;
+    Ok(n)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::testing::Fixture;
+  use std::net::TcpStream;
+
+  #[test]
+  fn a_lua_string_is_read_in_pieces() {
+    let lua = Lua::new();
+    let body = lua.create_string("0123456789").unwrap();
+    let mut reader = LuaStringReader::new(&lua, body);
+    let mut buf = [0; 4];
+    let mut read = Vec::new();
+    loop {
+      let n = reader.read(&mut buf).unwrap();
+      if n == 0 {
+        break;
+      }
+      read.extend_from_slice(&buf[..n]);
+    }
+    assert_eq!(read, b"0123456789");
+  }
+
+  /// Sends `request` on a connection of its own and returns the response.
+  fn send(addr: &str, request: &[u8]) -> String {
+    let mut stream = TcpStream::connect(addr).unwrap();
+    stream.write_all(request).unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+    response
+  }
+
+  #[test]
+  fn a_body_over_the_limit_is_answered_413() {
+    let fixture = Fixture::new(
+      r#"
+        CONFIG = {
+          limits = { body = 1000 },
+          body = { spill_bytes = 100, spill_dir = "spill" },
+        }
+        router.add("/upload", "upload.lua")
+      "#,
+      &[(
+        "upload.lua",
+        "return { handler = function(request, response)
+           response.body = tostring(request.body_size)
+         end }",
+      )],
+    );
+    fs::create_dir(fixture.path().join("spill")).unwrap();
+    let server = fixture
+      .builder()
+      .addr("127.0.0.1:0")
+      .workers(1)
+      .load()
+      .unwrap();
+    let addr = server.serve().unwrap().remove(0);
+    let post = |headers: &str, body: &str| {
+      send(
+        &addr,
+        format!(
+          "POST /upload HTTP/1.1\r\nHost: localhost\r\n{}\r\n{}",
+          headers, body
+        )
+        .as_bytes(),
+      )
+    };
+
+    // Spilled to disk, but within the limit.
+    let kept = post(
+      "Content-Length: 1000\r\nConnection: close\r\n",
+      &"x".repeat(1000),
+    );
+    assert!(kept.ends_with("\r\n\r\n1000"), "{}", kept);
+
+    // Refused by its length, without waiting for the body.
+    let declared = post("Content-Length: 1001\r\n", "");
+    assert!(declared.starts_with("HTTP/1.1 413"), "{}", declared);
+    assert!(
+      declared
+        .to_ascii_lowercase()
+        .contains("\r\nconnection: close\r\n"),
+      "{}",
+      declared
+    );
+
+    // Refused once a chunked body gets past the limit.
+    let chunk = format!("200\r\n{}\r\n", "x".repeat(0x200));
+    let chunked = post(
+      "Transfer-Encoding: chunked\r\n",
+      &format!("{}{}0\r\n\r\n", chunk, chunk),
+    );
+    assert!(chunked.starts_with("HTTP/1.1 413"), "{}", chunked);
+
+    assert_eq!(
+      server
+        .state
+        .connections
+        .oversized(crate::server::Oversized::Body),
+      2
+    );
+    // The refused chunked body's file was deleted.
+    assert_eq!(
+      fs::read_dir(fixture.path().join("spill")).unwrap().count(),
+      0
+    );
+    server.shutdown();
+  }
+
+  /// The largest resident set the process has had, from `VmHWM`.
+  #[cfg(target_os = "linux")]
+  fn peak_rss() -> u64 {
+    let status = fs::read_to_string("/proc/self/status").unwrap();
+    let line = status
+      .lines()
+      .find(|line| line.starts_with("VmHWM:"))
+      .unwrap();
+    let kb: u64 = line.split_whitespace().nth(1).unwrap().parse().unwrap();
+    kb * 1024
+  }
+
+  /// Prints how far a 100 MB `response.body` raises the peak resident set.
+  /// Run it on its own, since the peak covers the whole process, with
+  /// `cargo test --release response_peak_memory -- --ignored --nocapture`.
+  #[test]
+  #[ignore = "measurement"]
+  #[cfg(target_os = "linux")]
+  fn response_peak_memory() {
+    const SIZE: usize = 100 * 1024 * 1024;
+    let fixture = Fixture::new(
+      r#"router.add("/export", "export.lua")"#,
+      &[(
+        "export.lua",
+        "return { handler = function(request, response)
+           response.body = string.rep('x', 100 * 1024 * 1024)
+         end }",
+      )],
+    );
+    let server = fixture
+      .builder()
+      .addr("127.0.0.1:0")
+      .workers(1)
+      .load()
+      .unwrap();
+    let addr = server.serve().unwrap().remove(0);
+
+    let before = peak_rss();
+    let mut stream = TcpStream::connect(&addr).unwrap();
+    write!(
+      stream,
+      "GET /export HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n"
+    )
+    .unwrap();
+    // Discarded as it arrives, so the client doesn't add to the peak.
+    let received = io::copy(&mut stream, &mut io::sink()).unwrap();
+    let peak = peak_rss();
+    server.shutdown();
+
+    assert!(received > SIZE as u64);
+    println!(
+      "100 MB response: peak RSS rose {} MB",
+      (peak - before) / (1024 * 1024)
+    );
+  }
+}
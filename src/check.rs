@@ -0,0 +1,301 @@
+//! # Configuration Check
+//!
+//! `fyre check` loads the configuration the way `fyre serve` does, without
+//! binding a socket or running a handler, so CI can catch a broken config
+//! before it is deployed. It reports:
+//!
+//! - Errors, which would stop the server from starting or break a route:
+//!   the configuration failing to load (a missing script, a bad options
+//!   table, route limit, or schedule), a script that doesn't compile, or
+//!   `CONFIG.tls` files that can't be used.
+//! - Warnings: routes and static directories added more than once, routes that
+//!   can never match, `.lua` files in the scripts directory that no route,
+//!   queue worker, task, or `CONFIG.shutdown.script` uses (modules loaded with
+//!   `require` show up here too), and admin endpoints without an audit log
+//!   destination of their own.
+//!
+//! The configuration is checked for the `--env` environment, including its
+//! `config.<env>.lua` file. The files pulled in with `include` are listed
+//! in the order they ran.
+//! The exit status is non-zero when there are errors. With `--json` the
+//! report is printed as one JSON object, and the log lines written while
+//! loading go to stderr instead of stdout.
+
+use arc_swap::ArcSwap;
+use std::collections::HashSet;
+use std::error::Error;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use crate::{
+  cli, load_lua_config, paths, script_cache, tls, RouteTable, RoutesMap, SCRIPT_CHECK_THREADS,
+};
+
+/// What `fyre check` found.
+#[derive(Debug, Default)]
+struct Report {
+  /// The configuration script, canonical once it is found.
+  config: String,
+  /// The environment checked.
+  env: String,
+  /// The files run by `include`, in order.
+  included: Vec<String>,
+  routes: usize,
+  static_dirs: usize,
+  /// The scripts the configuration uses.
+  scripts: usize,
+  errors: Vec<String>,
+  warnings: Vec<String>,
+}
+
+/// Checks the configuration and prints the report, for `fyre check`.
+///
+/// # Errors
+///
+/// This function will return an error if the check found any errors.
+pub fn run(args: &cli::CheckArgs) -> Result<(), Box<dyn Error>> {
+  let report = if args.json {
+    let _redirect = StdoutToStderr::new();
+    check(&args.paths)
+  } else {
+    check(&args.paths)
+  };
+
+  if args.json {
+    println!("{}", report.to_json());
+  } else {
+    report.print();
+  }
+  match report.errors.len() {
+    0 => Ok(()),
+    n => Err(format!("{} error(s) found in {}", n, report.config).into()),
+  }
+}
+
+fn check(args: &cli::ConfigArgs) -> Report {
+  let mut report = Report {
+    config: args.config.display().to_string(),
+    env: args.env.clone(),
+    ..Report::default()
+  };
+  let paths = match paths::Paths::new(args) {
+    Ok(paths) => paths,
+    Err(e) => {
+      report.errors.push(e);
+      return report;
+    }
+  };
+  report.config = paths.config_file().display().to_string();
+
+  let routes: RoutesMap = Arc::new(ArcSwap::from_pointee(RouteTable::default()));
+  let config = match load_lua_config(routes.clone(), &paths) {
+    Ok(config) => config,
+    Err(e) => {
+      report.errors.push(e.to_string());
+      return report;
+    }
+  };
+  report.warnings = config.warnings;
+  if config.admin.is_some() && config.audit.is_none() {
+    report.warnings.push(
+      "Admin endpoints are enabled without an audit destination; set audit.file or \
+       audit.syslog to keep the audit log out of the main log"
+        .to_string(),
+    );
+  }
+  report.included = config
+    .included
+    .iter()
+    .map(|path| path.display().to_string())
+    .collect();
+  let table = routes.load();
+  report.routes = table.handlers.len();
+  report.static_dirs = table.mounts.len();
+
+  let mut scripts: Vec<String> = table
+    .handlers
+    .values()
+    .map(|route| route.script.clone())
+    .chain(
+      config
+        .queue_workers
+        .iter()
+        .map(|worker| worker.script.clone()),
+    )
+    .chain(config.schedules.iter().map(|task| task.script.clone()))
+    .chain(config.on_shutdown)
+    .collect();
+  scripts.sort();
+  scripts.dedup();
+  report.scripts = scripts.len();
+
+  let cache = script_cache::ScriptCache::new(false, None, script_cache::DEFAULT_MAX_BYTES);
+  let threads = std::thread::available_parallelism()
+    .map_or(1, |n| n.get())
+    .min(SCRIPT_CHECK_THREADS);
+  for (_, error) in cache.check_all(&scripts, threads) {
+    report
+      .errors
+      .push(format!("Script failed to compile: {}", error));
+  }
+
+  if let Some(tls) = &config.tls {
+    if let Err(e) = tls::server_config(tls) {
+      report.errors.push(e);
+    }
+  }
+
+  // Included config files may live in the scripts directory too.
+  let used: HashSet<&str> = scripts
+    .iter()
+    .chain(&report.included)
+    .map(String::as_str)
+    .collect();
+  let mut found = Vec::new();
+  if let Err(e) = find_scripts(paths.scripts_dir(), &mut found) {
+    report.errors.push(format!(
+      "Failed to list {}: {}",
+      paths.scripts_dir().display(),
+      e
+    ));
+  }
+  found.sort();
+  for path in found {
+    let Ok(canonical) = fs::canonicalize(&path) else {
+      continue;
+    };
+    if !used.contains(canonical.display().to_string().as_str()) {
+      report.warnings.push(format!(
+        "Script {} is not used by any route, worker, or task",
+        path.display()
+      ));
+    }
+  }
+  report
+}
+
+/// Collects the `.lua` files under `dir`, without following links to other
+/// directories.
+fn find_scripts(dir: &Path, found: &mut Vec<PathBuf>) -> std::io::Result<()> {
+  for entry in fs::read_dir(dir)? {
+    let entry = entry?;
+    let path = entry.path();
+    if entry.file_type()?.is_dir() {
+      find_scripts(&path, found)?;
+    } else if path.extension().is_some_and(|ext| ext == "lua") {
+      found.push(path);
+    }
+  }
+  Ok(())
+}
+
+impl Report {
+  fn print(&self) {
+    println!(
+      "{} ({}): {} route(s), {} static director{}, {} script(s)",
+      self.config,
+      self.env,
+      self.routes,
+      self.static_dirs,
+      if self.static_dirs == 1 { "y" } else { "ies" },
+      self.scripts
+    );
+    if !self.included.is_empty() {
+      println!("\nIncluded, in order:");
+      for file in &self.included {
+        println!("  {}", file);
+      }
+    }
+    if !self.errors.is_empty() {
+      println!("\nErrors:");
+      for error in &self.errors {
+        println!("  {}", error);
+      }
+    }
+    if !self.warnings.is_empty() {
+      println!("\nWarnings:");
+      for warning in &self.warnings {
+        println!("  {}", warning);
+      }
+    }
+    println!(
+      "\n{} error(s), {} warning(s)",
+      self.errors.len(),
+      self.warnings.len()
+    );
+  }
+
+  fn to_json(&self) -> serde_json::Value {
+    serde_json::json!({
+      "config": self.config,
+      "env": self.env,
+      "valid": self.errors.is_empty(),
+      "included": self.included,
+      "routes": self.routes,
+      "static_dirs": self.static_dirs,
+      "scripts": self.scripts,
+      "errors": self.errors,
+      "warnings": self.warnings,
+    })
+  }
+}
+
+/// Points stdout at stderr until dropped, so that with `--json` only the
+/// report is written to stdout. Output from `print` in `config.lua` goes
+/// through C stdio, which is flushed at both ends too.
+struct StdoutToStderr {
+  #[cfg(unix)]
+  saved: Option<libc::c_int>,
+}
+
+impl StdoutToStderr {
+  #[cfg(unix)]
+  fn new() -> Self {
+    flush_stdout();
+    // SAFETY: only the process's own standard descriptors are duplicated,
+    // and the copy of stdout is closed again in `drop`.
+    let saved = unsafe {
+      let saved = libc::dup(libc::STDOUT_FILENO);
+      if saved >= 0 && libc::dup2(libc::STDERR_FILENO, libc::STDOUT_FILENO) < 0 {
+        libc::close(saved);
+        -1
+      } else {
+        saved
+      }
+    };
+    StdoutToStderr {
+      saved: (saved >= 0).then_some(saved),
+    }
+  }
+
+  #[cfg(not(unix))]
+  fn new() -> Self {
+    StdoutToStderr {}
+  }
+}
+
+#[cfg(unix)]
+impl Drop for StdoutToStderr {
+  fn drop(&mut self) {
+    if let Some(saved) = self.saved {
+      flush_stdout();
+      // SAFETY: `saved` is the descriptor duplicated in `new`.
+      unsafe {
+        libc::dup2(saved, libc::STDOUT_FILENO);
+        libc::close(saved);
+      }
+    }
+  }
+}
+
+#[cfg(unix)]
+fn flush_stdout() {
+  use std::io::Write;
+
+  let _ = std::io::stdout().flush();
+  // SAFETY: a null stream flushes every open C stdio stream.
+  unsafe {
+    libc::fflush(std::ptr::null_mut());
+  }
+}
@@ -0,0 +1,189 @@
+//! # Command Line
+//!
+//! The command-line interface, parsed with clap:
+//!
+//! ```text
+//! fyre [serve] [--addr 0.0.0.0:8000]... [--workers 4] [--config config.lua] [--scripts scripts]
+//!   [--env production] [--pidfile /run/fyre.pid] [--daemon] [--log-file /var/log/fyre.log]
+//!   [--quiet] [--log-level info,fyre::pipeline=debug] [--debug-dump[=/api/*]]...
+//!   [--debug-dump-unsafe]
+//! fyre routes [--config config.lua] [--scripts scripts] [--env production]
+//! fyre check [--json] [--config config.lua] [--scripts scripts] [--env production]
+//! fyre bench <url> [--connections 16] [--duration 10s] ...
+//! fyre init [dir] [--template api|site] [--force]
+//! fyre secrets keygen | encrypt <secrets.json> <out>
+//! ```
+//!
+//! `serve` is the default, so a bare `fyre` starts the server. Options given
+//! here (or `--config`, `--scripts`, `--env`, and `--log-level` through
+//! `FYRE_CONFIG`, `FYRE_SCRIPTS_DIR`, `FYRE_ENV`, and `FYRE_LOG`) take
+//! precedence over `config.lua`, which takes precedence over the built-in
+//! defaults. An address given without `--addr` (`fyre 0.0.0.0:80`) still
+//! works but is deprecated.
+
+use clap::{Args, Parser, Subcommand};
+use std::path::PathBuf;
+
+use crate::{bench, init, logger, net, secrets, worker_stats};
+
+/// The configuration script used when `--config` is not given.
+pub const DEFAULT_CONFIG_FILE: &str = "config.lua";
+/// The handler script directory, next to the configuration script, used
+/// when `--scripts` is not given.
+pub const DEFAULT_SCRIPTS_DIR: &str = "scripts";
+/// The environment used when `--env` is not given.
+pub const DEFAULT_ENV: &str = "development";
+
+/// Serves HTTP requests with Lua handler scripts.
+#[derive(Debug, Parser)]
+#[command(name = "fyre", version, args_conflicts_with_subcommands = true)]
+pub struct Cli {
+  #[command(subcommand)]
+  pub command: Option<Command>,
+  /// The options of `serve`, when no subcommand is given.
+  #[command(flatten)]
+  pub serve: ServeArgs,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum Command {
+  /// Starts the server (the default).
+  Serve(ServeArgs),
+  /// Lists the routes config.lua declares, whether their scripts compile,
+  /// and the static directories.
+  Routes(ConfigArgs),
+  /// Loads config.lua and compiles every script it uses, without starting
+  /// the server, and reports any errors and warnings.
+  Check(CheckArgs),
+  /// Load-tests a running server.
+  Bench(bench::BenchArgs),
+  /// Creates config.lua, scripts/, and static/ for a new project.
+  Init(init::InitArgs),
+  /// Creates keys and encrypted files for fyre.secrets.
+  Secrets(secrets::SecretsArgs),
+}
+
+/// Where the configuration and the handler scripts are read from (see
+/// `paths`).
+#[derive(Debug, Clone, Args)]
+pub struct ConfigArgs {
+  /// The configuration script.
+  #[arg(long, value_name = "FILE", env = "FYRE_CONFIG", default_value = DEFAULT_CONFIG_FILE)]
+  pub config: PathBuf,
+  /// The directory handler scripts are loaded from [default: scripts, next
+  /// to the configuration script]
+  #[arg(long, value_name = "DIR", env = "FYRE_SCRIPTS_DIR")]
+  pub scripts: Option<PathBuf>,
+  /// The environment, which selects the `config.<env>.lua` file run after
+  /// the configuration script
+  #[arg(long, value_name = "NAME", env = "FYRE_ENV", default_value = DEFAULT_ENV)]
+  pub env: String,
+}
+
+#[derive(Debug, Clone, Args)]
+pub struct CheckArgs {
+  /// Prints the report as JSON.
+  #[arg(long)]
+  pub json: bool,
+  #[command(flatten)]
+  pub paths: ConfigArgs,
+}
+
+#[derive(Debug, Clone, Args)]
+pub struct ServeArgs {
+  /// An address to listen on, `host:port` or `unix:/path/to.sock`; repeat
+  /// for several. Overrides CONFIG.addr and CONFIG.addrs.
+  #[arg(long = "addr", value_name = "ADDR", value_parser = parse_addr)]
+  pub addrs: Vec<String>,
+  /// Deprecated: use --addr.
+  #[arg(value_name = "ADDR", hide = true, value_parser = parse_addr)]
+  pub legacy_addrs: Vec<String>,
+  /// The number of worker threads. Overrides CONFIG.workers.
+  #[arg(long, value_name = "N", value_parser = parse_workers)]
+  pub workers: Option<usize>,
+  /// Writes the process id to FILE once the addresses are bound. Overrides
+  /// CONFIG.pid_file.
+  #[arg(long, value_name = "FILE")]
+  pub pidfile: Option<PathBuf>,
+  /// Runs in the background once the addresses are bound (Unix only).
+  #[arg(long)]
+  pub daemon: bool,
+  /// Writes the TCP ports listened on to FILE, one per line, once the
+  /// server is running; useful with port 0.
+  #[arg(long, value_name = "FILE")]
+  pub port_file: Option<PathBuf>,
+  /// Appends the server's output to FILE. Overrides CONFIG.log.file.
+  #[arg(long, value_name = "FILE")]
+  pub log_file: Option<PathBuf>,
+  /// Leaves out the route summary and the startup banner: the version,
+  /// environment, route count, and addresses. Errors and warnings are still
+  /// logged.
+  #[arg(long)]
+  pub quiet: bool,
+  /// Which log lines to write: a level (error, warn, info, debug, trace),
+  /// then `target=level` overrides, e.g. `info,fyre::pipeline=debug`.
+  /// Overrides CONFIG.log.level.
+  #[arg(long, value_name = "SPEC", env = "FYRE_LOG", value_parser = parse_log_level)]
+  pub log_level: Option<logger::Filter>,
+  /// Writes each request for PATH, and its response, to a file under
+  /// CONFIG.debug.dump_dir; a PATH ending in `*` matches every path
+  /// starting with the rest, and no PATH matches them all. Repeat for
+  /// several. Overrides CONFIG.debug.dump_routes.
+  #[arg(
+    long,
+    value_name = "PATH",
+    num_args = 0..=1,
+    default_missing_value = "/*",
+    value_parser = parse_dump_route
+  )]
+  pub debug_dump: Vec<String>,
+  /// Writes the Authorization, Cookie, and Set-Cookie headers to debug
+  /// dumps as they were sent, rather than redacted.
+  #[arg(long)]
+  pub debug_dump_unsafe: bool,
+  #[command(flatten)]
+  pub paths: ConfigArgs,
+}
+
+impl ServeArgs {
+  /// Returns the addresses given with `--addr` or as arguments, warning
+  /// about the deprecated form.
+  pub fn addrs(&self) -> Vec<String> {
+    if !self.legacy_addrs.is_empty() {
+      warn!(
+        "Passing the address without --addr is deprecated; use --addr {}",
+        self.legacy_addrs.join(" --addr ")
+      );
+    }
+    self
+      .addrs
+      .iter()
+      .chain(&self.legacy_addrs)
+      .cloned()
+      .collect()
+  }
+}
+
+fn parse_addr(value: &str) -> Result<String, String> {
+  net::validate_addr(value).map(|()| value.to_string())
+}
+
+fn parse_dump_route(value: &str) -> Result<String, String> {
+  if value.starts_with('/') {
+    Ok(value.to_string())
+  } else {
+    Err("must be a path starting with /".to_string())
+  }
+}
+
+fn parse_log_level(value: &str) -> Result<logger::Filter, String> {
+  value.parse()
+}
+
+fn parse_workers(value: &str) -> Result<usize, String> {
+  value
+    .parse::<usize>()
+    .ok()
+    .filter(|n| (1..=worker_stats::MAX_WORKERS).contains(n))
+    .ok_or_else(|| format!("must be between 1 and {}", worker_stats::MAX_WORKERS))
+}
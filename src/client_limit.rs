@@ -0,0 +1,211 @@
+//! # Per-Client Rate Limits
+//!
+//! Limits how fast each client may send requests, before any Lua runs.
+//! `CONFIG.rate_limit = { requests = 100, window = 60, burst = 20 }` gives
+//! every client address a token bucket refilling `requests` tokens every
+//! `window` seconds and holding at most `burst` (by default `requests`); a
+//! request takes one token, and one arriving to an empty bucket is answered
+//! with `429`, `Retry-After`, and `RateLimit-Limit`, `RateLimit-Remaining`,
+//! and `RateLimit-Reset` headers. A route's `rate_limit` option adds a
+//! bucket of its own per client, on top of the global one.
+//!
+//! The buckets are kept in a `fyre::ratelimit::RateLimiter` separate from
+//! the one scripts use, which bounds how many keys it tracks. Health probes
+//! are answered before requests reach here, and the admin endpoints and
+//! Unix socket clients, which have no address to key on, aren't limited.
+//!
+//! An IPv4 client is keyed on its address. An IPv6 client is keyed on the
+//! `/64` its address is in, since one host is usually handed a whole `/64`
+//! and could otherwise rotate through its addresses to get a fresh bucket
+//! for each request; `ipv6_prefix` sets another prefix length.
+
+use mlua::prelude::*;
+use std::net::{IpAddr, Ipv6Addr};
+use std::time::Duration;
+
+/// The prefix length IPv6 clients are keyed on when `ipv6_prefix` is not
+/// set.
+pub const DEFAULT_IPV6_PREFIX: u8 = 64;
+
+/// A token bucket per client.
+#[derive(Debug, Clone)]
+pub struct RateLimit {
+  /// The tokens added every `window`.
+  pub requests: u32,
+  pub window: Duration,
+  /// The most tokens a bucket holds, i.e. the longest burst allowed.
+  pub burst: u32,
+  /// The length of the prefix IPv6 clients share a bucket within.
+  pub ipv6_prefix: u8,
+}
+
+impl RateLimit {
+  /// Reads a `{ requests, window, burst, by, ipv6_prefix }` table. `by` may
+  /// only be `"ip"`, the default. `what` names the declaration in error
+  /// messages.
+  ///
+  /// # Errors
+  ///
+  /// This function will return a `LuaError` if `requests` or `window` is
+  /// missing, a number is not positive, `by` is not `"ip"`, `ipv6_prefix`
+  /// is over 128, or a field has the wrong type.
+  pub fn from_lua(what: &str, table: &LuaTable) -> LuaResult<RateLimit> {
+    let requests = table.get::<Option<u32>>("requests")?;
+    let window = table.get::<Option<f64>>("window")?;
+    let (Some(requests), Some(window)) = (requests, window) else {
+      return Err(LuaError::external(format!(
+        "{}: rate_limit needs requests and window",
+        what
+      )));
+    };
+    let burst = table.get::<Option<u32>>("burst")?.unwrap_or(requests);
+    if requests == 0 || burst == 0 || window.is_nan() || window <= 0.0 || window > 1e9 {
+      return Err(LuaError::external(format!(
+        "{}: rate_limit requests, window, and burst must be positive",
+        what
+      )));
+    }
+    match table.get::<Option<String>>("by")?.as_deref() {
+      None | Some("ip") => {}
+      Some(other) => {
+        return Err(LuaError::external(format!(
+          "{}: rate_limit can only be by \"ip\", got \"{}\"",
+          what, other
+        )))
+      }
+    }
+    let ipv6_prefix = table
+      .get::<Option<u8>>("ipv6_prefix")?
+      .unwrap_or(DEFAULT_IPV6_PREFIX);
+    if ipv6_prefix > 128 {
+      return Err(LuaError::external(format!(
+        "{}: rate_limit ipv6_prefix must be from 0 to 128",
+        what
+      )));
+    }
+    Ok(RateLimit {
+      requests,
+      window: Duration::from_secs_f64(window),
+      burst,
+      ipv6_prefix,
+    })
+  }
+
+  /// Returns the key `ip`'s bucket is kept under: an IPv4 address itself,
+  /// or the `ipv6_prefix` network an IPv6 address is in.
+  pub fn client_key(&self, ip: IpAddr) -> String {
+    match ip.to_canonical() {
+      IpAddr::V4(ip) => ip.to_string(),
+      IpAddr::V6(ip) => {
+        let mask = u128::MAX
+          .checked_shl(128 - u32::from(self.ipv6_prefix))
+          .unwrap_or(0);
+        format!(
+          "{}/{}",
+          Ipv6Addr::from(u128::from(ip) & mask),
+          self.ipv6_prefix
+        )
+      }
+    }
+  }
+
+  /// Takes a token from `key`'s bucket in `store`.
+  pub fn check(&self, store: &crate::fyre::ratelimit::RateLimiter, key: &str) -> Decision {
+    let decision = store.check_bucket(
+      key,
+      f64::from(self.requests),
+      self.window,
+      f64::from(self.burst),
+    );
+    Decision {
+      allowed: decision.allowed,
+      limit: self.burst,
+      remaining: decision.remaining,
+      retry_after: decision.retry_after,
+      reset: decision.reset,
+    }
+  }
+
+  /// Returns what `check` would decide for `key` without taking a token.
+  pub fn peek(&self, store: &crate::fyre::ratelimit::RateLimiter, key: &str) -> Decision {
+    let decision = store.peek_bucket(
+      key,
+      f64::from(self.requests),
+      self.window,
+      f64::from(self.burst),
+    );
+    Decision {
+      allowed: decision.allowed,
+      limit: self.burst,
+      remaining: decision.remaining,
+      retry_after: decision.retry_after,
+      reset: decision.reset,
+    }
+  }
+}
+
+/// The outcome of taking a token, with what the `429` headers report.
+pub struct Decision {
+  pub allowed: bool,
+  pub limit: u32,
+  pub remaining: u64,
+  pub retry_after: u64,
+  pub reset: u64,
+}
+
+impl Decision {
+  /// The `Retry-After` and `RateLimit-*` headers for a `429`.
+  pub fn headers(&self) -> [(&'static str, String); 4] {
+    [
+      ("Retry-After", self.retry_after.max(1).to_string()),
+      ("RateLimit-Limit", self.limit.to_string()),
+      ("RateLimit-Remaining", self.remaining.to_string()),
+      ("RateLimit-Reset", self.reset.to_string()),
+    ]
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn limit(ipv6_prefix: u8) -> RateLimit {
+    RateLimit {
+      requests: 1,
+      window: Duration::from_secs(1),
+      burst: 1,
+      ipv6_prefix,
+    }
+  }
+
+  #[test]
+  fn ipv6_clients_are_keyed_by_prefix() {
+    let limit = limit(DEFAULT_IPV6_PREFIX);
+    let key = |ip: &str| limit.client_key(ip.parse().unwrap());
+    assert_eq!(key("2001:db8:1:2:aaaa::1"), "2001:db8:1:2::/64");
+    assert_eq!(key("2001:db8:1:2:aaaa::1"), key("2001:db8:1:2:ffff:1:2:3"));
+    assert_ne!(key("2001:db8:1:2::1"), key("2001:db8:1:3::1"));
+    assert_eq!(key("192.0.2.7"), "192.0.2.7");
+    assert_eq!(key("::ffff:192.0.2.7"), "192.0.2.7");
+  }
+
+  #[test]
+  fn ipv6_prefix_is_configurable() {
+    let ip = "2001:db8:1:2:3:4:5:6".parse().unwrap();
+    assert_eq!(limit(128).client_key(ip), "2001:db8:1:2:3:4:5:6/128");
+    assert_eq!(limit(48).client_key(ip), "2001:db8:1::/48");
+    assert_eq!(limit(0).client_key(ip), "::/0");
+  }
+
+  #[test]
+  fn clients_in_one_prefix_share_a_bucket() {
+    let store = crate::fyre::ratelimit::RateLimiter::default();
+    let limit = limit(DEFAULT_IPV6_PREFIX);
+    let first = limit.client_key("2001:db8::1".parse().unwrap());
+    let rotated = limit.client_key("2001:db8::2".parse().unwrap());
+    assert!(limit.check(&store, &first).allowed);
+    assert!(!limit.check(&store, &rotated).allowed);
+    let other = limit.client_key("2001:db8:0:1::1".parse().unwrap());
+    assert!(limit.check(&store, &other).allowed);
+  }
+}
@@ -0,0 +1,224 @@
+//! # Accepted Content Types
+//!
+//! Limits the request bodies a route takes to the media types it declares,
+//! with `router.add(path, script, { accept_types = { "application/json" } })`.
+//! A request that sends a body, or uses `POST`, `PUT`, or `PATCH`, whose
+//! `Content-Type` is missing or matches none of them is answered with `415`
+//! before its body is read or any Lua runs. Entries are `type/subtype`,
+//! `type/*`, or `*/*`, matched ignoring case and parameters such as
+//! `charset`. Rejections are counted per route for `fyre.metrics.render()`.
+
+use mlua::prelude::*;
+use std::sync::atomic::{AtomicU64, Ordering};
+use tiny_http::{Header, Method};
+
+/// A route's `accept_types`.
+#[derive(Debug)]
+pub struct AcceptTypes {
+  /// The media types, lowercased.
+  types: Vec<String>,
+  rejected: AtomicU64,
+}
+
+impl AcceptTypes {
+  /// Reads a route's `accept_types` option. Returns `None` when it isn't
+  /// set.
+  ///
+  /// # Errors
+  ///
+  /// This function will return a `LuaError` if the option is not a list of
+  /// strings, is empty, or has an entry that is not a media type.
+  pub fn from_route_options(path: &str, opts: &LuaTable) -> LuaResult<Option<AcceptTypes>> {
+    let Some(types) = opts.get::<Option<Vec<String>>>("accept_types")? else {
+      return Ok(None);
+    };
+    if types.is_empty() {
+      return Err(LuaError::external(format!(
+        "router.add('{}'): accept_types can't be empty",
+        path
+      )));
+    }
+    for media_type in &types {
+      let valid = media_type.split_once('/').is_some_and(|(kind, subtype)| {
+        is_token(kind) && is_token(subtype) && (kind != "*" || subtype == "*")
+      });
+      if !valid {
+        return Err(LuaError::external(format!(
+          "router.add('{}'): '{}' in accept_types is not a media type such as \
+           \"application/json\" or \"multipart/*\"",
+          path, media_type
+        )));
+      }
+    }
+    Ok(Some(AcceptTypes {
+      types: types.iter().map(|t| t.to_ascii_lowercase()).collect(),
+      rejected: AtomicU64::new(0),
+    }))
+  }
+
+  /// Whether a request with `method` and `headers` may go on. One without a
+  /// body, other than `POST`, `PUT`, or `PATCH`, always may; the rest need
+  /// a `Content-Type` matching one of the types. A refusal is counted.
+  pub fn allows(&self, method: &Method, headers: &[Header]) -> bool {
+    let header = |name: &'static str| {
+      headers
+        .iter()
+        .find(|h| h.field.equiv(name))
+        .map(|h| h.value.as_str())
+    };
+    let has_body = header("Transfer-Encoding").is_some()
+      || header("Content-Length").is_some_and(|length| length.trim() != "0");
+    if !has_body && !matches!(method, Method::Post | Method::Put | Method::Patch) {
+      return true;
+    }
+    let media_type = header("Content-Type")
+      .and_then(|value| value.split(';').next())
+      .map(|value| value.trim().to_ascii_lowercase());
+    let allowed = media_type.is_some_and(|media_type| {
+      let Some((kind, _)) = media_type.split_once('/') else {
+        return false;
+      };
+      self.types.iter().any(|accepted| {
+        *accepted == media_type
+          || *accepted == "*/*"
+          || accepted.strip_suffix("/*") == Some(kind)
+      })
+    });
+    if !allowed {
+      self.rejected.fetch_add(1, Ordering::Relaxed);
+    }
+    allowed
+  }
+
+  /// The number of requests refused since startup.
+  pub fn rejected(&self) -> u64 {
+    self.rejected.load(Ordering::Relaxed)
+  }
+}
+
+/// Whether `s` is a non-empty HTTP token, or `*`.
+fn is_token(s: &str) -> bool {
+  !s.is_empty()
+    && s
+      .bytes()
+      .all(|b| b.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&b))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::testing::{self, Fixture};
+
+  fn accept(types: &str) -> LuaResult<Option<AcceptTypes>> {
+    let lua = Lua::new();
+    let opts = lua
+      .load(format!("{{ accept_types = {} }}", types))
+      .eval::<LuaTable>()?;
+    AcceptTypes::from_route_options("/upload", &opts)
+  }
+
+  fn headers(pairs: &[(&str, &str)]) -> Vec<Header> {
+    pairs
+      .iter()
+      .map(|(name, value)| Header::from_bytes(*name, *value).unwrap())
+      .collect()
+  }
+
+  #[test]
+  fn types_match_ignoring_case_and_parameters() {
+    let types = accept(r#"{ "application/json", "multipart/*" }"#)
+      .unwrap()
+      .unwrap();
+    for content_type in [
+      "application/json",
+      "Application/JSON; charset=utf-8",
+      "multipart/form-data; boundary=x",
+    ] {
+      let sent = headers(&[("Content-Type", content_type), ("Content-Length", "2")]);
+      assert!(types.allows(&Method::Post, &sent), "{}", content_type);
+    }
+    for content_type in ["text/plain", "application/jsonp", "multipart", ""] {
+      let sent = headers(&[("Content-Type", content_type), ("Content-Length", "2")]);
+      assert!(!types.allows(&Method::Post, &sent), "{}", content_type);
+    }
+    assert_eq!(types.rejected(), 4);
+  }
+
+  #[test]
+  fn only_requests_with_a_body_need_a_type() {
+    let types = accept(r#"{ "application/json" }"#).unwrap().unwrap();
+    assert!(types.allows(&Method::Get, &[]));
+    assert!(types.allows(&Method::Delete, &headers(&[("Content-Length", "0")])));
+    assert!(!types.allows(&Method::Post, &[]));
+    assert!(!types.allows(&Method::Put, &headers(&[("Content-Length", "0")])));
+    assert!(!types.allows(&Method::Delete, &headers(&[("Content-Length", "2")])));
+    assert!(!types.allows(&Method::Get, &headers(&[("Transfer-Encoding", "chunked")])));
+  }
+
+  #[test]
+  fn bad_lists_are_refused() {
+    assert!(accept("nil").unwrap().is_none());
+    for types in [
+      "{}",
+      r#"{ "json" }"#,
+      r#"{ "*/json" }"#,
+      r#"{ "application/" }"#,
+      r#"{ "text/plain text" }"#,
+    ] {
+      let error = accept(types).unwrap_err().to_string();
+      assert!(
+        error.contains("router.add('/upload')"),
+        "{}: {}",
+        types,
+        error
+      );
+    }
+  }
+
+  #[test]
+  fn other_types_are_answered_415() {
+    let fixture = Fixture::new(
+      r#"router.add("/upload", "upload.lua", { accept_types = { "application/json" } })"#,
+      &[(
+        "upload.lua",
+        r#"return { handler = function(request, response) response.body = "took " .. request.body end }"#,
+      )],
+    );
+    let server = fixture
+      .builder()
+      .addr("127.0.0.1:0")
+      .workers(1)
+      .load()
+      .unwrap();
+    let addr = server.serve().unwrap().remove(0);
+    let post = |content_type: &str| {
+      testing::send(
+        &addr,
+        &format!(
+          "POST /upload HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n{}\
+           Content-Length: 2\r\n\r\n{{}}",
+          content_type
+        ),
+      )
+    };
+
+    let response = post("Content-Type: application/json; charset=utf-8\r\n");
+    assert!(response.starts_with("HTTP/1.1 200"), "{}", response);
+    assert!(response.ends_with("took {}"), "{}", response);
+    for content_type in ["Content-Type: text/plain\r\n", ""] {
+      let response = post(content_type);
+      assert!(
+        response.starts_with("HTTP/1.1 415"),
+        "{:?}: {}",
+        content_type,
+        response
+      );
+      assert!(
+        response.ends_with("415 Unsupported Media Type"),
+        "{}",
+        response
+      );
+    }
+    server.shutdown();
+  }
+}
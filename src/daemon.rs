@@ -0,0 +1,256 @@
+//! # PID File and Daemon Mode
+//!
+//! `--pidfile` (or `CONFIG.pid_file`) writes the server's process id to a file
+//! once its addresses are bound, for init scripts, and removes it on a clean
+//! shutdown. The server refuses to start if the file already names a running
+//! process; a file left by one that has exited is replaced.
+//!
+//! `--daemon` runs the server in the background on systems without a
+//! service manager. The process forks once the addresses are bound, before
+//! any other thread is started, and the command returns when the server is
+//! ready to handle requests (exiting non-zero if it failed to start). The
+//! server's output is appended to `--log-file` (or `CONFIG.log.file`), or
+//! discarded if neither is set. Daemon mode is only available on Unix.
+
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+/// A PID file, removed when dropped.
+pub struct PidFile {
+  path: PathBuf,
+}
+
+impl PidFile {
+  /// Checks that `path` doesn't name a running process, before the server
+  /// binds its addresses.
+  ///
+  /// # Errors
+  ///
+  /// Returns an error message if the file names a running process.
+  pub fn check(path: &Path) -> Result<(), String> {
+    let Some(pid) = fs::read_to_string(path)
+      .ok()
+      .and_then(|contents| contents.trim().parse::<u32>().ok())
+    else {
+      return Ok(());
+    };
+    if is_running(pid) {
+      return Err(format!(
+        "{} names process {}, which is still running",
+        path.display(),
+        pid
+      ));
+    }
+    warn!(
+      "Replacing stale PID file {} (process {})",
+      path.display(),
+      pid
+    );
+    Ok(())
+  }
+
+  /// Writes the current process id to `path`.
+  ///
+  /// # Errors
+  ///
+  /// Returns an error message if the file can't be written.
+  pub fn write(path: &Path) -> Result<PidFile, String> {
+    fs::write(path, format!("{}\n", std::process::id()))
+      .map_err(|e| format!("Failed to write PID file {}: {}", path.display(), e))?;
+    Ok(PidFile {
+      path: path.to_path_buf(),
+    })
+  }
+}
+
+impl Drop for PidFile {
+  fn drop(&mut self) {
+    if let Err(e) = fs::remove_file(&self.path) {
+      warn!("Failed to remove {}: {}", self.path.display(), e);
+    }
+  }
+}
+
+#[cfg(unix)]
+fn is_running(pid: u32) -> bool {
+  let Ok(pid) = libc::pid_t::try_from(pid) else {
+    return false;
+  };
+  // SAFETY: signal 0 only checks whether the process exists.
+  let result = unsafe { libc::kill(pid, 0) };
+  // EPERM means it exists but belongs to another user.
+  result == 0 || io::Error::last_os_error().raw_os_error() == Some(libc::EPERM)
+}
+
+#[cfg(not(unix))]
+fn is_running(_pid: u32) -> bool {
+  false
+}
+
+/// Points stdout and stderr at `file`, for the log file.
+///
+/// # Errors
+///
+/// Returns an error message if the descriptors can't be replaced.
+#[cfg(unix)]
+pub fn redirect_output(file: &fs::File) -> Result<(), String> {
+  redirect(file, &[libc::STDOUT_FILENO, libc::STDERR_FILENO])
+}
+
+/// Does nothing: the log is still written to the file, but output from
+/// `print` in scripts isn't.
+#[cfg(not(unix))]
+pub fn redirect_output(_file: &fs::File) -> Result<(), String> {
+  Ok(())
+}
+
+#[cfg(unix)]
+fn open_log(path: &Path) -> Result<fs::File, String> {
+  fs::OpenOptions::new()
+    .create(true)
+    .append(true)
+    .open(path)
+    .map_err(|e| format!("Failed to open log file {}: {}", path.display(), e))
+}
+
+/// Tells the command that started a daemon that the server is ready, so it
+/// can exit.
+pub struct Ready {
+  #[cfg(unix)]
+  pipe: fs::File,
+}
+
+impl Ready {
+  pub fn notify(self) {
+    #[cfg(unix)]
+    {
+      let mut pipe = self.pipe;
+      let _ = writeln!(pipe, "{}", std::process::id());
+    }
+  }
+}
+
+/// Moves the server into the background: the calling process waits until
+/// the server calls `Ready::notify` (or exits) and then exits itself,
+/// while the server continues in a new session, detached from the
+/// terminal, with its output appended to `log_file` or discarded.
+///
+/// It must be called before any thread is started, since only the calling
+/// thread survives a fork.
+///
+/// # Errors
+///
+/// Returns an error message if the process can't fork or the log file
+/// can't be opened, or always where daemon mode isn't supported.
+#[cfg(unix)]
+pub fn daemonize(log_file: Option<&Path>) -> Result<Ready, String> {
+  use std::io::Read;
+  use std::os::fd::FromRawFd;
+
+  // Opened first, so an error is still reported on the terminal.
+  let log = log_file.map(open_log).transpose()?;
+  let null = fs::OpenOptions::new()
+    .read(true)
+    .write(true)
+    .open("/dev/null")
+    .map_err(|e| format!("Failed to open /dev/null: {}", e))?;
+
+  let mut fds = [0; 2];
+  // SAFETY: `fds` has room for the two descriptors `pipe` creates.
+  if unsafe { libc::pipe(fds.as_mut_ptr()) } < 0 {
+    return Err(format!(
+      "Failed to daemonize: {}",
+      io::Error::last_os_error()
+    ));
+  }
+  // SAFETY: both descriptors were just created and are owned here.
+  let (mut reader, writer) =
+    unsafe { (fs::File::from_raw_fd(fds[0]), fs::File::from_raw_fd(fds[1])) };
+  // Not inherited by programs run with `fyre.exec`, which would keep the
+  // pipe open after the server is ready.
+  for fd in fds {
+    // SAFETY: sets a flag on a descriptor owned here.
+    unsafe { libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC) };
+  }
+
+  let _ = io::stdout().flush();
+  let _ = io::stderr().flush();
+  if fork()? {
+    // The original process: wait for the server to report that it is
+    // ready, or for the pipe to close because it exited.
+    drop(writer);
+    let mut pid = String::new();
+    let _ = reader.read_to_string(&mut pid);
+    match pid.trim() {
+      "" => {
+        error!("The server failed to start; see its log for details");
+        std::process::exit(1);
+      }
+      pid => {
+        info!("Server running in the background (pid {})", pid);
+        std::process::exit(0);
+      }
+    }
+  }
+  drop(reader);
+
+  // SAFETY: `setsid` has no memory-safety preconditions.
+  if unsafe { libc::setsid() } < 0 {
+    return Err(format!(
+      "Failed to daemonize: {}",
+      io::Error::last_os_error()
+    ));
+  }
+  // A second fork, so the server isn't a session leader and can never
+  // acquire a controlling terminal.
+  if fork()? {
+    // SAFETY: exits the intermediate process without running Rust's
+    // shutdown code, which belongs to the server.
+    unsafe { libc::_exit(0) };
+  }
+  redirect(&null, &[libc::STDIN_FILENO])?;
+  redirect(
+    log.as_ref().unwrap_or(&null),
+    &[libc::STDOUT_FILENO, libc::STDERR_FILENO],
+  )?;
+  Ok(Ready { pipe: writer })
+}
+
+#[cfg(not(unix))]
+pub fn daemonize(_log_file: Option<&Path>) -> Result<Ready, String> {
+  Err("--daemon is not supported on this platform; run fyre as a service instead".to_string())
+}
+
+/// Forks, returning `true` in the parent.
+#[cfg(unix)]
+fn fork() -> Result<bool, String> {
+  // SAFETY: called while the process has a single thread.
+  match unsafe { libc::fork() } {
+    -1 => Err(format!(
+      "Failed to daemonize: {}",
+      io::Error::last_os_error()
+    )),
+    0 => Ok(false),
+    _ => Ok(true),
+  }
+}
+
+/// Points the standard descriptors `fds` at `file`.
+#[cfg(unix)]
+fn redirect(file: &fs::File, fds: &[libc::c_int]) -> Result<(), String> {
+  use std::os::fd::AsRawFd;
+
+  let _ = io::stdout().flush();
+  let _ = io::stderr().flush();
+  for &fd in fds {
+    // SAFETY: replaces a standard descriptor with a copy of an open file.
+    if unsafe { libc::dup2(file.as_raw_fd(), fd) } < 0 {
+      return Err(format!(
+        "Failed to redirect output: {}",
+        io::Error::last_os_error()
+      ));
+    }
+  }
+  Ok(())
+}
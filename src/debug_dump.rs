@@ -0,0 +1,519 @@
+//! # Debug Dumps
+//!
+//! `fyre serve --debug-dump /api/checkout` (or `CONFIG.debug.dump_routes`)
+//! writes each handler request for a matching path, and the response it
+//! got, to a file of its own under `CONFIG.debug.dump_dir` (`dumps` by
+//! default), for finding out what a client really sent. A pattern ending in
+//! `*` matches every path starting with the rest, and `--debug-dump` with
+//! no path matches them all.
+//!
+//! A file is named for the time and the request's `X-Request-Id` (or a
+//! random id), and the log says which request went to which file. It holds
+//! the request line, the headers, and the body, then the status line, the
+//! headers, and the body of the response, in the style of `curl -v`:
+//!
+//! ```text
+//! > POST /api/checkout HTTP/1.1
+//! > Content-Type: application/json
+//! > Authorization: [redacted]
+//! >
+//! {"cart": 42}
+//!
+//! < HTTP/1.1 502 Bad Gateway
+//! < Content-Type: text/plain
+//! <
+//! payment provider unavailable
+//! ```
+//!
+//! A body that isn't printable text is written as a hex dump, and only its
+//! first `CONFIG.limits.body` are kept. The `Server`, `Date`, and `Connection`
+//! headers the server adds as it writes the response aren't shown.
+//! `Authorization`, `Proxy-Authorization`, `Cookie`, and `Set-Cookie` are
+//! redacted unless `--debug-dump-unsafe` is given. Dumps are only ever taken
+//! while the warning logged at startup says so.
+
+use std::cell::RefCell;
+use std::fmt::Write as _;
+use std::fs::{self, OpenOptions};
+use std::io::{self, Read, Write};
+use std::path::PathBuf;
+use std::rc::Rc;
+use std::time::Duration;
+
+use mlua::prelude::*;
+use tiny_http::{HTTPVersion, Header, Response};
+
+use crate::{server, trace, HandlerResponse};
+
+/// The most of each body written to a dump.
+pub const MAX_BODY_BYTES: usize = 64 * 1024;
+/// The directory dumps are written to when `CONFIG.debug.dump_dir` is not set.
+const DEFAULT_DIR: &str = "dumps";
+/// The headers left out of a dump without `--debug-dump-unsafe`.
+const SENSITIVE_HEADERS: &[&str] = &[
+  "authorization",
+  "proxy-authorization",
+  "cookie",
+  "set-cookie",
+];
+/// The longest `X-Request-Id` put in a file name.
+const MAX_ID_CHARS: usize = 64;
+
+/// Which requests are dumped, and where, from the `DEBUG_DUMP_*` globals
+/// and `--debug-dump`.
+#[derive(Debug, Clone, Default)]
+pub struct Settings {
+  /// The path patterns; empty turns dumps off.
+  pub routes: Vec<String>,
+  pub dir: PathBuf,
+  /// Whether credentials are written as they were sent.
+  pub unsafe_headers: bool,
+}
+
+impl Settings {
+  /// Reads `CONFIG.debug.dump_routes` and `CONFIG.debug.dump_dir`. A relative
+  /// directory is left for the caller to resolve.
+  ///
+  /// # Errors
+  ///
+  /// Returns an error message if either has the wrong type, or a pattern
+  /// doesn't start with `/`.
+  pub fn from_globals(globals: &LuaTable) -> Result<Settings, String> {
+    let routes = globals
+      .get::<Option<Vec<String>>>("DEBUG_DUMP_ROUTES")
+      .map_err(|e| format!("CONFIG.debug.dump_routes must be a list of paths: {}", e))?
+      .unwrap_or_default();
+    if let Some(route) = routes.iter().find(|route| !route.starts_with('/')) {
+      return Err(format!(
+        "CONFIG.debug.dump_routes entry {:?} must start with /",
+        route
+      ));
+    }
+    let dir = globals
+      .get::<Option<String>>("DEBUG_DUMP_DIR")
+      .map_err(|e| format!("CONFIG.debug.dump_dir must be a directory path: {}", e))?
+      .unwrap_or_else(|| DEFAULT_DIR.to_string());
+    Ok(Settings {
+      routes,
+      dir: PathBuf::from(dir),
+      unsafe_headers: false,
+    })
+  }
+}
+
+/// Writes the dumps, in `AppState` while they are on.
+pub struct Dumper {
+  settings: Settings,
+}
+
+impl Dumper {
+  /// Creates the dump directory and warns that dumps are on.
+  ///
+  /// # Errors
+  ///
+  /// Returns an error message if the directory can't be created.
+  pub fn start(settings: Settings) -> Result<Dumper, String> {
+    let mut builder = fs::DirBuilder::new();
+    builder.recursive(true);
+    #[cfg(unix)]
+    std::os::unix::fs::DirBuilderExt::mode(&mut builder, 0o700);
+    builder.create(&settings.dir).map_err(|e| {
+      format!(
+        "Failed to create debug dump directory {}: {}",
+        settings.dir.display(),
+        e
+      )
+    })?;
+
+    warn!("******************************************************************");
+    warn!(
+      "DEBUG DUMPS ARE ON: every request for {} and its response are written to {}",
+      settings.routes.join(", "),
+      settings.dir.display()
+    );
+    if settings.unsafe_headers {
+      warn!("Credentials are NOT redacted (--debug-dump-unsafe)");
+    } else {
+      warn!("Authorization, Cookie, and Set-Cookie headers are redacted");
+    }
+    warn!("Turn dumps off before serving real traffic");
+    warn!("******************************************************************");
+    Ok(Dumper { settings })
+  }
+
+  /// Whether requests for `url` are dumped.
+  pub fn matches(&self, url: &str) -> bool {
+    let path = url.split('?').next().unwrap_or_default();
+    self
+      .settings
+      .routes
+      .iter()
+      .any(|pattern| match pattern.strip_suffix('*') {
+        Some(prefix) => path.starts_with(prefix),
+        None => path == pattern,
+      })
+  }
+
+  /// Starts a transcript of `request`, once its body has been read. Its
+  /// body is what `server::Request::capture_body` kept.
+  pub fn begin(&self, request: &mut server::Request) -> Transcript {
+    let mut out = String::new();
+    let version = request.http_version().clone();
+    let _ = writeln!(
+      out,
+      "> {} {} HTTP/{}.{}",
+      request.method(),
+      request.url(),
+      version.0,
+      version.1
+    );
+    self.write_headers(&mut out, '>', request.headers());
+    let (body, total) = request.captured_body().unwrap_or_default();
+    write_body(&mut out, &body, total);
+    Transcript {
+      id: request_id(request.headers()),
+      method: request.method().to_string(),
+      url: request.url().to_string(),
+      remote_addr: request.remote_addr().to_string(),
+      version,
+      request: out,
+      response_head: String::new(),
+      response_body: Rc::new(RefCell::new(Kept::default())),
+    }
+  }
+
+  /// Has `response` kept for `transcript` as it is written.
+  pub fn capture(&self, transcript: &mut Transcript, response: HandlerResponse) -> HandlerResponse {
+    let status = response.status_code();
+    let mut head = String::new();
+    let _ = writeln!(
+      head,
+      "< HTTP/{}.{} {} {}",
+      transcript.version.0,
+      transcript.version.1,
+      status.0,
+      status.default_reason_phrase()
+    );
+    self.write_headers(&mut head, '<', response.headers());
+    transcript.response_head = head;
+    let headers = response.headers().to_vec();
+    let length = response.data_length();
+    let reader: Box<dyn Read> = Box::new(Tee {
+      inner: response.into_reader(),
+      kept: transcript.response_body.clone(),
+    });
+    Response::new(status, headers, reader, length, None)
+  }
+
+  /// Writes `transcript` to a file of its own, and logs which.
+  pub fn finish(&self, transcript: Transcript, elapsed: Duration) {
+    let time = chrono::Utc::now();
+    let mut out = format!(
+      "# {} {} from {} at {}, answered in {:.1} ms\n\n",
+      transcript.method,
+      transcript.url,
+      transcript.remote_addr,
+      time.to_rfc3339_opts(chrono::SecondsFormat::Millis, true),
+      elapsed.as_secs_f64() * 1000.0
+    );
+    out.push_str(&transcript.request);
+    out.push('\n');
+    out.push_str(&transcript.response_head);
+    let body = transcript.response_body.borrow();
+    write_body(&mut out, &body.bytes, body.total);
+
+    let stem = format!("{}-{}", time.format("%Y%m%dT%H%M%S%.3fZ"), transcript.id);
+    let written = self
+      .create(&stem)
+      .or_else(|e| match e.kind() {
+        io::ErrorKind::AlreadyExists => self.create(&format!("{}-{}", stem, trace::random_id(4))),
+        _ => Err(e),
+      })
+      .and_then(|(path, mut file)| file.write_all(out.as_bytes()).map(|()| path));
+    match written {
+      Ok(path) => info!(
+        "Debug dump of {} {} (request {}): {}",
+        transcript.method,
+        transcript.url,
+        transcript.id,
+        path.display()
+      ),
+      Err(e) => error!(
+        "Failed to write debug dump of {} {} (request {}): {}",
+        transcript.method, transcript.url, transcript.id, e
+      ),
+    }
+  }
+
+  /// Creates the dump file `<stem>.txt`, readable by the server's user
+  /// alone.
+  fn create(&self, stem: &str) -> io::Result<(PathBuf, fs::File)> {
+    let path = self.settings.dir.join(format!("{}.txt", stem));
+    let mut options = OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    options.open(&path).map(|file| (path, file))
+  }
+
+  fn write_headers(&self, out: &mut String, prefix: char, headers: &[Header]) {
+    for header in headers {
+      let name = header.field.as_str().as_str();
+      let sensitive = SENSITIVE_HEADERS
+        .iter()
+        .any(|sensitive| name.eq_ignore_ascii_case(sensitive));
+      let value = if sensitive && !self.settings.unsafe_headers {
+        "[redacted]"
+      } else {
+        header.value.as_str()
+      };
+      let _ = writeln!(out, "{} {}: {}", prefix, name, value);
+    }
+    let _ = writeln!(out, "{}", prefix);
+  }
+}
+
+/// A request and its response, written by `Dumper::finish`.
+pub struct Transcript {
+  /// The `X-Request-Id`, or a random id.
+  id: String,
+  method: String,
+  url: String,
+  remote_addr: String,
+  version: HTTPVersion,
+  /// The request line, headers, and body, as they are written.
+  request: String,
+  /// The response's status line and headers, as they are written.
+  response_head: String,
+  /// The response body, as it is written.
+  response_body: Rc<RefCell<Kept>>,
+}
+
+/// The first `CONFIG.limits.body` of a response body, and its length.
+#[derive(Default)]
+struct Kept {
+  bytes: Vec<u8>,
+  total: u64,
+}
+
+/// A response body that is kept as it is read.
+struct Tee {
+  inner: Box<dyn Read>,
+  kept: Rc<RefCell<Kept>>,
+}
+
+impl Read for Tee {
+  fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+    let n = self.inner.read(buf)?;
+    let mut kept = self.kept.borrow_mut();
+    let room = MAX_BODY_BYTES.saturating_sub(kept.bytes.len());
+    kept.bytes.extend_from_slice(&buf[..n.min(room)]);
+    kept.total += n as u64;
+    Ok(n)
+  }
+}
+
+/// The request's `X-Request-Id`, if it is safe in a file name, or a random
+/// id.
+fn request_id(headers: &[Header]) -> String {
+  headers
+    .iter()
+    .find(|header| header.field.equiv("X-Request-Id"))
+    .map(|header| header.value.as_str())
+    .filter(|id| {
+      !id.is_empty()
+        && id.len() <= MAX_ID_CHARS
+        && id
+          .bytes()
+          .all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_')
+    })
+    .map_or_else(|| trace::random_id(8), str::to_string)
+}
+
+/// Appends `body`, the start of one `total` bytes long, as text if it is
+/// printable and as a hex dump if not.
+fn write_body(out: &mut String, body: &[u8], total: u64) {
+  if total == 0 {
+    out.push_str("(no body)\n");
+    return;
+  }
+  let body = &body[..body.len().min(MAX_BODY_BYTES)];
+  let text = std::str::from_utf8(body).ok().filter(|text| {
+    !text
+      .chars()
+      .any(|c| c.is_control() && !matches!(c, '\n' | '\r' | '\t'))
+  });
+  match text {
+    Some(text) => {
+      out.push_str(text);
+      if !text.ends_with('\n') {
+        out.push('\n');
+      }
+    }
+    None => {
+      for (line, chunk) in body.chunks(16).enumerate() {
+        let _ = write!(out, "{:08x} ", line * 16);
+        for i in 0..16 {
+          match chunk.get(i) {
+            Some(byte) => {
+              let _ = write!(out, " {:02x}", byte);
+            }
+            None => out.push_str("   "),
+          }
+        }
+        let printable: String = chunk
+          .iter()
+          .map(|&b| {
+            if b.is_ascii_graphic() || b == b' ' {
+              b as char
+            } else {
+              '.'
+            }
+          })
+          .collect();
+        let _ = writeln!(out, "  |{}|", printable);
+      }
+    }
+  }
+  if total > body.len() as u64 {
+    let _ = writeln!(
+      out,
+      "({} bytes in all; the first {} are shown)",
+      total,
+      body.len()
+    );
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::testing::{self, Fixture};
+
+  fn body(bytes: &[u8], total: u64) -> String {
+    let mut out = String::new();
+    write_body(&mut out, bytes, total);
+    out
+  }
+
+  #[test]
+  fn bodies_are_text_or_a_hex_dump() {
+    assert_eq!(body(b"", 0), "(no body)\n");
+    assert_eq!(body(b"{\"cart\": 42}", 12), "{\"cart\": 42}\n");
+    assert_eq!(
+      body(b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR\0\0", 18),
+      "00000000  89 50 4e 47 0d 0a 1a 0a 00 00 00 0d 49 48 44 52  |.PNG........IHDR|\n\
+       00000010  00 00                                            |..|\n"
+    );
+    assert_eq!(
+      body(b"abc", 10),
+      "abc\n(10 bytes in all; the first 3 are shown)\n"
+    );
+  }
+
+  #[test]
+  fn only_safe_request_ids_name_files() {
+    let id = |value: &str| request_id(&[Header::from_bytes("X-Request-Id", value).unwrap()]);
+    assert_eq!(id("7f3a-b2_c9"), "7f3a-b2_c9");
+    for unsafe_id in ["../../etc/passwd", "a b", &"x".repeat(MAX_ID_CHARS + 1)] {
+      let random = id(unsafe_id);
+      assert_ne!(random, unsafe_id);
+      assert!(
+        random.bytes().all(|b| b.is_ascii_alphanumeric()),
+        "{}",
+        random
+      );
+    }
+  }
+
+  #[test]
+  fn settings_and_patterns() {
+    let lua = Lua::new();
+    lua
+      .load(r#"DEBUG_DUMP_ROUTES = { "/api/checkout", "/hooks/*" }"#)
+      .exec()
+      .unwrap();
+    let settings = Settings::from_globals(&lua.globals()).unwrap();
+    assert_eq!(settings.dir, PathBuf::from(DEFAULT_DIR));
+    let dumper = Dumper { settings };
+    for (url, matches) in [
+      ("/api/checkout", true),
+      ("/api/checkout?retry=1", true),
+      ("/api/checkout/2", false),
+      ("/hooks/stripe", true),
+      ("/hooks", false),
+    ] {
+      assert_eq!(dumper.matches(url), matches, "{}", url);
+    }
+
+    lua
+      .load(r#"DEBUG_DUMP_ROUTES = { "api/checkout" }"#)
+      .exec()
+      .unwrap();
+    assert!(Settings::from_globals(&lua.globals()).is_err());
+  }
+
+  #[test]
+  fn matching_requests_are_written_with_credentials_redacted() {
+    let fixture = Fixture::new(
+      r#"router.add("/api/checkout", "checkout.lua")"#,
+      &[(
+        "checkout.lua",
+        r#"return { handler = function(request, response)
+          response.status = 502
+          response.headers["Set-Cookie"] = "session=abc"
+          response.body = "payment provider unavailable"
+        end }"#,
+      )],
+    );
+    let server = fixture
+      .builder()
+      .addr("127.0.0.1:0")
+      .workers(1)
+      .debug_dump("/api/*")
+      .load()
+      .unwrap();
+    let addr = server.serve().unwrap().remove(0);
+    let response = testing::send(
+      &addr,
+      "POST /api/checkout HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\
+       Authorization: Bearer secret-token\r\nX-Request-Id: req-42\r\n\
+       Content-Length: 12\r\n\r\n{\"cart\": 42}",
+    );
+    assert!(response.starts_with("HTTP/1.1 502"), "{}", response);
+    server.shutdown();
+
+    let files: Vec<_> = fs::read_dir(fixture.path().join("dumps"))
+      .unwrap()
+      .map(|entry| entry.unwrap().path())
+      .collect();
+    assert_eq!(files.len(), 1);
+    assert!(files[0].to_str().unwrap().ends_with("-req-42.txt"));
+    let dump = fs::read_to_string(&files[0]).unwrap();
+    assert!(
+      dump.starts_with("# POST /api/checkout from 127.0.0.1:"),
+      "{}",
+      dump
+    );
+    for expected in [
+      "> POST /api/checkout HTTP/1.1\n",
+      "> Authorization: [redacted]\n",
+      "\n{\"cart\": 42}\n",
+      "< HTTP/1.1 502 Bad Gateway\n",
+      "< Set-Cookie: [redacted]\n",
+      "<\npayment provider unavailable\n",
+    ] {
+      // The async backend writes header names in lowercase.
+      assert!(
+        dump
+          .to_ascii_lowercase()
+          .contains(&expected.to_ascii_lowercase()),
+        "{:?} in {}",
+        expected,
+        dump
+      );
+    }
+    assert!(!dump.contains("secret-token"));
+    assert!(!dump.contains("session=abc"));
+  }
+}
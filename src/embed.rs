@@ -0,0 +1,1022 @@
+//! # Embedding
+//!
+//! The server as a library, to run it inside a larger program or call its
+//! routes from integration tests:
+//!
+//! ```no_run
+//! use scriptable_server::{FyreServer, SyntheticRequest};
+//!
+//! let server = FyreServer::builder()
+//!   .config_file("config.lua")
+//!   .scripts_dir("scripts")
+//!   .addr("127.0.0.1:0")
+//!   .load()?;
+//! for route in server.routes().routes {
+//!   println!("{} -> {}", route.path, route.script);
+//! }
+//!
+//! // In-process, without a listener:
+//! let response = server.handle(SyntheticRequest::get("/hello"));
+//! assert_eq!(response.status, 200);
+//!
+//! // Over the network:
+//! let addrs = server.serve()?;
+//! println!("Listening on {:?}", addrs);
+//! server.shutdown();
+//! # Ok::<(), scriptable_server::Error>(())
+//! ```
+//!
+//! `load` does everything `fyre serve` does before it listens: it runs
+//! `config.lua` and compiles every handler script, so a broken
+//! configuration is an `Error` rather than a failing request. Requests
+//! passed to `handle` go through the same pipeline as those from the
+//! network. `fyre serve` itself is built on this (see `serve`).
+
+use arc_swap::ArcSwap;
+use std::fmt;
+use std::fs;
+use std::path::PathBuf;
+use std::sync::{mpsc, Arc, Mutex};
+use std::time::Duration;
+use tiny_http::{Header, Method};
+
+use crate::net::Listener;
+use crate::{
+  admin, audit, auth, check_scripts, cli, debug_dump, error_reports, fyre, handle_request, health,
+  limiter, load_lua_config, locks, logger, lua_pool, net, panics, paths, request_log,
+  request_metrics, route_stats, route_summary, schedule, script_cache, server, shutdown, slow_log,
+  span_export, statics, tls, worker_stats, AppState, BindCheck, PipelineError, RouteTable,
+  RoutesMap, DEFAULT_SERVER_ADDR,
+};
+
+/// Why a server couldn't be loaded or started.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Error {
+  /// The configuration didn't load: a file is missing, `config.lua` failed
+  /// to run, or a setting is invalid.
+  Config(String),
+  /// A handler script doesn't compile, with `lua.script_check = "strict"`.
+  Script(String),
+  /// The server couldn't start listening, or was already started.
+  Start(String),
+}
+
+impl fmt::Display for Error {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      Error::Config(e) => write!(f, "Failed to load configuration: {}", e),
+      Error::Script(e) => write!(f, "Failed to compile handler scripts: {}", e),
+      Error::Start(e) => write!(f, "Could not start server: {}", e),
+    }
+  }
+}
+
+impl std::error::Error for Error {}
+
+/// Logs a configuration error as `fyre serve` always has, and returns it.
+fn config_error(e: impl fmt::Display) -> Error {
+  error!("Failed to load configuration: {}", e);
+  Error::Config(e.to_string())
+}
+
+/// Where to load a server from, created with `FyreServer::builder`.
+#[derive(Debug, Clone, Default)]
+pub struct Builder {
+  config_file: Option<PathBuf>,
+  scripts_dir: Option<PathBuf>,
+  env: Option<String>,
+  addrs: Vec<String>,
+  workers: Option<usize>,
+  quiet: bool,
+  dump_routes: Vec<String>,
+  dump_unsafe: bool,
+}
+
+impl Builder {
+  /// The configuration script, `config.lua` in the working directory by
+  /// default.
+  pub fn config_file(mut self, path: impl Into<PathBuf>) -> Self {
+    self.config_file = Some(path.into());
+    self
+  }
+
+  /// The handler script directory, `scripts` next to the configuration
+  /// script by default.
+  pub fn scripts_dir(mut self, path: impl Into<PathBuf>) -> Self {
+    self.scripts_dir = Some(path.into());
+    self
+  }
+
+  /// The environment, which selects the `config.<env>.lua` file run after
+  /// the configuration script; `development` by default.
+  pub fn env(mut self, name: impl Into<String>) -> Self {
+    self.env = Some(name.into());
+    self
+  }
+
+  /// An address for `serve` to listen on, `host:port` or
+  /// `unix:/path/to.sock`; call it again for several. Overrides
+  /// `CONFIG.addr` and `CONFIG.addrs`.
+  pub fn addr(mut self, addr: impl Into<String>) -> Self {
+    self.addrs.push(addr.into());
+    self
+  }
+
+  /// The number of worker threads `serve` starts. Overrides
+  /// `CONFIG.workers`.
+  pub fn workers(mut self, workers: usize) -> Self {
+    self.workers = Some(workers);
+    self
+  }
+
+  /// Leaves out the route summary `load` logs (see `route_summary`), and
+  /// the startup banner `serve` logs: the version, the environment, the
+  /// number of routes, and the addresses listened on. Errors and warnings
+  /// are still logged.
+  pub fn quiet(mut self, quiet: bool) -> Self {
+    self.quiet = quiet;
+    self
+  }
+
+  /// A path whose requests and responses are written to files under
+  /// `CONFIG.debug.dump_dir`; a path ending in `*` matches every path
+  /// starting with the rest. Call it again for several. Overrides
+  /// `CONFIG.debug.dump_routes`.
+  pub fn debug_dump(mut self, path: impl Into<String>) -> Self {
+    self.dump_routes.push(path.into());
+    self
+  }
+
+  /// Writes credentials to debug dumps as they were sent, rather than
+  /// redacted.
+  pub fn debug_dump_unsafe(mut self, unsafe_headers: bool) -> Self {
+    self.dump_unsafe = unsafe_headers;
+    self
+  }
+
+  /// Runs the configuration script and compiles every handler script.
+  ///
+  /// # Errors
+  ///
+  /// This function will return `Error::Config` if the configuration script or
+  /// scripts directory doesn't exist, the script fails to run, or a setting is
+  /// invalid (see `load_lua_config`), and `Error::Script` if a handler script
+  /// doesn't compile and `CONFIG.lua.script_check` is `"strict"`.
+  pub fn load(self) -> Result<FyreServer, Error> {
+    let started = std::time::Instant::now();
+    let args = cli::ConfigArgs {
+      config: self
+        .config_file
+        .unwrap_or_else(|| PathBuf::from(cli::DEFAULT_CONFIG_FILE)),
+      scripts: self.scripts_dir,
+      env: self.env.unwrap_or_else(|| cli::DEFAULT_ENV.to_string()),
+    };
+    let paths = paths::Paths::new(&args).map_err(config_error)?;
+    panics::install();
+    info!("Environment: {}", paths.env());
+    for addr in &self.addrs {
+      net::validate_addr(addr).map_err(config_error)?;
+    }
+    if let Some(workers) = self.workers {
+      if !(1..=worker_stats::MAX_WORKERS).contains(&workers) {
+        return Err(config_error(format!(
+          "workers must be between 1 and {}",
+          worker_stats::MAX_WORKERS
+        )));
+      }
+    }
+
+    let routes: RoutesMap = Arc::new(ArcSwap::from_pointee(RouteTable::default()));
+    let config = load_lua_config(routes.clone(), &paths).map_err(config_error)?;
+    logger::set_format(config.log_format);
+    if let Some(filter) = config.log_level.clone() {
+      logger::set_config_filter(filter);
+    }
+    info!(
+      "Successfully loaded routes from {}",
+      paths.config_file().display()
+    );
+    if !config.included.is_empty() {
+      info!("Included config files: {:?}", config.included);
+    }
+    let mut addrs = self.addrs;
+    if addrs.is_empty() && !config.server_addrs.is_empty() {
+      addrs = config.server_addrs.clone();
+      info!("Server address set by config.lua: {}", addrs.join(", "));
+    }
+    if addrs.is_empty() {
+      addrs.push(DEFAULT_SERVER_ADDR.to_string());
+    }
+
+    let tls_config = config
+      .tls
+      .clone()
+      .map(|settings| tls::ServerTls::new(settings).map(Arc::new))
+      .transpose()
+      .map_err(config_error)?;
+
+    let fs = fyre::fs::FsSandbox::new(
+      &config.fs_allow,
+      paths.base(),
+      config
+        .fs_max_read_bytes
+        .unwrap_or(fyre::fs::DEFAULT_MAX_READ_BYTES),
+    )
+    .map_err(config_error)?;
+
+    let queues = fyre::queue::Queues::new(config.queue_workers, config.queue_dir.as_deref())
+      .map_err(config_error)?;
+
+    let mail = config
+      .smtp
+      .map(fyre::mail::Mailer::new)
+      .transpose()
+      .map_err(config_error)?;
+
+    let workers = self.workers.or(config.workers).unwrap_or_else(|| {
+      std::thread::available_parallelism()
+        .map(|n| n.get())
+        .unwrap_or(1)
+        .min(worker_stats::MAX_WORKERS)
+    });
+
+    let audit = match config.audit {
+      Some(destination) => Some(destination),
+      None => config.admin.is_some().then_some(audit::Destination::Log),
+    }
+    .map(audit::Audit::start)
+    .transpose()
+    .map_err(config_error)?;
+    let span_export = config
+      .tracing
+      .map(|settings| span_export::Exporter::start(settings).map(Arc::new))
+      .transpose()
+      .map_err(config_error)?;
+    let error_reports = config
+      .error_reporting
+      .map(error_reports::Reporter::start)
+      .transpose()
+      .map_err(config_error)?;
+    let mut dump_settings = config.debug_dump;
+    if !self.dump_routes.is_empty() {
+      dump_settings.routes = self.dump_routes;
+    }
+    dump_settings.unsafe_headers = self.dump_unsafe;
+    if self.dump_unsafe && dump_settings.routes.is_empty() {
+      warn!("--debug-dump-unsafe has no effect without --debug-dump or CONFIG.debug.dump_routes");
+    }
+    let debug_dump = (!dump_settings.routes.is_empty())
+      .then(|| debug_dump::Dumper::start(dump_settings))
+      .transpose()
+      .map_err(config_error)?;
+
+    let admin_addr = config.admin.as_ref().and_then(|a| a.addr.clone());
+    let state = Arc::new(AppState {
+      env: Arc::new(fyre::env::EnvAccess::new(config.env_allowlist)),
+      http: fyre::http::HttpClient::new(config.http_allow, config.http_allow_private),
+      kv: fyre::kv::KvStore::new(
+        config
+          .kv_max_entries
+          .unwrap_or(fyre::kv::DEFAULT_MAX_ENTRIES),
+      ),
+      cache: fyre::cache::Cache::new(
+        config
+          .cache_max_entries
+          .unwrap_or(fyre::kv::DEFAULT_MAX_ENTRIES),
+      ),
+      sqlite: fyre::sqlite::SqlitePool::new(
+        config
+          .sqlite_dir
+          .unwrap_or_else(|| paths.resolve_string(fyre::sqlite::DEFAULT_DATA_DIR)),
+      ),
+      fs,
+      session: config.session,
+      cookie_keys: config.cookie_keys,
+      redis: fyre::redis::RedisPools::new(
+        config.redis_url,
+        config
+          .redis_pool_size
+          .unwrap_or(fyre::redis::DEFAULT_POOL_SIZE),
+        config
+          .redis_timeout_ms
+          .map(Duration::from_millis)
+          .unwrap_or(fyre::redis::DEFAULT_TIMEOUT),
+      ),
+      exec: fyre::exec::ExecPolicy::new(config.exec_allow),
+      metrics: fyre::metrics::Registry::new(
+        config
+          .metrics_max_series
+          .unwrap_or(fyre::metrics::DEFAULT_MAX_SERIES),
+      ),
+      mail,
+      secrets: config.secrets,
+      ratelimit: fyre::ratelimit::RateLimiter::default(),
+      rate_limit: config.rate_limit,
+      client_limits: fyre::ratelimit::RateLimiter::default(),
+      api_key_usage: auth::api_key::Usage::default(),
+      scripts: script_cache::ScriptCache::new(
+        config.bytecode_cache,
+        config.bytecode_cache_dir,
+        config
+          .cache_max_bytes
+          .unwrap_or(script_cache::DEFAULT_MAX_BYTES),
+      ),
+      instruction_limit: config.instruction_limit,
+      header_check: config.header_check,
+      connections: Arc::new(server::ConnectionStats::default()),
+      body_spill: config.body_spill,
+      in_flight: limiter::Limiter::new(config.in_flight),
+      access: config.access,
+      access_log: config.access_log,
+      allowed_hosts: config.allowed_hosts,
+      routes: routes.clone(),
+      files: statics::FileCache::new(
+        config
+          .static_mmap_entries
+          .unwrap_or(statics::DEFAULT_MMAP_ENTRIES),
+        config
+          .static_mmap_max_bytes
+          .unwrap_or(statics::DEFAULT_MMAP_MAX_BYTES),
+      ),
+      queues,
+      workers: worker_stats::WorkerStats::new(workers),
+      slow_log: slow_log::SlowLog::new(config.slow_request_ms.unwrap_or(0)),
+      request_metrics: Arc::new(request_metrics::RequestMetrics::new(config.metrics_endpoint)),
+      route_stats: route_stats::RouteStats::default(),
+      span_export,
+      error_reports,
+      debug_dump,
+      addrs: ArcSwap::from_pointee(Vec::new()),
+      server_header: config.server_header,
+      security_headers: config.security_headers,
+      admin: config
+        .admin
+        .map(|settings| admin::Admin::new(settings, paths.clone(), config.effective, workers)),
+      audit,
+    });
+
+    let checked = check_scripts(&routes.load_full(), &state.scripts, config.script_check);
+    if !self.quiet {
+      let summary = route_summary::lines(&routes.load(), config.schedules.len(), started.elapsed());
+      for line in summary {
+        info!("{}", line);
+      }
+    }
+    if let Err(e) = checked {
+      error!("Failed to load configuration: {}", e);
+      return Err(Error::Script(e.to_string()));
+    }
+
+    Ok(FyreServer {
+      state,
+      addrs,
+      workers,
+      bind_check: config.bind_check,
+      socket: config.socket,
+      connections: config.connections,
+      tls: tls_config,
+      lua_state_max_uses: config
+        .lua_state_max_uses
+        .unwrap_or(lua_pool::DEFAULT_MAX_USES),
+      grace: config
+        .shutdown_grace_ms
+        .map(Duration::from_millis)
+        .unwrap_or(shutdown::DEFAULT_GRACE_PERIOD),
+      on_shutdown: config.on_shutdown,
+      redirect_http: config.tls.and_then(|t| t.redirect_http),
+      admin_addr,
+      pid_file: config.pid_file,
+      run_as: config.run_as,
+      log_file: config.log_file,
+      log_rotate: config.log_rotate,
+      health: Arc::new(health::Health::new(config.health)),
+      request_log: config.request_log.map(Arc::new),
+      env: paths.env().to_string(),
+      quiet: self.quiet,
+      lifecycle: Mutex::new(Lifecycle::Loaded(config.schedules)),
+    })
+  }
+}
+
+/// A loaded server: its configuration has run and its handler scripts have
+/// compiled. It answers requests passed to `handle` right away, and from
+/// the network once `serve` is called.
+pub struct FyreServer {
+  pub(crate) state: Arc<AppState>,
+  /// What `serve` listens on.
+  pub(crate) addrs: Vec<String>,
+  workers: usize,
+  pub(crate) bind_check: BindCheck,
+  pub(crate) socket: net::SocketOptions,
+  pub(crate) connections: server::Limits,
+  pub(crate) tls: Option<Arc<tls::ServerTls>>,
+  lua_state_max_uses: u32,
+  /// How long running requests may take to finish at shutdown.
+  grace: Duration,
+  on_shutdown: Option<String>,
+  health: Arc<health::Health>,
+  /// Where each request is logged; `None` when the log is off.
+  request_log: Option<Arc<request_log::RequestLog>>,
+  env: String,
+  /// Whether `serve` leaves out the startup banner.
+  quiet: bool,
+  // The settings only `fyre serve` uses, since they concern the process
+  // rather than the server.
+  pub(crate) redirect_http: Option<String>,
+  pub(crate) admin_addr: Option<String>,
+  pub(crate) pid_file: Option<PathBuf>,
+  pub(crate) run_as: Option<crate::privileges::RunAs>,
+  pub(crate) log_file: Option<PathBuf>,
+  pub(crate) log_rotate: Option<crate::logger::Rotate>,
+  lifecycle: Mutex<Lifecycle>,
+}
+
+/// Whether a server has been started. A server is only started once.
+enum Lifecycle {
+  /// Not yet, with the scheduled tasks to start with it.
+  Loaded(Vec<schedule::Task>),
+  Serving(Running),
+  Stopped,
+}
+
+/// A started server's workers.
+struct Running {
+  server: Arc<server::Server>,
+  /// Receives a message as each worker thread exits.
+  exited: mpsc::Receiver<()>,
+  /// The Unix socket files to remove at shutdown.
+  sockets: Vec<PathBuf>,
+}
+
+/// A request for `FyreServer::handle`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SyntheticRequest {
+  /// The method, e.g. `"GET"`.
+  pub method: String,
+  /// The request target, including any query string.
+  pub path: String,
+  pub headers: Vec<(String, String)>,
+  pub body: Vec<u8>,
+}
+
+impl SyntheticRequest {
+  /// A request with no headers and an empty body.
+  pub fn new(method: impl Into<String>, path: impl Into<String>) -> Self {
+    SyntheticRequest {
+      method: method.into(),
+      path: path.into(),
+      headers: Vec::new(),
+      body: Vec::new(),
+    }
+  }
+
+  pub fn get(path: impl Into<String>) -> Self {
+    Self::new("GET", path)
+  }
+
+  pub fn post(path: impl Into<String>, body: impl Into<Vec<u8>>) -> Self {
+    Self::new("POST", path).body(body)
+  }
+
+  /// Adds a header, after any already added.
+  pub fn header(mut self, name: impl Into<String>, value: impl Into<String>) -> Self {
+    self.headers.push((name.into(), value.into()));
+    self
+  }
+
+  pub fn body(mut self, body: impl Into<Vec<u8>>) -> Self {
+    self.body = body.into();
+    self
+  }
+}
+
+/// The response to a `SyntheticRequest`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SyntheticResponse {
+  pub status: u16,
+  pub headers: Vec<(String, String)>,
+  pub body: Vec<u8>,
+  /// Why the handler failed, when the response is the `500` (or `503`)
+  /// sent in its place.
+  pub error: Option<PipelineError>,
+}
+
+impl SyntheticResponse {
+  /// Returns the last value of the header `name`, ignoring case.
+  pub fn header(&self, name: &str) -> Option<&str> {
+    self
+      .headers
+      .iter()
+      .rev()
+      .find(|(field, _)| field.eq_ignore_ascii_case(name))
+      .map(|(_, value)| value.as_str())
+  }
+
+  /// Returns the body as text, replacing invalid UTF-8.
+  pub fn text(&self) -> String {
+    String::from_utf8_lossy(&self.body).into_owned()
+  }
+}
+
+/// The routes and static directories a server has, from
+/// `FyreServer::routes`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RouteSnapshot {
+  /// The routes added with `router.add`, sorted by path.
+  pub routes: Vec<RouteInfo>,
+  /// The `router.static` directories, longest prefix first, as
+  /// `fyre routes` lists them.
+  pub static_dirs: Vec<String>,
+}
+
+/// A route added with `router.add`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RouteInfo {
+  pub path: String,
+  /// The handler script, including the scripts directory.
+  pub script: String,
+  /// Why the script failed to compile, with `lua.script_check = "lenient"`;
+  /// the route answers `503`.
+  pub compile_error: Option<String>,
+}
+
+impl FyreServer {
+  pub fn builder() -> Builder {
+    Builder::default()
+  }
+
+  /// Returns the routes and static directories, as reloaded by the admin
+  /// endpoints.
+  pub fn routes(&self) -> RouteSnapshot {
+    let table = self.state.routes.load();
+    let mut routes: Vec<RouteInfo> = table
+      .handlers
+      .iter()
+      .map(|(path, route)| RouteInfo {
+        path: path.clone(),
+        script: route.script.clone(),
+        compile_error: route.compile_error.get().cloned(),
+      })
+      .collect();
+    routes.sort_by(|a, b| a.path.cmp(&b.path));
+    RouteSnapshot {
+      routes,
+      static_dirs: table.mounts.iter().map(statics::describe).collect(),
+    }
+  }
+
+  /// Answers `request` in the calling thread, as a worker would answer it
+  /// from the network, but in a new Lua state each time. It comes from
+  /// `127.0.0.1` over plain HTTP, and counts toward the concurrency limits
+  /// but not the worker statistics.
+  pub fn handle(&self, request: SyntheticRequest) -> SyntheticResponse {
+    let Ok(method) = request.method.parse::<Method>() else {
+      return SyntheticResponse {
+        status: 400,
+        headers: Vec::new(),
+        body: b"400 Bad Request".to_vec(),
+        error: None,
+      };
+    };
+    let mut headers: Vec<Header> = Vec::new();
+    for (name, value) in &request.headers {
+      match Header::from_bytes(name.as_bytes(), value.as_bytes()) {
+        Ok(header) => headers.push(header),
+        Err(()) => warn!("Invalid header skipped: {}: {}", name, value),
+      }
+    }
+    let has_length = headers.iter().any(|h| h.field.equiv("Content-Length"));
+    if !request.body.is_empty() && !has_length {
+      let length = request.body.len().to_string();
+      if let Ok(header) = Header::from_bytes("Content-Length", length) {
+        headers.push(header);
+      }
+    }
+
+    let (reply, replies) = mpsc::channel();
+    let path = request.path.clone();
+    let request = server::Request::local(method, request.path, headers, request.body, reply);
+    let mut pool = lua_pool::LuaPool::new(&self.state, 1);
+    // One past the last worker, so it isn't counted as any of them.
+    let handled = panics::catch(|| handle_request(self.workers, request, &self.state, &mut pool));
+    let error = match handled {
+      Ok(error) => error,
+      Err(panic) => {
+        error!("Panic while handling {}: {}", path, panic);
+        panic.log_backtrace(self.workers);
+        Some(PipelineError::Panicked(panic.to_string()))
+      }
+    };
+    match replies.try_recv() {
+      Ok(response) => SyntheticResponse {
+        status: response.status,
+        headers: response
+          .headers
+          .iter()
+          .map(|h| (h.field.as_str().to_string(), h.value.to_string()))
+          .collect(),
+        body: response.body,
+        error,
+      },
+      Err(_) => SyntheticResponse {
+        status: 500,
+        headers: Vec::new(),
+        body: b"500 Internal Server Error".to_vec(),
+        error,
+      },
+    }
+  }
+
+  /// Listens on the server's addresses and starts its worker threads,
+  /// queue workers, and scheduled tasks, then returns. Returns the
+  /// addresses listened on, with port `0` replaced by the port chosen.
+  ///
+  /// # Errors
+  ///
+  /// This function will return `Error::Start` if an address can't be bound and
+  /// `CONFIG.bind_check` is `"strict"`, none of them can, a thread can't be
+  /// started, or the server has already been started.
+  pub fn serve(&self) -> Result<Vec<String>, Error> {
+    let mut listeners = Vec::new();
+    let mut bound_addrs = Vec::new();
+    for addr in &self.addrs {
+      match net::listen(addr, &self.socket) {
+        Ok(listener) => {
+          listeners.push(listener);
+          bound_addrs.push(addr.clone());
+        }
+        Err(e) if self.bind_check == BindCheck::Lenient => {
+          warn!("Could not listen on {}: {}", addr, e);
+        }
+        Err(e) => return Err(Error::Start(format!("{}: {}", addr, e))),
+      }
+    }
+    if listeners.is_empty() {
+      return Err(Error::Start(
+        "none of the addresses could be bound".to_string(),
+      ));
+    }
+    self.start(listeners, &bound_addrs, true)
+  }
+
+  /// Starts serving `listeners`, which were bound for `bound_addrs`. Their
+  /// Unix socket files are removed at shutdown if `remove_sockets` is set.
+  pub(crate) fn start(
+    &self,
+    listeners: Vec<Listener>,
+    bound_addrs: &[String],
+    remove_sockets: bool,
+  ) -> Result<Vec<String>, Error> {
+    let mut lifecycle = locks::lock(&self.lifecycle, "server lifecycle");
+    let schedules = match &mut *lifecycle {
+      Lifecycle::Loaded(schedules) => std::mem::take(schedules),
+      _ => return Err(Error::Start("the server was already started".to_string())),
+    };
+    *lifecycle = Lifecycle::Stopped;
+
+    // What was bound, which differs from what was asked for with port 0 or
+    // a host name.
+    let local_addrs: Vec<String> = listeners
+      .iter()
+      .zip(bound_addrs)
+      .map(|(listener, addr)| listener.local_addr().unwrap_or_else(|_| addr.clone()))
+      .collect();
+    self.state.addrs.store(Arc::new(local_addrs.clone()));
+
+    if let Err(e) = fyre::queue::start_workers(&self.state) {
+      error!("Failed to start queue workers: {}", e);
+      return Err(Error::Start(e));
+    }
+    if !schedules.is_empty() {
+      info!(
+        "Registered Schedules: {:?}",
+        schedules
+          .iter()
+          .map(|task| format!("{} ({})", task.script, task.timing))
+          .collect::<Vec<_>>()
+      );
+    }
+    if let Err(e) = schedule::start(&self.state, schedules) {
+      error!("Failed to start scheduler: {}", e);
+      return Err(Error::Start(e));
+    }
+
+    let server = server::Server::start(
+      listeners,
+      self.connections.clone(),
+      self.state.connections.clone(),
+      self.tls.clone(),
+      Some(self.health.clone()),
+      self.state.server_header.clone(),
+      self.request_log.clone(),
+    )
+    .map_err(|e| Error::Start(e.to_string()))?;
+    let scheme = if self.tls.is_some() { "https" } else { "http" };
+    if !self.quiet {
+      info!(
+        "fyre {} ({}), {} route(s)",
+        crate::VERSION,
+        self.env,
+        self.state.routes.load().handlers.len()
+      );
+      for (addr, local_addr) in bound_addrs.iter().zip(&local_addrs) {
+        if addr == local_addr {
+          info!("Server running at {}://{}", scheme, local_addr);
+        } else {
+          info!(
+            "Server running at {}://{} (for {})",
+            scheme, local_addr, addr
+          );
+        }
+      }
+    }
+
+    let server = Arc::new(server);
+    info!("Handling requests on {} worker thread(s)", self.workers);
+    let (exited_tx, exited) = mpsc::channel();
+    for id in 0..self.workers {
+      let server = server.clone();
+      let state = self.state.clone();
+      let exit_guard = shutdown::ExitGuard(exited_tx.clone());
+      let lua_state_max_uses = self.lua_state_max_uses;
+      std::thread::Builder::new()
+        .name(format!("http-{}", id))
+        .spawn(move || {
+          let _exit_guard = exit_guard;
+          let mut pool = lua_pool::LuaPool::new(&state, lua_state_max_uses);
+          // Request Loop
+          while let Some(request) = server.recv() {
+            let route = request.url().to_string();
+            let _busy = state.workers.begin(id, &route);
+            // A panic in the pipeline is answered inside `handle_request`;
+            // this catches one anywhere else, which loses the response.
+            let handled = panics::catch(|| handle_request(id, request, &state, &mut pool));
+            if let Err(panic) = handled {
+              error!("[worker {}] Panic while handling {}: {}", id, route, panic);
+              panic.log_backtrace(id);
+              pool = lua_pool::LuaPool::new(&state, lua_state_max_uses);
+              state.workers.restarted(id);
+              let label = state
+                .request_metrics
+                .route_label(&route, &state.routes.load());
+              state
+                .request_metrics
+                .record_panic(label.as_deref().unwrap_or(&route));
+            }
+          }
+        })
+        .map_err(|e| Error::Start(format!("Could not start worker thread: {}", e)))?;
+    }
+
+    let sockets = bound_addrs
+      .iter()
+      .filter(|_| remove_sockets)
+      .filter_map(|addr| net::unix_path(addr))
+      .map(|path| path.to_path_buf())
+      .collect();
+    *lifecycle = Lifecycle::Serving(Running {
+      server,
+      exited,
+      sockets,
+    });
+    self.health.start(&self.state);
+    Ok(local_addrs)
+  }
+
+  /// Stops the server started by `serve`: the workers finish the requests they
+  /// are running, for up to `CONFIG.shutdown.grace_ms`, then the
+  /// `CONFIG.shutdown.script` script runs and the background queues stop.
+  /// Requests arriving afterwards are answered with `503`.
+  ///
+  /// Returns the number of workers still running a request when the grace
+  /// period ran out; `0` if they all finished, or the server wasn't
+  /// started.
+  pub fn shutdown(&self) -> usize {
+    let running = {
+      let mut lifecycle = locks::lock(&self.lifecycle, "server lifecycle");
+      match std::mem::replace(&mut *lifecycle, Lifecycle::Stopped) {
+        Lifecycle::Serving(running) => running,
+        _ => return 0,
+      }
+    };
+    info!(
+      "Shutting down; waiting up to {} ms for {} request(s) in flight",
+      self.grace.as_millis(),
+      self.state.in_flight.in_flight()
+    );
+    self.health.stop();
+    running.server.stop();
+    let still_running = shutdown::drain(&running.exited, self.workers, self.grace);
+    if still_running > 0 {
+      warn!(
+        "{} worker(s) still running a request after the grace period",
+        still_running
+      );
+    }
+    for path in &running.sockets {
+      if let Err(e) = fs::remove_file(path) {
+        warn!("Failed to remove {}: {}", path.display(), e);
+      }
+    }
+
+    if let Some(script) = &self.on_shutdown {
+      info!("Running shutdown script {}", script);
+      if let Err(e) = schedule::run_task(&self.state, script) {
+        error!("Shutdown script {} failed: {}", script, e);
+      }
+    }
+    self.state.queues.shutdown();
+    if let Some(audit) = &self.state.audit {
+      audit.flush();
+    }
+    if let Some(exporter) = &self.state.span_export {
+      exporter.flush();
+    }
+    if let Some(reporter) = &self.state.error_reports {
+      reporter.flush();
+    }
+    still_running
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::testing::{self, Fixture};
+  use std::time::Instant;
+
+  /// Answers once two requests are in it at the same time, or after five
+  /// seconds, with how many were.
+  const RENDEZVOUS: &str = r#"
+    return {
+      handler = function(request, response)
+        fyre.kv.incr("arrived", 1)
+        local deadline = os.time() + 5
+        while fyre.kv.get("arrived") < 2 and os.time() < deadline do end
+        response.body = tostring(fyre.kv.get("arrived"))
+      end,
+    }
+  "#;
+
+  #[test]
+  fn workers_answer_requests_at_once() {
+    let fixture = Fixture::new(
+      r#"router.add("/meet", "meet.lua")"#,
+      &[("meet.lua", RENDEZVOUS)],
+    );
+    let server = fixture
+      .builder()
+      .addr("127.0.0.1:0")
+      .workers(2)
+      .load()
+      .unwrap();
+    let addr = server.serve().unwrap().remove(0);
+    let clients: Vec<_> = (0..2)
+      .map(|_| {
+        let addr = addr.clone();
+        std::thread::spawn(move || testing::get(&addr, "/meet"))
+      })
+      .collect();
+    for client in clients {
+      let response = client.join().unwrap();
+      assert!(response.starts_with("HTTP/1.1 200"), "{}", response);
+      assert!(response.ends_with("\r\n\r\n2"), "{}", response);
+    }
+    server.shutdown();
+  }
+
+  #[test]
+  fn worker_count_is_checked() {
+    let fixture = Fixture::new("", &[]);
+    for workers in [0, worker_stats::MAX_WORKERS + 1] {
+      assert!(matches!(
+        fixture.builder().workers(workers).load(),
+        Err(Error::Config(_))
+      ));
+    }
+    assert!(fixture.builder().workers(1).load().is_ok());
+  }
+
+  #[test]
+  fn builder_options_beat_config_which_beats_the_defaults() {
+    let configured = Fixture::new(r#"CONFIG = { addr = "127.0.0.1:9001", workers = 3 }"#, &[]);
+    let server = configured
+      .builder()
+      .addr("127.0.0.1:9002")
+      .workers(5)
+      .load()
+      .unwrap();
+    assert_eq!(server.addrs, ["127.0.0.1:9002"]);
+    assert_eq!(server.workers, 5);
+
+    let server = configured.server();
+    assert_eq!(server.addrs, ["127.0.0.1:9001"]);
+    assert_eq!(server.workers, 3);
+
+    let server = Fixture::new("", &[]).server();
+    assert_eq!(server.addrs, [DEFAULT_SERVER_ADDR]);
+    let parallelism = std::thread::available_parallelism().map_or(1, |n| n.get());
+    assert_eq!(server.workers, parallelism.min(worker_stats::MAX_WORKERS));
+  }
+
+  #[test]
+  fn command_line_options_reach_the_builder() {
+    use clap::Parser;
+
+    let fixture = Fixture::new(r#"CONFIG = { addrs = { "127.0.0.1:9001" } }"#, &[]);
+    let config = fixture.path().join("config.lua");
+    let cli = cli::Cli::try_parse_from([
+      "fyre",
+      "serve",
+      "--config",
+      config.to_str().unwrap(),
+      "--addr",
+      "127.0.0.1:9002",
+      "127.0.0.1:9003",
+      "--workers",
+      "2",
+      "--log-level",
+      "debug",
+    ])
+    .unwrap();
+    let Some(cli::Command::Serve(args)) = cli.command else {
+      panic!("not parsed as serve");
+    };
+    assert_eq!(args.addrs(), ["127.0.0.1:9002", "127.0.0.1:9003"]);
+    assert!(args.log_level.is_some());
+    let mut builder = fixture.builder().config_file(&args.paths.config);
+    for addr in args.addrs() {
+      builder = builder.addr(addr);
+    }
+    let server = builder.workers(args.workers.unwrap()).load().unwrap();
+    assert_eq!(server.addrs, ["127.0.0.1:9002", "127.0.0.1:9003"]);
+    assert_eq!(server.workers, 2);
+  }
+
+  #[test]
+  fn workers_keep_serving_through_panicking_handlers() {
+    let fixture = Fixture::new(
+      r#"
+        router.add("/boom", "boom.lua")
+        router.add("/ok", "ok.lua")
+      "#,
+      &[
+        (
+          "boom.lua",
+          "return { handler = function() fyre.panic('boom') end }",
+        ),
+        (
+          "ok.lua",
+          "return { handler = function(request, response) response.body = 'ok' end }",
+        ),
+      ],
+    );
+    let server = fixture
+      .builder()
+      .addr("127.0.0.1:0")
+      .workers(4)
+      .load()
+      .unwrap();
+    let addr = server.serve().unwrap().remove(0);
+    // 8 clients of 25 requests each, every other one panicking: 100 panics.
+    let clients: Vec<_> = (0..8)
+      .map(|client| {
+        let addr = addr.clone();
+        std::thread::spawn(move || {
+          for i in 0..25 {
+            let (path, status) = if (client + i) % 2 == 0 {
+              ("/boom", 500)
+            } else {
+              ("/ok", 200)
+            };
+            let response = testing::get(&addr, path);
+            let expected = format!("HTTP/1.1 {}", status);
+            assert!(response.starts_with(&expected), "{}: {}", path, response);
+          }
+        })
+      })
+      .collect();
+    for client in clients {
+      client.join().unwrap();
+    }
+
+    // A request is counted once its response is sent, so the last few may
+    // still be on their way.
+    let deadline = Instant::now() + Duration::from_secs(5);
+    let totals = loop {
+      let workers = server.state.workers.snapshot();
+      let totals = (
+        workers.iter().map(|w| w.restarts).sum::<u64>(),
+        workers.iter().map(|w| w.requests).sum::<u64>(),
+      );
+      if totals == (100, 200) || Instant::now() > deadline {
+        break totals;
+      }
+      std::thread::sleep(Duration::from_millis(10));
+    };
+    assert_eq!(totals, (100, 200));
+    let response = testing::get(&addr, "/ok");
+    assert!(response.ends_with("\r\n\r\nok"), "{}", response);
+    server.shutdown();
+  }
+}
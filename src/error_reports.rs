@@ -0,0 +1,520 @@
+//! # Error Reports
+//!
+//! With `CONFIG.error_reporting.webhook` set, handler failures are posted
+//! to it as JSON, so someone hears about them without reading the log:
+//!
+//! ```lua
+//! CONFIG.error_reporting = {
+//!   webhook = env.require("SLACK_WEBHOOK"),
+//!   min_level = "error", -- or "warn" to report every 5xx a handler sends
+//!   throttle = "5m",
+//! }
+//! ```
+//!
+//! A pipeline failure is an `error`: a script that didn't compile, couldn't
+//! be loaded, or raised an error in its `handler`, a panic, or a pipeline
+//! stopped by its instruction limit. With `min_level = "warn"`, any other
+//! `5xx` a handler answers with is reported as a `warn`.
+//!
+//! Reports are queued for one sender thread. The first after a quiet spell
+//! is posted `GATHER` later, with whatever followed it, and after that at
+//! most one post is made per `throttle` (`"5m"` by default), so an error
+//! storm sends a handful of messages. A post groups the reports since the
+//! last one by route, with how many there were and the latest one's error,
+//! its Lua traceback (cut to `MAX_TRACEBACK_BYTES`), and its
+//! `X-Request-Id`:
+//!
+//! ```json
+//! { "text": "fyre: 14 handler errors on 1 route: /checkout",
+//!   "errors": [{ "route": "/checkout", "script": "handlers/checkout.lua",
+//!                "level": "error", "status": 500, "count": 14,
+//!                "error": "handlers/checkout.lua:12: attempt to index a nil value",
+//!                "traceback": "stack traceback: ...", "request_id": "7f3a...",
+//!                "first_at": "2024-05-01T03:12:09Z", "last_at": "2024-05-01T03:16:40Z" }] }
+//! ```
+//!
+//! `text` is what chat webhooks such as Slack's show. A report that finds
+//! the queue full is dropped, and a post that fails is logged and not
+//! retried; neither holds up a request.
+
+use std::cmp::Reverse;
+use std::collections::BTreeMap;
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::time::{Duration, Instant};
+
+use chrono::{DateTime, SecondsFormat, Utc};
+use mlua::prelude::*;
+use serde_json::json;
+
+use crate::logger::Level;
+use crate::schedule;
+use crate::settings;
+
+/// How long the first report after a quiet spell waits for others.
+const GATHER: Duration = Duration::from_secs(10);
+/// The time between posts when `CONFIG.error_reporting.throttle` is not set.
+const DEFAULT_THROTTLE: Duration = Duration::from_secs(300);
+/// The reports waiting for the sender thread, past which new ones are
+/// dropped.
+const QUEUE_REPORTS: usize = 1024;
+/// The most routes listed in one post; the rest are only counted in `text`.
+const MAX_ROUTES: usize = 20;
+/// The longest traceback sent.
+const MAX_TRACEBACK_BYTES: usize = 2048;
+/// How long the webhook has to accept a post.
+const POST_TIMEOUT: Duration = Duration::from_secs(10);
+/// How long `flush` waits for the sender thread.
+const FLUSH_TIMEOUT: Duration = Duration::from_secs(15);
+
+/// Where reports go and which are sent, from `CONFIG.error_reporting.webhook`,
+/// `CONFIG.error_reporting.min_level`, and `CONFIG.error_reporting.throttle`.
+#[derive(Debug, Clone)]
+pub struct Settings {
+  pub webhook: String,
+  /// The least serious report sent: `Error` or `Warn`.
+  pub min_level: Level,
+  /// The least time between posts.
+  pub throttle: Duration,
+}
+
+impl Settings {
+  /// Reads `CONFIG.error_reporting.webhook`,
+  /// `CONFIG.error_reporting.min_level`, and `CONFIG.error_reporting.throttle`.
+  /// Returns `None` if no webhook is set.
+  ///
+  /// # Errors
+  ///
+  /// Returns an error message if one has the wrong type or value, or one is
+  /// set without the webhook.
+  pub fn from_globals(globals: &LuaTable) -> Result<Option<Settings>, String> {
+    let webhook = globals
+      .get::<Option<String>>("ERROR_WEBHOOK")
+      .map_err(|e| format!("CONFIG.error_reporting.webhook must be a URL: {}", e))?;
+    let Some(webhook) = webhook else {
+      for global in ["ERROR_REPORT_LEVEL", "ERROR_REPORT_THROTTLE"] {
+        if !globals
+          .get::<LuaValue>(global)
+          .is_ok_and(|value| value.is_nil())
+        {
+          return Err(format!(
+            "{} is set but CONFIG.error_reporting.webhook isn't",
+            settings::key(global)
+          ));
+        }
+      }
+      return Ok(None);
+    };
+    // The URL usually holds the webhook's secret, so it isn't repeated.
+    match url::Url::parse(&webhook) {
+      Ok(url) if matches!(url.scheme(), "http" | "https") => {}
+      _ => return Err("CONFIG.error_reporting.webhook must be an http or https URL".to_string()),
+    }
+
+    let min_level = match globals
+      .get::<Option<String>>("ERROR_REPORT_LEVEL")
+      .map_err(|e| {
+        format!("CONFIG.error_reporting.min_level must be \"error\" or \"warn\": {}", e)
+      })?
+      .as_deref()
+    {
+      None | Some("error") => Level::Error,
+      Some("warn") => Level::Warn,
+      Some(other) => {
+        return Err(format!(
+          "CONFIG.error_reporting.min_level must be \"error\" or \"warn\", got {:?}",
+          other
+        ))
+      }
+    };
+
+    let throttle = globals
+      .get::<Option<String>>("ERROR_REPORT_THROTTLE")
+      .map_err(|e| {
+        format!(
+          "CONFIG.error_reporting.throttle must be an interval such as \"5m\": {}",
+          e
+        )
+      })?
+      .map(|throttle| {
+        schedule::parse_interval(&throttle)
+          .map_err(|e| format!("CONFIG.error_reporting.throttle: {}", e))
+      })
+      .transpose()?
+      .unwrap_or(DEFAULT_THROTTLE);
+
+    Ok(Some(Settings {
+      webhook,
+      min_level,
+      throttle,
+    }))
+  }
+}
+
+/// One failed or `5xx` handler request.
+pub struct Report {
+  pub route: String,
+  pub script: String,
+  pub level: Level,
+  pub status: u16,
+  /// The error, with its Lua traceback if it has one.
+  pub error: String,
+  pub request_id: Option<String>,
+}
+
+enum Message {
+  Report(Report),
+  Flush(mpsc::Sender<()>),
+}
+
+/// The report queue's sending end, in `AppState`.
+pub struct Reporter {
+  sender: mpsc::SyncSender<Message>,
+  min_level: Level,
+}
+
+impl Reporter {
+  /// Starts the sender thread.
+  ///
+  /// # Errors
+  ///
+  /// Returns an error message if the thread can't be started.
+  pub fn start(settings: Settings) -> Result<Reporter, String> {
+    let (sender, receiver) = mpsc::sync_channel(QUEUE_REPORTS);
+    let min_level = settings.min_level;
+    std::thread::Builder::new()
+      .name("error-reports".to_string())
+      .spawn(move || run(settings, receiver))
+      .map_err(|e| format!("Could not start the error report thread: {}", e))?;
+    Ok(Reporter { sender, min_level })
+  }
+
+  /// Whether reports at `level` are sent.
+  pub fn wants(&self, level: Level) -> bool {
+    level <= self.min_level
+  }
+
+  /// Queues `report`, unless its level isn't sent or the queue is full.
+  pub fn report(&self, report: Report) {
+    if self.wants(report.level) {
+      let _ = self.sender.try_send(Message::Report(report));
+    }
+  }
+
+  /// Posts the reports queued so far, without waiting out the throttle,
+  /// and waits for up to `FLUSH_TIMEOUT` for them to be sent.
+  pub fn flush(&self) {
+    let (done, sent) = mpsc::channel();
+    if self.sender.send(Message::Flush(done)).is_ok() && sent.recv_timeout(FLUSH_TIMEOUT).is_err() {
+      warn!("Timed out waiting for error reports to be sent");
+    }
+  }
+}
+
+/// The reports for one route since the last post.
+struct Group {
+  script: String,
+  level: Level,
+  status: u16,
+  error: String,
+  traceback: Option<String>,
+  request_id: Option<String>,
+  count: u64,
+  first_at: DateTime<Utc>,
+  last_at: DateTime<Utc>,
+}
+
+/// The sender thread: groups reports and posts them when they are due.
+fn run(settings: Settings, receiver: mpsc::Receiver<Message>) {
+  let agent = ureq::AgentBuilder::new().timeout(POST_TIMEOUT).build();
+  let mut pending: BTreeMap<String, Group> = BTreeMap::new();
+  // When the first pending report arrived, and when the last post was made.
+  let mut gathering: Option<Instant> = None;
+  let mut last_post: Option<Instant> = None;
+  // Whether the last post failed, so a webhook that is down is reported
+  // once rather than for every post.
+  let mut failing = false;
+  loop {
+    let due = gathering.map(|since| {
+      let gathered = since + GATHER;
+      last_post.map_or(gathered, |last| gathered.max(last + settings.throttle))
+    });
+    let message = match due {
+      None => receiver.recv().map_err(|_| RecvTimeoutError::Disconnected),
+      Some(due) => receiver.recv_timeout(due.saturating_duration_since(Instant::now())),
+    };
+    let (flush, done) = match message {
+      // Checked again below, since a steady stream of reports never times
+      // out.
+      Ok(Message::Report(report)) => {
+        add(&mut pending, report);
+        gathering.get_or_insert_with(Instant::now);
+        (due.is_some_and(|due| due <= Instant::now()), None)
+      }
+      Ok(Message::Flush(done)) => (true, Some(done)),
+      Err(RecvTimeoutError::Timeout) => (true, None),
+      Err(RecvTimeoutError::Disconnected) => {
+        post(&agent, &settings, &mut pending, &mut failing);
+        return;
+      }
+    };
+    if flush && !pending.is_empty() {
+      post(&agent, &settings, &mut pending, &mut failing);
+      gathering = None;
+      last_post = Some(Instant::now());
+    }
+    if let Some(done) = done {
+      let _ = done.send(());
+    }
+  }
+}
+
+/// Adds `report` to its route's group.
+fn add(pending: &mut BTreeMap<String, Group>, report: Report) {
+  let now = Utc::now();
+  let (error, traceback) = match report.error.split_once("stack traceback:") {
+    Some((error, traceback)) => {
+      let mut traceback = format!("stack traceback:{}", traceback.trim_end());
+      if traceback.len() > MAX_TRACEBACK_BYTES {
+        let mut end = MAX_TRACEBACK_BYTES;
+        while !traceback.is_char_boundary(end) {
+          end -= 1;
+        }
+        traceback.truncate(end);
+        traceback.push_str("\n...");
+      }
+      (error.trim_end().to_string(), Some(traceback))
+    }
+    None => (report.error, None),
+  };
+  let group = pending.entry(report.route).or_insert_with(|| Group {
+    script: report.script.clone(),
+    level: report.level,
+    status: report.status,
+    error: String::new(),
+    traceback: None,
+    request_id: None,
+    count: 0,
+    first_at: now,
+    last_at: now,
+  });
+  group.script = report.script;
+  group.level = group.level.min(report.level);
+  group.status = report.status;
+  group.error = error;
+  group.traceback = traceback;
+  group.request_id = report.request_id;
+  group.count += 1;
+  group.last_at = now;
+}
+
+/// Posts the pending reports to the webhook and forgets them.
+fn post(
+  agent: &ureq::Agent,
+  settings: &Settings,
+  pending: &mut BTreeMap<String, Group>,
+  failing: &mut bool,
+) {
+  if pending.is_empty() {
+    return;
+  }
+  let total: u64 = pending.values().map(|group| group.count).sum();
+  let mut routes: Vec<(&String, &Group)> = pending.iter().collect();
+  routes.sort_by_key(|(_, route)| Reverse(route.count));
+  let names: Vec<&str> = routes
+    .iter()
+    .take(3)
+    .map(|(route, _)| route.as_str())
+    .collect();
+  let mut text = format!(
+    "fyre: {} handler {} on {} {}: {}",
+    total,
+    if total == 1 { "error" } else { "errors" },
+    routes.len(),
+    if routes.len() == 1 { "route" } else { "routes" },
+    names.join(", ")
+  );
+  if routes.len() > names.len() {
+    text.push_str(&format!(" and {} more", routes.len() - names.len()));
+  }
+  let timestamp = |at: &DateTime<Utc>| at.to_rfc3339_opts(SecondsFormat::Secs, true);
+  let errors: Vec<serde_json::Value> = routes
+    .iter()
+    .take(MAX_ROUTES)
+    .map(|(route, group)| {
+      json!({
+        "route": route,
+        "script": group.script,
+        "level": group.level.name(),
+        "status": group.status,
+        "count": group.count,
+        "error": group.error,
+        "traceback": group.traceback,
+        "request_id": group.request_id,
+        "first_at": timestamp(&group.first_at),
+        "last_at": timestamp(&group.last_at),
+      })
+    })
+    .collect();
+  let body = json!({ "text": text, "errors": errors });
+
+  let result = agent
+    .post(&settings.webhook)
+    .set("Content-Type", "application/json")
+    .send_bytes(body.to_string().as_bytes());
+  match result {
+    Ok(_) if *failing => {
+      info!("Sending error reports again");
+      *failing = false;
+    }
+    Ok(_) => {}
+    Err(e) if !*failing => {
+      // The error names the URL, which holds the webhook's secret.
+      let reason = match e {
+        ureq::Error::Status(status, _) => format!("status {}", status),
+        ureq::Error::Transport(transport) => transport.kind().to_string(),
+      };
+      warn!("Failed to send {} error reports: {}", total, reason);
+      *failing = true;
+    }
+    Err(_) => {}
+  }
+  pending.clear();
+}
+
+#[cfg(test)]
+mod tests {
+  use std::io::{BufRead, BufReader, Read, Write};
+  use std::net::TcpListener;
+
+  use serde_json::Value;
+
+  use super::*;
+
+  fn settings(config: &str) -> Result<Option<Settings>, String> {
+    let lua = Lua::new();
+    lua.load(config).exec().unwrap();
+    Settings::from_globals(&lua.globals())
+  }
+
+  fn report(route: &str, level: Level, error: &str) -> Report {
+    Report {
+      route: route.to_string(),
+      script: format!("handlers{}.lua", route),
+      level,
+      status: 500,
+      error: error.to_string(),
+      request_id: Some(format!("id{}", route.replace('/', "-"))),
+    }
+  }
+
+  #[test]
+  fn settings_need_an_http_webhook() {
+    assert!(settings("").unwrap().is_none());
+    let set = settings(
+      r#"
+        ERROR_WEBHOOK = "https://hooks.example.com/T0/B0/secret"
+        ERROR_REPORT_LEVEL = "warn"
+        ERROR_REPORT_THROTTLE = "30s"
+      "#,
+    )
+    .unwrap()
+    .unwrap();
+    assert_eq!(set.min_level, Level::Warn);
+    assert_eq!(set.throttle, Duration::from_secs(30));
+    let set = settings(r#"ERROR_WEBHOOK = "http://127.0.0.1:9/""#)
+      .unwrap()
+      .unwrap();
+    assert_eq!(set.min_level, Level::Error);
+    assert_eq!(set.throttle, DEFAULT_THROTTLE);
+
+    for config in [
+      r#"ERROR_REPORT_LEVEL = "warn""#,
+      r#"ERROR_WEBHOOK = "ftp://example.com/hook""#,
+      r#"ERROR_WEBHOOK = "not a url""#,
+      r#"ERROR_WEBHOOK = "https://example.com/" ERROR_REPORT_LEVEL = "info""#,
+      r#"ERROR_WEBHOOK = "https://example.com/" ERROR_REPORT_THROTTLE = "soon""#,
+    ] {
+      assert!(settings(config).is_err(), "{}", config);
+    }
+    let message = settings(r#"ERROR_WEBHOOK = "ftp://example.com/secret""#).unwrap_err();
+    assert!(!message.contains("secret"), "{}", message);
+  }
+
+  #[test]
+  fn reports_are_grouped_by_route_with_their_traceback() {
+    let mut pending = BTreeMap::new();
+    add(&mut pending, report("/a", Level::Warn, "first"));
+    let long = format!("boom\nstack traceback:\n{}", "\t[C]: in ?\n".repeat(500));
+    add(&mut pending, report("/a", Level::Error, &long));
+    add(&mut pending, report("/b", Level::Error, "plain"));
+
+    let a = &pending["/a"];
+    assert_eq!(a.count, 2);
+    assert_eq!(a.level, Level::Error);
+    assert_eq!(a.error, "boom");
+    let traceback = a.traceback.as_deref().unwrap();
+    assert!(traceback.starts_with("stack traceback:\n\t[C]: in ?"));
+    assert!(traceback.ends_with("\n..."));
+    assert_eq!(traceback.len(), MAX_TRACEBACK_BYTES + 4);
+    assert_eq!(pending["/b"].error, "plain");
+    assert_eq!(pending["/b"].traceback, None);
+  }
+
+  #[test]
+  fn a_flush_posts_the_queued_reports() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let webhook = format!("http://{}/hook", listener.local_addr().unwrap());
+    let received = std::thread::spawn(move || {
+      let (stream, _) = listener.accept().unwrap();
+      let mut reader = BufReader::new(stream);
+      let mut length = 0;
+      loop {
+        let mut line = String::new();
+        reader.read_line(&mut line).unwrap();
+        if line == "\r\n" {
+          break;
+        }
+        if let Some((name, value)) = line.split_once(':') {
+          if name.eq_ignore_ascii_case("content-length") {
+            length = value.trim().parse().unwrap();
+          }
+        }
+      }
+      let mut body = vec![0; length];
+      reader.read_exact(&mut body).unwrap();
+      reader
+        .get_mut()
+        .write_all(b"HTTP/1.1 204 No Content\r\nConnection: close\r\n\r\n")
+        .unwrap();
+      serde_json::from_slice::<Value>(&body).unwrap()
+    });
+
+    let reporter = Reporter::start(Settings {
+      webhook,
+      min_level: Level::Error,
+      throttle: DEFAULT_THROTTLE,
+    })
+    .unwrap();
+    assert!(reporter.wants(Level::Error));
+    assert!(!reporter.wants(Level::Warn));
+    reporter.report(report("/checkout", Level::Error, "one"));
+    reporter.report(report("/checkout", Level::Error, "two"));
+    reporter.report(report("/ignored", Level::Warn, "a 503"));
+    reporter.report(report("/cart", Level::Error, "three"));
+    reporter.flush();
+
+    let body = received.join().unwrap();
+    assert_eq!(
+      body["text"],
+      "fyre: 3 handler errors on 2 routes: /checkout, /cart"
+    );
+    let errors = body["errors"].as_array().unwrap();
+    assert_eq!(errors.len(), 2);
+    assert_eq!(errors[0]["route"], "/checkout");
+    assert_eq!(errors[0]["count"], 2);
+    assert_eq!(errors[0]["error"], "two");
+    assert_eq!(errors[0]["level"], Level::Error.name());
+    assert_eq!(errors[0]["request_id"], "id-checkout");
+    assert_eq!(errors[1]["route"], "/cart");
+  }
+}
@@ -0,0 +1,202 @@
+//! # `fyre.cache`
+//!
+//! A read-through value cache with stampede protection.
+//!
+//! ```lua
+//! local rates = fyre.cache.remember("rates:eur", 300, function()
+//!   return fetch_rates("EUR")   -- runs at most once per miss
+//! end)
+//! fyre.cache.forget("rates:eur")
+//! fyre.cache.flush("rates:")
+//! ```
+//!
+//! Entries live in their own `KvStore`, separate from `fyre.kv`, bounded by
+//! `CONFIG.cache.max_entries`, and follow the same copying rules. On a miss
+//! `remember` takes a per-key lock before calling the function, so
+//! concurrent requests for the same key wait for the first one's result
+//! instead of all computing it. The locks live here in Rust because each
+//! request has its own Lua state.
+
+use mlua::prelude::*;
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+use super::kv::{ttl_from_secs, KvStore};
+use super::value::SharedValue;
+use crate::locks;
+use crate::AppState;
+
+thread_local! {
+  /// The keys this thread is currently computing, to catch a `remember`
+  /// nested inside its own function, which would otherwise deadlock.
+  static COMPUTING: RefCell<HashSet<String>> = RefCell::new(HashSet::new());
+}
+
+/// The shared cache behind `fyre.cache`.
+pub struct Cache {
+  store: KvStore,
+  /// One lock per key currently being computed.
+  locks: Mutex<HashMap<String, Arc<Mutex<()>>>>,
+  hits: AtomicU64,
+  misses: AtomicU64,
+}
+
+/// A snapshot of the cache counters.
+pub struct CacheStats {
+  pub hits: u64,
+  pub misses: u64,
+}
+
+impl Cache {
+  /// Creates an empty cache holding at most `max_entries` entries.
+  pub fn new(max_entries: usize) -> Self {
+    Cache {
+      store: KvStore::new(max_entries),
+      locks: Mutex::new(HashMap::new()),
+      hits: AtomicU64::new(0),
+      misses: AtomicU64::new(0),
+    }
+  }
+
+  /// Returns the hit and miss counts since startup.
+  pub fn stats(&self) -> CacheStats {
+    CacheStats {
+      hits: self.hits.load(Ordering::Relaxed),
+      misses: self.misses.load(Ordering::Relaxed),
+    }
+  }
+
+  /// Removes every entry, returning how many were live. Values being
+  /// computed by `remember` are still stored once they are ready.
+  pub fn clear(&self) -> usize {
+    self.store.clear()
+  }
+
+  fn key_lock(&self, key: &str) -> LuaResult<Arc<Mutex<()>>> {
+    let mut key_locks = locks::lock(&self.locks, "cache");
+    Ok(key_locks.entry(key.to_string()).or_default().clone())
+  }
+
+  /// Drops the lock for `key` once nobody else holds or waits on it.
+  fn release_key_lock(&self, key: &str, lock: Arc<Mutex<()>>) {
+    let mut key_locks = locks::lock(&self.locks, "cache");
+    // One reference in the map and one here: nobody else is waiting.
+    if Arc::strong_count(&lock) == 2 {
+      key_locks.remove(key);
+    }
+  }
+
+  /// Returns the cached value for `key`, or calls `compute` under the key's
+  /// lock and caches its result. A `nil` result is returned but not cached.
+  fn remember(
+    &self,
+    lua: &Lua,
+    key: String,
+    ttl: Option<std::time::Duration>,
+    compute: LuaFunction,
+  ) -> LuaResult<LuaValue> {
+    if let Some(value) = self.store.get(&key)? {
+      self.hits.fetch_add(1, Ordering::Relaxed);
+      return value.to_lua_value(lua);
+    }
+
+    if COMPUTING.with(|keys| keys.borrow().contains(&key)) {
+      return Err(LuaError::external(format!(
+        "fyre.cache.remember: '{}' is already being computed by this request",
+        key
+      )));
+    }
+
+    let lock = self.key_lock(&key)?;
+    let result = {
+      // A Lua error inside `compute` returns normally, so the lock can only
+      // be poisoned by a panic; the guarded data is `()` either way.
+      let _guard = lock.lock().unwrap_or_else(|e| e.into_inner());
+
+      // Another request may have filled the entry while we waited.
+      match self.store.get(&key) {
+        Err(e) => Err(e),
+        Ok(Some(value)) => {
+          self.hits.fetch_add(1, Ordering::Relaxed);
+          value.to_lua_value(lua)
+        }
+        Ok(None) => {
+          self.misses.fetch_add(1, Ordering::Relaxed);
+          COMPUTING.with(|keys| keys.borrow_mut().insert(key.clone()));
+          let value = compute.call::<LuaValue>(());
+          COMPUTING.with(|keys| keys.borrow_mut().remove(&key));
+
+          value.and_then(|value| {
+            if !value.is_nil() {
+              let shared = SharedValue::from_lua_value(value.clone())?;
+              self.store.set(key.clone(), shared, ttl)?;
+            }
+            Ok(value)
+          })
+        }
+      }
+    };
+
+    self.release_key_lock(&key, lock);
+    result
+  }
+
+  fn flush(&self, prefix: &str) -> LuaResult<usize> {
+    let keys = self.store.keys(prefix)?;
+    for key in &keys {
+      self.store.delete(key)?;
+    }
+    Ok(keys.len())
+  }
+}
+
+/// Builds the `fyre.cache` module table.
+///
+/// # Errors
+///
+/// This function will return a `LuaError` if the module table or its functions
+/// cannot be created.
+pub fn module(lua: &Lua, state: &Arc<AppState>) -> LuaResult<LuaTable> {
+  let module = lua.create_table()?;
+
+  let st = state.clone();
+  module.set(
+    "remember",
+    lua.create_function(
+      move |lua, (key, ttl, compute): (String, Option<f64>, LuaFunction)| {
+        st.cache.remember(lua, key, ttl_from_secs(ttl)?, compute)
+      },
+    )?,
+  )?;
+
+  let st = state.clone();
+  module.set(
+    "forget",
+    lua.create_function(move |_, key: String| st.cache.store.delete(&key))?,
+  )?;
+
+  let st = state.clone();
+  module.set(
+    "flush",
+    lua.create_function(move |_, prefix: Option<String>| {
+      st.cache.flush(prefix.as_deref().unwrap_or(""))
+    })?,
+  )?;
+
+  let st = state.clone();
+  module.set(
+    "stats",
+    lua.create_function(move |lua, ()| {
+      let stats = st.cache.stats();
+      let table = lua.create_table()?;
+      table.set("hits", stats.hits)?;
+      table.set("misses", stats.misses)?;
+      table.set("entries", st.cache.store.keys("")?.len())?;
+      Ok(table)
+    })?,
+  )?;
+
+  Ok(module)
+}
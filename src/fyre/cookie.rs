@@ -0,0 +1,330 @@
+//! # Cookies
+//!
+//! Parsing of request `Cookie` headers and construction of `Set-Cookie`
+//! values, shared by the modules that read or issue cookies, and the
+//! `fyre.cookie` module signing cookie values with `CONFIG.keys`:
+//!
+//! ```lua
+//! response.headers["Set-Cookie"] = "flash=" .. fyre.cookie.sign("flash", "Saved")
+//!
+//! local value, key = fyre.cookie.verify("flash")   -- nil if missing or forged
+//! if key == "previous" then
+//!   -- signed with a retired key; sign it again with the current one
+//! end
+//! ```
+//!
+//! A signed value is `base64url(value).base64url(mac)`, the MAC being
+//! HMAC-SHA256 of `name=value` so a value signed for one cookie can't be
+//! passed off as another. Signing uses `keys.current`; verifying tries it
+//! and then each of `keys.previous`, so keys can be rotated without
+//! invalidating the cookies already out there.
+
+use mlua::prelude::*;
+use std::fmt;
+use std::sync::Arc;
+
+use super::crypto::{constant_time_eq, hmac};
+use super::encoding::{base64_decode_urlsafe, base64_encode_urlsafe};
+use crate::AppState;
+
+/// The largest value `sign` takes; signed, it stays under the 4 KB browsers
+/// keep per cookie.
+pub const MAX_VALUE_BYTES: usize = 2900;
+/// The longest signed value `verify` looks at.
+const MAX_SIGNED_BYTES: usize = 4000;
+
+/// Returns the value of the cookie called `name` in a `Cookie` header, if
+/// present. Surrounding double quotes are removed.
+pub fn find_cookie(header: &str, name: &str) -> Option<String> {
+  header.split(';').find_map(|pair| {
+    let (key, value) = pair.split_once('=')?;
+    (key.trim() == name).then(|| value.trim().trim_matches('"').to_string())
+  })
+}
+
+/// A `Set-Cookie` header value.
+///
+/// Cookies default to `Path=/`, `HttpOnly`, and `SameSite=Lax`.
+pub struct SetCookie {
+  name: String,
+  value: String,
+  max_age: Option<i64>,
+  secure: bool,
+}
+
+impl SetCookie {
+  /// Creates a session cookie (no `Max-Age`) with the default attributes.
+  pub fn new(name: impl Into<String>, value: impl Into<String>) -> Self {
+    SetCookie {
+      name: name.into(),
+      value: value.into(),
+      max_age: None,
+      secure: false,
+    }
+  }
+
+  /// Creates a cookie that tells the client to delete `name` immediately.
+  pub fn removal(name: impl Into<String>) -> Self {
+    SetCookie::new(name, "").max_age(0)
+  }
+
+  /// Sets how many seconds the client should keep the cookie.
+  pub fn max_age(mut self, seconds: i64) -> Self {
+    self.max_age = Some(seconds);
+    self
+  }
+
+  /// Restricts the cookie to HTTPS connections.
+  pub fn secure(mut self, secure: bool) -> Self {
+    self.secure = secure;
+    self
+  }
+
+  /// Formats the header value.
+  pub fn to_header_value(&self) -> String {
+    let mut out = format!("{}={}; Path=/", self.name, self.value);
+    if let Some(max_age) = self.max_age {
+      out.push_str(&format!("; Max-Age={}", max_age));
+    }
+    out.push_str("; HttpOnly; SameSite=Lax");
+    if self.secure {
+      out.push_str("; Secure");
+    }
+    out
+  }
+}
+
+/// The keys signing `fyre.cookie` values, from `CONFIG.keys`.
+pub struct CookieKeys {
+  current: Vec<u8>,
+  previous: Vec<Vec<u8>>,
+}
+
+impl fmt::Debug for CookieKeys {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    f.debug_struct("CookieKeys")
+      .field("current", &"<redacted>")
+      .field("previous", &self.previous.len())
+      .finish()
+  }
+}
+
+/// Which of the keys verified a value.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeyUsed {
+  Current,
+  Previous,
+}
+
+impl KeyUsed {
+  fn as_str(self) -> &'static str {
+    match self {
+      KeyUsed::Current => "current",
+      KeyUsed::Previous => "previous",
+    }
+  }
+}
+
+impl CookieKeys {
+  /// Reads a `{ current = "...", previous = { "..." } }` table.
+  ///
+  /// # Errors
+  ///
+  /// This function will return an error if `current` is missing or any key
+  /// is empty or not a string.
+  pub fn from_lua(table: &LuaTable) -> Result<CookieKeys, String> {
+    let current = table
+      .get::<Option<LuaString>>("current")
+      .map_err(|e| format!("keys.current must be a string: {}", e))?
+      .ok_or("keys.current must be set")?
+      .as_bytes()
+      .to_vec();
+    let previous: Vec<Vec<u8>> = table
+      .get::<Option<Vec<LuaString>>>("previous")
+      .map_err(|e| format!("keys.previous must be a list of strings: {}", e))?
+      .unwrap_or_default()
+      .iter()
+      .map(|key| key.as_bytes().to_vec())
+      .collect();
+    if current.is_empty() || previous.iter().any(Vec::is_empty) {
+      return Err("keys must not be empty".to_string());
+    }
+    Ok(CookieKeys { current, previous })
+  }
+
+  /// Signs `value` for the cookie `name` with the current key.
+  ///
+  /// # Errors
+  ///
+  /// This function will return an error if `value` is over
+  /// `MAX_VALUE_BYTES`.
+  pub fn sign(&self, name: &str, value: &[u8]) -> Result<String, String> {
+    if value.len() > MAX_VALUE_BYTES {
+      return Err(format!(
+        "cookie value is too large to sign ({} bytes, limit {})",
+        value.len(),
+        MAX_VALUE_BYTES
+      ));
+    }
+    Ok(format!(
+      "{}.{}",
+      base64_encode_urlsafe(value),
+      base64_encode_urlsafe(&mac(&self.current, name, value))
+    ))
+  }
+
+  /// Checks a value produced by `sign` for the cookie `name`, returning it
+  /// and the key that verified it.
+  pub fn verify(&self, name: &str, signed: &str) -> Option<(Vec<u8>, KeyUsed)> {
+    if signed.len() > MAX_SIGNED_BYTES {
+      return None;
+    }
+    let (value, tag) = signed.split_once('.')?;
+    let value = base64_decode_urlsafe(value.as_bytes()).ok()?;
+    let tag = base64_decode_urlsafe(tag.as_bytes()).ok()?;
+    if constant_time_eq(&mac(&self.current, name, &value), &tag) {
+      return Some((value, KeyUsed::Current));
+    }
+    self
+      .previous
+      .iter()
+      .any(|key| constant_time_eq(&mac(key, name, &value), &tag))
+      .then_some((value, KeyUsed::Previous))
+  }
+}
+
+fn mac(key: &[u8], name: &str, value: &[u8]) -> Vec<u8> {
+  let mut message = name.as_bytes().to_vec();
+  message.push(b'=');
+  message.extend_from_slice(value);
+  hmac("sha256", key, &message).expect("sha256 is supported")
+}
+
+/// Builds the `fyre.cookie` module table for one request.
+///
+/// # Arguments
+///
+/// * `lua` - The request's Lua state.
+/// * `state` - The server-wide state holding the keys.
+/// * `cookie_header` - The request's `Cookie` header, if any.
+///
+/// # Errors
+///
+/// This function will return a `LuaError` if the module table or its functions
+/// cannot be created.
+pub fn module(
+  lua: &Lua,
+  state: &Arc<AppState>,
+  cookie_header: Option<&str>,
+) -> LuaResult<LuaTable> {
+  let module = lua.create_table()?;
+
+  // fyre.cookie.sign(name, value) -> signed value (or nil, err if too large)
+  let s = state.clone();
+  module.set(
+    "sign",
+    lua.create_function(move |_, (name, value): (String, LuaString)| {
+      match keys(&s)?.sign(&name, &value.as_bytes()) {
+        Ok(signed) => Ok((Some(signed), None)),
+        Err(e) => Ok((None, Some(e))),
+      }
+    })?,
+  )?;
+
+  // fyre.cookie.verify(name) -> value, "current" or "previous" (or nil)
+  let s = state.clone();
+  let cookie_header = cookie_header.map(str::to_string);
+  module.set(
+    "verify",
+    lua.create_function(move |lua, name: String| {
+      let keys = keys(&s)?;
+      let Some(signed) = cookie_header.as_deref().and_then(|header| find_cookie(header, &name))
+      else {
+        return Ok((None, None));
+      };
+      match keys.verify(&name, &signed) {
+        Some((value, key)) => Ok((Some(lua.create_string(value)?), Some(key.as_str()))),
+        None => Ok((None, None)),
+      }
+    })?,
+  )?;
+
+  Ok(module)
+}
+
+fn keys(state: &AppState) -> LuaResult<&CookieKeys> {
+  state
+    .cookie_keys
+    .as_ref()
+    .ok_or_else(|| LuaError::external("fyre.cookie requires CONFIG.keys in config.lua"))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn keys(table: &str) -> Result<CookieKeys, String> {
+    let lua = Lua::new();
+    CookieKeys::from_lua(&lua.load(table).eval().unwrap())
+  }
+
+  #[test]
+  fn rotated_keys_still_verify_old_cookies() {
+    let v1 = keys(r#"{ current = "key-one" }"#).unwrap();
+    let old = v1.sign("flash", b"Saved").unwrap();
+    assert_eq!(
+      v1.verify("flash", &old),
+      Some((b"Saved".to_vec(), KeyUsed::Current))
+    );
+
+    let v2 = keys(r#"{ current = "key-two", previous = { "key-one" } }"#).unwrap();
+    assert_eq!(
+      v2.verify("flash", &old),
+      Some((b"Saved".to_vec(), KeyUsed::Previous))
+    );
+    let new = v2.sign("flash", b"Saved").unwrap();
+    assert_ne!(new, old);
+    assert_eq!(
+      v2.verify("flash", &new),
+      Some((b"Saved".to_vec(), KeyUsed::Current))
+    );
+    // Signed with the new key, which the old keys don't know.
+    assert_eq!(v1.verify("flash", &new), None);
+
+    let v3 = keys(r#"{ current = "key-three", previous = { "key-two" } }"#).unwrap();
+    assert_eq!(v3.verify("flash", &old), None);
+  }
+
+  #[test]
+  fn forged_values_are_refused() {
+    let keys = keys(r#"{ current = "secret" }"#).unwrap();
+    let signed = keys.sign("role", b"user").unwrap();
+    let (_, tag) = signed.split_once('.').unwrap();
+    let forged = format!("{}.{}", base64_encode_urlsafe(b"admin"), tag);
+    assert_eq!(keys.verify("role", &forged), None);
+    // A value signed for one cookie isn't valid for another.
+    assert_eq!(keys.verify("other", &signed), None);
+    for malformed in ["", ".", "dXNlcg", "dXNlcg.!!!", &signed[..signed.len() - 2]] {
+      assert_eq!(keys.verify("role", malformed), None, "{:?}", malformed);
+    }
+  }
+
+  #[test]
+  fn values_are_limited_in_size() {
+    let keys = keys(r#"{ current = "secret" }"#).unwrap();
+    let largest = vec![b'x'; MAX_VALUE_BYTES];
+    let signed = keys.sign("big", &largest).unwrap();
+    assert!(signed.len() <= MAX_SIGNED_BYTES);
+    assert_eq!(keys.verify("big", &signed).unwrap().0, largest);
+    assert!(keys.sign("big", &[b'x'; MAX_VALUE_BYTES + 1]).is_err());
+    assert_eq!(keys.verify("big", &"x".repeat(MAX_SIGNED_BYTES + 1)), None);
+  }
+
+  #[test]
+  fn keys_must_be_set_and_not_empty() {
+    assert!(keys("{}").is_err());
+    assert!(keys(r#"{ current = "" }"#).is_err());
+    assert!(keys(r#"{ current = "a", previous = { "" } }"#).is_err());
+    assert!(keys(r#"{ current = "a", previous = "b" }"#).is_err());
+  }
+}
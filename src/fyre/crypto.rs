@@ -0,0 +1,164 @@
+//! # `fyre.crypto`
+//!
+//! Hashing, HMAC, and constant-time comparison over binary-safe Lua strings.
+//!
+//! ```lua
+//! -- Verify a GitHub webhook signature over the exact request body.
+//! local expected = "sha256=" .. fyre.crypto.hmac_hex("sha256", secret, request.body)
+//! if not fyre.crypto.eq(expected, request.headers["X-Hub-Signature-256"] or "") then
+//!   response.status = 401
+//! end
+//! ```
+//!
+//! Digests and MACs are returned as raw bytes; the `*_hex` variants return
+//! lowercase hex. `md5` and `sha1` exist for interoperability with legacy
+//! systems only.
+
+use hmac::digest::KeyInit;
+use hmac::{Hmac, Mac};
+use md5::Md5;
+use mlua::prelude::*;
+use sha1::Sha1;
+use sha2::{Digest, Sha256, Sha384, Sha512};
+use subtle::ConstantTimeEq;
+
+use super::encoding::hex_encode;
+
+/// Computes the named digest of `data`.
+///
+/// # Errors
+///
+/// Returns an error message if `algo` is not one of `md5`, `sha1`,
+/// `sha256`, `sha384`, or `sha512`.
+pub fn digest(algo: &str, data: &[u8]) -> Result<Vec<u8>, String> {
+  Ok(match algo.to_ascii_lowercase().as_str() {
+    "md5" => Md5::digest(data).to_vec(),
+    "sha1" => Sha1::digest(data).to_vec(),
+    "sha256" => Sha256::digest(data).to_vec(),
+    "sha384" => Sha384::digest(data).to_vec(),
+    "sha512" => Sha512::digest(data).to_vec(),
+    other => return Err(format!("unsupported hash algorithm: {}", other)),
+  })
+}
+
+/// Computes the HMAC of `data` under `key` with the named digest.
+///
+/// # Errors
+///
+/// Returns an error message if `algo` is not one of `md5`, `sha1`,
+/// `sha256`, `sha384`, or `sha512`.
+pub fn hmac(algo: &str, key: &[u8], data: &[u8]) -> Result<Vec<u8>, String> {
+  fn compute<M: Mac + KeyInit>(key: &[u8], data: &[u8]) -> Vec<u8> {
+    // HMAC accepts keys of any length, so this cannot fail.
+    let mut mac = <M as KeyInit>::new_from_slice(key).expect("HMAC accepts any key length");
+    mac.update(data);
+    mac.finalize().into_bytes().to_vec()
+  }
+
+  Ok(match algo.to_ascii_lowercase().as_str() {
+    "md5" => compute::<Hmac<Md5>>(key, data),
+    "sha1" => compute::<Hmac<Sha1>>(key, data),
+    "sha256" => compute::<Hmac<Sha256>>(key, data),
+    "sha384" => compute::<Hmac<Sha384>>(key, data),
+    "sha512" => compute::<Hmac<Sha512>>(key, data),
+    other => return Err(format!("unsupported HMAC algorithm: {}", other)),
+  })
+}
+
+/// Compares two byte strings in time that depends only on their lengths.
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+  a.len() == b.len() && bool::from(a.ct_eq(b))
+}
+
+/// Builds the `fyre.crypto` module table.
+///
+/// # Errors
+///
+/// This function will return a `LuaError` if the module table or its functions
+/// cannot be created.
+pub fn module(lua: &Lua) -> LuaResult<LuaTable> {
+  let module = lua.create_table()?;
+
+  for algo in ["md5", "sha1", "sha256", "sha384", "sha512"] {
+    module.set(
+      algo,
+      lua.create_function(move |lua, data: LuaString| {
+        lua.create_string(digest(algo, &data.as_bytes()).map_err(LuaError::external)?)
+      })?,
+    )?;
+
+    module.set(
+      format!("{}_hex", algo),
+      lua.create_function(move |_, data: LuaString| {
+        let hash = digest(algo, &data.as_bytes()).map_err(LuaError::external)?;
+        Ok(hex_encode(&hash))
+      })?,
+    )?;
+  }
+
+  module.set(
+    "hmac",
+    lua.create_function(|lua, (algo, key, data): (String, LuaString, LuaString)| {
+      let mac = hmac(&algo, &key.as_bytes(), &data.as_bytes()).map_err(LuaError::external)?;
+      lua.create_string(mac)
+    })?,
+  )?;
+
+  module.set(
+    "hmac_hex",
+    lua.create_function(|_, (algo, key, data): (String, LuaString, LuaString)| {
+      let mac = hmac(&algo, &key.as_bytes(), &data.as_bytes()).map_err(LuaError::external)?;
+      Ok(hex_encode(&mac))
+    })?,
+  )?;
+
+  module.set(
+    "eq",
+    lua.create_function(|_, (a, b): (LuaString, LuaString)| {
+      Ok(constant_time_eq(&a.as_bytes(), &b.as_bytes()))
+    })?,
+  )?;
+
+  Ok(module)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn hmac_matches_rfc_4231_vector() {
+    let mac = hmac("SHA256", b"Jefe", b"what do ya want for nothing?").unwrap();
+    assert_eq!(
+      hex_encode(&mac),
+      "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+    );
+    assert!(hmac("sha3", b"Jefe", b"").is_err());
+  }
+
+  #[test]
+  fn github_signature_rejects_tampered_body() {
+    // The example from GitHub's "Validating webhook deliveries" guide.
+    let secret = b"It's a Secret to Everybody";
+    let header = "sha256=757107ea0eb2509fc211221cce984b8a37570b6d7586c22c46f4379c8b043e17";
+    let signature = |body: &[u8]| {
+      format!(
+        "sha256={}",
+        hex_encode(&hmac("sha256", secret, body).unwrap())
+      )
+    };
+
+    assert!(constant_time_eq(
+      signature(b"Hello, World!").as_bytes(),
+      header.as_bytes()
+    ));
+    assert!(!constant_time_eq(
+      signature(b"Hello, World?").as_bytes(),
+      header.as_bytes()
+    ));
+    assert!(!constant_time_eq(
+      signature(b"Hello, World!").as_bytes(),
+      &header.as_bytes()[..70]
+    ));
+  }
+}
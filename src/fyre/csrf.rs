@@ -0,0 +1,386 @@
+//! # `fyre.csrf`
+//!
+//! Cross-site request forgery tokens, using a signed double-submit cookie. Like
+//! `fyre.session`, it needs `CONFIG.session.secret`, which signs the cookie.
+//!
+//! ```lua
+//! -- in the page with the form
+//! local token = fyre.csrf.token()   -- sets the cookie if there isn't one
+//! response.body = '<input type="hidden" name="_csrf" value="' .. token .. '">'
+//!
+//! -- in the handler taking the form
+//! if not fyre.csrf.verify(request) then response.status = 403 return end
+//!
+//! -- after logging in
+//! fyre.csrf.rotate()
+//! ```
+//!
+//! The token is random, and the `fyre_csrf` cookie holds it with an
+//! HMAC-SHA256 signature. A request proves it came from one of the site's
+//! own pages by sending the token back in the `X-CSRF-Token` header or a
+//! `_csrf` field of an `application/x-www-form-urlencoded` body, which a
+//! forged cross-site request can't read from the cookie. The two are
+//! compared in constant time. A route declared with `{ csrf = true }` has
+//! this checked before its script runs (see `check`).
+
+use mlua::prelude::*;
+use std::cell::RefCell;
+use std::rc::Rc;
+use std::sync::Arc;
+
+use super::cookie::{find_cookie, SetCookie};
+use super::crypto::{constant_time_eq, hmac};
+use super::encoding::{base64_decode_urlsafe, base64_encode_urlsafe};
+use super::session::SessionConfig;
+use crate::AppState;
+
+/// The cookie holding the signed token.
+pub const COOKIE_NAME: &str = "fyre_csrf";
+/// The request header a token may be sent in.
+pub const HEADER: &str = "X-CSRF-Token";
+/// The form field a token may be sent in.
+pub const FORM_FIELD: &str = "_csrf";
+
+/// Whether requests with `method` are never checked.
+pub fn is_safe(method: &str) -> bool {
+  matches!(method, "GET" | "HEAD" | "OPTIONS")
+}
+
+/// Signs `token`, with the cookie name mixed in so a session cookie's
+/// signature can't be replayed as a CSRF token.
+fn sign(config: &SessionConfig, token: &str) -> Vec<u8> {
+  let message = format!("{}={}", COOKIE_NAME, token);
+  hmac("sha256", &config.secret, message.as_bytes()).expect("sha256 is supported")
+}
+
+/// The token in a `Cookie` header, if its signature holds.
+fn cookie_token(config: &SessionConfig, cookie_header: Option<&str>) -> Option<String> {
+  let cookie = find_cookie(cookie_header?, COOKIE_NAME)?;
+  let (token, mac) = cookie.split_once('.')?;
+  let mac = base64_decode_urlsafe(mac.as_bytes()).ok()?;
+  constant_time_eq(&sign(config, token), &mac).then(|| token.to_string())
+}
+
+/// The token in a form body's `_csrf` field, if the body is
+/// `application/x-www-form-urlencoded`.
+pub fn form_token(content_type: Option<&str>, body: &[u8]) -> Option<String> {
+  let media_type = content_type?.split(';').next()?.trim();
+  if !media_type.eq_ignore_ascii_case("application/x-www-form-urlencoded") {
+    return None;
+  }
+  body.split(|&b| b == b'&').find_map(|pair| {
+    let value = pair.strip_prefix(FORM_FIELD.as_bytes())?.strip_prefix(b"=")?;
+    String::from_utf8(super::url::decode_component(value)).ok()
+  })
+}
+
+/// Whether `submitted` matches the token in the request's cookie.
+pub fn check(
+  config: &SessionConfig,
+  cookie_header: Option<&str>,
+  submitted: Option<&str>,
+) -> bool {
+  match (cookie_token(config, cookie_header), submitted) {
+    (Some(expected), Some(submitted)) => {
+      constant_time_eq(expected.as_bytes(), submitted.trim().as_bytes())
+    }
+    _ => false,
+  }
+}
+
+/// A new token and the `Set-Cookie` value carrying it.
+fn issue(config: &SessionConfig) -> (String, String) {
+  let token = base64_encode_urlsafe(&rand::random::<[u8; 32]>());
+  let cookie = format!("{}.{}", token, base64_encode_urlsafe(&sign(config, &token)));
+  let set_cookie = SetCookie::new(COOKIE_NAME, cookie)
+    .secure(config.secure)
+    .to_header_value();
+  (token, set_cookie)
+}
+
+/// The per-request CSRF state: the request's cookie and the token its pages
+/// should embed, once one is known.
+struct Csrf {
+  state: Arc<AppState>,
+  cookie: Option<String>,
+  current: RefCell<Option<String>>,
+}
+
+impl Csrf {
+  fn config(&self) -> LuaResult<&SessionConfig> {
+    self
+      .state
+      .session
+      .as_ref()
+      .ok_or_else(|| LuaError::external("fyre.csrf requires CONFIG.session.secret in config.lua"))
+  }
+
+  /// Returns the cookie's token, or issues a new one if it has none.
+  fn token(&self, lua: &Lua, response: &LuaTable) -> LuaResult<String> {
+    if let Some(token) = self.current.borrow().clone() {
+      return Ok(token);
+    }
+    match cookie_token(self.config()?, self.cookie.as_deref()) {
+      Some(token) => {
+        *self.current.borrow_mut() = Some(token.clone());
+        Ok(token)
+      }
+      None => self.rotate(lua, response),
+    }
+  }
+
+  /// Issues a new token, replacing the cookie's.
+  fn rotate(&self, lua: &Lua, response: &LuaTable) -> LuaResult<String> {
+    let (token, set_cookie) = issue(self.config()?);
+    super::append_header(lua, response, "Set-Cookie", &set_cookie)?;
+    *self.current.borrow_mut() = Some(token.clone());
+    Ok(token)
+  }
+
+  /// Whether `request` is safe or sends back the cookie's token.
+  fn verify(&self, request: &LuaTable) -> LuaResult<bool> {
+    let config = self.config()?;
+    if is_safe(&request.get::<String>("method")?) {
+      return Ok(true);
+    }
+    let headers: LuaTable = request.get("headers")?;
+    let submitted = match headers.get::<Option<String>>(HEADER)? {
+      Some(token) => Some(token),
+      None => match request.get::<Option<LuaString>>("body")? {
+        Some(body) => form_token(
+          headers.get::<Option<String>>("Content-Type")?.as_deref(),
+          &body.as_bytes(),
+        ),
+        None => None,
+      },
+    };
+    Ok(check(config, self.cookie.as_deref(), submitted.as_deref()))
+  }
+}
+
+/// Builds the `fyre.csrf` module table for one request.
+///
+/// # Arguments
+///
+/// * `lua` - The request's Lua state.
+/// * `state` - The server-wide state holding the session secret.
+/// * `cookie_header` - The request's `Cookie` header, if any.
+/// * `response` - The request's `response` table, which a new token's
+///   `Set-Cookie` header is added to.
+///
+/// # Errors
+///
+/// This function will return a `LuaError` if the module table or its functions
+/// cannot be created.
+pub fn module(
+  lua: &Lua,
+  state: &Arc<AppState>,
+  cookie_header: Option<&str>,
+  response: LuaTable,
+) -> LuaResult<LuaTable> {
+  let csrf = Rc::new(Csrf {
+    state: state.clone(),
+    cookie: cookie_header.map(str::to_string),
+    current: RefCell::new(None),
+  });
+  let module = lua.create_table()?;
+
+  // fyre.csrf.token() -> token
+  let (c, res) = (csrf.clone(), response.clone());
+  module.set("token", lua.create_function(move |lua, ()| c.token(lua, &res))?)?;
+
+  // fyre.csrf.rotate() -> token, e.g. after logging in
+  let (c, res) = (csrf.clone(), response);
+  module.set("rotate", lua.create_function(move |lua, ()| c.rotate(lua, &res))?)?;
+
+  // fyre.csrf.verify(request) -> boolean
+  let c = csrf;
+  module.set(
+    "verify",
+    lua.create_function(move |_, request: LuaTable| c.verify(&request))?,
+  )?;
+
+  Ok(module)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::testing::{self, Fixture};
+  use std::time::Duration;
+
+  fn session_config() -> SessionConfig {
+    SessionConfig {
+      secret: b"session-secret".to_vec(),
+      cookie_name: "fyre_session".to_string(),
+      ttl: Duration::from_secs(3600),
+      store: super::super::session::SessionStore::Cookie,
+      secure: false,
+    }
+  }
+
+  /// A token and the `Cookie` header sending it back.
+  fn issued(config: &SessionConfig) -> (String, String) {
+    let (token, set_cookie) = issue(config);
+    let cookie = set_cookie.split(';').next().unwrap().to_string();
+    (token, cookie)
+  }
+
+  #[test]
+  fn only_the_cookies_token_passes() {
+    let config = session_config();
+    let (token, cookie) = issued(&config);
+    assert!(check(&config, Some(&cookie), Some(&token)));
+    assert!(check(
+      &config,
+      Some(&format!("a=1; {}", cookie)),
+      Some(&token)
+    ));
+
+    let (other, _) = issued(&config);
+    assert!(!check(&config, Some(&cookie), Some(&other)));
+    assert!(!check(&config, Some(&cookie), None));
+    assert!(!check(&config, None, Some(&token)));
+    assert!(!check(&config, Some(&cookie), Some("")));
+  }
+
+  #[test]
+  fn a_tampered_cookie_is_refused() {
+    let config = session_config();
+    let (token, cookie) = issued(&config);
+    // A cookie signed for another token, with the submitted one swapped in.
+    let (other, other_cookie) = issued(&config);
+    let swapped = other_cookie.replace(&other, &token);
+    assert!(!check(&config, Some(&swapped), Some(&token)));
+    // An unsigned one.
+    let unsigned = format!("{}={}", COOKIE_NAME, token);
+    assert!(!check(&config, Some(&unsigned), Some(&token)));
+    // One signed with another secret.
+    let mut elsewhere = session_config();
+    elsewhere.secret = b"another-secret".to_vec();
+    assert!(!check(&elsewhere, Some(&cookie), Some(&token)));
+  }
+
+  #[test]
+  fn safe_methods_are_exempt() {
+    for method in ["GET", "HEAD", "OPTIONS"] {
+      assert!(is_safe(method), "{}", method);
+    }
+    for method in ["POST", "PUT", "PATCH", "DELETE", "get"] {
+      assert!(!is_safe(method), "{}", method);
+    }
+  }
+
+  #[test]
+  fn forms_send_the_token_in_a_field() {
+    let form = Some("application/x-www-form-urlencoded; charset=utf-8");
+    assert_eq!(
+      form_token(form, b"name=a&_csrf=abc%2Dd"),
+      Some("abc-d".to_string())
+    );
+    assert_eq!(form_token(form, b"name=a&x_csrf=abc"), None);
+    assert_eq!(form_token(Some("text/plain"), b"_csrf=abc"), None);
+    assert_eq!(form_token(None, b"_csrf=abc"), None);
+  }
+
+  #[test]
+  fn routes_with_csrf_refuse_unsafe_requests_without_the_token() {
+    let fixture = Fixture::new(
+      r#"
+        CONFIG = { session = { secret = "session-secret" } }
+        router.add("/form", "form.lua", { csrf = true })
+      "#,
+      &[(
+        "form.lua",
+        r#"return {
+          handler = function(request, response)
+            if request.method == "GET" then
+              response.body = fyre.csrf.token()
+            else
+              response.body = "posted"
+            end
+          end,
+        }"#,
+      )],
+    );
+    let server = fixture
+      .builder()
+      .addr("127.0.0.1:0")
+      .workers(1)
+      .load()
+      .unwrap();
+    let addr = server.serve().unwrap().remove(0);
+
+    // A safe request isn't checked, and gets the token and its cookie.
+    let page = testing::get(&addr, "/form");
+    assert!(page.starts_with("HTTP/1.1 200"), "{}", page);
+    let (head, token) = page.split_once("\r\n\r\n").unwrap();
+    let cookie = head
+      .lines()
+      .find_map(|line| line.strip_prefix("Set-Cookie: "))
+      .or_else(|| {
+        head
+          .lines()
+          .find_map(|line| line.strip_prefix("set-cookie: "))
+      })
+      .unwrap()
+      .split(';')
+      .next()
+      .unwrap();
+
+    let post = |headers: &str, body: &str| {
+      testing::send(
+        &addr,
+        &format!(
+          "POST /form HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n{}\
+           Content-Length: {}\r\n\r\n{}",
+          headers,
+          body.len(),
+          body
+        ),
+      )
+    };
+    let tampered = format!("{}x", token);
+    let form = "Content-Type: application/x-www-form-urlencoded\r\n";
+    for (headers, body, status) in [
+      (String::new(), String::new(), "403"),
+      (format!("Cookie: {}\r\n", cookie), String::new(), "403"),
+      (format!("X-CSRF-Token: {}\r\n", token), String::new(), "403"),
+      (
+        format!("Cookie: {}\r\nX-CSRF-Token: {}\r\n", cookie, tampered),
+        String::new(),
+        "403",
+      ),
+      (
+        format!("Cookie: {}\r\nX-CSRF-Token: {}\r\n", cookie, token),
+        String::new(),
+        "200",
+      ),
+      (
+        format!("Cookie: {}\r\n{}", cookie, form),
+        format!("_csrf={}", tampered),
+        "403",
+      ),
+      (
+        format!("Cookie: {}\r\n{}", cookie, form),
+        format!("a=1&_csrf={}", token),
+        "200",
+      ),
+    ] {
+      let response = post(&headers, &body);
+      assert!(
+        response.starts_with(&format!("HTTP/1.1 {}", status)),
+        "{:?} {:?}: {}",
+        headers,
+        body,
+        response
+      );
+      if status == "403" {
+        assert!(response.ends_with("403 Forbidden"), "{}", response);
+      } else {
+        assert!(response.ends_with("posted"), "{}", response);
+      }
+    }
+    server.shutdown();
+  }
+}
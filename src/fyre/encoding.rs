@@ -0,0 +1,124 @@
+//! # `fyre.base64` and `fyre.hex`
+//!
+//! Binary-safe encoders and decoders for Lua strings.
+//!
+//! ```lua
+//! fyre.base64.encode("hi?")            -- "aGk/"
+//! fyre.base64.encode_urlsafe("hi?")    -- "aGk_" (never padded)
+//! local raw, err = fyre.base64.decode(request.body)
+//! fyre.hex.encode("\1\255")            -- "01ff"
+//! ```
+//!
+//! Decoders accept input with or without `=` padding and return `nil, err`
+//! on malformed input so handlers can answer with a 400. The same functions
+//! back `request.basic_auth()`.
+
+use base64::alphabet;
+use base64::engine::general_purpose::{GeneralPurpose, GeneralPurposeConfig};
+use base64::engine::DecodePaddingMode;
+use base64::Engine;
+use mlua::prelude::*;
+
+/// Standard alphabet, padded on encode, padding optional on decode.
+const STANDARD: GeneralPurpose = GeneralPurpose::new(
+  &alphabet::STANDARD,
+  GeneralPurposeConfig::new().with_decode_padding_mode(DecodePaddingMode::Indifferent),
+);
+
+/// URL-safe alphabet, unpadded on encode, padding optional on decode.
+const URL_SAFE: GeneralPurpose = GeneralPurpose::new(
+  &alphabet::URL_SAFE,
+  GeneralPurposeConfig::new()
+    .with_encode_padding(false)
+    .with_decode_padding_mode(DecodePaddingMode::Indifferent),
+);
+
+/// Encodes bytes as standard, padded base64.
+pub fn base64_encode(data: &[u8]) -> String {
+  STANDARD.encode(data)
+}
+
+/// Decodes standard base64, with or without padding.
+pub fn base64_decode(data: &[u8]) -> Result<Vec<u8>, String> {
+  STANDARD.decode(data).map_err(|e| format!("invalid base64: {}", e))
+}
+
+/// Encodes bytes as unpadded URL-safe base64.
+pub fn base64_encode_urlsafe(data: &[u8]) -> String {
+  URL_SAFE.encode(data)
+}
+
+/// Decodes URL-safe base64, with or without padding.
+pub fn base64_decode_urlsafe(data: &[u8]) -> Result<Vec<u8>, String> {
+  URL_SAFE.decode(data).map_err(|e| format!("invalid base64: {}", e))
+}
+
+/// Encodes bytes as lowercase hex.
+pub fn hex_encode(data: &[u8]) -> String {
+  hex::encode(data)
+}
+
+/// Decodes hex (either case).
+pub fn hex_decode(data: &[u8]) -> Result<Vec<u8>, String> {
+  hex::decode(data).map_err(|e| format!("invalid hex: {}", e))
+}
+
+/// Extracts the username and password from a `Basic` `Authorization` header
+/// value, returning `None` if it is missing, malformed, or not UTF-8.
+pub fn parse_basic_auth(header: &str) -> Option<(String, String)> {
+  let (scheme, credentials) = header.trim().split_once(' ')?;
+  if !scheme.eq_ignore_ascii_case("basic") {
+    return None;
+  }
+
+  let decoded = base64_decode(credentials.trim().as_bytes()).ok()?;
+  let decoded = String::from_utf8(decoded).ok()?;
+  let (user, pass) = decoded.split_once(':')?;
+  Some((user.to_string(), pass.to_string()))
+}
+
+/// Wraps a Rust decoder as a Lua function returning `bytes` or `nil, err`.
+fn decoder(
+  lua: &Lua,
+  decode: fn(&[u8]) -> Result<Vec<u8>, String>,
+) -> LuaResult<LuaFunction> {
+  lua.create_function(move |lua, data: LuaString| {
+    Ok(match decode(&data.as_bytes()) {
+      Ok(bytes) => (Some(lua.create_string(bytes)?), None),
+      Err(err) => (None, Some(err)),
+    })
+  })
+}
+
+/// Wraps a Rust encoder as a Lua function.
+fn encoder(lua: &Lua, encode: fn(&[u8]) -> String) -> LuaResult<LuaFunction> {
+  lua.create_function(move |_, data: LuaString| Ok(encode(&data.as_bytes())))
+}
+
+/// Builds the `fyre.base64` module table.
+///
+/// # Errors
+///
+/// This function will return a `LuaError` if the module table or its functions
+/// cannot be created.
+pub fn base64_module(lua: &Lua) -> LuaResult<LuaTable> {
+  let module = lua.create_table()?;
+  module.set("encode", encoder(lua, base64_encode)?)?;
+  module.set("decode", decoder(lua, base64_decode)?)?;
+  module.set("encode_urlsafe", encoder(lua, base64_encode_urlsafe)?)?;
+  module.set("decode_urlsafe", decoder(lua, base64_decode_urlsafe)?)?;
+  Ok(module)
+}
+
+/// Builds the `fyre.hex` module table.
+///
+/// # Errors
+///
+/// This function will return a `LuaError` if the module table or its functions
+/// cannot be created.
+pub fn hex_module(lua: &Lua) -> LuaResult<LuaTable> {
+  let module = lua.create_table()?;
+  module.set("encode", encoder(lua, hex_encode)?)?;
+  module.set("decode", decoder(lua, hex_decode)?)?;
+  Ok(module)
+}
@@ -0,0 +1,148 @@
+//! # `fyre.env`
+//!
+//! Read-only access to environment variables, restricted by
+//! `CONFIG.sandbox.env_allow` in `config.lua`.
+//!
+//! ```lua
+//! local db = fyre.env.get("DATABASE_URL")
+//! local mode = fyre.env.get("FYRE_MODE", "development")
+//! ```
+//!
+//! An allowlist entry ending in `_` or `*` is a prefix (`"FYRE_"` allows
+//! `FYRE_MODE`); any other entry must match the variable name exactly. Names
+//! outside the allowlist read as `nil` (or the default) and are logged once.
+//! `config.lua` itself is not restricted.
+//!
+//! `config.lua` also has a shorter `env` global, for settings that differ
+//! between environments:
+//!
+//! ```lua
+//! CONFIG = {
+//!   addr = env("FYRE_ADDR", "0.0.0.0:8000"),
+//!   sqlite = { dir = env.require("DATA_DIR") },
+//! }
+//! ```
+//!
+//! `env.require` returns `""` for an unset variable so the rest of the
+//! config still runs, and loading then fails listing every missing one.
+
+use mlua::prelude::*;
+use std::collections::HashSet;
+use std::sync::{Arc, Mutex};
+
+use crate::locks;
+
+/// The policy deciding which environment variables scripts may read.
+pub struct EnvAccess {
+  /// The allowed names and prefixes. `None` means unrestricted.
+  allow: Option<Vec<String>>,
+  /// Names that have already been reported as denied.
+  denied_logged: Mutex<HashSet<String>>,
+}
+
+impl EnvAccess {
+  /// Creates a policy that only permits the names and prefixes in `allow`.
+  pub fn new(allow: Vec<String>) -> Self {
+    EnvAccess {
+      allow: Some(allow),
+      denied_logged: Mutex::new(HashSet::new()),
+    }
+  }
+
+  /// Creates a policy that permits every variable, used for `config.lua`.
+  pub fn unrestricted() -> Self {
+    EnvAccess {
+      allow: None,
+      denied_logged: Mutex::new(HashSet::new()),
+    }
+  }
+
+  /// Returns `true` if `name` may be read.
+  fn is_allowed(&self, name: &str) -> bool {
+    let Some(allow) = &self.allow else {
+      return true;
+    };
+
+    allow.iter().any(|entry| {
+      if let Some(prefix) = entry.strip_suffix('*') {
+        name.starts_with(prefix)
+      } else if entry.ends_with('_') {
+        name.starts_with(entry.as_str())
+      } else {
+        entry == name
+      }
+    })
+  }
+
+  /// Looks up `name`, returning `None` if it is unset, not valid UTF-8, or
+  /// denied by the allowlist.
+  pub fn get(&self, name: &str) -> Option<String> {
+    if !self.is_allowed(name) {
+      if locks::lock(&self.denied_logged, "fyre.env log").insert(name.to_string()) {
+        warn!("fyre.env denied access to {} (not in CONFIG.sandbox.env_allow)", name);
+      }
+      return None;
+    }
+
+    std::env::var(name).ok()
+  }
+}
+
+/// Builds the `fyre.env` module table.
+///
+/// # Arguments
+///
+/// * `lua` - The Lua state to create the module in.
+/// * `access` - The policy lookups are checked against.
+///
+/// # Errors
+///
+/// This function will return a `LuaError` if the module table or its functions
+/// cannot be created.
+pub fn module(lua: &Lua, access: &Arc<EnvAccess>) -> LuaResult<LuaTable> {
+  let module = lua.create_table()?;
+
+  let access = access.clone();
+  module.set(
+    "get",
+    lua.create_function(move |_, (name, default): (String, Option<String>)| {
+      Ok(access.get(&name).or(default))
+    })?,
+  )?;
+
+  Ok(module)
+}
+
+/// Builds the `env` global of `config.lua`: `env(name [, default])` reads a
+/// variable, and `env.require(name)` reads one that must be set, adding its
+/// name to `missing` if it isn't.
+///
+/// # Errors
+///
+/// This function will return a `LuaError` if the table or its functions
+/// cannot be created.
+pub fn config_global(lua: &Lua, missing: Arc<Mutex<Vec<String>>>) -> LuaResult<LuaTable> {
+  let env = lua.create_table()?;
+  env.set(
+    "require",
+    lua.create_function(move |_, name: String| {
+      Ok(std::env::var(&name).unwrap_or_else(|_| {
+        let mut missing = locks::lock(&missing, "missing environment variables");
+        if !missing.contains(&name) {
+          missing.push(name);
+        }
+        String::new()
+      }))
+    })?,
+  )?;
+
+  let metatable = lua.create_table()?;
+  metatable.set(
+    "__call",
+    lua.create_function(|_, (_, name, default): (LuaTable, String, Option<String>)| {
+      Ok(std::env::var(name).ok().or(default))
+    })?,
+  )?;
+  env.set_metatable(Some(metatable))?;
+  Ok(env)
+}
@@ -0,0 +1,319 @@
+//! # `fyre.exec`
+//!
+//! Runs allowlisted external programs without a shell.
+//!
+//! ```lua
+//! local res, err = fyre.exec.run{
+//!   cmd = "convert",
+//!   args = { "-", "-resize", "200x200", "png:-" },
+//!   stdin = request.body,
+//!   timeout_ms = 5000,
+//! }
+//! if not res or res.status ~= 0 then
+//!   response.status = 500
+//!   return
+//! end
+//! response.body = res.stdout
+//! ```
+//!
+//! `cmd` must appear verbatim in `CONFIG.sandbox.exec_allow`; anything else
+//! raises an error rather than returning `nil, err`, so a missing entry shows
+//! up as a failed request instead of a silently empty result. Arguments are
+//! passed as an argv array and never interpreted by a shell. The child gets
+//! an empty environment apart from `PATH` and any `env` table passed in, and
+//! is killed if it runs past its timeout or writes more than
+//! `MAX_OUTPUT_BYTES` to stdout or stderr.
+//!
+//! `os.execute` and `io.popen` are taken out of script states (see
+//! `fyre::register`), so this is the only way a script can start a program.
+
+use mlua::prelude::*;
+use std::io::{Read, Write};
+use std::process::{Child, Command, Stdio};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use crate::AppState;
+
+/// The timeout applied when a script does not pass `timeout_ms`.
+const DEFAULT_TIMEOUT_MS: u64 = 5_000;
+/// The largest timeout a script may request.
+const MAX_TIMEOUT_MS: u64 = 60_000;
+/// The most output kept from each of stdout and stderr.
+const MAX_OUTPUT_BYTES: u64 = 10 * 1024 * 1024;
+/// How often the child is polled for exit.
+const POLL_INTERVAL: Duration = Duration::from_millis(5);
+
+/// The programs scripts may run, from `CONFIG.sandbox.exec_allow` in
+/// `config.lua`.
+pub struct ExecPolicy {
+  allow: Vec<String>,
+}
+
+impl ExecPolicy {
+  /// Creates a policy allowing exactly the listed commands. An empty list
+  /// allows nothing.
+  pub fn new(allow: Vec<String>) -> Self {
+    ExecPolicy { allow }
+  }
+
+  fn is_allowed(&self, cmd: &str) -> bool {
+    self.allow.iter().any(|entry| entry == cmd)
+  }
+}
+
+/// A command as described by the table passed to `fyre.exec.run`.
+struct ExecRequest {
+  cmd: String,
+  args: Vec<LuaString>,
+  stdin: Option<Vec<u8>>,
+  env: Vec<(String, String)>,
+  timeout: Duration,
+}
+
+impl ExecRequest {
+  fn from_table(opts: &LuaTable) -> LuaResult<Self> {
+    let cmd = opts
+      .get::<Option<String>>("cmd")?
+      .ok_or_else(|| LuaError::external("fyre.exec.run: 'cmd' is required"))?;
+
+    let args = match opts.get::<Option<LuaTable>>("args")? {
+      Some(args) => args.sequence_values().collect::<LuaResult<_>>()?,
+      None => Vec::new(),
+    };
+
+    let mut env = Vec::new();
+    if let Some(table) = opts.get::<Option<LuaTable>>("env")? {
+      for pair in table.pairs::<String, String>() {
+        env.push(pair?);
+      }
+    }
+
+    let timeout_ms = opts
+      .get::<Option<u64>>("timeout_ms")?
+      .unwrap_or(DEFAULT_TIMEOUT_MS)
+      .min(MAX_TIMEOUT_MS);
+
+    Ok(ExecRequest {
+      cmd,
+      args,
+      stdin: opts
+        .get::<Option<LuaString>>("stdin")?
+        .map(|s| s.as_bytes().to_vec()),
+      env,
+      timeout: Duration::from_millis(timeout_ms),
+    })
+  }
+}
+
+/// What a finished command produced.
+struct ExecOutput {
+  status: Option<i32>,
+  stdout: Vec<u8>,
+  stderr: Vec<u8>,
+}
+
+/// Reads a child's output stream on its own thread, raising `overflow` and
+/// stopping once more than `MAX_OUTPUT_BYTES` have arrived.
+fn capture(
+  mut stream: impl Read + Send + 'static,
+  overflow: Arc<AtomicBool>,
+) -> JoinHandle<Vec<u8>> {
+  thread::spawn(move || {
+    let mut data = Vec::new();
+    let _ = (&mut stream)
+      .take(MAX_OUTPUT_BYTES + 1)
+      .read_to_end(&mut data);
+    if data.len() as u64 > MAX_OUTPUT_BYTES {
+      overflow.store(true, Ordering::Relaxed);
+      data.truncate(MAX_OUTPUT_BYTES as usize);
+    }
+    data
+  })
+}
+
+fn kill(child: &mut Child) {
+  let _ = child.kill();
+  let _ = child.wait();
+}
+
+/// Spawns the command and waits for it, enforcing the timeout and output cap.
+fn run(req: ExecRequest) -> Result<ExecOutput, String> {
+  let mut command = Command::new(&req.cmd);
+  for arg in &req.args {
+    let arg = arg
+      .to_str()
+      .map_err(|_| "arguments must be valid UTF-8".to_string())?;
+    command.arg(&*arg);
+  }
+  command.env_clear();
+  if let Some(path) = std::env::var_os("PATH") {
+    command.env("PATH", path);
+  }
+  command
+    .envs(req.env)
+    .stdin(if req.stdin.is_some() {
+      Stdio::piped()
+    } else {
+      Stdio::null()
+    })
+    .stdout(Stdio::piped())
+    .stderr(Stdio::piped());
+
+  let mut child = command
+    .spawn()
+    .map_err(|e| format!("failed to run {}: {}", req.cmd, e))?;
+
+  let overflow = Arc::new(AtomicBool::new(false));
+  let stdout = capture(
+    child.stdout.take().expect("stdout is piped"),
+    overflow.clone(),
+  );
+  let stderr = capture(
+    child.stderr.take().expect("stderr is piped"),
+    overflow.clone(),
+  );
+
+  if let (Some(mut pipe), Some(input)) = (child.stdin.take(), req.stdin) {
+    // A child that exits without reading its input closes the pipe; that is
+    // its business, so write errors are ignored.
+    thread::spawn(move || {
+      let _ = pipe.write_all(&input);
+    });
+  }
+
+  let deadline = Instant::now() + req.timeout;
+  let status = loop {
+    match child.try_wait() {
+      Ok(Some(status)) => break status,
+      Ok(None) => {}
+      Err(e) => {
+        kill(&mut child);
+        return Err(format!("failed to wait for {}: {}", req.cmd, e));
+      }
+    }
+    if overflow.load(Ordering::Relaxed) {
+      kill(&mut child);
+      return Err(format!(
+        "{} produced more than {} bytes of output",
+        req.cmd, MAX_OUTPUT_BYTES
+      ));
+    }
+    if Instant::now() >= deadline {
+      kill(&mut child);
+      return Err(format!(
+        "{} timed out after {} ms",
+        req.cmd,
+        req.timeout.as_millis()
+      ));
+    }
+    thread::sleep(POLL_INTERVAL);
+  };
+
+  // The pipes close when the child exits, unless it left a background
+  // process holding them open, so the readers get the rest of the deadline.
+  while !(stdout.is_finished() && stderr.is_finished()) {
+    if Instant::now() >= deadline {
+      return Err(format!(
+        "{} exited but left a process holding its output open",
+        req.cmd
+      ));
+    }
+    thread::sleep(POLL_INTERVAL);
+  }
+  let stdout = stdout.join().unwrap_or_default();
+  let stderr = stderr.join().unwrap_or_default();
+  if overflow.load(Ordering::Relaxed) {
+    return Err(format!(
+      "{} produced more than {} bytes of output",
+      req.cmd, MAX_OUTPUT_BYTES
+    ));
+  }
+
+  Ok(ExecOutput {
+    status: status.code(),
+    stdout,
+    stderr,
+  })
+}
+
+/// Builds the `fyre.exec` module table.
+///
+/// # Errors
+///
+/// This function will return a `LuaError` if the module table or its functions
+/// cannot be created.
+pub fn module(lua: &Lua, state: &Arc<AppState>) -> LuaResult<LuaTable> {
+  let module = lua.create_table()?;
+
+  let st = state.clone();
+  module.set(
+    "run",
+    lua.create_function(move |lua, opts: LuaTable| {
+      let req = ExecRequest::from_table(&opts)?;
+      if !st.exec.is_allowed(&req.cmd) {
+        return Err(LuaError::external(format!(
+          "fyre.exec: '{}' is not listed in CONFIG.sandbox.exec_allow",
+          req.cmd
+        )));
+      }
+
+      match run(req) {
+        Ok(output) => {
+          let res = lua.create_table()?;
+          // `status` is nil if the child was killed by a signal.
+          res.set("status", output.status)?;
+          res.set("stdout", lua.create_string(output.stdout)?)?;
+          res.set("stderr", lua.create_string(output.stderr)?)?;
+          Ok((Some(res), None))
+        }
+        Err(err) => Ok((None, Some(err))),
+      }
+    })?,
+  )?;
+
+  Ok(module)
+}
+
+#[cfg(test)]
+mod tests {
+  use crate::testing::{self, Fixture};
+
+  #[test]
+  fn only_listed_programs_can_be_started() {
+    let fixture = Fixture::new(
+      r#"
+        CONFIG = { sandbox = { exec_allow = { "echo" } } }
+        router.add("/", "run.lua")
+      "#,
+      &[(
+        "run.lua",
+        r#"return { handler = function(request, response)
+          local listed = fyre.exec.run{ cmd = "echo", args = { "hi" } }
+          local ok, err = pcall(fyre.exec.run, { cmd = "id" })
+          local popen = pcall(function() return io.popen("id") end)
+          local execute = pcall(function() return os.execute("id") end)
+          response.body = string.format("%d %s|%s|%s|%s|%s", listed.status, listed.stdout,
+            tostring(ok), tostring(err):match("'id' is not listed") or err, tostring(popen),
+            tostring(execute))
+        end }"#,
+      )],
+    );
+    let server = fixture
+      .builder()
+      .addr("127.0.0.1:0")
+      .workers(1)
+      .load()
+      .unwrap();
+    let addr = server.serve().unwrap().remove(0);
+    let response = testing::get(&addr, "/");
+    assert!(
+      response.ends_with("\r\n\r\n0 hi\n|false|'id' is not listed|false|false"),
+      "{}",
+      response
+    );
+    server.shutdown();
+  }
+}
@@ -0,0 +1,294 @@
+//! # `fyre.fs`
+//!
+//! Filesystem access confined to the directories listed in
+//! `CONFIG.sandbox.fs_allow`.
+//!
+//! ```lua
+//! local data, err = fyre.fs.read("data/prices.json")
+//! fyre.fs.append("data/log.txt", request.path .. "\n")
+//! for _, name in ipairs(fyre.fs.list("data") or {}) do print(name) end
+//! ```
+//!
+//! Every path is resolved in Rust before use: `..` components are refused,
+//! and symlinks are followed and the result must still lie inside one of the
+//! allowed directories. All operations are binary-safe and report failures
+//! as `nil, err`.
+
+use mlua::prelude::*;
+use std::fs::{self, OpenOptions};
+use std::io::{Read, Write};
+use std::path::{Component, Path, PathBuf};
+use std::sync::Arc;
+
+use crate::AppState;
+
+/// The default largest file `fyre.fs.read` will load.
+pub const DEFAULT_MAX_READ_BYTES: u64 = 10 * 1024 * 1024;
+
+/// The directories scripts may touch and the read size limit.
+pub struct FsSandbox {
+  /// Canonicalized allowed directories.
+  roots: Vec<PathBuf>,
+  /// The directory relative handler paths are taken from.
+  base: PathBuf,
+  max_read_bytes: u64,
+}
+
+impl FsSandbox {
+  /// Creates a sandbox over the given directories. Relative directories,
+  /// and the relative paths handlers pass, are taken from `base`.
+  ///
+  /// # Errors
+  ///
+  /// Returns an error message if any directory does not exist or cannot be
+  /// canonicalized.
+  pub fn new(roots: &[String], base: &Path, max_read_bytes: u64) -> Result<Self, String> {
+    let roots = roots
+      .iter()
+      .map(|root| {
+        fs::canonicalize(base.join(root))
+          .map_err(|e| format!("CONFIG.sandbox.fs_allow directory '{}' is not usable: {}", root, e))
+      })
+      .collect::<Result<Vec<_>, _>>()?;
+
+    Ok(FsSandbox {
+      roots,
+      base: base.to_path_buf(),
+      max_read_bytes,
+    })
+  }
+
+  /// Resolves `path` to an absolute path inside one of the allowed
+  /// directories.
+  ///
+  /// The path need not exist yet (for `write` and `mkdir`): its deepest
+  /// existing ancestor is canonicalized, which resolves any symlinks, and the
+  /// remaining components are appended.
+  pub fn resolve(&self, path: &str) -> Result<PathBuf, String> {
+    let requested = Path::new(path);
+    if requested
+      .components()
+      .any(|c| matches!(c, Component::ParentDir))
+    {
+      return Err(format!("path may not contain '..': {}", path));
+    }
+
+    let mut existing = self.base.join(requested);
+    let mut missing = Vec::new();
+    // `symlink_metadata` so a dangling symlink counts as existing and then
+    // fails to canonicalize, rather than being written through.
+    while fs::symlink_metadata(&existing).is_err() {
+      match existing.file_name() {
+        Some(name) => missing.push(name.to_os_string()),
+        None => break,
+      }
+      existing.pop();
+    }
+    let mut resolved = fs::canonicalize(&existing)
+      .map_err(|e| format!("cannot resolve {}: {}", existing.display(), e))?;
+    resolved.extend(missing.iter().rev());
+
+    if self.roots.iter().any(|root| resolved.starts_with(root)) {
+      Ok(resolved)
+    } else {
+      Err(format!("path is outside CONFIG.sandbox.fs_allow: {}", path))
+    }
+  }
+
+  fn read(&self, path: &str) -> Result<Vec<u8>, String> {
+    let resolved = self.resolve(path)?;
+    let file = fs::File::open(&resolved).map_err(|e| format!("{}: {}", path, e))?;
+
+    let mut data = Vec::new();
+    file
+      .take(self.max_read_bytes + 1)
+      .read_to_end(&mut data)
+      .map_err(|e| format!("{}: {}", path, e))?;
+    if data.len() as u64 > self.max_read_bytes {
+      return Err(format!(
+        "{} is larger than the {} byte read limit",
+        path, self.max_read_bytes
+      ));
+    }
+    Ok(data)
+  }
+
+  fn write(&self, path: &str, data: &[u8], append: bool) -> Result<(), String> {
+    let resolved = self.resolve(path)?;
+    let mut file = OpenOptions::new()
+      .create(true)
+      .write(true)
+      .append(append)
+      .truncate(!append)
+      .open(&resolved)
+      .map_err(|e| format!("{}: {}", path, e))?;
+    file.write_all(data).map_err(|e| format!("{}: {}", path, e))
+  }
+
+  fn list(&self, path: &str) -> Result<Vec<String>, String> {
+    let resolved = self.resolve(path)?;
+    let mut names = fs::read_dir(&resolved)
+      .map_err(|e| format!("{}: {}", path, e))?
+      .filter_map(|entry| entry.ok())
+      .map(|entry| entry.file_name().to_string_lossy().into_owned())
+      .collect::<Vec<_>>();
+    names.sort();
+    Ok(names)
+  }
+
+  fn mkdir(&self, path: &str) -> Result<(), String> {
+    let resolved = self.resolve(path)?;
+    fs::create_dir_all(&resolved).map_err(|e| format!("{}: {}", path, e))
+  }
+}
+
+/// Converts a `Result` into the `value` / `nil, err` convention.
+fn lua_result<T>(result: Result<T, String>) -> (Option<T>, Option<String>) {
+  match result {
+    Ok(value) => (Some(value), None),
+    Err(err) => (None, Some(err)),
+  }
+}
+
+/// Builds the `fyre.fs` module table.
+///
+/// # Errors
+///
+/// This function will return a `LuaError` if the module table or its functions
+/// cannot be created.
+pub fn module(lua: &Lua, state: &Arc<AppState>) -> LuaResult<LuaTable> {
+  let module = lua.create_table()?;
+
+  let st = state.clone();
+  module.set(
+    "read",
+    lua.create_function(move |lua, path: String| {
+      Ok(match st.fs.read(&path) {
+        Ok(data) => (Some(lua.create_string(data)?), None),
+        Err(err) => (None, Some(err)),
+      })
+    })?,
+  )?;
+
+  let st = state.clone();
+  module.set(
+    "write",
+    lua.create_function(move |_, (path, data): (String, LuaString)| {
+      Ok(lua_result(st.fs.write(&path, &data.as_bytes(), false).map(|_| true)))
+    })?,
+  )?;
+
+  let st = state.clone();
+  module.set(
+    "append",
+    lua.create_function(move |_, (path, data): (String, LuaString)| {
+      Ok(lua_result(st.fs.write(&path, &data.as_bytes(), true).map(|_| true)))
+    })?,
+  )?;
+
+  let st = state.clone();
+  module.set(
+    "exists",
+    lua.create_function(move |_, path: String| {
+      Ok(st.fs.resolve(&path).is_ok_and(|resolved| resolved.exists()))
+    })?,
+  )?;
+
+  let st = state.clone();
+  module.set(
+    "list",
+    lua.create_function(move |_, path: String| Ok(lua_result(st.fs.list(&path))))?,
+  )?;
+
+  let st = state.clone();
+  module.set(
+    "mkdir",
+    lua.create_function(move |_, path: String| {
+      Ok(lua_result(st.fs.mkdir(&path).map(|_| true)))
+    })?,
+  )?;
+
+  Ok(module)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::testing::Fixture;
+
+  /// A sandbox over `data/` in a fresh directory, which also holds
+  /// `secret.txt` outside it.
+  fn sandbox(max_read_bytes: u64) -> (Fixture, FsSandbox) {
+    let fixture = Fixture::new("", &[]);
+    fs::create_dir(fixture.path().join("data")).unwrap();
+    fs::write(fixture.path().join("secret.txt"), "hunter2").unwrap();
+    let sandbox = FsSandbox::new(&["data/".to_string()], fixture.path(), max_read_bytes).unwrap();
+    (fixture, sandbox)
+  }
+
+  #[test]
+  fn parent_components_are_refused() {
+    let (_fixture, sandbox) = sandbox(DEFAULT_MAX_READ_BYTES);
+    for path in ["data/../secret.txt", "data/x/../../secret.txt", ".."] {
+      let err = sandbox.read(path).unwrap_err();
+      assert!(err.contains("may not contain '..'"), "{}: {}", path, err);
+    }
+    let err = sandbox.read("secret.txt").unwrap_err();
+    assert!(err.contains("outside CONFIG.sandbox.fs_allow"), "{}", err);
+  }
+
+  #[cfg(unix)]
+  #[test]
+  fn symlinks_out_of_the_allowed_directories_are_refused() {
+    let (fixture, sandbox) = sandbox(DEFAULT_MAX_READ_BYTES);
+    let data = fixture.path().join("data");
+    std::os::unix::fs::symlink(fixture.path().join("secret.txt"), data.join("file")).unwrap();
+    std::os::unix::fs::symlink(fixture.path(), data.join("dir")).unwrap();
+    std::os::unix::fs::symlink(fixture.path().join("gone"), data.join("dangling")).unwrap();
+    for path in ["data/file", "data/dir/secret.txt"] {
+      let err = sandbox.read(path).unwrap_err();
+      assert!(err.contains("outside CONFIG.sandbox.fs_allow"), "{}: {}", path, err);
+    }
+    // Writes through a link, even to a file that doesn't exist yet, are
+    // refused too.
+    assert!(sandbox.write("data/dir/new.txt", b"x", false).is_err());
+    assert!(sandbox.write("data/dangling", b"x", false).is_err());
+    assert!(!fixture.path().join("new.txt").exists());
+    assert!(!fixture.path().join("gone").exists());
+    // A link that stays inside is followed.
+    std::os::unix::fs::symlink(data.join("real.txt"), data.join("inside")).unwrap();
+    fs::write(data.join("real.txt"), "ok").unwrap();
+    assert_eq!(sandbox.read("data/inside").unwrap(), b"ok");
+  }
+
+  #[test]
+  fn reads_are_limited_in_size() {
+    let (fixture, sandbox) = sandbox(10);
+    fs::write(fixture.path().join("data/ten"), "0123456789").unwrap();
+    fs::write(fixture.path().join("data/eleven"), "0123456789a").unwrap();
+    assert_eq!(sandbox.read("data/ten").unwrap(), b"0123456789");
+    assert_eq!(
+      sandbox.read("data/eleven").unwrap_err(),
+      "data/eleven is larger than the 10 byte read limit"
+    );
+  }
+
+  #[test]
+  fn new_files_are_written_under_an_allowed_directory() {
+    let (fixture, sandbox) = sandbox(DEFAULT_MAX_READ_BYTES);
+    sandbox.mkdir("data/uploads/2024").unwrap();
+    sandbox
+      .write("data/uploads/2024/a.txt", b"one\n", false)
+      .unwrap();
+    sandbox
+      .write("data/uploads/2024/a.txt", b"two\n", true)
+      .unwrap();
+    assert_eq!(
+      fs::read(fixture.path().join("data/uploads/2024/a.txt")).unwrap(),
+      b"one\ntwo\n"
+    );
+    assert_eq!(sandbox.list("data/uploads/2024").unwrap(), ["a.txt"]);
+    assert!(sandbox.write("new.txt", b"x", false).is_err());
+    assert!(!fixture.path().join("new.txt").exists());
+  }
+}
@@ -0,0 +1,450 @@
+//! # `fyre.http`
+//!
+//! A blocking outbound HTTP(S) client for handler scripts, backed by `ureq`.
+//!
+//! ```lua
+//! local res, err = fyre.http.request{
+//!   method = "POST",
+//!   url = "https://api.example.com/hooks",
+//!   headers = { ["Content-Type"] = "application/json" },
+//!   body = '{"ok": true}',
+//!   timeout_ms = 2000,
+//! }
+//! if not res then
+//!   response.status = 502
+//!   return
+//! end
+//! print(res.status, res.headers["content-type"], res.body)
+//! ```
+//!
+//! Calls made while handling a request carry its trace context in
+//! `traceparent` and `tracestate` headers (see `trace`), unless the script
+//! sets `traceparent` itself or passes `trace = false`. When the request's
+//! spans are exported, each call is a client span of its own (see
+//! `span_export`).
+//!
+//! Every call is bounded by a timeout (`DEFAULT_TIMEOUT_MS` unless the script
+//! asks for less, never more than `MAX_TIMEOUT_MS`, and at least 1 ms). When
+//! `CONFIG.sandbox.http_allow` is set, only the listed hosts may be contacted
+//! and redirects are not followed. A listed name is also refused if it
+//! resolves to a loopback, private, link-local, or otherwise internal address
+//! (see `is_internal`), checked on the addresses the connection is actually
+//! made to, so a script can't be steered into requesting internal services
+//! through DNS. `sandbox.http_allow_private = true` lifts that check, for
+//! allowlists that name internal hosts on purpose. Without
+//! `sandbox.http_allow` outbound requests are unrestricted.
+
+use mlua::prelude::*;
+use std::io::{self, Read};
+use std::net::{IpAddr, SocketAddr, ToSocketAddrs};
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::span_export::{self, SpanKind};
+use crate::trace::TraceContext;
+use crate::AppState;
+
+/// The timeout applied when a script does not pass `timeout_ms`.
+const DEFAULT_TIMEOUT_MS: u64 = 10_000;
+/// The largest timeout a script may request.
+const MAX_TIMEOUT_MS: u64 = 60_000;
+/// The largest response body that will be read into the Lua state.
+const MAX_RESPONSE_BYTES: u64 = 10 * 1024 * 1024;
+
+/// The outbound HTTP client shared by every request.
+///
+/// The underlying `ureq::Agent` pools connections, so it is built once at
+/// startup and cloned into each Lua state.
+pub struct HttpClient {
+  agent: ureq::Agent,
+  allow: Option<Vec<String>>,
+}
+
+impl HttpClient {
+  /// Builds the client from the `CONFIG.sandbox.http_allow` host list in
+  /// `config.lua`.
+  ///
+  /// # Arguments
+  ///
+  /// * `allow` - The hosts scripts may contact. An entry of the form
+  ///   `*.example.com` matches any subdomain of `example.com`. `None` leaves
+  ///   outbound requests unrestricted.
+  /// * `allow_private` - Whether an allowed host may resolve to an internal
+  ///   address, from `CONFIG.sandbox.http_allow_private`.
+  pub fn new(allow: Option<Vec<String>>, allow_private: bool) -> Self {
+    let mut builder =
+      ureq::AgentBuilder::new().timeout(Duration::from_millis(MAX_TIMEOUT_MS));
+    if allow.is_some() {
+      // A redirect could point anywhere, so only follow them when unrestricted.
+      builder = builder.redirects(0);
+      if !allow_private {
+        builder = builder.resolver(resolve_public);
+      }
+    }
+
+    HttpClient {
+      agent: builder.build(),
+      allow: allow.map(|hosts| hosts.into_iter().map(|h| h.to_ascii_lowercase()).collect()),
+    }
+  }
+
+  /// Returns `true` if the allowlist permits requests to `host`.
+  fn is_allowed(&self, host: &str) -> bool {
+    let Some(allow) = &self.allow else {
+      return true;
+    };
+
+    let host = host.to_ascii_lowercase();
+    allow.iter().any(|entry| match entry.strip_prefix("*.") {
+      Some(domain) => host
+        .strip_suffix(domain)
+        .is_some_and(|sub| sub.len() > 1 && sub.ends_with('.')),
+      None => *entry == host,
+    })
+  }
+}
+
+/// Resolves `netloc` (`host:port`) for the agent, leaving out internal
+/// addresses, so an allowed name pointed at one can't be used to reach it.
+///
+/// # Errors
+///
+/// This function will return an error if the name doesn't resolve, or only
+/// to internal addresses.
+fn resolve_public(netloc: &str) -> io::Result<Vec<SocketAddr>> {
+  let addrs: Vec<SocketAddr> = netloc.to_socket_addrs()?.collect();
+  let public: Vec<SocketAddr> = addrs
+    .iter()
+    .filter(|addr| !is_internal(addr.ip()))
+    .copied()
+    .collect();
+  if public.is_empty() && !addrs.is_empty() {
+    warn!("fyre.http blocked request to {}: it resolves to an internal address", netloc);
+    return Err(io::Error::new(
+      io::ErrorKind::PermissionDenied,
+      format!("{} resolves to an internal address", netloc),
+    ));
+  }
+  Ok(public)
+}
+
+/// Whether `ip` is one a server shouldn't be made to call on a script's
+/// behalf: loopback, private, link-local (cloud metadata services live at
+/// `169.254.169.254`), shared, unspecified, broadcast, or multicast.
+fn is_internal(ip: IpAddr) -> bool {
+  match ip {
+    IpAddr::V4(ip) => {
+      let [a, b, ..] = ip.octets();
+      ip.is_loopback()
+        || ip.is_private()
+        || ip.is_link_local()
+        || ip.is_unspecified()
+        || ip.is_broadcast()
+        || ip.is_multicast()
+        || a == 0
+        // 100.64.0.0/10, carrier-grade NAT.
+        || (a == 100 && (64..128).contains(&b))
+    }
+    IpAddr::V6(ip) => {
+      if let Some(v4) = ip.to_ipv4_mapped() {
+        return is_internal(IpAddr::V4(v4));
+      }
+      let first = ip.segments()[0];
+      ip.is_loopback()
+        || ip.is_unspecified()
+        || ip.is_multicast()
+        // fc00::/7, unique local.
+        || first & 0xfe00 == 0xfc00
+        // fe80::/10, link-local.
+        || first & 0xffc0 == 0xfe80
+    }
+  }
+}
+
+/// An outbound request as described by the table passed to `fyre.http.request`.
+struct OutboundRequest {
+  method: String,
+  url: String,
+  headers: Vec<(String, String)>,
+  body: Vec<u8>,
+  timeout: Duration,
+  /// Whether the request's trace context is sent along.
+  trace: bool,
+}
+
+impl OutboundRequest {
+  /// Reads the request description from a Lua options table.
+  fn from_table(opts: &LuaTable) -> LuaResult<Self> {
+    let method = opts
+      .get::<Option<String>>("method")?
+      .unwrap_or_else(|| "GET".to_string())
+      .to_ascii_uppercase();
+
+    let url: String = opts
+      .get::<Option<String>>("url")?
+      .ok_or_else(|| LuaError::external("fyre.http.request: 'url' is required"))?;
+
+    let mut headers = Vec::new();
+    if let Some(table) = opts.get::<Option<LuaTable>>("headers")? {
+      for pair in table.pairs::<String, String>() {
+        headers.push(pair?);
+      }
+    }
+
+    let body = match opts.get::<Option<LuaString>>("body")? {
+      Some(body) => body.as_bytes().to_vec(),
+      None => Vec::new(),
+    };
+
+    let timeout_ms = opts
+      .get::<Option<u64>>("timeout_ms")?
+      .unwrap_or(DEFAULT_TIMEOUT_MS)
+      .min(MAX_TIMEOUT_MS);
+    if timeout_ms == 0 {
+      return Err(LuaError::external(
+        "fyre.http.request: 'timeout_ms' must be at least 1",
+      ));
+    }
+
+    let trace = opts.get::<Option<bool>>("trace")?.unwrap_or(true);
+
+    Ok(OutboundRequest {
+      method,
+      url,
+      headers,
+      body,
+      timeout: Duration::from_millis(timeout_ms),
+      trace,
+    })
+  }
+}
+
+/// Builds the `fyre.http` module table.
+///
+/// # Errors
+///
+/// This function will return a `LuaError` if the module table or its functions
+/// cannot be created.
+pub fn module(lua: &Lua, state: &Arc<AppState>) -> LuaResult<LuaTable> {
+  let module = lua.create_table()?;
+
+  let st = state.clone();
+  module.set(
+    "request",
+    lua.create_function(move |lua, opts: LuaTable| {
+      let req = OutboundRequest::from_table(&opts)?;
+      send(lua, &st.http, req)
+    })?,
+  )?;
+
+  let st = state.clone();
+  module.set(
+    "get",
+    lua.create_function(move |lua, (url, opts): (String, Option<LuaTable>)| {
+      let opts = opts.map_or_else(|| lua.create_table(), Ok)?;
+      opts.set("method", "GET")?;
+      opts.set("url", url)?;
+      send(lua, &st.http, OutboundRequest::from_table(&opts)?)
+    })?,
+  )?;
+
+  let st = state.clone();
+  module.set(
+    "post",
+    lua.create_function(
+      move |lua, (url, body, opts): (String, Option<LuaString>, Option<LuaTable>)| {
+        let opts = opts.map_or_else(|| lua.create_table(), Ok)?;
+        opts.set("method", "POST")?;
+        opts.set("url", url)?;
+        opts.set("body", body)?;
+        send(lua, &st.http, OutboundRequest::from_table(&opts)?)
+      },
+    )?,
+  )?;
+
+  Ok(module)
+}
+
+/// Performs an outbound request and converts the outcome into Lua values.
+///
+/// Returns `{ status, headers, body }` on any HTTP response (including 4xx and
+/// 5xx), or `nil, err` when the request could not be made at all.
+fn send(
+  lua: &Lua,
+  client: &HttpClient,
+  req: OutboundRequest,
+) -> LuaResult<(LuaValue, Option<String>)> {
+  let parsed = match url::Url::parse(&req.url) {
+    Ok(parsed) => parsed,
+    Err(e) => {
+      return Ok((
+        LuaValue::Nil,
+        Some(format!("invalid url '{}': {}", req.url, e)),
+      ))
+    }
+  };
+
+  if parsed.scheme() != "http" && parsed.scheme() != "https" {
+    return Ok((
+      LuaValue::Nil,
+      Some(format!("unsupported url scheme: {}", parsed.scheme())),
+    ));
+  }
+
+  let host = parsed.host_str().unwrap_or_default();
+  if !client.is_allowed(host) {
+    warn!("fyre.http blocked request to host not in CONFIG.sandbox.http_allow: {}", host);
+    return Ok((LuaValue::Nil, Some(format!("host not allowed: {}", host))));
+  }
+
+  let mut outbound = client.agent.request_url(&req.method, &parsed).timeout(req.timeout);
+  for (name, value) in &req.headers {
+    outbound = outbound.set(name, value);
+  }
+  let has_traceparent = req
+    .headers
+    .iter()
+    .any(|(name, _)| name.eq_ignore_ascii_case("traceparent"));
+  let span = span_export::start(lua, &req.method, SpanKind::Client);
+  if let (true, false, Some(trace)) = (
+    req.trace,
+    has_traceparent,
+    lua.app_data_ref::<TraceContext>(),
+  ) {
+    let traceparent = match &span {
+      Some(span) => trace.traceparent_from(span.span_id()),
+      None => trace.traceparent(),
+    };
+    outbound = outbound.set("traceparent", &traceparent);
+    if let Some(tracestate) = &trace.tracestate {
+      outbound = outbound.set("tracestate", tracestate);
+    }
+  }
+
+  let result = if req.body.is_empty() {
+    outbound.call()
+  } else {
+    outbound.send_bytes(&req.body)
+  };
+
+  let result = match result {
+    Ok(res) | Err(ureq::Error::Status(_, res)) => Ok(res),
+    Err(e) => Err(e.to_string()),
+  };
+  if let Some(span) = span {
+    // The query is left out, since it may carry credentials.
+    let mut url = parsed.clone();
+    url.set_query(None);
+    url.set_fragment(None);
+    let _ = url.set_username("");
+    let _ = url.set_password(None);
+    let mut attributes = vec![
+      ("http.request.method", req.method.as_str().into()),
+      ("url.full", url.to_string().into()),
+      ("server.address", host.into()),
+    ];
+    let error = match &result {
+      Ok(res) => {
+        attributes.push(("http.response.status_code", i64::from(res.status()).into()));
+        (res.status() >= 400).then(|| format!("status {}", res.status()))
+      }
+      Err(e) => Some(e.clone()),
+    };
+    span.end(lua, attributes, error);
+  }
+  let res = match result {
+    Ok(res) => res,
+    Err(e) => return Ok((LuaValue::Nil, Some(e))),
+  };
+
+  let status = res.status();
+  let headers = lua.create_table()?;
+  for name in res.headers_names() {
+    if let Some(value) = res.header(&name) {
+      headers.set(name.to_ascii_lowercase(), value)?;
+    }
+  }
+
+  let mut body = Vec::new();
+  if let Err(e) = res.into_reader().take(MAX_RESPONSE_BYTES + 1).read_to_end(&mut body) {
+    return Ok((LuaValue::Nil, Some(format!("failed to read response body: {}", e))));
+  }
+  if body.len() as u64 > MAX_RESPONSE_BYTES {
+    return Ok((
+      LuaValue::Nil,
+      Some(format!("response body exceeds {} bytes", MAX_RESPONSE_BYTES)),
+    ));
+  }
+
+  let result = lua.create_table()?;
+  result.set("status", status)?;
+  result.set("headers", headers)?;
+  result.set("body", lua.create_string(&body)?)?;
+
+  Ok((LuaValue::Table(result), None))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn internal_addresses_are_recognized() {
+    for ip in [
+      "127.0.0.1",
+      "10.1.2.3",
+      "172.16.0.1",
+      "192.168.1.1",
+      "169.254.169.254",
+      "100.64.0.1",
+      "0.0.0.0",
+      "::1",
+      "::",
+      "fd00::1",
+      "fe80::1",
+      "::ffff:127.0.0.1",
+    ] {
+      assert!(is_internal(ip.parse().unwrap()), "{} should be internal", ip);
+    }
+    for ip in ["93.184.216.34", "100.128.0.1", "2606:2800:220:1::1"] {
+      assert!(!is_internal(ip.parse().unwrap()), "{} should be public", ip);
+    }
+  }
+
+  #[test]
+  fn resolver_refuses_internal_addresses() {
+    assert_eq!(
+      resolve_public("169.254.169.254:80").unwrap_err().kind(),
+      io::ErrorKind::PermissionDenied
+    );
+    assert_eq!(
+      resolve_public("93.184.216.34:443").unwrap(),
+      vec!["93.184.216.34:443".parse::<SocketAddr>().unwrap()]
+    );
+  }
+
+  #[test]
+  fn allowlist_matches_hosts_and_subdomains() {
+    let client = HttpClient::new(
+      Some(vec!["api.example.com".into(), "*.Example.org".into()]),
+      false,
+    );
+    assert!(client.is_allowed("API.example.com"));
+    assert!(client.is_allowed("a.example.org"));
+    assert!(!client.is_allowed("example.org"));
+    assert!(!client.is_allowed("evilexample.org"));
+    assert!(!client.is_allowed("example.com"));
+  }
+
+  #[test]
+  fn zero_timeout_is_rejected() {
+    let lua = Lua::new();
+    let opts = lua.create_table().unwrap();
+    opts.set("url", "https://example.com/").unwrap();
+    opts.set("timeout_ms", 0).unwrap();
+    assert!(OutboundRequest::from_table(&opts).is_err());
+    opts.set("timeout_ms", 1).unwrap();
+    let req = OutboundRequest::from_table(&opts).unwrap();
+    assert_eq!(req.timeout, Duration::from_millis(1));
+  }
+}
@@ -0,0 +1,124 @@
+//! # JSON Conversion
+//!
+//! Conversion between Lua values and `serde_json::Value`, for the modules
+//! that exchange JSON with the outside world (e.g. `fyre.jwt`).
+//!
+//! A table whose keys are exactly `1..n` (with `n > 0`) becomes an array;
+//! any other table becomes an object, with integer keys written as strings.
+//! An empty table becomes `{}`.
+
+use mlua::prelude::*;
+use serde_json::{Map, Number, Value};
+
+/// How deeply nested a table may be before it is rejected (this also stops
+/// cyclic tables).
+const MAX_DEPTH: usize = 32;
+
+/// Converts a Lua value to JSON.
+///
+/// # Errors
+///
+/// This function will return a `LuaError` for functions, userdata, non-UTF-8
+/// strings, non-finite numbers, unsupported table keys, or tables nested
+/// deeper than `MAX_DEPTH`.
+pub fn to_json(value: &LuaValue) -> LuaResult<Value> {
+  convert(value, 0)
+}
+
+fn convert(value: &LuaValue, depth: usize) -> LuaResult<Value> {
+  Ok(match value {
+    LuaValue::Nil => Value::Null,
+    LuaValue::Boolean(b) => Value::Bool(*b),
+    LuaValue::Integer(i) => Value::Number((*i).into()),
+    LuaValue::Number(n) => Value::Number(
+      Number::from_f64(*n)
+        .ok_or_else(|| LuaError::external(format!("cannot encode {} as JSON", n)))?,
+    ),
+    LuaValue::String(s) => Value::String(s.to_str()?.to_string()),
+    LuaValue::Table(table) => {
+      if depth >= MAX_DEPTH {
+        return Err(LuaError::external(format!(
+          "table nesting exceeds {} levels (is it cyclic?)",
+          MAX_DEPTH
+        )));
+      }
+
+      let len = table.raw_len();
+      let mut entries = Vec::new();
+      for pair in table.pairs::<LuaValue, LuaValue>() {
+        entries.push(pair?);
+      }
+
+      if len > 0 && entries.len() == len {
+        let mut array = vec![Value::Null; len];
+        for (key, value) in &entries {
+          match key {
+            LuaValue::Integer(i) if *i >= 1 && (*i as usize) <= len => {
+              array[*i as usize - 1] = convert(value, depth + 1)?;
+            }
+            _ => return object(&entries, depth),
+          }
+        }
+        Value::Array(array)
+      } else {
+        object(&entries, depth)?
+      }
+    }
+    other => {
+      return Err(LuaError::external(format!(
+        "cannot encode {} as JSON",
+        other.type_name()
+      )))
+    }
+  })
+}
+
+fn object(entries: &[(LuaValue, LuaValue)], depth: usize) -> LuaResult<Value> {
+  let mut map = Map::new();
+  for (key, value) in entries {
+    let key = match key {
+      LuaValue::String(s) => s.to_str()?.to_string(),
+      LuaValue::Integer(i) => i.to_string(),
+      other => {
+        return Err(LuaError::external(format!(
+          "cannot encode a {} table key as JSON",
+          other.type_name()
+        )))
+      }
+    };
+    map.insert(key, convert(value, depth + 1)?);
+  }
+  Ok(Value::Object(map))
+}
+
+/// Converts JSON to a Lua value. `null` becomes `nil`.
+///
+/// # Errors
+///
+/// This function will return a `LuaError` if a string or table cannot be
+/// created.
+pub fn from_json(lua: &Lua, value: &Value) -> LuaResult<LuaValue> {
+  Ok(match value {
+    Value::Null => LuaValue::Nil,
+    Value::Bool(b) => LuaValue::Boolean(*b),
+    Value::Number(n) => match n.as_i64() {
+      Some(i) => LuaValue::Integer(i),
+      None => LuaValue::Number(n.as_f64().unwrap_or(f64::NAN)),
+    },
+    Value::String(s) => LuaValue::String(lua.create_string(s)?),
+    Value::Array(items) => {
+      let table = lua.create_table_with_capacity(items.len(), 0)?;
+      for (i, item) in items.iter().enumerate() {
+        table.raw_set(i + 1, from_json(lua, item)?)?;
+      }
+      LuaValue::Table(table)
+    }
+    Value::Object(map) => {
+      let table = lua.create_table_with_capacity(0, map.len())?;
+      for (key, item) in map {
+        table.raw_set(key.as_str(), from_json(lua, item)?)?;
+      }
+      LuaValue::Table(table)
+    }
+  })
+}
@@ -0,0 +1,517 @@
+//! # `fyre.jwt`
+//!
+//! Signing and verification of HMAC JSON Web Tokens (HS256, HS384, HS512).
+//!
+//! ```lua
+//! local token = fyre.jwt.sign({ sub = "42", aud = "api" }, secret, { exp = 3600 })
+//!
+//! local claims, reason = fyre.jwt.verify(token, secret, { leeway = 30, aud = "api" })
+//! if not claims then
+//!   response.status = 401
+//!   response.body = reason   -- e.g. "expired" or "bad_signature"
+//! end
+//! ```
+//!
+//! The algorithm is chosen by the caller, never by the token: `verify` only
+//! accepts the HMAC algorithms above (so `"alg": "none"` is always rejected),
+//! and if `opts.alg` is given the token must use exactly that one. Failures
+//! are reported as `nil, reason` with one of the `Reason` codes below.
+
+use mlua::prelude::*;
+use serde_json::{Map, Value};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use super::crypto::{constant_time_eq, hmac};
+use super::encoding::{base64_decode_urlsafe, base64_encode_urlsafe};
+use super::json::{from_json, to_json};
+
+/// The supported signing algorithms.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Algorithm {
+  Hs256,
+  Hs384,
+  Hs512,
+}
+
+impl Algorithm {
+  fn from_name(name: &str) -> Option<Self> {
+    match name {
+      "HS256" => Some(Algorithm::Hs256),
+      "HS384" => Some(Algorithm::Hs384),
+      "HS512" => Some(Algorithm::Hs512),
+      _ => None,
+    }
+  }
+
+  fn name(self) -> &'static str {
+    match self {
+      Algorithm::Hs256 => "HS256",
+      Algorithm::Hs384 => "HS384",
+      Algorithm::Hs512 => "HS512",
+    }
+  }
+
+  fn sign(self, secret: &[u8], message: &[u8]) -> Vec<u8> {
+    let digest = match self {
+      Algorithm::Hs256 => "sha256",
+      Algorithm::Hs384 => "sha384",
+      Algorithm::Hs512 => "sha512",
+    };
+    hmac(digest, secret, message).expect("SHA-2 HMACs are supported")
+  }
+}
+
+/// Why a token failed verification; `as_str` is what Lua sees.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Reason {
+  /// Not three base64url JSON segments, or a claim has the wrong type.
+  Malformed,
+  /// The header names an algorithm other than the accepted one(s).
+  UnsupportedAlg,
+  /// The signature does not match.
+  BadSignature,
+  /// `exp` has passed.
+  Expired,
+  /// `nbf` or `iat` is in the future.
+  NotYetValid,
+  /// `aud` does not include the expected audience.
+  BadAudience,
+  /// `iss` is not the expected issuer.
+  BadIssuer,
+}
+
+impl Reason {
+  pub fn as_str(self) -> &'static str {
+    match self {
+      Reason::Malformed => "malformed",
+      Reason::UnsupportedAlg => "unsupported_alg",
+      Reason::BadSignature => "bad_signature",
+      Reason::Expired => "expired",
+      Reason::NotYetValid => "not_yet_valid",
+      Reason::BadAudience => "bad_audience",
+      Reason::BadIssuer => "bad_issuer",
+    }
+  }
+}
+
+/// What `verify` checks beyond the signature.
+pub struct VerifyOptions {
+  alg: Option<Algorithm>,
+  leeway: f64,
+  aud: Option<Vec<String>>,
+  iss: Option<String>,
+}
+
+impl VerifyOptions {
+  pub fn from_table(lua: &Lua, opts: Option<LuaTable>) -> LuaResult<Self> {
+    let Some(opts) = opts else {
+      return Ok(VerifyOptions {
+        alg: None,
+        leeway: 0.0,
+        aud: None,
+        iss: None,
+      });
+    };
+
+    let alg = opts
+      .get::<Option<String>>("alg")?
+      .map(|name| parse_algorithm(&name))
+      .transpose()?;
+    let leeway = opts.get::<Option<f64>>("leeway")?.unwrap_or(0.0);
+    if leeway.is_nan() || leeway < 0.0 {
+      return Err(LuaError::external("leeway must be a non-negative number"));
+    }
+    let aud = match opts.get::<LuaValue>("aud")? {
+      LuaValue::Nil => None,
+      LuaValue::Table(list) => Some(list.sequence_values::<String>().collect::<LuaResult<_>>()?),
+      other => Some(vec![String::from_lua(other, lua)?]),
+    };
+
+    Ok(VerifyOptions {
+      alg,
+      leeway,
+      aud,
+      iss: opts.get("iss")?,
+    })
+  }
+}
+
+fn parse_algorithm(name: &str) -> LuaResult<Algorithm> {
+  Algorithm::from_name(name).ok_or_else(|| {
+    LuaError::external(format!(
+      "unsupported JWT algorithm '{}' (expected HS256, HS384, or HS512)",
+      name
+    ))
+  })
+}
+
+fn unix_now() -> f64 {
+  SystemTime::now()
+    .duration_since(UNIX_EPOCH)
+    .map(|d| d.as_secs_f64())
+    .unwrap_or(0.0)
+}
+
+fn require_secret(secret: &[u8]) -> LuaResult<()> {
+  if secret.is_empty() {
+    return Err(LuaError::external("JWT secret must not be empty"));
+  }
+  Ok(())
+}
+
+/// Encodes `value` as JSON and then unpadded base64url.
+fn encode_segment(value: &Value) -> LuaResult<String> {
+  let json = serde_json::to_vec(value).map_err(LuaError::external)?;
+  Ok(base64_encode_urlsafe(&json))
+}
+
+/// Decodes a base64url JSON object segment.
+fn decode_segment(segment: &str) -> Result<Map<String, Value>, Reason> {
+  let json = base64_decode_urlsafe(segment.as_bytes()).map_err(|_| Reason::Malformed)?;
+  match serde_json::from_slice(&json) {
+    Ok(Value::Object(map)) => Ok(map),
+    _ => Err(Reason::Malformed),
+  }
+}
+
+/// Signs `claims`, adding `iat` and `exp` when `exp_in` is given.
+fn sign(
+  mut claims: Map<String, Value>,
+  secret: &[u8],
+  alg: Algorithm,
+  exp_in: Option<i64>,
+) -> LuaResult<String> {
+  if let Some(exp_in) = exp_in {
+    let now = unix_now() as i64;
+    claims.entry("iat").or_insert_with(|| now.into());
+    claims.insert("exp".to_string(), (now + exp_in).into());
+  }
+
+  let header = serde_json::json!({ "alg": alg.name(), "typ": "JWT" });
+  let signing_input = format!(
+    "{}.{}",
+    encode_segment(&header)?,
+    encode_segment(&Value::Object(claims))?
+  );
+  let signature = alg.sign(secret, signing_input.as_bytes());
+  Ok(format!(
+    "{}.{}",
+    signing_input,
+    base64_encode_urlsafe(&signature)
+  ))
+}
+
+/// Verifies `token` and returns its claims.
+pub fn verify(
+  token: &str,
+  secret: &[u8],
+  opts: &VerifyOptions,
+) -> Result<Map<String, Value>, Reason> {
+  let mut segments = token.split('.');
+  let (Some(header), Some(payload), Some(signature), None) = (
+    segments.next(),
+    segments.next(),
+    segments.next(),
+    segments.next(),
+  ) else {
+    return Err(Reason::Malformed);
+  };
+
+  let alg = decode_segment(header)?
+    .get("alg")
+    .and_then(Value::as_str)
+    .map(Algorithm::from_name)
+    .ok_or(Reason::Malformed)?
+    .ok_or(Reason::UnsupportedAlg)?;
+  if opts.alg.is_some_and(|expected| expected != alg) {
+    return Err(Reason::UnsupportedAlg);
+  }
+
+  let signature = base64_decode_urlsafe(signature.as_bytes()).map_err(|_| Reason::Malformed)?;
+  let signing_input = &token[..header.len() + 1 + payload.len()];
+  if !constant_time_eq(&alg.sign(secret, signing_input.as_bytes()), &signature) {
+    return Err(Reason::BadSignature);
+  }
+
+  let claims = decode_segment(payload)?;
+  check_claims(&claims, opts, unix_now())?;
+  Ok(claims)
+}
+
+/// Checks the registered time, audience, and issuer claims.
+fn check_claims(claims: &Map<String, Value>, opts: &VerifyOptions, now: f64) -> Result<(), Reason> {
+  let time_claim = |name: &str| match claims.get(name) {
+    None => Ok(None),
+    Some(value) => value.as_f64().map(Some).ok_or(Reason::Malformed),
+  };
+
+  if let Some(exp) = time_claim("exp")? {
+    if now >= exp + opts.leeway {
+      return Err(Reason::Expired);
+    }
+  }
+  if let Some(nbf) = time_claim("nbf")? {
+    if now + opts.leeway < nbf {
+      return Err(Reason::NotYetValid);
+    }
+  }
+  if let Some(iat) = time_claim("iat")? {
+    if now + opts.leeway < iat {
+      return Err(Reason::NotYetValid);
+    }
+  }
+
+  if let Some(expected) = &opts.aud {
+    let matches = match claims.get("aud") {
+      Some(Value::String(aud)) => expected.contains(aud),
+      Some(Value::Array(auds)) => auds
+        .iter()
+        .filter_map(Value::as_str)
+        .any(|aud| expected.iter().any(|e| e == aud)),
+      _ => false,
+    };
+    if !matches {
+      return Err(Reason::BadAudience);
+    }
+  }
+
+  if let Some(expected) = &opts.iss {
+    if claims.get("iss").and_then(Value::as_str) != Some(expected.as_str()) {
+      return Err(Reason::BadIssuer);
+    }
+  }
+
+  Ok(())
+}
+
+/// Builds the `fyre.jwt` module table.
+///
+/// # Errors
+///
+/// This function will return a `LuaError` if the module table or its functions
+/// cannot be created.
+pub fn module(lua: &Lua) -> LuaResult<LuaTable> {
+  let module = lua.create_table()?;
+
+  module.set(
+    "sign",
+    lua.create_function(
+      |_, (claims, secret, opts): (LuaTable, LuaString, Option<LuaTable>)| {
+        require_secret(&secret.as_bytes())?;
+        let Value::Object(claims) = to_json(&LuaValue::Table(claims))? else {
+          return Err(LuaError::external(
+            "JWT claims must be a table of named claims",
+          ));
+        };
+
+        let (alg, exp_in) = match opts {
+          Some(opts) => (
+            opts.get::<Option<String>>("alg")?,
+            opts.get::<Option<i64>>("exp")?,
+          ),
+          None => (None, None),
+        };
+        let alg = parse_algorithm(alg.as_deref().unwrap_or("HS256"))?;
+
+        sign(claims, &secret.as_bytes(), alg, exp_in)
+      },
+    )?,
+  )?;
+
+  module.set(
+    "verify",
+    lua.create_function(
+      |lua, (token, secret, opts): (LuaString, LuaString, Option<LuaTable>)| {
+        require_secret(&secret.as_bytes())?;
+        let opts = VerifyOptions::from_table(lua, opts)?;
+
+        let Ok(token) = token.to_str() else {
+          return Ok((None, Some(Reason::Malformed.as_str())));
+        };
+        Ok(match verify(&token, &secret.as_bytes(), &opts) {
+          Ok(claims) => (Some(from_json(lua, &Value::Object(claims))?), None),
+          Err(reason) => (None, Some(reason.as_str())),
+        })
+      },
+    )?,
+  )?;
+
+  Ok(module)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use serde_json::json;
+
+  const SECRET: &[u8] = b"your-256-bit-secret";
+
+  /// The example token from jwt.io, signed with `SECRET`.
+  const EXAMPLE: &str = "eyJhbGciOiJIUzI1NiIsInR5cCI6IkpXVCJ9.\
+    eyJzdWIiOiIxMjM0NTY3ODkwIiwibmFtZSI6IkpvaG4gRG9lIiwiaWF0IjoxNTE2MjM5MDIyfQ.\
+    SflKxwRJSMeKKF2QT4fwpMeJf36POk6yJV_adQssw5c";
+
+  fn options(alg: Option<Algorithm>) -> VerifyOptions {
+    VerifyOptions {
+      alg,
+      leeway: 0.0,
+      aud: None,
+      iss: None,
+    }
+  }
+
+  /// A token with `header` and `claims`, signed as `alg` with `secret`.
+  fn token(header: Value, claims: Value, alg: Algorithm, secret: &[u8]) -> String {
+    let input = format!(
+      "{}.{}",
+      encode_segment(&header).unwrap(),
+      encode_segment(&claims).unwrap()
+    );
+    let signature = base64_encode_urlsafe(&alg.sign(secret, input.as_bytes()));
+    format!("{}.{}", input, signature)
+  }
+
+  #[test]
+  fn a_signed_token_verifies() {
+    let claims = verify(EXAMPLE, SECRET, &options(None)).unwrap();
+    assert_eq!(claims["sub"], "1234567890");
+    assert_eq!(claims["iat"], 1516239022);
+    assert!(verify(EXAMPLE, SECRET, &options(Some(Algorithm::Hs256))).is_ok());
+
+    for alg in [Algorithm::Hs256, Algorithm::Hs384, Algorithm::Hs512] {
+      let token = sign(claims.clone(), b"s3cret", alg, Some(60)).unwrap();
+      assert!(
+        verify(&token, b"s3cret", &options(Some(alg))).is_ok(),
+        "{}",
+        alg.name()
+      );
+    }
+  }
+
+  #[test]
+  fn alg_none_is_refused() {
+    let payload = encode_segment(&json!({ "sub": "admin" })).unwrap();
+    for header in [
+      json!({ "alg": "none" }),
+      json!({ "alg": "None", "typ": "JWT" }),
+    ] {
+      let header = encode_segment(&header).unwrap();
+      let unsigned = format!("{}.{}.", header, payload);
+      assert_eq!(
+        verify(&unsigned, SECRET, &options(None)),
+        Err(Reason::UnsupportedAlg)
+      );
+      // Nor with the signature a real algorithm would give it.
+      let input = format!("{}.{}", header, payload);
+      let signature = base64_encode_urlsafe(&Algorithm::Hs256.sign(SECRET, input.as_bytes()));
+      assert_eq!(
+        verify(&format!("{}.{}", input, signature), SECRET, &options(None)),
+        Err(Reason::UnsupportedAlg)
+      );
+    }
+  }
+
+  #[test]
+  fn each_failure_has_its_own_reason() {
+    let hs256 = |claims: Value| token(json!({ "alg": "HS256" }), claims, Algorithm::Hs256, SECRET);
+    let cases = [
+      (
+        hs256(json!({ "sub": "42", "exp": unix_now() - 10.0 })),
+        Reason::Expired,
+      ),
+      (
+        token(
+          json!({ "alg": "HS256" }),
+          json!({ "sub": "42" }),
+          Algorithm::Hs256,
+          b"another secret",
+        ),
+        Reason::BadSignature,
+      ),
+      (EXAMPLE.replace(".SflK", ".TflK"), Reason::BadSignature),
+      ("not a token".to_string(), Reason::Malformed),
+      (
+        EXAMPLE.rsplit_once('.').unwrap().0.to_string(),
+        Reason::Malformed,
+      ),
+      (format!("{}.extra", EXAMPLE), Reason::Malformed),
+      (format!("!{}", EXAMPLE), Reason::Malformed),
+      (
+        token(json!({ "typ": "JWT" }), json!({}), Algorithm::Hs256, SECRET),
+        Reason::Malformed,
+      ),
+      (hs256(json!({ "exp": "tomorrow" })), Reason::Malformed),
+      (
+        token(
+          json!({ "alg": "RS256" }),
+          json!({}),
+          Algorithm::Hs256,
+          SECRET,
+        ),
+        Reason::UnsupportedAlg,
+      ),
+    ];
+    for (token, reason) in &cases {
+      assert_eq!(
+        verify(token, SECRET, &options(None)),
+        Err(*reason),
+        "{}",
+        token
+      );
+    }
+    // A token signed with another HMAC than the one asked for.
+    let hs512 = token(
+      json!({ "alg": "HS512" }),
+      json!({}),
+      Algorithm::Hs512,
+      SECRET,
+    );
+    assert!(verify(&hs512, SECRET, &options(None)).is_ok());
+    assert_eq!(
+      verify(&hs512, SECRET, &options(Some(Algorithm::Hs256))),
+      Err(Reason::UnsupportedAlg)
+    );
+
+    let reasons: std::collections::HashSet<&str> =
+      cases.iter().map(|(_, reason)| reason.as_str()).collect();
+    assert_eq!(reasons.len(), 4);
+  }
+
+  #[test]
+  fn scripts_get_the_reason() {
+    let lua = Lua::new();
+    lua.globals().set("jwt", module(&lua).unwrap()).unwrap();
+    lua.globals().set("EXAMPLE", EXAMPLE).unwrap();
+    let results: Vec<String> = lua
+      .load(
+        r#"
+          local secret = "your-256-bit-secret"
+          local expired = jwt.sign({ sub = "42" }, secret, { exp = -10 })
+          local results = {}
+          for _, case in ipairs({
+            { EXAMPLE, secret },
+            { expired, secret },
+            { EXAMPLE, "wrong" },
+            { "a.b", secret },
+            { EXAMPLE, secret, { alg = "HS512" } },
+          }) do
+            local claims, reason = jwt.verify(case[1], case[2], case[3])
+            results[#results + 1] = claims and claims.sub or reason
+          end
+          return results
+        "#,
+      )
+      .eval()
+      .unwrap();
+    assert_eq!(
+      results,
+      [
+        "1234567890",
+        "expired",
+        "bad_signature",
+        "malformed",
+        "unsupported_alg"
+      ]
+    );
+  }
+}
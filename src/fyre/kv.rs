@@ -0,0 +1,341 @@
+//! # `fyre.kv`
+//!
+//! An in-memory key-value store shared by every request.
+//!
+//! ```lua
+//! fyre.kv.set("greeting", "hello", 60)   -- expires after 60 seconds
+//! local hits = fyre.kv.incr("hits:" .. request.path, 1)
+//! local flags = fyre.kv.keys("flag:")
+//! ```
+//!
+//! Values are deep-copied (see `SharedValue`), so a table read back is a fresh
+//! copy and mutating it does not affect the stored value. The store holds at
+//! most `CONFIG.kv.max_entries` entries, evicting the least recently used entry
+//! when full.
+
+use mlua::prelude::*;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use super::value::SharedValue;
+use crate::locks;
+use crate::AppState;
+
+/// The default maximum number of entries.
+pub const DEFAULT_MAX_ENTRIES: usize = 10_000;
+/// How often expired entries are swept out of the store.
+const SWEEP_INTERVAL: Duration = Duration::from_secs(1);
+
+/// A stored value and its bookkeeping.
+struct Entry {
+  value: SharedValue,
+  expires_at: Option<Instant>,
+  /// The LRU tick this entry was last touched at.
+  tick: u64,
+}
+
+impl Entry {
+  fn is_expired(&self, now: Instant) -> bool {
+    self.expires_at.is_some_and(|at| at <= now)
+  }
+}
+
+/// The map and LRU index, guarded together by the store's mutex.
+struct Inner {
+  entries: HashMap<String, Entry>,
+  /// Maps each entry's LRU tick to its key; the first key is the least
+  /// recently used.
+  lru: BTreeMap<u64, String>,
+  next_tick: u64,
+  last_sweep: Instant,
+}
+
+impl Inner {
+  /// Marks `key` as most recently used.
+  fn touch(&mut self, key: &str) {
+    let tick = self.next_tick;
+    if let Some(entry) = self.entries.get_mut(key) {
+      self.lru.remove(&entry.tick);
+      entry.tick = tick;
+      self.lru.insert(tick, key.to_string());
+      self.next_tick += 1;
+    }
+  }
+
+  fn remove(&mut self, key: &str) -> Option<Entry> {
+    let entry = self.entries.remove(key)?;
+    self.lru.remove(&entry.tick);
+    Some(entry)
+  }
+
+  /// Returns the live entry for `key`, dropping it if it has expired.
+  fn live(&mut self, key: &str, now: Instant) -> Option<&mut Entry> {
+    if self.entries.get(key)?.is_expired(now) {
+      self.remove(key);
+      return None;
+    }
+    self.touch(key);
+    self.entries.get_mut(key)
+  }
+
+  /// Removes every expired entry, at most once per `SWEEP_INTERVAL`.
+  fn sweep(&mut self, now: Instant) {
+    if now.duration_since(self.last_sweep) < SWEEP_INTERVAL {
+      return;
+    }
+    self.last_sweep = now;
+
+    let expired: Vec<String> = self
+      .entries
+      .iter()
+      .filter(|(_, entry)| entry.is_expired(now))
+      .map(|(key, _)| key.clone())
+      .collect();
+    for key in expired {
+      self.remove(&key);
+    }
+  }
+
+  fn insert(&mut self, key: String, value: SharedValue, expires_at: Option<Instant>, max: usize) {
+    self.remove(&key);
+    while self.entries.len() >= max {
+      let Some((_, oldest)) = self.lru.pop_first() else {
+        break;
+      };
+      self.entries.remove(&oldest);
+    }
+
+    let tick = self.next_tick;
+    self.next_tick += 1;
+    self.lru.insert(tick, key.clone());
+    self.entries.insert(key, Entry { value, expires_at, tick });
+  }
+}
+
+/// The shared store behind `fyre.kv`.
+pub struct KvStore {
+  inner: Mutex<Inner>,
+  max_entries: usize,
+}
+
+impl KvStore {
+  /// Creates an empty store holding at most `max_entries` entries.
+  pub fn new(max_entries: usize) -> Self {
+    KvStore {
+      inner: Mutex::new(Inner {
+        entries: HashMap::new(),
+        lru: BTreeMap::new(),
+        next_tick: 0,
+        last_sweep: Instant::now(),
+      }),
+      max_entries: max_entries.max(1),
+    }
+  }
+
+  fn lock(&self) -> std::sync::MutexGuard<'_, Inner> {
+    locks::lock(&self.inner, "kv store")
+  }
+
+  /// Stores `value` under `key`, replacing any existing value.
+  pub fn set(&self, key: String, value: SharedValue, ttl: Option<Duration>) -> LuaResult<()> {
+    let now = Instant::now();
+    let mut inner = self.lock();
+    inner.sweep(now);
+    inner.insert(key, value, ttl.map(|ttl| now + ttl), self.max_entries);
+    Ok(())
+  }
+
+  /// Returns a copy of the value stored under `key`, if it exists and has not
+  /// expired.
+  pub fn get(&self, key: &str) -> LuaResult<Option<SharedValue>> {
+    let now = Instant::now();
+    let mut inner = self.lock();
+    inner.sweep(now);
+    Ok(inner.live(key, now).map(|entry| entry.value.clone()))
+  }
+
+  /// Removes `key`, returning `true` if a live entry was removed.
+  pub fn delete(&self, key: &str) -> LuaResult<bool> {
+    let now = Instant::now();
+    let mut inner = self.lock();
+    Ok(inner.remove(key).is_some_and(|entry| !entry.is_expired(now)))
+  }
+
+  /// Atomically adds `by` to the number stored under `key`, treating a
+  /// missing key as 0. An existing expiry is kept.
+  ///
+  /// # Errors
+  ///
+  /// Returns an error message if the existing value is not a number.
+  pub fn incr(&self, key: &str, by: SharedValue) -> LuaResult<Result<SharedValue, String>> {
+    let now = Instant::now();
+    let mut inner = self.lock();
+    inner.sweep(now);
+
+    let (current, expires_at) = match inner.live(key, now) {
+      Some(entry) => (entry.value.clone(), entry.expires_at),
+      None => (SharedValue::Integer(0), None),
+    };
+
+    let next = match (current, by) {
+      (SharedValue::Integer(a), SharedValue::Integer(b)) => match a.checked_add(b) {
+        Some(sum) => SharedValue::Integer(sum),
+        None => SharedValue::Number(a as f64 + b as f64),
+      },
+      (SharedValue::Integer(a), SharedValue::Number(b)) => SharedValue::Number(a as f64 + b),
+      (SharedValue::Number(a), SharedValue::Integer(b)) => SharedValue::Number(a + b as f64),
+      (SharedValue::Number(a), SharedValue::Number(b)) => SharedValue::Number(a + b),
+      _ => return Ok(Err(format!("value at '{}' is not a number", key))),
+    };
+
+    inner.insert(key.to_string(), next.clone(), expires_at, self.max_entries);
+    Ok(Ok(next))
+  }
+
+  /// Removes every entry, returning how many were live.
+  pub fn clear(&self) -> usize {
+    let now = Instant::now();
+    let mut inner = self.lock();
+    let live = inner.entries.values().filter(|entry| !entry.is_expired(now)).count();
+    inner.entries.clear();
+    inner.lru.clear();
+    live
+  }
+
+  /// Returns the live keys starting with `prefix`, sorted.
+  pub fn keys(&self, prefix: &str) -> LuaResult<Vec<String>> {
+    let now = Instant::now();
+    let mut inner = self.lock();
+    inner.sweep(now);
+
+    let mut keys: Vec<String> = inner
+      .entries
+      .iter()
+      .filter(|(key, entry)| key.starts_with(prefix) && !entry.is_expired(now))
+      .map(|(key, _)| key.clone())
+      .collect();
+    keys.sort();
+    Ok(keys)
+  }
+}
+
+/// Converts an optional TTL in (possibly fractional) seconds to a `Duration`.
+pub fn ttl_from_secs(ttl: Option<f64>) -> LuaResult<Option<Duration>> {
+  match ttl {
+    None => Ok(None),
+    Some(secs) if secs.is_finite() && secs > 0.0 => Ok(Some(Duration::from_secs_f64(secs))),
+    Some(secs) => Err(LuaError::external(format!(
+      "ttl must be a positive number of seconds, got {}",
+      secs
+    ))),
+  }
+}
+
+/// Builds the `fyre.kv` module table.
+///
+/// # Errors
+///
+/// This function will return a `LuaError` if the module table or its functions
+/// cannot be created.
+pub fn module(lua: &Lua, state: &Arc<AppState>) -> LuaResult<LuaTable> {
+  let module = lua.create_table()?;
+
+  let st = state.clone();
+  module.set(
+    "set",
+    lua.create_function(move |_, (key, value, ttl): (String, LuaValue, Option<f64>)| {
+      let ttl = ttl_from_secs(ttl)?;
+      if value.is_nil() {
+        st.kv.delete(&key)?;
+      } else {
+        st.kv.set(key, SharedValue::from_lua_value(value)?, ttl)?;
+      }
+      Ok(true)
+    })?,
+  )?;
+
+  let st = state.clone();
+  module.set(
+    "get",
+    lua.create_function(move |lua, key: String| match st.kv.get(&key)? {
+      Some(value) => value.to_lua_value(lua),
+      None => Ok(LuaValue::Nil),
+    })?,
+  )?;
+
+  let st = state.clone();
+  module.set(
+    "delete",
+    lua.create_function(move |_, key: String| st.kv.delete(&key))?,
+  )?;
+
+  let st = state.clone();
+  module.set(
+    "incr",
+    lua.create_function(move |lua, (key, by): (String, Option<LuaValue>)| {
+      let by = match by {
+        None | Some(LuaValue::Nil) => SharedValue::Integer(1),
+        Some(LuaValue::Integer(i)) => SharedValue::Integer(i),
+        Some(LuaValue::Number(n)) => SharedValue::Number(n),
+        Some(other) => {
+          return Err(LuaError::external(format!(
+            "fyre.kv.incr: increment must be a number, got {}",
+            other.type_name()
+          )))
+        }
+      };
+
+      match st.kv.incr(&key, by)? {
+        Ok(value) => Ok((value.to_lua_value(lua)?, None)),
+        Err(err) => Ok((LuaValue::Nil, Some(err))),
+      }
+    })?,
+  )?;
+
+  let st = state.clone();
+  module.set(
+    "keys",
+    lua.create_function(move |_, prefix: Option<String>| {
+      st.kv.keys(prefix.as_deref().unwrap_or(""))
+    })?,
+  )?;
+
+  Ok(module)
+}
+
+#[cfg(test)]
+mod tests {
+  use crate::testing::{self, Fixture};
+  use std::panic::{self, AssertUnwindSafe};
+
+  #[test]
+  fn the_store_keeps_serving_after_a_panic_holding_it() {
+    let fixture = Fixture::new(
+      r#"router.add("/count", "count.lua")"#,
+      &[(
+        "count.lua",
+        "return { handler = function(request, response)
+           response.body = 'count ' .. fyre.kv.incr('count', 1)
+         end }",
+      )],
+    );
+    let server = fixture.builder().addr("127.0.0.1:0").load().unwrap();
+    let addr = server.serve().unwrap().remove(0);
+    let response = testing::get(&addr, "/count");
+    assert!(response.ends_with("\r\n\r\ncount 1"), "{}", response);
+
+    let panicked = panic::catch_unwind(AssertUnwindSafe(|| {
+      let _guard = server.state.kv.lock();
+      panic!("while holding the kv store");
+    }));
+    assert!(panicked.is_err());
+
+    for expected in ["count 2", "count 3"] {
+      let response = testing::get(&addr, "/count");
+      assert!(response.starts_with("HTTP/1.1 200"), "{}", response);
+      assert!(response.ends_with(expected), "{}", response);
+    }
+    server.shutdown();
+  }
+}
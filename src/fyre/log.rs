@@ -0,0 +1,61 @@
+//! # `fyre.log`
+//!
+//! Writes lines to the server's log, through the same logger as the
+//! server's own messages, so they go to the log file (rotated with it) when
+//! one is set and never interleave with other workers' lines.
+//!
+//! ```lua
+//! fyre.log.info("created order " .. id)
+//! fyre.log.warn("payment provider slow")
+//! fyre.log.error("could not reach the inventory service")
+//! fyre.log.debug("cart has " .. #cart.items .. " items")
+//! ```
+//!
+//! The lines have the target `fyre::script`, so
+//! `FYRE_LOG=warn,fyre::script=debug` shows a script's `debug` lines
+//! without the server's `info` ones.
+//!
+//! With `CONFIG.log.format = "json"`, `print` is replaced by the same
+//! thing at the `info` level, so its output is a JSON line too.
+
+use mlua::prelude::*;
+
+use crate::logger::{self, Level};
+
+/// The target of lines written by scripts.
+pub const TARGET: &str = "fyre::script";
+
+/// Builds the `fyre.log` table.
+pub fn module(lua: &Lua) -> LuaResult<LuaTable> {
+  let module = lua.create_table()?;
+  for (name, level) in [
+    ("error", Level::Error),
+    ("warn", Level::Warn),
+    ("info", Level::Info),
+    ("debug", Level::Debug),
+    ("trace", Level::Trace),
+  ] {
+    module.set(
+      name,
+      lua.create_function(move |_, message: String| {
+        logger::log(level, TARGET, format_args!("{}", message));
+        Ok(())
+      })?,
+    )?;
+  }
+  Ok(module)
+}
+
+/// Builds a `print` that writes its arguments, converted with `tostring`
+/// and separated by tabs as Lua's own does, as one `info` line.
+pub fn print(lua: &Lua) -> LuaResult<LuaFunction> {
+  let tostring: LuaFunction = lua.globals().get("tostring")?;
+  lua.create_function(move |_, args: LuaMultiValue| {
+    let mut parts = Vec::with_capacity(args.len());
+    for arg in args {
+      parts.push(tostring.call::<LuaString>(arg)?.to_string_lossy());
+    }
+    logger::log(Level::Info, TARGET, format_args!("{}", parts.join("\t")));
+    Ok(())
+  })
+}
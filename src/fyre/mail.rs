@@ -0,0 +1,795 @@
+//! # `fyre.mail`
+//!
+//! Outbound email over SMTP, configured with `CONFIG.smtp` in
+//! `config.lua`.
+//!
+//! ```lua
+//! local ok, err = fyre.mail.send{
+//!   to = { "ada@example.com", "Grace Hopper <grace@example.com>" },
+//!   subject = "Welcome",
+//!   text = "Hello!",
+//!   html = "<p>Hello!</p>",
+//!   attachments = { { filename = "terms.pdf", content = pdf, content_type = "application/pdf" } },
+//! }
+//! fyre.mail.send{ to = "ops@example.com", subject = "Alert", text = msg, async = true }
+//! ```
+//!
+//! Each send opens its own connection (`CONFIG.smtp.security` chooses STARTTLS,
+//! implicit TLS, or plain TCP), authenticates if `CONFIG.smtp.username` is set,
+//! and delivers one message, with every step bounded by
+//! `CONFIG.smtp.timeout_ms`. Addresses, subjects, and file names containing CR
+//! or LF are rejected so form input can't inject headers or SMTP commands. With
+//! `async = true` the checked message is pushed to the `fyre.queue` queue named
+//! by `CONFIG.smtp.queue`, whose worker calls `fyre.mail.send` again.
+
+use mlua::prelude::*;
+use rustls::pki_types::ServerName;
+use std::fmt;
+use std::io::{self, BufRead, BufReader, ErrorKind, Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::sync::Arc;
+use std::time::Duration;
+
+use super::encoding::base64_encode;
+use super::value::SharedValue;
+use crate::AppState;
+
+/// The default connect, read, and write timeout.
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(10);
+/// The largest message, attachments included, that will be sent.
+const MAX_MESSAGE_BYTES: usize = 25 * 1024 * 1024;
+/// The longest reply line accepted from the server.
+const MAX_LINE_BYTES: u64 = 4096;
+/// The most lines accepted in one multi-line reply.
+const MAX_REPLY_LINES: usize = 100;
+
+/// How the connection to the SMTP server is secured.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Security {
+  /// Plain TCP upgraded with `STARTTLS`, which the server must offer.
+  StartTls,
+  /// TLS from the start (usually port 465).
+  Tls,
+  /// Plain TCP, for local relays only.
+  None,
+}
+
+impl Security {
+  /// The port used when `CONFIG.smtp.port` is not set.
+  pub fn default_port(self) -> u16 {
+    match self {
+      Security::StartTls => 587,
+      Security::Tls => 465,
+      Security::None => 25,
+    }
+  }
+}
+
+/// SMTP settings from `config.lua`.
+pub struct SmtpConfig {
+  pub host: String,
+  pub port: u16,
+  pub security: Security,
+  pub username: Option<String>,
+  pub password: Option<String>,
+  /// The sender used when a message has no `from`.
+  pub from: Option<String>,
+  pub timeout: Duration,
+  /// The `fyre.queue` queue that `async = true` messages are pushed to.
+  pub queue: Option<String>,
+}
+
+impl fmt::Debug for SmtpConfig {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    f.debug_struct("SmtpConfig")
+      .field("host", &self.host)
+      .field("port", &self.port)
+      .field("security", &self.security)
+      .field("username", &self.username)
+      .field("password", &self.password.as_ref().map(|_| "<redacted>"))
+      .field("from", &self.from)
+      .field("timeout", &self.timeout)
+      .field("queue", &self.queue)
+      .finish()
+  }
+}
+
+/// The SMTP transport behind `fyre.mail`.
+pub struct Mailer {
+  config: SmtpConfig,
+  tls: Arc<rustls::ClientConfig>,
+}
+
+impl Mailer {
+  /// Creates a mailer, loading the TLS root certificates once.
+  ///
+  /// # Errors
+  ///
+  /// Returns an error message if the TLS configuration cannot be built.
+  pub fn new(config: SmtpConfig) -> Result<Self, String> {
+    let mut roots = rustls::RootCertStore::empty();
+    roots.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned());
+    let tls = rustls::ClientConfig::builder_with_provider(Arc::new(
+      rustls::crypto::ring::default_provider(),
+    ))
+    .with_safe_default_protocol_versions()
+    .map_err(|e| format!("failed to configure TLS for SMTP: {}", e))?
+    .with_root_certificates(roots)
+    .with_no_client_auth();
+
+    Ok(Mailer {
+      config,
+      tls: Arc::new(tls),
+    })
+  }
+
+  /// Connects to the server and delivers `message`.
+  fn send(&self, message: &Message) -> Result<(), String> {
+    let data = message.render(&self.config.host)?;
+    let mut session = Session::open(&self.config, &self.tls)?;
+    session.deliver(message, &data)?;
+    session.quit();
+    Ok(())
+  }
+}
+
+/// An address with an optional display name.
+struct Mailbox {
+  /// The address as written, for the message header.
+  header: String,
+  /// The bare address, for the SMTP envelope.
+  addr: String,
+}
+
+impl Mailbox {
+  /// Parses `ada@example.com` or `Ada Lovelace <ada@example.com>`.
+  fn parse(value: &str) -> Result<Self, String> {
+    check_header_value("address", value)?;
+    let value = value.trim();
+    let (name, addr) = match (value.rfind('<'), value.ends_with('>')) {
+      (Some(start), true) => (value[..start].trim(), &value[start + 1..value.len() - 1]),
+      _ => ("", value),
+    };
+
+    let valid = addr
+      .split_once('@')
+      .is_some_and(|(local, domain)| !local.is_empty() && !domain.is_empty())
+      && !addr.contains(|c: char| c.is_whitespace() || c.is_control() || "<>()[],;\"".contains(c));
+    if !valid {
+      return Err(format!("invalid email address '{}'", value));
+    }
+
+    let header = if name.is_empty() {
+      addr.to_string()
+    } else if name.is_ascii() {
+      let name = name
+        .trim_matches('"')
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"");
+      format!("\"{}\" <{}>", name, addr)
+    } else {
+      format!("{} <{}>", encode_word(name), addr)
+    };
+    Ok(Mailbox {
+      header,
+      addr: addr.to_string(),
+    })
+  }
+}
+
+/// Rejects CR and LF, which would end a header or an SMTP command early.
+fn check_header_value(what: &str, value: &str) -> Result<(), String> {
+  if value.contains(['\r', '\n']) {
+    Err(format!("{} must not contain line breaks", what))
+  } else {
+    Ok(())
+  }
+}
+
+/// Encodes non-ASCII header text as RFC 2047 encoded words.
+fn encode_word(text: &str) -> String {
+  if text.is_ascii() {
+    return text.to_string();
+  }
+  // Keep each encoded word under the 75 character limit, splitting only
+  // between characters.
+  let mut words = Vec::new();
+  let mut start = 0;
+  let mut end = 0;
+  for (i, c) in text.char_indices() {
+    if i + c.len_utf8() - start > 45 {
+      words.push(&text[start..end]);
+      start = end;
+    }
+    end = i + c.len_utf8();
+  }
+  words.push(&text[start..end]);
+
+  words
+    .iter()
+    .map(|word| format!("=?UTF-8?B?{}?=", base64_encode(word.as_bytes())))
+    .collect::<Vec<_>>()
+    .join("\r\n ")
+}
+
+/// Base64-encodes `data` in 76 character lines.
+fn base64_lines(data: &[u8]) -> String {
+  let encoded = base64_encode(data);
+  let mut out = String::with_capacity(encoded.len() + encoded.len() / 38 + 2);
+  for chunk in encoded.as_bytes().chunks(76) {
+    out.push_str(std::str::from_utf8(chunk).unwrap_or_default());
+    out.push_str("\r\n");
+  }
+  out
+}
+
+struct Attachment {
+  filename: String,
+  content_type: String,
+  content: Vec<u8>,
+}
+
+/// A checked message, as described by the table passed to `fyre.mail.send`.
+struct Message {
+  from: Mailbox,
+  to: Vec<Mailbox>,
+  cc: Vec<Mailbox>,
+  bcc: Vec<Mailbox>,
+  reply_to: Option<Mailbox>,
+  subject: String,
+  text: Option<Vec<u8>>,
+  html: Option<Vec<u8>>,
+  attachments: Vec<Attachment>,
+}
+
+/// Reads a field, turning type errors into messages.
+fn field<T: FromLua>(table: &LuaTable, key: &str) -> Result<T, String> {
+  table
+    .get(key)
+    .map_err(|e| format!("'{}' has the wrong type: {}", key, e))
+}
+
+/// Reads a field holding one address or a list of them.
+fn mailboxes(table: &LuaTable, key: &str) -> Result<Vec<Mailbox>, String> {
+  let values = match field::<LuaValue>(table, key)? {
+    LuaValue::Nil => Vec::new(),
+    LuaValue::String(s) => vec![s.to_string_lossy()],
+    LuaValue::Table(list) => list
+      .sequence_values::<String>()
+      .collect::<LuaResult<_>>()
+      .map_err(|e| format!("'{}' must be a list of addresses: {}", key, e))?,
+    other => {
+      return Err(format!(
+        "'{}' must be an address or a list of addresses, got {}",
+        key,
+        other.type_name()
+      ))
+    }
+  };
+  values.iter().map(|value| Mailbox::parse(value)).collect()
+}
+
+impl Message {
+  fn from_table(table: &LuaTable, default_from: Option<&str>) -> Result<Self, String> {
+    let from = field::<Option<String>>(table, "from")?
+      .or_else(|| default_from.map(str::to_string))
+      .ok_or("'from' is required when CONFIG.smtp.from is not set")?;
+
+    let subject = field::<Option<String>>(table, "subject")?.unwrap_or_default();
+    check_header_value("subject", &subject)?;
+
+    let mut attachments = Vec::new();
+    if let Some(list) = field::<Option<LuaTable>>(table, "attachments")? {
+      for entry in list.sequence_values::<LuaTable>() {
+        let entry = entry.map_err(|e| format!("each attachment must be a table: {}", e))?;
+        let filename = field::<String>(&entry, "filename")?;
+        let content_type = field::<Option<String>>(&entry, "content_type")?
+          .unwrap_or_else(|| "application/octet-stream".to_string());
+        check_header_value("attachment filename", &filename)?;
+        check_header_value("attachment content_type", &content_type)?;
+        if filename.contains('"') || content_type.contains(['"', ';']) {
+          return Err(format!("invalid attachment '{}'", filename));
+        }
+        attachments.push(Attachment {
+          filename,
+          content_type,
+          content: field::<LuaString>(&entry, "content")?.as_bytes().to_vec(),
+        });
+      }
+    }
+
+    let message = Message {
+      from: Mailbox::parse(&from)?,
+      to: mailboxes(table, "to")?,
+      cc: mailboxes(table, "cc")?,
+      bcc: mailboxes(table, "bcc")?,
+      reply_to: field::<Option<String>>(table, "reply_to")?
+        .map(|value| Mailbox::parse(&value))
+        .transpose()?,
+      subject,
+      text: field::<Option<LuaString>>(table, "text")?.map(|s| s.as_bytes().to_vec()),
+      html: field::<Option<LuaString>>(table, "html")?.map(|s| s.as_bytes().to_vec()),
+      attachments,
+    };
+    if message.to.is_empty() && message.cc.is_empty() && message.bcc.is_empty() {
+      return Err("at least one recipient is required".to_string());
+    }
+    if message.text.is_none() && message.html.is_none() {
+      return Err("'text' or 'html' is required".to_string());
+    }
+    Ok(message)
+  }
+
+  fn recipients(&self) -> impl Iterator<Item = &Mailbox> {
+    self.to.iter().chain(&self.cc).chain(&self.bcc)
+  }
+
+  /// Renders the message in MIME format with CRLF line endings. Bodies and
+  /// attachments are base64 encoded, so no line needs dot-stuffing.
+  fn render(&self, host: &str) -> Result<Vec<u8>, String> {
+    let join = |list: &[Mailbox]| {
+      list
+        .iter()
+        .map(|m| m.header.as_str())
+        .collect::<Vec<_>>()
+        .join(", ")
+    };
+    let domain = self.from.addr.rsplit('@').next().unwrap_or(host);
+
+    let mut out = String::new();
+    out.push_str(&format!("From: {}\r\n", self.from.header));
+    if !self.to.is_empty() {
+      out.push_str(&format!("To: {}\r\n", join(&self.to)));
+    }
+    if !self.cc.is_empty() {
+      out.push_str(&format!("Cc: {}\r\n", join(&self.cc)));
+    }
+    if let Some(reply_to) = &self.reply_to {
+      out.push_str(&format!("Reply-To: {}\r\n", reply_to.header));
+    }
+    out.push_str(&format!("Subject: {}\r\n", encode_word(&self.subject)));
+    out.push_str(&format!("Date: {}\r\n", chrono::Local::now().to_rfc2822()));
+    out.push_str(&format!(
+      "Message-ID: <{}@{}>\r\n",
+      uuid::Uuid::new_v4().simple(),
+      domain
+    ));
+    out.push_str("MIME-Version: 1.0\r\n");
+
+    let part = |content_type: &str, body: &[u8]| {
+      format!(
+        "Content-Type: {}\r\nContent-Transfer-Encoding: base64\r\n\r\n{}",
+        content_type,
+        base64_lines(body)
+      )
+    };
+    let boundary = || format!("=_fyre_{}", uuid::Uuid::new_v4().simple());
+
+    let body = match (&self.text, &self.html) {
+      (Some(text), Some(html)) => {
+        let boundary = boundary();
+        format!(
+          "Content-Type: multipart/alternative; boundary=\"{b}\"\r\n\r\n\
+           --{b}\r\n{}--{b}\r\n{}--{b}--\r\n",
+          part("text/plain; charset=utf-8", text),
+          part("text/html; charset=utf-8", html),
+          b = boundary
+        )
+      }
+      (Some(text), None) => part("text/plain; charset=utf-8", text),
+      (None, Some(html)) => part("text/html; charset=utf-8", html),
+      (None, None) => part("text/plain; charset=utf-8", b""),
+    };
+
+    if self.attachments.is_empty() {
+      out.push_str(&body);
+    } else {
+      let boundary = boundary();
+      out.push_str(&format!(
+        "Content-Type: multipart/mixed; boundary=\"{}\"\r\n\r\n--{}\r\n{}",
+        boundary, boundary, body
+      ));
+      for attachment in &self.attachments {
+        out.push_str(&format!(
+          "--{}\r\nContent-Disposition: attachment; filename=\"{}\"\r\n{}",
+          boundary,
+          encode_word(&attachment.filename),
+          part(&attachment.content_type, &attachment.content)
+        ));
+      }
+      out.push_str(&format!("--{}--\r\n", boundary));
+    }
+
+    if out.len() > MAX_MESSAGE_BYTES {
+      return Err(format!(
+        "message is larger than {} bytes",
+        MAX_MESSAGE_BYTES
+      ));
+    }
+    Ok(out.into_bytes())
+  }
+}
+
+/// A connection that may have been upgraded to TLS.
+enum Stream {
+  Plain(TcpStream),
+  Tls(Box<rustls::StreamOwned<rustls::ClientConnection, TcpStream>>),
+}
+
+impl Read for Stream {
+  fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+    match self {
+      Stream::Plain(s) => s.read(buf),
+      Stream::Tls(s) => s.read(buf),
+    }
+  }
+}
+
+impl Write for Stream {
+  fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+    match self {
+      Stream::Plain(s) => s.write(buf),
+      Stream::Tls(s) => s.write(buf),
+    }
+  }
+
+  fn flush(&mut self) -> io::Result<()> {
+    match self {
+      Stream::Plain(s) => s.flush(),
+      Stream::Tls(s) => s.flush(),
+    }
+  }
+}
+
+/// One SMTP conversation.
+struct Session {
+  reader: BufReader<Stream>,
+  host: String,
+  /// The extensions from the last `EHLO` reply, upper-cased.
+  extensions: Vec<String>,
+}
+
+impl Session {
+  /// Connects, secures the connection as configured, and authenticates.
+  fn open(config: &SmtpConfig, tls: &Arc<rustls::ClientConfig>) -> Result<Self, String> {
+    let addr = format!("{}:{}", config.host, config.port);
+    let fail = |e: io::Error| format!("smtp {}: {}", addr, e);
+
+    let mut last_err = io::Error::new(ErrorKind::NotFound, "no addresses resolved");
+    let mut stream = None;
+    for socket in addr.to_socket_addrs().map_err(fail)? {
+      match TcpStream::connect_timeout(&socket, config.timeout) {
+        Ok(s) => {
+          stream = Some(s);
+          break;
+        }
+        Err(e) => last_err = e,
+      }
+    }
+    let stream = stream.ok_or_else(|| fail(last_err))?;
+    stream
+      .set_read_timeout(Some(config.timeout))
+      .map_err(fail)?;
+    stream
+      .set_write_timeout(Some(config.timeout))
+      .map_err(fail)?;
+
+    let stream = match config.security {
+      Security::Tls => Stream::Tls(Box::new(wrap_tls(tls, &config.host, stream)?)),
+      _ => Stream::Plain(stream),
+    };
+    let mut session = Session {
+      reader: BufReader::new(stream),
+      host: config.host.clone(),
+      extensions: Vec::new(),
+    };
+
+    session.expect(220)?;
+    session.ehlo()?;
+
+    if config.security == Security::StartTls {
+      if !session.has_extension("STARTTLS") {
+        return Err(format!(
+          "smtp {} does not offer STARTTLS (set smtp.security = \"tls\" or \"none\")",
+          config.host
+        ));
+      }
+      session.command("STARTTLS", 220)?;
+      if !session.reader.buffer().is_empty() {
+        return Err(format!(
+          "smtp {} sent data before the TLS handshake",
+          config.host
+        ));
+      }
+      let Stream::Plain(stream) = session.reader.into_inner() else {
+        unreachable!("STARTTLS is only sent on plain connections");
+      };
+      session.reader = BufReader::new(Stream::Tls(Box::new(wrap_tls(tls, &config.host, stream)?)));
+      session.ehlo()?;
+    }
+
+    if let Some(username) = &config.username {
+      session.authenticate(username, config.password.as_deref().unwrap_or(""))?;
+    }
+    Ok(session)
+  }
+
+  fn has_extension(&self, name: &str) -> bool {
+    self
+      .extensions
+      .iter()
+      .any(|ext| ext.split_whitespace().next() == Some(name))
+  }
+
+  fn ehlo(&mut self) -> Result<(), String> {
+    let lines = self.command("EHLO localhost", 250)?;
+    self.extensions = lines
+      .iter()
+      .skip(1)
+      .map(|l| l.to_ascii_uppercase())
+      .collect();
+    Ok(())
+  }
+
+  fn authenticate(&mut self, username: &str, password: &str) -> Result<(), String> {
+    let mechanisms: Vec<String> = self
+      .extensions
+      .iter()
+      .filter_map(|ext| ext.strip_prefix("AUTH "))
+      .flat_map(|list| list.split_whitespace().map(str::to_string))
+      .collect();
+
+    if mechanisms.iter().any(|m| m == "PLAIN") || mechanisms.is_empty() {
+      let token = base64_encode(format!("\0{}\0{}", username, password).as_bytes());
+      self.command(&format!("AUTH PLAIN {}", token), 235)?;
+    } else if mechanisms.iter().any(|m| m == "LOGIN") {
+      self.command("AUTH LOGIN", 334)?;
+      self.command(&base64_encode(username.as_bytes()), 334)?;
+      self.command(&base64_encode(password.as_bytes()), 235)?;
+    } else {
+      return Err(format!(
+        "smtp {} supports no known AUTH mechanism ({})",
+        self.host,
+        mechanisms.join(" ")
+      ));
+    }
+    Ok(())
+  }
+
+  fn deliver(&mut self, message: &Message, data: &[u8]) -> Result<(), String> {
+    self.command(&format!("MAIL FROM:<{}>", message.from.addr), 250)?;
+    for rcpt in message.recipients() {
+      self.command_any(&format!("RCPT TO:<{}>", rcpt.addr), &[250, 251])?;
+    }
+    self.command("DATA", 354)?;
+
+    let mut body = Vec::with_capacity(data.len() + 5);
+    for line in data.split_inclusive(|&b| b == b'\n') {
+      if line.first() == Some(&b'.') {
+        body.push(b'.');
+      }
+      body.extend_from_slice(line);
+    }
+    body.extend_from_slice(b".\r\n");
+    self
+      .reader
+      .get_mut()
+      .write_all(&body)
+      .and_then(|_| self.reader.get_mut().flush())
+      .map_err(|e| format!("smtp {}: {}", self.host, e))?;
+    self.expect(250)?;
+    Ok(())
+  }
+
+  /// Says goodbye; the message is already accepted, so errors are ignored.
+  fn quit(mut self) {
+    let _ = self.command("QUIT", 221);
+  }
+
+  fn command(&mut self, line: &str, code: u16) -> Result<Vec<String>, String> {
+    self.command_any(line, &[code])
+  }
+
+  fn command_any(&mut self, line: &str, codes: &[u16]) -> Result<Vec<String>, String> {
+    let stream = self.reader.get_mut();
+    stream
+      .write_all(format!("{}\r\n", line).as_bytes())
+      .and_then(|_| stream.flush())
+      .map_err(|e| format!("smtp {}: {}", self.host, e))?;
+    self.expect_any(codes)
+  }
+
+  fn expect(&mut self, code: u16) -> Result<Vec<String>, String> {
+    self.expect_any(&[code])
+  }
+
+  /// Reads a reply, returning its text lines if the code is one of `codes`.
+  fn expect_any(&mut self, codes: &[u16]) -> Result<Vec<String>, String> {
+    let mut lines = Vec::new();
+    loop {
+      let mut line = Vec::new();
+      (&mut self.reader)
+        .take(MAX_LINE_BYTES)
+        .read_until(b'\n', &mut line)
+        .map_err(|e| format!("smtp {}: {}", self.host, e))?;
+      if !line.ends_with(b"\n") {
+        return Err(format!(
+          "smtp {}: connection closed or reply too long",
+          self.host
+        ));
+      }
+      let line = String::from_utf8_lossy(&line).trim_end().to_string();
+      if line.len() < 3 || lines.len() >= MAX_REPLY_LINES {
+        return Err(format!("smtp {}: malformed reply '{}'", self.host, line));
+      }
+
+      let code: u16 = line[..3]
+        .parse()
+        .map_err(|_| format!("smtp {}: malformed reply '{}'", self.host, line))?;
+      let last = line.as_bytes().get(3) != Some(&b'-');
+      lines.push(line.get(4..).unwrap_or("").to_string());
+      if last {
+        if codes.contains(&code) {
+          return Ok(lines);
+        }
+        return Err(format!("smtp {}: {} {}", self.host, code, lines.join(" ")));
+      }
+    }
+  }
+}
+
+fn wrap_tls(
+  tls: &Arc<rustls::ClientConfig>,
+  host: &str,
+  stream: TcpStream,
+) -> Result<rustls::StreamOwned<rustls::ClientConnection, TcpStream>, String> {
+  let name = ServerName::try_from(host.to_string())
+    .map_err(|e| format!("smtp host '{}' is not a valid TLS name: {}", host, e))?;
+  let conn = rustls::ClientConnection::new(tls.clone(), name)
+    .map_err(|e| format!("smtp {}: {}", host, e))?;
+  Ok(rustls::StreamOwned::new(conn, stream))
+}
+
+/// Builds the `fyre.mail` module table.
+///
+/// # Errors
+///
+/// This function will return a `LuaError` if the module table or its functions
+/// cannot be created.
+pub fn module(lua: &Lua, state: &Arc<AppState>) -> LuaResult<LuaTable> {
+  let module = lua.create_table()?;
+
+  // fyre.mail.send(message) -> ok, err
+  let st = state.clone();
+  module.set(
+    "send",
+    lua.create_function(move |lua, table: LuaTable| {
+      let mailer = st
+        .mail
+        .as_ref()
+        .ok_or_else(|| LuaError::external("fyre.mail: CONFIG.smtp.host is not set in config.lua"))?;
+
+      let message = match Message::from_table(&table, mailer.config.from.as_deref()) {
+        Ok(message) => message,
+        Err(e) => return Ok((false, Some(e))),
+      };
+
+      if table.get::<Option<bool>>("async")?.unwrap_or(false) {
+        let queue = mailer.config.queue.as_deref().ok_or_else(|| {
+          LuaError::external("fyre.mail: async = true needs CONFIG.smtp.queue in config.lua")
+        })?;
+        // The worker sends a copy without the flag.
+        let job = lua.create_table()?;
+        for pair in table.pairs::<LuaValue, LuaValue>() {
+          let (key, value) = pair?;
+          if !matches!(&key, LuaValue::String(s) if s == "async") {
+            job.set(key, value)?;
+          }
+        }
+        st.queues.push(
+          queue,
+          SharedValue::from_lua_value(LuaValue::Table(job))?,
+          Duration::ZERO,
+        )?;
+        return Ok((true, None));
+      }
+
+      match mailer.send(&message) {
+        Ok(()) => Ok((true, None)),
+        Err(e) => Ok((false, Some(e))),
+      }
+    })?,
+  )?;
+
+  Ok(module)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  /// Checks the message table `fields` with `CONFIG.smtp.from` set to
+  /// `default_from`.
+  fn message(fields: &str, default_from: Option<&str>) -> Result<Message, String> {
+    let lua = Lua::new();
+    let table: LuaTable = lua.load(fields).eval().unwrap();
+    Message::from_table(&table, default_from)
+  }
+
+  #[test]
+  fn line_breaks_in_headers_are_refused() {
+    for (fields, expected) in [
+      (
+        r#"{ to = "ada@example.com\r\nBcc: eve@example.com", subject = "Hi", text = "." }"#,
+        "address must not contain line breaks",
+      ),
+      (
+        r#"{ to = { "ada@example.com", "bob@example.com\nBcc: eve@example.com" }, text = "." }"#,
+        "address must not contain line breaks",
+      ),
+      (
+        r#"{ to = "ada@example.com", cc = "eve@example.com\r", text = "." }"#,
+        "address must not contain line breaks",
+      ),
+      (
+        r#"{ from = "Ada <ada@example.com>\r\nBcc: eve@example.com", to = "bob@example.com", text = "." }"#,
+        "address must not contain line breaks",
+      ),
+      (
+        r#"{ to = "ada@example.com", reply_to = "ada@example.com\nX-Evil: 1", text = "." }"#,
+        "address must not contain line breaks",
+      ),
+      (
+        r#"{ to = "ada@example.com", subject = "Hi\r\nBcc: eve@example.com", text = "." }"#,
+        "subject must not contain line breaks",
+      ),
+      (
+        r#"{ to = "ada@example.com", subject = "Hi\nthere", text = "." }"#,
+        "subject must not contain line breaks",
+      ),
+      (
+        r#"{ to = "ada@example.com", text = ".",
+             attachments = { { filename = "a.txt\r\nX-Evil: 1", content = "" } } }"#,
+        "attachment filename must not contain line breaks",
+      ),
+    ] {
+      let error = message(fields, Some("app@example.com")).err().unwrap();
+      assert_eq!(error, expected, "{}", fields);
+    }
+
+    let error = message(
+      r#"{ to = "ada@example.com", text = "." }"#,
+      Some("app@example.com\r\nBcc: eve@example.com"),
+    )
+    .err()
+    .unwrap();
+    assert_eq!(error, "address must not contain line breaks");
+  }
+
+  #[test]
+  fn headers_without_line_breaks_are_sent() {
+    let message = message(
+      r#"{ to = { "Ada Lovelace <ada@example.com>" }, subject = "Hi there", text = "Hello" }"#,
+      Some("app@example.com"),
+    )
+    .unwrap();
+    let rendered = String::from_utf8(message.render("example.com").unwrap()).unwrap();
+    assert!(
+      rendered.starts_with("From: app@example.com\r\n"),
+      "{}",
+      rendered
+    );
+    assert!(
+      rendered.contains("\r\nTo: \"Ada Lovelace\" <ada@example.com>\r\n"),
+      "{}",
+      rendered
+    );
+    assert!(
+      rendered.contains("\r\nSubject: Hi there\r\n"),
+      "{}",
+      rendered
+    );
+    let addrs: Vec<&str> = message.recipients().map(|m| m.addr.as_str()).collect();
+    assert_eq!(addrs, ["ada@example.com"]);
+  }
+}
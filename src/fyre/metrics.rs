@@ -0,0 +1,649 @@
+//! # `fyre.metrics`
+//!
+//! Counters, gauges, and histograms recorded from Lua into a registry shared
+//! by every request.
+//!
+//! ```lua
+//! fyre.metrics.counter("orders_total", { help = "Orders placed" }):inc()
+//! fyre.metrics.counter("orders_total"):inc(1, { region = "eu" })
+//! fyre.metrics.gauge("queue_depth"):set(fyre.queue.stats("emails").pending)
+//! fyre.metrics.histogram("payment_seconds"):observe(0.42, { provider = "stripe" })
+//! ```
+//!
+//! Metric and label names are validated when a metric or label set is first
+//! seen; after that, updates are lock-free atomics on the stored series. Each
+//! metric keeps at most `CONFIG.metrics.max_series` label combinations, and
+//! further combinations are dropped (with one warning) so high-cardinality
+//! labels can't exhaust memory. `render` produces the Prometheus text
+//! exposition format, and `workers` lists what each request worker is doing
+//! (see `worker_stats`).
+
+use mlua::prelude::*;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Write;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+use crate::{locks, server};
+use crate::AppState;
+
+/// The default number of label combinations kept per metric.
+pub const DEFAULT_MAX_SERIES: usize = 1000;
+/// The default histogram buckets, as used by the Prometheus client
+/// libraries.
+pub const DEFAULT_BUCKETS: &[f64] = &[
+  0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+/// An `f64` updated atomically through its bit pattern.
+#[derive(Default)]
+struct AtomicF64(AtomicU64);
+
+impl AtomicF64 {
+  fn get(&self) -> f64 {
+    f64::from_bits(self.0.load(Ordering::Relaxed))
+  }
+
+  fn set(&self, value: f64) {
+    self.0.store(value.to_bits(), Ordering::Relaxed);
+  }
+
+  fn add(&self, delta: f64) {
+    let mut current = self.0.load(Ordering::Relaxed);
+    loop {
+      let next = (f64::from_bits(current) + delta).to_bits();
+      match self
+        .0
+        .compare_exchange_weak(current, next, Ordering::Relaxed, Ordering::Relaxed)
+      {
+        Ok(_) => return,
+        Err(actual) => current = actual,
+      }
+    }
+  }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Kind {
+  Counter,
+  Gauge,
+  Histogram,
+}
+
+impl Kind {
+  fn as_str(self) -> &'static str {
+    match self {
+      Kind::Counter => "counter",
+      Kind::Gauge => "gauge",
+      Kind::Histogram => "histogram",
+    }
+  }
+}
+
+/// One label combination's values.
+struct Series {
+  /// The counter or gauge value, or the histogram sum.
+  value: AtomicF64,
+  /// Histogram observations per bucket (not cumulative).
+  buckets: Vec<AtomicU64>,
+  /// Histogram observation count.
+  count: AtomicU64,
+}
+
+/// Sorted label name/value pairs.
+type Labels = Vec<(String, String)>;
+
+/// A named metric and its series.
+struct Family {
+  name: String,
+  kind: Kind,
+  help: String,
+  /// Histogram bucket upper bounds, ascending.
+  bounds: Vec<f64>,
+  series: Mutex<HashMap<Labels, Arc<Series>>>,
+  /// Whether the series cap has been reported for this metric.
+  capped: AtomicBool,
+}
+
+impl Family {
+  /// Returns the series for `labels`, creating it if the cap allows.
+  fn series(&self, labels: Labels, max_series: usize) -> LuaResult<Option<Arc<Series>>> {
+    let mut series = locks::lock(&self.series, "metric");
+    if let Some(existing) = series.get(&labels) {
+      return Ok(Some(existing.clone()));
+    }
+
+    for (name, _) in &labels {
+      if !is_valid_name(name, false) || name.starts_with("__") || name == "le" {
+        return Err(LuaError::external(format!(
+          "invalid label name '{}' for metric {}",
+          name, self.name
+        )));
+      }
+    }
+    if series.len() >= max_series {
+      if !self.capped.swap(true, Ordering::Relaxed) {
+        warn!(
+          "Metric {} reached {} label combinations; new ones are dropped",
+          self.name, max_series
+        );
+      }
+      return Ok(None);
+    }
+
+    let created = Arc::new(Series {
+      value: AtomicF64::default(),
+      buckets: self.bounds.iter().map(|_| AtomicU64::new(0)).collect(),
+      count: AtomicU64::new(0),
+    });
+    series.insert(labels, created.clone());
+    Ok(Some(created))
+  }
+}
+
+/// Returns `true` for a valid Prometheus metric (`colons` allowed) or label
+/// name.
+fn is_valid_name(name: &str, colons: bool) -> bool {
+  let mut chars = name.chars();
+  let valid_start = |c: char| c.is_ascii_alphabetic() || c == '_' || (colons && c == ':');
+  chars.next().is_some_and(valid_start) && chars.all(|c| valid_start(c) || c.is_ascii_digit())
+}
+
+/// The metrics shared by every request.
+pub struct Registry {
+  families: Mutex<BTreeMap<String, Arc<Family>>>,
+  max_series: usize,
+}
+
+impl Registry {
+  /// Creates an empty registry keeping at most `max_series` label
+  /// combinations per metric.
+  pub fn new(max_series: usize) -> Self {
+    Registry {
+      families: Mutex::new(BTreeMap::new()),
+      max_series: max_series.max(1),
+    }
+  }
+
+  /// Returns the metric called `name`, registering it on first use.
+  fn family(
+    &self,
+    name: &str,
+    kind: Kind,
+    help: Option<String>,
+    bounds: Option<Vec<f64>>,
+  ) -> LuaResult<Arc<Family>> {
+    let mut families = locks::lock(&self.families, "metrics registry");
+
+    if let Some(family) = families.get(name) {
+      if family.kind != kind {
+        return Err(LuaError::external(format!(
+          "metric {} is already registered as a {}",
+          name,
+          family.kind.as_str()
+        )));
+      }
+      return Ok(family.clone());
+    }
+
+    if !is_valid_name(name, true) {
+      return Err(LuaError::external(format!(
+        "invalid metric name '{}'",
+        name
+      )));
+    }
+    let bounds = match bounds {
+      Some(mut bounds) => {
+        if bounds.iter().any(|b| !b.is_finite()) {
+          return Err(LuaError::external(format!(
+            "histogram {} buckets must be finite numbers",
+            name
+          )));
+        }
+        bounds.sort_by(f64::total_cmp);
+        bounds.dedup();
+        bounds
+      }
+      None if kind == Kind::Histogram => DEFAULT_BUCKETS.to_vec(),
+      None => Vec::new(),
+    };
+
+    let family = Arc::new(Family {
+      name: name.to_string(),
+      kind,
+      help: help.unwrap_or_default(),
+      bounds,
+      series: Mutex::new(HashMap::new()),
+      capped: AtomicBool::new(false),
+    });
+    families.insert(name.to_string(), family.clone());
+    Ok(family)
+  }
+
+  /// Appends every metric in the Prometheus text exposition format.
+  pub fn render_into(&self, out: &mut String) {
+    let families: Vec<Arc<Family>> = locks::lock(&self.families, "metrics registry")
+      .values()
+      .cloned()
+      .collect();
+
+    for family in families {
+      let series = locks::lock(&family.series, "metric");
+      let mut series: Vec<(&Labels, &Arc<Series>)> = series.iter().collect();
+      series.sort_by(|a, b| a.0.cmp(b.0));
+
+      if !family.help.is_empty() {
+        let help = family.help.replace('\\', "\\\\").replace('\n', "\\n");
+        let _ = writeln!(out, "# HELP {} {}", family.name, help);
+      }
+      let _ = writeln!(out, "# TYPE {} {}", family.name, family.kind.as_str());
+
+      for (labels, values) in series {
+        if family.kind != Kind::Histogram {
+          let _ = writeln!(
+            out,
+            "{}{} {}",
+            family.name,
+            format_labels(labels, None),
+            format_number(values.value.get())
+          );
+          continue;
+        }
+
+        let mut cumulative = 0;
+        for (bound, count) in family.bounds.iter().zip(&values.buckets) {
+          cumulative += count.load(Ordering::Relaxed);
+          let _ = writeln!(
+            out,
+            "{}_bucket{} {}",
+            family.name,
+            format_labels(labels, Some(&format_number(*bound))),
+            cumulative
+          );
+        }
+        let count = values.count.load(Ordering::Relaxed);
+        let _ = writeln!(
+          out,
+          "{}_bucket{} {}",
+          family.name,
+          format_labels(labels, Some("+Inf")),
+          count
+        );
+        let _ = writeln!(
+          out,
+          "{}_sum{} {}",
+          family.name,
+          format_labels(labels, None),
+          format_number(values.value.get())
+        );
+        let _ = writeln!(
+          out,
+          "{}_count{} {}",
+          family.name,
+          format_labels(labels, None),
+          count
+        );
+      }
+    }
+  }
+}
+
+/// Formats `labels`, and a histogram bucket's `le`, as `{name="value",...}`.
+pub fn format_labels(labels: &Labels, le: Option<&str>) -> String {
+  let escape = |value: &str| {
+    value
+      .replace('\\', "\\\\")
+      .replace('"', "\\\"")
+      .replace('\n', "\\n")
+  };
+  let mut parts: Vec<String> = labels
+    .iter()
+    .map(|(name, value)| format!("{}=\"{}\"", name, escape(value)))
+    .collect();
+  if let Some(le) = le {
+    parts.push(format!("le=\"{}\"", le));
+  }
+
+  if parts.is_empty() {
+    String::new()
+  } else {
+    format!("{{{}}}", parts.join(","))
+  }
+}
+
+/// Formats a sample value as Prometheus expects.
+pub fn format_number(value: f64) -> String {
+  if value.is_nan() {
+    "NaN".to_string()
+  } else if value == f64::INFINITY {
+    "+Inf".to_string()
+  } else if value == f64::NEG_INFINITY {
+    "-Inf".to_string()
+  } else {
+    value.to_string()
+  }
+}
+
+/// Renders the registry and the server's built-in counters in the
+/// Prometheus text exposition format.
+pub fn render(state: &AppState) -> String {
+  let mut out = String::new();
+  state.metrics.render_into(&mut out);
+  state.request_metrics.render_into(&mut out);
+
+  let cache = state.cache.stats();
+  let _ = writeln!(out, "# TYPE fyre_cache_hits_total counter");
+  let _ = writeln!(out, "fyre_cache_hits_total {}", cache.hits);
+  let _ = writeln!(out, "# TYPE fyre_cache_misses_total counter");
+  let _ = writeln!(out, "fyre_cache_misses_total {}", cache.misses);
+
+  let scripts = state.scripts.stats();
+  let _ = writeln!(out, "# TYPE fyre_bytecode_cache_hits_total counter");
+  let _ = writeln!(out, "fyre_bytecode_cache_hits_total {}", scripts.hits);
+  let _ = writeln!(out, "# TYPE fyre_bytecode_cache_misses_total counter");
+  let _ = writeln!(out, "fyre_bytecode_cache_misses_total {}", scripts.misses);
+  let _ = writeln!(out, "# TYPE fyre_bytecode_cache_evictions_total counter");
+  let _ = writeln!(out, "fyre_bytecode_cache_evictions_total {}", scripts.evictions);
+  let _ = writeln!(out, "# TYPE fyre_bytecode_cache_bytes gauge");
+  let _ = writeln!(out, "fyre_bytecode_cache_bytes {}", scripts.bytes);
+
+  let ratelimit = state.ratelimit.stats();
+  let _ = writeln!(out, "# TYPE fyre_ratelimit_allowed_total counter");
+  let _ = writeln!(out, "fyre_ratelimit_allowed_total {}", ratelimit.allowed);
+  let _ = writeln!(out, "# TYPE fyre_ratelimit_limited_total counter");
+  let _ = writeln!(out, "fyre_ratelimit_limited_total {}", ratelimit.limited);
+  let clients = state.client_limits.stats();
+  let _ = writeln!(out, "# TYPE fyre_client_ratelimit_allowed_total counter");
+  let _ = writeln!(out, "fyre_client_ratelimit_allowed_total {}", clients.allowed);
+  let _ = writeln!(out, "# TYPE fyre_client_ratelimit_limited_total counter");
+  let _ = writeln!(out, "fyre_client_ratelimit_limited_total {}", clients.limited);
+  let _ = writeln!(out, "# TYPE fyre_client_ratelimit_keys gauge");
+  let _ = writeln!(out, "fyre_client_ratelimit_keys {}", state.client_limits.keys());
+
+  let api_keys = state.api_key_usage.snapshot();
+  if !api_keys.is_empty() {
+    let _ = writeln!(out, "# TYPE fyre_api_key_last_used_timestamp_seconds gauge");
+    for (id, last_used) in api_keys {
+      let labels = format_labels(&Vec::from([("key_id".to_string(), id)]), None);
+      let _ = writeln!(out, "fyre_api_key_last_used_timestamp_seconds{} {}", labels, last_used);
+    }
+  }
+
+  let _ = writeln!(out, "# TYPE fyre_connections_active gauge");
+  let _ = writeln!(out, "fyre_connections_active {}", state.connections.active());
+  let _ = writeln!(out, "# TYPE fyre_connections_rejected_total counter");
+  let _ = writeln!(out, "fyre_connections_rejected_total {}", state.connections.rejected());
+  let _ = writeln!(out, "# TYPE fyre_requests_oversized_total counter");
+  for limit in server::Oversized::ALL {
+    let _ = writeln!(
+      out,
+      "fyre_requests_oversized_total{{limit=\"{}\"}} {}",
+      limit.name(),
+      state.connections.oversized(limit)
+    );
+  }
+  let _ = writeln!(out, "# TYPE fyre_connections_timed_out_total counter");
+  for phase in server::Phase::ALL {
+    let _ = writeln!(
+      out,
+      "fyre_connections_timed_out_total{{phase=\"{}\"}} {}",
+      phase.name(),
+      state.connections.timed_out(phase)
+    );
+  }
+  let _ = writeln!(out, "# TYPE fyre_requests_in_flight gauge");
+  let _ = writeln!(out, "fyre_requests_in_flight {}", state.in_flight.in_flight());
+  let _ = writeln!(out, "# TYPE fyre_requests_rejected_total counter");
+  let _ = writeln!(out, "fyre_requests_rejected_total {}", state.in_flight.rejected());
+  let _ = writeln!(out, "# TYPE fyre_slow_requests_total counter");
+  let _ = writeln!(out, "fyre_slow_requests_total {}", state.slow_log.count());
+
+  let routes = state.routes.load();
+  let mut limited: Vec<_> = routes
+    .handlers
+    .iter()
+    .filter_map(|(route, handler)| {
+      let labels = format_labels(&Vec::from([("route".to_string(), route.clone())]), None);
+      Some((labels, handler.limiter.as_ref()?))
+    })
+    .collect();
+  if !limited.is_empty() {
+    limited.sort_by(|(a, _), (b, _)| a.cmp(b));
+    let _ = writeln!(out, "# TYPE fyre_route_requests_in_flight gauge");
+    for (labels, limiter) in &limited {
+      let _ = writeln!(out, "fyre_route_requests_in_flight{} {}", labels, limiter.in_flight());
+    }
+    let _ = writeln!(out, "# TYPE fyre_route_requests_rejected_total counter");
+    for (labels, limiter) in &limited {
+      let _ = writeln!(out, "fyre_route_requests_rejected_total{} {}", labels, limiter.rejected());
+    }
+  }
+
+  let mut typed: Vec<_> = routes
+    .handlers
+    .iter()
+    .filter_map(|(route, handler)| {
+      let labels = format_labels(&Vec::from([("route".to_string(), route.clone())]), None);
+      Some((labels, handler.accept_types.as_ref()?))
+    })
+    .collect();
+  if !typed.is_empty() {
+    typed.sort_by(|(a, _), (b, _)| a.cmp(b));
+    let _ = writeln!(out, "# TYPE fyre_route_unsupported_media_type_total counter");
+    for (labels, accept_types) in &typed {
+      let _ = writeln!(
+        out,
+        "fyre_route_unsupported_media_type_total{} {}",
+        labels,
+        accept_types.rejected()
+      );
+    }
+  }
+
+  let workers = state.workers.snapshot();
+  let labels: Vec<String> = workers
+    .iter()
+    .map(|worker| format_labels(&Vec::from([("worker".to_string(), worker.id.to_string())]), None))
+    .collect();
+  let _ = writeln!(out, "# TYPE fyre_worker_requests_total counter");
+  for (labels, worker) in labels.iter().zip(&workers) {
+    let _ = writeln!(out, "fyre_worker_requests_total{} {}", labels, worker.requests);
+  }
+  let _ = writeln!(out, "# TYPE fyre_worker_busy_ratio gauge");
+  for (labels, worker) in labels.iter().zip(&workers) {
+    let ratio = format_number(worker.busy_percent / 100.0);
+    let _ = writeln!(out, "fyre_worker_busy_ratio{} {}", labels, ratio);
+  }
+  let _ = writeln!(out, "# TYPE fyre_worker_restarts_total counter");
+  for (labels, worker) in labels.iter().zip(&workers) {
+    let _ = writeln!(out, "fyre_worker_restarts_total{} {}", labels, worker.restarts);
+  }
+  out
+}
+
+/// Reads an optional label table into sorted name/value pairs.
+fn to_labels(labels: Option<LuaTable>) -> LuaResult<Labels> {
+  let mut out = Vec::new();
+  if let Some(labels) = labels {
+    for pair in labels.pairs::<String, LuaValue>() {
+      let (name, value) = pair?;
+      let value = match value {
+        LuaValue::String(s) => s.to_string_lossy(),
+        LuaValue::Integer(i) => i.to_string(),
+        LuaValue::Number(n) => n.to_string(),
+        LuaValue::Boolean(b) => b.to_string(),
+        other => {
+          return Err(LuaError::external(format!(
+            "label '{}' must be a string or number, got {}",
+            name,
+            other.type_name()
+          )))
+        }
+      };
+      out.push((name, value));
+    }
+  }
+  out.sort();
+  Ok(out)
+}
+
+/// The handle returned by `fyre.metrics.counter`, `gauge`, and `histogram`.
+struct Metric {
+  family: Arc<Family>,
+  max_series: usize,
+}
+
+impl Metric {
+  fn series(&self, labels: Option<LuaTable>) -> LuaResult<Option<Arc<Series>>> {
+    self.family.series(to_labels(labels)?, self.max_series)
+  }
+
+  fn require(&self, kind: Kind, method: &str) -> LuaResult<()> {
+    if self.family.kind == kind {
+      Ok(())
+    } else {
+      Err(LuaError::external(format!(
+        "{}() is not available on {} {}",
+        method,
+        self.family.kind.as_str(),
+        self.family.name
+      )))
+    }
+  }
+}
+
+/// Splits the `([amount,] [labels])` arguments of `inc` and `dec`.
+fn amount_and_labels(
+  first: Option<LuaValue>,
+  second: Option<LuaTable>,
+) -> LuaResult<(f64, Option<LuaTable>)> {
+  match first {
+    None | Some(LuaValue::Nil) => Ok((1.0, second)),
+    Some(LuaValue::Table(labels)) => Ok((1.0, Some(labels))),
+    Some(LuaValue::Integer(i)) => Ok((i as f64, second)),
+    Some(LuaValue::Number(n)) => Ok((n, second)),
+    Some(other) => Err(LuaError::external(format!(
+      "amount must be a number, got {}",
+      other.type_name()
+    ))),
+  }
+}
+
+impl LuaUserData for Metric {
+  fn add_methods<M: LuaUserDataMethods<Self>>(methods: &mut M) {
+    // counter:inc([amount] [, labels]) / gauge:inc(...)
+    methods.add_method(
+      "inc",
+      |_, metric, (first, second): (Option<LuaValue>, Option<LuaTable>)| {
+        if metric.family.kind == Kind::Histogram {
+          metric.require(Kind::Counter, "inc")?;
+        }
+        let (amount, labels) = amount_and_labels(first, second)?;
+        if metric.family.kind == Kind::Counter && (amount.is_nan() || amount < 0.0) {
+          return Err(LuaError::external(format!(
+            "counter {} can only increase",
+            metric.family.name
+          )));
+        }
+        if let Some(series) = metric.series(labels)? {
+          series.value.add(amount);
+        }
+        Ok(())
+      },
+    );
+
+    // gauge:dec([amount] [, labels])
+    methods.add_method(
+      "dec",
+      |_, metric, (first, second): (Option<LuaValue>, Option<LuaTable>)| {
+        metric.require(Kind::Gauge, "dec")?;
+        let (amount, labels) = amount_and_labels(first, second)?;
+        if let Some(series) = metric.series(labels)? {
+          series.value.add(-amount);
+        }
+        Ok(())
+      },
+    );
+
+    // gauge:set(value [, labels])
+    methods.add_method(
+      "set",
+      |_, metric, (value, labels): (f64, Option<LuaTable>)| {
+        metric.require(Kind::Gauge, "set")?;
+        if let Some(series) = metric.series(labels)? {
+          series.value.set(value);
+        }
+        Ok(())
+      },
+    );
+
+    // histogram:observe(value [, labels])
+    methods.add_method(
+      "observe",
+      |_, metric, (value, labels): (f64, Option<LuaTable>)| {
+        metric.require(Kind::Histogram, "observe")?;
+        if let Some(series) = metric.series(labels)? {
+          if let Some(bucket) = metric.family.bounds.iter().position(|b| value <= *b) {
+            series.buckets[bucket].fetch_add(1, Ordering::Relaxed);
+          }
+          series.count.fetch_add(1, Ordering::Relaxed);
+          series.value.add(value);
+        }
+        Ok(())
+      },
+    );
+  }
+}
+
+/// Builds the `fyre.metrics` module table.
+///
+/// # Errors
+///
+/// This function will return a `LuaError` if the module table or its functions
+/// cannot be created.
+pub fn module(lua: &Lua, state: &Arc<AppState>) -> LuaResult<LuaTable> {
+  let module = lua.create_table()?;
+
+  for kind in [Kind::Counter, Kind::Gauge, Kind::Histogram] {
+    let st = state.clone();
+    module.set(
+      kind.as_str(),
+      lua.create_function(move |lua, (name, opts): (String, Option<LuaTable>)| {
+        let (help, buckets) = match opts {
+          Some(opts) => (opts.get("help")?, opts.get("buckets")?),
+          None => (None, None),
+        };
+        let family = st.metrics.family(&name, kind, help, buckets)?;
+        lua.create_userdata(Metric {
+          family,
+          max_series: st.metrics.max_series,
+        })
+      })?,
+    )?;
+  }
+
+  // fyre.metrics.render() -> Prometheus text
+  let st = state.clone();
+  module.set("render", lua.create_function(move |_, ()| Ok(render(&st)))?)?;
+
+  // fyre.metrics.workers() -> { { id, requests, route, busy_percent, restarts }, ... }
+  let st = state.clone();
+  module.set(
+    "workers",
+    lua.create_function(move |lua, ()| {
+      let list = lua.create_table()?;
+      for worker in st.workers.snapshot() {
+        let entry = lua.create_table()?;
+        entry.set("id", worker.id)?;
+        entry.set("requests", worker.requests)?;
+        entry.set("route", worker.route)?;
+        entry.set("busy_percent", worker.busy_percent)?;
+        entry.set("restarts", worker.restarts)?;
+        list.push(entry)?;
+      }
+      Ok(list)
+    })?,
+  )?;
+
+  Ok(module)
+}
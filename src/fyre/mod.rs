@@ -0,0 +1,223 @@
+//! # The `fyre` Helper Modules
+//!
+//! Handler scripts get a global `fyre` table whose fields are helper modules
+//! implemented in Rust (e.g. `fyre.http`). Each submodule exposes a `module`
+//! function that builds its Lua table; `register` wires them all together.
+//! Modules that depend on the current request (e.g. `fyre.session`) are added
+//! by `execute_handler_pipeline` once the request tables exist.
+//!
+//! `register` also takes away the parts of Lua's standard library that
+//! reach outside the state: `io`, `package`, `require`, `dofile`,
+//! `loadfile`, and all of `os` but `clock`, `time`, and `date`. Otherwise a
+//! script could read any file, environment variable, or program around the
+//! `sandbox` settings `fyre.fs`, `fyre.env`, `fyre.exec`, and `fyre.http`
+//! apply. `config.lua` is trusted and keeps the whole library.
+
+pub mod cache;
+pub mod cookie;
+pub mod crypto;
+pub mod csrf;
+pub mod encoding;
+pub mod env;
+pub mod exec;
+pub mod fs;
+pub mod http;
+pub mod json;
+pub mod jwt;
+pub mod kv;
+pub mod log;
+pub mod mail;
+pub mod metrics;
+pub mod queue;
+pub mod random;
+pub mod ratelimit;
+pub mod redis;
+pub mod secrets;
+pub mod server;
+pub mod session;
+pub mod sqlite;
+pub mod time;
+pub mod url;
+pub mod validate;
+pub mod value;
+
+use mlua::prelude::*;
+use std::sync::Arc;
+
+use crate::AppState;
+
+/// The `os` functions scripts keep.
+const OS_KEPT: [&str; 3] = ["clock", "time", "date"];
+
+/// The standard library globals scripts don't get.
+const REMOVED_GLOBALS: [&str; 5] = ["io", "package", "require", "dofile", "loadfile"];
+
+/// Creates the `fyre` global table and registers every helper module on it,
+/// after removing the standard library functions that get around the
+/// sandbox.
+///
+/// This is called once per Lua state, before the handler script is loaded, so
+/// the modules are available both at module load time and inside the pipeline
+/// functions.
+///
+/// # Arguments
+///
+/// * `lua` - The Lua state to register the modules in.
+/// * `state` - The server-wide state the helper modules operate on.
+///
+/// # Errors
+///
+/// This function will return a `LuaError` if any module table or function
+/// cannot be created.
+pub fn register(lua: &Lua, state: &Arc<AppState>) -> LuaResult<()> {
+  restrict_stdlib(lua)?;
+  let fyre = lua.create_table()?;
+  fyre.set("base64", encoding::base64_module(lua)?)?;
+  fyre.set("cache", cache::module(lua, state)?)?;
+  fyre.set("crypto", crypto::module(lua)?)?;
+  fyre.set("env", env::module(lua, &state.env)?)?;
+  fyre.set("exec", exec::module(lua, state)?)?;
+  fyre.set("fs", fs::module(lua, state)?)?;
+  fyre.set("hex", encoding::hex_module(lua)?)?;
+  fyre.set("http", http::module(lua, state)?)?;
+  fyre.set("jwt", jwt::module(lua)?)?;
+  fyre.set("kv", kv::module(lua, state)?)?;
+  fyre.set("log", log::module(lua)?)?;
+  fyre.set("mail", mail::module(lua, state)?)?;
+  fyre.set("metrics", metrics::module(lua, state)?)?;
+  fyre.set("queue", queue::module(lua, state)?)?;
+  fyre.set("random", random::random_module(lua)?)?;
+  fyre.set("ratelimit", ratelimit::module(lua, state)?)?;
+  fyre.set("redis", redis::module(lua, state)?)?;
+  fyre.set("secrets", secrets::module(lua, &state.secrets)?)?;
+  fyre.set("server", server::module(lua, state)?)?;
+  fyre.set("sqlite", sqlite::module(lua, state)?)?;
+  fyre.set("time", time::module(lua)?)?;
+  fyre.set("url", url::module(lua)?)?;
+  fyre.set("uuid", random::uuid_module(lua)?)?;
+  fyre.set("validate", validate::module(lua)?)?;
+  fyre.set("version", crate::VERSION)?;
+  // A Rust helper that panics, for the tests of panic recovery.
+  #[cfg(test)]
+  fyre.set(
+    "panic",
+    lua.create_function(|_, message: String| -> LuaResult<()> { panic!("{}", message) })?,
+  )?;
+
+  lua.globals().set("fyre", fyre)?;
+  if crate::logger::json() {
+    lua.globals().set("print", log::print(lua)?)?;
+  }
+  Ok(())
+}
+
+/// Removes `REMOVED_GLOBALS` and replaces `os` with a table of the
+/// `OS_KEPT` functions.
+fn restrict_stdlib(lua: &Lua) -> LuaResult<()> {
+  let globals = lua.globals();
+  let os: LuaTable = globals.get("os")?;
+  let kept = lua.create_table()?;
+  for name in OS_KEPT {
+    kept.set(name, os.get::<LuaFunction>(name)?)?;
+  }
+  globals.set("os", kept)?;
+  for name in REMOVED_GLOBALS {
+    globals.set(name, LuaNil)?;
+  }
+  Ok(())
+}
+
+/// Checks a response header before it is sent: the name must be a token
+/// (letters, digits, and ``!#$%&'*+-.^_`|~``) and the value can't contain
+/// CR, LF, or NUL, any of which would let text a script took from a request
+/// end the header early and start another, or the body.
+///
+/// # Errors
+///
+/// Returns an error message saying what is wrong with the header.
+pub fn check_header(name: &[u8], value: &[u8]) -> Result<(), String> {
+  let token = |b: &u8| b.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(b);
+  if name.is_empty() || !name.iter().all(token) {
+    return Err("the name must be letters, digits, and !#$%&'*+-.^_`|~".to_string());
+  }
+  if value.iter().any(|b| matches!(b, b'\r' | b'\n' | b'\0')) {
+    return Err("the value can't contain CR, LF, or NUL".to_string());
+  }
+  Ok(())
+}
+
+/// Adds a header to a `response` table without replacing an existing value of
+/// the same name.
+///
+/// Repeated headers (such as `Set-Cookie`) are stored as a list of values,
+/// which the finalize step sends as one header line each.
+///
+/// # Errors
+///
+/// This function will return a `LuaError` if the header fails
+/// `check_header`, or the response has no `headers` table or it cannot be
+/// updated.
+pub fn append_header(lua: &Lua, response: &LuaTable, name: &str, value: &str) -> LuaResult<()> {
+  check_header(name.as_bytes(), value.as_bytes())
+    .map_err(|e| LuaError::external(format!("header {}: {}", name, e)))?;
+  let headers: LuaTable = response.get("headers")?;
+  match headers.get::<LuaValue>(name)? {
+    LuaValue::Nil => headers.set(name, value),
+    LuaValue::Table(values) => values.push(value),
+    existing => {
+      let values = lua.create_sequence_from([existing])?;
+      values.push(value)?;
+      headers.set(name, values)
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use crate::testing::{self, Fixture};
+
+  #[test]
+  fn handlers_cannot_reach_files_the_environment_or_programs() {
+    let fixture = Fixture::new(
+      r#"router.add("/", "probe.lua")"#,
+      &[(
+        "probe.lua",
+        r#"return { handler = function(request, response)
+          local seen = {}
+          for _, name in ipairs({ "io", "package", "require", "dofile", "loadfile" }) do
+            seen[#seen + 1] = name .. "=" .. type(_G[name])
+          end
+          for _, name in ipairs({ "getenv", "execute", "remove", "rename", "exit", "tmpname" }) do
+            seen[#seen + 1] = "os." .. name .. "=" .. type(os[name])
+          end
+          local open = pcall(function() return io.open("/etc/passwd") end)
+          local popen = pcall(function() return io.popen("id") end)
+          seen[#seen + 1] = tostring(open) .. " " .. tostring(popen)
+          seen[#seen + 1] = type(os.time()) .. " " .. type(os.clock()) .. " " .. os.date("!%Y", 0)
+          response.body = table.concat(seen, " ")
+        end }"#,
+      )],
+    );
+    let server = fixture
+      .builder()
+      .addr("127.0.0.1:0")
+      .workers(1)
+      .load()
+      .unwrap();
+    let addr = server.serve().unwrap().remove(0);
+    // Twice, so the second request runs on the reused state.
+    for _ in 0..2 {
+      let response = testing::get(&addr, "/");
+      assert!(
+        response.ends_with(
+          "\r\n\r\nio=nil package=nil require=nil dofile=nil loadfile=nil \
+           os.getenv=nil os.execute=nil os.remove=nil os.rename=nil os.exit=nil \
+           os.tmpname=nil false false number number 1970"
+        ),
+        "{}",
+        response
+      );
+    }
+    server.shutdown();
+  }
+}
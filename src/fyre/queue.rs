@@ -0,0 +1,841 @@
+//! # `fyre.queue`
+//!
+//! In-memory background job queues processed by worker scripts on dedicated
+//! threads.
+//!
+//! ```lua
+//! -- config.lua
+//! queue.worker("emails", "workers/email.lua", { concurrency = 2, max_attempts = 5 })
+//!
+//! -- a handler
+//! fyre.queue.push("emails", { to = "ada@example.com", body = "Welcome!" })
+//!
+//! -- scripts/workers/email.lua
+//! return {
+//!   perform = function(job)
+//!     send_email(job.to, job.body)   -- raise an error to retry
+//!   end,
+//! }
+//! ```
+//!
+//! Each worker thread owns one Lua state, loaded once with the `fyre`
+//! modules, and calls `perform(job)` for every job it takes. A job that
+//! raises an error is retried with exponential backoff until `max_attempts`
+//! is reached and then moved to the queue's dead-letter list. Job payloads
+//! are copied like `fyre.kv` values.
+//!
+//! When `CONFIG.queue.dir` is set, each queue's pending, running, and dead jobs
+//! are written to `<dir>/<name>.queue` after every change and reloaded at
+//! startup, so a crash or restart re-runs unfinished jobs rather than losing
+//! them.
+
+use mlua::prelude::*;
+use std::collections::{HashMap, VecDeque};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::mpsc;
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use super::value::{SharedKey, SharedValue};
+use crate::locks;
+use crate::AppState;
+
+/// The default number of worker threads per queue.
+pub const DEFAULT_CONCURRENCY: usize = 1;
+/// The default number of times a job is tried before it is dead-lettered.
+pub const DEFAULT_MAX_ATTEMPTS: u32 = 5;
+/// The default delay before the first retry; it doubles on each retry.
+pub const DEFAULT_BACKOFF: Duration = Duration::from_secs(1);
+/// The longest delay between retries.
+const MAX_BACKOFF: Duration = Duration::from_secs(10 * 60);
+/// How many dead jobs each queue keeps; the oldest are dropped first.
+const MAX_DEAD_JOBS: usize = 1000;
+/// How often idle workers wake to check for shutdown.
+const IDLE_WAKE: Duration = Duration::from_secs(1);
+
+/// A worker declared with `queue.worker` in `config.lua`.
+#[derive(Debug)]
+pub struct WorkerSpec {
+  pub queue: String,
+  /// The worker script path, including the scripts directory.
+  pub script: String,
+  pub concurrency: usize,
+  pub max_attempts: u32,
+  pub backoff: Duration,
+}
+
+impl WorkerSpec {
+  /// Reads a worker declaration, with its optional settings table.
+  ///
+  /// # Errors
+  ///
+  /// This function will return a `LuaError` if a setting has the wrong type
+  /// or `concurrency` or `max_attempts` is 0.
+  pub fn from_lua(queue: String, script: String, opts: Option<LuaTable>) -> LuaResult<Self> {
+    let (concurrency, max_attempts, backoff_ms) = match opts {
+      Some(opts) => (
+        opts.get::<Option<usize>>("concurrency")?,
+        opts.get::<Option<u32>>("max_attempts")?,
+        opts.get::<Option<u64>>("backoff_ms")?,
+      ),
+      None => (None, None, None),
+    };
+
+    let concurrency = concurrency.unwrap_or(DEFAULT_CONCURRENCY);
+    let max_attempts = max_attempts.unwrap_or(DEFAULT_MAX_ATTEMPTS);
+    if concurrency == 0 || max_attempts == 0 {
+      return Err(LuaError::external(format!(
+        "queue.worker('{}'): concurrency and max_attempts must be at least 1",
+        queue
+      )));
+    }
+
+    Ok(WorkerSpec {
+      queue,
+      script,
+      concurrency,
+      max_attempts,
+      backoff: backoff_ms
+        .map(Duration::from_millis)
+        .unwrap_or(DEFAULT_BACKOFF),
+    })
+  }
+}
+
+/// A job waiting to run or running.
+struct Job {
+  id: u64,
+  payload: SharedValue,
+  attempts: u32,
+  run_at: Instant,
+}
+
+/// A job that used up its attempts.
+struct DeadJob {
+  id: u64,
+  payload: SharedValue,
+  attempts: u32,
+  error: String,
+  failed_at: i64,
+}
+
+#[derive(Default)]
+struct QueueState {
+  pending: VecDeque<Job>,
+  running: HashMap<u64, Job>,
+  dead: VecDeque<DeadJob>,
+}
+
+/// One named queue.
+struct Queue {
+  name: String,
+  max_attempts: u32,
+  backoff: Duration,
+  state: Mutex<QueueState>,
+  ready: Condvar,
+  /// The snapshot file, when `CONFIG.queue.dir` is set.
+  file: Option<PathBuf>,
+}
+
+impl Queue {
+  fn lock(&self) -> MutexGuard<'_, QueueState> {
+    locks::lock(&self.state, "queue")
+  }
+
+  fn push(&self, id: u64, payload: SharedValue, delay: Duration) {
+    let mut state = self.lock();
+    state.pending.push_back(Job {
+      id,
+      payload,
+      attempts: 0,
+      run_at: Instant::now() + delay,
+    });
+    self.persist(&state);
+    self.ready.notify_one();
+  }
+
+  /// Waits for a job that is due, returning `None` once `stopping` is set.
+  /// The job moves to `running` until it is completed or failed.
+  fn next(&self, stopping: &AtomicBool) -> Option<(u64, SharedValue)> {
+    let mut state = self.lock();
+    loop {
+      if stopping.load(Ordering::Relaxed) {
+        return None;
+      }
+
+      let now = Instant::now();
+      if let Some(index) = state.pending.iter().position(|job| job.run_at <= now) {
+        let job = state.pending.remove(index).expect("index is in range");
+        let taken = (job.id, job.payload.clone());
+        state.running.insert(job.id, job);
+        return Some(taken);
+      }
+
+      let wait = state
+        .pending
+        .iter()
+        .map(|job| job.run_at.saturating_duration_since(now))
+        .min()
+        .unwrap_or(IDLE_WAKE)
+        .min(IDLE_WAKE);
+      state = self
+        .ready
+        .wait_timeout(state, wait)
+        .unwrap_or_else(|e| e.into_inner())
+        .0;
+    }
+  }
+
+  fn complete(&self, id: u64) {
+    let mut state = self.lock();
+    state.running.remove(&id);
+    self.persist(&state);
+  }
+
+  /// Schedules a retry, or dead-letters the job once it is out of attempts.
+  fn fail(&self, id: u64, error: String) {
+    let mut state = self.lock();
+    let Some(mut job) = state.running.remove(&id) else {
+      return;
+    };
+    job.attempts += 1;
+
+    if job.attempts >= self.max_attempts {
+      error!(
+        "Queue '{}' job {} failed {} times, moving to dead letters: {}",
+        self.name, job.id, job.attempts, error
+      );
+      if state.dead.len() >= MAX_DEAD_JOBS {
+        state.dead.pop_front();
+      }
+      state.dead.push_back(DeadJob {
+        id: job.id,
+        payload: job.payload,
+        attempts: job.attempts,
+        error,
+        failed_at: unix_now(),
+      });
+    } else {
+      let delay = self
+        .backoff
+        .saturating_mul(1 << (job.attempts - 1).min(16))
+        .min(MAX_BACKOFF);
+      warn!(
+        "Queue '{}' job {} failed (attempt {}), retrying in {:?}: {}",
+        self.name, job.id, job.attempts, delay, error
+      );
+      job.run_at = Instant::now() + delay;
+      state.pending.push_back(job);
+      self.ready.notify_one();
+    }
+    self.persist(&state);
+  }
+
+  /// Writes the queue to its snapshot file, if it has one. Running jobs are
+  /// saved as pending so they run again after a restart.
+  fn persist(&self, state: &QueueState) {
+    let Some(file) = &self.file else {
+      return;
+    };
+
+    let job = |payload: &SharedValue, attempts: u32| {
+      vec![
+        (key("payload"), payload.clone()),
+        (key("attempts"), SharedValue::Integer(attempts as i64)),
+      ]
+    };
+    let pending = state
+      .running
+      .values()
+      .chain(state.pending.iter())
+      .map(|j| SharedValue::Table(job(&j.payload, j.attempts)));
+    let dead = state.dead.iter().map(|d| {
+      let mut fields = job(&d.payload, d.attempts);
+      fields.push((
+        key("error"),
+        SharedValue::String(d.error.clone().into_bytes()),
+      ));
+      fields.push((key("failed_at"), SharedValue::Integer(d.failed_at)));
+      SharedValue::Table(fields)
+    });
+    let snapshot = SharedValue::Table(vec![
+      (key("pending"), list(pending)),
+      (key("dead"), list(dead)),
+    ]);
+
+    let mut data = Vec::new();
+    snapshot.encode(&mut data);
+    // Write to a temporary file and rename, so a crash mid-write leaves the
+    // previous snapshot intact.
+    let tmp = file.with_extension("queue.tmp");
+    if let Err(e) = fs::write(&tmp, &data).and_then(|_| fs::rename(&tmp, file)) {
+      error!(
+        "Failed to persist queue '{}' to {}: {}",
+        self.name,
+        file.display(),
+        e
+      );
+    }
+  }
+
+  /// Loads a snapshot written by `persist`.
+  fn restore(&self, path: &Path, ids: &AtomicU64) -> Result<(), String> {
+    let data = match fs::read(path) {
+      Ok(data) => data,
+      Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+      Err(e) => return Err(format!("{}: {}", path.display(), e)),
+    };
+    let snapshot = SharedValue::decode(&data)
+      .ok_or_else(|| format!("{} is not a valid queue snapshot", path.display()))?;
+
+    let now = Instant::now();
+    let mut state = self.lock();
+    for job in items(field(&snapshot, "pending")) {
+      state.pending.push_back(Job {
+        id: ids.fetch_add(1, Ordering::Relaxed),
+        payload: field(job, "payload")
+          .cloned()
+          .unwrap_or(SharedValue::Boolean(true)),
+        attempts: integer(field(job, "attempts")) as u32,
+        run_at: now,
+      });
+    }
+    for job in items(field(&snapshot, "dead")) {
+      state.dead.push_back(DeadJob {
+        id: ids.fetch_add(1, Ordering::Relaxed),
+        payload: field(job, "payload")
+          .cloned()
+          .unwrap_or(SharedValue::Boolean(true)),
+        attempts: integer(field(job, "attempts")) as u32,
+        error: match field(job, "error") {
+          Some(SharedValue::String(e)) => String::from_utf8_lossy(e).into_owned(),
+          _ => String::new(),
+        },
+        failed_at: integer(field(job, "failed_at")),
+      });
+    }
+
+    if !state.pending.is_empty() {
+      info!(
+        "Restored {} pending job(s) for queue '{}'",
+        state.pending.len(),
+        self.name
+      );
+    }
+    Ok(())
+  }
+}
+
+fn key(name: &str) -> SharedKey {
+  SharedKey::String(name.as_bytes().to_vec())
+}
+
+fn list(values: impl Iterator<Item = SharedValue>) -> SharedValue {
+  SharedValue::Table(
+    values
+      .enumerate()
+      .map(|(i, value)| (SharedKey::Integer(i as i64 + 1), value))
+      .collect(),
+  )
+}
+
+fn field<'a>(value: &'a SharedValue, name: &str) -> Option<&'a SharedValue> {
+  match value {
+    SharedValue::Table(entries) => entries
+      .iter()
+      .find(|(k, _)| *k == key(name))
+      .map(|(_, v)| v),
+    _ => None,
+  }
+}
+
+fn items(value: Option<&SharedValue>) -> impl Iterator<Item = &SharedValue> {
+  let entries = match value {
+    Some(SharedValue::Table(entries)) => entries.as_slice(),
+    _ => &[],
+  };
+  entries.iter().map(|(_, v)| v)
+}
+
+fn integer(value: Option<&SharedValue>) -> i64 {
+  match value {
+    Some(SharedValue::Integer(i)) => *i,
+    _ => 0,
+  }
+}
+
+fn unix_now() -> i64 {
+  SystemTime::now()
+    .duration_since(UNIX_EPOCH)
+    .map(|d| d.as_secs() as i64)
+    .unwrap_or(0)
+}
+
+/// The queues declared in `config.lua` and their worker threads.
+pub struct Queues {
+  specs: Vec<WorkerSpec>,
+  queues: HashMap<String, Arc<Queue>>,
+  next_id: AtomicU64,
+  stopping: AtomicBool,
+  workers: Mutex<Vec<JoinHandle<()>>>,
+}
+
+impl Queues {
+  /// Creates the declared queues, restoring their snapshots from `dir`.
+  ///
+  /// # Errors
+  ///
+  /// Returns an error message if `dir` cannot be created or a snapshot
+  /// cannot be read.
+  pub fn new(specs: Vec<WorkerSpec>, dir: Option<&str>) -> Result<Self, String> {
+    if let Some(dir) = dir {
+      fs::create_dir_all(dir)
+        .map_err(|e| format!("CONFIG.queue.dir '{}' is not usable: {}", dir, e))?;
+    }
+
+    let next_id = AtomicU64::new(1);
+    let mut queues = HashMap::new();
+    for spec in &specs {
+      if queues.contains_key(&spec.queue) {
+        continue;
+      }
+      let file = dir.map(|dir| Path::new(dir).join(format!("{}.queue", spec.queue)));
+      let queue = Queue {
+        name: spec.queue.clone(),
+        max_attempts: spec.max_attempts,
+        backoff: spec.backoff,
+        state: Mutex::new(QueueState::default()),
+        ready: Condvar::new(),
+        file: file.clone(),
+      };
+      if let Some(file) = &file {
+        queue.restore(file, &next_id)?;
+      }
+      queues.insert(spec.queue.clone(), Arc::new(queue));
+    }
+
+    Ok(Queues {
+      specs,
+      queues,
+      next_id,
+      stopping: AtomicBool::new(false),
+      workers: Mutex::new(Vec::new()),
+    })
+  }
+
+  fn queue(&self, name: &str) -> LuaResult<&Arc<Queue>> {
+    self.queues.get(name).ok_or_else(|| {
+      LuaError::external(format!(
+        "fyre.queue: no worker is declared for queue '{}' in config.lua",
+        name
+      ))
+    })
+  }
+
+  /// Adds a job to the queue called `name`, to run after `delay`, and
+  /// returns its id.
+  ///
+  /// # Errors
+  ///
+  /// This function will return a `LuaError` if no worker is declared for the
+  /// queue.
+  pub fn push(&self, name: &str, job: SharedValue, delay: Duration) -> LuaResult<u64> {
+    let queue = self.queue(name)?;
+    let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+    queue.push(id, job, delay);
+    Ok(id)
+  }
+
+  /// Stops the workers once their current jobs finish and waits for them.
+  /// Jobs still pending stay in the snapshot when `CONFIG.queue.dir` is set.
+  pub fn shutdown(&self) {
+    self.stopping.store(true, Ordering::Relaxed);
+    for queue in self.queues.values() {
+      queue.ready.notify_all();
+    }
+
+    let workers = std::mem::take(&mut *locks::lock(&self.workers, "queue workers"));
+    for worker in workers {
+      let _ = worker.join();
+    }
+
+    for queue in self.queues.values() {
+      let pending = queue.lock().pending.len();
+      if pending > 0 {
+        match &queue.file {
+          Some(file) => info!(
+            "Queue '{}' has {} pending job(s) saved in {}",
+            queue.name,
+            pending,
+            file.display()
+          ),
+          None => warn!(
+            "Queue '{}' dropped {} pending job(s) on shutdown (set CONFIG.queue.dir to keep them)",
+            queue.name, pending
+          ),
+        }
+      }
+    }
+  }
+}
+
+/// Starts the worker threads for every declared queue.
+///
+/// Each thread loads its worker script before this returns, so a broken
+/// worker stops the server at startup rather than failing every job.
+///
+/// # Errors
+///
+/// Returns an error message if a worker script fails to load or does not
+/// return a table with a `perform` function.
+pub fn start_workers(state: &Arc<AppState>) -> Result<(), String> {
+  let mut handles = Vec::new();
+  let (ready_tx, ready_rx) = mpsc::channel();
+
+  for spec in &state.queues.specs {
+    let queue = state.queues.queues[&spec.queue].clone();
+    for n in 0..spec.concurrency {
+      let state = state.clone();
+      let queue = queue.clone();
+      let script = spec.script.clone();
+      let ready_tx = ready_tx.clone();
+      let handle = thread::Builder::new()
+        .name(format!("queue-{}-{}", spec.queue, n))
+        .spawn(move || run_worker(&state, &queue, &script, ready_tx))
+        .map_err(|e| format!("failed to start worker for queue '{}': {}", spec.queue, e))?;
+      handles.push(handle);
+    }
+  }
+  drop(ready_tx);
+
+  let mut failure = None;
+  for result in ready_rx {
+    if let Err(e) = result {
+      failure.get_or_insert(e);
+    }
+  }
+  locks::lock(&state.queues.workers, "queue workers").extend(handles);
+
+  match failure {
+    Some(e) => {
+      state.queues.shutdown();
+      Err(e)
+    }
+    None => Ok(()),
+  }
+}
+
+fn load_worker(lua: &Lua, state: &Arc<AppState>, script: &str) -> LuaResult<LuaFunction> {
+  super::random::seed_math_random(lua)?;
+  super::register(lua, state)?;
+  let code = fs::read_to_string(script)
+    .map_err(|e| LuaError::external(format!("Failed to read worker script {}: {}", script, e)))?;
+  let module = lua
+    .load(&code)
+    .set_name(script)
+    .eval::<LuaTable>()
+    .map_err(|e| LuaError::external(format!("Worker script failed to return a table: {}", e)))?;
+  module
+    .get::<LuaFunction>("perform")
+    .map_err(|_| LuaError::external(format!("{} has no 'perform' function", script)))
+}
+
+fn run_worker(
+  state: &Arc<AppState>,
+  queue: &Queue,
+  script: &str,
+  ready: mpsc::Sender<Result<(), String>>,
+) {
+  let lua = Lua::new();
+  let perform = match load_worker(&lua, state, script) {
+    Ok(perform) => {
+      let _ = ready.send(Ok(()));
+      perform
+    }
+    Err(e) => {
+      let _ = ready.send(Err(format!("queue '{}': {}", queue.name, e)));
+      return;
+    }
+  };
+  drop(ready);
+
+  while let Some((id, payload)) = queue.next(&state.queues.stopping) {
+    let result = payload
+      .to_lua_value(&lua)
+      .and_then(|job| perform.call::<()>(job));
+    match result {
+      Ok(()) => queue.complete(id),
+      Err(e) => queue.fail(id, e.to_string()),
+    }
+  }
+}
+
+/// Builds the `fyre.queue` module table.
+///
+/// # Errors
+///
+/// This function will return a `LuaError` if the module table or its functions
+/// cannot be created.
+pub fn module(lua: &Lua, state: &Arc<AppState>) -> LuaResult<LuaTable> {
+  let module = lua.create_table()?;
+
+  // fyre.queue.push(name, job [, { delay = seconds }]) -> id
+  let st = state.clone();
+  module.set(
+    "push",
+    lua.create_function(
+      move |_, (name, job, opts): (String, LuaValue, Option<LuaTable>)| {
+        if job.is_nil() {
+          return Err(LuaError::external("fyre.queue.push: job must not be nil"));
+        }
+        let delay = match opts {
+          Some(opts) => super::kv::ttl_from_secs(opts.get("delay")?)?.unwrap_or_default(),
+          None => Duration::ZERO,
+        };
+        st.queues.push(&name, SharedValue::from_lua_value(job)?, delay)
+      },
+    )?,
+  )?;
+
+  // fyre.queue.stats(name) -> { pending, running, dead }
+  let st = state.clone();
+  module.set(
+    "stats",
+    lua.create_function(move |lua, name: String| {
+      let queue = st.queues.queue(&name)?;
+      let state = queue.lock();
+      let stats = lua.create_table()?;
+      stats.set("pending", state.pending.len())?;
+      stats.set("running", state.running.len())?;
+      stats.set("dead", state.dead.len())?;
+      Ok(stats)
+    })?,
+  )?;
+
+  // fyre.queue.dead(name) -> { { id, job, attempts, error, failed_at }, ... }
+  let st = state.clone();
+  module.set(
+    "dead",
+    lua.create_function(move |lua, name: String| {
+      let queue = st.queues.queue(&name)?;
+      let state = queue.lock();
+      let list = lua.create_table_with_capacity(state.dead.len(), 0)?;
+      for dead in &state.dead {
+        let entry = lua.create_table()?;
+        entry.set("id", dead.id)?;
+        entry.set("job", dead.payload.to_lua_value(lua)?)?;
+        entry.set("attempts", dead.attempts)?;
+        entry.set("error", dead.error.as_str())?;
+        entry.set("failed_at", dead.failed_at)?;
+        list.push(entry)?;
+      }
+      Ok(list)
+    })?,
+  )?;
+
+  // fyre.queue.retry_dead(name) -> number of jobs requeued
+  let st = state.clone();
+  module.set(
+    "retry_dead",
+    lua.create_function(move |_, name: String| {
+      let queue = st.queues.queue(&name)?;
+      let mut state = queue.lock();
+      let dead = std::mem::take(&mut state.dead);
+      let count = dead.len();
+      let now = Instant::now();
+      state.pending.extend(dead.into_iter().map(|dead| Job {
+        id: dead.id,
+        payload: dead.payload,
+        attempts: 0,
+        run_at: now,
+      }));
+      queue.persist(&state);
+      queue.ready.notify_all();
+      Ok(count)
+    })?,
+  )?;
+
+  Ok(module)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::testing::{self, Fixture};
+
+  fn spec(queue: &str, max_attempts: u32) -> WorkerSpec {
+    WorkerSpec {
+      queue: queue.to_string(),
+      script: "worker.lua".to_string(),
+      concurrency: 1,
+      max_attempts,
+      backoff: Duration::from_millis(20),
+    }
+  }
+
+  fn text(value: &str) -> SharedValue {
+    SharedValue::String(value.as_bytes().to_vec())
+  }
+
+  /// The next due job, without waiting for one.
+  fn take(queue: &Queue) -> Option<(u64, SharedValue)> {
+    let stopping = AtomicBool::new(false);
+    let due = queue
+      .lock()
+      .pending
+      .iter()
+      .any(|job| job.run_at <= Instant::now());
+    due.then(|| queue.next(&stopping).unwrap())
+  }
+
+  #[test]
+  fn failed_jobs_back_off_then_go_to_dead_letters() {
+    let queues = Queues::new(vec![spec("emails", 2)], None).unwrap();
+    let id = queues.push("emails", text("ada"), Duration::ZERO).unwrap();
+    let queue = &queues.queues["emails"];
+
+    let (taken, payload) = take(queue).unwrap();
+    assert_eq!((taken, payload), (id, text("ada")));
+    queue.fail(id, "smtp down".to_string());
+    // Retried after the backoff, not before.
+    assert!(take(queue).is_none());
+    thread::sleep(Duration::from_millis(30));
+    let (taken, _) = take(queue).unwrap();
+    assert_eq!(taken, id);
+    queue.fail(id, "smtp still down".to_string());
+
+    let state = queue.lock();
+    assert!(state.pending.is_empty() && state.running.is_empty());
+    let dead = &state.dead[0];
+    assert_eq!(
+      (dead.id, dead.attempts, dead.error.as_str()),
+      (id, 2, "smtp still down")
+    );
+  }
+
+  #[test]
+  fn completed_jobs_are_removed() {
+    let queues = Queues::new(vec![spec("emails", 2)], None).unwrap();
+    let id = queues.push("emails", text("ada"), Duration::ZERO).unwrap();
+    let queue = &queues.queues["emails"];
+    take(queue).unwrap();
+    assert_eq!(queue.lock().running.len(), 1);
+    queue.complete(id);
+    let state = queue.lock();
+    assert!(state.pending.is_empty() && state.running.is_empty() && state.dead.is_empty());
+  }
+
+  #[test]
+  fn unfinished_and_dead_jobs_survive_a_restart() {
+    let fixture = Fixture::new("", &[]);
+    let dir = fixture.path().join("queues");
+    let dir = dir.to_str().unwrap();
+    {
+      let queues = Queues::new(vec![spec("emails", 1)], Some(dir)).unwrap();
+      let queue = &queues.queues["emails"];
+      let failed = queues
+        .push("emails", text("failed"), Duration::ZERO)
+        .unwrap();
+      take(queue).unwrap();
+      queue.fail(failed, "bounced".to_string());
+      queues
+        .push("emails", text("running"), Duration::ZERO)
+        .unwrap();
+      take(queue).unwrap();
+      queues
+        .push("emails", text("pending"), Duration::from_secs(60))
+        .unwrap();
+    }
+
+    let queues = Queues::new(vec![spec("emails", 1)], Some(dir)).unwrap();
+    let state = queues.queues["emails"].lock();
+    let pending: Vec<_> = state
+      .pending
+      .iter()
+      .map(|job| job.payload.clone())
+      .collect();
+    assert_eq!(pending, [text("running"), text("pending")]);
+    assert_eq!(state.dead.len(), 1);
+    assert_eq!(
+      (&state.dead[0].payload, state.dead[0].error.as_str()),
+      (&text("failed"), "bounced")
+    );
+  }
+
+  #[test]
+  fn workers_and_queues_are_checked() {
+    let lua = Lua::new();
+    for opts in [
+      "{ concurrency = 0 }",
+      "{ max_attempts = 0 }",
+      "{ backoff_ms = 'soon' }",
+    ] {
+      let opts: LuaTable = lua.load(opts).eval().unwrap();
+      assert!(WorkerSpec::from_lua("q".into(), "w.lua".into(), Some(opts)).is_err());
+    }
+    let spec = WorkerSpec::from_lua("q".into(), "w.lua".into(), None).unwrap();
+    assert_eq!(
+      (spec.concurrency, spec.max_attempts, spec.backoff),
+      (DEFAULT_CONCURRENCY, DEFAULT_MAX_ATTEMPTS, DEFAULT_BACKOFF)
+    );
+    let queues = Queues::new(vec![], None).unwrap();
+    let error = queues
+      .push("emails", text("ada"), Duration::ZERO)
+      .unwrap_err();
+    assert!(
+      error
+        .to_string()
+        .contains("no worker is declared for queue 'emails'"),
+      "{}",
+      error
+    );
+  }
+
+  #[test]
+  fn workers_perform_pushed_jobs() {
+    let fixture = Fixture::new(
+      r#"
+        queue.worker("greetings", "worker.lua")
+        router.add("/push", "push.lua")
+        router.add("/done", "done.lua")
+      "#,
+      &[
+        (
+          "worker.lua",
+          r#"return { perform = function(job) fyre.kv.set("greeted", job.name) end }"#,
+        ),
+        (
+          "push.lua",
+          r#"return { handler = function(request, response)
+            response.body = tostring(fyre.queue.push("greetings", { name = "ada" }))
+          end }"#,
+        ),
+        (
+          "done.lua",
+          r#"return { handler = function(request, response)
+            response.body = fyre.kv.get("greeted") or "not yet"
+          end }"#,
+        ),
+      ],
+    );
+    let server = fixture
+      .builder()
+      .addr("127.0.0.1:0")
+      .workers(1)
+      .load()
+      .unwrap();
+    let addr = server.serve().unwrap().remove(0);
+    assert!(testing::get(&addr, "/push").ends_with("\r\n\r\n1"));
+    let deadline = Instant::now() + Duration::from_secs(5);
+    let mut done = testing::get(&addr, "/done");
+    while !done.ends_with("ada") && Instant::now() < deadline {
+      thread::sleep(Duration::from_millis(10));
+      done = testing::get(&addr, "/done");
+    }
+    assert!(done.ends_with("\r\n\r\nada"), "{}", done);
+    server.shutdown();
+  }
+}
@@ -0,0 +1,108 @@
+//! # `fyre.uuid` and `fyre.random`
+//!
+//! Identifiers and random values from the operating system's CSPRNG (via
+//! `rand::thread_rng`), suitable for tokens and other secrets.
+//!
+//! ```lua
+//! local id = fyre.uuid.v4()          -- "0f8fad5b-d9cb-469f-a165-70867728950e"
+//! local sortable = fyre.uuid.v7()    -- time-ordered
+//! local token = fyre.random.hex(16)  -- 32 hex characters
+//! local raw = fyre.random.bytes(32)  -- binary Lua string
+//! local roll = fyre.random.int(1, 6)
+//! ```
+
+use mlua::prelude::*;
+use rand::{Rng, RngCore};
+
+use super::encoding::hex_encode;
+
+/// The most bytes `fyre.random.bytes` and `fyre.random.hex` will generate at
+/// once.
+const MAX_RANDOM_BYTES: usize = 1024 * 1024;
+
+/// Builds the `fyre.uuid` module table.
+///
+/// # Errors
+///
+/// This function will return a `LuaError` if the module table or its functions
+/// cannot be created.
+pub fn uuid_module(lua: &Lua) -> LuaResult<LuaTable> {
+  let module = lua.create_table()?;
+
+  module.set(
+    "v4",
+    lua.create_function(|_, ()| Ok(uuid::Uuid::new_v4().to_string()))?,
+  )?;
+
+  module.set(
+    "v7",
+    lua.create_function(|_, ()| Ok(uuid::Uuid::now_v7().to_string()))?,
+  )?;
+
+  Ok(module)
+}
+
+/// Builds the `fyre.random` module table.
+///
+/// # Errors
+///
+/// This function will return a `LuaError` if the module table or its functions
+/// cannot be created.
+pub fn random_module(lua: &Lua) -> LuaResult<LuaTable> {
+  let module = lua.create_table()?;
+
+  module.set(
+    "bytes",
+    lua.create_function(|lua, n: usize| lua.create_string(random_bytes(n)?))?,
+  )?;
+
+  module.set(
+    "hex",
+    lua.create_function(|_, n: usize| Ok(hex_encode(&random_bytes(n)?)))?,
+  )?;
+
+  module.set(
+    "int",
+    lua.create_function(|_, (min, max): (i64, i64)| {
+      if min > max {
+        return Err(LuaError::external(format!(
+          "fyre.random.int: min ({}) is greater than max ({})",
+          min, max
+        )));
+      }
+      Ok(rand::thread_rng().gen_range(min..=max))
+    })?,
+  )?;
+
+  Ok(module)
+}
+
+/// Returns `n` random bytes.
+fn random_bytes(n: usize) -> LuaResult<Vec<u8>> {
+  if n > MAX_RANDOM_BYTES {
+    return Err(LuaError::external(format!(
+      "cannot generate more than {} random bytes at once",
+      MAX_RANDOM_BYTES
+    )));
+  }
+
+  let mut bytes = vec![0u8; n];
+  rand::thread_rng().fill_bytes(&mut bytes);
+  Ok(bytes)
+}
+
+/// Seeds Lua's `math.random` from the CSPRNG.
+///
+/// Every request gets a fresh Lua state, and states created close together
+/// can otherwise start from similar seeds.
+///
+/// # Errors
+///
+/// This function will return a `LuaError` if `math.randomseed` cannot be
+/// called.
+pub fn seed_math_random(lua: &Lua) -> LuaResult<()> {
+  let math: LuaTable = lua.globals().get("math")?;
+  let randomseed: LuaFunction = math.get("randomseed")?;
+  let mut rng = rand::thread_rng();
+  randomseed.call::<()>((rng.gen::<i64>(), rng.gen::<i64>()))
+}
@@ -0,0 +1,479 @@
+//! # `fyre.ratelimit`
+//!
+//! Per-key rate limits shared by every request.
+//!
+//! ```lua
+//! local allowed, remaining, retry_after =
+//!   fyre.ratelimit.check("login:" .. user, { limit = 5, window = 3600 })
+//!
+//! -- or let it answer 429 with Retry-After:
+//! local api = { limit = 10, window = 1, mode = "bucket" }
+//! if not fyre.ratelimit.enforce(response, "api:" .. ip, api) then return end
+//! ```
+//!
+//! `mode = "fixed"` (the default) allows `limit` hits per `window` seconds,
+//! counted from a key's first hit in the window. `mode = "bucket"` is a token
+//! bucket holding up to `limit` tokens and refilling at `limit / window`
+//! tokens per second, which smooths bursts out. Each check updates the key
+//! under one lock, so concurrent requests can't both take the last slot.
+//! The allowed and limited counts appear in `fyre.metrics.render()`.
+//!
+//! The keys are split across `SHARDS` maps by hash, each behind a lock of
+//! its own, so checks on different keys rarely wait for each other. Each
+//! map keeps its keys in the order they reset as well, so keys that have
+//! reset are dropped, and a full map makes room, without a scan.
+
+use mlua::prelude::*;
+use std::collections::hash_map::RandomState;
+use std::collections::{BTreeMap, HashMap};
+use std::hash::BuildHasher;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+use crate::locks;
+use crate::AppState;
+
+/// The most keys tracked at once; past this the key closest to resetting in
+/// the new key's shard is dropped.
+const MAX_KEYS: usize = 100_000;
+/// How many shards the keys are split across.
+const SHARDS: usize = 16;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Mode {
+  Fixed,
+  Bucket,
+}
+
+/// The state of one key.
+struct Entry {
+  mode: Mode,
+  /// Hits in the window (fixed) or tokens left (bucket).
+  level: f64,
+  /// When the window started (fixed) or tokens were last refilled (bucket).
+  since: Instant,
+  /// When the key is back to its initial state and can be forgotten.
+  resets_at: Instant,
+  /// Tells apart entries resetting at the same instant in `Shard::resets`.
+  id: u64,
+}
+
+/// The outcome of one check.
+pub struct Decision {
+  pub allowed: bool,
+  pub remaining: u64,
+  /// Seconds until a retry can succeed; 0 when allowed.
+  pub retry_after: u64,
+  /// Seconds until the key is back to its initial state.
+  pub reset: u64,
+}
+
+/// The keys in one shard, and the order they reset in, guarded together by
+/// the shard's mutex.
+#[derive(Default)]
+struct Shard {
+  entries: HashMap<String, Entry>,
+  /// Maps each entry's `(resets_at, id)` to its key; the first key is the
+  /// next to reset.
+  resets: BTreeMap<(Instant, u64), String>,
+  next_id: u64,
+}
+
+impl Shard {
+  fn remove(&mut self, key: &str) -> Option<Entry> {
+    let entry = self.entries.remove(key)?;
+    self.resets.remove(&(entry.resets_at, entry.id));
+    Some(entry)
+  }
+
+  fn insert(&mut self, key: String, mut entry: Entry) {
+    entry.id = self.next_id;
+    self.next_id += 1;
+    self.resets.insert((entry.resets_at, entry.id), key.clone());
+    self.entries.insert(key, entry);
+  }
+
+  /// Drops the keys that have reset by `now`.
+  fn expire(&mut self, now: Instant) {
+    while let Some(entry) = self.resets.first_entry() {
+      if entry.key().0 > now {
+        break;
+      }
+      let key = entry.remove();
+      self.entries.remove(&key);
+    }
+  }
+}
+
+/// The counters behind `fyre.ratelimit`.
+pub struct RateLimiter {
+  shards: [Mutex<Shard>; SHARDS],
+  hasher: RandomState,
+  /// The most keys one shard holds.
+  shard_keys: usize,
+  allowed: AtomicU64,
+  limited: AtomicU64,
+}
+
+/// A snapshot of the allowed and limited counts since startup.
+pub struct RateLimitStats {
+  pub allowed: u64,
+  pub limited: u64,
+}
+
+impl Default for RateLimiter {
+  fn default() -> Self {
+    RateLimiter::new(MAX_KEYS)
+  }
+}
+
+impl RateLimiter {
+  /// Creates a limiter tracking at most about `max_keys` keys, at least one
+  /// per shard.
+  fn new(max_keys: usize) -> Self {
+    RateLimiter {
+      shards: std::array::from_fn(|_| Mutex::default()),
+      hasher: RandomState::new(),
+      shard_keys: (max_keys / SHARDS).max(1),
+      allowed: AtomicU64::new(0),
+      limited: AtomicU64::new(0),
+    }
+  }
+
+  /// Locks the shard `key` is in.
+  fn shard(&self, key: &str) -> MutexGuard<'_, Shard> {
+    let index = self.hasher.hash_one(key) as usize % SHARDS;
+    locks::lock(&self.shards[index], "rate limiter")
+  }
+
+  /// Returns the allowed and limited counts since startup.
+  pub fn stats(&self) -> RateLimitStats {
+    RateLimitStats {
+      allowed: self.allowed.load(Ordering::Relaxed),
+      limited: self.limited.load(Ordering::Relaxed),
+    }
+  }
+
+  /// Returns the number of keys tracked.
+  pub fn keys(&self) -> usize {
+    self
+      .shards
+      .iter()
+      .map(|shard| locks::lock(shard, "rate limiter").entries.len())
+      .sum()
+  }
+
+  fn check(&self, key: &str, mode: Mode, limit: f64, window: Duration) -> LuaResult<Decision> {
+    Ok(self.decide(key, mode, limit, window, limit))
+  }
+
+  /// Checks `key` against a token bucket refilling `limit` tokens every
+  /// `window` and holding at most `burst`, for the server's own per-client
+  /// limits.
+  pub fn check_bucket(&self, key: &str, limit: f64, window: Duration, burst: f64) -> Decision {
+    self.decide(key, Mode::Bucket, limit, window, burst)
+  }
+
+  /// Returns what `check_bucket` would decide for `key` without taking a
+  /// token. A key not tracked has a full bucket, and isn't added.
+  pub fn peek_bucket(&self, key: &str, limit: f64, window: Duration, burst: f64) -> Decision {
+    let shard = self.shard(key);
+    let now = Instant::now();
+    let rate = limit / window.as_secs_f64();
+    let (level, resets_at) = match shard.entries.get(key) {
+      Some(entry) if entry.mode == Mode::Bucket && entry.resets_at > now => {
+        let elapsed = now.duration_since(entry.since).as_secs_f64();
+        ((entry.level + elapsed * rate).min(burst), entry.resets_at)
+      }
+      _ => (burst, now),
+    };
+    let allowed = level >= 1.0;
+    Decision {
+      allowed,
+      remaining: level.floor() as u64,
+      retry_after: if allowed {
+        0
+      } else {
+        ceil_secs(Duration::from_secs_f64((1.0 - level) / rate))
+      },
+      reset: ceil_secs(resets_at.saturating_duration_since(now)),
+    }
+  }
+
+  /// Counts a hit on `key`. A bucket holds up to `capacity` tokens; a fixed
+  /// window ignores it.
+  fn decide(&self, key: &str, mode: Mode, limit: f64, window: Duration, capacity: f64) -> Decision {
+    let mut shard = self.shard(key);
+    let now = Instant::now();
+
+    // A key whose window has passed starts over.
+    shard.expire(now);
+    let mut entry = match shard.remove(key) {
+      Some(entry) => entry,
+      None => {
+        if shard.entries.len() >= self.shard_keys {
+          if let Some((_, closest)) = shard.resets.pop_first() {
+            shard.entries.remove(&closest);
+          }
+        }
+        Entry {
+          mode,
+          level: if mode == Mode::Fixed { 0.0 } else { capacity },
+          since: now,
+          resets_at: now,
+          id: 0,
+        }
+      }
+    };
+    // So does a key checked with different settings.
+    if entry.mode != mode {
+      entry.mode = mode;
+      entry.level = if mode == Mode::Fixed { 0.0 } else { capacity };
+      entry.since = now;
+    }
+
+    let decision = match mode {
+      Mode::Fixed => {
+        let allowed = entry.level < limit;
+        if allowed {
+          entry.level += 1.0;
+        }
+        entry.resets_at = entry.since + window;
+        Decision {
+          allowed,
+          remaining: (limit - entry.level).max(0.0) as u64,
+          retry_after: if allowed {
+            0
+          } else {
+            ceil_secs(entry.resets_at.saturating_duration_since(now))
+          },
+          reset: ceil_secs(entry.resets_at.saturating_duration_since(now)),
+        }
+      }
+      Mode::Bucket => {
+        let rate = limit / window.as_secs_f64();
+        let elapsed = now.duration_since(entry.since).as_secs_f64();
+        entry.level = (entry.level + elapsed * rate).min(capacity);
+        entry.since = now;
+
+        let allowed = entry.level >= 1.0;
+        if allowed {
+          entry.level -= 1.0;
+        }
+        entry.resets_at = now + Duration::from_secs_f64((capacity - entry.level) / rate);
+        Decision {
+          allowed,
+          remaining: entry.level.floor() as u64,
+          retry_after: if allowed {
+            0
+          } else {
+            ceil_secs(Duration::from_secs_f64((1.0 - entry.level) / rate))
+          },
+          reset: ceil_secs(entry.resets_at.saturating_duration_since(now)),
+        }
+      }
+    };
+    shard.insert(key.to_string(), entry);
+
+    if decision.allowed {
+      self.allowed.fetch_add(1, Ordering::Relaxed);
+    } else {
+      self.limited.fetch_add(1, Ordering::Relaxed);
+    }
+    decision
+  }
+}
+
+/// Rounds up to whole seconds, as `Retry-After` needs.
+fn ceil_secs(duration: Duration) -> u64 {
+  duration.as_secs() + u64::from(duration.subsec_nanos() > 0)
+}
+
+/// Reads the `{ limit, window, mode }` options.
+fn options(opts: &LuaTable) -> LuaResult<(Mode, f64, Duration)> {
+  let limit: u32 = opts
+    .get::<Option<u32>>("limit")?
+    .ok_or_else(|| LuaError::external("fyre.ratelimit: 'limit' is required"))?;
+  let window: f64 = opts
+    .get::<Option<f64>>("window")?
+    .ok_or_else(|| LuaError::external("fyre.ratelimit: 'window' is required"))?;
+  if limit == 0 || window.is_nan() || window <= 0.0 || window > 1e9 {
+    return Err(LuaError::external(
+      "fyre.ratelimit: 'limit' and 'window' must be positive",
+    ));
+  }
+
+  let mode = match opts.get::<Option<String>>("mode")?.as_deref() {
+    None | Some("fixed") => Mode::Fixed,
+    Some("bucket") => Mode::Bucket,
+    Some(other) => {
+      return Err(LuaError::external(format!(
+        "fyre.ratelimit: mode must be \"fixed\" or \"bucket\", got \"{}\"",
+        other
+      )))
+    }
+  };
+  Ok((mode, f64::from(limit), Duration::from_secs_f64(window)))
+}
+
+/// Builds the `fyre.ratelimit` module table.
+///
+/// # Errors
+///
+/// This function will return a `LuaError` if the module table or its functions
+/// cannot be created.
+pub fn module(lua: &Lua, state: &Arc<AppState>) -> LuaResult<LuaTable> {
+  let module = lua.create_table()?;
+
+  // fyre.ratelimit.check(key, opts) -> allowed, remaining, retry_after
+  let st = state.clone();
+  module.set(
+    "check",
+    lua.create_function(move |_, (key, opts): (String, LuaTable)| {
+      let (mode, limit, window) = options(&opts)?;
+      let decision = st.ratelimit.check(&key, mode, limit, window)?;
+      Ok((decision.allowed, decision.remaining, decision.retry_after))
+    })?,
+  )?;
+
+  // fyre.ratelimit.enforce(response, key, opts) -> allowed
+  let st = state.clone();
+  module.set(
+    "enforce",
+    lua.create_function(
+      move |_, (response, key, opts): (LuaTable, String, LuaTable)| {
+        let (mode, limit, window) = options(&opts)?;
+        let decision = st.ratelimit.check(&key, mode, limit, window)?;
+        if !decision.allowed {
+          response.set("status", 429)?;
+          response.set("body", "Too Many Requests")?;
+          let headers: LuaTable = response.get("headers")?;
+          headers.set("Retry-After", decision.retry_after.to_string())?;
+        }
+        Ok(decision.allowed)
+      },
+    )?,
+  )?;
+
+  Ok(module)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  const MINUTE: Duration = Duration::from_secs(60);
+
+  #[test]
+  fn a_fixed_window_allows_its_limit() {
+    let limiter = RateLimiter::default();
+    for remaining in [1, 0] {
+      let decision = limiter.check("login", Mode::Fixed, 2.0, MINUTE).unwrap();
+      assert!(decision.allowed);
+      assert_eq!(decision.remaining, remaining);
+    }
+    let decision = limiter.check("login", Mode::Fixed, 2.0, MINUTE).unwrap();
+    assert!(!decision.allowed);
+    assert_eq!(decision.retry_after, 60);
+    assert!(
+      limiter
+        .check("other", Mode::Fixed, 2.0, MINUTE)
+        .unwrap()
+        .allowed
+    );
+    let stats = limiter.stats();
+    assert_eq!((stats.allowed, stats.limited), (3, 1));
+  }
+
+  #[test]
+  fn a_bucket_refills_and_then_forgets_the_key() {
+    let limiter = RateLimiter::default();
+    let window = Duration::from_millis(100);
+    assert!(limiter.check_bucket("api", 1.0, window, 1.0).allowed);
+    let decision = limiter.check_bucket("api", 1.0, window, 1.0);
+    assert!(!decision.allowed);
+    assert_eq!(decision.retry_after, 1);
+    assert!(!limiter.peek_bucket("api", 1.0, window, 1.0).allowed);
+    std::thread::sleep(window * 2);
+    assert!(limiter.peek_bucket("api", 1.0, window, 1.0).allowed);
+    // The key reset, so it is dropped by the next check in its shard.
+    assert!(limiter.check_bucket("api", 1.0, window, 1.0).allowed);
+    std::thread::sleep(window * 2);
+    limiter.check_bucket("api", 1.0, window, 1.0);
+    assert_eq!(limiter.keys(), 1);
+  }
+
+  #[test]
+  fn a_full_shard_drops_the_key_closest_to_resetting() {
+    let limiter = RateLimiter::new(2 * SHARDS);
+    let hour = Duration::from_secs(3600);
+    assert!(
+      limiter
+        .check("long", Mode::Fixed, 1.0, hour)
+        .unwrap()
+        .allowed
+    );
+    for i in 0..1000 {
+      limiter
+        .check(&format!("short{}", i), Mode::Fixed, 1.0, MINUTE)
+        .unwrap();
+      assert!(limiter.keys() <= 2 * SHARDS);
+    }
+    // Still tracked, so its one hit is used up.
+    assert!(
+      !limiter
+        .check("long", Mode::Fixed, 1.0, hour)
+        .unwrap()
+        .allowed
+    );
+  }
+
+  #[test]
+  fn a_key_checked_with_another_mode_starts_over() {
+    let limiter = RateLimiter::default();
+    assert!(
+      limiter
+        .check("key", Mode::Fixed, 1.0, MINUTE)
+        .unwrap()
+        .allowed
+    );
+    assert!(
+      !limiter
+        .check("key", Mode::Fixed, 1.0, MINUTE)
+        .unwrap()
+        .allowed
+    );
+    assert!(limiter.check_bucket("key", 1.0, MINUTE, 1.0).allowed);
+    assert_eq!(limiter.keys(), 1);
+  }
+
+  /// Times checks on new keys with every shard full, from 8 threads. Run
+  /// with `cargo test --release ratelimit -- --ignored --nocapture`.
+  #[test]
+  #[ignore = "benchmark"]
+  fn check_time_at_capacity() {
+    let limiter = RateLimiter::default();
+    for i in 0..MAX_KEYS {
+      limiter.check_bucket(&format!("warm{}", i), 1.0, MINUTE, 1.0);
+    }
+    let per_thread = 100_000;
+    let started = Instant::now();
+    std::thread::scope(|scope| {
+      for thread in 0..8 {
+        let limiter = &limiter;
+        scope.spawn(move || {
+          for i in 0..per_thread {
+            limiter.check_bucket(&format!("{}:{}", thread, i), 1.0, MINUTE, 1.0);
+          }
+        });
+      }
+    });
+    let elapsed = started.elapsed();
+    assert!(limiter.keys() <= MAX_KEYS);
+    println!(
+      "{} ns per check",
+      elapsed.as_nanos() / (8 * per_thread) as u128
+    );
+  }
+}
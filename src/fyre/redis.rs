@@ -0,0 +1,492 @@
+//! # `fyre.redis`
+//!
+//! A small, pooled Redis client speaking RESP2 over plain TCP.
+//!
+//! ```lua
+//! local redis, err = fyre.redis.connect()   -- CONFIG.redis.url
+//! redis:set("greeting", "hello", 60)        -- optional TTL in seconds
+//! local value, err = redis:get("greeting")
+//! local hits = redis:incr("hits")
+//! local reply = redis:command("HSET", "user:1", "name", "ada")
+//! ```
+//!
+//! Connections are pooled per URL and shared by every request. Each call
+//! checks a connection out for just that command, so a script can't leave
+//! one in a half-read state. Connect, read, and write are bounded by
+//! `CONFIG.redis.timeout_ms`, and any failure is returned as `nil, err`. A
+//! connection that errors is discarded rather than returned to the pool; if
+//! a pooled connection turns out to have been closed by the server, the
+//! command is retried once on a fresh connection.
+
+use mlua::prelude::*;
+use std::collections::HashMap;
+use std::io::{self, BufRead, BufReader, ErrorKind, Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use url::Url;
+
+use super::url::decode_component;
+use crate::locks;
+use crate::AppState;
+
+/// The default number of idle connections kept per URL.
+pub const DEFAULT_POOL_SIZE: usize = 8;
+/// The default connect, read, and write timeout.
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(2);
+/// The default Redis port.
+const DEFAULT_PORT: u16 = 6379;
+/// The largest bulk string accepted in a reply.
+const MAX_BULK_BYTES: i64 = 64 * 1024 * 1024;
+/// The longest status or length line accepted in a reply.
+const MAX_LINE_BYTES: u64 = 64 * 1024;
+/// How deeply nested a reply may be.
+const MAX_DEPTH: usize = 32;
+/// Commands that would change or take over a pooled connection.
+const BLOCKED_COMMANDS: &[&str] = &[
+  "SUBSCRIBE",
+  "PSUBSCRIBE",
+  "SSUBSCRIBE",
+  "MONITOR",
+  "SELECT",
+  "QUIT",
+  "RESET",
+];
+
+/// A Redis reply.
+enum Reply {
+  Nil,
+  Status(String),
+  Integer(i64),
+  Bulk(Vec<u8>),
+  Array(Vec<Reply>),
+  Error(String),
+}
+
+/// Where and how to connect, parsed from a `redis://` URL.
+struct Target {
+  addr: String,
+  username: Option<Vec<u8>>,
+  password: Option<Vec<u8>>,
+  db: Option<i64>,
+}
+
+impl Target {
+  fn parse(url: &str) -> Result<Self, String> {
+    let parsed = Url::parse(url).map_err(|e| format!("invalid redis url '{}': {}", url, e))?;
+    if parsed.scheme() != "redis" {
+      return Err(format!("only redis:// urls are supported: {}", url));
+    }
+    let host = parsed
+      .host_str()
+      .ok_or_else(|| format!("redis url has no host: {}", url))?;
+
+    let db = match parsed.path().trim_matches('/') {
+      "" => None,
+      db => Some(
+        db.parse()
+          .map_err(|_| format!("redis url database must be a number: {}", url))?,
+      ),
+    };
+
+    Ok(Target {
+      addr: format!("{}:{}", host, parsed.port().unwrap_or(DEFAULT_PORT)),
+      username: Some(parsed.username())
+        .filter(|user| !user.is_empty())
+        .map(|user| decode_component(user.as_bytes())),
+      password: parsed
+        .password()
+        .map(|password| decode_component(password.as_bytes())),
+      db,
+    })
+  }
+}
+
+/// One open connection.
+struct Connection {
+  reader: BufReader<TcpStream>,
+}
+
+impl Connection {
+  /// Connects, authenticates, and selects the database.
+  fn open(target: &Target, timeout: Duration) -> Result<Self, String> {
+    let fail = |e: io::Error| format!("redis {}: {}", target.addr, e);
+
+    let mut last_err = io::Error::new(ErrorKind::NotFound, "no addresses resolved");
+    let mut stream = None;
+    for addr in target.addr.to_socket_addrs().map_err(fail)? {
+      match TcpStream::connect_timeout(&addr, timeout) {
+        Ok(s) => {
+          stream = Some(s);
+          break;
+        }
+        Err(e) => last_err = e,
+      }
+    }
+    let stream = stream.ok_or_else(|| fail(last_err))?;
+    stream.set_read_timeout(Some(timeout)).map_err(fail)?;
+    stream.set_write_timeout(Some(timeout)).map_err(fail)?;
+    stream.set_nodelay(true).map_err(fail)?;
+
+    let mut conn = Connection {
+      reader: BufReader::new(stream),
+    };
+
+    if let Some(password) = &target.password {
+      let mut args = vec![b"AUTH".to_vec()];
+      args.extend(target.username.clone());
+      args.push(password.clone());
+      if let Reply::Error(e) = conn.call(&args).map_err(fail)? {
+        return Err(format!("redis {}: AUTH failed: {}", target.addr, e));
+      }
+    }
+    if let Some(db) = target.db {
+      let args = [b"SELECT".to_vec(), db.to_string().into_bytes()];
+      if let Reply::Error(e) = conn.call(&args).map_err(fail)? {
+        return Err(format!("redis {}: SELECT failed: {}", target.addr, e));
+      }
+    }
+
+    Ok(conn)
+  }
+
+  /// Sends one command and reads its reply.
+  fn call(&mut self, args: &[Vec<u8>]) -> io::Result<Reply> {
+    let mut out = format!("*{}\r\n", args.len()).into_bytes();
+    for arg in args {
+      out.extend_from_slice(format!("${}\r\n", arg.len()).as_bytes());
+      out.extend_from_slice(arg);
+      out.extend_from_slice(b"\r\n");
+    }
+    self.reader.get_mut().write_all(&out)?;
+    self.read_reply(0)
+  }
+
+  fn read_line(&mut self) -> io::Result<Vec<u8>> {
+    let mut line = Vec::new();
+    (&mut self.reader)
+      .take(MAX_LINE_BYTES)
+      .read_until(b'\n', &mut line)?;
+    if line.is_empty() {
+      return Err(ErrorKind::UnexpectedEof.into());
+    }
+    if !line.ends_with(b"\r\n") {
+      return Err(invalid_data("unterminated reply line"));
+    }
+    line.truncate(line.len() - 2);
+    Ok(line)
+  }
+
+  fn read_reply(&mut self, depth: usize) -> io::Result<Reply> {
+    let line = self.read_line()?;
+    let (&kind, rest) = line
+      .split_first()
+      .ok_or_else(|| invalid_data("empty reply line"))?;
+
+    match kind {
+      b'+' => Ok(Reply::Status(String::from_utf8_lossy(rest).into_owned())),
+      b'-' => Ok(Reply::Error(String::from_utf8_lossy(rest).into_owned())),
+      b':' => parse_integer(rest).map(Reply::Integer),
+      b'$' => {
+        let len = parse_integer(rest)?;
+        if len < 0 {
+          return Ok(Reply::Nil);
+        }
+        if len > MAX_BULK_BYTES {
+          return Err(invalid_data("bulk reply too large"));
+        }
+        let mut data = vec![0; len as usize + 2];
+        self.reader.read_exact(&mut data)?;
+        data.truncate(len as usize);
+        Ok(Reply::Bulk(data))
+      }
+      b'*' => {
+        let len = parse_integer(rest)?;
+        if len < 0 {
+          return Ok(Reply::Nil);
+        }
+        if depth >= MAX_DEPTH {
+          return Err(invalid_data("reply nested too deeply"));
+        }
+        let mut items = Vec::with_capacity(len.min(1024) as usize);
+        for _ in 0..len {
+          items.push(self.read_reply(depth + 1)?);
+        }
+        Ok(Reply::Array(items))
+      }
+      _ => Err(invalid_data("unknown reply type")),
+    }
+  }
+}
+
+fn invalid_data(message: &str) -> io::Error {
+  io::Error::new(ErrorKind::InvalidData, message)
+}
+
+fn parse_integer(data: &[u8]) -> io::Result<i64> {
+  std::str::from_utf8(data)
+    .ok()
+    .and_then(|s| s.parse().ok())
+    .ok_or_else(|| invalid_data("invalid integer in reply"))
+}
+
+/// The idle connections for one URL.
+struct Pool {
+  target: Target,
+  idle: Mutex<Vec<Connection>>,
+  max_idle: usize,
+  timeout: Duration,
+}
+
+impl Pool {
+  /// Runs one command, returning a server error reply as `Err`.
+  fn run(&self, args: &[Vec<u8>]) -> Result<Reply, String> {
+    let pooled = locks::lock(&self.idle, "redis pool").pop();
+    let reused = pooled.is_some();
+    let mut conn = match pooled {
+      Some(conn) => conn,
+      None => Connection::open(&self.target, self.timeout)?,
+    };
+
+    let reply = match conn.call(args) {
+      Ok(reply) => reply,
+      // The server closed an idle connection (e.g. it restarted): the
+      // command was never read, so it is safe to send again.
+      Err(e) if reused && is_closed(&e) => {
+        conn = Connection::open(&self.target, self.timeout)?;
+        conn
+          .call(args)
+          .map_err(|e| format!("redis {}: {}", self.target.addr, e))?
+      }
+      Err(e) => return Err(format!("redis {}: {}", self.target.addr, e)),
+    };
+
+    let mut idle = locks::lock(&self.idle, "redis pool");
+    if idle.len() < self.max_idle {
+      idle.push(conn);
+    }
+    drop(idle);
+
+    match reply {
+      Reply::Error(e) => Err(e),
+      reply => Ok(reply),
+    }
+  }
+}
+
+fn is_closed(e: &io::Error) -> bool {
+  matches!(
+    e.kind(),
+    ErrorKind::UnexpectedEof | ErrorKind::ConnectionReset | ErrorKind::BrokenPipe
+  )
+}
+
+/// The connection pools behind `fyre.redis`, keyed by URL.
+pub struct RedisPools {
+  default_url: Option<String>,
+  pool_size: usize,
+  timeout: Duration,
+  pools: Mutex<HashMap<String, Arc<Pool>>>,
+}
+
+impl RedisPools {
+  /// Creates an empty set of pools. `default_url` is used by
+  /// `fyre.redis.connect()` without arguments.
+  pub fn new(default_url: Option<String>, pool_size: usize, timeout: Duration) -> Self {
+    RedisPools {
+      default_url,
+      pool_size,
+      timeout,
+      pools: Mutex::new(HashMap::new()),
+    }
+  }
+
+  /// Returns the pool for `url` (or the default URL), creating it on first
+  /// use. No connection is opened until a command is sent.
+  fn pool(&self, url: Option<String>) -> Result<Arc<Pool>, String> {
+    let url = url
+      .or_else(|| self.default_url.clone())
+      .ok_or_else(|| "no redis url given and CONFIG.redis.url is not set".to_string())?;
+
+    let mut pools = locks::lock(&self.pools, "redis pools");
+    if let Some(pool) = pools.get(&url) {
+      return Ok(pool.clone());
+    }
+
+    let pool = Arc::new(Pool {
+      target: Target::parse(&url)?,
+      idle: Mutex::new(Vec::new()),
+      max_idle: self.pool_size,
+      timeout: self.timeout,
+    });
+    pools.insert(url, pool.clone());
+    Ok(pool)
+  }
+}
+
+/// The handle returned by `fyre.redis.connect`.
+struct Client {
+  pool: Arc<Pool>,
+}
+
+/// Converts a command argument to bytes.
+fn to_arg(value: LuaValue) -> LuaResult<Vec<u8>> {
+  Ok(match value {
+    LuaValue::String(s) => s.as_bytes().to_vec(),
+    LuaValue::Integer(i) => i.to_string().into_bytes(),
+    LuaValue::Number(n) => n.to_string().into_bytes(),
+    other => {
+      return Err(LuaError::external(format!(
+        "redis arguments must be strings or numbers, got {}",
+        other.type_name()
+      )))
+    }
+  })
+}
+
+/// Converts a reply to a Lua value. Nil elements inside arrays become
+/// `false` so the array has no holes.
+fn to_lua(lua: &Lua, reply: Reply) -> LuaResult<LuaValue> {
+  Ok(match reply {
+    Reply::Nil => LuaValue::Nil,
+    Reply::Status(s) | Reply::Error(s) => LuaValue::String(lua.create_string(s)?),
+    Reply::Integer(i) => LuaValue::Integer(i),
+    Reply::Bulk(data) => LuaValue::String(lua.create_string(data)?),
+    Reply::Array(items) => {
+      let table = lua.create_table_with_capacity(items.len(), 0)?;
+      for item in items {
+        match item {
+          Reply::Nil => table.push(false)?,
+          item => table.push(to_lua(lua, item)?)?,
+        }
+      }
+      LuaValue::Table(table)
+    }
+  })
+}
+
+impl LuaUserData for Client {
+  fn add_methods<M: LuaUserDataMethods<Self>>(methods: &mut M) {
+    // client:command(name, ...) -> reply | nil, err
+    methods.add_method(
+      "command",
+      |lua, client, (name, args): (String, LuaVariadic<LuaValue>)| {
+        if BLOCKED_COMMANDS.contains(&name.to_ascii_uppercase().as_str()) {
+          return Err(LuaError::external(format!(
+            "{} is not supported on pooled connections",
+            name
+          )));
+        }
+        let mut command = vec![name.into_bytes()];
+        for arg in args {
+          command.push(to_arg(arg)?);
+        }
+        Ok(match client.pool.run(&command) {
+          Ok(reply) => (to_lua(lua, reply)?, None),
+          Err(err) => (LuaValue::Nil, Some(err)),
+        })
+      },
+    );
+
+    // client:get(key) -> value | nil (missing) | nil, err
+    methods.add_method("get", |lua, client, key: LuaString| {
+      let command = [b"GET".to_vec(), key.as_bytes().to_vec()];
+      Ok(match client.pool.run(&command) {
+        Ok(reply) => (to_lua(lua, reply)?, None),
+        Err(err) => (LuaValue::Nil, Some(err)),
+      })
+    });
+
+    // client:set(key, value [, ttl_seconds]) -> true | nil, err
+    methods.add_method(
+      "set",
+      |_, client, (key, value, ttl): (LuaString, LuaValue, Option<u64>)| {
+        let mut command = vec![b"SET".to_vec(), key.as_bytes().to_vec(), to_arg(value)?];
+        if let Some(ttl) = ttl {
+          command.push(b"EX".to_vec());
+          command.push(ttl.to_string().into_bytes());
+        }
+        Ok(match client.pool.run(&command) {
+          Ok(_) => (Some(true), None),
+          Err(err) => (None, Some(err)),
+        })
+      },
+    );
+
+    // client:del(key, ...) -> number deleted | nil, err
+    methods.add_method("del", |_, client, keys: LuaVariadic<LuaString>| {
+      let mut command = vec![b"DEL".to_vec()];
+      command.extend(keys.iter().map(|key| key.as_bytes().to_vec()));
+      Ok(integer_result(client.pool.run(&command)))
+    });
+
+    // client:incr(key [, by]) -> new value | nil, err
+    methods.add_method("incr", |_, client, (key, by): (LuaString, Option<i64>)| {
+      let command = [
+        b"INCRBY".to_vec(),
+        key.as_bytes().to_vec(),
+        by.unwrap_or(1).to_string().into_bytes(),
+      ];
+      Ok(integer_result(client.pool.run(&command)))
+    });
+
+    // client:expire(key, seconds) -> true if the key exists | nil, err
+    methods.add_method("expire", |_, client, (key, seconds): (LuaString, i64)| {
+      let command = [
+        b"EXPIRE".to_vec(),
+        key.as_bytes().to_vec(),
+        seconds.to_string().into_bytes(),
+      ];
+      Ok(match integer_result(client.pool.run(&command)) {
+        (Some(n), err) => (Some(n == 1), err),
+        (None, err) => (None, err),
+      })
+    });
+
+    // client:publish(channel, message) -> receivers | nil, err
+    methods.add_method(
+      "publish",
+      |_, client, (channel, message): (LuaString, LuaString)| {
+        let command = [
+          b"PUBLISH".to_vec(),
+          channel.as_bytes().to_vec(),
+          message.as_bytes().to_vec(),
+        ];
+        Ok(integer_result(client.pool.run(&command)))
+      },
+    );
+  }
+}
+
+/// Converts an integer reply into the `value` / `nil, err` convention.
+fn integer_result(result: Result<Reply, String>) -> (Option<i64>, Option<String>) {
+  match result {
+    Ok(Reply::Integer(n)) => (Some(n), None),
+    Ok(_) => (None, Some("unexpected reply from redis".to_string())),
+    Err(err) => (None, Some(err)),
+  }
+}
+
+/// Builds the `fyre.redis` module table.
+///
+/// # Errors
+///
+/// This function will return a `LuaError` if the module table or its functions
+/// cannot be created.
+pub fn module(lua: &Lua, state: &Arc<AppState>) -> LuaResult<LuaTable> {
+  let module = lua.create_table()?;
+
+  let st = state.clone();
+  module.set(
+    "connect",
+    lua.create_function(move |lua, url: Option<String>| match st.redis.pool(url) {
+      Ok(pool) => Ok((
+        LuaValue::UserData(lua.create_userdata(Client { pool })?),
+        None,
+      )),
+      Err(err) => Ok((LuaValue::Nil, Some(err))),
+    })?,
+  )?;
+
+  Ok(module)
+}
@@ -0,0 +1,108 @@
+//! # `fyre.secrets`
+//!
+//! Named secrets from the environment, a secrets directory, or an encrypted
+//! file (see `crate::secrets`), for `config.lua` and handler scripts alike.
+//!
+//! ```lua
+//! local key = fyre.secrets.get("stripe_key")
+//! local token = fyre.secrets.get("api_token", "dev-token")
+//! ```
+//!
+//! A route declared with `router.add(path, script, { secrets = { "stripe_key"
+//! } })` may only read the secrets it lists; any other name reads as `nil`
+//! (or the default) and is logged. Routes without the option, and
+//! `config.lua`, queue workers, and tasks, may read any.
+
+use mlua::prelude::*;
+use std::sync::Arc;
+
+use crate::secrets::Secrets;
+
+/// The secrets the request being handled may read, set on the Lua state by
+/// `handle_request` before each request. `None` allows every name.
+pub struct Scope(pub Option<Arc<[String]>>);
+
+/// Builds the `fyre.secrets` module table.
+///
+/// # Errors
+///
+/// This function will return a `LuaError` if the module table or its functions
+/// cannot be created.
+pub fn module(lua: &Lua, secrets: &Arc<Secrets>) -> LuaResult<LuaTable> {
+  let module = lua.create_table()?;
+
+  // fyre.secrets.get(name [, default]) -> value (or default, or nil)
+  let secrets = secrets.clone();
+  module.set(
+    "get",
+    lua.create_function(move |lua, (name, default): (String, Option<String>)| {
+      let allowed = match lua.app_data_ref::<Scope>() {
+        Some(scope) => scope.0.as_ref().is_none_or(|names| names.contains(&name)),
+        None => true,
+      };
+      if !allowed {
+        warn!("fyre.secrets denied {} (not in the route's secrets list)", name);
+        return Ok(default);
+      }
+      let value = secrets.get(&name).map_err(LuaError::external)?;
+      Ok(value.map(|value| value.to_string()).or(default))
+    })?,
+  )?;
+
+  Ok(module)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::testing::Fixture;
+
+  #[test]
+  fn routes_only_read_the_secrets_they_list() {
+    let fixture = Fixture::new("", &[]);
+    std::fs::write(fixture.path().join("stripe_key"), "sk_test").unwrap();
+    std::fs::write(fixture.path().join("db_password"), "hunter2").unwrap();
+    let secrets = Arc::new(Secrets::in_dir(fixture.path()));
+    let lua = Lua::new();
+    lua
+      .globals()
+      .set("secrets", module(&lua, &secrets).unwrap())
+      .unwrap();
+    let read = |lua: &Lua| -> (Option<String>, Option<String>, String) {
+      lua
+        .load(
+          r#"return secrets.get("stripe_key"), secrets.get("db_password"),
+            secrets.get("db_password", "default")"#,
+        )
+        .eval()
+        .unwrap()
+    };
+
+    // Outside a request, and on routes without the option, any may be read.
+    let all = (
+      Some("sk_test".to_string()),
+      Some("hunter2".to_string()),
+      "hunter2".to_string(),
+    );
+    assert_eq!(read(&lua), all);
+    lua.set_app_data(Scope(None));
+    assert_eq!(read(&lua), all);
+
+    lua.set_app_data(Scope(Some(Arc::from(["stripe_key".to_string()]))));
+    assert_eq!(
+      read(&lua),
+      (Some("sk_test".to_string()), None, "default".to_string())
+    );
+
+    lua.set_app_data(Scope(None));
+    let error = lua
+      .load(r#"secrets.get("../stripe_key")"#)
+      .exec()
+      .unwrap_err();
+    assert!(
+      error.to_string().contains("is not a secret name"),
+      "{}",
+      error
+    );
+  }
+}
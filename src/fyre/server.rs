@@ -0,0 +1,31 @@
+//! # `fyre.server`
+//!
+//! Facts about the running server, for scripts that need to know where it
+//! listens (e.g. to build their own URLs, or in tests that bind port 0):
+//!
+//! ```lua
+//! fyre.server.addr    -- "127.0.0.1:43817", the first address listened on
+//! fyre.server.addrs   -- { "127.0.0.1:43817", "unix:/run/fyre.sock" }
+//! ```
+//!
+//! The addresses are the ones actually bound: the port the OS chose for
+//! port 0, and the IP a host name resolved to. Before the server listens
+//! (for a request made with `FyreServer::handle`), there are none.
+
+use mlua::prelude::*;
+use std::sync::Arc;
+
+use crate::AppState;
+
+/// Builds the `fyre.server` module table.
+///
+/// # Errors
+///
+/// This function will return a `LuaError` if the table cannot be created.
+pub fn module(lua: &Lua, state: &Arc<AppState>) -> LuaResult<LuaTable> {
+  let addrs = state.addrs.load();
+  let module = lua.create_table()?;
+  module.set("addr", addrs.first().cloned())?;
+  module.set("addrs", addrs.to_vec())?;
+  Ok(module)
+}
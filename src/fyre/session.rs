@@ -0,0 +1,449 @@
+//! # `fyre.session`
+//!
+//! Cookie-backed sessions, enabled by setting `CONFIG.session.secret` in
+//! `config.lua`.
+//!
+//! ```lua
+//! local user = fyre.session.get("user")
+//! fyre.session.set("user", { id = 42, name = "ada" })
+//! fyre.session.save()      -- emits the Set-Cookie header
+//! fyre.session.clear()     -- e.g. on logout, followed by save()
+//! ```
+//!
+//! In the default `cookie` store the whole session is serialized (see
+//! `SharedValue::encode`), HMAC-SHA256 signed with the secret, and kept in
+//! the cookie, which limits it to `MAX_COOKIE_BYTES`. With
+//! `session.store = "kv"` the cookie only carries a signed random id and the
+//! data lives in `fyre.kv`. Either way a tampered, malformed, or expired
+//! cookie simply yields an empty session.
+
+use mlua::prelude::*;
+use std::cell::RefCell;
+use std::rc::Rc;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use super::cookie::{find_cookie, SetCookie};
+use super::crypto::{constant_time_eq, hmac};
+use super::encoding::{base64_decode_urlsafe, base64_encode_urlsafe, hex_encode};
+use super::value::SharedValue;
+use crate::AppState;
+
+/// The default session cookie name.
+pub const DEFAULT_COOKIE_NAME: &str = "fyre_session";
+/// The default session lifetime.
+pub const DEFAULT_TTL: Duration = Duration::from_secs(24 * 60 * 60);
+/// The largest cookie value the `cookie` store will emit; browsers reject
+/// cookies much beyond 4 KB.
+const MAX_COOKIE_BYTES: usize = 4000;
+/// The `fyre.kv` key prefix for server-side sessions.
+const KV_PREFIX: &str = "session:";
+
+/// Where session data is kept.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SessionStore {
+  /// Signed data in the cookie itself.
+  Cookie,
+  /// A signed id in the cookie, data in `fyre.kv`.
+  Kv,
+}
+
+/// Session settings from `config.lua`.
+pub struct SessionConfig {
+  pub secret: Vec<u8>,
+  pub cookie_name: String,
+  pub ttl: Duration,
+  pub store: SessionStore,
+  /// Whether the cookie is marked `Secure`.
+  pub secure: bool,
+}
+
+impl std::fmt::Debug for SessionConfig {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    f.debug_struct("SessionConfig")
+      .field("secret", &"<redacted>")
+      .field("cookie_name", &self.cookie_name)
+      .field("ttl", &self.ttl)
+      .field("store", &self.store)
+      .field("secure", &self.secure)
+      .finish()
+  }
+}
+
+impl SessionConfig {
+  /// Signs `payload` for the session cookie. The cookie name is mixed in so
+  /// a value signed for one cookie can't be replayed as another.
+  fn sign(&self, payload: &[u8]) -> Vec<u8> {
+    let mut message = self.cookie_name.as_bytes().to_vec();
+    message.push(b'=');
+    message.extend_from_slice(payload);
+    hmac("sha256", &self.secret, &message).expect("sha256 is supported")
+  }
+
+  /// Produces a cookie value of the form `base64(payload).base64(mac)`.
+  fn seal(&self, payload: &[u8]) -> String {
+    format!(
+      "{}.{}",
+      base64_encode_urlsafe(payload),
+      base64_encode_urlsafe(&self.sign(payload))
+    )
+  }
+
+  /// Verifies a cookie produced by `seal`, returning its payload.
+  fn open(&self, cookie: &str) -> Option<Vec<u8>> {
+    let (payload, mac) = cookie.split_once('.')?;
+    let payload = base64_decode_urlsafe(payload.as_bytes()).ok()?;
+    let mac = base64_decode_urlsafe(mac.as_bytes()).ok()?;
+    constant_time_eq(&self.sign(&payload), &mac).then_some(payload)
+  }
+}
+
+fn unix_now() -> u64 {
+  SystemTime::now()
+    .duration_since(UNIX_EPOCH)
+    .map(|d| d.as_secs())
+    .unwrap_or(0)
+}
+
+/// The per-request session: the data table (loaded on first use) and, for
+/// the `kv` store, the session id.
+struct Session {
+  state: Arc<AppState>,
+  cookie: Option<String>,
+  data: RefCell<Option<LuaTable>>,
+  id: RefCell<Option<String>>,
+}
+
+impl Session {
+  fn config(&self) -> LuaResult<&SessionConfig> {
+    self
+      .state
+      .session
+      .as_ref()
+      .ok_or_else(|| {
+        LuaError::external("fyre.session requires CONFIG.session.secret in config.lua")
+      })
+  }
+
+  /// Returns the session data table, loading it from the cookie on first
+  /// use.
+  fn data(&self, lua: &Lua) -> LuaResult<LuaTable> {
+    if let Some(data) = self.data.borrow().as_ref() {
+      return Ok(data.clone());
+    }
+
+    let config = self.config()?;
+    let loaded = match config.store {
+      SessionStore::Cookie => self.load_from_cookie(config),
+      SessionStore::Kv => self.load_from_kv(config)?,
+    };
+    let table = match loaded.map(|value| value.to_lua_value(lua)).transpose()? {
+      Some(LuaValue::Table(table)) => table,
+      _ => lua.create_table()?,
+    };
+
+    *self.data.borrow_mut() = Some(table.clone());
+    Ok(table)
+  }
+
+  fn load_from_cookie(&self, config: &SessionConfig) -> Option<SharedValue> {
+    let payload = config.open(self.cookie.as_deref()?)?;
+    if payload.len() < 8 {
+      return None;
+    }
+    let (expires, encoded) = payload.split_at(8);
+    if u64::from_be_bytes(expires.try_into().ok()?) <= unix_now() {
+      return None;
+    }
+    SharedValue::decode(encoded)
+  }
+
+  fn load_from_kv(&self, config: &SessionConfig) -> LuaResult<Option<SharedValue>> {
+    let Some(id) = self
+      .cookie
+      .as_deref()
+      .and_then(|cookie| config.open(cookie))
+      .and_then(|id| String::from_utf8(id).ok())
+    else {
+      return Ok(None);
+    };
+
+    let value = self.state.kv.get(&format!("{}{}", KV_PREFIX, id))?;
+    *self.id.borrow_mut() = Some(id);
+    Ok(value)
+  }
+
+  /// Empties the session and forgets its id, so the next save starts a new
+  /// session rather than reusing one an attacker may have planted.
+  fn clear(&self, lua: &Lua) -> LuaResult<()> {
+    let config = self.config()?;
+    if config.store == SessionStore::Kv {
+      if let Some(id) = self.id.borrow_mut().take() {
+        self.state.kv.delete(&format!("{}{}", KV_PREFIX, id))?;
+      }
+    }
+    *self.data.borrow_mut() = Some(lua.create_table()?);
+    Ok(())
+  }
+
+  /// Persists the session and returns the `Set-Cookie` value to send.
+  fn save(&self, lua: &Lua) -> LuaResult<Result<String, String>> {
+    let config = self.config()?;
+    let data = self.data(lua)?;
+    let ttl = config.ttl.as_secs();
+
+    if data.is_empty() && config.store == SessionStore::Cookie {
+      return Ok(Ok(
+        SetCookie::removal(&config.cookie_name)
+          .secure(config.secure)
+          .to_header_value(),
+      ));
+    }
+
+    let value = SharedValue::from_lua_value(LuaValue::Table(data))?;
+    let cookie = match config.store {
+      SessionStore::Cookie => {
+        let mut payload = (unix_now() + ttl).to_be_bytes().to_vec();
+        value.encode(&mut payload);
+        let sealed = config.seal(&payload);
+        if sealed.len() > MAX_COOKIE_BYTES {
+          return Ok(Err(format!(
+            "session is too large for a cookie ({} bytes, limit {})",
+            sealed.len(),
+            MAX_COOKIE_BYTES
+          )));
+        }
+        sealed
+      }
+      SessionStore::Kv => {
+        let id = self
+          .id
+          .borrow_mut()
+          .get_or_insert_with(|| hex_encode(&rand::random::<[u8; 16]>()))
+          .clone();
+        self
+          .state
+          .kv
+          .set(format!("{}{}", KV_PREFIX, id), value, Some(config.ttl))?;
+        config.seal(id.as_bytes())
+      }
+    };
+
+    Ok(Ok(
+      SetCookie::new(&config.cookie_name, cookie)
+        .max_age(ttl as i64)
+        .secure(config.secure)
+        .to_header_value(),
+    ))
+  }
+}
+
+/// Builds the `fyre.session` module table for one request.
+///
+/// # Arguments
+///
+/// * `lua` - The request's Lua state.
+/// * `state` - The server-wide state holding the session settings.
+/// * `cookie_header` - The request's `Cookie` header, if any.
+/// * `response` - The request's `response` table, which `save()` adds the
+///   `Set-Cookie` header to.
+///
+/// # Errors
+///
+/// This function will return a `LuaError` if the module table or its functions
+/// cannot be created.
+pub fn module(
+  lua: &Lua,
+  state: &Arc<AppState>,
+  cookie_header: Option<&str>,
+  response: LuaTable,
+) -> LuaResult<LuaTable> {
+  let session = Rc::new(Session {
+    state: state.clone(),
+    cookie: state
+      .session
+      .as_ref()
+      .and_then(|config| find_cookie(cookie_header?, &config.cookie_name)),
+    data: RefCell::new(None),
+    id: RefCell::new(None),
+  });
+  let module = lua.create_table()?;
+
+  // fyre.session.get() -> table, or fyre.session.get(key) -> value
+  let s = session.clone();
+  module.set(
+    "get",
+    lua.create_function(move |lua, key: Option<LuaValue>| {
+      let data = s.data(lua)?;
+      match key {
+        Some(key) => data.get::<LuaValue>(key),
+        None => Ok(LuaValue::Table(data)),
+      }
+    })?,
+  )?;
+
+  let s = session.clone();
+  module.set(
+    "set",
+    lua.create_function(move |lua, (key, value): (LuaValue, LuaValue)| {
+      s.data(lua)?.set(key, value)
+    })?,
+  )?;
+
+  let s = session.clone();
+  module.set("clear", lua.create_function(move |lua, ()| s.clear(lua))?)?;
+
+  let s = session;
+  module.set(
+    "save",
+    lua.create_function(move |lua, ()| match s.save(lua)? {
+      Ok(cookie) => {
+        super::append_header(lua, &response, "Set-Cookie", &cookie)?;
+        Ok((Some(true), None))
+      }
+      Err(err) => Ok((None, Some(err))),
+    })?,
+  )?;
+
+  Ok(module)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::testing::{self, Fixture};
+
+  fn session_config() -> SessionConfig {
+    SessionConfig {
+      secret: b"session-secret".to_vec(),
+      cookie_name: DEFAULT_COOKIE_NAME.to_string(),
+      ttl: DEFAULT_TTL,
+      store: SessionStore::Cookie,
+      secure: false,
+    }
+  }
+
+  /// The `name=value` part of a response's `Set-Cookie` header.
+  fn set_cookie(response: &str) -> &str {
+    let (head, _) = response.split_once("\r\n\r\n").unwrap();
+    let line = head
+      .lines()
+      .find(|line| line.to_ascii_lowercase().starts_with("set-cookie: "))
+      .unwrap_or_else(|| panic!("no Set-Cookie: {}", response));
+    line["set-cookie: ".len()..].split(';').next().unwrap()
+  }
+
+  fn get_with_cookie(addr: &str, path: &str, cookie: &str) -> String {
+    testing::send(
+      addr,
+      &format!(
+        "GET {} HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\nCookie: {}\r\n\r\n",
+        path, cookie
+      ),
+    )
+  }
+
+  #[test]
+  fn sealed_values_only_open_unchanged() {
+    let config = session_config();
+    let sealed = config.seal(b"payload");
+    assert_eq!(config.open(&sealed).as_deref(), Some(&b"payload"[..]));
+
+    let (payload, mac) = sealed.split_once('.').unwrap();
+    let other_payload = base64_encode_urlsafe(b"pay1oad");
+    assert_eq!(config.open(&format!("{}.{}", other_payload, mac)), None);
+    let other_mac = base64_encode_urlsafe(&config.sign(b"pay1oad"));
+    assert_eq!(config.open(&format!("{}.{}", payload, other_mac)), None);
+    assert_eq!(config.open(payload), None);
+    assert_eq!(config.open(""), None);
+
+    let mut elsewhere = session_config();
+    elsewhere.secret = b"another-secret".to_vec();
+    assert_eq!(elsewhere.open(&sealed), None);
+    // A value signed for another cookie can't be replayed as this one.
+    let mut csrf = session_config();
+    csrf.cookie_name = "fyre_csrf".to_string();
+    assert_eq!(csrf.open(&sealed), None);
+  }
+
+  #[test]
+  fn tampered_and_expired_cookies_give_an_empty_session() {
+    let fixture = Fixture::new(
+      r#"
+        CONFIG = { session = { secret = "session-secret" } }
+        router.add("/login", "login.lua")
+        router.add("/whoami", "whoami.lua")
+        router.add("/logout", "logout.lua")
+      "#,
+      &[
+        (
+          "login.lua",
+          r#"return { handler = function(request, response)
+            fyre.session.set("user", "ada")
+            fyre.session.save()
+          end }"#,
+        ),
+        (
+          "whoami.lua",
+          r#"return { handler = function(request, response)
+            response.body = fyre.session.get("user") or "nobody"
+          end }"#,
+        ),
+        (
+          "logout.lua",
+          r#"return { handler = function(request, response)
+            fyre.session.clear()
+            fyre.session.save()
+          end }"#,
+        ),
+      ],
+    );
+    let server = fixture
+      .builder()
+      .addr("127.0.0.1:0")
+      .workers(1)
+      .load()
+      .unwrap();
+    let addr = server.serve().unwrap().remove(0);
+    let whoami = |cookie: &str| {
+      let response = get_with_cookie(&addr, "/whoami", cookie);
+      response.split_once("\r\n\r\n").unwrap().1.to_string()
+    };
+
+    let login = testing::get(&addr, "/login");
+    let cookie = set_cookie(&login).to_string();
+    assert!(cookie.starts_with("fyre_session="), "{}", cookie);
+    assert_eq!(whoami(&cookie), "ada");
+    assert_eq!(whoami(""), "nobody");
+
+    // The user changed without signing again.
+    let config = session_config();
+    let value = &cookie["fyre_session=".len()..];
+    let (payload, mac) = value.split_once('.').unwrap();
+    let payload = base64_decode_urlsafe(payload.as_bytes()).unwrap();
+    let at = payload.windows(3).position(|w| w == b"ada").unwrap();
+    let mut forged = payload.clone();
+    forged[at..at + 3].copy_from_slice(b"eve");
+    let forged_cookie = format!("fyre_session={}.{}", base64_encode_urlsafe(&forged), mac);
+    assert_eq!(whoami(&forged_cookie), "nobody");
+    // Signed with the secret, the same change is taken, so it was only the
+    // signature that refused it.
+    assert_eq!(
+      whoami(&format!("fyre_session={}", config.seal(&forged))),
+      "eve"
+    );
+
+    // Signed, but past its expiry.
+    let mut expired = payload;
+    expired[..8].copy_from_slice(&(unix_now() - 1).to_be_bytes());
+    assert_eq!(
+      whoami(&format!("fyre_session={}", config.seal(&expired))),
+      "nobody"
+    );
+
+    // Logging out removes the cookie.
+    let logout = get_with_cookie(&addr, "/logout", &cookie);
+    assert_eq!(set_cookie(&logout), "fyre_session=");
+    server.shutdown();
+  }
+}
@@ -0,0 +1,221 @@
+//! # `fyre.sqlite`
+//!
+//! SQLite bindings for handler scripts, backed by `rusqlite`.
+//!
+//! ```lua
+//! local db = fyre.sqlite.open("app.db")
+//! db:exec("CREATE TABLE IF NOT EXISTS users (id INTEGER PRIMARY KEY, name TEXT)")
+//! db:exec("INSERT INTO users (name) VALUES (?)", request.body)
+//! local rows, err = db:query("SELECT id, name FROM users WHERE id > ?", 10)
+//! for _, row in ipairs(rows) do print(row.id, row.name) end
+//! ```
+//!
+//! Database paths are relative to the `CONFIG.sqlite.dir` directory from
+//! `config.lua` (default `data`). Each database is opened once and the
+//! connection is shared by every request, with a busy timeout so concurrent
+//! writers wait for the lock instead of failing immediately.
+
+use mlua::prelude::*;
+use rusqlite::types::Value as SqlValue;
+use rusqlite::Connection;
+use std::collections::HashMap;
+use std::path::{Component, Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use crate::locks;
+use crate::AppState;
+
+/// The default directory database files are restricted to.
+pub const DEFAULT_DATA_DIR: &str = "data";
+/// How long a statement waits on a locked database before failing.
+const BUSY_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The open database connections, keyed by path.
+pub struct SqlitePool {
+  dir: PathBuf,
+  connections: Mutex<HashMap<PathBuf, Arc<Mutex<Connection>>>>,
+}
+
+impl SqlitePool {
+  /// Creates an empty pool restricted to databases under `dir`.
+  pub fn new(dir: impl Into<PathBuf>) -> Self {
+    SqlitePool {
+      dir: dir.into(),
+      connections: Mutex::new(HashMap::new()),
+    }
+  }
+
+  /// Returns the shared connection for `name`, opening it on first use.
+  ///
+  /// # Errors
+  ///
+  /// Returns an error message if `name` is not a plain relative path inside
+  /// the data directory, or if the database cannot be opened.
+  fn open(&self, name: &str) -> Result<Arc<Mutex<Connection>>, String> {
+    let relative = Path::new(name);
+    if name.is_empty()
+      || !relative
+        .components()
+        .all(|component| matches!(component, Component::Normal(_)))
+    {
+      return Err(format!(
+        "database path must be relative to {} without '..': {}",
+        self.dir.display(),
+        name
+      ));
+    }
+
+    let path = self.dir.join(relative);
+    let mut connections = locks::lock(&self.connections, "sqlite pool");
+
+    if let Some(conn) = connections.get(&path) {
+      return Ok(conn.clone());
+    }
+
+    let conn = Connection::open(&path)
+      .and_then(|conn| conn.busy_timeout(BUSY_TIMEOUT).map(|_| conn))
+      .map_err(|e| format!("failed to open {}: {}", path.display(), e))?;
+
+    info!("Opened SQLite database: {}", path.display());
+    let conn = Arc::new(Mutex::new(conn));
+    connections.insert(path, conn.clone());
+    Ok(conn)
+  }
+}
+
+/// The handle returned by `fyre.sqlite.open`.
+struct Database {
+  conn: Arc<Mutex<Connection>>,
+}
+
+impl Database {
+  /// Runs `f` with the locked connection, turning any failure into an error
+  /// message for a `nil, err` return.
+  fn with_conn<T>(
+    &self,
+    f: impl FnOnce(&Connection) -> rusqlite::Result<T>,
+  ) -> Result<T, String> {
+    let conn = locks::lock(&self.conn, "sqlite connection");
+    f(&conn).map_err(|e| e.to_string())
+  }
+}
+
+impl LuaUserData for Database {
+  fn add_methods<M: LuaUserDataMethods<Self>>(methods: &mut M) {
+    // db:exec(sql, ...) -> changes | nil, err
+    //
+    // Without parameters the SQL may contain several statements.
+    methods.add_method("exec", |_, db, (sql, params): (String, LuaVariadic<LuaValue>)| {
+      let params = to_sql_params(params)?;
+      let result = db.with_conn(|conn| {
+        if params.is_empty() {
+          conn.execute_batch(&sql)?;
+          Ok(conn.changes() as i64)
+        } else {
+          Ok(conn.execute(&sql, rusqlite::params_from_iter(params))? as i64)
+        }
+      });
+
+      Ok(match result {
+        Ok(changes) => (Some(changes), None),
+        Err(err) => (None, Some(err)),
+      })
+    });
+
+    // db:query(sql, ...) -> rows | nil, err
+    methods.add_method("query", |lua, db, (sql, params): (String, LuaVariadic<LuaValue>)| {
+      let params = to_sql_params(params)?;
+      let result = db.with_conn(|conn| {
+        let mut stmt = conn.prepare(&sql)?;
+        let names: Vec<String> = stmt.column_names().into_iter().map(String::from).collect();
+        let mut rows = stmt.query(rusqlite::params_from_iter(params))?;
+
+        let mut out = Vec::new();
+        while let Some(row) = rows.next()? {
+          let mut values = Vec::with_capacity(names.len());
+          for i in 0..names.len() {
+            values.push(SqlValue::from(row.get_ref(i)?));
+          }
+          out.push(values);
+        }
+        Ok((names, out))
+      });
+
+      let (names, rows) = match result {
+        Ok(result) => result,
+        Err(err) => return Ok((LuaValue::Nil, Some(err))),
+      };
+
+      let table = lua.create_table_with_capacity(rows.len(), 0)?;
+      for values in rows {
+        let row = lua.create_table_with_capacity(0, names.len())?;
+        for (name, value) in names.iter().zip(values) {
+          row.set(name.as_str(), from_sql_value(lua, value)?)?;
+        }
+        table.push(row)?;
+      }
+      Ok((LuaValue::Table(table), None))
+    });
+
+    // db:last_insert_id() -> integer
+    methods.add_method("last_insert_id", |_, db, ()| {
+      db.with_conn(|conn| Ok(conn.last_insert_rowid()))
+        .map_err(LuaError::external)
+    });
+  }
+}
+
+/// Converts script arguments into SQL parameters.
+fn to_sql_params(params: LuaVariadic<LuaValue>) -> LuaResult<Vec<SqlValue>> {
+  params
+    .into_iter()
+    .map(|value| match value {
+      LuaValue::Nil => Ok(SqlValue::Null),
+      LuaValue::Boolean(b) => Ok(SqlValue::Integer(b as i64)),
+      LuaValue::Integer(i) => Ok(SqlValue::Integer(i)),
+      LuaValue::Number(n) => Ok(SqlValue::Real(n)),
+      LuaValue::String(s) => Ok(match s.to_str() {
+        Ok(text) => SqlValue::Text(text.to_string()),
+        Err(_) => SqlValue::Blob(s.as_bytes().to_vec()),
+      }),
+      other => Err(LuaError::external(format!(
+        "unsupported SQL parameter type: {}",
+        other.type_name()
+      ))),
+    })
+    .collect()
+}
+
+/// Converts a column value into a Lua value. Text and blobs both become Lua
+/// strings; NULL becomes `nil`.
+fn from_sql_value(lua: &Lua, value: SqlValue) -> LuaResult<LuaValue> {
+  Ok(match value {
+    SqlValue::Null => LuaValue::Nil,
+    SqlValue::Integer(i) => LuaValue::Integer(i),
+    SqlValue::Real(n) => LuaValue::Number(n),
+    SqlValue::Text(s) => LuaValue::String(lua.create_string(s)?),
+    SqlValue::Blob(b) => LuaValue::String(lua.create_string(b)?),
+  })
+}
+
+/// Builds the `fyre.sqlite` module table.
+///
+/// # Errors
+///
+/// This function will return a `LuaError` if the module table or its functions
+/// cannot be created.
+pub fn module(lua: &Lua, state: &Arc<AppState>) -> LuaResult<LuaTable> {
+  let module = lua.create_table()?;
+
+  let st = state.clone();
+  module.set(
+    "open",
+    lua.create_function(move |lua, name: String| match st.sqlite.open(&name) {
+      Ok(conn) => Ok((LuaValue::UserData(lua.create_userdata(Database { conn })?), None)),
+      Err(err) => Ok((LuaValue::Nil, Some(err))),
+    })?,
+  )?;
+
+  Ok(module)
+}
@@ -0,0 +1,232 @@
+//! # `fyre.time`
+//!
+//! Timestamps, formatting, and parsing on top of `chrono`. Timestamps are
+//! Unix seconds (with a fractional part where relevant) and everything is UTC
+//! unless a script asks for local time.
+//!
+//! ```lua
+//! local ts = fyre.time.now()                           -- 1700000000.123
+//! fyre.time.format(ts)                                 -- "2023-11-14T22:13:20Z"
+//! fyre.time.format(ts, "%Y-%m-%d", "local")            -- in the server's timezone
+//! response.headers["Last-Modified"] = fyre.time.http_date(ts)
+//! local since = fyre.time.parse_http_date(request.headers["If-Modified-Since"])
+//! local started = fyre.time.monotonic_ms()
+//! ```
+
+use chrono::{DateTime, Local, NaiveDateTime, Utc};
+use mlua::prelude::*;
+use std::fmt::Write;
+use std::sync::OnceLock;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+/// The format used by `fyre.time.format` when none is given.
+const DEFAULT_FORMAT: &str = "%Y-%m-%dT%H:%M:%SZ";
+/// The preferred HTTP-date format (IMF-fixdate), used for output.
+const IMF_FIXDATE: &str = "%a, %d %b %Y %H:%M:%S GMT";
+/// The obsolete RFC 850 HTTP-date format.
+const RFC_850: &str = "%A, %d-%b-%y %H:%M:%S GMT";
+/// The obsolete ANSI C `asctime()` HTTP-date format, after collapsing runs of
+/// spaces (so the space-padded day parses with `%d`).
+const ASCTIME: &str = "%a %b %d %H:%M:%S %Y";
+
+/// The reference point for `fyre.time.monotonic_ms`.
+fn monotonic_origin() -> Instant {
+  static ORIGIN: OnceLock<Instant> = OnceLock::new();
+  *ORIGIN.get_or_init(Instant::now)
+}
+
+/// Converts a Unix timestamp in seconds to a UTC datetime.
+fn to_datetime(ts: f64) -> Option<DateTime<Utc>> {
+  if !ts.is_finite() {
+    return None;
+  }
+  let secs = ts.floor();
+  let nanos = ((ts - secs) * 1e9) as u32;
+  DateTime::from_timestamp(secs as i64, nanos)
+}
+
+/// Formats a UTC timestamp as an IMF-fixdate HTTP date.
+pub fn http_date(ts: i64) -> Option<String> {
+  DateTime::from_timestamp(ts, 0).map(|dt| dt.format(IMF_FIXDATE).to_string())
+}
+
+/// Parses an HTTP date in any of the three formats RFC 9110 requires
+/// recipients to accept, returning a Unix timestamp.
+pub fn parse_http_date(value: &str) -> Option<i64> {
+  let value = value.trim();
+  if let Ok(dt) = NaiveDateTime::parse_from_str(value, IMF_FIXDATE) {
+    return Some(dt.and_utc().timestamp());
+  }
+  if let Ok(dt) = NaiveDateTime::parse_from_str(value, RFC_850) {
+    return Some(dt.and_utc().timestamp());
+  }
+
+  let collapsed = value.split_whitespace().collect::<Vec<_>>().join(" ");
+  NaiveDateTime::parse_from_str(&collapsed, ASCTIME)
+    .ok()
+    .map(|dt| dt.and_utc().timestamp())
+}
+
+/// Builds the `fyre.time` module table.
+///
+/// # Errors
+///
+/// This function will return a `LuaError` if the module table or its functions
+/// cannot be created.
+pub fn module(lua: &Lua) -> LuaResult<LuaTable> {
+  let module = lua.create_table()?;
+
+  module.set(
+    "now",
+    lua.create_function(|_, ()| {
+      let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_err(LuaError::external)?;
+      Ok(now.as_secs_f64())
+    })?,
+  )?;
+
+  // fyre.time.format(ts [, fmt [, "utc" | "local"]]) -> string | nil, err
+  module.set(
+    "format",
+    lua.create_function(|_, (ts, fmt, tz): (f64, Option<String>, Option<String>)| {
+      let Some(dt) = to_datetime(ts) else {
+        return Ok((None, Some(format!("timestamp out of range: {}", ts))));
+      };
+      let fmt = fmt.as_deref().unwrap_or(DEFAULT_FORMAT);
+
+      // chrono reports bad format strings through fmt::Error rather than a
+      // Result, so write into a String to catch it instead of panicking.
+      let mut out = String::new();
+      let written = match tz.as_deref().unwrap_or("utc") {
+        "utc" => write!(out, "{}", dt.format(fmt)),
+        "local" => write!(out, "{}", dt.with_timezone(&Local).format(fmt)),
+        other => return Ok((None, Some(format!("unknown timezone '{}'", other)))),
+      };
+
+      Ok(match written {
+        Ok(()) => (Some(out), None),
+        Err(_) => (None, Some(format!("invalid format string: {}", fmt))),
+      })
+    })?,
+  )?;
+
+  // fyre.time.parse(str, fmt) -> ts | nil, err, interpreting the result as UTC.
+  module.set(
+    "parse",
+    lua.create_function(|_, (value, fmt): (String, String)| {
+      Ok(match NaiveDateTime::parse_from_str(&value, &fmt) {
+        Ok(dt) => (Some(dt.and_utc().timestamp()), None),
+        Err(e) => (None, Some(format!("could not parse '{}': {}", value, e))),
+      })
+    })?,
+  )?;
+
+  module.set(
+    "http_date",
+    lua.create_function(|_, ts: Option<f64>| {
+      let ts = match ts {
+        Some(ts) => ts.floor() as i64,
+        None => Utc::now().timestamp(),
+      };
+      Ok(http_date(ts))
+    })?,
+  )?;
+
+  module.set(
+    "parse_http_date",
+    lua.create_function(|_, value: Option<String>| {
+      let Some(value) = value else {
+        return Ok((None, Some("no date given".to_string())));
+      };
+      Ok(match parse_http_date(&value) {
+        Some(ts) => (Some(ts), None),
+        None => (None, Some(format!("not an HTTP date: {}", value))),
+      })
+    })?,
+  )?;
+
+  module.set(
+    "monotonic_ms",
+    lua.create_function(|_, ()| Ok(monotonic_origin().elapsed().as_secs_f64() * 1000.0))?,
+  )?;
+
+  Ok(module)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  /// The example date in RFC 9110 section 5.6.7.
+  const EXAMPLE: i64 = 784_111_777;
+
+  #[test]
+  fn imf_fixdates_round_trip() {
+    assert_eq!(http_date(EXAMPLE).unwrap(), "Sun, 06 Nov 1994 08:49:37 GMT");
+    assert_eq!(
+      parse_http_date("Sun, 06 Nov 1994 08:49:37 GMT"),
+      Some(EXAMPLE)
+    );
+    for ts in [0, 951_782_400, 1_700_000_000, 4_102_444_799] {
+      assert_eq!(parse_http_date(&http_date(ts).unwrap()), Some(ts), "{}", ts);
+    }
+  }
+
+  #[test]
+  fn obsolete_formats_are_accepted() {
+    assert_eq!(
+      parse_http_date("Sunday, 06-Nov-94 08:49:37 GMT"),
+      Some(EXAMPLE)
+    );
+    assert_eq!(parse_http_date("Sun Nov  6 08:49:37 1994"), Some(EXAMPLE));
+    assert_eq!(parse_http_date("  Sun Nov 6 08:49:37 1994 "), Some(EXAMPLE));
+  }
+
+  #[test]
+  fn other_dates_are_refused() {
+    for value in [
+      "",
+      "yesterday",
+      "1994-11-06T08:49:37Z",
+      "Sun, 06 Nov 1994 08:49:37 UTC",
+      "Sun, 31 Nov 1994 08:49:37 GMT",
+      "Mon, 06 Nov 1994 08:49:37 GMT",
+      "Sun, 06 Nov 1994 25:49:37 GMT",
+    ] {
+      assert_eq!(parse_http_date(value), None, "{:?}", value);
+    }
+  }
+
+  #[test]
+  fn scripts_format_and_parse() {
+    let lua = Lua::new();
+    lua.globals().set("time", module(&lua).unwrap()).unwrap();
+    let results: (String, String, i64, String, Option<i64>, String) = lua
+      .load(
+        r#"
+          local _, format_error = time.format(0, "%Y", "mars")
+          local _, date_error = time.parse_http_date("soon")
+          return time.format(784111777.5),
+            time.http_date(784111777.9),
+            time.parse("1994-11-06 08:49:37", "%Y-%m-%d %H:%M:%S"),
+            format_error,
+            time.parse_http_date(nil),
+            date_error
+        "#,
+      )
+      .eval()
+      .unwrap();
+    assert_eq!(
+      results,
+      (
+        "1994-11-06T08:49:37Z".to_string(),
+        "Sun, 06 Nov 1994 08:49:37 GMT".to_string(),
+        EXAMPLE,
+        "unknown timezone 'mars'".to_string(),
+        None,
+        "not an HTTP date: soon".to_string(),
+      )
+    );
+  }
+}
@@ -0,0 +1,282 @@
+//! # `fyre.url`
+//!
+//! URL parsing, building, and percent-encoding, backed by the `url` crate.
+//!
+//! ```lua
+//! local u = fyre.url.parse("https://user@[::1]:8443/a%20b?tag=x&tag=y#top")
+//! -- u.scheme == "https", u.host == "[::1]", u.port == 8443,
+//! -- u.path == "/a%20b", u.params.tag == { "x", "y" }, u.fragment == "top"
+//!
+//! local next = fyre.url.build{ scheme = "https", host = "example.com",
+//!                              path = "/login", params = { next = "/a b" } }
+//! fyre.url.encode("a b&c")         -- "a%20b%26c"
+//! fyre.url.query_encode{ q = "rust", page = 2 }
+//! ```
+//!
+//! Query strings are decoded as `application/x-www-form-urlencoded`, and a
+//! key that appears more than once becomes a list of its values.
+//! `encode_component` and `decode_component` are public so the rest of the
+//! server can share them rather than growing a second percent-codec.
+
+use mlua::prelude::*;
+use percent_encoding::{percent_decode, utf8_percent_encode, AsciiSet, NON_ALPHANUMERIC};
+use url::Url;
+
+/// Everything except the RFC 3986 unreserved characters is escaped.
+const COMPONENT: &AsciiSet = &NON_ALPHANUMERIC
+  .remove(b'-')
+  .remove(b'.')
+  .remove(b'_')
+  .remove(b'~');
+
+/// Percent-encodes a URL component.
+pub fn encode_component(value: &str) -> String {
+  utf8_percent_encode(value, COMPONENT).to_string()
+}
+
+/// Percent-decodes a URL component into raw bytes. Invalid escapes are left
+/// as-is and `+` is not treated as a space.
+pub fn decode_component(value: &[u8]) -> Vec<u8> {
+  percent_decode(value).collect()
+}
+
+/// Decodes a query string into a table, collecting repeated keys into a list.
+fn query_to_table(lua: &Lua, query: &str) -> LuaResult<LuaTable> {
+  let table = lua.create_table()?;
+  for (key, value) in url::form_urlencoded::parse(query.as_bytes()) {
+    match table.raw_get::<LuaValue>(key.as_ref())? {
+      LuaValue::Nil => table.raw_set(key.as_ref(), value.as_ref())?,
+      LuaValue::Table(values) => values.push(value.as_ref())?,
+      first => {
+        let values = lua.create_sequence_from([first])?;
+        values.push(value.as_ref())?;
+        table.raw_set(key.as_ref(), values)?;
+      }
+    }
+  }
+  Ok(table)
+}
+
+/// Encodes a table as a query string. List values produce one pair per
+/// element; keys are sorted so the output is stable.
+fn table_to_query(params: &LuaTable) -> LuaResult<String> {
+  let mut pairs = Vec::new();
+  for pair in params.pairs::<String, LuaValue>() {
+    let (key, value) = pair?;
+    match value {
+      LuaValue::Table(values) => {
+        for value in values.sequence_values::<LuaString>() {
+          pairs.push((key.clone(), value?.to_string_lossy()));
+        }
+      }
+      LuaValue::String(s) => pairs.push((key, s.to_string_lossy())),
+      LuaValue::Integer(i) => pairs.push((key, i.to_string())),
+      LuaValue::Number(n) => pairs.push((key, n.to_string())),
+      LuaValue::Boolean(b) => pairs.push((key, b.to_string())),
+      other => {
+        return Err(LuaError::external(format!(
+          "query parameter '{}' has unsupported type {}",
+          key,
+          other.type_name()
+        )))
+      }
+    }
+  }
+  pairs.sort();
+
+  Ok(
+    url::form_urlencoded::Serializer::new(String::new())
+      .extend_pairs(pairs)
+      .finish(),
+  )
+}
+
+/// Converts a parsed URL into the table returned by `fyre.url.parse`.
+fn url_to_table(lua: &Lua, url: &Url) -> LuaResult<LuaTable> {
+  let table = lua.create_table()?;
+  table.set("scheme", url.scheme())?;
+  if !url.username().is_empty() {
+    table.set("username", url.username())?;
+  }
+  table.set("password", url.password())?;
+  table.set("host", url.host_str())?;
+  table.set("port", url.port())?;
+  table.set("path", url.path())?;
+  table.set("query", url.query())?;
+  table.set("fragment", url.fragment())?;
+  table.set("params", query_to_table(lua, url.query().unwrap_or(""))?)?;
+  Ok(table)
+}
+
+/// Builds a URL from the parts accepted by `fyre.url.build`.
+fn build(parts: &LuaTable) -> LuaResult<Result<String, String>> {
+  let scheme = parts
+    .get::<Option<String>>("scheme")?
+    .unwrap_or_else(|| "http".to_string());
+  let Some(host) = parts.get::<Option<String>>("host")? else {
+    return Ok(Err("'host' is required".to_string()));
+  };
+
+  let mut url = match Url::parse(&format!("{}://{}", scheme, host)) {
+    Ok(url) => url,
+    Err(e) => return Ok(Err(format!("invalid scheme or host: {}", e))),
+  };
+
+  if let Some(port) = parts.get::<Option<u16>>("port")? {
+    if url.set_port(Some(port)).is_err() {
+      return Ok(Err("cannot set a port on this URL".to_string()));
+    }
+  }
+  if let Some(username) = parts.get::<Option<String>>("username")? {
+    if url.set_username(&username).is_err() {
+      return Ok(Err("cannot set a username on this URL".to_string()));
+    }
+  }
+  if let Some(password) = parts.get::<Option<String>>("password")? {
+    if url.set_password(Some(&password)).is_err() {
+      return Ok(Err("cannot set a password on this URL".to_string()));
+    }
+  }
+  if let Some(path) = parts.get::<Option<String>>("path")? {
+    url.set_path(&path);
+  }
+
+  match parts.get::<Option<LuaTable>>("params")? {
+    Some(params) => url.set_query(Some(&table_to_query(&params)?)),
+    None => url.set_query(parts.get::<Option<String>>("query")?.as_deref()),
+  }
+  url.set_fragment(parts.get::<Option<String>>("fragment")?.as_deref());
+
+  Ok(Ok(url.to_string()))
+}
+
+/// Builds the `fyre.url` module table.
+///
+/// # Errors
+///
+/// This function will return a `LuaError` if the module table or its functions
+/// cannot be created.
+pub fn module(lua: &Lua) -> LuaResult<LuaTable> {
+  let module = lua.create_table()?;
+
+  module.set(
+    "encode",
+    lua.create_function(|_, value: String| Ok(encode_component(&value)))?,
+  )?;
+
+  module.set(
+    "decode",
+    lua.create_function(|lua, value: LuaString| {
+      lua.create_string(decode_component(&value.as_bytes()))
+    })?,
+  )?;
+
+  module.set(
+    "parse",
+    lua.create_function(|lua, value: String| {
+      Ok(match Url::parse(&value) {
+        Ok(url) => (Some(url_to_table(lua, &url)?), None),
+        Err(e) => (None, Some(format!("invalid url '{}': {}", value, e))),
+      })
+    })?,
+  )?;
+
+  module.set(
+    "build",
+    lua.create_function(|_, parts: LuaTable| {
+      Ok(match build(&parts)? {
+        Ok(url) => (Some(url), None),
+        Err(err) => (None, Some(err)),
+      })
+    })?,
+  )?;
+
+  module.set(
+    "query_encode",
+    lua.create_function(|_, params: LuaTable| table_to_query(&params))?,
+  )?;
+
+  module.set(
+    "query_decode",
+    lua.create_function(|lua, query: String| {
+      query_to_table(lua, query.strip_prefix('?').unwrap_or(&query))
+    })?,
+  )?;
+
+  Ok(module)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn components_round_trip() {
+    assert_eq!(encode_component("a b&c/é~"), "a%20b%26c%2F%C3%A9~");
+    assert_eq!(
+      decode_component(b"a%20b%26c%2F%C3%A9~"),
+      "a b&c/é~".as_bytes()
+    );
+    assert_eq!(decode_component(b"a+b%zz"), b"a+b%zz");
+  }
+
+  #[test]
+  fn repeated_query_keys_become_lists() {
+    let lua = Lua::new();
+    let params = query_to_table(&lua, "tag=x&q=a+b&tag=y&tag=z").unwrap();
+    assert_eq!(params.get::<String>("q").unwrap(), "a b");
+    let tags: Vec<String> = params
+      .get::<LuaTable>("tag")
+      .unwrap()
+      .sequence_values()
+      .collect::<LuaResult<_>>()
+      .unwrap();
+    assert_eq!(tags, ["x", "y", "z"]);
+  }
+
+  #[test]
+  fn query_encoding_is_sorted() {
+    let lua = Lua::new();
+    let params: LuaTable = lua
+      .load(r#"{ q = "a b", page = 2, tag = { "x", "y" } }"#)
+      .eval()
+      .unwrap();
+    assert_eq!(table_to_query(&params).unwrap(), "page=2&q=a+b&tag=x&tag=y");
+
+    params
+      .set("fn", lua.create_function(|_, ()| Ok(())).unwrap())
+      .unwrap();
+    assert!(table_to_query(&params).is_err());
+  }
+
+  #[test]
+  fn build_assembles_parts() {
+    let lua = Lua::new();
+    let parts: LuaTable = lua
+      .load(
+        r#"{ scheme = "https", host = "example.com", port = 8443, path = "/login",
+             params = { next = "/a b" }, fragment = "top" }"#,
+      )
+      .eval()
+      .unwrap();
+    assert_eq!(
+      build(&parts).unwrap().unwrap(),
+      "https://example.com:8443/login?next=%2Fa+b#top"
+    );
+
+    parts.set("host", LuaNil).unwrap();
+    assert_eq!(build(&parts).unwrap().unwrap_err(), "'host' is required");
+  }
+
+  #[test]
+  fn parse_splits_a_url() {
+    let lua = Lua::new();
+    let url = Url::parse("https://user@[::1]:8443/a%20b?tag=x#top").unwrap();
+    let table = url_to_table(&lua, &url).unwrap();
+    assert_eq!(table.get::<String>("host").unwrap(), "[::1]");
+    assert_eq!(table.get::<u16>("port").unwrap(), 8443);
+    assert_eq!(table.get::<String>("username").unwrap(), "user");
+    assert_eq!(table.get::<String>("path").unwrap(), "/a%20b");
+    assert_eq!(table.get::<String>("fragment").unwrap(), "top");
+  }
+}
@@ -0,0 +1,619 @@
+//! # `fyre.validate`
+//!
+//! Schema validation for decoded request payloads.
+//!
+//! ```lua
+//! local signup = fyre.validate.compile{
+//!   fields = {
+//!     email = { type = "string", required = true, max = 254, pattern = "^[^@]+@[^@]+$" },
+//!     age   = { type = "integer", min = 13 },
+//!     plan  = { type = "string", enum = { "free", "pro" } },
+//!     tags  = { type = "array", max = 10, items = { type = "string" } },
+//!   },
+//! }
+//!
+//! local ok, errors = fyre.validate(signup, value)
+//! -- errors = { { path = "email", message = "is required" }, ... }
+//!
+//! local body = request.validate(signup)   -- nil after a 422 response
+//! ```
+//!
+//! A schema is compiled into Rust once (patterns included), so declaring it
+//! at module load and reusing it per request costs only the check itself.
+//! A plain schema table may be passed instead and is compiled on each call.
+
+use mlua::prelude::*;
+use regex::Regex;
+use serde_json::{json, Value};
+use std::fmt;
+use std::sync::Arc;
+
+use super::json::from_json;
+use crate::body::BodySource;
+
+/// The types a schema can require.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Type {
+  Any,
+  String,
+  Number,
+  Integer,
+  Boolean,
+  Table,
+  Array,
+}
+
+impl Type {
+  fn parse(name: &str) -> LuaResult<Self> {
+    Ok(match name {
+      "any" => Type::Any,
+      "string" => Type::String,
+      "number" => Type::Number,
+      "integer" => Type::Integer,
+      "boolean" => Type::Boolean,
+      "table" => Type::Table,
+      "array" => Type::Array,
+      other => {
+        return Err(LuaError::external(format!(
+          "fyre.validate: unknown type '{}'",
+          other
+        )))
+      }
+    })
+  }
+}
+
+/// A value allowed by `enum`.
+#[derive(Debug, Clone, PartialEq)]
+enum Literal {
+  String(Vec<u8>),
+  Number(f64),
+  Boolean(bool),
+}
+
+impl Literal {
+  fn from_lua(value: &LuaValue) -> Option<Self> {
+    match value {
+      LuaValue::String(s) => Some(Literal::String(s.as_bytes().to_vec())),
+      LuaValue::Integer(i) => Some(Literal::Number(*i as f64)),
+      LuaValue::Number(n) => Some(Literal::Number(*n)),
+      LuaValue::Boolean(b) => Some(Literal::Boolean(*b)),
+      _ => None,
+    }
+  }
+}
+
+impl fmt::Display for Literal {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      Literal::String(s) => write!(f, "{}", String::from_utf8_lossy(s)),
+      Literal::Number(n) => write!(f, "{}", n),
+      Literal::Boolean(b) => write!(f, "{}", b),
+    }
+  }
+}
+
+/// A compiled schema.
+#[derive(Debug)]
+struct Schema {
+  ty: Type,
+  required: bool,
+  /// The smallest number, or the shortest string, array, or table.
+  min: Option<f64>,
+  /// The largest number, or the longest string, array, or table.
+  max: Option<f64>,
+  /// The exact length of a string, array, or table.
+  length: Option<usize>,
+  one_of: Option<Vec<Literal>>,
+  pattern: Option<Regex>,
+  fields: Vec<(String, Schema)>,
+  items: Option<Box<Schema>>,
+}
+
+impl Schema {
+  /// Compiles a schema table, reporting mistakes in the schema itself as
+  /// errors.
+  fn compile(table: &LuaTable) -> LuaResult<Self> {
+    let mut fields = Vec::new();
+    if let Some(defs) = table.get::<Option<LuaTable>>("fields")? {
+      for pair in defs.pairs::<String, LuaTable>() {
+        let (name, def) = pair?;
+        fields.push((name, Schema::compile(&def)?));
+      }
+      fields.sort_by(|a, b| a.0.cmp(&b.0));
+    }
+    let items = match table.get::<Option<LuaTable>>("items")? {
+      Some(def) => Some(Box::new(Schema::compile(&def)?)),
+      None => None,
+    };
+
+    let ty = match table.get::<Option<String>>("type")? {
+      Some(name) => Type::parse(&name)?,
+      None if items.is_some() => Type::Array,
+      None if !fields.is_empty() => Type::Table,
+      None => Type::Any,
+    };
+
+    let one_of = match table.get::<Option<LuaTable>>("enum")? {
+      Some(values) => Some(
+        values
+          .sequence_values::<LuaValue>()
+          .map(|value| {
+            Literal::from_lua(&value?).ok_or_else(|| {
+              LuaError::external("fyre.validate: enum values must be strings, numbers, or booleans")
+            })
+          })
+          .collect::<LuaResult<_>>()?,
+      ),
+      None => None,
+    };
+
+    let pattern = match table.get::<Option<String>>("pattern")? {
+      Some(pattern) => Some(Regex::new(&pattern).map_err(|e| {
+        LuaError::external(format!(
+          "fyre.validate: invalid pattern '{}': {}",
+          pattern, e
+        ))
+      })?),
+      None => None,
+    };
+
+    Ok(Schema {
+      ty,
+      required: table.get::<Option<bool>>("required")?.unwrap_or(false),
+      min: table.get("min")?,
+      max: table.get("max")?,
+      length: table.get("length")?,
+      one_of,
+      pattern,
+      fields,
+      items,
+    })
+  }
+
+  /// Checks `value` against the schema, appending a `(path, message)` pair
+  /// to `errors` for each problem.
+  fn check(
+    &self,
+    value: &LuaValue,
+    path: &str,
+    errors: &mut Vec<(String, String)>,
+  ) -> LuaResult<()> {
+    let mut fail = |message: String| errors.push((path.to_string(), message));
+
+    if value.is_nil() {
+      if self.required || path.is_empty() {
+        fail("is required".to_string());
+      }
+      return Ok(());
+    }
+
+    match (self.ty, value) {
+      (Type::Any, _) => {}
+      (Type::String, LuaValue::String(_)) => {}
+      (Type::Number, LuaValue::Integer(_)) => {}
+      (Type::Number, LuaValue::Number(n)) if !n.is_nan() => {}
+      (Type::Integer, LuaValue::Integer(_)) => {}
+      (Type::Integer, LuaValue::Number(n)) if n.is_finite() && n.fract() == 0.0 => {}
+      (Type::Boolean, LuaValue::Boolean(_)) => {}
+      (Type::Table, LuaValue::Table(_)) => {}
+      (Type::Array, LuaValue::Table(table)) if is_array(table)? => {}
+      (ty, _) => {
+        let expected = match ty {
+          Type::String => "a string",
+          Type::Number => "a number",
+          Type::Integer => "an integer",
+          Type::Boolean => "a boolean",
+          Type::Table => "an object",
+          _ => "an array",
+        };
+        fail(format!("must be {}", expected));
+        return Ok(());
+      }
+    }
+
+    if let Some(one_of) = &self.one_of {
+      let matches = Literal::from_lua(value).is_some_and(|literal| one_of.contains(&literal));
+      if !matches {
+        let allowed: Vec<String> = one_of.iter().map(|l| l.to_string()).collect();
+        fail(format!("must be one of: {}", allowed.join(", ")));
+      }
+    }
+
+    match *value {
+      LuaValue::Integer(i) => self.check_range(i as f64, &mut fail),
+      LuaValue::Number(n) => self.check_range(n, &mut fail),
+      _ => {}
+    }
+
+    match value {
+      LuaValue::String(s) => {
+        let text = s.to_str();
+        let len = match &text {
+          Ok(text) => text.chars().count(),
+          Err(_) => s.as_bytes().len(),
+        };
+        self.check_size(len, "characters", &mut fail);
+        if let Some(pattern) = &self.pattern {
+          match &text {
+            Ok(text) if pattern.is_match(text) => {}
+            Ok(_) => fail(format!("must match the pattern {}", pattern.as_str())),
+            Err(_) => fail("must be valid UTF-8".to_string()),
+          }
+        }
+      }
+      LuaValue::Table(table) => {
+        let len = if self.ty == Type::Array {
+          table.raw_len()
+        } else {
+          table.pairs::<LuaValue, LuaValue>().count()
+        };
+        let unit = if self.ty == Type::Array {
+          "items"
+        } else {
+          "fields"
+        };
+        self.check_size(len, unit, &mut fail);
+
+        for (name, schema) in &self.fields {
+          let field_path = if path.is_empty() {
+            name.clone()
+          } else {
+            format!("{}.{}", path, name)
+          };
+          schema.check(&table.get::<LuaValue>(name.as_str())?, &field_path, errors)?;
+        }
+        if let Some(items) = &self.items {
+          for i in 1..=table.raw_len() {
+            let item_path = format!("{}[{}]", path, i);
+            items.check(&table.get::<LuaValue>(i)?, &item_path, errors)?;
+          }
+        }
+      }
+      _ => {}
+    }
+    Ok(())
+  }
+
+  /// Checks a number against `min` and `max`.
+  fn check_range(&self, n: f64, fail: &mut impl FnMut(String)) {
+    if let Some(min) = self.min.filter(|min| n < *min) {
+      fail(format!("must be at least {}", min));
+    }
+    if let Some(max) = self.max.filter(|max| n > *max) {
+      fail(format!("must be at most {}", max));
+    }
+  }
+
+  /// Checks a string, array, or table length against `min`, `max`, and
+  /// `length`.
+  fn check_size(&self, len: usize, unit: &str, fail: &mut impl FnMut(String)) {
+    if let Some(length) = self.length.filter(|length| len != *length) {
+      fail(format!("must have exactly {} {}", length, unit));
+    }
+    if let Some(min) = self.min.filter(|min| (len as f64) < *min) {
+      fail(format!("must have at least {} {}", min, unit));
+    }
+    if let Some(max) = self.max.filter(|max| (len as f64) > *max) {
+      fail(format!("must have at most {} {}", max, unit));
+    }
+  }
+}
+
+/// Returns `true` if the table's keys are exactly `1..n` (an empty table
+/// counts as an array).
+fn is_array(table: &LuaTable) -> LuaResult<bool> {
+  let len = table.raw_len();
+  let mut count = 0;
+  for pair in table.pairs::<LuaValue, LuaValue>() {
+    match pair?.0 {
+      LuaValue::Integer(i) if i >= 1 && i as usize <= len => count += 1,
+      _ => return Ok(false),
+    }
+  }
+  Ok(count == len)
+}
+
+/// The userdata returned by `fyre.validate.compile`.
+#[derive(Clone)]
+struct CompiledSchema(Arc<Schema>);
+
+impl LuaUserData for CompiledSchema {}
+
+/// Accepts either a compiled schema or a schema table.
+fn to_schema(schema: LuaValue) -> LuaResult<Arc<Schema>> {
+  match schema {
+    LuaValue::UserData(ud) => Ok(ud.borrow::<CompiledSchema>()?.0.clone()),
+    LuaValue::Table(table) => Ok(Arc::new(Schema::compile(&table)?)),
+    other => Err(LuaError::external(format!(
+      "fyre.validate: expected a schema, got {}",
+      other.type_name()
+    ))),
+  }
+}
+
+/// Validates `value`, returning the errors as `(path, message)` pairs.
+fn validate(schema: &Schema, value: &LuaValue) -> LuaResult<Vec<(String, String)>> {
+  let mut errors = Vec::new();
+  schema.check(value, "", &mut errors)?;
+  Ok(errors)
+}
+
+/// Converts validation errors to a Lua list of `{ path, message }` tables.
+fn errors_table(lua: &Lua, errors: Vec<(String, String)>) -> LuaResult<LuaTable> {
+  let list = lua.create_table_with_capacity(errors.len(), 0)?;
+  for (path, message) in errors {
+    let entry = lua.create_table()?;
+    entry.set("path", path)?;
+    entry.set("message", message)?;
+    list.push(entry)?;
+  }
+  Ok(list)
+}
+
+/// Builds the `request.validate(schema)` function for one request.
+///
+/// It decodes the JSON `body` and validates it. On success it returns the
+/// decoded value. Otherwise it fills in `response` with a 400 (malformed
+/// JSON) or 422 (schema errors) JSON error list and returns `nil`.
+///
+/// # Errors
+///
+/// This function will return a `LuaError` if the function cannot be created.
+pub fn request_function(
+  lua: &Lua,
+  body: BodySource,
+  response: LuaTable,
+) -> LuaResult<LuaFunction> {
+  lua.create_function(move |lua, schema: LuaValue| {
+    let schema = to_schema(schema)?;
+    let body = body.load(lua)?;
+
+    let parsed = body.map(|body| serde_json::from_slice::<Value>(&body.as_bytes()));
+    let (status, errors) = match parsed {
+      None => (
+        413,
+        vec![(
+          String::new(),
+          format!("body is larger than {} bytes", crate::body::MAX_LOAD_BYTES),
+        )],
+      ),
+      Some(Ok(json)) => {
+        let value = from_json(lua, &json)?;
+        let errors = validate(&schema, &value)?;
+        if errors.is_empty() {
+          return Ok(value);
+        }
+        (422, errors)
+      }
+      Some(Err(e)) => (400, vec![(String::new(), format!("invalid JSON: {}", e))]),
+    };
+
+    let errors: Vec<Value> = errors
+      .into_iter()
+      .map(|(path, message)| json!({ "path": path, "message": message }))
+      .collect();
+    response.set("status", status)?;
+    response
+      .get::<LuaTable>("headers")?
+      .set("Content-Type", "application/json")?;
+    response.set("body", json!({ "errors": errors }).to_string())?;
+    Ok(LuaValue::Nil)
+  })
+}
+
+/// Builds the `fyre.validate` module table, which is also callable as
+/// `fyre.validate(schema, value)`.
+///
+/// # Errors
+///
+/// This function will return a `LuaError` if the module table or its functions
+/// cannot be created.
+pub fn module(lua: &Lua) -> LuaResult<LuaTable> {
+  let module = lua.create_table()?;
+
+  // fyre.validate.compile(schema) -> compiled schema
+  module.set(
+    "compile",
+    lua.create_function(|_, schema: LuaTable| {
+      Ok(CompiledSchema(Arc::new(Schema::compile(&schema)?)))
+    })?,
+  )?;
+
+  // fyre.validate(schema, value) -> ok, errors
+  let metatable = lua.create_table()?;
+  metatable.set(
+    "__call",
+    lua.create_function(|lua, (_, schema, value): (LuaTable, LuaValue, LuaValue)| {
+      let schema = to_schema(schema)?;
+      let errors = validate(&schema, &value)?;
+      if errors.is_empty() {
+        Ok((true, None))
+      } else {
+        Ok((false, Some(errors_table(lua, errors)?)))
+      }
+    })?,
+  )?;
+  module.set_metatable(Some(metatable))?;
+
+  Ok(module)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::testing::{self, Fixture};
+
+  const SIGNUP: &str = r#"{
+    fields = {
+      email = { type = "string", required = true, max = 254, pattern = "^[^@]+@[^@]+$" },
+      age = { type = "integer", min = 13 },
+      plan = { type = "string", enum = { "free", "pro" } },
+      tags = { max = 2, items = { type = "string", length = 3 } },
+      address = { fields = { zip = { type = "string", required = true } } },
+    },
+  }"#;
+
+  /// The errors `fyre.validate(SIGNUP, value)` gives, as `path: message`.
+  fn errors(value: &str) -> Vec<String> {
+    let lua = Lua::new();
+    lua
+      .globals()
+      .set("validate", module(&lua).unwrap())
+      .unwrap();
+    let (ok, errors): (bool, Option<Vec<LuaTable>>) = lua
+      .load(format!(
+        "return validate(validate.compile({}), {})",
+        SIGNUP, value
+      ))
+      .eval()
+      .unwrap();
+    let errors: Vec<String> = errors
+      .unwrap_or_default()
+      .iter()
+      .map(|e| {
+        format!(
+          "{}: {}",
+          e.get::<String>("path").unwrap(),
+          e.get::<String>("message").unwrap()
+        )
+      })
+      .collect();
+    assert_eq!(ok, errors.is_empty());
+    errors
+  }
+
+  #[test]
+  fn a_valid_value_has_no_errors() {
+    let value = r#"{ email = "ada@example.com", age = 36, plan = "pro", tags = { "abc" },
+      address = { zip = "12345" } }"#;
+    assert_eq!(errors(value), Vec::<String>::new());
+    assert_eq!(
+      errors(r#"{ email = "ada@example.com", age = 13.0 }"#),
+      Vec::<String>::new()
+    );
+  }
+
+  #[test]
+  fn every_error_is_reported_with_its_path() {
+    let value = r#"{ age = 12.5, plan = "gold", tags = { "abc", 7, "de" },
+      address = {} }"#;
+    assert_eq!(
+      errors(value),
+      vec![
+        "address.zip: is required",
+        "age: must be an integer",
+        "email: is required",
+        "plan: must be one of: free, pro",
+        "tags: must have at most 2 items",
+        "tags[2]: must be a string",
+        "tags[3]: must have exactly 3 characters",
+      ]
+    );
+    assert_eq!(
+      errors(r#"{ email = "ada", age = 12, tags = { x = 1 }, address = "home" }"#),
+      vec![
+        "address: must be an object",
+        "age: must be at least 13",
+        "email: must match the pattern ^[^@]+@[^@]+$",
+        "tags: must be an array",
+      ]
+    );
+    assert_eq!(errors("nil"), vec![": is required"]);
+  }
+
+  #[test]
+  fn mistakes_in_the_schema_are_errors() {
+    let lua = Lua::new();
+    lua
+      .globals()
+      .set("validate", module(&lua).unwrap())
+      .unwrap();
+    for (schema, expected) in [
+      (r#"{ type = "text" }"#, "unknown type 'text'"),
+      (r#"{ pattern = "(" }"#, "invalid pattern '('"),
+      (
+        r#"{ enum = { {} } }"#,
+        "enum values must be strings, numbers, or booleans",
+      ),
+      (
+        r#"{ fields = { name = { type = "strnig" } } }"#,
+        "unknown type 'strnig'",
+      ),
+    ] {
+      let error = lua
+        .load(format!("validate.compile({})", schema))
+        .exec()
+        .unwrap_err();
+      assert!(
+        error.to_string().contains(expected),
+        "{}: {}",
+        schema,
+        error
+      );
+    }
+    let error = lua.load("validate(1, {})").exec().unwrap_err();
+    assert!(
+      error.to_string().contains("expected a schema, got integer"),
+      "{}",
+      error
+    );
+  }
+
+  #[test]
+  fn request_validate_answers_bad_bodies() {
+    let fixture = Fixture::new(
+      r#"router.add("/signup", "signup.lua")"#,
+      &[(
+        "signup.lua",
+        &format!(
+          r#"local schema = fyre.validate.compile({})
+          return {{ handler = function(request, response)
+            local body = request.validate(schema)
+            if body then response.body = "welcome " .. body.email end
+          end }}"#,
+          SIGNUP
+        ),
+      )],
+    );
+    let server = fixture
+      .builder()
+      .addr("127.0.0.1:0")
+      .workers(1)
+      .load()
+      .unwrap();
+    let addr = server.serve().unwrap().remove(0);
+    let post = |body: &str| {
+      let response = testing::send(
+        &addr,
+        &format!(
+          "POST /signup HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\
+           Content-Type: application/json\r\nContent-Length: {}\r\n\r\n{}",
+          body.len(),
+          body
+        ),
+      );
+      let (head, body) = response.split_once("\r\n\r\n").unwrap();
+      (head[9..12].to_string(), body.to_string())
+    };
+
+    assert_eq!(
+      post(r#"{"email": "ada@example.com"}"#),
+      ("200".to_string(), "welcome ada@example.com".to_string())
+    );
+    let (status, body) = post(r#"{"age": 12}"#);
+    assert_eq!(status, "422");
+    let body: Value = serde_json::from_str(&body).unwrap();
+    assert_eq!(
+      body,
+      json!({ "errors": [
+        { "path": "age", "message": "must be at least 13" },
+        { "path": "email", "message": "is required" },
+      ] })
+    );
+    let (status, body) = post("{\"email\": ");
+    assert_eq!(status, "400");
+    assert!(body.contains("invalid JSON"), "{}", body);
+    server.shutdown();
+  }
+}
@@ -0,0 +1,212 @@
+//! # Shared Values
+//!
+//! Lua values can't outlive the state that created them, so anything stored
+//! across requests (e.g. in `fyre.kv`) is deep-copied into a `SharedValue`
+//! and rebuilt as fresh Lua values when read back.
+//!
+//! Strings, numbers, booleans, and tables of those are supported. Table keys
+//! must be strings or integers, so every storable table is also
+//! JSON-serializable. Functions, userdata, threads, and cyclic tables are
+//! rejected.
+//!
+//! `SharedValue::encode` and `SharedValue::decode` give a compact binary form
+//! for values that have to leave the process (e.g. session cookies).
+
+use mlua::prelude::*;
+
+/// How deeply nested a table may be before it is rejected (this also stops
+/// cyclic tables).
+const MAX_DEPTH: usize = 32;
+
+/// A Lua value copied out of a Lua state.
+#[derive(Debug, Clone, PartialEq)]
+pub enum SharedValue {
+  Boolean(bool),
+  Integer(i64),
+  Number(f64),
+  /// A Lua string. Lua strings are byte strings, so this is binary-safe.
+  String(Vec<u8>),
+  Table(Vec<(SharedKey, SharedValue)>),
+}
+
+/// A table key inside a `SharedValue::Table`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum SharedKey {
+  Integer(i64),
+  String(Vec<u8>),
+}
+
+impl SharedValue {
+  /// Deep-copies a Lua value.
+  ///
+  /// # Errors
+  ///
+  /// This function will return a `LuaError` if the value (or anything nested
+  /// in it) has an unsupported type, or if tables nest deeper than
+  /// `MAX_DEPTH`.
+  pub fn from_lua_value(value: LuaValue) -> LuaResult<Self> {
+    Self::copy(value, 0)
+  }
+
+  fn copy(value: LuaValue, depth: usize) -> LuaResult<Self> {
+    match value {
+      LuaValue::Boolean(b) => Ok(SharedValue::Boolean(b)),
+      LuaValue::Integer(i) => Ok(SharedValue::Integer(i)),
+      LuaValue::Number(n) => Ok(SharedValue::Number(n)),
+      LuaValue::String(s) => Ok(SharedValue::String(s.as_bytes().to_vec())),
+      LuaValue::Table(table) => {
+        if depth >= MAX_DEPTH {
+          return Err(LuaError::external(format!(
+            "table nesting exceeds {} levels (is it cyclic?)",
+            MAX_DEPTH
+          )));
+        }
+
+        let mut entries = Vec::new();
+        for pair in table.pairs::<LuaValue, LuaValue>() {
+          let (key, value) = pair?;
+          let key = match key {
+            LuaValue::Integer(i) => SharedKey::Integer(i),
+            LuaValue::String(s) => SharedKey::String(s.as_bytes().to_vec()),
+            other => {
+              return Err(LuaError::external(format!(
+                "unsupported table key type: {}",
+                other.type_name()
+              )))
+            }
+          };
+          entries.push((key, Self::copy(value, depth + 1)?));
+        }
+        Ok(SharedValue::Table(entries))
+      }
+      other => Err(LuaError::external(format!(
+        "unsupported value type: {}",
+        other.type_name()
+      ))),
+    }
+  }
+
+  /// Rebuilds the value inside a Lua state.
+  ///
+  /// # Errors
+  ///
+  /// This function will return a `LuaError` if a string or table cannot be
+  /// created.
+  pub fn to_lua_value(&self, lua: &Lua) -> LuaResult<LuaValue> {
+    Ok(match self {
+      SharedValue::Boolean(b) => LuaValue::Boolean(*b),
+      SharedValue::Integer(i) => LuaValue::Integer(*i),
+      SharedValue::Number(n) => LuaValue::Number(*n),
+      SharedValue::String(s) => LuaValue::String(lua.create_string(s)?),
+      SharedValue::Table(entries) => {
+        let table = lua.create_table_with_capacity(0, entries.len())?;
+        for (key, value) in entries {
+          let value = value.to_lua_value(lua)?;
+          match key {
+            SharedKey::Integer(i) => table.raw_set(*i, value)?,
+            SharedKey::String(s) => table.raw_set(lua.create_string(s)?, value)?,
+          }
+        }
+        LuaValue::Table(table)
+      }
+    })
+  }
+
+  /// Serializes the value into a compact, self-describing binary form.
+  pub fn encode(&self, out: &mut Vec<u8>) {
+    match self {
+      SharedValue::Boolean(false) => out.push(TAG_FALSE),
+      SharedValue::Boolean(true) => out.push(TAG_TRUE),
+      SharedValue::Integer(i) => {
+        out.push(TAG_INTEGER);
+        out.extend_from_slice(&i.to_be_bytes());
+      }
+      SharedValue::Number(n) => {
+        out.push(TAG_NUMBER);
+        out.extend_from_slice(&n.to_bits().to_be_bytes());
+      }
+      SharedValue::String(s) => encode_bytes(out, s),
+      SharedValue::Table(entries) => {
+        out.push(TAG_TABLE);
+        out.extend_from_slice(&(entries.len() as u32).to_be_bytes());
+        for (key, value) in entries {
+          match key {
+            SharedKey::Integer(i) => {
+              out.push(TAG_INTEGER);
+              out.extend_from_slice(&i.to_be_bytes());
+            }
+            SharedKey::String(s) => encode_bytes(out, s),
+          }
+          value.encode(out);
+        }
+      }
+    }
+  }
+
+  /// Deserializes a value produced by `encode`, returning `None` if the
+  /// input is malformed or has trailing bytes.
+  pub fn decode(mut input: &[u8]) -> Option<Self> {
+    let value = Self::decode_from(&mut input, 0)?;
+    input.is_empty().then_some(value)
+  }
+
+  fn decode_from(input: &mut &[u8], depth: usize) -> Option<Self> {
+    Some(match take(input, 1)?[0] {
+      TAG_FALSE => SharedValue::Boolean(false),
+      TAG_TRUE => SharedValue::Boolean(true),
+      TAG_INTEGER => SharedValue::Integer(take_u64(input)? as i64),
+      TAG_NUMBER => SharedValue::Number(f64::from_bits(take_u64(input)?)),
+      TAG_STRING => SharedValue::String(decode_bytes(input)?),
+      TAG_TABLE if depth < MAX_DEPTH => {
+        let count = take_u32(input)? as usize;
+        let mut entries = Vec::with_capacity(count.min(input.len()));
+        for _ in 0..count {
+          let key = match take(input, 1)?[0] {
+            TAG_INTEGER => SharedKey::Integer(take_u64(input)? as i64),
+            TAG_STRING => SharedKey::String(decode_bytes(input)?),
+            _ => return None,
+          };
+          entries.push((key, Self::decode_from(input, depth + 1)?));
+        }
+        SharedValue::Table(entries)
+      }
+      _ => return None,
+    })
+  }
+}
+
+const TAG_FALSE: u8 = 0;
+const TAG_TRUE: u8 = 1;
+const TAG_INTEGER: u8 = 2;
+const TAG_NUMBER: u8 = 3;
+const TAG_STRING: u8 = 4;
+const TAG_TABLE: u8 = 5;
+
+fn encode_bytes(out: &mut Vec<u8>, bytes: &[u8]) {
+  out.push(TAG_STRING);
+  out.extend_from_slice(&(bytes.len() as u32).to_be_bytes());
+  out.extend_from_slice(bytes);
+}
+
+fn decode_bytes(input: &mut &[u8]) -> Option<Vec<u8>> {
+  let len = take_u32(input)? as usize;
+  Some(take(input, len)?.to_vec())
+}
+
+/// Splits `n` bytes off the front of `input`.
+fn take<'a>(input: &mut &'a [u8], n: usize) -> Option<&'a [u8]> {
+  if input.len() < n {
+    return None;
+  }
+  let (head, rest) = input.split_at(n);
+  *input = rest;
+  Some(head)
+}
+
+fn take_u32(input: &mut &[u8]) -> Option<u32> {
+  Some(u32::from_be_bytes(take(input, 4)?.try_into().ok()?))
+}
+
+fn take_u64(input: &mut &[u8]) -> Option<u64> {
+  Some(u64::from_be_bytes(take(input, 8)?.try_into().ok()?))
+}
@@ -0,0 +1,249 @@
+//! # Health and Readiness Probes
+//!
+//! For load balancers and Kubernetes probes, two paths are answered by the
+//! connection threads themselves, before route lookup and without a Lua
+//! state, so they respond even when every worker is busy and don't run
+//! any route's `middleware`:
+//!
+//! - `/healthz` (`CONFIG.health.live_path`) answers `200` whenever the
+//!   server is accepting connections.
+//! - `/readyz` (`CONFIG.health.ready_path`) answers `200` once the
+//!   configuration has loaded, every handler has compiled, and the workers
+//!   are running, and `503` before that and once shutdown begins.
+//!
+//! Setting a path to `false` disables it. Only `GET` and `HEAD` are
+//! answered; other methods go to the routes as usual. Probes aren't logged
+//! unless `CONFIG.health.log` is `true`.
+//!
+//! `CONFIG.health.readiness` can add a check of the application's own:
+//!
+//! ```lua
+//! CONFIG = {
+//!   health = {
+//!     readiness = function()
+//!       return fyre.kv.get("maintenance") == nil
+//!     end,
+//!   },
+//! }
+//! ```
+//!
+//! The function runs on a thread of its own every
+//! `readiness_interval_ms` (default 1000), in a Lua state with the `fyre`
+//! modules, and the server is ready while it returns a true value. The
+//! probe reports the last result rather than waiting for a new one. Since
+//! the function is copied out of `config.lua`, it can use globals but not
+//! the script's local variables.
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use mlua::prelude::*;
+use mlua::ChunkMode;
+use tiny_http::{Header, Method, Response, StatusCode};
+
+use crate::server::Request;
+use crate::{fyre, settings, AppState};
+
+/// The liveness probe's path when `live_path` is not set.
+pub const DEFAULT_LIVE_PATH: &str = "/healthz";
+/// The readiness probe's path when `ready_path` is not set.
+pub const DEFAULT_READY_PATH: &str = "/readyz";
+/// How often the readiness function runs when `readiness_interval_ms` is
+/// not set.
+pub const DEFAULT_READINESS_INTERVAL: Duration = Duration::from_secs(1);
+
+/// The probe settings, from the `health` section of `CONFIG`.
+#[derive(Debug, Clone)]
+pub struct HealthSettings {
+  /// The liveness probe's path; `None` disables it.
+  pub live_path: Option<String>,
+  /// The readiness probe's path; `None` disables it.
+  pub ready_path: Option<String>,
+  /// The bytecode of the `readiness` function.
+  pub readiness: Option<Vec<u8>>,
+  pub readiness_interval: Duration,
+  /// Whether probes are logged like other requests.
+  pub log: bool,
+}
+
+impl Default for HealthSettings {
+  fn default() -> Self {
+    HealthSettings {
+      live_path: Some(DEFAULT_LIVE_PATH.to_string()),
+      ready_path: Some(DEFAULT_READY_PATH.to_string()),
+      readiness: None,
+      readiness_interval: DEFAULT_READINESS_INTERVAL,
+      log: false,
+    }
+  }
+}
+
+impl HealthSettings {
+  /// Reads the settings from `CONFIG.health`.
+  ///
+  /// # Errors
+  ///
+  /// Returns an error message if a path is neither a path nor `false`, or
+  /// another global has the wrong type.
+  pub fn from_globals(globals: &LuaTable) -> Result<HealthSettings, String> {
+    let defaults = HealthSettings::default();
+    Ok(HealthSettings {
+      live_path: probe_path(globals, "HEALTH_LIVE_PATH", defaults.live_path)?,
+      ready_path: probe_path(globals, "HEALTH_READY_PATH", defaults.ready_path)?,
+      readiness: globals
+        .get::<Option<LuaFunction>>("HEALTH_READINESS")
+        .map_err(|e| format!("CONFIG.health.readiness must be a function: {}", e))?
+        // Not stripped, so errors still report line numbers.
+        .map(|readiness| readiness.dump(false)),
+      readiness_interval: globals
+        .get::<Option<u64>>("HEALTH_READINESS_INTERVAL_MS")
+        .map_err(|e| {
+          format!(
+            "CONFIG.health.readiness_interval_ms must be a number of milliseconds: {}",
+            e
+          )
+        })?
+        .map_or(defaults.readiness_interval, Duration::from_millis),
+      log: globals
+        .get::<Option<bool>>("HEALTH_LOG")
+        .map_err(|e| format!("CONFIG.health.log must be true or false: {}", e))?
+        .unwrap_or(defaults.log),
+    })
+  }
+}
+
+/// Reads a probe's path from `global`: `false` disables the probe.
+fn probe_path(
+  globals: &LuaTable,
+  global: &str,
+  default: Option<String>,
+) -> Result<Option<String>, String> {
+  match globals.get::<LuaValue>(global) {
+    Ok(LuaValue::Nil) => Ok(default),
+    Ok(LuaValue::Boolean(false)) => Ok(None),
+    Ok(LuaValue::String(path)) if path.as_bytes().starts_with(b"/") => {
+      Ok(Some(path.to_string_lossy()))
+    }
+    _ => Err(format!(
+      "{} must be a path starting with / or false",
+      settings::key(global)
+    )),
+  }
+}
+
+/// Answers the probes and tracks whether the server is ready.
+pub struct Health {
+  settings: HealthSettings,
+  ready: AtomicBool,
+  stopped: AtomicBool,
+}
+
+impl Health {
+  pub fn new(settings: HealthSettings) -> Health {
+    Health {
+      settings,
+      ready: AtomicBool::new(false),
+      stopped: AtomicBool::new(false),
+    }
+  }
+
+  /// Answers `request` if it is a probe, and returns it otherwise.
+  /// `stopping` is whether the server has begun to shut down.
+  pub fn answer(&self, request: Request, stopping: bool) -> Option<Request> {
+    if !matches!(request.method(), Method::Get | Method::Head) {
+      return Some(request);
+    }
+    let path = request.url().split('?').next().unwrap_or_default();
+    let ok = if self.settings.live_path.as_deref() == Some(path) {
+      true
+    } else if self.settings.ready_path.as_deref() == Some(path) {
+      !stopping && self.ready.load(Ordering::Relaxed)
+    } else {
+      return Some(request);
+    };
+
+    let (status, body) = if ok { (200, "ok") } else { (503, "not ready") };
+    if self.settings.log {
+      info!("Request: {} -> health probe ({})", request.url(), status);
+    }
+    let response = Response::from_string(body)
+      .with_status_code(StatusCode(status))
+      .with_header(Header::from_bytes("Content-Type", "text/plain").unwrap())
+      .with_header(Header::from_bytes("Cache-Control", "no-store").unwrap());
+    let _ = request.respond(response);
+    None
+  }
+
+  /// Marks the server as started: it is ready from now on, or, with a
+  /// `readiness` function, once the function first returns true.
+  pub fn start(self: &Arc<Self>, state: &Arc<AppState>) {
+    let Some(bytecode) = self.settings.readiness.clone() else {
+      self.ready.store(true, Ordering::Relaxed);
+      return;
+    };
+    let health = self.clone();
+    let state = state.clone();
+    let spawned = std::thread::Builder::new()
+      .name("readiness".to_string())
+      .spawn(move || health.watch(&state, &bytecode));
+    if let Err(e) = spawned {
+      error!("Could not start the readiness check: {}", e);
+    }
+  }
+
+  /// Stops reporting the server as ready, at shutdown.
+  pub fn stop(&self) {
+    self.stopped.store(true, Ordering::Relaxed);
+    self.ready.store(false, Ordering::Relaxed);
+  }
+
+  /// Runs the readiness function until the server stops, logging each
+  /// change in its result.
+  fn watch(&self, state: &Arc<AppState>, bytecode: &[u8]) {
+    let lua = Lua::new();
+    let readiness = fyre::random::seed_math_random(&lua)
+      .and_then(|()| fyre::register(&lua, state))
+      .and_then(|()| {
+        lua
+          .load(bytecode)
+          .set_name("readiness")
+          .set_mode(ChunkMode::Binary)
+          .into_function()
+      });
+    let readiness = match readiness {
+      Ok(readiness) => readiness,
+      Err(e) => {
+        error!("Could not load the readiness function: {}", e);
+        return;
+      }
+    };
+
+    let mut was_ready = None;
+    while !self.stopped.load(Ordering::Relaxed) {
+      let ready = match readiness.call::<bool>(()) {
+        Ok(true) => true,
+        Ok(false) => {
+          if was_ready != Some(false) {
+            warn!("Not ready: the readiness function returned false");
+          }
+          false
+        }
+        Err(e) => {
+          if was_ready != Some(false) {
+            warn!("Not ready: the readiness function failed: {}", e);
+          }
+          false
+        }
+      };
+      if ready && was_ready != Some(true) {
+        info!("Ready: the readiness function returned true");
+      }
+      was_ready = Some(ready);
+      if !self.stopped.load(Ordering::Relaxed) {
+        self.ready.store(ready, Ordering::Relaxed);
+      }
+      std::thread::sleep(self.settings.readiness_interval);
+    }
+  }
+}
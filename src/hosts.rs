@@ -0,0 +1,281 @@
+//! # Allowed Hosts
+//!
+//! Turns away requests for host names the server doesn't serve, so a forged
+//! `Host` header can't end up in cached pages or in links a handler builds
+//! from it (a password reset mail, say). `CONFIG.allowed_hosts = {
+//! "example.com", "*.example.com", "localhost" }` lists the names; a
+//! request whose `Host`, without its port, matches none of them is answered
+//! with `421` before its route is looked up, and the value is logged.
+//!
+//! Names match case-insensitively, and a `*.` entry matches exactly one
+//! more label: `*.example.com` matches `www.example.com` but neither
+//! `example.com` nor `a.b.example.com`. IPv6 literals are written without
+//! brackets (`"::1"`). With a list set, an HTTP/1.1 request with no `Host`,
+//! an empty one, or more than one is answered with `400`, as RFC 9112
+//! (section 3.2) requires; an HTTP/1.0 request may leave it out. Without a
+//! list, every host is accepted. Requests made through the embedding API
+//! are checked like any other.
+
+use tiny_http::Header;
+
+/// One `allowed_hosts` entry.
+#[derive(Debug, Clone)]
+enum Pattern {
+  /// A whole name, lowercased.
+  Exact(String),
+  /// The name a `*.` entry's wildcard label is followed by, lowercased.
+  Subdomain(String),
+}
+
+impl Pattern {
+  fn matches(&self, host: &str) -> bool {
+    match self {
+      Pattern::Exact(name) => host == name,
+      Pattern::Subdomain(parent) => host
+        .strip_suffix(parent.as_str())
+        .and_then(|label| label.strip_suffix('.'))
+        .is_some_and(|label| !label.is_empty() && !label.contains('.')),
+    }
+  }
+}
+
+/// The host names requests may be for, from `CONFIG.allowed_hosts`.
+#[derive(Debug, Clone, Default)]
+pub struct AllowedHosts {
+  patterns: Vec<Pattern>,
+}
+
+/// Why a request's `Host` was refused.
+pub enum Refused {
+  /// There was no usable `Host` header; answered with `400`.
+  Missing,
+  /// The host isn't in the list; answered with `421`.
+  NotAllowed(String),
+}
+
+impl AllowedHosts {
+  /// Parses the entries of `CONFIG.allowed_hosts`.
+  ///
+  /// # Errors
+  ///
+  /// Returns an error message naming an empty entry, one with a port, or a
+  /// wildcard anywhere but a leading `*.`.
+  pub fn parse(entries: &[String]) -> Result<AllowedHosts, String> {
+    let patterns = entries
+      .iter()
+      .map(|entry| {
+        let name = entry.trim().to_ascii_lowercase();
+        let invalid = |why: &str| format!("CONFIG.allowed_hosts entry '{}' {}", entry, why);
+        let rest = name.strip_prefix("*.").unwrap_or(&name);
+        if rest.is_empty() {
+          return Err(invalid("is empty"));
+        }
+        if rest.contains('*') {
+          return Err(invalid("may only use a wildcard as its whole first label, as in '*.'"));
+        }
+        let ipv6 = rest.parse::<std::net::Ipv6Addr>().is_ok();
+        if rest.contains('/') || (rest.contains(':') && !ipv6) {
+          return Err(invalid("must be a host name without a scheme, path, or port"));
+        }
+        Ok(match name.strip_prefix("*.") {
+          Some(parent) => Pattern::Subdomain(parent.to_string()),
+          None => Pattern::Exact(name.clone()),
+        })
+      })
+      .collect::<Result<_, _>>()?;
+    Ok(AllowedHosts { patterns })
+  }
+
+  /// Whether no list is set, so every host is accepted.
+  pub fn is_empty(&self) -> bool {
+    self.patterns.is_empty()
+  }
+
+  /// Checks a request's headers. `http_1_0` says whether it was an
+  /// HTTP/1.0 request, which may leave `Host` out.
+  pub fn check(&self, headers: &[Header], http_1_0: bool) -> Result<(), Refused> {
+    if self.is_empty() {
+      return Ok(());
+    }
+    let mut hosts = headers
+      .iter()
+      .filter(|h| h.field.equiv("Host"))
+      .map(|h| h.value.as_str().trim());
+    let host = match (hosts.next(), hosts.next()) {
+      (Some(host), None) if !host.is_empty() => host,
+      (None, _) if http_1_0 => return Ok(()),
+      _ => return Err(Refused::Missing),
+    };
+    // A fully qualified name may end in a dot.
+    let name = host_name(host).trim_end_matches('.').to_ascii_lowercase();
+    if self.patterns.iter().any(|pattern| pattern.matches(&name)) {
+      Ok(())
+    } else {
+      Err(Refused::NotAllowed(host.to_string()))
+    }
+  }
+}
+
+/// `host` without its port, and an IPv6 literal without its brackets.
+fn host_name(host: &str) -> &str {
+  if let Some(rest) = host.strip_prefix('[') {
+    return rest.split_once(']').map_or(rest, |(addr, _)| addr);
+  }
+  host.rsplit_once(':').map_or(host, |(name, _)| name)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::testing::Fixture;
+  use std::io::{Read, Write};
+  use std::net::TcpStream;
+
+  fn allowed(entries: &[&str]) -> AllowedHosts {
+    AllowedHosts::parse(&entries.iter().map(|e| e.to_string()).collect::<Vec<_>>()).unwrap()
+  }
+
+  fn hosts(values: &[&str]) -> Vec<Header> {
+    values
+      .iter()
+      .map(|value| Header::from_bytes("Host", value.as_bytes()).unwrap())
+      .collect()
+  }
+
+  /// The status `list` answers a request with `Host` headers `values`.
+  fn status(list: &AllowedHosts, values: &[&str], http_1_0: bool) -> u16 {
+    match list.check(&hosts(values), http_1_0) {
+      Ok(()) => 200,
+      Err(Refused::Missing) => 400,
+      Err(Refused::NotAllowed(_)) => 421,
+    }
+  }
+
+  #[test]
+  fn the_port_is_left_out() {
+    let list = allowed(&["example.com", "::1", "127.0.0.1"]);
+    for host in [
+      "example.com",
+      "example.com:8080",
+      "EXAMPLE.com:443",
+      "example.com.",
+      "example.com.:80",
+      "[::1]",
+      "[::1]:8080",
+      "127.0.0.1:3000",
+    ] {
+      assert_eq!(status(&list, &[host], false), 200, "{}", host);
+    }
+    for host in [
+      "example.org:8080",
+      "example.com.evil.org",
+      "[::2]:8080",
+      "127.0.0.2:3000",
+    ] {
+      assert_eq!(status(&list, &[host], false), 421, "{}", host);
+    }
+    match list.check(&hosts(&["evil.org:8080"]), false) {
+      Err(Refused::NotAllowed(host)) => assert_eq!(host, "evil.org:8080"),
+      _ => panic!("evil.org was allowed"),
+    }
+  }
+
+  #[test]
+  fn a_wildcard_matches_one_label() {
+    let list = allowed(&["*.example.com"]);
+    for host in ["www.example.com", "API.Example.com:8443"] {
+      assert_eq!(status(&list, &[host], false), 200, "{}", host);
+    }
+    for host in [
+      "example.com",
+      "a.b.example.com",
+      ".example.com",
+      "wwwexample.com",
+    ] {
+      assert_eq!(status(&list, &[host], false), 421, "{}", host);
+    }
+  }
+
+  #[test]
+  fn a_missing_host_is_refused() {
+    let list = allowed(&["example.com"]);
+    assert_eq!(status(&list, &[], false), 400);
+    assert_eq!(status(&list, &[""], false), 400);
+    assert_eq!(status(&list, &["example.com", "example.com"], false), 400);
+    // HTTP/1.0 doesn't require one.
+    assert_eq!(status(&list, &[], true), 200);
+    assert_eq!(status(&list, &[""], true), 400);
+    // Without a list nothing is checked.
+    assert_eq!(status(&AllowedHosts::default(), &[], false), 200);
+  }
+
+  #[test]
+  fn bad_entries_are_refused() {
+    for (entry, expected) in [
+      ("", "is empty"),
+      ("*.", "is empty"),
+      ("www.*.example.com", "may only use a wildcard"),
+      ("*example.com", "may only use a wildcard"),
+      (
+        "example.com:8080",
+        "must be a host name without a scheme, path, or port",
+      ),
+      (
+        "https://example.com",
+        "must be a host name without a scheme, path, or port",
+      ),
+    ] {
+      let error = AllowedHosts::parse(&[entry.to_string()]).unwrap_err();
+      assert!(error.starts_with("CONFIG.allowed_hosts entry"), "{}", error);
+      assert!(error.contains(expected), "{:?}: {}", entry, error);
+    }
+  }
+
+  #[test]
+  fn refused_hosts_are_answered_421_and_missing_ones_400() {
+    let fixture = Fixture::new(
+      r#"
+        CONFIG = { allowed_hosts = { "example.com", "*.example.com" } }
+        router.add("/hello", "hello.lua")
+      "#,
+      &[(
+        "hello.lua",
+        r#"return { handler = function(request, response) response.body = "hello" end }"#,
+      )],
+    );
+    let server = fixture
+      .builder()
+      .addr("127.0.0.1:0")
+      .workers(1)
+      .load()
+      .unwrap();
+    let addr = server.serve().unwrap().remove(0);
+    let send = |head: &str| {
+      let mut stream = TcpStream::connect(&addr).unwrap();
+      write!(stream, "GET /hello {}\r\nConnection: close\r\n\r\n", head).unwrap();
+      let mut response = String::new();
+      stream.read_to_string(&mut response).unwrap();
+      response
+    };
+
+    for (head, expected) in [
+      ("HTTP/1.1\r\nHost: www.example.com:8080", "HTTP/1.1 200"),
+      ("HTTP/1.1\r\nHost: evil.org", "HTTP/1.1 421"),
+      ("HTTP/1.1\r\nHost: a.b.example.com", "HTTP/1.1 421"),
+      (
+        "HTTP/1.1\r\nHost: example.com\r\nHost: evil.org",
+        "HTTP/1.1 400",
+      ),
+    ] {
+      let response = send(head);
+      assert!(response.starts_with(expected), "{:?}: {}", head, response);
+    }
+    let response = send("HTTP/1.1\r\nHost: evil.org");
+    assert!(
+      response.ends_with("\r\n\r\n421 Misdirected Request"),
+      "{}",
+      response
+    );
+    server.shutdown();
+  }
+}
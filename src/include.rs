@@ -0,0 +1,270 @@
+//! # `include()`
+//!
+//! Splits the configuration across files, so a shared `config.lua` can
+//! pull in local overrides without them being clobbered when it is
+//! replaced:
+//!
+//! ```lua
+//! CONFIG = { addr = "0.0.0.0:8000", workers = 4 }
+//! router.add("/", "index.lua")
+//! include("conf.d/*.lua")
+//! ```
+//!
+//! Each matching file is run in the same Lua state as `config.lua`, in
+//! sorted order, so it can call `router.add` and the other functions and
+//! set settings. Paths are relative to the config file's directory. `*`
+//! and `?` may be used in the file name (not in directory names) and don't
+//! match a leading `.`; a pattern that matches nothing is fine, but a file
+//! named without wildcards must exist. Included files may include others.
+//!
+//! A file that assigns `CONFIG = { ... }` only overrides the keys it sets:
+//! its table is merged into the one set before it (see `settings::merge`),
+//! so later files win over earlier ones.
+
+use mlua::prelude::*;
+use std::cell::RefCell;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use std::sync::{Arc, Mutex};
+
+use crate::{locks, settings};
+
+/// Creates the `include(pattern)` function for `config.lua`. The files it
+/// runs are added to `included` in order.
+///
+/// # Errors
+///
+/// This function will return a `LuaError` if the function can't be
+/// created.
+pub fn config_global(
+  lua: &Lua,
+  base: PathBuf,
+  included: Arc<Mutex<Vec<PathBuf>>>,
+) -> LuaResult<LuaFunction> {
+  // The files being run, innermost last, to catch a file including itself.
+  let running: Rc<RefCell<Vec<PathBuf>>> = Rc::default();
+  lua.create_function(move |lua, pattern: String| {
+    let files = expand(&pattern, &base)
+      .map_err(|e| LuaError::external(format!("include({:?}): {}", pattern, e)))?;
+    for file in files {
+      let file = fs::canonicalize(&file).unwrap_or(file);
+      if running.borrow().contains(&file) {
+        return Err(LuaError::external(format!(
+          "include({:?}): {} includes itself",
+          pattern,
+          file.display()
+        )));
+      }
+      let code = fs::read_to_string(&file)
+        .map_err(|e| LuaError::external(format!("Failed to read {}: {}", file.display(), e)))?;
+      locks::lock(&included, "included files").push(file.clone());
+
+      let globals = lua.globals();
+      let before = globals.raw_get::<Option<LuaTable>>("CONFIG").ok().flatten();
+      running.borrow_mut().push(file.clone());
+      let result = lua.load(&code).set_name(file.display().to_string()).exec();
+      running.borrow_mut().pop();
+      result?;
+
+      if let (Some(before), LuaValue::Table(after)) =
+        (before, globals.raw_get::<LuaValue>("CONFIG")?)
+      {
+        if before != after {
+          settings::merge(&before, &after)?;
+          globals.raw_set("CONFIG", before)?;
+        }
+      }
+    }
+    Ok(())
+  })
+}
+
+/// Returns the files `pattern` names, relative to `base`, sorted.
+///
+/// # Errors
+///
+/// Returns an error message if a file named without wildcards doesn't
+/// exist, a directory name has a wildcard, or the directory can't be read.
+fn expand(pattern: &str, base: &Path) -> Result<Vec<PathBuf>, String> {
+  let path = base.join(pattern);
+  let (Some(dir), Some(name)) = (path.parent(), path.file_name().and_then(|n| n.to_str())) else {
+    return Err("not a file name".to_string());
+  };
+  if dir.to_string_lossy().contains(['*', '?']) {
+    return Err("wildcards are only supported in the file name".to_string());
+  }
+  if !name.contains(['*', '?']) {
+    if !path.is_file() {
+      return Err(format!("{} doesn't exist", path.display()));
+    }
+    return Ok(vec![path]);
+  }
+
+  let entries = match fs::read_dir(dir) {
+    Ok(entries) => entries,
+    Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+    Err(e) => return Err(format!("Failed to list {}: {}", dir.display(), e)),
+  };
+  let mut files: Vec<PathBuf> = entries
+    .filter_map(Result::ok)
+    .filter(|entry| {
+      entry.file_name().to_str().is_some_and(|entry_name| {
+        !entry_name.starts_with('.') && matches(name.as_bytes(), entry_name.as_bytes())
+      })
+    })
+    .map(|entry| entry.path())
+    .filter(|path| path.is_file())
+    .collect();
+  files.sort();
+  Ok(files)
+}
+
+/// Whether `name` matches `pattern`, where `*` matches any run of
+/// characters and `?` any one.
+fn matches(pattern: &[u8], name: &[u8]) -> bool {
+  match (pattern.split_first(), name.split_first()) {
+    (None, None) => true,
+    (Some((b'*', rest)), _) => {
+      matches(rest, name)
+        || name
+          .split_first()
+          .is_some_and(|(_, name)| matches(pattern, name))
+    }
+    (Some((b'?', rest)), Some((_, name))) => matches(rest, name),
+    (Some((p, rest)), Some((n, name))) if p == n => matches(rest, name),
+    _ => false,
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::testing::Fixture;
+
+  /// Runs `config` with `include` set up for the fixture's directory,
+  /// returning the Lua state and the files included.
+  fn run(fixture: &Fixture, config: &str) -> (Lua, LuaResult<()>, Vec<PathBuf>) {
+    let lua = Lua::new();
+    let included = Arc::new(Mutex::new(Vec::new()));
+    let include = config_global(&lua, fixture.path().to_path_buf(), included.clone()).unwrap();
+    lua.globals().set("include", include).unwrap();
+    let result = lua.load(config).exec();
+    let included = included.lock().unwrap().clone();
+    (lua, result, included)
+  }
+
+  fn write(fixture: &Fixture, name: &str, code: &str) {
+    let path = fixture.path().join(name);
+    fs::create_dir_all(path.parent().unwrap()).unwrap();
+    fs::write(path, code).unwrap();
+  }
+
+  #[test]
+  fn wildcards_match_file_names() {
+    for (pattern, name, expected) in [
+      ("*.lua", "local.lua", true),
+      ("*.lua", ".lua", true),
+      ("*.lua", "local.lua.bak", false),
+      ("?0-*.lua", "10-db.lua", true),
+      ("?0-*.lua", "0-db.lua", false),
+      ("db.lua", "db.lua", true),
+      ("db.lua", "dbxlua", false),
+    ] {
+      assert_eq!(matches(pattern.as_bytes(), name.as_bytes()), expected, "{} {}", pattern, name);
+    }
+  }
+
+  #[test]
+  fn files_run_in_order_and_merge_config() {
+    let fixture = Fixture::new("", &[]);
+    write(
+      &fixture,
+      "conf.d/20-limits.lua",
+      r#"CONFIG = { limits = { max_body_bytes = 2048 } }"#,
+    );
+    write(
+      &fixture,
+      "conf.d/10-workers.lua",
+      r#"CONFIG = { workers = 8, limits = { max_body_bytes = 1024, max_headers = 50 } }"#,
+    );
+    write(
+      &fixture,
+      "conf.d/.hidden.lua",
+      "error('hidden files are skipped')",
+    );
+    write(
+      &fixture,
+      "conf.d/notes.txt",
+      "error('other names are skipped')",
+    );
+    let (lua, result, included) = run(
+      &fixture,
+      r#"
+        CONFIG = { addr = "127.0.0.1:8000", workers = 2 }
+        include("conf.d/*.lua")
+        include("missing.d/*.lua")
+      "#,
+    );
+    result.unwrap();
+    let names: Vec<_> = included
+      .iter()
+      .map(|f| f.file_name().unwrap().to_owned())
+      .collect();
+    assert_eq!(names, ["10-workers.lua", "20-limits.lua"]);
+
+    let config: LuaTable = lua.globals().get("CONFIG").unwrap();
+    assert_eq!(config.get::<String>("addr").unwrap(), "127.0.0.1:8000");
+    assert_eq!(config.get::<i64>("workers").unwrap(), 8);
+    let limits: LuaTable = config.get("limits").unwrap();
+    assert_eq!(limits.get::<i64>("max_body_bytes").unwrap(), 2048);
+    assert_eq!(limits.get::<i64>("max_headers").unwrap(), 50);
+  }
+
+  #[test]
+  fn a_file_including_itself_is_refused() {
+    let fixture = Fixture::new("", &[]);
+    write(&fixture, "a.lua", r#"include("b.lua")"#);
+    write(&fixture, "b.lua", r#"include("a.lua")"#);
+    write(&fixture, "self.lua", r#"include("self.lua")"#);
+    for (config, file) in [
+      (r#"include("a.lua")"#, "a.lua"),
+      (r#"include("self.lua")"#, "self.lua"),
+    ] {
+      let (_, result, _) = run(&fixture, config);
+      let error = result.unwrap_err().to_string();
+      let expected = format!(
+        "{} includes itself",
+        fs::canonicalize(fixture.path().join(file))
+          .unwrap()
+          .display()
+      );
+      assert!(error.contains(&expected), "{}", error);
+    }
+    // A file may be included twice, just not inside itself.
+    write(&fixture, "c.lua", r#"include("shared.lua")"#);
+    write(&fixture, "d.lua", r#"include("shared.lua")"#);
+    write(&fixture, "shared.lua", "SHARED = (SHARED or 0) + 1");
+    let (lua, result, included) = run(&fixture, r#"include("c.lua") include("d.lua")"#);
+    result.unwrap();
+    assert_eq!(included.len(), 4);
+    assert_eq!(lua.globals().get::<i64>("SHARED").unwrap(), 2);
+  }
+
+  #[test]
+  fn bad_patterns_are_refused() {
+    let fixture = Fixture::new("", &[]);
+    for (pattern, expected) in [
+      ("missing.lua", "missing.lua doesn't exist"),
+      (
+        "conf.*/local.lua",
+        "wildcards are only supported in the file name",
+      ),
+    ] {
+      let (_, result, _) = run(&fixture, &format!("include({:?})", pattern));
+      let error = result.unwrap_err().to_string();
+      assert!(error.contains(expected), "{}: {}", pattern, error);
+    }
+  }
+}
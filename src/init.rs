@@ -0,0 +1,131 @@
+//! # `fyre init`
+//!
+//! Creates a new project, so the `config.lua` and `scripts/` layout doesn't
+//! have to be explained by hand:
+//!
+//! ```text
+//! fyre init [dir] [--template api|site] [--force]
+//! ```
+//!
+//! It writes `config.lua` with an example route, `scripts/hello.lua`
+//! showing the middleware, handler, and response hook stages, a `static/`
+//! directory, and a `.gitignore`. The `api` template (the default) answers
+//! with JSON, the `site` template with an HTML page and a stylesheet.
+//! Existing files are never overwritten unless `--force` is given; without
+//! it, nothing is written if any of them exists.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::cli;
+
+/// The `fyre init` command line.
+#[derive(Debug, clap::Args)]
+pub struct InitArgs {
+  /// The directory to create the project in.
+  #[arg(default_value = ".")]
+  dir: PathBuf,
+  /// The kind of example to start from.
+  #[arg(short, long, value_enum, default_value_t = Template::Api)]
+  template: Template,
+  /// Overwrites files that already exist.
+  #[arg(long)]
+  force: bool,
+}
+
+#[derive(Debug, Clone, Copy, clap::ValueEnum)]
+enum Template {
+  /// A JSON API.
+  Api,
+  /// An HTML site with static assets.
+  Site,
+}
+
+const GITIGNORE: &str = include_str!("../templates/common/gitignore");
+
+impl Template {
+  /// The files to create, as paths relative to the project directory and
+  /// their contents.
+  fn files(self) -> Vec<(String, &'static str)> {
+    let scripts = cli::DEFAULT_SCRIPTS_DIR;
+    let mut files = vec![(".gitignore".to_string(), GITIGNORE)];
+    match self {
+      Template::Api => files.extend([
+        (
+          cli::DEFAULT_CONFIG_FILE.to_string(),
+          include_str!("../templates/api/config.lua"),
+        ),
+        (
+          format!("{}/hello.lua", scripts),
+          include_str!("../templates/api/hello.lua"),
+        ),
+        ("static/.gitkeep".to_string(), ""),
+      ]),
+      Template::Site => files.extend([
+        (
+          cli::DEFAULT_CONFIG_FILE.to_string(),
+          include_str!("../templates/site/config.lua"),
+        ),
+        (
+          format!("{}/hello.lua", scripts),
+          include_str!("../templates/site/hello.lua"),
+        ),
+        (
+          "static/style.css".to_string(),
+          include_str!("../templates/site/style.css"),
+        ),
+      ]),
+    }
+    files
+  }
+}
+
+/// Creates the project, for `fyre init`.
+///
+/// # Errors
+///
+/// This function will return an error if one of the files already exists
+/// and `--force` wasn't given, in which case nothing is written, or if a
+/// file or directory can't be created.
+pub fn run(args: InitArgs) -> Result<(), Box<dyn std::error::Error>> {
+  let files: Vec<(PathBuf, &str)> = args
+    .template
+    .files()
+    .into_iter()
+    .map(|(path, contents)| (args.dir.join(path), contents))
+    .collect();
+
+  if !args.force {
+    let existing: Vec<String> = files
+      .iter()
+      .filter(|(path, _)| path.exists())
+      .map(|(path, _)| path.display().to_string())
+      .collect();
+    if !existing.is_empty() {
+      return Err(
+        format!(
+          "Not overwriting {} (use --force to replace them)",
+          existing.join(", ")
+        )
+        .into(),
+      );
+    }
+  }
+
+  for (path, contents) in &files {
+    write(path, contents)?;
+    info!("Created {}", path.display());
+  }
+  info!(
+    "Start the server with: fyre --config {}",
+    args.dir.join(cli::DEFAULT_CONFIG_FILE).display()
+  );
+  Ok(())
+}
+
+fn write(path: &Path, contents: &str) -> Result<(), String> {
+  if let Some(dir) = path.parent() {
+    fs::create_dir_all(dir).map_err(|e| format!("Failed to create {}: {}", dir.display(), e))?;
+  }
+  fs::write(path, contents).map_err(|e| format!("Failed to write {}: {}", path.display(), e))
+}
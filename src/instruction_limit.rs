@@ -0,0 +1,196 @@
+//! # Instruction Limit
+//!
+//! With `CONFIG.lua.instruction_limit` set, or a route's `instruction_limit`
+//! option, each run of a handler's pipeline may execute that many Lua VM
+//! instructions. A script going over it is stopped with an error, the
+//! request is answered with `500`, and the route and script are logged.
+//! Unlike a timeout, the budget doesn't depend on how busy the machine is,
+//! so a script that passes once passes every time with the same input.
+//!
+//! Instructions are counted by a VM hook called every
+//! `SAMPLE_INSTRUCTIONS`, so the count is rounded up to a multiple of that
+//! and handlers run at close to full speed. Once the budget is spent the
+//! hook is called on every instruction, so a script catching the error
+//! with `pcall` is stopped again straight away. Time spent inside Rust
+//! functions (`fyre.http` waiting for a reply, say) isn't counted.
+
+use mlua::prelude::*;
+use mlua::{HookTriggers, VmState};
+use std::fmt;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+
+/// How many instructions run between two calls of the counting hook.
+pub const SAMPLE_INSTRUCTIONS: u32 = 1000;
+
+/// The error a script going over its budget is stopped with.
+#[derive(Debug)]
+pub struct Exceeded {
+  pub limit: u64,
+}
+
+impl fmt::Display for Exceeded {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(f, "instruction limit of {} exceeded", self.limit)
+  }
+}
+
+impl std::error::Error for Exceeded {}
+
+/// Reads a route's `instruction_limit` option.
+///
+/// # Errors
+///
+/// This function will return a `LuaError` if it is not a positive whole
+/// number.
+pub fn from_route_options(path: &str, opts: &LuaTable) -> LuaResult<Option<u64>> {
+  match opts.get::<Option<u64>>("instruction_limit")? {
+    Some(0) => Err(LuaError::external(format!(
+      "router.add('{}'): instruction_limit must be at least 1",
+      path
+    ))),
+    limit => Ok(limit),
+  }
+}
+
+/// Counts the instructions one pipeline run executes.
+pub struct Budget {
+  limit: u64,
+  exceeded: Arc<AtomicBool>,
+}
+
+impl Budget {
+  /// Starts counting on `lua` against `limit`, until `finish`.
+  ///
+  /// # Errors
+  ///
+  /// This function will return a `LuaError` if the hook can't be set.
+  pub fn start(lua: &Lua, limit: u64) -> LuaResult<Budget> {
+    let exceeded = Arc::new(AtomicBool::new(false));
+    let used = AtomicU64::new(0);
+    let flag = exceeded.clone();
+    lua.set_hook(
+      HookTriggers::new().every_nth_instruction(SAMPLE_INSTRUCTIONS),
+      move |lua, _| {
+        let used = used.fetch_add(u64::from(SAMPLE_INSTRUCTIONS), Ordering::Relaxed)
+          + u64::from(SAMPLE_INSTRUCTIONS);
+        if used <= limit {
+          return Ok(VmState::Continue);
+        }
+        if !flag.swap(true, Ordering::Relaxed) {
+          // From now on every instruction fails, wherever a `pcall` puts
+          // the script.
+          lua.set_hook(HookTriggers::new().every_nth_instruction(1), move |_, _| {
+            Err(LuaError::external(Exceeded { limit }))
+          })?;
+        }
+        Err(LuaError::external(Exceeded { limit }))
+      },
+    )?;
+    Ok(Budget { limit, exceeded })
+  }
+
+  /// Stops counting. Returns the error for the run if it went over.
+  pub fn finish(self, lua: &Lua) -> Option<Exceeded> {
+    lua.remove_hook();
+    self
+      .exceeded
+      .load(Ordering::Relaxed)
+      .then_some(Exceeded { limit: self.limit })
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::testing::{self, Fixture};
+
+  #[test]
+  fn a_budget_stops_a_busy_loop_even_under_pcall() {
+    let lua = Lua::new();
+    for script in [
+      "while true do end",
+      "pcall(function() while true do end end) while true do end",
+    ] {
+      let budget = Budget::start(&lua, 100_000).unwrap();
+      let error = lua.load(script).exec().unwrap_err().to_string();
+      assert!(
+        error.contains("instruction limit of 100000 exceeded"),
+        "{}",
+        error
+      );
+      assert_eq!(budget.finish(&lua).unwrap().limit, 100_000);
+    }
+
+    // A script under the budget isn't touched, and nothing is counted
+    // once the budget is finished.
+    let budget = Budget::start(&lua, 100_000).unwrap();
+    let sum: u64 = lua
+      .load("local n = 0 for i = 1, 1000 do n = n + i end return n")
+      .eval()
+      .unwrap();
+    assert_eq!(sum, 500_500);
+    assert!(budget.finish(&lua).is_none());
+    lua
+      .load("local n = 0 for i = 1, 1000000 do n = n + i end")
+      .exec()
+      .unwrap();
+  }
+
+  #[test]
+  fn a_runaway_handler_is_stopped_and_a_heavy_one_completes() {
+    let heavy = r#"
+      return {
+        handler = function(request, response)
+          local numbers = {}
+          for i = 1, 20000 do
+            numbers[i] = (i * 7919) % 20011
+          end
+          table.sort(numbers)
+          response.body = tostring(numbers[1]) .. " " .. tostring(numbers[20000])
+        end,
+      }
+    "#;
+    let fixture = Fixture::new(
+      r#"
+        CONFIG = { lua = { instruction_limit = 5000000 } }
+        router.add("/spin", "spin.lua")
+        router.add("/heavy", "heavy.lua")
+        router.add("/capped", "heavy.lua", { instruction_limit = 10000 })
+      "#,
+      &[
+        (
+          "spin.lua",
+          "return { handler = function() while true do end end }",
+        ),
+        ("heavy.lua", heavy),
+      ],
+    );
+    let server = fixture
+      .builder()
+      .addr("127.0.0.1:0")
+      .workers(1)
+      .load()
+      .unwrap();
+    let addr = server.serve().unwrap().remove(0);
+
+    let spin = testing::get(&addr, "/spin");
+    assert!(spin.starts_with("HTTP/1.1 500"), "{}", spin);
+    assert!(
+      spin.ends_with("handler exceeded its limit of 5000000 instructions"),
+      "{}",
+      spin
+    );
+    // The same worker goes on to run a handler well within the budget.
+    let heavy = testing::get(&addr, "/heavy");
+    assert!(heavy.ends_with("\r\n\r\n1 20010"), "{}", heavy);
+    // A route's own limit overrides the global one.
+    let capped = testing::get(&addr, "/capped");
+    assert!(
+      capped.ends_with("handler exceeded its limit of 10000 instructions"),
+      "{}",
+      capped
+    );
+    server.shutdown();
+  }
+}
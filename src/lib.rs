@@ -0,0 +1,3839 @@
+//! # A Lightweight, Scriptable Rust HTTP Server
+//!
+//! Fyre combines the performance of a compiled Rust core with the
+//! dynamic flexibility of Lua scripting for all endpoint logic.
+//! This file contains the main server logic, configuration loading,
+//! and the Lua pipeline execution. The `fyre` command (`main.rs`) only
+//! parses its arguments and calls `run`; other programs can embed the
+//! server with `FyreServer` (see `embed`).
+
+use std::cell::{Cell, RefCell};
+use std::collections::HashMap;
+use std::fs;
+use std::fmt;
+use std::sync::mpsc::RecvTimeoutError;
+use std::sync::{Arc, Mutex, OnceLock};
+
+// Importing necessary mlua types.
+use mlua::prelude::*; // Brings LuaTable, LuaFunction, etc. into scope
+use mlua::{Error as LuaError, Lua}; // Only imports what is available in the root mlua module
+use std::io::{Cursor, Read};
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+
+use arc_swap::ArcSwap;
+use tiny_http::{Header, Response, StatusCode};
+
+#[macro_use]
+mod logger;
+mod access;
+mod admin;
+mod audit;
+mod auth;
+mod bench;
+mod body;
+mod check;
+mod client_limit;
+mod content_types;
+pub mod cli;
+mod daemon;
+mod debug_dump;
+mod embed;
+mod error_reports;
+mod fyre;
+mod health;
+mod hosts;
+mod include;
+mod init;
+mod instruction_limit;
+mod limiter;
+mod locks;
+mod lua_pool;
+mod net;
+mod panics;
+mod paths;
+mod prefix_tree;
+mod privileges;
+mod request_log;
+mod request_metrics;
+mod route_stats;
+mod route_summary;
+mod schedule;
+mod script_cache;
+mod secrets;
+mod security_headers;
+mod server;
+mod settings;
+mod shutdown;
+mod slow_log;
+mod span_export;
+mod statics;
+mod systemd;
+#[cfg(test)]
+mod testing;
+mod tls;
+mod trace;
+mod worker_stats;
+
+pub use embed::{
+  Builder, Error, FyreServer, RouteInfo, RouteSnapshot, SyntheticRequest, SyntheticResponse,
+};
+
+/// The server's version, also `fyre.version` in scripts.
+pub const VERSION: &str = env!("CARGO_PKG_VERSION");
+
+/// The routes and static directories.
+///
+/// The keys of `handlers` are the routes and the values are the Lua scripts
+/// that handle them. `mounts` are the `router.static` directories, longest
+/// prefix first, which serve requests no route matches. `protected` are the
+/// `router.protect` rules, most specific first.
+///
+/// Routes are exact paths, so a lookup is one hash of the request path and
+/// its cost doesn't grow with the number of routes. The mounts and rules
+/// cover the paths under theirs too, so they are looked up in a tree over
+/// path segments built by `index` (see `prefix_tree`), whose cost depends
+/// on the depth of the request path rather than on how many there are.
+#[derive(Default)]
+struct RouteTable {
+  handlers: HashMap<String, Route>,
+  mounts: Vec<statics::Mount>,
+  protected: Vec<auth::Protected>,
+  /// The strategies in `CONFIG.auth`, by name, for `require_auth`.
+  strategies: HashMap<String, auth::Strategy>,
+  /// The positions in `mounts` by prefix.
+  mount_index: prefix_tree::PrefixTree<usize>,
+  /// The positions in `protected` by path.
+  protect_index: prefix_tree::PrefixTree<usize>,
+}
+
+impl RouteTable {
+  /// Builds the lookup trees over `mounts` and `protected`, once they are
+  /// complete.
+  fn index(&mut self) {
+    self.mount_index = prefix_tree::PrefixTree::default();
+    for (i, mount) in self.mounts.iter().enumerate() {
+      self.mount_index.insert(&mount.prefix, true, i);
+    }
+    self.protect_index = prefix_tree::PrefixTree::default();
+    for (i, rule) in self.protected.iter().enumerate() {
+      self.protect_index.insert(&rule.path, rule.prefix, i);
+    }
+  }
+
+  /// Finds the mount serving `url` and the path below it. The most
+  /// specific mount wins.
+  fn find_mount<'a>(&'a self, url: &'a str) -> Option<(&'a statics::Mount, &'a str)> {
+    let path = url.split(['?', '#']).next().unwrap_or(url);
+    let mount = &self.mounts[*self.mount_index.find(path)?];
+    Some((mount, &path[mount.prefix.len()..]))
+  }
+
+  /// Returns the credentials a request for `url` needs: its route's own
+  /// `auth` or `require_auth` if it has one, or else the most specific
+  /// `router.protect` rule covering it.
+  fn auth_for(&self, url: &str) -> Option<auth::Required<'_>> {
+    if let Some(route) = self.handlers.get(url) {
+      if let Some(auth) = &route.auth {
+        return Some(auth::Required::Basic(auth));
+      }
+      if !route.require_auth.is_empty() {
+        // The names were checked against `CONFIG.auth` when it was loaded.
+        let strategies = route
+          .require_auth
+          .iter()
+          .filter_map(|name| self.strategies.get_key_value(name))
+          .map(|(name, strategy)| (name.as_str(), strategy))
+          .collect();
+        return Some(auth::Required::AnyOf(strategies));
+      }
+    }
+    if self.protect_index.is_empty() {
+      return None;
+    }
+    let path = auth::normalize(url);
+    self
+      .protect_index
+      .find(&path)
+      .map(|&i| auth::Required::Basic(&self.protected[i].auth))
+  }
+}
+
+/// A route added with `router.add`.
+struct Route {
+  /// The handler script path, including the scripts directory.
+  script: String,
+  /// The route's own concurrency limit, if `max_concurrent` was set.
+  limiter: Option<limiter::Limiter>,
+  /// Whether a request must come with a TLS client certificate, from
+  /// `require_client_cert`; one without is answered with `403`.
+  require_client_cert: bool,
+  /// The addresses the route is limited to, from `allow` and `deny`; a
+  /// client outside them is answered with `403`.
+  access: Option<access::AccessList>,
+  /// The route's own per-client rate limit, from `rate_limit`, checked
+  /// after the global one.
+  rate_limit: Option<client_limit::RateLimit>,
+  /// The media types the route takes in request bodies, from
+  /// `accept_types`; a request with another is answered with `415`.
+  accept_types: Option<content_types::AcceptTypes>,
+  /// Whether requests other than `GET`, `HEAD`, and `OPTIONS` must send
+  /// the `fyre.csrf` token, from `csrf`; one without is answered with `403`.
+  csrf: bool,
+  /// The names `fyre.secrets` may read for the route, from `secrets`;
+  /// `None` allows every name.
+  secrets: Option<Arc<[String]>>,
+  /// The credentials the route needs, from `auth`; a request without them
+  /// is answered with `401`.
+  auth: Option<auth::Auth>,
+  /// The names of the `CONFIG.auth` strategies the route accepts, from
+  /// `require_auth`; a request passing none of them is answered with `401`.
+  require_auth: Vec<String>,
+  /// The Lua instructions a run of the route's pipeline may execute, from
+  /// `instruction_limit`; overrides `CONFIG.lua.instruction_limit`.
+  instruction_limit: Option<u64>,
+  /// Why the script failed to compile at startup, with
+  /// `CONFIG.lua.script_check = "lenient"`. Requests to the route are answered with `503` until the
+  /// server is restarted.
+  compile_error: OnceLock<String>,
+  /// The configuration warnings about the route, shown next to it in the
+  /// route summary (see `route_summary`).
+  warnings: Vec<String>,
+}
+
+/// What happens when a handler script fails to compile at startup, from
+/// `CONFIG.lua.script_check`.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+enum ScriptCheck {
+  /// The server refuses to start.
+  #[default]
+  Strict,
+  /// The server starts, and the routes using the script answer `503`.
+  Lenient,
+}
+
+/// What happens when a script sets a response header that would split the
+/// response or isn't valid, from `CONFIG.lua.header_check`.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+enum HeaderCheck {
+  /// The header is logged and left out.
+  #[default]
+  Lenient,
+  /// The handler fails, and the request is answered with `500`.
+  Strict,
+}
+
+/// What happens when one of the server addresses can't be bound at startup,
+/// from `CONFIG.bind_check`.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+enum BindCheck {
+  /// The server refuses to start.
+  #[default]
+  Strict,
+  /// The address is skipped with a warning, as long as one is bound.
+  Lenient,
+}
+
+/// A handler's response. Its body may be read from a Lua string, which can't
+/// leave the worker thread, so unlike a `ResponseBox` it isn't `Send`.
+type HandlerResponse = Response<Box<dyn Read>>;
+
+/// A type alias for the shared, swappable route table.
+///
+/// Requests read a snapshot with `load()` without taking a lock. The table is
+/// never mutated in place; loading the configuration builds a whole new
+/// table and `store`s it, so a request sees either the old routes or the new
+/// ones, never a mix.
+type RoutesMap = Arc<ArcSwap<RouteTable>>;
+
+/// Settings read from `config.lua` by `load_lua_config`.
+#[derive(Debug, Default)]
+struct Config {
+  /// The server addresses from `CONFIG.addr` or `CONFIG.addrs`; empty if
+  /// neither is set.
+  server_addrs: Vec<String>,
+  /// What happens when a server address can't be bound, from
+  /// `CONFIG.bind_check`.
+  bind_check: BindCheck,
+  /// The hosts `fyre.http` may contact, from `CONFIG.sandbox.http_allow`.
+  /// `None` leaves outbound requests unrestricted.
+  http_allow: Option<Vec<String>>,
+  /// Whether a host in `http_allow` may resolve to an internal address,
+  /// from `CONFIG.sandbox.http_allow_private`.
+  http_allow_private: bool,
+  /// The environment variable names and prefixes `fyre.env` may read, from
+  /// `CONFIG.sandbox.env_allow`.
+  env_allowlist: Vec<String>,
+  /// The maximum number of entries in the `fyre.kv` store, from
+  /// `CONFIG.kv.max_entries`.
+  kv_max_entries: Option<usize>,
+  /// The maximum number of entries in `fyre.cache`, from
+  /// `CONFIG.cache.max_entries`.
+  cache_max_entries: Option<usize>,
+  /// The directory `fyre.sqlite` databases live in, from `CONFIG.sqlite.dir`.
+  sqlite_dir: Option<String>,
+  /// The directories `fyre.fs` may access, from `CONFIG.sandbox.fs_allow`.
+  fs_allow: Vec<String>,
+  /// The largest file `fyre.fs.read` will load, from
+  /// `CONFIG.sandbox.fs_max_read_bytes`.
+  fs_max_read_bytes: Option<u64>,
+  /// The `fyre.session` settings, present when `CONFIG.session.secret` is
+  /// set.
+  session: Option<fyre::session::SessionConfig>,
+  /// The keys `fyre.cookie` signs with, from `CONFIG.keys`.
+  cookie_keys: Option<fyre::cookie::CookieKeys>,
+  /// The default `fyre.redis` server, from `CONFIG.redis.url`.
+  redis_url: Option<String>,
+  /// The idle connections kept per Redis server, from `CONFIG.redis.pool_size`.
+  redis_pool_size: Option<usize>,
+  /// The `fyre.redis` connect, read, and write timeout in milliseconds, from
+  /// `CONFIG.redis.timeout_ms`.
+  redis_timeout_ms: Option<u64>,
+  /// The programs `fyre.exec` may run, from `CONFIG.sandbox.exec_allow`.
+  exec_allow: Vec<String>,
+  /// The background workers declared with `queue.worker`.
+  queue_workers: Vec<fyre::queue::WorkerSpec>,
+  /// The directory `fyre.queue` snapshots are kept in, from `CONFIG.queue.dir`.
+  queue_dir: Option<String>,
+  /// The tasks declared with `schedule.every` and `schedule.cron`.
+  schedules: Vec<schedule::Task>,
+  /// The label combinations kept per `fyre.metrics` metric, from
+  /// `CONFIG.metrics.max_series`.
+  metrics_max_series: Option<usize>,
+  /// The `fyre.mail` settings, present when `CONFIG.smtp.host` is set.
+  smtp: Option<fyre::mail::SmtpConfig>,
+  /// The number of request-handling threads, from `CONFIG.workers`.
+  workers: Option<usize>,
+  /// The requests each worker's Lua state serves before it is rebuilt, from
+  /// `CONFIG.lua.state_max_uses`.
+  lua_state_max_uses: Option<u32>,
+  /// The Lua instructions a run of a handler's pipeline may execute, from
+  /// `CONFIG.lua.instruction_limit`.
+  instruction_limit: Option<u64>,
+  /// The listening socket options, from `CONFIG.socket.nodelay`,
+  /// `CONFIG.socket.backlog`, `CONFIG.socket.recv_buffer`,
+  /// `CONFIG.socket.send_buffer`, `CONFIG.socket.unix_mode`,
+  /// `CONFIG.bind.reuse_addr`, `CONFIG.bind.reuse_port`, and
+  /// `CONFIG.bind.retry`.
+  socket: net::SocketOptions,
+  /// The connection and request head limits, from `CONFIG.limits.connections`,
+  /// `CONFIG.limits.requests_per_connection`, `CONFIG.limits.url_bytes`,
+  /// `CONFIG.limits.headers`, `CONFIG.limits.header_bytes`,
+  /// `CONFIG.limits.header_total_bytes`, `CONFIG.http.keep_alive`, and
+  /// `CONFIG.http.version_compat`, and the timeouts, from
+  /// `CONFIG.limits.keep_alive_timeout_ms`, `CONFIG.timeouts.header_read_ms`,
+  /// `CONFIG.timeouts.body_read_ms`, and `CONFIG.timeouts.write_ms`, and the
+  /// `CONFIG.timeouts.header_deadline_ms` deadline.
+  connections: server::Limits,
+  /// The limit on requests running their handler at once, from
+  /// `CONFIG.limits.in_flight`, `CONFIG.limits.in_flight_queue`, and
+  /// `CONFIG.limits.in_flight_queue_timeout_ms`.
+  in_flight: Option<limiter::Limit>,
+  /// The addresses every request is checked against, from `CONFIG.access.allow`
+  /// and `CONFIG.access.deny`.
+  access: access::AccessList,
+  /// Whether allowed requests are logged with the entry they matched, from
+  /// `CONFIG.access.log`.
+  access_log: bool,
+  /// The host names requests may be for, from `CONFIG.allowed_hosts`.
+  allowed_hosts: hosts::AllowedHosts,
+  /// The per-client request rate limit, from `CONFIG.rate_limit`.
+  rate_limit: Option<client_limit::RateLimit>,
+  /// Where large request bodies are written, from `CONFIG.body.spill_bytes` and
+  /// `CONFIG.body.spill_dir`, and the largest accepted, from
+  /// `CONFIG.limits.body`.
+  body_spill: body::SpillOptions,
+  /// Whether handler scripts are loaded from cached bytecode, from
+  /// `CONFIG.lua.bytecode_cache`.
+  bytecode_cache: bool,
+  /// The directory compiled handler scripts are kept in, from
+  /// `CONFIG.lua.bytecode_cache_dir`.
+  bytecode_cache_dir: Option<String>,
+  /// The compiled scripts kept in memory, from `CONFIG.lua.cache_max_bytes`.
+  cache_max_bytes: Option<usize>,
+  /// The number of files `router.static` mounts keep mapped, from
+  /// `CONFIG.static.mmap_entries`.
+  static_mmap_entries: Option<usize>,
+  /// The largest file served from a mapping, from
+  /// `CONFIG.static.mmap_max_bytes`.
+  static_mmap_max_bytes: Option<u64>,
+  /// What to do when a handler script fails to compile at startup, from
+  /// `CONFIG.lua.script_check`.
+  script_check: ScriptCheck,
+  /// What to do with an invalid response header, from
+  /// `CONFIG.lua.header_check`.
+  header_check: HeaderCheck,
+  /// How long running requests may take to finish at shutdown, from
+  /// `CONFIG.shutdown.grace_ms`.
+  shutdown_grace_ms: Option<u64>,
+  /// The script run at shutdown, from `CONFIG.shutdown.script`.
+  on_shutdown: Option<String>,
+  /// The handler time past which a request is logged, from
+  /// `CONFIG.log.slow_request_ms`.
+  slow_request_ms: Option<u64>,
+  /// The certificate and key to serve HTTPS with, from `CONFIG.tls`.
+  tls: Option<tls::TlsSettings>,
+  /// The file the process id is written to, from `CONFIG.pid_file`.
+  pid_file: Option<PathBuf>,
+  /// The file the server's output is appended to, from `CONFIG.log.file`.
+  log_file: Option<PathBuf>,
+  /// When the log file is rotated, from `CONFIG.log.rotate`.
+  log_rotate: Option<logger::Rotate>,
+  /// How log lines are written, from `CONFIG.log.format`.
+  log_format: logger::Format,
+  /// Which log lines are written, from `CONFIG.log.level`.
+  log_level: Option<logger::Filter>,
+  /// The account to switch to once the addresses are bound, from
+  /// `CONFIG.run_as`.
+  run_as: Option<privileges::RunAs>,
+  /// The health probes, from `CONFIG.health`.
+  health: health::HealthSettings,
+  /// The request log, from `CONFIG.log.requests` and
+  /// `CONFIG.log.requests_exclude`; `None` when it is off.
+  request_log: Option<request_log::RequestLog>,
+  /// Where the Prometheus endpoint is served, from `CONFIG.metrics.path`,
+  /// `CONFIG.metrics.listener`, and `CONFIG.metrics.count_self`.
+  metrics_endpoint: request_metrics::Endpoint,
+  /// The collector spans are exported to, from `CONFIG.tracing`; `None`
+  /// exports none.
+  tracing: Option<span_export::Settings>,
+  /// Where handler failures are reported, from
+  /// `CONFIG.error_reporting.webhook`, `CONFIG.error_reporting.min_level`, and
+  /// `CONFIG.error_reporting.throttle`; `None` reports none.
+  error_reporting: Option<error_reports::Settings>,
+  /// Which requests are dumped, and where, from `CONFIG.debug.dump_routes`
+  /// and `CONFIG.debug.dump_dir`.
+  debug_dump: debug_dump::Settings,
+  /// The `Server` header, from `CONFIG.server_header`; `None` sends none.
+  server_header: Option<String>,
+  /// The headers added to handler responses, from `CONFIG.security_headers`.
+  security_headers: Option<security_headers::SecurityHeaders>,
+  /// The admin endpoints' token and address, from `CONFIG.admin.token` and
+  /// `CONFIG.admin.addr`. `None` disables them.
+  admin: Option<admin::AdminSettings>,
+  /// Where the audit log goes, from `CONFIG.audit.file` or
+  /// `CONFIG.audit.syslog`.
+  audit: Option<audit::Destination>,
+  /// The `fyre.secrets` store, already holding the secrets `config.lua`
+  /// read.
+  secrets: Arc<secrets::Secrets>,
+  /// The settings in effect, with secrets left out, for `GET
+  /// /admin/config`.
+  effective: serde_json::Value,
+  /// The files run by `include`, in the order they ran.
+  included: Vec<PathBuf>,
+  /// Mistakes that don't stop the server from starting, such as a route
+  /// added twice or one that can never match. Each is also logged as it
+  /// is found.
+  warnings: Vec<String>,
+}
+
+/// Server-wide state shared by every request.
+///
+/// This is built once after `config.lua` has loaded and handed to each
+/// per-request Lua state so the `fyre` helper modules can reach it.
+struct AppState {
+  /// The environment variable policy behind `fyre.env`.
+  env: Arc<fyre::env::EnvAccess>,
+  /// The outbound client behind `fyre.http`.
+  http: fyre::http::HttpClient,
+  /// The shared store behind `fyre.kv`.
+  kv: fyre::kv::KvStore,
+  /// The read-through cache behind `fyre.cache`.
+  cache: fyre::cache::Cache,
+  /// The shared database connections behind `fyre.sqlite`.
+  sqlite: fyre::sqlite::SqlitePool,
+  /// The directory sandbox behind `fyre.fs`.
+  fs: fyre::fs::FsSandbox,
+  /// The `fyre.session` settings, if sessions are enabled.
+  session: Option<fyre::session::SessionConfig>,
+  /// The keys `fyre.cookie` signs and verifies with, if `CONFIG.keys` is set.
+  cookie_keys: Option<fyre::cookie::CookieKeys>,
+  /// The connection pools behind `fyre.redis`.
+  redis: fyre::redis::RedisPools,
+  /// The allowlisted programs behind `fyre.exec`.
+  exec: fyre::exec::ExecPolicy,
+  /// The background job queues behind `fyre.queue`.
+  queues: fyre::queue::Queues,
+  /// The registry behind `fyre.metrics`.
+  metrics: fyre::metrics::Registry,
+  /// The SMTP transport behind `fyre.mail`, if `CONFIG.smtp.host` is set.
+  mail: Option<fyre::mail::Mailer>,
+  /// The store behind `fyre.secrets`.
+  secrets: Arc<secrets::Secrets>,
+  /// The per-key counters behind `fyre.ratelimit`.
+  ratelimit: fyre::ratelimit::RateLimiter,
+  /// The per-client limit every request is checked against, from
+  /// `CONFIG.rate_limit`.
+  rate_limit: Option<client_limit::RateLimit>,
+  /// The buckets behind `rate_limit`, the routes' own limits, and the
+  /// limits on failed API key attempts, kept apart from the ones scripts
+  /// use.
+  client_limits: fyre::ratelimit::RateLimiter,
+  /// When each API key was last used.
+  api_key_usage: auth::api_key::Usage,
+  /// The compiled handler scripts, if `CONFIG.lua.bytecode_cache` is enabled.
+  scripts: script_cache::ScriptCache,
+  /// The Lua instructions a run of a handler's pipeline may execute, for
+  /// routes without their own `instruction_limit`.
+  instruction_limit: Option<u64>,
+  /// What to do with an invalid response header, from
+  /// `CONFIG.lua.header_check`.
+  header_check: HeaderCheck,
+  /// The open and rejected connection counts.
+  connections: Arc<server::ConnectionStats>,
+  /// Where request bodies too large for memory are written, and the
+  /// largest accepted.
+  body_spill: body::SpillOptions,
+  /// The requests running their handler, bounded by `CONFIG.limits.in_flight`.
+  in_flight: limiter::Limiter,
+  /// The `CONFIG.access.allow` and `CONFIG.access.deny` lists.
+  access: access::AccessList,
+  /// Whether allowed requests are logged, from `CONFIG.access.log`.
+  access_log: bool,
+  /// The `CONFIG.allowed_hosts` list.
+  allowed_hosts: hosts::AllowedHosts,
+  /// The routes, for the per-route counts in `fyre.metrics.render()`.
+  routes: RoutesMap,
+  /// The memory-mapped files shared by `mmap` static mounts.
+  files: statics::FileCache,
+  /// What each request worker is doing, for `fyre.metrics.workers()`.
+  workers: worker_stats::WorkerStats,
+  /// The requests slower than `CONFIG.log.slow_request_ms`.
+  slow_log: slow_log::SlowLog,
+  /// The request counts and durations, and the Prometheus endpoint.
+  request_metrics: Arc<request_metrics::RequestMetrics>,
+  /// The per-route statistics behind `GET /admin/stats`.
+  route_stats: route_stats::RouteStats,
+  /// The span exporter, if `CONFIG.tracing.endpoint` is set.
+  span_export: Option<Arc<span_export::Exporter>>,
+  /// The webhook handler failures are posted to, if
+  /// `CONFIG.error_reporting.webhook` is set.
+  error_reports: Option<error_reports::Reporter>,
+  /// The debug dump writer, while dumps are on.
+  debug_dump: Option<debug_dump::Dumper>,
+  /// The addresses actually listened on, behind `fyre.server`; empty until
+  /// the server is started.
+  addrs: ArcSwap<Vec<String>>,
+  /// The `Server` header sent with responses that don't set one, from
+  /// `CONFIG.server_header`; `None` sends none.
+  server_header: Option<String>,
+  /// The headers added to handler responses that don't set them, from
+  /// `CONFIG.security_headers`.
+  security_headers: Option<security_headers::SecurityHeaders>,
+  /// The admin endpoints, if `CONFIG.admin.token` is set.
+  admin: Option<admin::Admin>,
+  /// The audit log, if a destination is set or the admin endpoints are.
+  audit: Option<audit::Audit>,
+}
+
+// --- Configuration ---
+/// The default server address and port.
+const DEFAULT_SERVER_ADDR: &str = "0.0.0.0:8000";
+/// The `Server` header when `CONFIG.server_header` is not set.
+const DEFAULT_SERVER_HEADER: &str = "fyre";
+/// The most threads used to compile the handler scripts at startup.
+const SCRIPT_CHECK_THREADS: usize = 8;
+/// The log target of the lines about a request's way through the
+/// pipeline, so `FYRE_LOG=info,fyre::pipeline=debug` can follow requests
+/// without the rest of the server's debug output.
+const PIPELINE_TARGET: &str = "fyre::pipeline";
+
+/// Runs the subcommand the command line (see `cli`) names, `serve` by
+/// default.
+///
+/// # Errors
+///
+/// This function will return an error if the subcommand fails.
+pub fn run(cli: cli::Cli) -> std::result::Result<(), Box<dyn std::error::Error>> {
+  match cli.command {
+    None => serve(cli.serve),
+    Some(cli::Command::Serve(args)) => serve(args),
+    Some(cli::Command::Routes(paths)) => print_routes(&paths),
+    Some(cli::Command::Check(args)) => check::run(&args),
+    Some(cli::Command::Bench(args)) => bench::run(args),
+    Some(cli::Command::Init(args)) => init::run(args),
+    Some(cli::Command::Secrets(args)) => secrets::run(args),
+  }
+}
+
+/// Initializes and runs the web server, for `fyre serve`.
+///
+/// It performs the following steps:
+///
+/// 1. **Loads Configuration:** A `FyreServer` is loaded from the `--config`
+///    script (see `embed`): `load_lua_config` runs it, and every handler
+///    script is then compiled by `check_scripts`. The addresses to listen
+///    on are determined in the following order of precedence:
+///    - The `--addr` options, if given.
+///    - The `CONFIG.addrs` or `CONFIG.addr` variable in `config.lua`, if
+///      set.
+///    - The `DEFAULT_SERVER_ADDR` constant.
+///
+/// 2. **Binds:** Every address is bound, or the sockets systemd passed are
+///    used instead (see `systemd`), before the process daemonizes with
+///    `--daemon`.
+///
+/// 3. **Starts Server:** The server listens on every socket, serving HTTPS when
+///    `CONFIG.tls` is set. Requests from all of them go to the same workers.
+///    Once the workers are running, systemd is told the server is ready.
+///
+/// 4. **Starts Workers:** `--workers` or `CONFIG.workers` threads (by default
+///    one per CPU) each take requests from the server in a loop, so a slow
+///    handler only ties up its own thread. For each request, the worker looks
+///    up the route in the `RoutesMap` and, if found, executes the corresponding
+///    Lua handler script. If a route is not found, a 404 Not Found response is
+///    sent.
+///
+/// 5. **Shuts Down:** On SIGINT or SIGTERM the workers finish the requests they
+///    are running, for up to `CONFIG.shutdown.grace_ms`, then the
+///    `CONFIG.shutdown.script` script runs and the background queues stop. The
+///    process exits with `shutdown::FORCED_EXIT_CODE` if requests were still
+///    running.
+///
+/// # Errors
+///
+/// This function will return an error if:
+/// - The Lua configuration file cannot be loaded.
+/// - A handler script doesn't compile and `CONFIG.lua.script_check` is
+///   `"strict"`.
+/// - The PID file names a running process, or `--daemon` is used where it
+///   isn't supported.
+/// - The signal handler can't be installed.
+/// - The server fails to start, or can't listen on one of its addresses and
+///   `CONFIG.bind_check` is `"strict"`.
+fn serve(args: cli::ServeArgs) -> std::result::Result<(), Box<dyn std::error::Error>> {
+  let cli_addrs = args.addrs();
+  if let Some(filter) = &args.log_level {
+    logger::set_filter(filter.clone());
+  }
+
+  info!("Server starting up...");
+
+  let mut builder = FyreServer::builder()
+    .config_file(&args.paths.config)
+    .env(&args.paths.env)
+    .quiet(args.quiet);
+  if let Some(dir) = &args.paths.scripts {
+    builder = builder.scripts_dir(dir);
+  }
+  if !cli_addrs.is_empty() {
+    info!(
+      "Server address set by CLI argument: {}",
+      cli_addrs.join(", ")
+    );
+  }
+  for addr in cli_addrs {
+    builder = builder.addr(addr);
+  }
+  if let Some(workers) = args.workers {
+    builder = builder.workers(workers);
+  }
+  for route in args.debug_dump {
+    builder = builder.debug_dump(route);
+  }
+  builder = builder.debug_dump_unsafe(args.debug_dump_unsafe);
+  let fyre = builder.load()?;
+
+  let pid_file = args.pidfile.or_else(|| fyre.pid_file.clone());
+  if let Some(path) = &pid_file {
+    daemon::PidFile::check(path)?;
+  }
+
+  // Sockets passed by systemd replace the configured addresses.
+  let mut server_addrs = fyre.addrs.clone();
+  let mut listeners = systemd::listen_fds()?;
+  let socket_activated = !listeners.is_empty();
+  let mut bound_addrs = Vec::new();
+  if socket_activated {
+    for listener in &listeners {
+      bound_addrs.push(listener.local_addr()?);
+    }
+    info!(
+      "Using {} socket(s) passed by systemd instead of {}",
+      listeners.len(),
+      server_addrs.join(", ")
+    );
+    server_addrs.clear();
+  }
+  for addr in &server_addrs {
+    match net::listen(addr, &fyre.socket) {
+      Ok(listener) => {
+        listeners.push(listener);
+        bound_addrs.push(addr.clone());
+      }
+      Err(e) if fyre.bind_check == BindCheck::Lenient => {
+        warn!("Could not listen on {}: {}", addr, e);
+      }
+      Err(e) => return Err(format!("Could not start server on {}: {}", addr, e).into()),
+    }
+  }
+  if listeners.is_empty() {
+    return Err("Could not start server: none of the addresses could be bound".into());
+  }
+  let redirect_listener = match fyre.redirect_http.clone() {
+    Some(addr) => {
+      let listener = net::bind(&addr, &fyre.socket)
+        .map_err(|e| format!("Could not start the HTTP redirect: {}", e))?;
+      Some((addr, listener))
+    }
+    None => None,
+  };
+  let admin_listener = match fyre.admin_addr.clone() {
+    Some(addr) => {
+      let listener = net::listen(&addr, &fyre.socket)
+        .map_err(|e| format!("Could not start the admin endpoints on {}: {}", addr, e))?;
+      Some((addr, listener))
+    }
+    None => None,
+  };
+
+  // Everything up to here runs on one thread, so the process can fork.
+  let log_file = args.log_file.or_else(|| fyre.log_file.clone());
+  let ready = if args.daemon {
+    Some(daemon::daemonize(log_file.as_deref())?)
+  } else {
+    None
+  };
+  if let Some(path) = &log_file {
+    logger::open(path, fyre.log_rotate.clone())?;
+  }
+  let _pid_file = pid_file.as_deref().map(daemon::PidFile::write).transpose()?;
+
+  // The addresses are bound, the TLS files read, and the log and PID files
+  // open, so nothing past here needs root.
+  if let Some(run_as) = &fyre.run_as {
+    for path in log_file.iter().chain(&pid_file) {
+      run_as.chown(path)?;
+    }
+    run_as.drop_privileges()?;
+  }
+
+  let signals = shutdown::signals()?;
+  if log_file.is_some() || auth::htpasswd::in_use() || fyre.tls.is_some() {
+    logger::handle_sighup()?;
+  }
+
+  let https_port = listeners
+    .iter()
+    .find_map(net::Listener::port)
+    .unwrap_or(443);
+  // A socket passed by systemd is systemd's to remove.
+  let local_addrs = fyre.start(listeners, &bound_addrs, !socket_activated)?;
+  if let Some(path) = &args.port_file {
+    write_port_file(path, &local_addrs)?;
+  }
+
+  let admin_server = match admin_listener {
+    Some((addr, listener)) => {
+      let server = admin::start(
+        listener,
+        fyre.connections.clone(),
+        fyre.tls.clone(),
+        &fyre.state,
+      )
+      .map_err(|e| format!("Could not start the admin endpoints: {}", e))?;
+      info!("Admin endpoints at {}", addr);
+      Some((addr, server))
+    }
+    None => None,
+  };
+  if fyre.state.admin.is_some() && admin_server.is_none() {
+    info!("Admin endpoints under {}", admin::PREFIX);
+  }
+
+  if let Some((redirect_addr, listener)) = redirect_listener {
+    server::start_redirect(listener, https_port, fyre.connections.clone())
+      .map_err(|e| format!("Could not start the HTTP redirect: {}", e))?;
+    info!("Redirecting http://{} to HTTPS", redirect_addr);
+  }
+
+  if let Some(ready) = ready {
+    ready.notify();
+  }
+  systemd::notify("READY=1");
+
+  // The sender is kept in the handler, so this only returns on a signal.
+  match systemd::watchdog_interval() {
+    Some(interval) => {
+      while let Err(RecvTimeoutError::Timeout) = signals.recv_timeout(interval) {
+        // Twice the interval is systemd's timeout.
+        if fyre.state.workers.all_stuck(interval * 2) {
+          warn!("Every worker is stuck on a request; not pinging the systemd watchdog");
+        } else {
+          systemd::notify("WATCHDOG=1");
+        }
+      }
+    }
+    None => {
+      let _ = signals.recv();
+    }
+  }
+  systemd::notify("STOPPING=1");
+  if let Some(audit) = &fyre.state.audit {
+    audit.record("signal", "shutdown", serde_json::json!({}), "ok");
+  }
+  if let Some((_, admin_server)) = &admin_server {
+    admin_server.stop();
+  }
+  let still_running = fyre.shutdown();
+  let port_file = args.port_file.iter().map(PathBuf::as_path);
+  let admin_addr = admin_server.iter().map(|(addr, _)| addr);
+  for path in admin_addr
+    .filter_map(|addr| net::unix_path(addr))
+    .chain(port_file)
+  {
+    if let Err(e) = fs::remove_file(path) {
+      warn!("Failed to remove {}: {}", path.display(), e);
+    }
+  }
+
+  if still_running > 0 {
+    std::process::exit(shutdown::FORCED_EXIT_CODE);
+  }
+  info!("Server stopped");
+  Ok(())
+}
+
+/// Writes the TCP ports of `addrs` to `path`, one per line, for test
+/// harnesses that start the server on port 0. The file is written under
+/// another name and renamed, so it is never read half-written.
+///
+/// # Errors
+///
+/// This function will return an error if the file can't be written.
+fn write_port_file(path: &Path, addrs: &[String]) -> std::result::Result<(), String> {
+  let ports: String = addrs
+    .iter()
+    .filter(|addr| net::unix_path(addr).is_none())
+    .filter_map(|addr| addr.rsplit_once(':'))
+    .map(|(_, port)| format!("{}\n", port))
+    .collect();
+  let temp = path.with_extension("tmp");
+  fs::write(&temp, ports)
+    .and_then(|()| fs::rename(&temp, path))
+    .map_err(|e| format!("Failed to write {}: {}", path.display(), e))
+}
+
+/// Why a handler script didn't produce the response, which is answered
+/// with an error status instead.
+#[derive(Debug, Clone, PartialEq)]
+pub enum PipelineError {
+  /// The script failed to compile at startup, with
+  /// `CONFIG.lua.script_check = "lenient"`; answered with `503`.
+  NotCompiled(String),
+  /// The script couldn't be read, didn't return a table, or its `handler`
+  /// raised an error; answered with `500`.
+  Failed(String),
+  /// The pipeline panicked; answered with `500`.
+  Panicked(String),
+  /// The pipeline ran past its instruction limit, given here; answered
+  /// with `500`.
+  InstructionLimit(u64),
+}
+
+impl fmt::Display for PipelineError {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      PipelineError::NotCompiled(e) => write!(f, "handler failed to compile: {}", e),
+      PipelineError::Failed(e) => write!(f, "{}", e),
+      PipelineError::Panicked(e) => write!(f, "handler panicked: {}", e),
+      PipelineError::InstructionLimit(limit) => {
+        write!(f, "handler exceeded its limit of {} instructions", limit)
+      }
+    }
+  }
+}
+
+impl std::error::Error for PipelineError {}
+
+/// The longest `X-Request-Id` carried into the log.
+const MAX_REQUEST_ID_BYTES: usize = 128;
+
+/// Returns the request's `X-Request-Id`, for the JSON log, if it is
+/// printable ASCII and not too long to be an id.
+fn request_id(headers: &[Header]) -> Option<String> {
+  headers
+    .iter()
+    .find(|header| header.field.equiv("X-Request-Id"))
+    .map(|header| header.value.as_str())
+    .filter(|id| {
+      !id.is_empty()
+        && id.len() <= MAX_REQUEST_ID_BYTES
+        && id.bytes().all(|b| (b'!'..=b'~').contains(&b))
+    })
+    .map(str::to_string)
+}
+
+/// Reports a handler request that failed with `error`, or was answered
+/// with a `5xx`, to `state.error_reports`, if it is set and wants it.
+fn report_error(
+  state: &AppState,
+  route: &str,
+  script: &str,
+  status: u16,
+  error: Option<&PipelineError>,
+  request_id: Option<String>,
+) {
+  let Some(reporter) = &state.error_reports else {
+    return;
+  };
+  let level = match error {
+    Some(_) => logger::Level::Error,
+    None if status >= 500 => logger::Level::Warn,
+    None => return,
+  };
+  if !reporter.wants(level) {
+    return;
+  }
+  let error = match error {
+    Some(error) => error.to_string(),
+    None => format!("answered with status {}", status),
+  };
+  reporter.report(error_reports::Report {
+    route: route.to_string(),
+    script: script.to_string(),
+    level,
+    status,
+    error,
+    request_id,
+  });
+}
+
+/// The server span of a request for `route`, named for its method and the
+/// route or static directory it matched.
+fn server_span(
+  exporter: Arc<span_export::Exporter>,
+  trace: &trace::TraceContext,
+  request: &server::Request,
+  route: &str,
+  table: &RouteTable,
+) -> span_export::ServerSpan {
+  let pattern = if table.handlers.contains_key(route) {
+    Some(route.to_string())
+  } else {
+    table.find_mount(route).map(|(mount, _)| format!("{}/*", mount.prefix))
+  };
+  let method = request.method().to_string();
+  let name = match &pattern {
+    Some(pattern) => format!("{} {}", method, pattern),
+    None => method.clone(),
+  };
+  let mut attributes = vec![("http.request.method", method.into())];
+  if let Some(pattern) = pattern {
+    attributes.push(("http.route", pattern.into()));
+  }
+  if let server::RemoteAddr::Tcp(addr) = request.remote_addr() {
+    attributes.push(("client.address", addr.ip().to_canonical().to_string().into()));
+  }
+  span_export::ServerSpan::new(exporter, trace, name, attributes)
+}
+
+/// Routes one request to its handler script and sends the response.
+///
+/// `worker` is the id of the calling worker thread, included in the log lines
+/// so interleaved output from concurrent requests can be told apart. A handler
+/// request slower than `CONFIG.log.slow_request_ms` is also logged by
+/// `state.slow_log`, and every one is counted in `state.route_stats`. A sampled
+/// request's spans go to `state.span_export`, when it is set. Returns why the
+/// handler failed, if it did.
+fn handle_request(
+  worker: usize,
+  mut request: server::Request,
+  state: &Arc<AppState>,
+  pool: &mut lua_pool::LuaPool,
+) -> Option<PipelineError> {
+  let started = std::time::Instant::now();
+  let route = request.url().to_string();
+  let table = state.routes.load_full();
+  let mut trace = trace::TraceContext::from_headers(request.headers());
+  if let Some(exporter) = &state.span_export {
+    exporter.sample(&mut trace);
+  }
+  let exporter = state.span_export.as_ref().filter(|_| trace.sampled());
+  // Every JSON log line about the request names it.
+  let _log_context = logger::scope(|| logger::Context {
+    route: route.clone(),
+    script: table.handlers.get(&route).map(|handler| handler.script.clone()),
+    request_id: request_id(request.headers()),
+    trace_id: Some(trace.trace_id.clone()),
+    span_id: Some(trace.span_id.clone()),
+  });
+  debug!(
+    target: PIPELINE_TARGET,
+    "[worker {}] {} {} from {}: {} headers, script {}",
+    worker,
+    request.method(),
+    route,
+    request.remote_addr(),
+    request.headers().len(),
+    table.handlers.get(&route).map_or("none", |handler| handler.script.as_str())
+  );
+  if logger::enabled(logger::Level::Trace, PIPELINE_TARGET) {
+    let names: Vec<&str> = request
+      .headers()
+      .iter()
+      .map(|header| header.field.as_str().as_str())
+      .collect();
+    trace!(target: PIPELINE_TARGET, "[worker {}] Headers: {}", worker, names.join(", "));
+  }
+
+  // Counted from here, so requests refused below are too; admin requests
+  // aren't, so the metrics describe the routes alone.
+  let admin = state.admin.as_ref().filter(|admin| admin.serves(&route));
+  if let (None, Some(label)) = (admin, state.request_metrics.route_label(&route, &table)) {
+    request.count_in(state.request_metrics.clone(), label);
+  }
+  if let Some(exporter) = exporter {
+    let span = server_span(exporter.clone(), &trace, &request, &route, &table);
+    request.export_span(span);
+  }
+
+  if !access_allowed(worker, &state.access, &request, &route, state.access_log) {
+    forbid(worker, request);
+    return None;
+  }
+
+  let http_1_0 = *request.http_version() == (1, 0);
+  if let Err(refused) = state.allowed_hosts.check(request.headers(), http_1_0) {
+    reject_host(worker, request, &route, refused);
+    return None;
+  }
+
+  if let Some(admin) = admin {
+    admin.handle(request, state);
+    return None;
+  }
+
+  if state.request_metrics.serves(&route, &table) {
+    request_metrics::serve(request, state);
+    return None;
+  }
+
+  if let Some(list) = table.handlers.get(&route).and_then(|handler| handler.access.as_ref()) {
+    if !access_allowed(worker, list, &request, &route, state.access_log) {
+      forbid(worker, request);
+      return None;
+    }
+  }
+
+  if let Some(decision) = rate_limited(state, table.handlers.get(&route), &request, &route) {
+    reject_rate_limited(worker, request, &route, &decision);
+    return None;
+  }
+
+  // Checked before the route is looked at further, so an unauthenticated
+  // client can't tell a protected route from a missing one, or a broken one.
+  let identity = match table.auth_for(&route) {
+    Some(required) => {
+      // A client that has used up its failed API key attempts isn't let
+      // try again until its bucket refills.
+      let failures = match (required.failure_limit(), request.remote_addr()) {
+        (Some(limit), server::RemoteAddr::Tcp(addr)) => {
+          Some((limit, format!("api_key failures {}", limit.client_key(addr.ip()))))
+        }
+        _ => None,
+      };
+      if let Some((limit, key)) = &failures {
+        let decision = limit.peek(&state.client_limits, key);
+        if !decision.allowed {
+          reject_rate_limited(worker, request, &route, &decision);
+          return None;
+        }
+      }
+      match required.authenticate(request.headers()) {
+        Some(identity) => {
+          if let Some(key_id) = &identity.key_id {
+            state.api_key_usage.record(key_id);
+          }
+          Some(identity)
+        }
+        None => {
+          if let Some((limit, key)) = &failures {
+            limit.check(&state.client_limits, key);
+          }
+          reject_unauthorized(worker, request, &route, &required.challenges());
+          return None;
+        }
+      }
+    }
+    None => None,
+  };
+
+  if let Some(handler) = table.handlers.get(&route) {
+    let script_path = &handler.script;
+    // Read now, since the request is gone once it is answered.
+    let reported_id = state
+      .error_reports
+      .as_ref()
+      .and_then(|_| request_id(request.headers()));
+    match request.tls_negotiated() {
+      Some(tls) => debug!(
+        target: PIPELINE_TARGET,
+        "[worker {}] Request: {} -> Handler: {} ({} {})",
+        worker, route, script_path, tls.version, tls.cipher
+      ),
+      None => debug!(
+        target: PIPELINE_TARGET,
+        "[worker {}] Request: {} -> Handler: {}",
+        worker, route, script_path
+      ),
+    }
+
+    if let Some(error) = handler.compile_error.get() {
+      warn!(
+        target: PIPELINE_TARGET,
+        "[worker {}] 503 Handler failed to compile at startup: {}",
+        worker, script_path
+      );
+      let unavailable = Response::from_string("503 Service Unavailable").with_status_code(503);
+      if let Err(e) = request.respond(unavailable) {
+        error!(target: PIPELINE_TARGET, "[worker {}] Error sending 503 response: {}", worker, e);
+      }
+      let error = PipelineError::NotCompiled(error.clone());
+      report_error(state, &route, script_path, 503, Some(&error), reported_id);
+      return Some(error);
+    }
+
+    if handler.require_client_cert && request.client_cert().is_none() {
+      warn!(
+        target: PIPELINE_TARGET,
+        "[worker {}] 403 {} requires a client certificate; {} sent none",
+        worker,
+        route,
+        request.remote_addr()
+      );
+      let forbidden = Response::from_string("403 Forbidden").with_status_code(403);
+      if let Err(e) = request.respond(forbidden) {
+        error!(target: PIPELINE_TARGET, "[worker {}] Error sending 403 response: {}", worker, e);
+      }
+      return None;
+    }
+
+    if let Some(accept_types) = &handler.accept_types {
+      if !accept_types.allows(request.method(), request.headers()) {
+        let content_type = request
+          .headers()
+          .iter()
+          .find(|h| h.field.equiv("Content-Type"))
+          .map_or("none", |h| h.value.as_str());
+        warn!(
+          target: PIPELINE_TARGET,
+          "[worker {}] 415 {} from {}: Content-Type {} is not accepted",
+          worker,
+          route,
+          request.remote_addr(),
+          content_type
+        );
+        let unsupported =
+          Response::from_string("415 Unsupported Media Type").with_status_code(415);
+        if let Err(e) = request.respond(unsupported) {
+          error!(target: PIPELINE_TARGET, "[worker {}] Error sending 415 response: {}", worker, e);
+        }
+        return None;
+      }
+    }
+
+    // The route's slot is taken before the global one, so requests queued
+    // on a busy route don't hold global slots while they wait.
+    let route_permit = match &handler.limiter {
+      Some(limiter) => match limiter.acquire() {
+        Some(permit) => Some(permit),
+        None => {
+          reject_busy(worker, request, &route, limiter.status());
+          return None;
+        }
+      },
+      None => None,
+    };
+    let Some(_permit) = state.in_flight.acquire() else {
+      drop(route_permit);
+      reject_busy(worker, request, &route, state.in_flight.status());
+      return None;
+    };
+
+    let dumper = state
+      .debug_dump
+      .as_ref()
+      .filter(|dumper| dumper.matches(&route));
+    if dumper.is_some() {
+      request.capture_body(debug_dump::MAX_BODY_BYTES);
+    }
+
+    let mut phases = slow_log::Phases::default();
+    let pipeline_started = std::time::Instant::now();
+    let instruction_limit = handler.instruction_limit.or(state.instruction_limit);
+    let mut exceeded = None;
+    let run = panics::catch(|| {
+      pool.checkout().and_then(|pooled| {
+        pooled
+          .lua
+          .set_app_data(fyre::secrets::Scope(handler.secrets.clone()));
+        pooled.lua.set_app_data(trace.clone());
+        if let Some(exporter) = exporter {
+          pooled
+            .lua
+            .set_app_data(span_export::Recorder::new(exporter.clone(), &trace));
+        }
+        let budget = instruction_limit
+          .map(|limit| instruction_limit::Budget::start(&pooled.lua, limit))
+          .transpose();
+        let result = budget.and_then(|budget| {
+          let result = execute_handler_pipeline(
+            &mut request,
+            script_path,
+            identity.as_ref(),
+            handler.csrf,
+            state,
+            &pooled.lua,
+            &mut phases,
+          );
+          exceeded = budget.and_then(|budget| budget.finish(&pooled.lua));
+          result
+        });
+        // The state is reused, and the next request may not be exported.
+        if exporter.is_some() {
+          pooled.lua.remove_app_data::<span_export::Recorder>();
+        }
+        pool.checkin(pooled, result.is_ok() && exceeded.is_none());
+        result
+      })
+    });
+    phases.lua = pipeline_started.elapsed().saturating_sub(phases.read);
+    state.request_metrics.record_lua(&route, phases.lua);
+    // The Lua state was dropped as the panic unwound, so the next request
+    // gets a new one.
+    let result = run
+      .map(|result| match &exceeded {
+        Some(exceeded) => Err(PipelineError::InstructionLimit(exceeded.limit)),
+        None => result.map_err(|e| PipelineError::Failed(e.to_string())),
+      })
+      .unwrap_or_else(|panic| {
+        error!(
+          target: PIPELINE_TARGET,
+          "[worker {}] Panic in handler {} for {}: {}",
+          worker, script_path, route, panic
+        );
+        panic.log_backtrace(worker);
+        state.workers.restarted(worker);
+        state.request_metrics.record_panic(&route);
+        Err(PipelineError::Panicked(panic.to_string()))
+      });
+
+    let (response, error) = match result {
+      Ok(response) => (response, None),
+      Err(e) => {
+        match &e {
+          PipelineError::InstructionLimit(limit) => error!(
+            target: PIPELINE_TARGET,
+            "[worker {}] Handler {} for {} stopped after {} instructions",
+            worker, script_path, route, limit
+          ),
+          e => error!(
+            target: PIPELINE_TARGET,
+            "[worker {}] Pipeline execution fatal error for {}: {}",
+            worker, route, e
+          ),
+        }
+        let body = match &e {
+          PipelineError::Panicked(_) => "Server Error: handler panicked".to_string(),
+          e => format!("Server Error: {}", e),
+        };
+        let response = Response::from_string(body).with_status_code(500);
+        (handler_response(response), Some(e))
+      }
+    };
+    let status = response.status_code().0;
+    phases.response_bytes = response.data_length();
+    let (response, transcript) = match dumper {
+      Some(dumper) => {
+        let mut transcript = dumper.begin(&mut request);
+        let response = dumper.capture(&mut transcript, response);
+        (response, Some(transcript))
+      }
+      None => (response, None),
+    };
+    let writing = std::time::Instant::now();
+    if let Err(e) = request.respond(response) {
+      error!(target: PIPELINE_TARGET, "[worker {}] Error sending response: {}", worker, e);
+    }
+    phases.write = writing.elapsed();
+    if let (Some(dumper), Some(transcript)) = (dumper, transcript) {
+      dumper.finish(transcript, started.elapsed());
+    }
+    state
+      .slow_log
+      .record(worker, &route, script_path, status, started.elapsed(), &phases);
+    let message = error.as_ref().map(PipelineError::to_string);
+    state.route_stats.record(
+      &route,
+      &route_stats::Sample {
+        status,
+        elapsed: started.elapsed(),
+        request_bytes: phases.request_bytes,
+        response_bytes: phases.response_bytes,
+        error: message.as_deref(),
+      },
+    );
+    report_error(state, &route, script_path, status, error.as_ref(), reported_id);
+    error
+  } else if let Some((mount, rest)) = table.find_mount(&route) {
+    let response = statics::serve(request.method(), mount, rest, &state.files);
+    if let Err(e) = request.respond(response) {
+      error!(target: PIPELINE_TARGET, "[worker {}] Error sending file: {}", worker, e);
+    }
+    None
+  } else {
+    warn!(target: PIPELINE_TARGET, "[worker {}] 404 Not Found: {}", worker, route);
+    let not_found = Response::from_string("404 Not Found").with_status_code(404);
+    if let Err(e) = request.respond(not_found) {
+      error!(target: PIPELINE_TARGET, "[worker {}] Error sending 404 response: {}", worker, e);
+    }
+    None
+  }
+}
+
+/// Converts `response` into a `HandlerResponse`.
+fn handler_response<R: Read + 'static>(response: Response<R>) -> HandlerResponse {
+  let status = response.status_code();
+  let headers = response.headers().to_vec();
+  let length = response.data_length();
+  let reader: Box<dyn Read> = Box::new(response.into_reader());
+  Response::new(status, headers, reader, length, None)
+}
+
+/// Builds `request.headers`, which only copies a header into Lua when a
+/// script reads it, since most handlers read one or two at most.
+///
+/// Names are matched ignoring case, and for a repeated header the last
+/// value is returned. `pairs(request.headers)` still lists every header
+/// once, under the name as sent.
+///
+/// # Errors
+///
+/// This function will return a `LuaError` if the table or its metatable
+/// cannot be created.
+fn lazy_headers(lua: &Lua, headers: &[Header]) -> LuaResult<LuaTable> {
+  let headers: Rc<Vec<(String, String)>> = Rc::new(
+    headers
+      .iter()
+      .map(|h| (h.field.as_str().to_string(), h.value.to_string()))
+      .collect(),
+  );
+
+  let meta = lua.create_table()?;
+  let lookup = headers.clone();
+  meta.set(
+    "__index",
+    lua.create_function(move |lua, (_, name): (LuaTable, LuaValue)| {
+      let LuaValue::String(name) = name else {
+        return Ok(None);
+      };
+      let Ok(name) = name.to_str() else {
+        return Ok(None);
+      };
+      lookup
+        .iter()
+        .rev()
+        .find(|(field, _)| field.eq_ignore_ascii_case(&name))
+        .map(|(_, value)| lua.create_string(value))
+        .transpose()
+    })?,
+  )?;
+  meta.set(
+    "__pairs",
+    lua.create_function(move |lua, _: LuaTable| {
+      let headers = headers.clone();
+      let next = Cell::new(0);
+      lua.create_function(move |_, ()| {
+        // Skip a header repeated later, so each name is listed once with the
+        // value `__index` returns.
+        while let Some((field, value)) = headers.get(next.get()) {
+          next.set(next.get() + 1);
+          let repeated = headers[next.get()..]
+            .iter()
+            .any(|(later, _)| later.eq_ignore_ascii_case(field));
+          if !repeated {
+            return Ok((Some(field.clone()), Some(value.clone())));
+          }
+        }
+        Ok((None, None))
+      })
+    })?,
+  )?;
+
+  let table = lua.create_table()?;
+  table.set_metatable(Some(meta))?;
+  Ok(table)
+}
+
+/// Answers a request turned away by a concurrency limit.
+fn reject_busy(worker: usize, request: server::Request, route: &str, status: u16) {
+  warn!(
+    target: PIPELINE_TARGET,
+    "[worker {}] {} Too many requests in flight: {}",
+    worker, status, route
+  );
+  let status = StatusCode(status);
+  let mut busy = Response::from_string(format!("{} {}", status.0, status.default_reason_phrase()))
+    .with_status_code(status);
+  if let Ok(retry_after) = Header::from_bytes("Retry-After", "1") {
+    busy.add_header(retry_after);
+  }
+  if let Err(e) = request.respond(busy) {
+    error!(
+      target: PIPELINE_TARGET,
+      "[worker {}] Error sending {} response: {}",
+      worker, status.0, e
+    );
+  }
+}
+
+/// Checks the client's address against `list`. A refusal is logged with the
+/// entry that decided it, and with `log` so is a request an `allow` entry
+/// let in.
+fn access_allowed(
+  worker: usize,
+  list: &access::AccessList,
+  request: &server::Request,
+  route: &str,
+  log: bool,
+) -> bool {
+  if list.is_empty() {
+    return true;
+  }
+  let ip = match request.remote_addr() {
+    server::RemoteAddr::Tcp(addr) => Some(addr.ip()),
+    server::RemoteAddr::Unix(_) => None,
+  };
+  match list.check(ip) {
+    Ok(rule) => {
+      if let Some(rule) = rule.filter(|_| log) {
+        info!(
+          target: PIPELINE_TARGET,
+          "[worker {}] {} from {} allowed by {}",
+          worker,
+          route,
+          request.remote_addr(),
+          rule
+        );
+      }
+      true
+    }
+    Err(denied) => {
+      warn!(
+        target: PIPELINE_TARGET,
+        "[worker {}] 403 {} from {}: {}",
+        worker,
+        route,
+        request.remote_addr(),
+        denied
+      );
+      false
+    }
+  }
+}
+
+/// Answers a request from a client that may not reach its route.
+fn forbid(worker: usize, request: server::Request) {
+  let forbidden = Response::from_string("403 Forbidden").with_status_code(403);
+  if let Err(e) = request.respond(forbidden) {
+    error!(target: PIPELINE_TARGET, "[worker {}] Error sending 403 response: {}", worker, e);
+  }
+}
+
+/// Answers a request whose `Host` isn't in `CONFIG.allowed_hosts` with `421`,
+/// or one without a usable `Host` with `400`.
+fn reject_host(worker: usize, request: server::Request, route: &str, refused: hosts::Refused) {
+  let (status, body) = match refused {
+    hosts::Refused::Missing => {
+      warn!(
+        target: PIPELINE_TARGET,
+        "[worker {}] 400 {} from {}: no single Host header",
+        worker,
+        route,
+        request.remote_addr()
+      );
+      (400, "400 Bad Request")
+    }
+    hosts::Refused::NotAllowed(host) => {
+      warn!(
+        target: PIPELINE_TARGET,
+        "[worker {}] 421 {} from {}: Host {:?} is not in CONFIG.allowed_hosts",
+        worker,
+        route,
+        request.remote_addr(),
+        host
+      );
+      (421, "421 Misdirected Request")
+    }
+  };
+  let response = Response::from_string(body).with_status_code(status);
+  if let Err(e) = request.respond(response) {
+    error!(target: PIPELINE_TARGET, "[worker {}] Error sending {} response: {}", worker, status, e);
+  }
+}
+
+/// Takes a token for the client from the global rate limit and then from
+/// `handler`'s own. Returns the decision that refused the request, if one
+/// did.
+fn rate_limited(
+  state: &AppState,
+  handler: Option<&Route>,
+  request: &server::Request,
+  route: &str,
+) -> Option<client_limit::Decision> {
+  let server::RemoteAddr::Tcp(addr) = request.remote_addr() else {
+    return None;
+  };
+  if let Some(limit) = &state.rate_limit {
+    let decision = limit.check(&state.client_limits, &limit.client_key(addr.ip()));
+    if !decision.allowed {
+      return Some(decision);
+    }
+  }
+  let limit = handler.and_then(|handler| handler.rate_limit.as_ref())?;
+  let key = format!("{} {}", route, limit.client_key(addr.ip()));
+  let decision = limit.check(&state.client_limits, &key);
+  (!decision.allowed).then_some(decision)
+}
+
+/// Answers a request over its client's rate limit.
+fn reject_rate_limited(
+  worker: usize,
+  request: server::Request,
+  route: &str,
+  decision: &client_limit::Decision,
+) {
+  warn!(
+    target: PIPELINE_TARGET,
+    "[worker {}] 429 {} from {}: rate limit exceeded",
+    worker,
+    route,
+    request.remote_addr()
+  );
+  let mut limited = Response::from_string("429 Too Many Requests").with_status_code(429);
+  for (name, value) in decision.headers() {
+    if let Ok(header) = Header::from_bytes(name, value) {
+      limited.add_header(header);
+    }
+  }
+  if let Err(e) = request.respond(limited) {
+    error!(target: PIPELINE_TARGET, "[worker {}] Error sending 429 response: {}", worker, e);
+  }
+}
+
+/// Answers a request without valid credentials for its route.
+fn reject_unauthorized(
+  worker: usize,
+  request: server::Request,
+  route: &str,
+  challenges: &[String],
+) {
+  warn!(
+    target: PIPELINE_TARGET,
+    "[worker {}] 401 {} needs credentials; {} sent none that match",
+    worker,
+    route,
+    request.remote_addr()
+  );
+  let mut unauthorized = Response::from_string("401 Unauthorized").with_status_code(401);
+  for challenge in challenges {
+    if let Ok(challenge) = Header::from_bytes("WWW-Authenticate", challenge.as_str()) {
+      unauthorized.add_header(challenge);
+    }
+  }
+  if let Err(e) = request.respond(unauthorized) {
+    error!(target: PIPELINE_TARGET, "[worker {}] Error sending 401 response: {}", worker, e);
+  }
+}
+
+/// Loads and executes the Lua configuration script.
+///
+/// This function is responsible for setting up the Lua environment and running the
+/// `config.lua` script. It creates a new `Lua` instance and exposes two
+/// functions to the script:
+///
+/// - `router.add(path, script [, opts])`: Registers a new route. `path` is the
+///   URL path and `script` is the filename of the Lua handler script in the
+///   scripts directory. `opts` may set `max_concurrent`, `queue`,
+///   `queue_timeout_ms`, and `status` to limit the route's concurrent requests,
+///   `require_client_cert`, `allow` and `deny` to limit the client addresses
+///   (see `access`), `rate_limit` to add a per-client rate limit (see
+///   `client_limit`), `accept_types` to limit the request body media types (see
+///   `content_types`), `csrf` to require the `fyre.csrf` token on unsafe
+///   methods, `secrets` to limit the names `fyre.secrets` may read, `auth` to
+///   require basic authentication, and `require_auth` to require one of the
+///   `CONFIG.auth` strategies (see `auth`), and `instruction_limit` to override
+///   `CONFIG.lua.instruction_limit`.
+/// - `router.protect(pattern, auth)`: Requires basic authentication for
+///   `pattern`, an exact path or one ending in `/*` for everything under
+///   it, static files included. A route's own `auth` takes precedence.
+/// - `router.static(prefix, dir [, opts])`: Serves the files in `dir` under
+///   the URL `prefix`, for requests no route matches. `opts` may set `mmap`
+///   to serve small files from shared memory maps, `follow_symlinks` to
+///   serve files symlinked from outside `dir`, and `serve_hidden` to serve
+///   dotfiles (see `statics`).
+/// - `router.remove(path)`: Removes the route or static directory added for
+///   `path`, so an environment's file can drop one. Returns whether there
+///   was one.
+/// - `router.set_addr(address)`: Sets the server address, like `CONFIG.addr`.
+///   The last call wins, and `CONFIG.addr` or `CONFIG.addrs` overrides it.
+/// - `queue.worker(name, script [, opts])`: Declares the queue `name`, whose
+///   jobs are run by the `perform` function of `script` (in the scripts
+///   directory). `opts` may set `concurrency`, `max_attempts`, and
+///   `backoff_ms`.
+/// - `schedule.every(interval, script)` and `schedule.cron(expr, script)`:
+///   Declare a task script (in the scripts directory) whose `run` function
+///   is called every `interval` (e.g. `"5m"`) or whenever the local time
+///   matches the five-field cron expression `expr`.
+/// - `include(pattern)`: Runs the files matching `pattern`, relative to the
+///   config file's directory, in sorted order in the same state (see
+///   `include`).
+///
+/// Once the script has run, the environment's file (`config.<env>.lua`,
+/// see `paths`) runs in the same state as if it were included, if it
+/// exists. `fyre.env_name` is the environment's name.
+///
+/// `fyre.env.get` is also available, without the `CONFIG.sandbox.env_allow`
+/// restriction that applies to handler scripts, so the config can be computed
+/// from the environment, as is the shorter `env(name [, default])`. Variables
+/// read with `env.require(name)` must be set; the missing ones are reported
+/// together once the script has run.
+///
+/// After the script runs, the settings in its `CONFIG` table are checked
+/// and copied to the globals below (see `settings` for the keys), which are
+/// read into the returned `Config`. Setting the globals directly still
+/// works, but is deprecated:
+///
+/// - `CONFIG.addr`: The server address, `host:port` or `unix:/path/to.sock`.
+/// - `CONFIG.addrs`: A list of server addresses to listen on at once,
+///   instead of `CONFIG.addr`.
+/// - `CONFIG.bind_check`: `"strict"` (the default) to refuse to start when an
+///   address can't be bound, or `"lenient"` to skip it with a warning.
+/// - `CONFIG.sandbox.http_allow`: A list of hosts that `fyre.http` is allowed
+///   to contact.
+/// - `CONFIG.sandbox.http_allow_private`: Whether those hosts may resolve to
+///   loopback, private, or link-local addresses; `false` by default.
+/// - `CONFIG.sandbox.env_allow`: A list of environment variable names and
+///   prefixes that `fyre.env` may read in handler scripts.
+/// - `CONFIG.kv.max_entries`: The maximum number of entries in the `fyre.kv`
+///   store.
+/// - `CONFIG.cache.max_entries`: The maximum number of entries in `fyre.cache`.
+/// - `CONFIG.sqlite.dir`: The directory `fyre.sqlite` databases are restricted
+///   to.
+/// - `CONFIG.sandbox.fs_allow`: A list of directories `fyre.fs` may access.
+/// - `CONFIG.sandbox.fs_max_read_bytes`: The largest file `fyre.fs.read` will
+///   load.
+/// - `CONFIG.session.secret`, `CONFIG.session.store`, `CONFIG.session.ttl`,
+///   `CONFIG.session.cookie`, and `CONFIG.session.secure`: The `fyre.session`
+///   settings. Sessions are only enabled when `CONFIG.session.secret` is set.
+/// - `CONFIG.keys`: The `current` key `fyre.cookie` signs with, and the
+///   `previous` ones it still accepts.
+/// - `CONFIG.redis.url`, `CONFIG.redis.pool_size`, and
+///   `CONFIG.redis.timeout_ms`: The `fyre.redis` default server, idle
+///   connections per server, and I/O timeout.
+/// - `CONFIG.sandbox.exec_allow`: A list of programs `fyre.exec` may run.
+/// - `CONFIG.queue.dir`: The directory `fyre.queue` snapshots are written to.
+/// - `CONFIG.metrics.max_series`: The label combinations kept per
+///   `fyre.metrics` metric.
+/// - `CONFIG.smtp.host`, `CONFIG.smtp.port`, `CONFIG.smtp.security`,
+///   `CONFIG.smtp.username`, `CONFIG.smtp.password`, `CONFIG.smtp.from`,
+///   `CONFIG.smtp.timeout_ms`, and `CONFIG.smtp.queue`: The `fyre.mail`
+///   settings. Mail is only enabled when `CONFIG.smtp.host` is set.
+/// - `CONFIG.workers`: The number of threads handling requests, at most
+///   `worker_stats::MAX_WORKERS`. `--workers` takes precedence.
+/// - `CONFIG.lua.state_max_uses`: The requests a worker's Lua state serves
+///   before it is replaced.
+/// - `CONFIG.lua.instruction_limit`: The Lua instructions a run of a handler's
+///   pipeline may execute (see `instruction_limit`).
+/// - `CONFIG.socket.nodelay`, `CONFIG.socket.backlog`,
+///   `CONFIG.socket.recv_buffer`, and `CONFIG.socket.send_buffer`: The
+///   listening socket options.
+/// - `CONFIG.socket.unix_mode`: The permissions of a `unix:` socket, as an
+///   octal string such as `"660"`.
+/// - `CONFIG.bind.reuse_addr` and `CONFIG.bind.reuse_port`: Whether the
+///   listening sockets set them; `CONFIG.bind.reuse_addr` is on by default on
+///   Unix.
+/// - `CONFIG.bind.retry`: A table with how many `attempts` to make at binding
+///   an address that is in use, and the `delay_ms` before the first retry,
+///   doubled for each one after.
+/// - `CONFIG.limits.connections`, `CONFIG.limits.keep_alive_timeout_ms`, and
+///   `CONFIG.limits.requests_per_connection`: The limits on open connections,
+///   how long an idle connection is kept, and how many requests one connection
+///   may send.
+/// - `CONFIG.http.keep_alive` and `CONFIG.http.version_compat`: Whether
+///   connections are kept open between requests, and whether HTTP/1.0 ones are
+///   closed after each and never sent `100 Continue`.
+/// - `CONFIG.access.allow`, `CONFIG.access.deny`, and `CONFIG.access.log`: The
+///   client addresses and CIDR ranges every request is checked against, and
+///   whether allowed requests are logged with the entry they matched (see
+///   `access`).
+/// - `CONFIG.allowed_hosts`: The host names, or `*.` wildcards, a request's
+///   `Host` must match (see `hosts`).
+/// - `CONFIG.auth`: The authentication strategies routes can name in
+///   `require_auth`, by name (see `auth`).
+/// - `CONFIG.api_keys`: The SHA-256 of each API key, by id, for the `api_key`
+///   strategy (see `auth::api_key`).
+/// - `CONFIG.rate_limit`: A table with the `requests` each client may make per
+///   `window` seconds and the `burst` it may make at once (see
+///   `client_limit`).
+/// - `CONFIG.timeouts.header_read_ms`, `CONFIG.timeouts.body_read_ms`, and
+///   `CONFIG.timeouts.write_ms`: How long a connection may stall while a
+///   request's head or body is read or its response written.
+/// - `CONFIG.timeouts.header_deadline_ms`: How long a request's head may take
+///   in all, from the accept for a connection's first request.
+/// - `CONFIG.limits.url_bytes`, `CONFIG.limits.headers`,
+///   `CONFIG.limits.header_bytes`, and `CONFIG.limits.header_total_bytes`: The
+///   longest URL, and the most headers, the longest header line, and the most
+///   header bytes in one request.
+/// - `CONFIG.limits.in_flight`, `CONFIG.limits.in_flight_queue`, and
+///   `CONFIG.limits.in_flight_queue_timeout_ms`: The most requests running
+///   their handler at once, how many more may wait for a slot, and for how
+///   long.
+/// - `CONFIG.body.spill_bytes` and `CONFIG.body.spill_dir`: The largest request
+///   body kept in memory, and the directory larger ones are written to.
+/// - `CONFIG.limits.body`: The largest request body accepted; a larger one is
+///   answered with `413`.
+/// - `CONFIG.lua.bytecode_cache` and `CONFIG.lua.bytecode_cache_dir`: Whether
+///   handler scripts are compiled once and loaded from bytecode, and the
+///   directory the bytecode is also written to.
+/// - `CONFIG.lua.cache_max_bytes`: How much compiled script is kept in memory;
+///   the old name `CONFIG.lua.cache_max_bytes` still works. `fyre.cache` is
+///   limited by `CONFIG.cache.max_entries` instead.
+/// - `CONFIG.static.mmap_entries` and `CONFIG.static.mmap_max_bytes`: The
+///   number of files `mmap` static mounts keep mapped, and the largest file
+///   they map.
+/// - `CONFIG.lua.script_check`: `"strict"` (the default) to refuse to start
+///   when a handler script doesn't compile, or `"lenient"` to start anyway and
+///   answer `503` on the routes using it.
+/// - `CONFIG.lua.header_check`: `"lenient"` (the default) to log and leave out
+///   a response header a script set with an invalid name or a CR, LF, or NUL in
+///   its value, or `"strict"` to fail the request with `500`.
+/// - `CONFIG.shutdown.grace_ms`: How long running requests may take to finish
+///   once a shutdown signal arrives.
+/// - `CONFIG.shutdown.script`: A script (in the scripts directory) whose `run`
+///   function is called at shutdown, after the requests finish.
+/// - `CONFIG.log.slow_request_ms`: The time past which a handler request is
+///   logged as slow; 0 (the default) logs none.
+/// - `CONFIG.tls`: A table with the `cert` and `key` PEM files to serve HTTPS
+///   with, and optionally a `redirect_http` address whose plain HTTP requests
+///   are redirected to HTTPS, a `client_ca` PEM file client certificates are
+///   checked against, whether to `require_client_cert`, the `min_version` and
+///   `max_version` offered, and the `cipher_suites` allowed.
+/// - `CONFIG.pid_file`: The file the process id is written to. `--pidfile`
+///   takes precedence.
+/// - `CONFIG.log.file`: The file the server's output is appended to, also where
+///   `--daemon` sends it. `--log-file` takes precedence.
+/// - `CONFIG.log.rotate`: A table with the `max_size` (bytes, or a size such as
+///   `"50MB"`) past which the log file is rotated, and how many rotated files
+///   to `keep` (5 by default).
+/// - `CONFIG.log.format`: `"plain"` (the default) or `"json"` for one JSON
+///   object per log line (see `logger`).
+/// - `CONFIG.log.level`: The log filter, such as `"warn"` or
+///   `"info,fyre::pipeline=debug"` (see `logger`). `--log-level` and
+///   `FYRE_LOG` take precedence.
+/// - `CONFIG.run_as`: A table with the `user`, and optionally the `group`, that
+///   `fyre serve` switches to after binding its addresses (see `privileges`).
+/// - `CONFIG.admin.token` and `CONFIG.admin.addr`: The token that enables the
+///   `admin` endpoints, and the address they are served on instead of under
+///   `/admin/`.
+/// - `CONFIG.audit.file` or `CONFIG.audit.syslog`: The file or syslog facility
+///   the audit log goes to (see `audit`).
+/// - `CONFIG.server_header`: The `Server` header sent with responses that don't
+///   set their own, `"fyre"` by default; `false` sends none.
+/// - `CONFIG.security_headers`: A table of the hardening headers added to
+///   handler responses (see `security_headers`).
+/// - `CONFIG.health.live_path`, `CONFIG.health.ready_path`,
+///   `CONFIG.health.readiness`, `CONFIG.health.readiness_interval_ms`, and
+///   `CONFIG.health.log`: The health probes' paths (`false` disables one), an
+///   extra readiness check, how often it runs, and whether probes are logged
+///   (see `health`).
+/// - `CONFIG.log.requests` and `CONFIG.log.requests_exclude`: The format of the
+///   line logged for each request, `"combined"` by default or `"common"`
+///   (`false` logs none), and the paths left out (see `request_log`).
+/// - `CONFIG.metrics.path`, `CONFIG.metrics.listener`, and
+///   `CONFIG.metrics.count_self`: The path of the Prometheus endpoint
+///   (`"/metrics"` by default; `false` disables it), whether it is served on
+///   the `"main"` addresses or only the `"admin"` one, and whether requests for
+///   it are counted (see `request_metrics`).
+/// - `CONFIG.tracing.endpoint`, `CONFIG.tracing.protocol`,
+///   `CONFIG.tracing.sample_ratio`, and `CONFIG.tracing.resource`: The OTLP
+///   collector URL spans are exported to, `"http/protobuf"` (the default) or
+///   `"http/json"`, the share of new traces sampled, and the resource
+///   attributes (see `span_export`).
+/// - `CONFIG.error_reporting.webhook`, `CONFIG.error_reporting.min_level`, and
+///   `CONFIG.error_reporting.throttle`: The URL handler failures are posted to,
+///   `"error"` (the default) or `"warn"` to also report every `5xx` a handler
+///   sends, and the least time between posts, `"5m"` by default (see
+///   `error_reports`).
+/// - `CONFIG.debug.dump_routes` and `CONFIG.debug.dump_dir`: The paths whose
+///   requests and responses are written to files, for debugging, and the
+///   directory they are written to, `"dumps"` by default (see `debug_dump`).
+///
+/// # Arguments
+///
+/// * `routes_arc` - The shared `RoutesMap`. The routes added by `router.add`
+///   are collected into a new table, which replaces the current one once the
+///   whole script has loaded successfully.
+/// * `paths` - The configuration script to run, the directory the scripts
+///   it names are in, and the directory its relative paths are resolved
+///   against.
+///
+/// # Errors
+///
+/// This function will return an error if:
+/// - The `config.lua` file cannot be read.
+/// - The Lua script, a file it includes, or the environment's file fails to
+///   execute.
+/// - `include` names a file that doesn't exist or that includes itself.
+/// - A variable read with `env.require` is not set.
+/// - `FYRE_SECRETS_FILE` is set but can't be read, `FYRE_SECRETS_KEY` is
+///   missing or not 64 hex digits, or the file doesn't decrypt with it.
+/// - `CONFIG` is set but is not a table, has a key that isn't a setting or
+///   a value of the wrong type, or sets a setting whose global is also set.
+/// - `CONFIG.addrs` is set but is not a non-empty list of strings, or both
+///   `CONFIG.addr` and `CONFIG.addrs` are set.
+/// - A server address is not `host:port` or `unix:/path`.
+/// - `CONFIG.bind_check` is set but is not `"strict"` or `"lenient"`.
+/// - `CONFIG.sandbox.http_allow`, `CONFIG.sandbox.env_allow`,
+///   `CONFIG.sandbox.fs_allow`, or `CONFIG.sandbox.exec_allow` is set but is
+///   not a list of strings.
+/// - `CONFIG.kv.max_entries`, `CONFIG.cache.max_entries`,
+///   `CONFIG.redis.timeout_ms`, `CONFIG.metrics.max_series`, `CONFIG.workers`,
+///   `CONFIG.lua.state_max_uses`, or `CONFIG.lua.instruction_limit` is set but
+///   is not a positive integer, or `CONFIG.workers` is more than
+///   `worker_stats::MAX_WORKERS`.
+/// - `CONFIG.keys` is set but is not a table, or lacks `current`, or has a key
+///   that is empty or not a string.
+/// - A session setting has the wrong type, or `CONFIG.session.store` is not
+///   `"cookie"` or `"kv"`.
+/// - An SMTP setting has the wrong type, or `CONFIG.smtp.security` is not
+///   `"starttls"`, `"tls"`, or `"none"`.
+/// - A socket option has the wrong type or is out of range.
+/// - `CONFIG.limits.connections`, `CONFIG.limits.keep_alive_timeout_ms`,
+///   `CONFIG.timeouts.header_read_ms`, `CONFIG.timeouts.header_deadline_ms`,
+///   `CONFIG.timeouts.body_read_ms`, `CONFIG.timeouts.write_ms`,
+///   `CONFIG.limits.requests_per_connection`, `CONFIG.limits.url_bytes`,
+///   `CONFIG.limits.headers`, `CONFIG.limits.header_bytes`, or
+///   `CONFIG.limits.header_total_bytes` is set but is not a positive integer.
+/// - `CONFIG.http.keep_alive` or `CONFIG.http.version_compat` is set but is not
+///   a boolean.
+/// - `CONFIG.limits.in_flight` or `CONFIG.limits.in_flight_queue_timeout_ms` is
+///   set but is not a positive integer, or `CONFIG.limits.in_flight_queue` is
+///   set but is not a non-negative integer.
+/// - `CONFIG.body.spill_bytes` or `CONFIG.limits.body` is set but is not a
+///   number of bytes, or `CONFIG.body.spill_dir` is set but is not a string.
+/// - `CONFIG.lua.bytecode_cache` is set but is not a boolean,
+///   `CONFIG.lua.bytecode_cache_dir` is set but is not a string, or
+///   `CONFIG.lua.cache_max_bytes` is set but is not a positive integer.
+/// - `router.static` is given a prefix not starting with `/` or a directory
+///   that doesn't exist.
+/// - `CONFIG.access.allow` or `CONFIG.access.deny`, or a route's `allow` or
+///   `deny`, is not a list of IP addresses and CIDR ranges, or
+///   `CONFIG.access.log` is not a boolean.
+/// - `CONFIG.allowed_hosts` is not a list of host names, or has an entry with a
+///   port or a misplaced wildcard.
+/// - `CONFIG.rate_limit` or a route's `rate_limit` is not a table, lacks
+///   `requests` or `window`, has a number that isn't positive, or has a
+///   `by` other than `"ip"`.
+/// - A route's `accept_types` is empty or has an entry that is not a media
+///   type.
+/// - An `auth` table or `router.protect` has a type other than `"basic"`,
+///   no users, or a password that is not a bcrypt or argon2 hash, or
+///   `router.protect` is given a pattern with a `*` not at the end.
+/// - `CONFIG.auth` is not a table of strategies, one is not valid for its type,
+///   `CONFIG.api_keys` is not a table of SHA-256 hashes, a route names a
+///   strategy that isn't defined in `require_auth`, or a route sets both `auth`
+///   and `require_auth`.
+/// - `CONFIG.static.mmap_entries` or `CONFIG.static.mmap_max_bytes` is set but
+///   is not a positive integer.
+/// - `CONFIG.lua.script_check` or `CONFIG.lua.header_check` is set but is not
+///   `"strict"` or `"lenient"`.
+/// - `CONFIG.shutdown.grace_ms` is set but is not a number of milliseconds.
+/// - `CONFIG.shutdown.script` is set but is not a string, or the script doesn't
+///   exist.
+/// - `CONFIG.log.slow_request_ms` is set but is not a number of milliseconds.
+/// - `CONFIG.tls` is set but is not a table, lacks `cert` or `key`, an entry
+///   has the wrong type, `require_client_cert` is set without `client_ca`, a
+///   version is not `"1.2"` or `"1.3"` or `min_version` is newer than
+///   `max_version`, or `cipher_suites` is empty.
+/// - `CONFIG.pid_file` or `CONFIG.log.file` is set but is not a string.
+/// - `CONFIG.log.rotate` is set but is not a table, or lacks a valid
+///   `max_size`.
+/// - `CONFIG.run_as` is set but is not a table, lacks `user`, names a user or
+///   group that doesn't exist, or the platform isn't Unix.
+/// - `CONFIG.server_header` is set but is neither a printable ASCII string nor
+///   `false`.
+/// - `CONFIG.security_headers` is set but is not a table, or one of its headers
+///   has the wrong type or a value that can't be sent.
+/// - `CONFIG.health.live_path` or `CONFIG.health.ready_path` is set but is
+///   neither a path nor `false`, `CONFIG.health.readiness` is set but is not a
+///   function, or `CONFIG.health.readiness_interval_ms` or `CONFIG.health.log`
+///   has the wrong type.
+/// - `CONFIG.admin.token` is shorter than `admin::MIN_TOKEN_LEN`,
+///   `CONFIG.admin.addr` is not an address, or `CONFIG.admin.addr` is set
+///   without `CONFIG.admin.token`.
+/// - `CONFIG.audit.file` and `CONFIG.audit.syslog` are both set, or
+///   `CONFIG.audit.syslog` is not a syslog facility name.
+/// - `CONFIG.metrics.path` is set but is neither a path nor `false`,
+///   `CONFIG.metrics.listener` is not `"main"` or `"admin"`, or is `"admin"`
+///   without `CONFIG.admin.addr`, or `CONFIG.metrics.count_self` is not a
+///   boolean.
+fn load_lua_config(
+  routes_arc: RoutesMap,
+  paths: &paths::Paths,
+) -> std::result::Result<Config, Box<dyn std::error::Error>> {
+  let lua = Lua::new();
+  let globals = lua.globals();
+
+  let mut config = Config {
+    secrets: Arc::new(secrets::Secrets::from_env(paths.base())?),
+    ..Config::default()
+  };
+
+  let routes = Arc::new(Mutex::new(RouteTable::default()));
+  let warnings = Arc::new(Mutex::new(Vec::new()));
+  let router_table = lua.create_table()?;
+  let routes_ref = routes.clone();
+  let router_paths = paths.clone();
+  let router_warnings = warnings.clone();
+  router_table.set(
+    "add",
+    lua.create_function(move |_, (path, script, opts): (String, String, Option<LuaTable>)| {
+      let limit = match &opts {
+        Some(opts) => limiter::Limit::from_route_options(&path, opts)?,
+        None => None,
+      };
+      let require_client_cert = match &opts {
+        Some(opts) => opts.get::<Option<bool>>("require_client_cert")?.unwrap_or(false),
+        None => false,
+      };
+      let access = match &opts {
+        Some(opts) => access::AccessList::from_route_options(&path, opts)?,
+        None => None,
+      };
+      let rate_limit = match &opts {
+        Some(opts) => match opts.get::<Option<LuaTable>>("rate_limit")? {
+          Some(table) => Some(client_limit::RateLimit::from_lua(
+            &format!("router.add('{}')", path),
+            &table,
+          )?),
+          None => None,
+        },
+        None => None,
+      };
+      let accept_types = match &opts {
+        Some(opts) => content_types::AcceptTypes::from_route_options(&path, opts)?,
+        None => None,
+      };
+      let csrf = match &opts {
+        Some(opts) => opts.get::<Option<bool>>("csrf")?.unwrap_or(false),
+        None => false,
+      };
+      let secrets = match &opts {
+        Some(opts) => opts.get::<Option<Vec<String>>>("secrets")?.map(Arc::from),
+        None => None,
+      };
+      let auth = match &opts {
+        Some(opts) => match opts.get::<Option<LuaTable>>("auth")? {
+          Some(auth) => Some(auth::Auth::from_lua(
+            &format!("router.add('{}')", path),
+            &auth,
+            router_paths.base(),
+          )?),
+          None => None,
+        },
+        None => None,
+      };
+      let require_auth = match &opts {
+        Some(opts) => match opts.get::<LuaValue>("require_auth")? {
+          LuaValue::Nil => Vec::new(),
+          LuaValue::String(name) => vec![name.to_str()?.to_string()],
+          LuaValue::Table(names) => names.sequence_values::<String>().collect::<LuaResult<_>>()?,
+          _ => {
+            return Err(LuaError::external(format!(
+              "router.add('{}'): require_auth must be a strategy name or a list of them",
+              path
+            )))
+          }
+        },
+        None => Vec::new(),
+      };
+      let instruction_limit = match &opts {
+        Some(opts) => instruction_limit::from_route_options(&path, opts)?,
+        None => None,
+      };
+      if auth.is_some() && !require_auth.is_empty() {
+        return Err(LuaError::external(format!(
+          "router.add('{}'): auth and require_auth can't both be set",
+          path
+        )));
+      }
+      let mut routes = locks::lock(&routes_ref, "routes");
+
+      let full_script_path = router_paths
+        .script(&script)
+        .map_err(|e| LuaError::external(format!("Route {}: bad handler script {}", path, e)))?;
+
+      info!("Registering route: {} -> {}", path, full_script_path);
+      let mut warnings = locks::lock(&router_warnings, "config warnings");
+      let mut route_warnings = Vec::new();
+      if let Some(problem) = unreachable_route(&path) {
+        warn_config(&mut warnings, format!("Route {} can never match: {}", path, problem));
+        route_warnings.push(format!("can never match: {}", problem));
+      }
+      if let Some(previous) = routes.handlers.get(&path) {
+        warn_config(
+          &mut warnings,
+          format!("Route {} is added more than once; {} is replaced", path, previous.script),
+        );
+        route_warnings.push(format!("added more than once; {} is replaced", previous.script));
+      }
+      routes.handlers.insert(
+        path.clone(),
+        Route {
+          script: full_script_path,
+          limiter: limit.map(|limit| limiter::Limiter::new(Some(limit))),
+          require_client_cert,
+          access,
+          rate_limit,
+          accept_types,
+          csrf,
+          secrets,
+          auth,
+          require_auth,
+          instruction_limit,
+          compile_error: OnceLock::new(),
+          warnings: route_warnings,
+        },
+      );
+      Ok(())
+    })?,
+  )?;
+
+  let routes_ref = routes.clone();
+  let static_paths = paths.clone();
+  let static_warnings = warnings.clone();
+  router_table.set(
+    "static",
+    lua.create_function(move |_, (prefix, dir, opts): (String, String, Option<LuaTable>)| {
+      let mount = statics::Mount::from_lua(prefix, static_paths.resolve_string(&dir), opts)?;
+      let mut routes = locks::lock(&routes_ref, "routes");
+      info!(
+        "Registering static directory: {}/ -> {}",
+        mount.prefix,
+        mount.dir.display()
+      );
+      if let Some(previous) = routes.mounts.iter().find(|m| m.prefix == mount.prefix) {
+        warn_config(
+          &mut locks::lock(&static_warnings, "config warnings"),
+          format!(
+            "Static directory {}/ is added more than once; {} is replaced",
+            mount.prefix,
+            previous.dir.display()
+          ),
+        );
+      }
+      routes.mounts.retain(|m| m.prefix != mount.prefix);
+      routes.mounts.push(mount);
+      // Longest prefix first, so the most specific mount wins.
+      routes
+        .mounts
+        .sort_by_key(|m| std::cmp::Reverse(m.prefix.len()));
+      Ok(())
+    })?,
+  )?;
+
+  let routes_ref = routes.clone();
+  let protect_paths = paths.clone();
+  router_table.set(
+    "protect",
+    lua.create_function(move |_, (pattern, opts): (String, LuaTable)| {
+      let rule = auth::Protected::from_lua(&pattern, &opts, protect_paths.base())?;
+      let mut routes = locks::lock(&routes_ref, "routes");
+      info!("Protecting {} with basic auth", rule.pattern());
+      routes.protected.push(rule);
+      // Most specific first, so a rule for `/admin/users` can set different
+      // users than one for `/admin/*`.
+      routes
+        .protected
+        .sort_by_key(|rule| (std::cmp::Reverse(rule.path.len()), rule.prefix));
+      Ok(())
+    })?,
+  )?;
+
+  let routes_ref = routes.clone();
+  let remove_warnings = warnings.clone();
+  router_table.set(
+    "remove",
+    lua.create_function(move |_, path: String| {
+      let mut routes = locks::lock(&routes_ref, "routes");
+      let prefix = path.trim_end_matches('/');
+      let mounts = routes.mounts.len();
+      routes.mounts.retain(|m| m.prefix != prefix);
+      let removed = routes.handlers.remove(&path).is_some() || routes.mounts.len() < mounts;
+      if removed {
+        info!("Removed route: {}", path);
+      } else {
+        warn_config(
+          &mut locks::lock(&remove_warnings, "config warnings"),
+          format!("router.remove({:?}): no route or static directory has that path", path),
+        );
+      }
+      Ok(removed)
+    })?,
+  )?;
+
+  let set_addr = Arc::new(Mutex::new(None));
+  let set_addr_ref = set_addr.clone();
+  router_table.set(
+    "set_addr",
+    lua.create_function(move |_, addr: String| {
+      net::validate_addr(&addr)
+        .map_err(|e| LuaError::external(format!("Invalid server address {}", e)))?;
+      *locks::lock(&set_addr_ref, "server address") = Some(addr);
+      Ok(())
+    })?,
+  )?;
+
+  globals.set("router", router_table)?;
+
+  let workers = Arc::new(Mutex::new(Vec::new()));
+  let queue_table = lua.create_table()?;
+  let workers_ref = workers.clone();
+  let worker_paths = paths.clone();
+  queue_table.set(
+    "worker",
+    lua.create_function(
+      move |_, (name, script, opts): (String, String, Option<LuaTable>)| {
+        let full_script_path = worker_paths
+          .script(&script)
+          .map_err(|e| LuaError::external(format!("Queue {}: bad worker script {}", name, e)))?;
+
+        let spec = fyre::queue::WorkerSpec::from_lua(name, full_script_path, opts)?;
+        info!(
+          "Registering queue worker: {} -> {} (x{})",
+          spec.queue, spec.script, spec.concurrency
+        );
+        locks::lock(&workers_ref, "queue workers").push(spec);
+        Ok(())
+      },
+    )?,
+  )?;
+  globals.set("queue", queue_table)?;
+
+  let schedules = Arc::new(Mutex::new(Vec::new()));
+  let schedule_table = lua.create_table()?;
+  for kind in ["every", "cron"] {
+    let schedules_ref = schedules.clone();
+    let task_paths = paths.clone();
+    schedule_table.set(
+      kind,
+      lua.create_function(move |_, (when, script): (String, String)| {
+        let full_script_path = task_paths
+          .script(&script)
+          .map_err(|e| LuaError::external(format!("Bad task script {}", e)))?;
+
+        let timing = if kind == "every" {
+          schedule::parse_interval(&when).map(schedule::Timing::Every)
+        } else {
+          schedule::CronSchedule::parse(&when).map(schedule::Timing::Cron)
+        }
+        .map_err(LuaError::external)?;
+        info!("Registering task: {} ({})", full_script_path, timing);
+        locks::lock(&schedules_ref, "schedules")
+          .push(schedule::Task::new(full_script_path, timing));
+        Ok(())
+      })?,
+    )?;
+  }
+  globals.set("schedule", schedule_table)?;
+
+  let fyre_table = lua.create_table()?;
+  fyre_table.set(
+    "env",
+    fyre::env::module(&lua, &Arc::new(fyre::env::EnvAccess::unrestricted()))?,
+  )?;
+  fyre_table.set("env_name", paths.env())?;
+  fyre_table.set("secrets", fyre::secrets::module(&lua, &config.secrets)?)?;
+  globals.set("fyre", fyre_table)?;
+  let missing_env = Arc::new(Mutex::new(Vec::new()));
+  globals.set("env", fyre::env::config_global(&lua, missing_env.clone())?)?;
+  let included = Arc::new(Mutex::new(Vec::new()));
+  let include = include::config_global(&lua, paths.base().to_path_buf(), included.clone())?;
+  globals.set("include", include.clone())?;
+
+  let config_file = paths.config_file();
+  let config_code = fs::read_to_string(config_file)
+    .map_err(|e| format!("Failed to read {}: {}", config_file.display(), e))?;
+  // The environment's file runs like an `include` at the end, so its
+  // `CONFIG` keys override the ones set before it.
+  let env_file = paths.env_config_file();
+  let result = lua
+    .load(&config_code)
+    .set_name(config_file.display().to_string())
+    .exec()
+    .and_then(|()| match env_file.file_name() {
+      Some(name) if env_file.is_file() => include.call::<()>(name.to_string_lossy()),
+      _ => Ok(()),
+    });
+  // Reported first, since a missing variable is the likely cause of any
+  // error after it.
+  let missing_env = std::mem::take(&mut *locks::lock(
+    &missing_env,
+    "missing environment variables",
+  ));
+  if !missing_env.is_empty() {
+    return Err(
+      format!(
+        "Missing required environment variable(s): {}",
+        missing_env.join(", ")
+      )
+      .into(),
+    );
+  }
+  result?;
+  if let Some(warning) = settings::apply(&globals)? {
+    warn_config(&mut locks::lock(&warnings, "config warnings"), warning);
+  }
+  config.effective = settings::effective(&globals, &config.secrets.values())?;
+
+  if let Some(addr) = globals
+    .get::<Option<String>>("SERVER_ADDR")
+    .map_err(|e| format!("CONFIG.addr must be an address: {}", e))?
+  {
+    config.server_addrs = vec![addr];
+  }
+
+  if let Some(addrs) = globals
+    .get::<Option<Vec<String>>>("SERVER_ADDRS")
+    .map_err(|e| format!("CONFIG.addrs must be a list of addresses: {}", e))?
+  {
+    if !config.server_addrs.is_empty() {
+      return Err("Set CONFIG.addr or CONFIG.addrs, not both".into());
+    }
+    if addrs.is_empty() {
+      return Err("CONFIG.addrs must list at least one address".into());
+    }
+    config.server_addrs = addrs;
+  }
+  // Either global takes precedence over `router.set_addr`.
+  if config.server_addrs.is_empty() {
+    config
+      .server_addrs
+      .extend(locks::lock(&set_addr, "server address").take());
+  }
+  for addr in &config.server_addrs {
+    net::validate_addr(addr).map_err(|e| format!("Invalid server address {}", e))?;
+  }
+
+  config.bind_check = match globals
+    .get::<Option<String>>("BIND_CHECK")
+    .map_err(|e| format!("CONFIG.bind_check must be \"strict\" or \"lenient\": {}", e))?
+    .as_deref()
+  {
+    None | Some("strict") => BindCheck::Strict,
+    Some("lenient") => BindCheck::Lenient,
+    Some(other) => {
+      return Err(
+        format!("CONFIG.bind_check must be \"strict\" or \"lenient\", got {:?}", other).into(),
+      );
+    }
+  };
+
+  config.http_allow = globals
+    .get::<Option<Vec<String>>>("HTTP_ALLOW")
+    .map_err(|e| format!("CONFIG.sandbox.http_allow must be a list of host names: {}", e))?;
+  config.http_allow_private = globals
+    .get::<Option<bool>>("HTTP_ALLOW_PRIVATE")
+    .map_err(|e| format!("CONFIG.sandbox.http_allow_private must be a boolean: {}", e))?
+    .unwrap_or(false);
+
+  config.env_allowlist = globals
+    .get::<Option<Vec<String>>>("ENV_ALLOWLIST")
+    .map_err(|e| format!("CONFIG.sandbox.env_allow must be a list of variable names: {}", e))?
+    .unwrap_or_default();
+
+  config.kv_max_entries = globals
+    .get::<Option<usize>>("KV_MAX_ENTRIES")
+    .map_err(|e| format!("CONFIG.kv.max_entries must be a positive integer: {}", e))?;
+  if config.kv_max_entries == Some(0) {
+    return Err("CONFIG.kv.max_entries must be a positive integer".into());
+  }
+
+  config.cache_max_entries = globals
+    .get::<Option<usize>>("CACHE_MAX_ENTRIES")
+    .map_err(|e| format!("CONFIG.cache.max_entries must be a positive integer: {}", e))?;
+  if config.cache_max_entries == Some(0) {
+    return Err("CONFIG.cache.max_entries must be a positive integer".into());
+  }
+
+  config.sqlite_dir = globals
+    .get::<Option<String>>("SQLITE_DIR")
+    .map_err(|e| format!("CONFIG.sqlite.dir must be a directory path: {}", e))?
+    .map(|dir| paths.resolve_string(&dir));
+
+  config.fs_allow = globals
+    .get::<Option<Vec<String>>>("FS_ALLOW")
+    .map_err(|e| format!("CONFIG.sandbox.fs_allow must be a list of directories: {}", e))?
+    .unwrap_or_default()
+    .iter()
+    .map(|dir| paths.resolve_string(dir))
+    .collect();
+
+  config.fs_max_read_bytes = globals
+    .get::<Option<u64>>("FS_MAX_READ_BYTES")
+    .map_err(|e| format!("CONFIG.sandbox.fs_max_read_bytes must be a number of bytes: {}", e))?;
+
+  config.session = load_session_config(&globals)?;
+  config.cookie_keys = globals
+    .get::<Option<LuaTable>>("KEYS")
+    .map_err(|e| format!("CONFIG.keys must be a table: {}", e))?
+    .map(|table| fyre::cookie::CookieKeys::from_lua(&table))
+    .transpose()?;
+
+  config.redis_url = globals
+    .get::<Option<String>>("REDIS_URL")
+    .map_err(|e| format!("CONFIG.redis.url must be a redis:// url: {}", e))?;
+
+  config.redis_pool_size = globals
+    .get::<Option<usize>>("REDIS_POOL_SIZE")
+    .map_err(|e| format!("CONFIG.redis.pool_size must be a number of connections: {}", e))?;
+
+  config.redis_timeout_ms = globals
+    .get::<Option<u64>>("REDIS_TIMEOUT_MS")
+    .map_err(|e| format!("CONFIG.redis.timeout_ms must be a positive integer: {}", e))?;
+  if config.redis_timeout_ms == Some(0) {
+    return Err("CONFIG.redis.timeout_ms must be a positive integer".into());
+  }
+
+  config.exec_allow = globals
+    .get::<Option<Vec<String>>>("EXEC_ALLOW")
+    .map_err(|e| format!("CONFIG.sandbox.exec_allow must be a list of program names: {}", e))?
+    .unwrap_or_default();
+
+  config.queue_dir = globals
+    .get::<Option<String>>("QUEUE_DIR")
+    .map_err(|e| format!("CONFIG.queue.dir must be a directory path: {}", e))?
+    .map(|dir| paths.resolve_string(&dir));
+
+  config.metrics_max_series = globals
+    .get::<Option<usize>>("METRICS_MAX_SERIES")
+    .map_err(|e| format!("CONFIG.metrics.max_series must be a positive integer: {}", e))?;
+  if config.metrics_max_series == Some(0) {
+    return Err("CONFIG.metrics.max_series must be a positive integer".into());
+  }
+
+  config.smtp = load_smtp_config(&globals)?;
+
+  config.workers = globals
+    .get::<Option<usize>>("WORKERS")
+    .map_err(|e| format!("CONFIG.workers must be a positive integer: {}", e))?;
+  if config.workers == Some(0) {
+    return Err("CONFIG.workers must be a positive integer".into());
+  }
+  if config.workers.is_some_and(|n| n > worker_stats::MAX_WORKERS) {
+    return Err(format!("CONFIG.workers must be at most {}", worker_stats::MAX_WORKERS).into());
+  }
+
+  config.lua_state_max_uses = globals
+    .get::<Option<u32>>("LUA_STATE_MAX_USES")
+    .map_err(|e| format!("CONFIG.lua.state_max_uses must be a positive integer: {}", e))?;
+  if config.lua_state_max_uses == Some(0) {
+    return Err("CONFIG.lua.state_max_uses must be a positive integer".into());
+  }
+
+  config.instruction_limit = globals
+    .get::<Option<u64>>("LUA_INSTRUCTION_LIMIT")
+    .map_err(|e| format!("CONFIG.lua.instruction_limit must be a positive integer: {}", e))?;
+  if config.instruction_limit == Some(0) {
+    return Err("CONFIG.lua.instruction_limit must be a positive integer".into());
+  }
+
+  config.socket = load_socket_options(&globals)?;
+
+  config.connections = load_connection_limits(&globals)?;
+
+  config.in_flight = load_in_flight_limit(&globals)?;
+
+  let access_list = |name: &str| {
+    globals
+      .get::<Option<Vec<String>>>(name)
+      .map(Option::unwrap_or_default)
+      .map_err(|e| {
+        let name = settings::key(name);
+        format!("{} must be a list of addresses or CIDR ranges: {}", name, e)
+      })
+  };
+  config.access = access::AccessList::parse(
+    "CONFIG.access",
+    &access_list("ACCESS_ALLOW")?,
+    &access_list("ACCESS_DENY")?,
+  )?;
+  config.access_log = globals
+    .get::<Option<bool>>("ACCESS_LOG")
+    .map_err(|e| format!("CONFIG.access.log must be a boolean: {}", e))?
+    .unwrap_or(false);
+  config.allowed_hosts = hosts::AllowedHosts::parse(
+    &globals
+      .get::<Option<Vec<String>>>("ALLOWED_HOSTS")
+      .map_err(|e| format!("CONFIG.allowed_hosts must be a list of host names: {}", e))?
+      .unwrap_or_default(),
+  )?;
+  config.rate_limit = globals
+    .get::<Option<LuaTable>>("RATE_LIMIT")
+    .map_err(|e| format!("CONFIG.rate_limit must be a table: {}", e))?
+    .map(|table| client_limit::RateLimit::from_lua("CONFIG.rate_limit", &table))
+    .transpose()?;
+
+  if let Some(threshold) = globals
+    .get::<Option<u64>>("BODY_SPILL_BYTES")
+    .map_err(|e| format!("CONFIG.body.spill_bytes must be a number of bytes: {}", e))?
+  {
+    config.body_spill.threshold = threshold;
+  }
+  if let Some(dir) = globals
+    .get::<Option<String>>("BODY_SPILL_DIR")
+    .map_err(|e| format!("CONFIG.body.spill_dir must be a directory path: {}", e))?
+  {
+    config.body_spill.dir = paths.resolve(dir);
+  }
+  if let Some(max) = globals
+    .get::<Option<u64>>("MAX_BODY_BYTES")
+    .map_err(|e| format!("CONFIG.limits.body must be a number of bytes: {}", e))?
+  {
+    config.body_spill.max = max;
+  }
+
+  config.bytecode_cache = globals
+    .get::<Option<bool>>("BYTECODE_CACHE")
+    .map_err(|e| format!("CONFIG.lua.bytecode_cache must be a boolean: {}", e))?
+    .unwrap_or(false);
+
+  config.bytecode_cache_dir = globals
+    .get::<Option<String>>("BYTECODE_CACHE_DIR")
+    .map_err(|e| format!("CONFIG.lua.bytecode_cache_dir must be a directory path: {}", e))?
+    .map(|dir| paths.resolve_string(&dir));
+
+  config.cache_max_bytes = globals
+    .get::<Option<usize>>("CACHE_MAX_BYTES")
+    .map_err(|e| format!("CONFIG.lua.cache_max_bytes must be a positive integer: {}", e))?;
+  if config.cache_max_bytes == Some(0) {
+    return Err("CONFIG.lua.cache_max_bytes must be a positive integer".into());
+  }
+
+  config.static_mmap_entries = globals
+    .get::<Option<usize>>("STATIC_MMAP_ENTRIES")
+    .map_err(|e| format!("CONFIG.static.mmap_entries must be a positive integer: {}", e))?;
+  if config.static_mmap_entries == Some(0) {
+    return Err("CONFIG.static.mmap_entries must be a positive integer".into());
+  }
+
+  config.static_mmap_max_bytes = globals
+    .get::<Option<u64>>("STATIC_MMAP_MAX_BYTES")
+    .map_err(|e| format!("CONFIG.static.mmap_max_bytes must be a positive integer: {}", e))?;
+  if config.static_mmap_max_bytes == Some(0) {
+    return Err("CONFIG.static.mmap_max_bytes must be a positive integer".into());
+  }
+
+  config.script_check = match globals
+    .get::<Option<String>>("SCRIPT_CHECK")
+    .map_err(|e| format!("CONFIG.lua.script_check must be \"strict\" or \"lenient\": {}", e))?
+    .as_deref()
+  {
+    None | Some("strict") => ScriptCheck::Strict,
+    Some("lenient") => ScriptCheck::Lenient,
+    Some(other) => {
+      return Err(
+        format!("CONFIG.lua.script_check must be \"strict\" or \"lenient\", got {:?}", other)
+          .into(),
+      );
+    }
+  };
+
+  config.header_check = match globals
+    .get::<Option<String>>("HEADER_CHECK")
+    .map_err(|e| format!("CONFIG.lua.header_check must be \"strict\" or \"lenient\": {}", e))?
+    .as_deref()
+  {
+    None | Some("lenient") => HeaderCheck::Lenient,
+    Some("strict") => HeaderCheck::Strict,
+    Some(other) => {
+      return Err(
+        format!("CONFIG.lua.header_check must be \"strict\" or \"lenient\", got {:?}", other)
+          .into(),
+      );
+    }
+  };
+
+  config.shutdown_grace_ms = globals
+    .get::<Option<u64>>("SHUTDOWN_GRACE_MS")
+    .map_err(|e| format!("CONFIG.shutdown.grace_ms must be a number of milliseconds: {}", e))?;
+
+  if let Some(script) = globals
+    .get::<Option<String>>("ON_SHUTDOWN")
+    .map_err(|e| format!("CONFIG.shutdown.script must be a script filename: {}", e))?
+  {
+    let full_script_path = paths
+      .script(&script)
+      .map_err(|e| format!("Bad CONFIG.shutdown.script {}", e))?;
+    config.on_shutdown = Some(full_script_path);
+  }
+
+  config.slow_request_ms = globals
+    .get::<Option<u64>>("SLOW_REQUEST_MS")
+    .map_err(|e| format!("CONFIG.log.slow_request_ms must be a number of milliseconds: {}", e))?;
+
+  config.tls = globals
+    .get::<Option<LuaTable>>("TLS")
+    .map_err(|e| format!("TLS must be a table: {}", e))?
+    .map(|table| tls::TlsSettings::from_lua(&table))
+    .transpose()?
+    .map(|tls| tls::TlsSettings {
+      cert: paths.resolve(&tls.cert),
+      key: paths.resolve(&tls.key),
+      client_ca: tls.client_ca.as_ref().map(|path| paths.resolve(path)),
+      ..tls
+    });
+
+  config.pid_file = globals
+    .get::<Option<String>>("PID_FILE")
+    .map_err(|e| format!("CONFIG.pid_file must be a file path: {}", e))?
+    .map(|path| paths.resolve(path));
+
+  config.log_file = globals
+    .get::<Option<String>>("LOG_FILE")
+    .map_err(|e| format!("CONFIG.log.file must be a file path: {}", e))?
+    .map(|path| paths.resolve(path));
+
+  config.log_rotate = globals
+    .get::<Option<LuaTable>>("LOG_ROTATE")
+    .map_err(|e| format!("CONFIG.log.rotate must be a table: {}", e))?
+    .map(|table| logger::Rotate::from_lua(&table))
+    .transpose()?;
+
+  config.log_format = match globals
+    .get::<Option<String>>("LOG_FORMAT")
+    .map_err(|e| format!("CONFIG.log.format must be \"plain\" or \"json\": {}", e))?
+    .as_deref()
+  {
+    None | Some("plain") => logger::Format::Plain,
+    Some("json") => logger::Format::Json,
+    Some(other) => {
+      return Err(
+        format!("CONFIG.log.format must be \"plain\" or \"json\", got {:?}", other).into(),
+      );
+    }
+  };
+
+  config.log_level = globals
+    .get::<Option<String>>("LOG_LEVEL")
+    .map_err(|e| format!("CONFIG.log.level must be a string: {}", e))?
+    .map(|spec| spec.parse::<logger::Filter>())
+    .transpose()
+    .map_err(|e| format!("CONFIG.log.level: {}", e))?;
+
+  config.run_as = globals
+    .get::<Option<LuaTable>>("RUN_AS")
+    .map_err(|e| format!("CONFIG.run_as must be a table: {}", e))?
+    .map(|table| privileges::RunAs::from_lua(&table))
+    .transpose()?;
+
+  config.health = health::HealthSettings::from_globals(&globals)?;
+
+  config.request_log = request_log::RequestLog::from_globals(&globals)?;
+
+  config.server_header = match globals.get::<LuaValue>("SERVER_HEADER")? {
+    LuaValue::Nil => Some(DEFAULT_SERVER_HEADER.to_string()),
+    LuaValue::Boolean(false) => None,
+    LuaValue::String(value)
+      if !value.as_bytes().is_empty()
+        && value.as_bytes().iter().all(|&b| (b' '..=b'~').contains(&b)) =>
+    {
+      Some(value.to_string_lossy())
+    }
+    _ => {
+      return Err(
+        "CONFIG.server_header must be a non-empty string of printable ASCII or false".into(),
+      )
+    }
+  };
+
+  config.security_headers = globals
+    .get::<Option<LuaTable>>("SECURITY_HEADERS")
+    .map_err(|e| format!("CONFIG.security_headers must be a table: {}", e))?
+    .map(|table| security_headers::SecurityHeaders::from_lua(&table))
+    .transpose()?;
+
+  let admin_token = globals
+    .get::<Option<String>>("ADMIN_TOKEN")
+    .map_err(|e| format!("CONFIG.admin.token must be a string: {}", e))?;
+  let admin_addr = globals
+    .get::<Option<String>>("ADMIN_ADDR")
+    .map_err(|e| format!("CONFIG.admin.addr must be an address: {}", e))?;
+  config.admin = match (admin_token, admin_addr) {
+    (Some(token), _) if token.len() < admin::MIN_TOKEN_LEN => {
+      return Err(
+        format!(
+          "CONFIG.admin.token must be at least {} characters",
+          admin::MIN_TOKEN_LEN
+        )
+        .into(),
+      );
+    }
+    (Some(token), addr) => {
+      if let Some(addr) = &addr {
+        net::validate_addr(addr).map_err(|e| format!("Invalid CONFIG.admin.addr {}", e))?;
+      } else if config.tls.is_none() {
+        warn_config(
+          &mut locks::lock(&warnings, "config warnings"),
+          "The admin endpoints are served without TLS on the server's addresses; set \
+           ADMIN_ADDR to a local address to keep the token off the network"
+            .to_string(),
+        );
+      }
+      Some(admin::AdminSettings { token, addr })
+    }
+    (None, Some(_)) => return Err("CONFIG.admin.addr is set but CONFIG.admin.token isn't".into()),
+    (None, None) => None,
+  };
+
+  config.metrics_endpoint = request_metrics::Endpoint::from_globals(&globals)?;
+  let admin_addr = config.admin.as_ref().and_then(|admin| admin.addr.as_ref());
+  if config.metrics_endpoint.admin_only && admin_addr.is_none() {
+    return Err("metrics.listener = \"admin\" needs admin.addr".into());
+  }
+
+  config.tracing = span_export::Settings::from_globals(&globals)?;
+  config.error_reporting = error_reports::Settings::from_globals(&globals)?;
+  config.debug_dump = debug_dump::Settings::from_globals(&globals)?;
+  config.debug_dump.dir = paths.resolve(&config.debug_dump.dir);
+
+  config.audit = audit::Destination::from_globals(&globals)?.map(|destination| match destination {
+    audit::Destination::File(path) => audit::Destination::File(paths.resolve(path)),
+    destination => destination,
+  });
+
+  if config.tls.as_ref().is_none_or(|tls| tls.client_ca.is_none()) {
+    let mut routes = locks::lock(&routes, "routes");
+    let mut requiring: Vec<&str> = Vec::new();
+    for (path, route) in routes.handlers.iter_mut() {
+      if route.require_client_cert {
+        route
+          .warnings
+          .push("requires a client certificate, but CONFIG.tls.client_ca isn't set".to_string());
+        requiring.push(path);
+      }
+    }
+    if !requiring.is_empty() {
+      requiring.sort_unstable();
+      warn_config(
+        &mut locks::lock(&warnings, "config warnings"),
+        format!(
+          "Route(s) {} require a client certificate, but TLS.client_ca isn't set, so every \
+           request to them gets 403",
+          requiring.join(", ")
+        ),
+      );
+    }
+  }
+
+  if config.session.is_none() {
+    let mut routes = locks::lock(&routes, "routes");
+    let mut requiring: Vec<&str> = Vec::new();
+    for (path, route) in routes.handlers.iter_mut() {
+      if route.csrf {
+        route
+          .warnings
+          .push("requires a CSRF token, but CONFIG.session.secret isn't set".to_string());
+        requiring.push(path);
+      }
+    }
+    if !requiring.is_empty() {
+      requiring.sort_unstable();
+      warn_config(
+        &mut locks::lock(&warnings, "config warnings"),
+        format!(
+          "Route(s) {} require a CSRF token, but SESSION_SECRET isn't set, so every unsafe \
+           request to them gets 403",
+          requiring.join(", ")
+        ),
+      );
+    }
+  }
+
+  let auth_table = globals
+    .get::<Option<LuaTable>>("AUTH")
+    .map_err(|e| format!("CONFIG.auth must be a table of strategies by name: {}", e))?;
+  let api_keys = globals
+    .get::<Option<LuaTable>>("API_KEYS")
+    .map_err(|e| format!("CONFIG.api_keys must be a table of key hashes by id: {}", e))?;
+  let strategies = auth::strategies_from_lua(
+    &lua,
+    auth_table.as_ref(),
+    api_keys.as_ref(),
+    paths.base(),
+  )?;
+  {
+    let mut routes = locks::lock(&routes, "routes");
+    for (path, route) in &routes.handlers {
+      if let Some(name) = route
+        .require_auth
+        .iter()
+        .find(|name| !strategies.contains_key(*name))
+      {
+        return Err(
+          format!(
+            "Route {} requires auth strategy '{}', which CONFIG.auth doesn't define",
+            path, name
+          )
+          .into(),
+        );
+      }
+    }
+    routes.strategies = strategies;
+  }
+
+  config.queue_workers = std::mem::take(&mut *locks::lock(&workers, "queue workers"));
+  config.schedules = std::mem::take(&mut *locks::lock(&schedules, "schedules"));
+  config.warnings = std::mem::take(&mut *locks::lock(&warnings, "config warnings"));
+  config.included = std::mem::take(&mut *locks::lock(&included, "included files"));
+
+  let mut routes = std::mem::take(&mut *locks::lock(&routes, "routes"));
+  routes.index();
+  routes_arc.store(Arc::new(routes));
+
+  Ok(config)
+}
+
+/// Why a route added for `path` can never match a request, if it can't:
+/// request paths start with `/`, have no whitespace, and never carry a
+/// fragment.
+fn unreachable_route(path: &str) -> Option<&'static str> {
+  if !path.starts_with('/') {
+    Some("request paths start with '/'")
+  } else if path.contains(char::is_whitespace) {
+    Some("request paths can't contain whitespace")
+  } else if path.contains('#') {
+    Some("clients don't send the '#' fragment")
+  } else {
+    None
+  }
+}
+
+/// Logs a configuration warning and keeps it for `Config::warnings`.
+fn warn_config(warnings: &mut Vec<String>, warning: String) {
+  warn!("{}", warning);
+  warnings.push(warning);
+}
+
+/// Compiles every handler script without running it, so a syntax error
+/// shows up at startup rather than as a `500` on the route's first request.
+/// All the failures are logged together, and the routes using a failed
+/// script are marked with its error, which makes them answer `503` with
+/// `ScriptCheck::Lenient`.
+///
+/// # Errors
+///
+/// This function will return an error if a script fails to compile and
+/// `check` is `ScriptCheck::Strict`.
+fn check_scripts(
+  table: &RouteTable,
+  scripts: &script_cache::ScriptCache,
+  check: ScriptCheck,
+) -> std::result::Result<(), Box<dyn std::error::Error>> {
+  let mut paths: Vec<String> = table.handlers.values().map(|r| r.script.clone()).collect();
+  paths.sort();
+  paths.dedup();
+
+  let started = std::time::Instant::now();
+  let threads = std::thread::available_parallelism()
+    .map_or(1, |n| n.get())
+    .min(SCRIPT_CHECK_THREADS);
+  let failures = scripts.check_all(&paths, threads);
+  info!(
+    "Compiled {} handler script(s) in {} ms",
+    paths.len(),
+    started.elapsed().as_millis()
+  );
+  if failures.is_empty() {
+    return Ok(());
+  }
+
+  for (_, error) in &failures {
+    error!("Handler script failed to compile: {}", error);
+  }
+  let count = failures.len();
+  mark_failed(table, failures);
+  if check == ScriptCheck::Strict {
+    return Err(format!("{} handler script(s) failed to compile", count).into());
+  }
+  for (path, route) in &table.handlers {
+    if route.compile_error.get().is_some() {
+      warn!("Route {} will answer 503: {}", path, route.script);
+    }
+  }
+  Ok(())
+}
+
+/// Sets `compile_error` on the routes using each script in `failures`, a
+/// list of scripts and why they failed to compile.
+fn mark_failed(table: &RouteTable, failures: Vec<(String, String)>) {
+  let failures: HashMap<String, String> = failures.into_iter().collect();
+  for route in table.handlers.values() {
+    if let Some(error) = failures.get(&route.script) {
+      let _ = route.compile_error.set(error.clone());
+    }
+  }
+}
+
+/// Prints the route summary, with every handler script compiled, for
+/// `fyre routes`.
+///
+/// # Errors
+///
+/// This function will return an error if the configuration doesn't load.
+fn print_routes(args: &cli::ConfigArgs) -> std::result::Result<(), Box<dyn std::error::Error>> {
+  let started = std::time::Instant::now();
+  let routes: RoutesMap = Arc::new(ArcSwap::from_pointee(RouteTable::default()));
+  let config = load_lua_config(routes.clone(), &paths::Paths::new(args)?)?;
+  let table = routes.load();
+
+  let mut scripts: Vec<String> = table.handlers.values().map(|r| r.script.clone()).collect();
+  scripts.sort();
+  scripts.dedup();
+  let threads = std::thread::available_parallelism()
+    .map_or(1, |n| n.get())
+    .min(SCRIPT_CHECK_THREADS);
+  let cache = script_cache::ScriptCache::new(false, None, script_cache::DEFAULT_MAX_BYTES);
+  mark_failed(&table, cache.check_all(&scripts, threads));
+
+  for line in route_summary::lines(&table, config.schedules.len(), started.elapsed()) {
+    println!("{}", line);
+  }
+  Ok(())
+}
+
+/// Reads the `fyre.session` settings from the config globals.
+///
+/// Returns `None` when `CONFIG.session.secret` is not set, which leaves
+/// sessions disabled.
+///
+/// # Errors
+///
+/// This function will return an error if a setting has the wrong type, the
+/// secret is empty, or `CONFIG.session.store` is not `"cookie"` or `"kv"`.
+fn load_session_config(
+  globals: &LuaTable,
+) -> std::result::Result<Option<fyre::session::SessionConfig>, Box<dyn std::error::Error>> {
+  let Some(secret) = globals
+    .get::<Option<LuaString>>("SESSION_SECRET")
+    .map_err(|e| format!("CONFIG.session.secret must be a string: {}", e))?
+  else {
+    return Ok(None);
+  };
+  if secret.as_bytes().is_empty() {
+    return Err("CONFIG.session.secret must not be empty".into());
+  }
+
+  let store = match globals
+    .get::<Option<String>>("SESSION_STORE")
+    .map_err(|e| format!("CONFIG.session.store must be a string: {}", e))?
+    .as_deref()
+  {
+    None | Some("cookie") => fyre::session::SessionStore::Cookie,
+    Some("kv") => fyre::session::SessionStore::Kv,
+    Some(other) => {
+      return Err(
+        format!("CONFIG.session.store must be \"cookie\" or \"kv\", got \"{}\"", other).into(),
+      )
+    }
+  };
+
+  let ttl = globals
+    .get::<Option<u64>>("SESSION_TTL")
+    .map_err(|e| format!("CONFIG.session.ttl must be a number of seconds: {}", e))?
+    .map(std::time::Duration::from_secs)
+    .unwrap_or(fyre::session::DEFAULT_TTL);
+
+  let cookie_name = globals
+    .get::<Option<String>>("SESSION_COOKIE")
+    .map_err(|e| format!("CONFIG.session.cookie must be a string: {}", e))?
+    .unwrap_or_else(|| fyre::session::DEFAULT_COOKIE_NAME.to_string());
+
+  let secure = globals
+    .get::<Option<bool>>("SESSION_SECURE")
+    .map_err(|e| format!("CONFIG.session.secure must be a boolean: {}", e))?
+    .unwrap_or(false);
+
+  Ok(Some(fyre::session::SessionConfig {
+    secret: secret.as_bytes().to_vec(),
+    cookie_name,
+    ttl,
+    store,
+    secure,
+  }))
+}
+
+/// Reads the listening socket options from the config globals. Unset
+/// options keep the operating system defaults.
+///
+/// # Errors
+///
+/// This function will return an error if `CONFIG.socket.nodelay`,
+/// `CONFIG.bind.reuse_addr`, or `CONFIG.bind.reuse_port` is not a boolean,
+/// `CONFIG.socket.backlog`, `CONFIG.socket.recv_buffer`, or
+/// `CONFIG.socket.send_buffer` is not a positive integer within its limit,
+/// `CONFIG.socket.unix_mode` is not an octal mode, or `CONFIG.bind.retry` lacks
+/// a positive number of `attempts`.
+fn load_socket_options(
+  globals: &LuaTable,
+) -> std::result::Result<net::SocketOptions, Box<dyn std::error::Error>> {
+  let mut options = net::SocketOptions::default();
+
+  if let Some(nodelay) = globals
+    .get::<Option<bool>>("TCP_NODELAY")
+    .map_err(|e| format!("CONFIG.socket.nodelay must be a boolean: {}", e))?
+  {
+    options.nodelay = nodelay;
+  }
+
+  if let Some(backlog) = globals
+    .get::<Option<u32>>("LISTEN_BACKLOG")
+    .map_err(|e| format!("CONFIG.socket.backlog must be a positive integer: {}", e))?
+  {
+    if backlog == 0 || backlog > net::MAX_BACKLOG {
+      return Err(
+        format!("CONFIG.socket.backlog must be between 1 and {}", net::MAX_BACKLOG).into(),
+      );
+    }
+    options.backlog = backlog;
+  }
+
+  if let Some(mode) = globals
+    .get::<Option<String>>("UNIX_SOCKET_MODE")
+    .map_err(|e| format!("CONFIG.socket.unix_mode must be an octal string: {}", e))?
+  {
+    options.unix_mode = Some(
+      u32::from_str_radix(&mode, 8)
+        .ok()
+        .filter(|mode| *mode <= 0o777)
+        .ok_or_else(|| {
+          format!("CONFIG.socket.unix_mode must be octal, e.g. \"660\", got {:?}", mode)
+        })?,
+    );
+  }
+
+  for (name, slot) in [
+    ("SO_REUSEADDR", &mut options.reuse_addr),
+    ("SO_REUSEPORT", &mut options.reuse_port),
+  ] {
+    if let Some(value) = globals
+      .get::<Option<bool>>(name)
+      .map_err(|e| format!("{} must be a boolean: {}", settings::key(name), e))?
+    {
+      *slot = value;
+    }
+  }
+
+  if let Some(retry) = globals
+    .get::<Option<LuaTable>>("BIND_RETRY")
+    .map_err(|e| format!("CONFIG.bind.retry must be a table: {}", e))?
+  {
+    let attempts = retry
+      .get::<Option<u32>>("attempts")
+      .map_err(|e| format!("CONFIG.bind.retry.attempts must be a positive integer: {}", e))?
+      .ok_or("CONFIG.bind.retry needs attempts")?;
+    if attempts == 0 {
+      return Err("CONFIG.bind.retry.attempts must be a positive integer".into());
+    }
+    let delay = retry
+      .get::<Option<u64>>("delay_ms")
+      .map_err(|e| format!("CONFIG.bind.retry.delay_ms must be a number of milliseconds: {}", e))?
+      .map_or(net::DEFAULT_RETRY_DELAY, std::time::Duration::from_millis);
+    options.retry = net::BindRetry { attempts, delay };
+  }
+
+  for (name, slot) in [
+    ("SO_RCVBUF", &mut options.recv_buffer),
+    ("SO_SNDBUF", &mut options.send_buffer),
+  ] {
+    *slot = globals
+      .get::<Option<usize>>(name)
+      .map_err(|e| format!("{} must be a number of bytes: {}", settings::key(name), e))?;
+    if slot.is_some_and(|size| size == 0 || size > net::MAX_BUFFER_BYTES) {
+      return Err(
+        format!(
+          "{} must be between 1 and {} bytes",
+          settings::key(name),
+          net::MAX_BUFFER_BYTES
+        )
+        .into(),
+      );
+    }
+  }
+
+  Ok(options)
+}
+
+/// Reads the connection limits from the config globals. Unset limits keep
+/// their defaults.
+///
+/// # Errors
+///
+/// This function will return an error if `CONFIG.limits.connections`,
+/// `CONFIG.limits.requests_per_connection`, one of the timeouts, or one of the
+/// request head limits is not a positive integer, or `CONFIG.http.keep_alive`
+/// or `CONFIG.http.version_compat` is not a boolean.
+fn load_connection_limits(
+  globals: &LuaTable,
+) -> std::result::Result<server::Limits, Box<dyn std::error::Error>> {
+  let mut limits = server::Limits::default();
+
+  for (global, flag) in [
+    ("HTTP_KEEP_ALIVE", &mut limits.keep_alive),
+    ("HTTP_VERSION_COMPAT", &mut limits.version_compat),
+  ] {
+    if let Some(value) = globals
+      .get::<Option<bool>>(global)
+      .map_err(|e| format!("{} must be a boolean: {}", settings::key(global), e))?
+    {
+      *flag = value;
+    }
+  }
+
+  if let Some(max) = globals
+    .get::<Option<usize>>("MAX_CONNECTIONS")
+    .map_err(|e| format!("CONFIG.limits.connections must be a positive integer: {}", e))?
+  {
+    if max == 0 {
+      return Err("CONFIG.limits.connections must be a positive integer".into());
+    }
+    limits.max_connections = max;
+  }
+
+  for (global, timeout) in [
+    ("KEEP_ALIVE_TIMEOUT_MS", &mut limits.keep_alive_timeout),
+    ("HEADER_READ_TIMEOUT_MS", &mut limits.header_read_timeout),
+    ("HEADER_DEADLINE_MS", &mut limits.header_deadline),
+    ("BODY_READ_TIMEOUT_MS", &mut limits.body_read_timeout),
+    ("WRITE_TIMEOUT_MS", &mut limits.write_timeout),
+  ] {
+    match globals
+      .get::<Option<u64>>(global)
+      .map_err(|e| format!("{} must be a positive integer: {}", settings::key(global), e))?
+    {
+      Some(0) => return Err(format!("{} must be a positive integer", settings::key(global)).into()),
+      Some(timeout_ms) => *timeout = std::time::Duration::from_millis(timeout_ms),
+      None => {}
+    }
+  }
+
+  limits.max_requests_per_connection = globals
+    .get::<Option<u32>>("MAX_REQUESTS_PER_CONNECTION")
+    .map_err(|e| {
+      format!("CONFIG.limits.requests_per_connection must be a positive integer: {}", e)
+    })?;
+  if limits.max_requests_per_connection == Some(0) {
+    return Err("CONFIG.limits.requests_per_connection must be a positive integer".into());
+  }
+
+  for (global, limit) in [
+    ("MAX_URL_BYTES", &mut limits.max_url_bytes),
+    ("MAX_HEADERS", &mut limits.max_headers),
+    ("MAX_HEADER_BYTES", &mut limits.max_header_bytes),
+    ("MAX_HEADER_TOTAL_BYTES", &mut limits.max_header_total_bytes),
+  ] {
+    match globals
+      .get::<Option<usize>>(global)
+      .map_err(|e| format!("{} must be a positive integer: {}", settings::key(global), e))?
+    {
+      Some(0) => return Err(format!("{} must be a positive integer", settings::key(global)).into()),
+      Some(max) => *limit = max,
+      None => {}
+    }
+  }
+
+  Ok(limits)
+}
+
+/// Reads the limit on requests in flight from the config globals.
+///
+/// Returns `None` when `CONFIG.limits.in_flight` is not set, which leaves
+/// requests unlimited (beyond the number of workers).
+///
+/// # Errors
+///
+/// This function will return an error if `CONFIG.limits.in_flight` or
+/// `CONFIG.limits.in_flight_queue_timeout_ms` is not a positive integer, or
+/// `CONFIG.limits.in_flight_queue` is not a non-negative integer.
+fn load_in_flight_limit(
+  globals: &LuaTable,
+) -> std::result::Result<Option<limiter::Limit>, Box<dyn std::error::Error>> {
+  let Some(max) = globals
+    .get::<Option<usize>>("MAX_IN_FLIGHT")
+    .map_err(|e| format!("CONFIG.limits.in_flight must be a positive integer: {}", e))?
+  else {
+    return Ok(None);
+  };
+  if max == 0 {
+    return Err("CONFIG.limits.in_flight must be a positive integer".into());
+  }
+
+  let queue = globals
+    .get::<Option<usize>>("IN_FLIGHT_QUEUE")
+    .map_err(|e| format!("CONFIG.limits.in_flight_queue must be a non-negative integer: {}", e))?
+    .unwrap_or(0);
+
+  let queue_timeout = match globals
+    .get::<Option<u64>>("IN_FLIGHT_QUEUE_TIMEOUT_MS")
+    .map_err(|e| {
+      format!("CONFIG.limits.in_flight_queue_timeout_ms must be a positive integer: {}", e)
+    })?
+  {
+    Some(0) => {
+      return Err("CONFIG.limits.in_flight_queue_timeout_ms must be a positive integer".into())
+    }
+    Some(timeout_ms) => std::time::Duration::from_millis(timeout_ms),
+    None => limiter::DEFAULT_QUEUE_TIMEOUT,
+  };
+
+  Ok(Some(limiter::Limit {
+    max,
+    queue,
+    queue_timeout,
+    status: 503,
+  }))
+}
+
+/// Reads the `fyre.mail` settings from the config globals.
+///
+/// Returns `None` when `CONFIG.smtp.host` is not set, which leaves mail
+/// disabled.
+///
+/// # Errors
+///
+/// This function will return an error if a setting has the wrong type,
+/// `CONFIG.smtp.security` is not `"starttls"`, `"tls"`, or `"none"`, or
+/// `CONFIG.smtp.timeout_ms` is 0.
+fn load_smtp_config(
+  globals: &LuaTable,
+) -> std::result::Result<Option<fyre::mail::SmtpConfig>, Box<dyn std::error::Error>> {
+  let Some(host) = globals
+    .get::<Option<String>>("SMTP_HOST")
+    .map_err(|e| format!("CONFIG.smtp.host must be a host name: {}", e))?
+  else {
+    return Ok(None);
+  };
+
+  let security = match globals
+    .get::<Option<String>>("SMTP_SECURITY")
+    .map_err(|e| format!("CONFIG.smtp.security must be a string: {}", e))?
+    .as_deref()
+  {
+    None | Some("starttls") => fyre::mail::Security::StartTls,
+    Some("tls") => fyre::mail::Security::Tls,
+    Some("none") => fyre::mail::Security::None,
+    Some(other) => {
+      return Err(
+        format!(
+          "CONFIG.smtp.security must be \"starttls\", \"tls\", or \"none\", got \"{}\"",
+          other
+        )
+        .into(),
+      )
+    }
+  };
+
+  let port = globals
+    .get::<Option<u16>>("SMTP_PORT")
+    .map_err(|e| format!("CONFIG.smtp.port must be a port number: {}", e))?
+    .unwrap_or_else(|| security.default_port());
+
+  let timeout_ms = globals
+    .get::<Option<u64>>("SMTP_TIMEOUT_MS")
+    .map_err(|e| format!("CONFIG.smtp.timeout_ms must be a positive integer: {}", e))?;
+  if timeout_ms == Some(0) {
+    return Err("CONFIG.smtp.timeout_ms must be a positive integer".into());
+  }
+
+  let string = |name: &str| {
+    globals
+      .get::<Option<String>>(name)
+      .map_err(|e| format!("{} must be a string: {}", settings::key(name), e))
+  };
+
+  Ok(Some(fyre::mail::SmtpConfig {
+    host,
+    port,
+    security,
+    username: string("SMTP_USERNAME")?,
+    password: string("SMTP_PASSWORD")?,
+    from: string("SMTP_FROM")?,
+    timeout: timeout_ms
+      .map(std::time::Duration::from_millis)
+      .unwrap_or(fyre::mail::DEFAULT_TIMEOUT),
+    queue: string("SMTP_QUEUE")?,
+  }))
+}
+
+/// Ends the span of a pipeline stage of `script_path`, if the request is
+/// exported.
+fn end_stage_span(
+  lua: &Lua,
+  span: Option<span_export::Timing>,
+  script_path: &str,
+  result: &LuaResult<()>,
+) {
+  if let Some(span) = span {
+    let error = result.as_ref().err().map(LuaError::to_string);
+    span.end(lua, vec![("code.filepath", script_path.into())], error);
+  }
+}
+
+// Executes the three-stage handler pipeline: MIDDLEWARE -> HANDLER (conditional) -> RESPONSE HOOK.
+/// Executes a Lua handler script and its associated middleware.
+///
+/// This function orchestrates the execution of a Lua script in a three-stage
+/// pipeline:
+///
+/// 1.  **`middleware`:** If the script returns a table containing a `middleware`
+///     function, it is executed first. This function can inspect the request and
+///     modify the response. If it sets the response status to anything other
+///     than 200, the main `handler` is skipped.
+///
+/// 2.  **`handler`:** If the script returns a table containing a `handler` function
+///     and the middleware did not intercept the request, this function is
+///     executed. It is responsible for the main request processing logic.
+/// 3.  **`response_hook`:** If the script returns a table containing a
+///     `response_hook` function, it is always executed after the `handler`
+///     (or after the `middleware` if the handler was skipped). This can be used
+///     for final modifications to the response, such as adding headers or
+///     logging.
+///
+/// The function sets up two global tables for the Lua script:
+///
+/// - `request`: An immutable table containing request data (method, path,
+///   scheme, remote_addr, tls, body, body_size, headers), a `read_body([size])`
+///   function reading the body in pieces, a `basic_auth()` function returning
+///   the decoded Basic credentials, a `json()` function decoding the body, and
+///   a `validate(schema)` function checking the decoded body with
+///   `fyre.validate`. A body over `CONFIG.body.spill_bytes` is written to a
+///   temporary file at `body_path` instead of being set as `body`; the file is
+///   deleted when this function returns. `headers` is filled in lazily (see
+///   `lazy_headers`).
+/// - `response`: A mutable table that the script can modify to set the response
+///   status, body, and headers. A header value may be a list of strings to
+///   send the header several times.
+///
+/// `lua` comes from the worker's `LuaPool`, with the `fyre` helper modules
+/// registered and `math.random` freshly seeded. The script is loaded with its
+/// own environment table holding `request`, `response`, and whatever globals
+/// the script sets, so nothing carries over to the next request on the same
+/// state. With `CONFIG.lua.bytecode_cache` enabled, the script is loaded from
+/// its compiled bytecode rather than parsed again.
+///
+/// # Arguments
+///
+/// * `req` - A mutable reference to the request being handled.
+/// * `script_path` - The path to the Lua handler script to execute.
+/// * `identity` - Who the request authenticated as, for a route with `auth`
+///   or `require_auth`; it becomes `request.auth`, and its subject
+///   `request.user`.
+/// * `csrf` - Whether the route has `csrf`, so an unsafe request without
+///   the `fyre.csrf` token is answered with `403` before the script runs.
+/// * `state` - The server-wide state backing the `fyre` helper modules.
+/// * `lua` - The Lua state to run the script in.
+/// * `phases` - Where the time spent reading the request body and its size
+///   are recorded, for the slow request log.
+///
+/// # Errors
+///
+/// This function will return a `LuaError` if:
+/// - The handler script cannot be read.
+/// - The handler script fails to return a table.
+/// - The main `handler` function in the script returns an error.
+/// - There are issues getting or setting values in the `response` table.
+fn execute_handler_pipeline(
+  req: &mut server::Request,
+  script_path: &str,
+  identity: Option<&auth::Identity>,
+  csrf: bool,
+  state: &Arc<AppState>,
+  lua: &Lua,
+  phases: &mut slow_log::Phases,
+) -> std::result::Result<HandlerResponse, LuaError> {
+
+  // --- 1. Prepare Data Tables ---
+  let reading = std::time::Instant::now();
+  let read_span = span_export::start(lua, "read body", span_export::SpanKind::Internal);
+  let content_length = req
+    .headers()
+    .iter()
+    .find(|h| h.field.equiv("Content-Length"))
+    .and_then(|h| h.value.as_str().parse::<u64>().ok());
+  // Dropped when the pipeline returns, which deletes a spilled body's file.
+  let request_body = match body::Body::read(req.as_reader(), content_length, &state.body_spill) {
+    Ok(request_body) => request_body,
+    Err(e) if e.kind() == std::io::ErrorKind::FileTooLarge => {
+      warn!(
+        target: PIPELINE_TARGET,
+        "413 {} {} from {}: {}",
+        req.method(),
+        req.url(),
+        req.remote_addr(),
+        e
+      );
+      let status = state.connections.refuse_oversized(server::Oversized::Body);
+      // The rest of the body is left unread, so the connection can't be
+      // used again.
+      req.set_connection("close");
+      let too_large = Response::from_string("413 Payload Too Large").with_status_code(status);
+      return Ok(handler_response(too_large));
+    }
+    Err(e) => return Err(LuaError::external(format!("Failed to read request body: {}", e))),
+  };
+  phases.read = reading.elapsed();
+  phases.request_bytes = request_body.size();
+  if let Some(span) = read_span {
+    let size = i64::try_from(request_body.size()).unwrap_or(i64::MAX);
+    span.end(lua, vec![("http.request.body.size", size.into())], None);
+  }
+
+  // Checked once the body is read, since a form sends the token in it. A
+  // spilled body is too large to be a form, so only the header counts then.
+  if csrf && !fyre::csrf::is_safe(req.method().as_str()) {
+    let header = |name: &'static str| {
+      req
+        .headers()
+        .iter()
+        .find(|h| h.field.equiv(name))
+        .map(|h| h.value.as_str())
+    };
+    let submitted = header(fyre::csrf::HEADER).map(str::to_string).or_else(|| {
+      match &request_body {
+        body::Body::Memory(bytes) => fyre::csrf::form_token(header("Content-Type"), bytes),
+        body::Body::File { .. } => None,
+      }
+    });
+    let valid = state.session.as_ref().is_some_and(|config| {
+      fyre::csrf::check(config, header("Cookie"), submitted.as_deref())
+    });
+    if !valid {
+      warn!(
+        target: PIPELINE_TARGET,
+        "403 {} {} from {}: missing or invalid CSRF token",
+        req.method(),
+        req.url(),
+        req.remote_addr()
+      );
+      let forbidden = Response::from_string("403 Forbidden").with_status_code(403);
+      return Ok(handler_response(forbidden));
+    }
+  }
+
+  // Request Table (Immutable Input)
+  let req_table = lua.create_table()?;
+  req_table.set("method", req.method().as_str())?;
+  req_table.set("path", req.url())?;
+  req_table.set("scheme", req.scheme())?;
+  req_table.set("remote_addr", req.remote_addr().to_string())?;
+  if let Some(trace) = lua.app_data_ref::<trace::TraceContext>() {
+    req_table.set("trace", trace.to_lua(lua)?)?;
+  }
+  if let Some(identity) = identity {
+    req_table.set("user", identity.subject.as_str())?;
+    req_table.set("auth", identity.to_lua(lua)?)?;
+  }
+  if req.scheme() == "https" {
+    let tls_table = lua.create_table()?;
+    if let Some(cert) = req.client_cert() {
+      tls_table.set("client_cert", cert.to_lua(lua)?)?;
+    }
+    if let Some(negotiated) = req.tls_negotiated() {
+      negotiated.to_lua(&tls_table)?;
+    }
+    req_table.set("tls", tls_table)?;
+  }
+  // Lua strings are byte strings, so the body is passed through unchanged
+  // (binary uploads and signature checks need the exact bytes). A spilled
+  // body is only available through `body_path` and `read_body`.
+  let body = match &request_body {
+    body::Body::Memory(bytes) => {
+      let body = lua.create_string(bytes)?;
+      req_table.set("body", body.clone())?;
+      body::BodySource::Memory(body)
+    }
+    body::Body::File { .. } => {
+      let path = request_body.path().map(Path::to_path_buf).unwrap_or_default();
+      req_table.set("body_path", path.to_string_lossy().into_owned())?;
+      body::BodySource::File(path)
+    }
+  };
+  req_table.set("body_size", request_body.size())?;
+  // request.read_body([size]) -> next piece of the body (or nil at the end)
+  req_table.set("read_body", body::reader_function(lua, body.clone())?)?;
+  req_table.set("headers", lazy_headers(lua, req.headers())?)?;
+
+  // request.basic_auth() -> user, pass (or nil if absent/malformed)
+  let authorization = req
+    .headers()
+    .iter()
+    .find(|h| h.field.equiv("Authorization"))
+    .map(|h| h.value.to_string());
+  req_table.set(
+    "basic_auth",
+    lua.create_function(move |_, ()| {
+      Ok(
+        match authorization.as_deref().and_then(fyre::encoding::parse_basic_auth) {
+          Some((user, pass)) => (Some(user), Some(pass)),
+          None => (None, None),
+        },
+      )
+    })?,
+  )?;
+
+  // Response Table (Mutable Output/State)
+  let res_table = lua.create_table()?;
+  res_table.set("status", 200i32)?;
+  res_table.set("body", String::new())?;
+  res_table.set("headers", lua.create_table()?)?;
+
+  // response.file(path) -> true (or nil, err); the file is sent instead of
+  // `response.body`, streamed from disk rather than read into memory.
+  let file: Rc<RefCell<Option<statics::OpenFile>>> = Rc::new(RefCell::new(None));
+  let file_ref = file.clone();
+  let file_state = state.clone();
+  res_table.set(
+    "file",
+    lua.create_function(move |_, path: String| {
+      let opened = file_state.fs.resolve(&path).and_then(|resolved| {
+        statics::open(&resolved, None).map_err(|e| format!("{}: {}", path, e))
+      });
+      match opened {
+        Ok(opened) => {
+          *file_ref.borrow_mut() = Some(opened);
+          Ok((Some(true), None))
+        }
+        Err(e) => Ok((None, Some(e))),
+      }
+    })?,
+  )?;
+
+  // request.json() -> value (or nil, err if the body is not valid JSON or
+  // is too large to decode)
+  let json_body = body.clone();
+  req_table.set(
+    "json",
+    lua.create_function(move |lua, ()| {
+      let Some(json_body) = json_body.load(lua)? else {
+        return Ok((
+          LuaValue::Nil,
+          Some(format!("body is larger than {} bytes", body::MAX_LOAD_BYTES)),
+        ));
+      };
+      match serde_json::from_slice::<serde_json::Value>(&json_body.as_bytes()) {
+        Ok(json) => Ok((fyre::json::from_json(lua, &json)?, None)),
+        Err(e) => Ok((LuaValue::Nil, Some(format!("invalid JSON: {}", e)))),
+      }
+    })?,
+  )?;
+  // request.validate(schema) -> value (or nil after a 400/422 error response)
+  req_table.set(
+    "validate",
+    fyre::validate::request_function(lua, body, res_table.clone())?,
+  )?;
+
+  // The script runs in its own environment: reads fall through to the
+  // state's globals, but everything it sets stays in `env` and is dropped
+  // with the request, since the state itself is reused.
+  let globals = lua.globals();
+  let env = lua.create_table()?;
+  let env_meta = lua.create_table()?;
+  env_meta.set("__index", globals.clone())?;
+  env.set_metatable(Some(env_meta))?;
+  env.set("_G", env.clone())?;
+
+  // Expose tables as globals for Lua
+  env.set("request", req_table.clone())?;
+  env.set("response", res_table.clone())?;
+
+  // Request-scoped helper modules, on a `fyre` table of the request's own
+  // that reads through to the shared one, so they never outlive it.
+  let cookie_header = req
+    .headers()
+    .iter()
+    .find(|h| h.field.equiv("Cookie"))
+    .map(|h| h.value.to_string());
+  let fyre_table = lua.create_table()?;
+  let fyre_meta = lua.create_table()?;
+  fyre_meta.set("__index", globals.get::<LuaTable>("fyre")?)?;
+  fyre_table.set_metatable(Some(fyre_meta))?;
+  env.set("fyre", fyre_table.clone())?;
+  fyre_table.set(
+    "session",
+    fyre::session::module(lua, state, cookie_header.as_deref(), res_table.clone())?,
+  )?;
+  fyre_table.set(
+    "cookie",
+    fyre::cookie::module(lua, state, cookie_header.as_deref())?,
+  )?;
+  fyre_table.set(
+    "csrf",
+    fyre::csrf::module(lua, state, cookie_header.as_deref(), res_table.clone())?,
+  )?;
+
+  // --- 2. Load the Route Script (Modular Module Execution) ---
+  let script_code = fs::read(script_path).map_err(|e| {
+    LuaError::external(format!(
+      "Failed to read handler script {}: {}",
+      script_path, e
+    ))
+  })?;
+
+  // Execute script and capture its returned value (the module table)
+  let module_table = state
+    .scripts
+    .load(lua, script_path, &script_code, env)
+    .and_then(|chunk| chunk.call::<LuaTable>(())) // Expects the Lua script to `return { ... }`
+    .map_err(|e| {
+      LuaError::external(format!("Handler script failed to return a table: {}", e))
+    })?;
+
+    // --- 3. Execute Pipeline ---
+
+    // A. BEFORE Middleware: Get 'middleware' function
+    if let Ok(before) = module_table.get::<LuaFunction>("middleware") {
+      let span = span_export::start(lua, "middleware", span_export::SpanKind::Internal);
+      let result = before.call::<()>((req_table.clone(), res_table.clone()));
+      end_stage_span(lua, span, script_path, &result);
+      if let Err(e) = result {
+        warn!(target: PIPELINE_TARGET, "Middleware error (before handler): {}", e);
+      }
+    }
+
+    // Check if BEFORE middleware intercepted (status != 200)
+    let current_status: i32 = res_table.get("status").unwrap_or(200);
+
+    if current_status == 200 {
+      // B. MAIN HANDLER: Get 'handler' function
+      match module_table.get::<LuaFunction>("handler") {
+        Ok(handler) => {
+          let span = span_export::start(lua, "handler", span_export::SpanKind::Internal);
+          let result = handler.call::<()>((req_table.clone(), res_table.clone()));
+          end_stage_span(lua, span, script_path, &result);
+          result?; // Propagate handler failure
+        }
+        Err(_) => {
+          warn!(
+            target: PIPELINE_TARGET,
+            "No 'handler' function found in {}. Response might be empty.",
+              script_path
+            );
+          }
+        }
+    } else {
+      debug!(
+        target: PIPELINE_TARGET,
+        "Request intercepted by middleware (Status: {})",
+        current_status
+      );
+    }
+
+    // C. AFTER Middleware: Get 'response_hook' function
+    if let Ok(after) = module_table.get::<LuaFunction>("response_hook") {
+      let span = span_export::start(lua, "response_hook", span_export::SpanKind::Internal);
+      let result = after.call::<()>((req_table.clone(), res_table.clone()));
+      end_stage_span(lua, span, script_path, &result);
+      if let Err(e) = result {
+        warn!(target: PIPELINE_TARGET, "Response hook error (after handler): {}", e);
+      }
+    }
+
+    // --- 4. Finalize Response ---
+    let final_status: i32 = res_table.get("status").unwrap_or(500);
+    let status = StatusCode(final_status as u16);
+    let file = file.borrow_mut().take();
+    let mut response = match file {
+      // Script headers are added after the file's Content-Type, so a script
+      // can override it.
+      Some(file) => handler_response(file.into_response(status)),
+      None => {
+        // A small body is copied once, straight from the Lua string's bytes;
+        // a large one is read from the string in chunks as it is written,
+        // so it is never in memory twice. Either way its length lets the
+        // response carry a Content-Length instead of being chunked.
+        let body: LuaString = res_table.get("body").map_err(|e| {
+          LuaError::external(format!("Failed to get body from response table: {}", e))
+        })?;
+        let body_len = body.as_bytes().len();
+        let reader: Box<dyn Read> = if body_len < body::STREAM_RESPONSE_BYTES {
+          Box::new(Cursor::new(body.as_bytes().to_vec()))
+        } else {
+          Box::new(body::LuaStringReader::new(lua, body))
+        };
+        Response::new(status, Vec::new(), reader, Some(body_len), None)
+      }
+    };
+
+    let headers_table: LuaTable = res_table.get("headers")?;
+    for pair in headers_table.pairs::<LuaString, LuaValue>() {
+      let (key, value) = pair?;
+      // tiny_http drops a `Connection` header, so it goes to the connection
+      // instead.
+      if key.as_bytes().eq_ignore_ascii_case(b"connection") {
+        if let LuaValue::String(value) = &value {
+          req.set_connection(&value.to_string_lossy());
+        }
+        continue;
+      }
+      // Checked before `Header::from_bytes`, which would take a value with
+      // a line break in it and so let a script split the response.
+      let mut add = |value: LuaString| {
+        let header = fyre::check_header(&key.as_bytes(), &value.as_bytes()).and_then(|()| {
+          Header::from_bytes(&key.as_bytes()[..], &value.as_bytes()[..])
+            .map_err(|()| "not a valid header".to_string())
+        });
+        match header {
+          Ok(header) => response.add_header(header),
+          // Quoted with `{:?}`, so the line breaks don't reach the log.
+          Err(e) if state.header_check == HeaderCheck::Strict => {
+            return Err(LuaError::external(format!(
+              "response header {:?}: {}",
+              key.to_string_lossy(),
+              e
+            )));
+          }
+          Err(e) => warn!(
+            target: PIPELINE_TARGET,
+            "Invalid header skipped: {:?}: {:?}: {}",
+            key.to_string_lossy(),
+            value.to_string_lossy(),
+            e
+          ),
+        }
+        Ok(())
+      };
+      // A list of values (e.g. several Set-Cookie headers) sends one header
+      // line per value.
+      match value {
+        LuaValue::Table(list) => {
+          for value in list.sequence_values::<LuaString>() {
+            add(value?)?;
+          }
+        }
+        other => add(LuaString::from_lua(other, lua)?)?,
+      }
+    }
+
+    if let Some(security) = &state.security_headers {
+      for (name, value) in security.headers(req.scheme() == "https") {
+        if !response.headers().iter().any(|h| h.field.equiv(name)) {
+          if let Ok(header) = Header::from_bytes(name, value) {
+            response.add_header(header);
+          }
+        }
+      }
+    }
+
+    Ok(response)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn headers(lua: &Lua, pairs: &[(&str, &str)]) -> LuaTable {
+    let headers: Vec<Header> = pairs
+      .iter()
+      .map(|(name, value)| Header::from_bytes(*name, *value).unwrap())
+      .collect();
+    lazy_headers(lua, &headers).unwrap()
+  }
+
+  #[test]
+  fn headers_are_found_ignoring_case() {
+    let lua = Lua::new();
+    let table = headers(&lua, &[("Content-Type", "text/plain"), ("X-Token", "abc")]);
+    lua.globals().set("headers", table).unwrap();
+    let (exact, lower, upper, missing): (String, String, String, Option<String>) = lua
+      .load("return headers['Content-Type'], headers['content-type'], headers['X-TOKEN'], headers.accept")
+      .eval()
+      .unwrap();
+    assert_eq!(exact, "text/plain");
+    assert_eq!(lower, "text/plain");
+    assert_eq!(upper, "abc");
+    assert_eq!(missing, None);
+    let odd: Option<String> = lua.load("return headers[1]").eval().unwrap();
+    assert_eq!(odd, None);
+  }
+
+  #[test]
+  fn a_repeated_header_gives_its_last_value_and_is_listed_once() {
+    let lua = Lua::new();
+    let table = headers(
+      &lua,
+      &[
+        ("Accept", "text/html"),
+        ("Host", "example.com"),
+        ("accept", "application/json"),
+      ],
+    );
+    lua.globals().set("headers", table).unwrap();
+    let accept: String = lua.load("return headers.Accept").eval().unwrap();
+    assert_eq!(accept, "application/json");
+    let listed: Vec<String> = lua
+      .load(
+        "local listed = {}
+         for name, value in pairs(headers) do listed[#listed + 1] = name .. '=' .. value end
+         return listed",
+      )
+      .eval()
+      .unwrap();
+    assert_eq!(listed, ["Host=example.com", "accept=application/json"]);
+  }
+
+  #[test]
+  fn no_headers_lists_nothing() {
+    let lua = Lua::new();
+    lua.globals().set("headers", headers(&lua, &[])).unwrap();
+    let count: i64 = lua
+      .load("local n = 0 for _ in pairs(headers) do n = n + 1 end return n")
+      .eval()
+      .unwrap();
+    assert_eq!(count, 0);
+  }
+
+  /// Routes whose handlers each try to split the response through a
+  /// different way of setting a header, and `/kept`, which doesn't.
+  fn header_fixture(header_check: &str) -> crate::testing::Fixture {
+    let attacks = r#"
+      local attacks = {
+        ["/location"] = function(response)
+          response.status = 302
+          response.headers["Location"] = "/next\r\nSet-Cookie: admin=1"
+        end,
+        ["/name"] = function(response)
+          response.headers["X-A\r\nSet-Cookie: admin=1\r\nX-B"] = "1"
+        end,
+        ["/list"] = function(response)
+          response.headers["Set-Cookie"] = { "a=1", "b=2\r\nSet-Cookie: admin=1" }
+        end,
+        ["/nul"] = function(response)
+          response.headers["X-Nul"] = "a\0b"
+        end,
+        ["/cookie"] = function(response)
+          response.headers["Set-Cookie"] = "flash=" .. fyre.cookie.sign("flash", "saved")
+            .. "\r\nSet-Cookie: admin=1"
+        end,
+        ["/session"] = function(response)
+          fyre.session.set("user", "ada")
+          assert(fyre.session.save())
+        end,
+        ["/kept"] = function() end,
+      }
+      return {
+        handler = function(request, response)
+          response.headers["X-Kept"] = "yes"
+          attacks[request.path](response)
+          response.body = "done"
+        end,
+      }
+    "#;
+    let mut config = format!(
+      r#"
+        CONFIG = {{
+          lua = {{ header_check = "{}" }},
+          keys = {{ current = "key-one" }},
+          session = {{ secret = "session-secret", cookie = "sid\r\nSet-Cookie: admin=1" }},
+        }}
+      "#,
+      header_check
+    );
+    for path in [
+      "/location",
+      "/name",
+      "/list",
+      "/nul",
+      "/cookie",
+      "/session",
+      "/kept",
+    ] {
+      config.push_str(&format!("router.add(\"{}\", \"attacks.lua\")\n", path));
+    }
+    crate::testing::Fixture::new(&config, &[("attacks.lua", attacks)])
+  }
+
+  #[test]
+  fn script_headers_cannot_split_the_response() {
+    let fixture = header_fixture("lenient");
+    let server = fixture
+      .builder()
+      .addr("127.0.0.1:0")
+      .workers(1)
+      .load()
+      .unwrap();
+    let addr = server.serve().unwrap().remove(0);
+    // The bad header is left out, and the rest of the response sent.
+    for (path, status) in [
+      ("/location", 302),
+      ("/name", 200),
+      ("/list", 200),
+      ("/nul", 200),
+      ("/cookie", 200),
+    ] {
+      let response = crate::testing::get(&addr, path);
+      let (head, body) = response.split_once("\r\n\r\n").unwrap();
+      assert!(
+        head.starts_with(&format!("HTTP/1.1 {}", status)),
+        "{}: {}",
+        path,
+        head
+      );
+      // Header names are lowercased by hyper with the `async` feature.
+      let names = head.to_ascii_lowercase();
+      assert!(names.contains("\r\nx-kept: yes"), "{}: {}", path, head);
+      assert!(!head.contains("admin"), "{}: {}", path, head);
+      assert!(!head.contains('\0'), "{}: {}", path, head);
+      assert_eq!(body, "done", "{}", path);
+    }
+    let list = crate::testing::get(&addr, "/list").to_ascii_lowercase();
+    assert!(list.contains("\r\nset-cookie: a=1\r\n"), "{}", list);
+    // The session helper refuses to add its cookie, which fails the handler.
+    let session = crate::testing::get(&addr, "/session");
+    assert!(session.starts_with("HTTP/1.1 500"), "{}", session);
+    assert!(!session.contains("admin=1\r\n"), "{}", session);
+    server.shutdown();
+  }
+
+  #[test]
+  fn strict_header_check_fails_the_handler() {
+    let fixture = header_fixture("strict");
+    let server = fixture
+      .builder()
+      .addr("127.0.0.1:0")
+      .workers(1)
+      .load()
+      .unwrap();
+    let addr = server.serve().unwrap().remove(0);
+    for path in ["/location", "/name", "/list", "/nul", "/cookie", "/session"] {
+      let response = crate::testing::get(&addr, path);
+      assert!(
+        response.starts_with("HTTP/1.1 500"),
+        "{}: {}",
+        path,
+        response
+      );
+      assert!(
+        !response.to_ascii_lowercase().contains("x-kept"),
+        "{}: {}",
+        path,
+        response
+      );
+    }
+    let kept = crate::testing::get(&addr, "/kept");
+    assert!(kept.starts_with("HTTP/1.1 200"), "{}", kept);
+    server.shutdown();
+  }
+}
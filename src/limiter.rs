@@ -0,0 +1,323 @@
+//! # Concurrency Limits
+//!
+//! Bounds how many requests run their handler at once, so a burst sheds load
+//! with a quick `503` instead of piling up behind slow handlers. With
+//! `CONFIG.limits.in_flight` set, a request arriving when that many are running
+//! waits for a slot if fewer than `CONFIG.limits.in_flight_queue` requests are
+//! already waiting, for at most `CONFIG.limits.in_flight_queue_timeout_ms`, and
+//! is rejected otherwise. Only handler scripts count; static files and the
+//! server's built-in endpoints are served regardless.
+//!
+//! A route can have its own limit as well, declared with
+//! `router.add(path, script, { max_concurrent = 1, queue = 5 })`, so one
+//! heavy route can't take every worker. A request to it needs a slot in
+//! both: it takes the route's slot first, so requests queued on a busy
+//! route don't hold global slots while they wait.
+
+use mlua::prelude::*;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Condvar, Mutex, PoisonError};
+use std::time::{Duration, Instant};
+
+use crate::locks;
+
+/// How long a queued request waits when
+/// `CONFIG.limits.in_flight_queue_timeout_ms` is not set.
+pub const DEFAULT_QUEUE_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// The bound a `Limiter` enforces.
+#[derive(Debug, Clone)]
+pub struct Limit {
+  /// The most requests running at once.
+  pub max: usize,
+  /// The most requests waiting for a slot; `0` rejects as soon as the limit
+  /// is reached.
+  pub queue: usize,
+  /// How long a request waits for a slot before it is rejected.
+  pub queue_timeout: Duration,
+  /// The status a rejected request is answered with: `503`, or `429` for a
+  /// route limit declared with `status = 429`.
+  pub status: u16,
+}
+
+impl Limit {
+  /// Reads a route's limit from its `router.add` options. Returns `None`
+  /// when `max_concurrent` is not set.
+  ///
+  /// # Errors
+  ///
+  /// This function will return a `LuaError` if an option has the wrong type,
+  /// `max_concurrent` or `queue_timeout_ms` is 0, or `status` is not 429 or
+  /// 503.
+  pub fn from_route_options(path: &str, opts: &LuaTable) -> LuaResult<Option<Limit>> {
+    let Some(max) = opts.get::<Option<usize>>("max_concurrent")? else {
+      return Ok(None);
+    };
+    let queue = opts.get::<Option<usize>>("queue")?.unwrap_or(0);
+    let queue_timeout_ms = opts.get::<Option<u64>>("queue_timeout_ms")?;
+    let status = opts.get::<Option<u16>>("status")?.unwrap_or(503);
+    if max == 0 || queue_timeout_ms == Some(0) {
+      return Err(LuaError::external(format!(
+        "router.add('{}'): max_concurrent and queue_timeout_ms must be at least 1",
+        path
+      )));
+    }
+    if status != 429 && status != 503 {
+      return Err(LuaError::external(format!(
+        "router.add('{}'): status must be 429 or 503, got {}",
+        path, status
+      )));
+    }
+    Ok(Some(Limit {
+      max,
+      queue,
+      queue_timeout: queue_timeout_ms
+        .map(Duration::from_millis)
+        .unwrap_or(DEFAULT_QUEUE_TIMEOUT),
+      status,
+    }))
+  }
+}
+
+#[derive(Default)]
+struct Counts {
+  in_flight: usize,
+  waiting: usize,
+}
+
+/// Counts the requests in flight and, with a `Limit`, bounds them. Shared by
+/// every worker thread.
+#[derive(Default)]
+pub struct Limiter {
+  limit: Option<Limit>,
+  counts: Mutex<Counts>,
+  released: Condvar,
+  rejected: AtomicU64,
+}
+
+impl Limiter {
+  /// Creates a limiter. With no `limit` it only counts.
+  pub fn new(limit: Option<Limit>) -> Self {
+    Limiter {
+      limit,
+      ..Limiter::default()
+    }
+  }
+
+  /// Takes a slot, waiting in the queue if the limit is reached. Returns
+  /// `None` if the request is rejected; the slot is freed when the returned
+  /// `Permit` is dropped.
+  pub fn acquire(&self) -> Option<Permit<'_>> {
+    let mut counts = locks::lock(&self.counts, "in-flight requests");
+    if let Some(limit) = &self.limit {
+      if counts.in_flight >= limit.max {
+        if counts.waiting >= limit.queue {
+          self.rejected.fetch_add(1, Ordering::Relaxed);
+          return None;
+        }
+        counts.waiting += 1;
+        let deadline = Instant::now() + limit.queue_timeout;
+        while counts.in_flight >= limit.max {
+          let now = Instant::now();
+          if now >= deadline {
+            counts.waiting -= 1;
+            self.rejected.fetch_add(1, Ordering::Relaxed);
+            return None;
+          }
+          counts = self
+            .released
+            .wait_timeout(counts, deadline - now)
+            .unwrap_or_else(PoisonError::into_inner)
+            .0;
+        }
+        counts.waiting -= 1;
+      }
+    }
+    counts.in_flight += 1;
+    Some(Permit(self))
+  }
+
+  /// Returns the number of requests running now.
+  pub fn in_flight(&self) -> usize {
+    locks::lock(&self.counts, "in-flight requests").in_flight
+  }
+
+  /// Returns the most requests running at once, if there is a limit.
+  pub fn max(&self) -> Option<usize> {
+    self.limit.as_ref().map(|limit| limit.max)
+  }
+
+  /// Returns the status to answer a rejected request with.
+  pub fn status(&self) -> u16 {
+    self.limit.as_ref().map_or(503, |limit| limit.status)
+  }
+
+  /// Returns the number of requests rejected since startup.
+  pub fn rejected(&self) -> u64 {
+    self.rejected.load(Ordering::Relaxed)
+  }
+}
+
+/// A slot in a `Limiter`, freed when dropped.
+pub struct Permit<'a>(&'a Limiter);
+
+impl Drop for Permit<'_> {
+  fn drop(&mut self) {
+    let mut counts = locks::lock(&self.0.counts, "in-flight requests");
+    counts.in_flight -= 1;
+    drop(counts);
+    self.0.released.notify_one();
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::fyre::value::SharedValue;
+  use crate::testing::{self, Fixture};
+  use std::thread;
+
+  fn limit(max: usize, queue: usize, queue_timeout: Duration) -> Limit {
+    Limit {
+      max,
+      queue,
+      queue_timeout,
+      status: 503,
+    }
+  }
+
+  #[test]
+  fn full_limiter_rejects_without_a_queue() {
+    let limiter = Limiter::new(Some(limit(1, 0, DEFAULT_QUEUE_TIMEOUT)));
+    let permit = limiter.acquire().unwrap();
+    assert!(limiter.acquire().is_none());
+    assert_eq!(limiter.rejected(), 1);
+    drop(permit);
+    assert!(limiter.acquire().is_some());
+    assert_eq!(limiter.in_flight(), 0);
+  }
+
+  #[test]
+  fn queued_request_gets_the_freed_slot() {
+    let limiter = Limiter::new(Some(limit(1, 1, Duration::from_secs(10))));
+    let permit = limiter.acquire().unwrap();
+    thread::scope(|scope| {
+      let waiter = scope.spawn(|| limiter.acquire().map(|_| ()));
+      while locks::lock(&limiter.counts, "in-flight requests").waiting == 0 {
+        thread::yield_now();
+      }
+      // The queue is full, so a third request is turned away at once.
+      assert!(limiter.acquire().is_none());
+      drop(permit);
+      assert!(waiter.join().unwrap().is_some());
+    });
+    assert_eq!(limiter.rejected(), 1);
+  }
+
+  #[test]
+  fn queued_request_times_out() {
+    let limiter = Limiter::new(Some(limit(1, 1, Duration::from_millis(20))));
+    let _permit = limiter.acquire().unwrap();
+    let started = Instant::now();
+    assert!(limiter.acquire().is_none());
+    assert!(started.elapsed() >= Duration::from_millis(20));
+    assert_eq!(
+      locks::lock(&limiter.counts, "in-flight requests").waiting,
+      0
+    );
+  }
+
+  #[test]
+  fn route_options_are_checked() {
+    let lua = Lua::new();
+    let options = |source: &str| {
+      let table: LuaTable = lua.load(source).eval().unwrap();
+      Limit::from_route_options("/export", &table)
+    };
+    assert!(options("{}").unwrap().is_none());
+    let limit = options("{ max_concurrent = 2, queue = 5, status = 429 }")
+      .unwrap()
+      .unwrap();
+    assert_eq!((limit.max, limit.queue, limit.status), (2, 5, 429));
+    assert_eq!(limit.queue_timeout, DEFAULT_QUEUE_TIMEOUT);
+    for source in [
+      "{ max_concurrent = 0 }",
+      "{ max_concurrent = 1, queue_timeout_ms = 0 }",
+      "{ max_concurrent = 1, status = 500 }",
+    ] {
+      assert!(options(source).is_err(), "{}", source);
+    }
+  }
+
+  /// Holds its request until the `release` key is set in `fyre.kv`.
+  const HOLD: &str = r#"
+    return {
+      handler = function(request, response)
+        local deadline = os.time() + 10
+        while not fyre.kv.get("release") and os.time() < deadline do end
+        response.body = "done"
+      end,
+    }
+  "#;
+
+  #[test]
+  fn route_and_global_limits_both_apply() {
+    let fixture = Fixture::new(
+      r#"
+        CONFIG = { limits = { in_flight = 2, in_flight_queue = 0 } }
+        router.add("/export", "hold.lua", { max_concurrent = 1, status = 429 })
+        router.add("/report", "hold.lua")
+        router.add("/fast", "fast.lua")
+      "#,
+      &[
+        ("hold.lua", HOLD),
+        (
+          "fast.lua",
+          r#"return { handler = function(request, response) response.body = "fast" end }"#,
+        ),
+      ],
+    );
+    let server = fixture
+      .builder()
+      .addr("127.0.0.1:0")
+      .workers(4)
+      .load()
+      .unwrap();
+    let addr = server.serve().unwrap().remove(0);
+    let state = server.state.clone();
+    let hold = |path: &'static str, running: usize| {
+      let addr = addr.clone();
+      let client = thread::spawn(move || testing::get(&addr, path));
+      while state.in_flight.in_flight() < running {
+        thread::yield_now();
+      }
+      client
+    };
+
+    // The route allows one export, and its rejection takes no global slot.
+    let export = hold("/export", 1);
+    assert!(testing::get(&addr, "/export").starts_with("HTTP/1.1 429"));
+    assert_eq!(state.in_flight.rejected(), 0);
+
+    // A second route's request takes the other global slot, after which
+    // every handler is turned away.
+    let report = hold("/report", 2);
+    assert!(testing::get(&addr, "/fast").starts_with("HTTP/1.1 503"));
+    assert_eq!(state.in_flight.rejected(), 1);
+
+    state
+      .kv
+      .set("release".to_string(), SharedValue::Boolean(true), None)
+      .unwrap();
+    for client in [export, report] {
+      assert!(client.join().unwrap().ends_with("\r\n\r\ndone"));
+    }
+    // A slot is given back once the handler returns, which may be after its
+    // response has reached the client.
+    while state.in_flight.in_flight() > 0 {
+      thread::yield_now();
+    }
+    assert!(testing::get(&addr, "/fast").ends_with("\r\n\r\nfast"));
+    server.shutdown();
+  }
+}
@@ -0,0 +1,46 @@
+//! # Poisoned Locks
+//!
+//! A mutex is poisoned when a thread panics while holding it, and from then
+//! on every `lock()` fails. The state behind the server's locks stays
+//! consistent between statements, so a panic in one request loses at most
+//! the update it was making. Rather than failing every later request that
+//! touches the same store, `lock` logs the panic, clears the poison, and
+//! carries on with the data.
+
+use std::sync::{Mutex, MutexGuard};
+
+/// Locks `mutex`, recovering it if a panic poisoned it. `name` describes
+/// the lock in the warning, which is logged once per panic since the poison
+/// is cleared.
+pub fn lock<'a, T: ?Sized>(mutex: &'a Mutex<T>, name: &str) -> MutexGuard<'a, T> {
+  mutex.lock().unwrap_or_else(|poisoned| {
+    warn!(
+      "Recovered the {} lock after a thread panicked while holding it",
+      name
+    );
+    mutex.clear_poison();
+    poisoned.into_inner()
+  })
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::panic;
+
+  #[test]
+  fn a_poisoned_lock_is_recovered_with_its_data() {
+    let mutex = Mutex::new(vec![1]);
+    let panicked = panic::catch_unwind(|| {
+      let mut guard = lock(&mutex, "test");
+      guard.push(2);
+      panic!("while holding the lock");
+    });
+    assert!(panicked.is_err());
+    assert!(mutex.is_poisoned());
+
+    assert_eq!(*lock(&mutex, "test"), [1, 2]);
+    assert!(!mutex.is_poisoned());
+    assert!(mutex.lock().is_ok());
+  }
+}
@@ -0,0 +1,653 @@
+//! # Logging
+//!
+//! Every log line, from the server and from `fyre.log` in scripts, goes
+//! through `log` (with the `error!`, `warn!`, `info!`, `debug!`, and
+//! `trace!` macros), which formats the whole line first and writes it in
+//! one call under a lock, so lines from concurrent workers never
+//! interleave.
+//!
+//! Each line has a level and a target: by default the module it comes
+//! from, named `fyre` for the crate root and `fyre::server`,
+//! `fyre::statics`, and so on below it (helper modules such as
+//! `fyre::queue` drop their extra `fyre`). Request handling logs to
+//! `fyre::pipeline` and scripts to `fyre::script`. The `Filter` decides
+//! which lines are written: `info` by default, or a spec such as
+//! `info,fyre::pipeline=debug` from `--log-level`, `FYRE_LOG`, or
+//! `CONFIG.log.level`, in that order. A target's level also applies below
+//! it, and the most specific one wins. `POST /admin/log-level` replaces the
+//! filter while the server runs (see `admin`).
+//!
+//! Lines go to stdout (`INFO`, `DEBUG`, and `TRACE`) and stderr (`WARN`
+//! and `ERROR`) until `open` is called with the log file from `--log-file`
+//! or `CONFIG.log.file`. From then on they are appended to the file, and
+//! stdout and stderr point at it too, so output from `print` in scripts
+//! lands in the same place.
+//!
+//! With `CONFIG.log.rotate = { max_size = "50MB", keep = 5 }` the file is
+//! rotated before a line would take it past `max_size`: `fyre.log` becomes
+//! `fyre.log.1`, the older files move up one, and the one past `keep` is
+//! deleted. On `SIGHUP` the file is reopened by name before the next line,
+//! for logrotate's default `create` mode, htpasswd files are read again on
+//! their next use (see `auth::htpasswd`), and so are the TLS certificate
+//! and key on the next connection (see `tls`).
+//!
+//! With `CONFIG.log.format = "json"` each line is instead one JSON object,
+//! for log pipelines such as Loki: `ts` (RFC 3339, UTC), `level` (`error`
+//! through `trace`), and `msg`, then any fields the line was written
+//! with (`status` and `duration_ms`, say). A line written while a worker
+//! handles a request also gets the request's `route`, `script`,
+//! `request_id` (its `X-Request-Id` header), `trace_id`, and `span_id` (see
+//! `trace`), from the `Context` the worker sets. `print` in handler scripts
+//! goes through `log` too, so nothing reaches the log in another format.
+
+use std::cell::RefCell;
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicU8, Ordering};
+use std::sync::{Mutex, MutexGuard, PoisonError, RwLock};
+
+use chrono::{SecondsFormat, Utc};
+use mlua::prelude::*;
+use serde_json::Value;
+
+/// The rotated files kept when `keep` is not set.
+pub const DEFAULT_KEEP: usize = 5;
+
+/// Logs a line about a failure.
+macro_rules! error {
+  (target: $target:expr, $($arg:tt)*) => {
+    $crate::logger::log($crate::logger::Level::Error, $target, format_args!($($arg)*))
+  };
+  ($($arg:tt)*) => {
+    $crate::logger::log($crate::logger::Level::Error, module_path!(), format_args!($($arg)*))
+  };
+}
+
+/// Logs a line about something wrong that the server works around.
+macro_rules! warn {
+  (target: $target:expr, $($arg:tt)*) => {
+    $crate::logger::log($crate::logger::Level::Warn, $target, format_args!($($arg)*))
+  };
+  ($($arg:tt)*) => {
+    $crate::logger::log($crate::logger::Level::Warn, module_path!(), format_args!($($arg)*))
+  };
+}
+
+/// Logs an informational line.
+macro_rules! info {
+  (target: $target:expr, $($arg:tt)*) => {
+    $crate::logger::log($crate::logger::Level::Info, $target, format_args!($($arg)*))
+  };
+  ($($arg:tt)*) => {
+    $crate::logger::log($crate::logger::Level::Info, module_path!(), format_args!($($arg)*))
+  };
+}
+
+/// Logs a line of detail for working out what the server is doing.
+macro_rules! debug {
+  (target: $target:expr, $($arg:tt)*) => {
+    $crate::logger::log($crate::logger::Level::Debug, $target, format_args!($($arg)*))
+  };
+  ($($arg:tt)*) => {
+    $crate::logger::log($crate::logger::Level::Debug, module_path!(), format_args!($($arg)*))
+  };
+}
+
+/// Logs a line of finer detail than `debug!`.
+macro_rules! trace {
+  (target: $target:expr, $($arg:tt)*) => {
+    $crate::logger::log($crate::logger::Level::Trace, $target, format_args!($($arg)*))
+  };
+  ($($arg:tt)*) => {
+    $crate::logger::log($crate::logger::Level::Trace, module_path!(), format_args!($($arg)*))
+  };
+}
+
+/// How serious a log line is, most serious first.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Level {
+  Error,
+  Warn,
+  Info,
+  Debug,
+  Trace,
+}
+
+impl Level {
+  const ALL: [Level; 5] = [
+    Level::Error,
+    Level::Warn,
+    Level::Info,
+    Level::Debug,
+    Level::Trace,
+  ];
+
+  fn label(self) -> &'static str {
+    match self {
+      Level::Error => "ERROR",
+      Level::Warn => "WARN",
+      Level::Info => "INFO",
+      Level::Debug => "DEBUG",
+      Level::Trace => "TRACE",
+    }
+  }
+
+  /// The level's name in a filter and a JSON line.
+  pub fn name(self) -> &'static str {
+    match self {
+      Level::Error => "error",
+      Level::Warn => "warn",
+      Level::Info => "info",
+      Level::Debug => "debug",
+      Level::Trace => "trace",
+    }
+  }
+
+  fn parse(name: &str) -> Result<Level, String> {
+    Level::ALL
+      .into_iter()
+      .find(|level| level.name().eq_ignore_ascii_case(name.trim()))
+      .ok_or_else(|| {
+        format!(
+          "unknown log level {:?}; use error, warn, info, debug, or trace",
+          name.trim()
+        )
+      })
+  }
+}
+
+/// Which lines are written: those at or above a level, which can differ by
+/// target.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Filter {
+  default: Level,
+  /// Targets with a level of their own.
+  targets: Vec<(String, Level)>,
+}
+
+impl Default for Filter {
+  fn default() -> Self {
+    Filter::DEFAULT
+  }
+}
+
+impl Filter {
+  const DEFAULT: Filter = Filter {
+    default: Level::Info,
+    targets: Vec::new(),
+  };
+
+  /// The level for `target`: that of the most specific target it is or is
+  /// below, or the default.
+  fn level(&self, target: &str) -> Level {
+    self
+      .targets
+      .iter()
+      .filter(|(name, _)| {
+        target
+          .strip_prefix(name.as_str())
+          .is_some_and(|rest| rest.is_empty() || rest.starts_with("::"))
+      })
+      .max_by_key(|(name, _)| name.len())
+      .map_or(self.default, |(_, level)| *level)
+  }
+
+  /// The most detailed level any target gets.
+  fn max(&self) -> Level {
+    self
+      .targets
+      .iter()
+      .map(|(_, level)| *level)
+      .fold(self.default, Level::max)
+  }
+}
+
+impl std::str::FromStr for Filter {
+  type Err = String;
+
+  /// Reads a spec such as `info,fyre::pipeline=debug`: a default level
+  /// and `target=level` pairs, separated by commas, in any order.
+  fn from_str(spec: &str) -> Result<Filter, String> {
+    let mut filter = Filter::DEFAULT;
+    for part in spec.split(',').map(str::trim).filter(|part| !part.is_empty()) {
+      match part.split_once('=') {
+        Some((target, level)) => {
+          let target = target.trim();
+          if target.is_empty() {
+            return Err(format!("{:?} has no target before the =", part));
+          }
+          let level = Level::parse(level)?;
+          filter.targets.retain(|(name, _)| name != target);
+          filter.targets.push((target.to_string(), level));
+        }
+        None => filter.default = Level::parse(part)?,
+      }
+    }
+    Ok(filter)
+  }
+}
+
+impl fmt::Display for Filter {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    f.write_str(self.default.name())?;
+    for (target, level) in &self.targets {
+      write!(f, ",{}={}", target, level.name())?;
+    }
+    Ok(())
+  }
+}
+
+/// How lines are written, from `CONFIG.log.format`.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+  /// `LEVEL: message`.
+  #[default]
+  Plain,
+  /// One JSON object per line.
+  Json,
+}
+
+/// The request a worker is handling, added to the JSON lines it writes.
+#[derive(Debug, Clone)]
+pub struct Context {
+  pub route: String,
+  /// `None` for a request no handler script answers.
+  pub script: Option<String>,
+  pub request_id: Option<String>,
+  /// The request's trace and span (see `trace`).
+  pub trace_id: Option<String>,
+  pub span_id: Option<String>,
+}
+
+/// Removes a thread's `Context` when dropped.
+pub struct ContextGuard(());
+
+impl Drop for ContextGuard {
+  fn drop(&mut self) {
+    CONTEXT.with(|context| *context.borrow_mut() = None);
+  }
+}
+
+/// Adds the context `make` returns to the lines this thread writes until
+/// the guard is dropped. `make` is only called for the JSON format, since
+/// plain lines don't show it.
+pub fn scope(make: impl FnOnce() -> Context) -> ContextGuard {
+  if json() {
+    let context = make();
+    CONTEXT.with(|current| *current.borrow_mut() = Some(context));
+  }
+  ContextGuard(())
+}
+
+/// When the log file is rotated, from `CONFIG.log.rotate`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Rotate {
+  /// The size the file is kept under.
+  pub max_size: u64,
+  /// The rotated files kept, `fyre.log.1` being the newest.
+  pub keep: usize,
+}
+
+impl Rotate {
+  /// Reads the settings from the `CONFIG.log.rotate` table.
+  ///
+  /// # Errors
+  ///
+  /// Returns an error message if `max_size` is missing or isn't a size, or
+  /// `keep` isn't a whole number.
+  pub fn from_lua(table: &LuaTable) -> Result<Rotate, String> {
+    let max_size = match table.get::<LuaValue>("max_size") {
+      Ok(LuaValue::Nil) => return Err("CONFIG.log.rotate.max_size is required".to_string()),
+      Ok(LuaValue::Integer(size)) if size > 0 => size as u64,
+      Ok(LuaValue::String(size)) => {
+        parse_size(&size.to_string_lossy())
+          .map_err(|e| format!("CONFIG.log.rotate.max_size: {}", e))?
+      }
+      _ => return Err("CONFIG.log.rotate.max_size must be a size such as \"50MB\"".to_string()),
+    };
+    let keep = table
+      .get::<Option<usize>>("keep")
+      .map_err(|e| format!("CONFIG.log.rotate.keep must be a whole number: {}", e))?
+      .unwrap_or(DEFAULT_KEEP);
+    Ok(Rotate { max_size, keep })
+  }
+}
+
+/// The open log file.
+struct Sink {
+  path: PathBuf,
+  file: File,
+  size: u64,
+  rotate: Option<Rotate>,
+}
+
+static SINK: Mutex<Option<Sink>> = Mutex::new(None);
+/// Set by the `SIGHUP` handler; the file is reopened before the next line.
+static REOPEN: AtomicBool = AtomicBool::new(false);
+/// The number of `SIGHUP`s received.
+static HANGUPS: AtomicU64 = AtomicU64::new(0);
+/// Set when `CONFIG.log.format` is `"json"`.
+static JSON: AtomicBool = AtomicBool::new(false);
+static FILTER: RwLock<Filter> = RwLock::new(Filter::DEFAULT);
+/// `FILTER.max()`, checked before taking the lock, so lines no target
+/// wants cost one load.
+static MAX_LEVEL: AtomicU8 = AtomicU8::new(Level::Info as u8);
+/// Set once the filter comes from the command line or the environment,
+/// which `CONFIG.log.level` doesn't override.
+static FILTER_PINNED: AtomicBool = AtomicBool::new(false);
+
+thread_local! {
+  static CONTEXT: RefCell<Option<Context>> = const { RefCell::new(None) };
+}
+
+/// Writes lines in `format` from now on.
+pub fn set_format(format: Format) {
+  JSON.store(format == Format::Json, Ordering::Relaxed);
+}
+
+/// Whether lines are written as JSON.
+pub fn json() -> bool {
+  JSON.load(Ordering::Relaxed)
+}
+
+/// Filters lines with `filter` from now on, whatever `CONFIG.log.level`
+/// says, for `--log-level`, `FYRE_LOG`, and the admin endpoint.
+pub fn set_filter(filter: Filter) {
+  FILTER_PINNED.store(true, Ordering::Relaxed);
+  store_filter(filter);
+}
+
+/// Filters lines with `filter`, from `CONFIG.log.level`, unless
+/// `set_filter` has been called.
+pub fn set_config_filter(filter: Filter) {
+  if !FILTER_PINNED.load(Ordering::Relaxed) {
+    store_filter(filter);
+  }
+}
+
+fn store_filter(filter: Filter) {
+  let mut current = FILTER.write().unwrap_or_else(PoisonError::into_inner);
+  MAX_LEVEL.store(filter.max() as u8, Ordering::Relaxed);
+  *current = filter;
+}
+
+/// The filter in effect.
+pub fn filter() -> Filter {
+  FILTER.read().unwrap_or_else(PoisonError::into_inner).clone()
+}
+
+/// Whether a line at `level` from `target`, a target name or a module
+/// path, would be written.
+pub fn enabled(level: Level, target: &str) -> bool {
+  if level as u8 > MAX_LEVEL.load(Ordering::Relaxed) {
+    return false;
+  }
+  let filter = FILTER.read().unwrap_or_else(PoisonError::into_inner);
+  if filter.targets.is_empty() {
+    return level <= filter.default;
+  }
+  level <= filter.level(&target_name(target))
+}
+
+/// Names a module path's target: the crate is `fyre`, and a helper module
+/// under `fyre` drops the second one.
+fn target_name(target: &str) -> String {
+  let Some(rest) = target.strip_prefix(env!("CARGO_CRATE_NAME")) else {
+    return target.to_string();
+  };
+  let rest = rest
+    .strip_prefix("::fyre")
+    .filter(|rest| rest.is_empty() || rest.starts_with("::"))
+    .unwrap_or(rest);
+  format!("fyre{}", rest)
+}
+
+/// The number of `SIGHUP`s received since the server started, for files
+/// that are read again after one.
+pub fn hangups() -> u64 {
+  HANGUPS.load(Ordering::Relaxed)
+}
+
+/// Writes one log line from `target` if the filter lets it through.
+pub fn log(level: Level, target: &str, message: fmt::Arguments<'_>) {
+  log_fields(level, target, message, &[]);
+}
+
+/// Writes one log line from `target`, with `fields` after the message in
+/// the JSON format, if the filter lets it through. The plain format leaves
+/// the fields out, so the message should say what matters in them.
+pub fn log_fields(
+  level: Level,
+  target: &str,
+  message: fmt::Arguments<'_>,
+  fields: &[(&str, Value)],
+) {
+  if enabled(level, target) {
+    write_fields(level, message, fields);
+  }
+}
+
+/// Writes one log line whatever the filter says, for logs with a switch
+/// of their own (see `request_log` and `audit`).
+pub fn write_fields(level: Level, message: fmt::Arguments<'_>, fields: &[(&str, Value)]) {
+  let line = if json() {
+    json_line(level, &message.to_string(), fields)
+  } else {
+    format!("{}: {}\n", level.label(), message)
+  };
+  let mut sink = sink();
+  match &mut *sink {
+    Some(sink) => sink.write(&line),
+    None if level >= Level::Info => {
+      let _ = io::stdout().lock().write_all(line.as_bytes());
+    }
+    None => {
+      let _ = io::stderr().lock().write_all(line.as_bytes());
+    }
+  }
+}
+
+/// Formats a JSON line: the time, level, and message, then `fields`, then
+/// the thread's `Context` for any field `fields` doesn't already have.
+fn json_line(level: Level, message: &str, fields: &[(&str, Value)]) -> String {
+  let mut line = String::with_capacity(message.len() + 96);
+  let mut push = |key: &str, value: &Value| {
+    line.push(if line.is_empty() { '{' } else { ',' });
+    line.push_str(&Value::from(key).to_string());
+    line.push(':');
+    line.push_str(&value.to_string());
+  };
+  push(
+    "ts",
+    &Value::from(Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true)),
+  );
+  push("level", &Value::from(level.name()));
+  push("msg", &Value::from(message));
+  for (key, value) in fields {
+    push(key, value);
+  }
+  CONTEXT.with(|context| {
+    if let Some(context) = &*context.borrow() {
+      let known = [
+        ("route", Some(&context.route)),
+        ("script", context.script.as_ref()),
+        ("request_id", context.request_id.as_ref()),
+        ("trace_id", context.trace_id.as_ref()),
+        ("span_id", context.span_id.as_ref()),
+      ];
+      for (key, value) in known {
+        if let (Some(value), false) = (value, fields.iter().any(|(k, _)| *k == key)) {
+          push(key, &Value::from(value.as_str()));
+        }
+      }
+    }
+  });
+  line.push_str("}\n");
+  line
+}
+
+/// Writes `line` as it is, without a level, to stdout or the log file, for
+/// logs with a format of their own (see `request_log`).
+pub fn write_line(line: &str) {
+  let line = format!("{}\n", line);
+  let mut sink = sink();
+  match &mut *sink {
+    Some(sink) => sink.write(&line),
+    None => {
+      let _ = io::stdout().lock().write_all(line.as_bytes());
+    }
+  }
+}
+
+/// Appends the log, and stdout and stderr, to `path` from now on.
+///
+/// # Errors
+///
+/// Returns an error message if the file can't be opened.
+pub fn open(path: &Path, rotate: Option<Rotate>) -> Result<(), String> {
+  let (file, size) = open_file(path)?;
+  *sink() = Some(Sink {
+    path: path.to_path_buf(),
+    file,
+    size,
+    rotate,
+  });
+  Ok(())
+}
+
+/// Reopens the log file, and counts the signal in `hangups`, on `SIGHUP`
+/// instead of stopping the server. It must be called after
+/// `shutdown::signals`, whose handler it replaces for that signal.
+///
+/// # Errors
+///
+/// Returns an error message if the handler can't be installed.
+#[cfg(unix)]
+pub fn handle_sighup() -> Result<(), String> {
+  extern "C" fn on_sighup(_: libc::c_int) {
+    REOPEN.store(true, Ordering::Relaxed);
+    HANGUPS.fetch_add(1, Ordering::Relaxed);
+  }
+  // SAFETY: an all-zero `sigaction` is valid, and the handler only updates
+  // atomics, which is async-signal-safe.
+  let result = unsafe {
+    let mut action: libc::sigaction = std::mem::zeroed();
+    action.sa_sigaction = on_sighup as extern "C" fn(libc::c_int) as libc::sighandler_t;
+    action.sa_flags = libc::SA_RESTART;
+    libc::sigemptyset(&mut action.sa_mask);
+    libc::sigaction(libc::SIGHUP, &action, std::ptr::null_mut())
+  };
+  if result < 0 {
+    return Err(format!(
+      "Failed to handle SIGHUP: {}",
+      io::Error::last_os_error()
+    ));
+  }
+  Ok(())
+}
+
+#[cfg(not(unix))]
+pub fn handle_sighup() -> Result<(), String> {
+  Ok(())
+}
+
+/// Locks the log file. Not `locks::lock`, whose warning would be logged
+/// through this same lock; a panic can't leave the file half-updated.
+fn sink() -> MutexGuard<'static, Option<Sink>> {
+  SINK.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+impl Sink {
+  fn write(&mut self, line: &str) {
+    if REOPEN.swap(false, Ordering::Relaxed) {
+      self.reopen();
+    }
+    let len = line.len() as u64;
+    if let Some(rotate) = &self.rotate {
+      if self.size > 0 && self.size + len > rotate.max_size {
+        self.rotate();
+      }
+    }
+    if self.file.write_all(line.as_bytes()).is_ok() {
+      self.size += len;
+    }
+  }
+
+  /// Moves each file up one number and starts a new one.
+  fn rotate(&mut self) {
+    let keep = self.rotate.as_ref().map_or(0, |rotate| rotate.keep);
+    let numbered = |n: usize| PathBuf::from(format!("{}.{}", self.path.display(), n));
+    let moved = if keep == 0 {
+      fs::remove_file(&self.path)
+    } else {
+      for n in (1..keep).rev() {
+        let _ = fs::rename(numbered(n), numbered(n + 1));
+      }
+      fs::rename(&self.path, numbered(1))
+    };
+    match moved {
+      Ok(()) => self.reopen(),
+      Err(e) => {
+        let line = format!("WARN: Failed to rotate {}: {}\n", self.path.display(), e);
+        let _ = self.file.write_all(line.as_bytes());
+        // Not retried until the file has grown by another `max_size`.
+        self.size = 0;
+      }
+    }
+  }
+
+  /// Opens the file by name again, after it was rotated or moved away.
+  fn reopen(&mut self) {
+    match open_file(&self.path) {
+      Ok((file, size)) => {
+        self.file = file;
+        self.size = size;
+      }
+      Err(e) => {
+        let _ = self.file.write_all(format!("WARN: {}\n", e).as_bytes());
+      }
+    }
+  }
+}
+
+/// Opens `path` for appending and points stdout and stderr at it, returning
+/// the file and its size.
+fn open_file(path: &Path) -> Result<(File, u64), String> {
+  let file = fs::OpenOptions::new()
+    .create(true)
+    .append(true)
+    .open(path)
+    .map_err(|e| format!("Failed to open log file {}: {}", path.display(), e))?;
+  let size = file.metadata().map_or(0, |metadata| metadata.len());
+  crate::daemon::redirect_output(&file)?;
+  Ok((file, size))
+}
+
+/// Parses a size such as `"50MB"`: a whole number of bytes, optionally
+/// followed by `KB`, `MB`, or `GB` (powers of 1024).
+///
+/// # Errors
+///
+/// Returns an error message if `size` isn't a positive size.
+pub fn parse_size(size: &str) -> Result<u64, String> {
+  let size = size.trim();
+  let digits = size
+    .find(|c: char| !c.is_ascii_digit())
+    .unwrap_or(size.len());
+  let (number, unit) = size.split_at(digits);
+  let multiplier: u64 = match unit.trim().to_ascii_uppercase().as_str() {
+    "" | "B" => 1,
+    "KB" => 1024,
+    "MB" => 1024 * 1024,
+    "GB" => 1024 * 1024 * 1024,
+    _ => return Err(format!("{:?} is not a size such as \"50MB\"", size)),
+  };
+  number
+    .parse::<u64>()
+    .ok()
+    .and_then(|n| n.checked_mul(multiplier))
+    .filter(|&n| n > 0)
+    .ok_or_else(|| format!("{:?} is not a size such as \"50MB\"", size))
+}
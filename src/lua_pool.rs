@@ -0,0 +1,210 @@
+//! # Lua State Reuse
+//!
+//! Building a Lua state and registering the `fyre` modules costs more than
+//! running a small handler, so each request worker keeps its state between
+//! requests instead of starting from scratch.
+//!
+//! Requests stay isolated because a handler script never runs against the
+//! state's globals directly: `execute_handler_pipeline` loads it with a
+//! fresh environment table that reads through to the globals but takes all
+//! of the script's writes (`request`, `response`, `fyre.session` and the
+//! other per-request modules, and any global the script sets).
+//!
+//! That doesn't stop a script from changing a table it reads through to, as in
+//! `string.trim = ...`, `fyre.kv.get = nil`, or `rawset(_G, ...)` from a
+//! library. So when a state is set up, every table reachable from its globals
+//! and the string metatable is copied, and when it is returned each is put back
+//! as it was: fields added are removed, fields changed or removed get their old
+//! value, and a metatable set or cleared is restored. A state is retired after
+//! `CONFIG.lua.state_max_uses` requests, when its memory use passes
+//! `MAX_MEMORY_BYTES`, after a request fails, or if it can't be restored (e.g.
+//! a metatable was locked with `__metatable`), which bounds whatever a script
+//! manages to leak another way, such as through a closure's upvalues.
+
+use mlua::prelude::*;
+use std::sync::Arc;
+
+use crate::{fyre, AppState};
+
+/// The default number of requests a state serves before it is replaced.
+pub const DEFAULT_MAX_USES: u32 = 1000;
+/// The memory use past which a state is replaced rather than reused.
+const MAX_MEMORY_BYTES: usize = 64 * 1024 * 1024;
+
+/// Copies every table reachable from the globals and the string metatable,
+/// and returns the function that puts them back. The functions it calls are
+/// held as upvalues, so a script replacing the globals can't change what
+/// it does.
+const SNAPSHOT: &str = r#"
+local next, rawget, rawset, rawequal, type, getmetatable, setmetatable =
+  next, rawget, rawset, rawequal, type, getmetatable, setmetatable
+local saved = {}
+local function save(t)
+  if saved[t] then return end
+  local fields = {}
+  saved[t] = { fields = fields, metatable = getmetatable(t) }
+  for k, v in next, t do
+    fields[k] = v
+    if type(v) == "table" then save(v) end
+  end
+end
+save(_G)
+save(getmetatable(""))
+return function()
+  for t, s in next, saved do
+    local fields = s.fields
+    for k, v in next, t do
+      local old = rawget(fields, k)
+      if not rawequal(old, v) then rawset(t, k, old) end
+    end
+    for k, v in next, fields do
+      if not rawequal(rawget(t, k), v) then rawset(t, k, v) end
+    end
+    if not rawequal(getmetatable(t), s.metatable) then setmetatable(t, s.metatable) end
+  end
+end
+"#;
+
+/// A Lua state with the `fyre` modules registered.
+pub struct PooledLua {
+  pub lua: Lua,
+  uses: u32,
+  /// Puts the tables reachable from the globals back as they were after
+  /// setup.
+  restore: LuaFunction,
+}
+
+/// The reusable Lua state of one request worker.
+pub struct LuaPool {
+  state: Arc<AppState>,
+  idle: Option<PooledLua>,
+  max_uses: u32,
+}
+
+impl LuaPool {
+  /// Creates an empty pool; the first `checkout` builds the state.
+  pub fn new(state: &Arc<AppState>, max_uses: u32) -> Self {
+    LuaPool {
+      state: state.clone(),
+      idle: None,
+      max_uses,
+    }
+  }
+
+  /// Returns the idle state, or a new one if there is none.
+  ///
+  /// # Errors
+  ///
+  /// This function will return a `LuaError` if a new state cannot be set up.
+  pub fn checkout(&mut self) -> LuaResult<PooledLua> {
+    let pooled = match self.idle.take() {
+      Some(pooled) => pooled,
+      None => {
+        let lua = Lua::new();
+        fyre::register(&lua, &self.state)?;
+        let restore = lua.load(SNAPSHOT).set_name("=snapshot").call(())?;
+        PooledLua {
+          lua,
+          uses: 0,
+          restore,
+        }
+      }
+    };
+    // Reseed per request so a handler calling `math.randomseed` can't make
+    // the next request's numbers predictable.
+    fyre::random::seed_math_random(&pooled.lua)?;
+    Ok(pooled)
+  }
+
+  /// Returns a state after a request. `ok` is `false` if the request failed,
+  /// in which case the state is dropped.
+  pub fn checkin(&mut self, mut pooled: PooledLua, ok: bool) {
+    pooled.uses += 1;
+    if !ok || pooled.uses >= self.max_uses || pooled.lua.used_memory() > MAX_MEMORY_BYTES {
+      return;
+    }
+    if pooled.restore.call::<()>(()).is_err() {
+      return;
+    }
+    self.idle = Some(pooled);
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::testing::{self, Fixture};
+
+  #[test]
+  fn shared_tables_are_restored() {
+    let fixture = Fixture::new("", &[]);
+    let server = fixture.server();
+    let mut pool = LuaPool::new(&server.state, 10);
+
+    let pooled = pool.checkout().unwrap();
+    pooled
+      .lua
+      .load(
+        r#"
+          string.upper = function() return "changed" end
+          string.extra = true
+          fyre.kv = nil
+          rawset(_G, "leaked", 1)
+          setmetatable(math, { __index = function() return 0 end })
+          getmetatable("").__index = { len = function() return -1 end }
+        "#,
+      )
+      .exec()
+      .unwrap();
+    pool.checkin(pooled, true);
+    assert!(pool.idle.is_some(), "the state should be reused");
+
+    let pooled = pool.checkout().unwrap();
+    let seen: (String, Option<bool>, bool, Option<i64>, Option<i64>, i64) = pooled
+      .lua
+      .load(
+        r#"return ("a"):upper(), string.extra, fyre.kv ~= nil, leaked, math.missing,
+          ("ab"):len()"#,
+      )
+      .eval()
+      .unwrap();
+    assert_eq!(seen, ("A".to_string(), None, true, None, None, 2));
+  }
+
+  #[test]
+  fn a_request_changing_string_is_not_seen_by_the_next() {
+    let fixture = Fixture::new(
+      r#"
+        router.add("/change", "change.lua")
+        router.add("/read", "read.lua")
+      "#,
+      &[
+        (
+          "change.lua",
+          r#"return { handler = function(request, response)
+            string.upper = function() return "changed" end
+            response.body = ("a"):upper()
+          end }"#,
+        ),
+        (
+          "read.lua",
+          r#"return { handler = function(request, response)
+            local shared = getmetatable(fyre).__index
+            response.body = ("a"):upper() .. " " .. tostring(rawget(shared, "session"))
+          end }"#,
+        ),
+      ],
+    );
+    let server = fixture
+      .builder()
+      .addr("127.0.0.1:0")
+      .workers(1)
+      .load()
+      .unwrap();
+    let addr = server.serve().unwrap().remove(0);
+    assert!(testing::get(&addr, "/change").ends_with("\r\n\r\nchanged"));
+    let read = testing::get(&addr, "/read");
+    assert!(read.ends_with("\r\n\r\nA nil"), "{}", read);
+    server.shutdown();
+  }
+}
@@ -0,0 +1,322 @@
+//! # Listening Socket
+//!
+//! Creates the server's TCP listener with the socket options from
+//! `config.lua`, then hands it to `server::Server`.
+//!
+//! Options are set on the listener. Linux copies `CONFIG.socket.nodelay` and
+//! the buffer sizes to every accepted connection.
+//!
+//! An address of the form `unix:/run/fyre.sock` listens on a Unix domain socket
+//! instead, for a proxy on the same host. A socket file left behind by a server
+//! that is no longer running is removed first, but one another server is still
+//! accepting on is left alone and binding fails. The socket's permissions are
+//! set from `CONFIG.socket.unix_mode`; `CONFIG.socket.nodelay` doesn't apply to
+//! it.
+//!
+//! A bind that fails because the address is in use, e.g. while a crashed
+//! server's connections sit in TIME_WAIT, is retried as `CONFIG.bind.retry`
+//! says: up to `attempts` tries in all, waiting `delay_ms` after the first
+//! failure and twice as long after each one since, up to `MAX_RETRY_DELAY`.
+//! `CONFIG.bind.reuse_addr` is set by default on Unix, as `TcpListener::bind`
+//! does; `CONFIG.bind.reuse_port`, off by default, lets several servers listen
+//! on the same port, with the OS spreading connections between them.
+
+use socket2::{Domain, Protocol, Socket, Type};
+use std::io;
+use std::net::{SocketAddr, TcpListener, ToSocketAddrs};
+#[cfg(unix)]
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::Path;
+use std::time::Duration;
+
+/// The accept backlog used when `CONFIG.socket.backlog` is not set, matching
+/// `TcpListener::bind`.
+pub const DEFAULT_BACKLOG: u32 = 128;
+/// The largest accepted `CONFIG.socket.backlog`.
+pub const MAX_BACKLOG: u32 = 65_535;
+/// The largest accepted `CONFIG.socket.recv_buffer` or
+/// `CONFIG.socket.send_buffer`.
+pub const MAX_BUFFER_BYTES: usize = 64 * 1024 * 1024;
+/// The first wait before retrying a bind when `CONFIG.bind.retry` sets no
+/// `delay_ms`.
+pub const DEFAULT_RETRY_DELAY: Duration = Duration::from_millis(500);
+/// The longest wait between two binds, however many attempts are left.
+pub const MAX_RETRY_DELAY: Duration = Duration::from_secs(10);
+
+/// Socket settings from `config.lua`. The defaults match a plain
+/// `TcpListener::bind`.
+#[derive(Debug, Clone)]
+pub struct SocketOptions {
+  pub nodelay: bool,
+  pub backlog: u32,
+  pub recv_buffer: Option<usize>,
+  pub send_buffer: Option<usize>,
+  /// The permissions of a Unix socket, e.g. `0o660`. `None` leaves them to
+  /// the process umask.
+  pub unix_mode: Option<u32>,
+  pub reuse_addr: bool,
+  /// Set on platforms with `CONFIG.bind.reuse_port`; elsewhere binding fails.
+  pub reuse_port: bool,
+  pub retry: BindRetry,
+}
+
+impl Default for SocketOptions {
+  fn default() -> Self {
+    SocketOptions {
+      nodelay: false,
+      backlog: DEFAULT_BACKLOG,
+      recv_buffer: None,
+      send_buffer: None,
+      unix_mode: None,
+      reuse_addr: cfg!(unix),
+      reuse_port: false,
+      retry: BindRetry::default(),
+    }
+  }
+}
+
+/// How a bind that fails because the address is in use is retried.
+#[derive(Debug, Clone)]
+pub struct BindRetry {
+  /// The tries in all, the first included; `1` doesn't retry.
+  pub attempts: u32,
+  /// The wait after the first failure, doubled after each one since.
+  pub delay: Duration,
+}
+
+impl Default for BindRetry {
+  fn default() -> Self {
+    BindRetry {
+      attempts: 1,
+      delay: DEFAULT_RETRY_DELAY,
+    }
+  }
+}
+
+/// A listening socket the server accepts connections on.
+pub enum Listener {
+  Tcp(TcpListener),
+  #[cfg(unix)]
+  Unix(UnixListener),
+}
+
+impl Listener {
+  /// Returns the TCP port listened on, or `None` for a Unix socket.
+  pub fn port(&self) -> Option<u16> {
+    match self {
+      Listener::Tcp(listener) => listener.local_addr().ok().map(|addr| addr.port()),
+      #[cfg(unix)]
+      Listener::Unix(_) => None,
+    }
+  }
+
+  /// Returns the address actually listened on, as `ip:port` or
+  /// `unix:/path`: the port the OS chose for port 0, and the IP a host name
+  /// resolved to.
+  ///
+  /// # Errors
+  ///
+  /// This function will return an error if the OS can't report it.
+  pub fn local_addr(&self) -> io::Result<String> {
+    match self {
+      Listener::Tcp(listener) => listener.local_addr().map(|addr| addr.to_string()),
+      #[cfg(unix)]
+      Listener::Unix(listener) => {
+        let addr = listener.local_addr()?;
+        let path = addr.as_pathname().unwrap_or(Path::new(""));
+        Ok(format!("unix:{}", path.display()))
+      }
+    }
+  }
+}
+
+/// Returns the socket path of a `unix:` address.
+pub fn unix_path(addr: &str) -> Option<&Path> {
+  addr.strip_prefix("unix:").map(Path::new)
+}
+
+/// Checks that `addr` has a form `listen` accepts, `host:port` or
+/// `unix:/path/to.sock`, without resolving or binding it, so a typo is
+/// reported when the configuration is read.
+///
+/// # Errors
+///
+/// Returns an error message saying what is wrong with `addr`.
+pub fn validate_addr(addr: &str) -> Result<(), String> {
+  if let Some(path) = unix_path(addr) {
+    if path.as_os_str().is_empty() {
+      return Err(format!("{}: the socket path is empty", addr));
+    }
+    return Ok(());
+  }
+  let Some((host, port)) = addr.rsplit_once(':') else {
+    return Err(format!("{}: expected host:port or unix:/path", addr));
+  };
+  if host.is_empty() {
+    return Err(format!(
+      "{}: the host is empty (0.0.0.0{} listens on every interface)",
+      addr,
+      &addr[host.len()..]
+    ));
+  }
+  if host.contains(':') && !(host.starts_with('[') && host.ends_with(']')) {
+    return Err(format!("{}: IPv6 addresses go in brackets, e.g. [::1]:8000", addr));
+  }
+  if port.parse::<u16>().is_err() {
+    return Err(format!("{}: '{}' is not a port number", addr, port));
+  }
+  Ok(())
+}
+
+/// Binds a listener on `addr`: a Unix socket for a `unix:` address, or else
+/// TCP as `bind` does. Retries while the address is in use, as
+/// `options.retry` says.
+///
+/// # Errors
+///
+/// This function will return an error if the listener can't be created, or
+/// for a `unix:` address if the path exists and is not a socket or another
+/// server is still listening on it after the last attempt.
+pub fn listen(addr: &str, options: &SocketOptions) -> io::Result<Listener> {
+  match unix_path(addr) {
+    #[cfg(unix)]
+    Some(path) => retry(addr, &options.retry, || bind_unix(path, options)).map(Listener::Unix),
+    #[cfg(not(unix))]
+    Some(_) => Err(io::Error::new(
+      io::ErrorKind::Unsupported,
+      "Unix sockets are not supported on this platform",
+    )),
+    None => bind(addr, options).map(Listener::Tcp),
+  }
+}
+
+/// Binds a listener on the first address `addr` resolves to that works,
+/// retrying while they are in use as `options.retry` says.
+///
+/// # Errors
+///
+/// This function will return an error if `addr` doesn't resolve, an option
+/// can't be applied, or no address can be bound.
+pub fn bind(addr: &str, options: &SocketOptions) -> io::Result<TcpListener> {
+  retry(addr, &options.retry, || bind_any(addr, options))
+}
+
+/// Calls `bind` until it succeeds, fails other than because `addr` is in
+/// use, or has been tried `retry.attempts` times, logging each failure
+/// before waiting to try again.
+fn retry<T>(
+  addr: &str,
+  retry: &BindRetry,
+  mut bind: impl FnMut() -> io::Result<T>,
+) -> io::Result<T> {
+  let mut delay = retry.delay;
+  let mut attempt = 1;
+  loop {
+    match bind() {
+      Err(e) if attempt < retry.attempts && is_in_use(&e) => {
+        warn!(
+          "Could not listen on {} (attempt {} of {}): {}; retrying in {} ms",
+          addr,
+          attempt,
+          retry.attempts,
+          e,
+          delay.as_millis()
+        );
+        std::thread::sleep(delay);
+        delay = (delay * 2).min(MAX_RETRY_DELAY);
+        attempt += 1;
+      }
+      result => return result,
+    }
+  }
+}
+
+/// Whether a bind failed for a reason that may pass: the address is in use,
+/// or not yet assigned to an interface.
+fn is_in_use(e: &io::Error) -> bool {
+  matches!(e.kind(), io::ErrorKind::AddrInUse | io::ErrorKind::AddrNotAvailable)
+}
+
+/// Binds the first address `addr` resolves to that works.
+fn bind_any(addr: &str, options: &SocketOptions) -> io::Result<TcpListener> {
+  let mut last_err = io::Error::new(io::ErrorKind::NotFound, "address did not resolve");
+  for socket_addr in addr.to_socket_addrs()? {
+    match bind_one(socket_addr, options) {
+      Ok(listener) => return Ok(listener),
+      Err(e) => last_err = e,
+    }
+  }
+  Err(last_err)
+}
+
+fn bind_one(addr: SocketAddr, options: &SocketOptions) -> io::Result<TcpListener> {
+  let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+  // On by default on Unix, as `TcpListener::bind` does, so a restart
+  // doesn't fail while old connections sit in TIME_WAIT.
+  if options.reuse_addr {
+    socket.set_reuse_address(true)?;
+  }
+  if options.reuse_port {
+    set_reuse_port(&socket)?;
+  }
+  if options.nodelay {
+    socket.set_nodelay(true)?;
+  }
+  if let Some(size) = options.recv_buffer {
+    socket.set_recv_buffer_size(size)?;
+  }
+  if let Some(size) = options.send_buffer {
+    socket.set_send_buffer_size(size)?;
+  }
+  socket.bind(&addr.into())?;
+  socket.listen(options.backlog as i32)?;
+  Ok(socket.into())
+}
+
+#[cfg(all(unix, not(any(target_os = "solaris", target_os = "illumos"))))]
+fn set_reuse_port(socket: &Socket) -> io::Result<()> {
+  socket.set_reuse_port(true)
+}
+
+#[cfg(not(all(unix, not(any(target_os = "solaris", target_os = "illumos")))))]
+fn set_reuse_port(_socket: &Socket) -> io::Result<()> {
+  Err(io::Error::new(
+    io::ErrorKind::Unsupported,
+    "CONFIG.bind.reuse_port is not supported on this platform",
+  ))
+}
+
+#[cfg(unix)]
+fn bind_unix(path: &Path, options: &SocketOptions) -> io::Result<UnixListener> {
+  use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+
+  if let Ok(metadata) = std::fs::symlink_metadata(path) {
+    if !metadata.file_type().is_socket() {
+      return Err(io::Error::new(
+        io::ErrorKind::AlreadyExists,
+        format!("{} exists and is not a socket", path.display()),
+      ));
+    }
+    if UnixStream::connect(path).is_ok() {
+      return Err(io::Error::new(
+        io::ErrorKind::AddrInUse,
+        format!("another server is listening on {}", path.display()),
+      ));
+    }
+    std::fs::remove_file(path)?;
+  }
+
+  let socket = Socket::new(Domain::UNIX, Type::STREAM, None)?;
+  if let Some(size) = options.recv_buffer {
+    socket.set_recv_buffer_size(size)?;
+  }
+  if let Some(size) = options.send_buffer {
+    socket.set_send_buffer_size(size)?;
+  }
+  socket.bind(&socket2::SockAddr::unix(path)?)?;
+  if let Some(mode) = options.unix_mode {
+    std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode))?;
+  }
+  socket.listen(options.backlog as i32)?;
+  Ok(socket.into())
+}
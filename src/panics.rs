@@ -0,0 +1,193 @@
+//! # Panics
+//!
+//! A panic while a request is handled, from a bug in the server's own Rust
+//! code, a helper unwrapping bad data, or a lock held across it, must not
+//! take the server down with it. Request handling runs inside `catch`,
+//! which stops the unwind and returns the panic as a `Panic`:
+//!
+//! - A panic in the pipeline is answered with a `500`, and the Lua state
+//!   it ran in is dropped as it unwinds, so the next request gets a new one
+//!   (see `handle_request`). A panic anywhere else in `handle_request` loses
+//!   the response, and the worker starts again with a new pool of states.
+//!   Either way the worker is counted in `restarts` (see `worker_stats`).
+//! - It is logged as an error with its message and where it was raised,
+//!   and the stack at that point is logged at `debug` level for the
+//!   `fyre::pipeline` target.
+//! - It is counted in `fyre_panics_total{route}` (see `request_metrics`).
+//!
+//! The hook `install` sets keeps the default `thread '...' panicked`
+//! message off stderr for the panics `catch` handles, since they are
+//! logged; a panic on any other thread goes to the hook that was there
+//! before.
+//!
+//! What a handler can leave half-updated when it panics is its Lua state,
+//! which is thrown away, and the shared stores behind a `Mutex`, which
+//! `locks::lock` recovers (see `locks`). That is why `catch` can take a
+//! closure that isn't `UnwindSafe`.
+
+use std::any::Any;
+use std::backtrace::Backtrace;
+use std::cell::{Cell, RefCell};
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::Once;
+
+use crate::logger::{self, Level};
+use crate::PIPELINE_TARGET;
+
+thread_local! {
+  /// How many `catch` calls this thread is inside.
+  static CATCHING: Cell<usize> = const { Cell::new(0) };
+  /// Where the panic being caught was raised, and the stack then.
+  static CAUGHT: RefCell<Option<(Option<String>, Option<Backtrace>)>> =
+    const { RefCell::new(None) };
+}
+
+/// A panic stopped by `catch`.
+#[derive(Debug)]
+pub struct Panic {
+  pub message: String,
+  /// Where it was raised, `file:line:column`.
+  pub location: Option<String>,
+  /// The stack where it was raised, captured when `fyre::pipeline` logs at
+  /// `debug` level.
+  pub backtrace: Option<Backtrace>,
+}
+
+impl Panic {
+  /// Logs the backtrace, if one was captured, at `debug` level.
+  pub fn log_backtrace(&self, worker: usize) {
+    if let Some(backtrace) = &self.backtrace {
+      debug!(
+        target: PIPELINE_TARGET,
+        "[worker {}] Backtrace of the panic:\n{}", worker, backtrace
+      );
+    }
+  }
+}
+
+impl std::fmt::Display for Panic {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    match &self.location {
+      Some(location) => write!(f, "{} at {}", self.message, location),
+      None => f.write_str(&self.message),
+    }
+  }
+}
+
+/// Sets the panic hook `catch` relies on, once per process.
+pub fn install() {
+  static INSTALL: Once = Once::new();
+  INSTALL.call_once(|| {
+    let previous = panic::take_hook();
+    panic::set_hook(Box::new(move |info| {
+      if CATCHING.with(Cell::get) == 0 {
+        previous(info);
+        return;
+      }
+      let location = info.location().map(ToString::to_string);
+      let backtrace = logger::enabled(Level::Debug, PIPELINE_TARGET).then(Backtrace::force_capture);
+      CAUGHT.with(|caught| *caught.borrow_mut() = Some((location, backtrace)));
+    }));
+  });
+}
+
+/// Runs `f`, returning the panic that ended it if it panicked. `f` is run
+/// as if it were `UnwindSafe`; the caller drops whatever state it may have
+/// left half-updated.
+pub fn catch<R>(f: impl FnOnce() -> R) -> Result<R, Panic> {
+  CATCHING.with(|catching| catching.set(catching.get() + 1));
+  let result = panic::catch_unwind(AssertUnwindSafe(f));
+  CATCHING.with(|catching| catching.set(catching.get() - 1));
+  result.map_err(|payload| {
+    let (location, backtrace) = CAUGHT
+      .with(|caught| caught.borrow_mut().take())
+      .unwrap_or_default();
+    Panic {
+      message: message(&*payload).to_string(),
+      location,
+      backtrace,
+    }
+  })
+}
+
+/// Returns the message a panic was raised with.
+fn message(payload: &(dyn Any + Send)) -> &str {
+  if let Some(message) = payload.downcast_ref::<&str>() {
+    message
+  } else if let Some(message) = payload.downcast_ref::<String>() {
+    message
+  } else {
+    "unknown panic"
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::testing::{self, Fixture};
+
+  #[test]
+  fn a_caught_panic_keeps_its_message_and_location() {
+    install();
+    let panic = catch(|| -> u32 { panic!("bad data: {}", 7) }).unwrap_err();
+    assert_eq!(panic.message, "bad data: 7");
+    assert!(panic
+      .location
+      .as_deref()
+      .unwrap()
+      .starts_with("src/panics.rs:"));
+    assert_eq!(catch(|| 7).unwrap(), 7);
+  }
+
+  #[test]
+  fn a_panicking_handler_is_answered_and_counted_and_the_worker_goes_on() {
+    let fixture = Fixture::new(
+      r#"
+        router.add("/boom", "boom.lua")
+        router.add("/ok", "ok.lua")
+      "#,
+      &[
+        (
+          "boom.lua",
+          "return { handler = function() fyre.panic('helper failed') end }",
+        ),
+        (
+          "ok.lua",
+          "return { handler = function(request, response) response.body = 'ok' end }",
+        ),
+      ],
+    );
+    let server = fixture
+      .builder()
+      .addr("127.0.0.1:0")
+      .workers(1)
+      .load()
+      .unwrap();
+    let addr = server.serve().unwrap().remove(0);
+
+    for _ in 0..2 {
+      let boom = testing::get(&addr, "/boom");
+      assert!(boom.starts_with("HTTP/1.1 500"), "{}", boom);
+      assert!(
+        boom.ends_with("\r\n\r\nServer Error: handler panicked"),
+        "{}",
+        boom
+      );
+      // The one worker serves the next request.
+      let ok = testing::get(&addr, "/ok");
+      assert!(ok.ends_with("\r\n\r\nok"), "{}", ok);
+    }
+    let metrics = crate::fyre::metrics::render(&server.state);
+    assert!(
+      metrics.contains("\nfyre_panics_total{route=\"/boom\"} 2\n"),
+      "{}",
+      metrics
+    );
+    assert!(
+      !metrics.contains("fyre_panics_total{route=\"/ok\"}"),
+      "{}",
+      metrics
+    );
+    server.shutdown();
+  }
+}
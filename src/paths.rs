@@ -0,0 +1,217 @@
+//! # File Locations
+//!
+//! Where the configuration script and the handler scripts are: `--config`
+//! or `FYRE_CONFIG` (default `config.lua`), and `--scripts` or
+//! `FYRE_SCRIPTS_DIR` (default `scripts` next to the config file).
+//!
+//! Relative paths written in `config.lua` (static directories, `tls`
+//! files, `sandbox.fs_allow`, and the data and cache directories) are
+//! resolved against the config file's directory, not the working
+//! directory, so the server behaves the same wherever it is started from
+//! and several instances can run from one directory. Script names are resolved against
+//! the scripts directory by `Paths::script`, which every script reference
+//! goes through, and must stay inside it, symlinks included.
+//!
+//! The environment (`--env` or `FYRE_ENV`, default `development`) picks the
+//! file run after the configuration script: `config.production.lua` next
+//! to `config.lua` for `production`, if it exists.
+
+use std::fs;
+use std::path::{Component, Path, PathBuf};
+
+use crate::cli;
+
+/// The resolved locations of a configuration.
+#[derive(Debug, Clone)]
+pub struct Paths {
+  /// The canonical path of the configuration script.
+  config_file: PathBuf,
+  /// The directory relative paths in the configuration are resolved
+  /// against.
+  base: PathBuf,
+  /// The canonical scripts directory.
+  scripts_dir: PathBuf,
+  /// The environment name, e.g. `"production"`.
+  env: String,
+}
+
+impl Paths {
+  /// Resolves the configuration script and scripts directory from the
+  /// command line. A relative `--config` or `--scripts` is taken from the
+  /// working directory.
+  ///
+  /// # Errors
+  ///
+  /// Returns an error message if the configuration script or the scripts
+  /// directory doesn't exist, or the environment name isn't made of
+  /// letters, digits, `-`, and `_`.
+  pub fn new(args: &cli::ConfigArgs) -> Result<Paths, String> {
+    let valid = |c: char| c.is_ascii_alphanumeric() || c == '-' || c == '_';
+    if args.env.is_empty() || !args.env.chars().all(valid) {
+      return Err(format!(
+        "The environment name must be letters, digits, '-', and '_', got {:?}",
+        args.env
+      ));
+    }
+    let config_file = fs::canonicalize(&args.config)
+      .map_err(|e| format!("Config file {}: {}", args.config.display(), e))?;
+    let base = config_file
+      .parent()
+      .map_or_else(|| PathBuf::from("/"), Path::to_path_buf);
+    let scripts = args
+      .scripts
+      .clone()
+      .unwrap_or_else(|| base.join(cli::DEFAULT_SCRIPTS_DIR));
+    let scripts_dir = fs::canonicalize(&scripts)
+      .map_err(|e| format!("Scripts directory {}: {}", scripts.display(), e))?;
+    Ok(Paths {
+      config_file,
+      base,
+      scripts_dir,
+      env: args.env.clone(),
+    })
+  }
+
+  pub fn config_file(&self) -> &Path {
+    &self.config_file
+  }
+
+  pub fn scripts_dir(&self) -> &Path {
+    &self.scripts_dir
+  }
+
+  /// The configuration script's directory.
+  pub fn base(&self) -> &Path {
+    &self.base
+  }
+
+  pub fn env(&self) -> &str {
+    &self.env
+  }
+
+  /// The environment's configuration file, `<stem>.<env>.lua` next to the
+  /// configuration script. It may not exist.
+  pub fn env_config_file(&self) -> PathBuf {
+    let stem = self
+      .config_file
+      .file_stem()
+      .map_or_else(|| "config".into(), |stem| stem.to_string_lossy());
+    self.base.join(format!("{}.{}.lua", stem, self.env))
+  }
+
+  /// Resolves a path written in the configuration against its directory.
+  /// Absolute paths are returned unchanged.
+  pub fn resolve(&self, path: impl AsRef<Path>) -> PathBuf {
+    self.base.join(path)
+  }
+
+  /// Like `resolve`, for the settings kept as strings.
+  pub fn resolve_string(&self, path: &str) -> String {
+    self.resolve(path).display().to_string()
+  }
+
+  /// Resolves the script `name` against the scripts directory, returning
+  /// its canonical path. The script must be inside the directory: `name`
+  /// can't be absolute or contain `..`, and a symlink may only point to
+  /// another file inside it. The directory itself may be a symlink.
+  ///
+  /// # Errors
+  ///
+  /// Returns an error message naming the path if the script doesn't exist
+  /// or isn't inside the scripts directory.
+  pub fn script(&self, name: &str) -> Result<String, String> {
+    let relative = Path::new(name);
+    if relative.has_root() || relative.is_absolute() {
+      return Err(format!(
+        "{}: script names are relative to the scripts directory {}",
+        name,
+        self.scripts_dir.display()
+      ));
+    }
+    if relative.components().any(|c| c == Component::ParentDir) {
+      return Err(format!("{}: script names can't contain '..'", name));
+    }
+    let path = self.scripts_dir.join(relative);
+    let canonical = fs::canonicalize(&path).map_err(|e| format!("{}: {}", path.display(), e))?;
+    if !canonical.starts_with(&self.scripts_dir) {
+      return Err(format!(
+        "{} resolves to {}, outside the scripts directory {}",
+        name,
+        canonical.display(),
+        self.scripts_dir.display()
+      ));
+    }
+    Ok(canonical.display().to_string())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::testing::Fixture;
+
+  fn load(fixture: &Fixture, scripts: Option<PathBuf>) -> Paths {
+    Paths::new(&cli::ConfigArgs {
+      config: fixture.path().join("config.lua"),
+      scripts,
+      env: cli::DEFAULT_ENV.to_string(),
+    })
+    .unwrap()
+  }
+
+  #[test]
+  fn scripts_outside_the_directory_are_refused() {
+    let fixture = Fixture::new("", &[("hello.lua", "")]);
+    fs::write(fixture.path().join("secret.lua"), "").unwrap();
+    let paths = load(&fixture, None);
+    let refused = |name: &str| paths.script(name).unwrap_err();
+    assert!(refused("/etc/passwd").contains("relative to the scripts directory"));
+    assert!(refused("../secret.lua").contains("can't contain '..'"));
+    assert!(refused("nested/../../secret.lua").contains("can't contain '..'"));
+    assert!(refused("missing.lua").contains("missing.lua"));
+    assert_eq!(
+      paths.script("hello.lua").unwrap(),
+      paths.scripts_dir().join("hello.lua").display().to_string()
+    );
+  }
+
+  #[cfg(unix)]
+  #[test]
+  fn symlinks_may_not_leave_the_directory() {
+    use std::os::unix::fs::symlink;
+
+    let fixture = Fixture::new("", &[("hello.lua", "")]);
+    let scripts = fixture.path().join("scripts");
+    fs::write(fixture.path().join("secret.lua"), "").unwrap();
+    symlink(
+      fixture.path().join("secret.lua"),
+      scripts.join("escape.lua"),
+    )
+    .unwrap();
+    symlink(scripts.join("hello.lua"), scripts.join("alias.lua")).unwrap();
+    let paths = load(&fixture, None);
+    assert!(paths
+      .script("escape.lua")
+      .unwrap_err()
+      .contains("outside the scripts directory"));
+    assert_eq!(
+      paths.script("alias.lua").unwrap(),
+      paths.script("hello.lua").unwrap()
+    );
+
+    // A symlinked scripts directory is where its scripts are.
+    let linked = fixture.path().join("linked");
+    symlink(&scripts, &linked).unwrap();
+    let paths = load(&fixture, Some(linked));
+    assert_eq!(paths.scripts_dir(), fs::canonicalize(&scripts).unwrap());
+    assert!(paths.script("hello.lua").is_ok());
+    assert!(paths.script("escape.lua").is_err());
+  }
+
+  #[test]
+  fn a_route_to_a_script_outside_fails_loading() {
+    let fixture = Fixture::new(r#"router.add("/x", "../../etc/passwd")"#, &[]);
+    let error = fixture.builder().load().err().unwrap().to_string();
+    assert!(error.contains("Route /x: bad handler script"), "{}", error);
+  }
+}
@@ -0,0 +1,246 @@
+//! # Dropping Privileges
+//!
+//! Binding ports below 1024 takes root, but running Lua handlers as root
+//! shouldn't. With `CONFIG.run_as = { user = "fyre", group = "fyre" }`,
+//! `fyre serve` binds its addresses, reads the TLS certificate and key,
+//! opens the log file, and writes the PID file as the user it was started
+//! as, then switches to the named account before any handler, queue
+//! worker, or scheduled task runs. `group` defaults to the user's primary
+//! group, and the supplementary groups are dropped to just that one.
+//!
+//! The log and PID files are handed to the account first, so the log can
+//! still be reopened and rotated. If the switch fails, or root could be
+//! regained afterwards, the server doesn't start. The account is looked up
+//! when the configuration is loaded, so a typo is caught then. `run_as` is
+//! only available on Unix.
+
+use mlua::prelude::*;
+use std::path::Path;
+
+/// The account to run as, from `CONFIG.run_as`.
+#[derive(Debug, Clone)]
+pub struct RunAs {
+  user: String,
+  #[cfg(unix)]
+  uid: libc::uid_t,
+  #[cfg(unix)]
+  gid: libc::gid_t,
+}
+
+impl RunAs {
+  /// Reads `CONFIG.run_as`: `user`, and optionally `group`, by name.
+  ///
+  /// # Errors
+  ///
+  /// Returns an error message if `user` is missing, the user or group
+  /// doesn't exist, a field has the wrong type, or the platform isn't Unix.
+  #[cfg(unix)]
+  pub fn from_lua(table: &LuaTable) -> Result<RunAs, String> {
+    let bad = |e: LuaError| format!("CONFIG.run_as must be {{ user = ..., group = ... }}: {}", e);
+    let user = table
+      .get::<Option<String>>("user")
+      .map_err(bad)?
+      .ok_or("CONFIG.run_as needs a user")?;
+    let group = table.get::<Option<String>>("group").map_err(bad)?;
+    let (uid, primary_gid) = lookup_user(&user)?;
+    let gid = match &group {
+      Some(group) => lookup_group(group)?,
+      None => primary_gid,
+    };
+    Ok(RunAs { user, uid, gid })
+  }
+
+  #[cfg(not(unix))]
+  pub fn from_lua(_table: &LuaTable) -> Result<RunAs, String> {
+    Err(
+      "CONFIG.run_as is not supported on this platform; run fyre as a service account instead"
+        .to_string(),
+    )
+  }
+
+  /// Gives `path` to the account, so it can still be written, renamed, or
+  /// reopened once privileges are dropped.
+  ///
+  /// # Errors
+  ///
+  /// Returns an error message if the owner can't be changed.
+  #[cfg(unix)]
+  pub fn chown(&self, path: &Path) -> Result<(), String> {
+    std::os::unix::fs::chown(path, Some(self.uid), Some(self.gid)).map_err(|e| {
+      format!(
+        "Failed to give {} to user {}: {}",
+        path.display(),
+        self.user,
+        e
+      )
+    })
+  }
+
+  #[cfg(not(unix))]
+  pub fn chown(&self, _path: &Path) -> Result<(), String> {
+    Ok(())
+  }
+
+  /// Switches the process to the account: the supplementary groups, then
+  /// the group, then the user, in the order that keeps the permission to
+  /// make the next change. Does nothing if it already runs as the account.
+  ///
+  /// # Errors
+  ///
+  /// Returns an error message if a change fails, or root can still be
+  /// regained afterwards.
+  #[cfg(unix)]
+  pub fn drop_privileges(&self) -> Result<(), String> {
+    use std::io;
+
+    // SAFETY: these calls take plain integers and a pointer to one gid,
+    // which outlives the call.
+    unsafe {
+      if libc::geteuid() == self.uid && libc::getuid() == self.uid && libc::getegid() == self.gid {
+        return Ok(());
+      }
+      let failed = |what: &str| {
+        format!(
+          "Failed to switch to user {} ({}): {}",
+          self.user,
+          what,
+          io::Error::last_os_error()
+        )
+      };
+      if libc::setgroups(1, &self.gid) != 0 {
+        return Err(failed("setgroups"));
+      }
+      if libc::setgid(self.gid) != 0 {
+        return Err(failed("setgid"));
+      }
+      if libc::setuid(self.uid) != 0 {
+        return Err(failed("setuid"));
+      }
+      let switched = libc::getuid() == self.uid
+        && libc::geteuid() == self.uid
+        && libc::getgid() == self.gid
+        && libc::getegid() == self.gid;
+      if !switched || (self.uid != 0 && libc::setuid(0) == 0) {
+        return Err(format!(
+          "Switching to user {} didn't take; refusing to run handlers",
+          self.user
+        ));
+      }
+    }
+    info!("Running as user {} (uid {}, gid {})", self.user, self.uid, self.gid);
+    Ok(())
+  }
+
+  #[cfg(not(unix))]
+  pub fn drop_privileges(&self) -> Result<(), String> {
+    Ok(())
+  }
+}
+
+/// Looks up a user's uid and primary gid by name.
+#[cfg(unix)]
+fn lookup_user(name: &str) -> Result<(libc::uid_t, libc::gid_t), String> {
+  let c_name =
+    std::ffi::CString::new(name).map_err(|_| format!("Bad CONFIG.run_as user {:?}", name))?;
+  let mut buffer = vec![0 as libc::c_char; 16 * 1024];
+  // SAFETY: an all-zero `passwd` is valid; `getpwnam_r` fills it with
+  // pointers into `buffer`, which outlives them.
+  let mut entry: libc::passwd = unsafe { std::mem::zeroed() };
+  let mut found = std::ptr::null_mut();
+  let result = unsafe {
+    libc::getpwnam_r(
+      c_name.as_ptr(),
+      &mut entry,
+      buffer.as_mut_ptr(),
+      buffer.len(),
+      &mut found,
+    )
+  };
+  if result != 0 || found.is_null() {
+    return Err(format!("CONFIG.run_as user {} doesn't exist", name));
+  }
+  Ok((entry.pw_uid, entry.pw_gid))
+}
+
+/// Looks up a group's gid by name.
+#[cfg(unix)]
+fn lookup_group(name: &str) -> Result<libc::gid_t, String> {
+  let c_name =
+    std::ffi::CString::new(name).map_err(|_| format!("Bad CONFIG.run_as group {:?}", name))?;
+  let mut buffer = vec![0 as libc::c_char; 16 * 1024];
+  // SAFETY: as in `lookup_user`.
+  let mut entry: libc::group = unsafe { std::mem::zeroed() };
+  let mut found = std::ptr::null_mut();
+  let result = unsafe {
+    libc::getgrnam_r(
+      c_name.as_ptr(),
+      &mut entry,
+      buffer.as_mut_ptr(),
+      buffer.len(),
+      &mut found,
+    )
+  };
+  if result != 0 || found.is_null() {
+    return Err(format!("CONFIG.run_as group {} doesn't exist", name));
+  }
+  Ok(entry.gr_gid)
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+  use super::*;
+
+  fn run_as(table: &str) -> Result<RunAs, String> {
+    let lua = Lua::new();
+    RunAs::from_lua(&lua.load(table).eval().unwrap())
+  }
+
+  #[test]
+  fn accounts_are_looked_up_by_name() {
+    let root = run_as(r#"{ user = "root" }"#).unwrap();
+    assert_eq!((root.uid, root.gid), (0, 0));
+    let in_group = run_as(r#"{ user = "root", group = "daemon" }"#).unwrap();
+    assert_eq!(
+      (in_group.uid, in_group.gid),
+      (0, lookup_group("daemon").unwrap())
+    );
+    assert_ne!(in_group.gid, 0);
+  }
+
+  #[test]
+  fn unknown_accounts_are_refused_at_load() {
+    for (table, expected) in [
+      ("{}", "CONFIG.run_as needs a user"),
+      (
+        r#"{ user = "no-such-user-fyre" }"#,
+        "CONFIG.run_as user no-such-user-fyre doesn't exist",
+      ),
+      (
+        r#"{ user = "root", group = "no-such-group-fyre" }"#,
+        "CONFIG.run_as group no-such-group-fyre doesn't exist",
+      ),
+      (r#"{ user = "root\0" }"#, "Bad CONFIG.run_as user"),
+      (
+        r#"{ user = { "root" } }"#,
+        "CONFIG.run_as must be { user = ..., group = ... }",
+      ),
+    ] {
+      let error = run_as(table).unwrap_err();
+      assert!(error.starts_with(expected), "{}: {}", table, error);
+    }
+  }
+
+  #[test]
+  fn running_as_the_account_already_changes_nothing() {
+    // SAFETY: these only read the process's ids.
+    let (uid, gid) = unsafe { (libc::getuid(), libc::getegid()) };
+    assert_eq!(unsafe { libc::geteuid() }, uid);
+    let current = RunAs {
+      user: "current".to_string(),
+      uid,
+      gid,
+    };
+    current.drop_privileges().unwrap();
+    assert_eq!(unsafe { (libc::geteuid(), libc::getegid()) }, (uid, gid));
+  }
+}
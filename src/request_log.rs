@@ -0,0 +1,329 @@
+//! # Request Log
+//!
+//! Every request the server answers gets one line in the log, in Apache's
+//! Combined Log Format with the time taken in milliseconds at the end:
+//!
+//! ```text
+//! 203.0.113.9 - - [16/Oct/2026:14:02:11 +0000] "GET /api/users?page=2 HTTP/1.1" 200 1534 "https://example.com/" "curl/8.5.0" 12
+//! ```
+//!
+//! The lines are written as they are, without a level, to stdout or the log
+//! file. Requests answered by a handler, a static file, or a health probe are
+//! logged, whatever the status: `404`s, `429`s, and `500`s from a failing
+//! pipeline included. So are requests turned away before a worker saw them, for
+//! a head that was too large (`414`, `431`), malformed (`400`), or too slow
+//! (`408`), or a connection over `CONFIG.limits.connections` (`503`); their
+//! request line wasn't read, so it is logged as `"-"`.
+//!
+//! `CONFIG.log.requests = "common"` leaves out the referer, user agent, and
+//! time, for tools that expect exactly the Common Log Format, and `false`
+//! turns the log off. `log.requests_exclude` lists paths not to log, such
+//! as a load balancer's health check; an entry ending in `*` matches every
+//! path starting with the rest.
+//!
+//! With `CONFIG.log.format = "json"` the fields go in a JSON line instead (see
+//! `logger`), whatever `CONFIG.log.requests` says, with the request line as
+//! `msg`: `remote_addr`, `method`, `path`, `status`, `bytes`, `referer`,
+//! `user_agent`, and `duration_ms`, each left out when it is unknown, and for a
+//! request a worker answered its `route`, `script`, and `request_id`.
+
+use chrono::{Local, TimeDelta};
+use serde_json::Value;
+use std::fmt::Write;
+use std::time::Duration;
+use tiny_http::{HTTPVersion, Header, Method};
+
+use mlua::prelude::*;
+
+use crate::logger::{self, Level};
+use crate::server::RemoteAddr;
+
+/// How each line is laid out, from `CONFIG.log.requests`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+  /// The Common Log Format with the referer, the user agent, and the time
+  /// taken in milliseconds.
+  Combined,
+  /// The Common Log Format alone.
+  Common,
+}
+
+/// The request log's settings.
+#[derive(Debug)]
+pub struct RequestLog {
+  format: Format,
+  /// Paths not logged; one ending in `*` is a prefix.
+  exclude: Vec<String>,
+}
+
+/// One answered request.
+pub struct Entry<'a> {
+  pub remote_addr: &'a RemoteAddr,
+  /// The method, target, and version; `None` for a request turned away
+  /// before its request line was read.
+  pub request: Option<(&'a Method, &'a str, &'a HTTPVersion)>,
+  pub headers: &'a [Header],
+  pub status: u16,
+  /// The length of the response body, if it was known up front.
+  pub bytes: Option<usize>,
+  /// From the request's first byte to its response being written.
+  pub elapsed: Duration,
+}
+
+impl RequestLog {
+  /// Reads `CONFIG.log.requests` and `CONFIG.log.requests_exclude`. Returns
+  /// `None` when the log is turned off.
+  ///
+  /// # Errors
+  ///
+  /// Returns an error message if either has the wrong type or value.
+  pub fn from_globals(globals: &LuaTable) -> Result<Option<RequestLog>, String> {
+    let bad = || "CONFIG.log.requests must be \"combined\", \"common\", or false".to_string();
+    let format = match globals.get::<LuaValue>("REQUEST_LOG") {
+      Ok(LuaValue::Nil) => Format::Combined,
+      Ok(LuaValue::Boolean(false)) => return Ok(None),
+      Ok(LuaValue::String(format)) => match &*format.to_string_lossy() {
+        "combined" => Format::Combined,
+        "common" => Format::Common,
+        _ => return Err(bad()),
+      },
+      _ => return Err(bad()),
+    };
+    let exclude = globals
+      .get::<Option<Vec<String>>>("REQUEST_LOG_EXCLUDE")
+      .map_err(|e| format!("CONFIG.log.requests_exclude must be a list of paths: {}", e))?
+      .unwrap_or_default();
+    if let Some(path) = exclude.iter().find(|path| !path.starts_with('/')) {
+      return Err(format!(
+        "CONFIG.log.requests_exclude entry {:?} must start with /",
+        path
+      ));
+    }
+    Ok(Some(RequestLog { format, exclude }))
+  }
+
+  /// Whether requests for `url` are left out of the log.
+  fn excluded(&self, url: &str) -> bool {
+    let path = url.split('?').next().unwrap_or_default();
+    self.exclude.iter().any(|pattern| match pattern.strip_suffix('*') {
+      Some(prefix) => path.starts_with(prefix),
+      None => path == pattern,
+    })
+  }
+
+  /// Logs `entry`, unless its path is excluded.
+  pub fn record(&self, entry: &Entry<'_>) {
+    if let Some((_, url, _)) = entry.request {
+      if self.excluded(url) {
+        return;
+      }
+    }
+    if logger::json() {
+      write_json(entry);
+    } else {
+      logger::write_line(&self.format(entry));
+    }
+  }
+
+  /// Formats `entry` as a line, without its line break.
+  fn format(&self, entry: &Entry<'_>) -> String {
+    let received = Local::now() - TimeDelta::from_std(entry.elapsed).unwrap_or_default();
+    let mut line = format!(
+      "{} - - [{}] \"",
+      host(entry.remote_addr),
+      received.format("%d/%b/%Y:%H:%M:%S %z")
+    );
+    match entry.request {
+      Some((method, url, version)) => {
+        let request = format!("{} {} HTTP/{}.{}", method, url, version.0, version.1);
+        escape(&mut line, &request);
+      }
+      None => line.push('-'),
+    }
+    let _ = write!(line, "\" {} ", entry.status);
+    match entry.bytes {
+      Some(bytes) => {
+        let _ = write!(line, "{}", bytes);
+      }
+      None => line.push('-'),
+    }
+    if self.format == Format::Combined {
+      for name in ["Referer", "User-Agent"] {
+        line.push_str(" \"");
+        match header(entry.headers, name) {
+          Some(value) => escape(&mut line, value),
+          None => line.push('-'),
+        }
+        line.push('"');
+      }
+      let _ = write!(line, " {}", entry.elapsed.as_millis());
+    }
+    line
+  }
+}
+
+/// Logs `entry` as a JSON line.
+fn write_json(entry: &Entry<'_>) {
+  let mut fields = vec![
+    ("remote_addr", Value::from(host(entry.remote_addr))),
+    ("status", Value::from(entry.status)),
+  ];
+  let message = match entry.request {
+    Some((method, url, version)) => {
+      fields.push(("method", Value::from(method.as_str())));
+      fields.push(("path", Value::from(url)));
+      format!("{} {} HTTP/{}.{}", method, url, version.0, version.1)
+    }
+    None => "-".to_string(),
+  };
+  if let Some(bytes) = entry.bytes {
+    fields.push(("bytes", Value::from(bytes)));
+  }
+  for (key, name) in [("referer", "Referer"), ("user_agent", "User-Agent")] {
+    if let Some(value) = header(entry.headers, name) {
+      fields.push((key, Value::from(value)));
+    }
+  }
+  fields.push((
+    "duration_ms",
+    Value::from(entry.elapsed.as_millis() as u64),
+  ));
+  logger::write_fields(Level::Info, format_args!("{}", message), &fields);
+}
+
+/// The client's address without its port, or a Unix socket client's
+/// credentials.
+fn host(remote_addr: &RemoteAddr) -> String {
+  match remote_addr {
+    RemoteAddr::Tcp(addr) => addr.ip().to_string(),
+    other => other.to_string(),
+  }
+}
+
+/// Returns the value of the first header called `name`.
+fn header<'a>(headers: &'a [Header], name: &'static str) -> Option<&'a str> {
+  headers
+    .iter()
+    .find(|header| header.field.equiv(name))
+    .map(|header| header.value.as_str())
+}
+
+/// Appends `value` for a quoted field, with quotes, backslashes, and bytes
+/// outside printable ASCII written as `\xHH`, so a client can't break the
+/// line or forge another.
+fn escape(line: &mut String, value: &str) {
+  for &byte in value.as_bytes() {
+    if byte == b'"' || byte == b'\\' || !(b' '..=b'~').contains(&byte) {
+      let _ = write!(line, "\\x{:02X}", byte);
+    } else {
+      line.push(char::from(byte));
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn log(config: &str) -> Result<Option<RequestLog>, String> {
+    let lua = Lua::new();
+    lua.load(config).exec().unwrap();
+    RequestLog::from_globals(&lua.globals())
+  }
+
+  fn header(name: &str, value: &str) -> Header {
+    Header::from_bytes(name.as_bytes(), value.as_bytes()).unwrap()
+  }
+
+  /// A line for a request for `url` with `headers`, without its timestamp.
+  fn line(format: &str, url: &str, headers: &[Header]) -> String {
+    let log = log(&format!("REQUEST_LOG = {:?}", format))
+      .unwrap()
+      .unwrap();
+    let remote_addr = RemoteAddr::Tcp("203.0.113.9:51234".parse().unwrap());
+    let entry = Entry {
+      remote_addr: &remote_addr,
+      request: Some((&Method::Get, url, &HTTPVersion(1, 1))),
+      headers,
+      status: 200,
+      bytes: Some(1534),
+      elapsed: Duration::from_millis(12),
+    };
+    let line = log.format(&entry);
+    let (start, rest) = line.split_once(" [").unwrap();
+    format!("{} [-]{}", start, &rest[rest.find(']').unwrap() + 1..])
+  }
+
+  #[test]
+  fn lines_follow_the_combined_and_common_formats() {
+    let headers = [
+      header("Referer", "https://example.com/"),
+      header("User-Agent", "curl/8.5.0"),
+    ];
+    assert_eq!(
+      line("combined", "/api/users?page=2", &headers),
+      r#"203.0.113.9 - - [-] "GET /api/users?page=2 HTTP/1.1" 200 1534 "https://example.com/" "curl/8.5.0" 12"#
+    );
+    assert_eq!(
+      line("common", "/api/users?page=2", &headers),
+      r#"203.0.113.9 - - [-] "GET /api/users?page=2 HTTP/1.1" 200 1534"#
+    );
+    assert_eq!(
+      line("combined", "/", &[]),
+      r#"203.0.113.9 - - [-] "GET / HTTP/1.1" 200 1534 "-" "-" 12"#
+    );
+  }
+
+  #[test]
+  fn clients_cant_break_the_line() {
+    let headers = [header("User-Agent", "evil\" 200 1 \"-\" \"-\" 0\\")];
+    assert_eq!(
+      line("combined", "/a\"b\u{e9}", &headers),
+      r#"203.0.113.9 - - [-] "GET /a\x22b\xC3\xA9 HTTP/1.1" 200 1534 "-" "evil\x22 200 1 \x22-\x22 \x22-\x22 0\x5C" 12"#
+    );
+  }
+
+  #[test]
+  fn requests_turned_away_early_log_a_dash() {
+    let log = log("").unwrap().unwrap();
+    let remote_addr = RemoteAddr::Tcp("[2001:db8::1]:443".parse().unwrap());
+    let entry = Entry {
+      remote_addr: &remote_addr,
+      request: None,
+      headers: &[],
+      status: 431,
+      bytes: None,
+      elapsed: Duration::ZERO,
+    };
+    let line = log.format(&entry);
+    assert!(line.starts_with("2001:db8::1 - - ["), "{}", line);
+    assert!(line.ends_with(r#"] "-" 431 - "-" "-" 0"#), "{}", line);
+  }
+
+  #[test]
+  fn excluded_paths_and_bad_settings() {
+    let filter = log(r#"REQUEST_LOG_EXCLUDE = { "/healthz", "/static/*" }"#)
+      .unwrap()
+      .unwrap();
+    for (url, excluded) in [
+      ("/healthz", true),
+      ("/healthz?probe=1", true),
+      ("/healthz/deep", false),
+      ("/static/app.css", true),
+      ("/static", false),
+      ("/", false),
+    ] {
+      assert_eq!(filter.excluded(url), excluded, "{}", url);
+    }
+
+    assert!(log("REQUEST_LOG = false").unwrap().is_none());
+    for config in [
+      r#"REQUEST_LOG = "apache""#,
+      "REQUEST_LOG = true",
+      r#"REQUEST_LOG_EXCLUDE = { "healthz" }"#,
+      r#"REQUEST_LOG_EXCLUDE = "/healthz""#,
+    ] {
+      assert!(log(config).is_err(), "{}", config);
+    }
+  }
+}
@@ -0,0 +1,343 @@
+//! # Request Metrics
+//!
+//! Counts every request a worker answers, and how long it took, by route
+//! and status class, for Prometheus to scrape from `/metrics`
+//! (`CONFIG.metrics.path`). The endpoint answers `GET` and `HEAD` with
+//! everything `fyre.metrics.render()` returns, which adds these to the
+//! metrics scripts register, the cache, connection, in-flight, and worker
+//! counts:
+//!
+//! - `fyre_http_requests_total{route, status}` and
+//!   `fyre_http_request_duration_seconds{route, status}`, a histogram from
+//!   a request's first byte to its response being written. `status` is the
+//!   class, `2xx` to `5xx`.
+//! - `fyre_lua_duration_seconds{route}`, a histogram of the time spent
+//!   loading a handler's script and running its pipeline.
+//! - `fyre_panics_total{route}`, the requests whose handling panicked (see
+//!   `panics`).
+//!
+//! `route` is the path a route was registered with, `<prefix>/*` for a
+//! static directory, or `unmatched`, never the raw path, so a client can't
+//! make up new series. Admin requests, probes, and requests turned away
+//! before a worker saw them aren't counted here, and neither is the
+//! endpoint itself unless `CONFIG.metrics.count_self` is `true`.
+//!
+//! A route registered at the same path takes precedence, so a script
+//! serving `fyre.metrics.render()` there keeps working. With
+//! `CONFIG.metrics.listener = "admin"` the endpoint is only served on
+//! `CONFIG.admin.addr`, without the admin token, by the admin thread, so it
+//! answers even while every worker is busy. `CONFIG.metrics.path = false`
+//! turns it off; the counts are kept either way.
+
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use mlua::prelude::*;
+use tiny_http::{Header, Method, Response};
+
+use crate::fyre::metrics::{format_labels, format_number, DEFAULT_BUCKETS};
+use crate::{fyre, locks, server, AppState, RouteTable};
+
+/// The endpoint's path when `CONFIG.metrics.path` is not set.
+pub const DEFAULT_PATH: &str = "/metrics";
+/// The `route` label of a request no route or static directory matched.
+const UNMATCHED: &str = "unmatched";
+/// The content type of the Prometheus text exposition format.
+const CONTENT_TYPE: &str = "text/plain; version=0.0.4; charset=utf-8";
+
+/// Where the endpoint is served, from `CONFIG.metrics`.
+#[derive(Debug, Clone)]
+pub struct Endpoint {
+  /// The endpoint's path; `None` turns it off.
+  pub path: Option<String>,
+  /// Whether it is only served on the admin address.
+  pub admin_only: bool,
+  /// Whether requests for it are counted like others.
+  pub count_self: bool,
+}
+
+impl Default for Endpoint {
+  fn default() -> Self {
+    Endpoint {
+      path: Some(DEFAULT_PATH.to_string()),
+      admin_only: false,
+      count_self: false,
+    }
+  }
+}
+
+impl Endpoint {
+  /// Reads `CONFIG.metrics.path`, `CONFIG.metrics.listener`, and
+  /// `CONFIG.metrics.count_self`.
+  ///
+  /// # Errors
+  ///
+  /// Returns an error message if one has the wrong type or value.
+  pub fn from_globals(globals: &LuaTable) -> Result<Endpoint, String> {
+    let path = match globals.get::<LuaValue>("METRICS_PATH") {
+      Ok(LuaValue::Nil) => Some(DEFAULT_PATH.to_string()),
+      Ok(LuaValue::Boolean(false)) => None,
+      Ok(LuaValue::String(path)) => {
+        let path = path.to_string_lossy();
+        if !path.starts_with('/') {
+          return Err(format!("CONFIG.metrics.path {:?} must start with /", path));
+        }
+        Some(path)
+      }
+      _ => return Err("CONFIG.metrics.path must be a path or false".to_string()),
+    };
+    let admin_only = match globals
+      .get::<Option<String>>("METRICS_LISTENER")
+      .map_err(|e| format!("CONFIG.metrics.listener must be \"main\" or \"admin\": {}", e))?
+      .as_deref()
+    {
+      None | Some("main") => false,
+      Some("admin") => true,
+      Some(other) => {
+        return Err(format!(
+          "CONFIG.metrics.listener must be \"main\" or \"admin\", got {:?}",
+          other
+        ))
+      }
+    };
+    let count_self = globals
+      .get::<Option<bool>>("METRICS_COUNT_SELF")
+      .map_err(|e| format!("CONFIG.metrics.count_self must be a boolean: {}", e))?
+      .unwrap_or(false);
+    Ok(Endpoint {
+      path,
+      admin_only,
+      count_self,
+    })
+  }
+}
+
+/// Observations bucketed by `DEFAULT_BUCKETS`.
+struct Histogram {
+  /// Observations per bucket (not cumulative).
+  buckets: Vec<AtomicU64>,
+  count: AtomicU64,
+  sum_micros: AtomicU64,
+}
+
+impl Histogram {
+  fn new() -> Self {
+    Histogram {
+      buckets: DEFAULT_BUCKETS.iter().map(|_| AtomicU64::new(0)).collect(),
+      count: AtomicU64::new(0),
+      sum_micros: AtomicU64::new(0),
+    }
+  }
+
+  fn observe(&self, elapsed: Duration) {
+    let seconds = elapsed.as_secs_f64();
+    if let Some(bucket) = DEFAULT_BUCKETS.iter().position(|b| seconds <= *b) {
+      self.buckets[bucket].fetch_add(1, Ordering::Relaxed);
+    }
+    self.count.fetch_add(1, Ordering::Relaxed);
+    self
+      .sum_micros
+      .fetch_add(elapsed.as_micros() as u64, Ordering::Relaxed);
+  }
+
+  /// Appends the `_bucket`, `_sum`, and `_count` lines.
+  fn render_into(&self, out: &mut String, name: &str, labels: &[(String, String)]) {
+    let labels = labels.to_vec();
+    let mut cumulative = 0;
+    for (bound, count) in DEFAULT_BUCKETS.iter().zip(&self.buckets) {
+      cumulative += count.load(Ordering::Relaxed);
+      let le = format_number(*bound);
+      let _ = writeln!(
+        out,
+        "{}_bucket{} {}",
+        name,
+        format_labels(&labels, Some(&le)),
+        cumulative
+      );
+    }
+    let count = self.count.load(Ordering::Relaxed);
+    let sum = self.sum_micros.load(Ordering::Relaxed) as f64 / 1e6;
+    let _ = writeln!(
+      out,
+      "{}_bucket{} {}",
+      name,
+      format_labels(&labels, Some("+Inf")),
+      count
+    );
+    let _ = writeln!(
+      out,
+      "{}_sum{} {}",
+      name,
+      format_labels(&labels, None),
+      format_number(sum)
+    );
+    let _ = writeln!(
+      out,
+      "{}_count{} {}",
+      name,
+      format_labels(&labels, None),
+      count
+    );
+  }
+}
+
+/// The request counts and durations, and where they are served.
+pub struct RequestMetrics {
+  endpoint: Endpoint,
+  /// Request durations by route label and status class.
+  requests: Mutex<BTreeMap<(String, &'static str), Arc<Histogram>>>,
+  /// Pipeline durations by route.
+  lua: Mutex<BTreeMap<String, Arc<Histogram>>>,
+  /// Panics by route.
+  panics: Mutex<BTreeMap<String, u64>>,
+}
+
+impl RequestMetrics {
+  pub fn new(endpoint: Endpoint) -> Self {
+    RequestMetrics {
+      endpoint,
+      requests: Mutex::new(BTreeMap::new()),
+      lua: Mutex::new(BTreeMap::new()),
+      panics: Mutex::new(BTreeMap::new()),
+    }
+  }
+
+  /// Whether `url` is the endpoint on the server's own addresses and no
+  /// route is registered there, so `handle_request` answers it with
+  /// `serve`.
+  pub fn serves(&self, url: &str, table: &RouteTable) -> bool {
+    !self.endpoint.admin_only && self.is_endpoint(url) && !table.handlers.contains_key(url)
+  }
+
+  /// Whether `url` is the endpoint on the admin address.
+  pub fn serves_admin(&self, url: &str) -> bool {
+    self.endpoint.admin_only && self.is_endpoint(url)
+  }
+
+  fn is_endpoint(&self, url: &str) -> bool {
+    let path = url.split('?').next().unwrap_or_default();
+    self.endpoint.path.as_deref() == Some(path)
+  }
+
+  /// The `route` label requests for `url` are counted under, or `None` for
+  /// one that isn't counted.
+  pub fn route_label(&self, url: &str, table: &RouteTable) -> Option<String> {
+    if table.handlers.contains_key(url) {
+      return Some(url.to_string());
+    }
+    if self.serves(url, table) {
+      return self.endpoint.count_self.then(|| url.to_string());
+    }
+    match table.find_mount(url) {
+      Some((mount, _)) => Some(format!("{}/*", mount.prefix)),
+      None => Some(UNMATCHED.to_string()),
+    }
+  }
+
+  /// Counts a request for `route` answered with `status` after `elapsed`.
+  pub fn record(&self, route: &str, status: u16, elapsed: Duration) {
+    let class = match status {
+      100..=199 => "1xx",
+      200..=299 => "2xx",
+      300..=399 => "3xx",
+      400..=499 => "4xx",
+      _ => "5xx",
+    };
+    let histogram = locks::lock(&self.requests, "request metrics")
+      .entry((route.to_string(), class))
+      .or_insert_with(|| Arc::new(Histogram::new()))
+      .clone();
+    histogram.observe(elapsed);
+  }
+
+  /// Counts the time a request for `route` spent in its Lua pipeline.
+  pub fn record_lua(&self, route: &str, elapsed: Duration) {
+    let histogram = locks::lock(&self.lua, "request metrics")
+      .entry(route.to_string())
+      .or_insert_with(|| Arc::new(Histogram::new()))
+      .clone();
+    histogram.observe(elapsed);
+  }
+
+  /// Counts a request for `route` whose handling panicked.
+  pub fn record_panic(&self, route: &str) {
+    *locks::lock(&self.panics, "request metrics")
+      .entry(route.to_string())
+      .or_default() += 1;
+  }
+
+  /// Appends the request metrics in the Prometheus text exposition format.
+  pub fn render_into(&self, out: &mut String) {
+    let requests: Vec<_> = locks::lock(&self.requests, "request metrics")
+      .iter()
+      .map(|((route, class), histogram)| {
+        let labels = vec![
+          ("route".to_string(), route.clone()),
+          ("status".to_string(), class.to_string()),
+        ];
+        (labels, histogram.clone())
+      })
+      .collect();
+    let _ = writeln!(out, "# TYPE fyre_http_requests_total counter");
+    for (labels, histogram) in &requests {
+      let count = histogram.count.load(Ordering::Relaxed);
+      let _ = writeln!(
+        out,
+        "fyre_http_requests_total{} {}",
+        format_labels(labels, None),
+        count
+      );
+    }
+    let _ = writeln!(out, "# TYPE fyre_http_request_duration_seconds histogram");
+    for (labels, histogram) in &requests {
+      histogram.render_into(out, "fyre_http_request_duration_seconds", labels);
+    }
+
+    let lua: Vec<_> = locks::lock(&self.lua, "request metrics")
+      .iter()
+      .map(|(route, histogram)| {
+        (
+          vec![("route".to_string(), route.clone())],
+          histogram.clone(),
+        )
+      })
+      .collect();
+    let _ = writeln!(out, "# TYPE fyre_lua_duration_seconds histogram");
+    for (labels, histogram) in &lua {
+      histogram.render_into(out, "fyre_lua_duration_seconds", labels);
+    }
+
+    let _ = writeln!(out, "# TYPE fyre_panics_total counter");
+    for (route, count) in locks::lock(&self.panics, "request metrics").iter() {
+      let labels = vec![("route".to_string(), route.clone())];
+      let _ = writeln!(
+        out,
+        "fyre_panics_total{} {}",
+        format_labels(&labels, None),
+        count
+      );
+    }
+  }
+}
+
+/// Answers a request for the endpoint with every metric.
+pub fn serve(request: server::Request, state: &AppState) {
+  if !matches!(request.method(), Method::Get | Method::Head) {
+    let mut response = Response::from_string("405 Method Not Allowed").with_status_code(405);
+    if let Ok(header) = Header::from_bytes("Allow", "GET, HEAD") {
+      response.add_header(header);
+    }
+    let _ = request.respond(response);
+    return;
+  }
+  let mut response = Response::from_string(fyre::metrics::render(state));
+  if let Ok(header) = Header::from_bytes("Content-Type", CONTENT_TYPE) {
+    response.add_header(header);
+  }
+  if let Err(e) = request.respond(response) {
+    error!("Error sending metrics: {}", e);
+  }
+}
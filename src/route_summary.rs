@@ -0,0 +1,148 @@
+//! # Route Summary
+//!
+//! The table of routes logged at startup (unless `--quiet` is given) and
+//! printed by `fyre routes`: each route's path, handler script, the
+//! script's size, whether it compiled, and the options it was added with,
+//! then the static directories and the totals:
+//!
+//! ```text
+//! PATH        SCRIPT                SIZE    STATUS  OPTIONS
+//! /admin      scripts/admin.lua     3.1 KB  ok      auth, csrf, max_concurrent=4
+//!   warning: requires a CSRF token, but CONFIG.session.secret isn't set
+//! /broken     scripts/broken.lua    97 B    FAILED  -
+//!   error: scripts/broken.lua:3: unexpected symbol near '='
+//! /hello      scripts/hello.lua     412 B   ok      -
+//! static  /assets/ -> /srv/site/public
+//! 3 route(s), 1 static dir(s), 2 scheduled task(s), loaded in 38 ms
+//! ```
+//!
+//! A script that failed to compile, and the configuration warnings about a
+//! route (added twice, unreachable, or needing a setting that isn't there),
+//! are shown under it; they are logged as they are found too, so
+//! `--quiet` doesn't hide them. Routes answer every method, and the
+//! `middleware` and `response_hook` a script returns aren't known until it
+//! runs, so neither is shown.
+
+use std::fs;
+use std::time::Duration;
+
+use crate::{statics, Route, RouteTable};
+
+/// The column headings.
+const HEADINGS: [&str; 5] = ["PATH", "SCRIPT", "SIZE", "STATUS", "OPTIONS"];
+
+/// Returns the summary of `table`, one line each, with the number of
+/// `schedules` and how long loading took.
+pub fn lines(table: &RouteTable, schedules: usize, elapsed: Duration) -> Vec<String> {
+  let mut handlers: Vec<(&String, &Route)> = table.handlers.iter().collect();
+  handlers.sort_by_key(|(path, _)| path.as_str());
+  let rows: Vec<[String; 5]> = handlers
+    .iter()
+    .map(|(path, route)| {
+      let size = fs::metadata(&route.script).map_or_else(|_| "-".to_string(), |m| size(m.len()));
+      let status = if route.compile_error.get().is_some() {
+        "FAILED"
+      } else {
+        "ok"
+      };
+      let options = options(route);
+      [
+        path.to_string(),
+        route.script.clone(),
+        size,
+        status.to_string(),
+        if options.is_empty() {
+          "-".to_string()
+        } else {
+          options.join(", ")
+        },
+      ]
+    })
+    .collect();
+
+  let mut widths = HEADINGS.map(str::len);
+  for row in &rows {
+    for (width, cell) in widths.iter_mut().zip(row) {
+      *width = (*width).max(cell.len());
+    }
+  }
+  let format_row = |cells: [&str; 5]| {
+    let padded: Vec<String> = cells
+      .iter()
+      .zip(widths)
+      .map(|(cell, width)| format!("{:width$}", cell, width = width))
+      .collect();
+    padded.join("  ").trim_end().to_string()
+  };
+
+  let mut lines = Vec::new();
+  if !rows.is_empty() {
+    lines.push(format_row(HEADINGS));
+  }
+  for (row, (_, route)) in rows.iter().zip(&handlers) {
+    lines.push(format_row(row.each_ref().map(String::as_str)));
+    if let Some(error) = route.compile_error.get() {
+      lines.push(format!("  error: {}", error));
+    }
+    for warning in &route.warnings {
+      lines.push(format!("  warning: {}", warning));
+    }
+  }
+  for mount in &table.mounts {
+    lines.push(format!("static  {}", statics::describe(mount)));
+  }
+  lines.push(format!(
+    "{} route(s), {} static dir(s), {} scheduled task(s), loaded in {} ms",
+    table.handlers.len(),
+    table.mounts.len(),
+    schedules,
+    elapsed.as_millis()
+  ));
+  lines
+}
+
+/// The options `route` was added with that change how it is answered.
+fn options(route: &Route) -> Vec<String> {
+  let mut options = Vec::new();
+  if route.auth.is_some() {
+    options.push("auth".to_string());
+  }
+  if !route.require_auth.is_empty() {
+    options.push(format!("require_auth={}", route.require_auth.join("|")));
+  }
+  if route.require_client_cert {
+    options.push("client_cert".to_string());
+  }
+  if route.csrf {
+    options.push("csrf".to_string());
+  }
+  if route.access.is_some() {
+    options.push("allow/deny".to_string());
+  }
+  if route.rate_limit.is_some() {
+    options.push("rate_limit".to_string());
+  }
+  if let Some(max) = route.limiter.as_ref().and_then(|limiter| limiter.max()) {
+    options.push(format!("max_concurrent={}", max));
+  }
+  if route.accept_types.is_some() {
+    options.push("accept_types".to_string());
+  }
+  if let Some(limit) = route.instruction_limit {
+    options.push(format!("instruction_limit={}", limit));
+  }
+  if route.secrets.is_some() {
+    options.push("secrets".to_string());
+  }
+  options
+}
+
+/// Formats a file size in bytes, `KB`, or `MB` (powers of 1024).
+fn size(bytes: u64) -> String {
+  const KB: f64 = 1024.0;
+  match bytes as f64 {
+    b if b < KB => format!("{} B", bytes),
+    b if b < KB * KB => format!("{:.1} KB", b / KB),
+    b => format!("{:.1} MB", b / (KB * KB)),
+  }
+}
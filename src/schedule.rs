@@ -0,0 +1,465 @@
+//! # Scheduled Tasks
+//!
+//! Periodic task scripts declared in `config.lua`:
+//!
+//! ```lua
+//! schedule.every("5m", "tasks/cleanup.lua")
+//! schedule.cron("0 3 * * *", "tasks/backup.lua")
+//! ```
+//!
+//! A single scheduler thread decides when each task is due and runs it on a
+//! thread of its own, in a fresh Lua state with the `fyre` helper modules
+//! but no `request` or `response`. The script must return a table with a
+//! `run` function. If a task is still running when it comes due again, that
+//! run is skipped.
+
+use chrono::{DateTime, Datelike, Local, Timelike};
+use mlua::prelude::*;
+use std::fmt;
+use std::fs;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::{fyre, AppState};
+
+/// The longest the scheduler sleeps before re-checking its tasks.
+const TICK: Duration = Duration::from_secs(1);
+
+/// When a task runs.
+#[derive(Debug)]
+pub enum Timing {
+  /// At a fixed interval, starting one interval after startup.
+  Every(Duration),
+  /// Whenever the local time matches a cron expression.
+  Cron(CronSchedule),
+}
+
+impl fmt::Display for Timing {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      Timing::Every(interval) => write!(f, "every {:?}", interval),
+      Timing::Cron(cron) => write!(f, "cron '{}'", cron.source),
+    }
+  }
+}
+
+/// A task declared with `schedule.every` or `schedule.cron`.
+#[derive(Debug)]
+pub struct Task {
+  /// The task script path, including the scripts directory. It doubles as
+  /// the task's name in logs.
+  pub script: String,
+  pub timing: Timing,
+  running: AtomicBool,
+}
+
+impl Task {
+  /// Creates a task.
+  pub fn new(script: String, timing: Timing) -> Self {
+    Task {
+      script,
+      timing,
+      running: AtomicBool::new(false),
+    }
+  }
+}
+
+/// Parses an interval such as `"30s"`, `"5m"`, `"2h"`, or `"1d"`. A bare
+/// number is taken as seconds.
+///
+/// # Errors
+///
+/// Returns an error message if the interval is malformed or zero.
+pub fn parse_interval(interval: &str) -> Result<Duration, String> {
+  let interval = interval.trim();
+  let split = interval
+    .find(|c: char| !c.is_ascii_digit())
+    .unwrap_or(interval.len());
+  let (number, unit) = interval.split_at(split);
+
+  let number: u64 = number
+    .parse()
+    .map_err(|_| format!("invalid interval '{}'", interval))?;
+  let seconds = match unit {
+    "" | "s" => number,
+    "m" => number * 60,
+    "h" => number * 60 * 60,
+    "d" => number * 24 * 60 * 60,
+    _ => {
+      return Err(format!(
+        "invalid interval '{}' (use s, m, h, or d)",
+        interval
+      ))
+    }
+  };
+  if seconds == 0 {
+    return Err(format!("interval '{}' must be positive", interval));
+  }
+  Ok(Duration::from_secs(seconds))
+}
+
+/// A parsed five-field cron expression: minute, hour, day of month, month,
+/// and day of week (0 or 7 is Sunday).
+#[derive(Debug)]
+pub struct CronSchedule {
+  source: String,
+  minutes: u64,
+  hours: u64,
+  days: u64,
+  months: u64,
+  weekdays: u64,
+  /// Whether the day-of-month and day-of-week fields were `*`. When both
+  /// are restricted, a day matching either one fires, as in cron.
+  any_day: bool,
+  any_weekday: bool,
+}
+
+impl CronSchedule {
+  /// Parses a cron expression. Each field accepts `*`, numbers, ranges
+  /// (`1-5`), lists (`1,15`), and steps (`*/10`, `0-30/5`).
+  ///
+  /// # Errors
+  ///
+  /// Returns an error message if the expression does not have five valid
+  /// fields.
+  pub fn parse(expr: &str) -> Result<Self, String> {
+    let fields: Vec<&str> = expr.split_whitespace().collect();
+    let &[minute, hour, day, month, weekday] = fields.as_slice() else {
+      return Err(format!(
+        "cron expression '{}' must have 5 fields (minute hour day month weekday)",
+        expr
+      ));
+    };
+
+    let field = |value: &str, min: u32, max: u32| {
+      parse_cron_field(value, min, max)
+        .map_err(|e| format!("invalid cron expression '{}': {}", expr, e))
+    };
+
+    let mut weekdays = field(weekday, 0, 7)?;
+    // Sunday may be written as 7.
+    if weekdays & (1 << 7) != 0 {
+      weekdays |= 1;
+    }
+
+    Ok(CronSchedule {
+      source: expr.to_string(),
+      minutes: field(minute, 0, 59)?,
+      hours: field(hour, 0, 23)?,
+      days: field(day, 1, 31)?,
+      months: field(month, 1, 12)?,
+      weekdays,
+      any_day: day == "*",
+      any_weekday: weekday == "*",
+    })
+  }
+
+  /// Returns `true` if the schedule fires during the minute containing
+  /// `time`.
+  fn matches(&self, time: &DateTime<Local>) -> bool {
+    let bit = |mask: u64, value: u32| mask & (1 << value) != 0;
+
+    let day = bit(self.days, time.day());
+    let weekday = bit(self.weekdays, time.weekday().num_days_from_sunday());
+    let day_matches = match (self.any_day, self.any_weekday) {
+      (true, true) => true,
+      (true, false) => weekday,
+      (false, true) => day,
+      (false, false) => day || weekday,
+    };
+
+    bit(self.minutes, time.minute())
+      && bit(self.hours, time.hour())
+      && bit(self.months, time.month())
+      && day_matches
+  }
+}
+
+/// Parses one cron field into a bit mask of the values it allows.
+fn parse_cron_field(field: &str, min: u32, max: u32) -> Result<u64, String> {
+  let number = |value: &str| -> Result<u32, String> {
+    let n: u32 = value
+      .parse()
+      .map_err(|_| format!("'{}' is not a number", value))?;
+    if n < min || n > max {
+      return Err(format!("{} is outside {}-{}", n, min, max));
+    }
+    Ok(n)
+  };
+
+  let mut mask = 0u64;
+  for part in field.split(',') {
+    let (range, step) = match part.split_once('/') {
+      Some((range, step)) => (range, number_step(step)?),
+      None => (part, 1),
+    };
+    let (start, end) = match range {
+      "*" => (min, max),
+      _ => match range.split_once('-') {
+        Some((start, end)) => (number(start)?, number(end)?),
+        // `5/15` means from 5 to the end in steps of 15.
+        None if step > 1 => (number(range)?, max),
+        None => {
+          let n = number(range)?;
+          (n, n)
+        }
+      },
+    };
+    if start > end {
+      return Err(format!("range {} is backwards", range));
+    }
+    for value in (start..=end).step_by(step) {
+      mask |= 1 << value;
+    }
+  }
+  Ok(mask)
+}
+
+fn number_step(step: &str) -> Result<usize, String> {
+  match step.parse() {
+    Ok(step) if step > 0 => Ok(step),
+    _ => Err(format!("invalid step '{}'", step)),
+  }
+}
+
+/// Starts the scheduler thread, if any tasks are declared.
+///
+/// # Errors
+///
+/// Returns an error message if the thread cannot be started.
+pub fn start(state: &Arc<AppState>, tasks: Vec<Task>) -> Result<(), String> {
+  if tasks.is_empty() {
+    return Ok(());
+  }
+
+  let state = state.clone();
+  let tasks: Vec<Arc<Task>> = tasks.into_iter().map(Arc::new).collect();
+  thread::Builder::new()
+    .name("scheduler".to_string())
+    .spawn(move || run_scheduler(&state, &tasks))
+    .map(|_| ())
+    .map_err(|e| format!("failed to start scheduler: {}", e))
+}
+
+fn run_scheduler(state: &Arc<AppState>, tasks: &[Arc<Task>]) {
+  let started = Instant::now();
+  let mut next_due: Vec<Option<Instant>> = tasks
+    .iter()
+    .map(|task| match task.timing {
+      Timing::Every(interval) => Some(started + interval),
+      Timing::Cron(_) => None,
+    })
+    .collect();
+  let mut last_minute = Local::now().timestamp().div_euclid(60);
+
+  loop {
+    let now = Instant::now();
+    let local = Local::now();
+    let minute = local.timestamp().div_euclid(60);
+    let new_minute = minute != last_minute;
+    last_minute = minute;
+
+    for (task, due) in tasks.iter().zip(next_due.iter_mut()) {
+      let fire = match (&task.timing, due.as_mut()) {
+        (Timing::Every(interval), Some(due)) if now >= *due => {
+          // Skip intervals missed while the machine was suspended.
+          while *due <= now {
+            *due += *interval;
+          }
+          true
+        }
+        (Timing::Cron(cron), _) => new_minute && cron.matches(&local),
+        _ => false,
+      };
+      if fire {
+        launch(state, task);
+      }
+    }
+
+    let sleep = next_due
+      .iter()
+      .flatten()
+      .map(|due| due.saturating_duration_since(Instant::now()))
+      .min()
+      .unwrap_or(TICK)
+      .min(TICK);
+    thread::sleep(sleep);
+  }
+}
+
+/// Runs `task` on its own thread unless its previous run is still going.
+fn launch(state: &Arc<AppState>, task: &Arc<Task>) {
+  if task.running.swap(true, Ordering::AcqRel) {
+    warn!(
+      "Scheduled task {} is still running, skipping this run",
+      task.script
+    );
+    return;
+  }
+
+  let state = state.clone();
+  let thread_task = task.clone();
+  let spawned = thread::Builder::new()
+    .name(format!("task-{}", task.script))
+    .spawn(move || {
+      if let Err(e) = run_task(&state, &thread_task.script) {
+        error!("Scheduled task {} failed: {}", thread_task.script, e);
+      }
+      thread_task.running.store(false, Ordering::Release);
+    });
+
+  if let Err(e) = spawned {
+    error!("Scheduled task {} could not start: {}", task.script, e);
+    task.running.store(false, Ordering::Release);
+  }
+}
+
+/// Loads a task script in a fresh Lua state and calls its `run` function.
+/// Also runs the `CONFIG.shutdown.script` script.
+pub fn run_task(state: &Arc<AppState>, script: &str) -> LuaResult<()> {
+  let lua = Lua::new();
+  fyre::random::seed_math_random(&lua)?;
+  fyre::register(&lua, state)?;
+
+  let code = fs::read_to_string(script)
+    .map_err(|e| LuaError::external(format!("Failed to read task script: {}", e)))?;
+  let module = lua
+    .load(&code)
+    .set_name(script)
+    .eval::<LuaTable>()
+    .map_err(|e| LuaError::external(format!("Task script failed to return a table: {}", e)))?;
+  let run = module
+    .get::<LuaFunction>("run")
+    .map_err(|_| LuaError::external("task script has no 'run' function"))?;
+  run.call::<()>(())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::testing::{self, Fixture};
+  use chrono::TimeZone;
+
+  fn at(year: i32, month: u32, day: u32, hour: u32, minute: u32) -> DateTime<Local> {
+    Local
+      .with_ymd_and_hms(year, month, day, hour, minute, 0)
+      .earliest()
+      .unwrap()
+  }
+
+  #[test]
+  fn intervals_take_a_unit() {
+    for (interval, seconds) in [
+      ("30", 30),
+      ("30s", 30),
+      ("5m", 300),
+      (" 2h ", 7200),
+      ("1d", 86400),
+    ] {
+      assert_eq!(
+        parse_interval(interval),
+        Ok(Duration::from_secs(seconds)),
+        "{}",
+        interval
+      );
+    }
+    for interval in ["", "0s", "5 m", "5w", "-1s", "1.5h", "m"] {
+      assert!(parse_interval(interval).is_err(), "{:?}", interval);
+    }
+  }
+
+  #[test]
+  fn cron_fields_take_lists_ranges_and_steps() {
+    // 2024-01-01 was a Monday.
+    let cron = CronSchedule::parse("*/15 9-17 * * 1-5").unwrap();
+    assert!(cron.matches(&at(2024, 1, 1, 9, 0)));
+    assert!(cron.matches(&at(2024, 1, 5, 17, 45)));
+    assert!(!cron.matches(&at(2024, 1, 1, 9, 10)));
+    assert!(!cron.matches(&at(2024, 1, 1, 18, 0)));
+    assert!(!cron.matches(&at(2024, 1, 6, 9, 0)));
+
+    let cron = CronSchedule::parse("0 3 1,15 6 *").unwrap();
+    assert!(cron.matches(&at(2024, 6, 15, 3, 0)));
+    assert!(!cron.matches(&at(2024, 6, 14, 3, 0)));
+    assert!(!cron.matches(&at(2024, 7, 15, 3, 0)));
+
+    let cron = CronSchedule::parse("5/20 * * * *").unwrap();
+    for (minute, expected) in [(5, true), (25, true), (45, true), (0, false), (20, false)] {
+      assert_eq!(
+        cron.matches(&at(2024, 1, 1, 12, minute)),
+        expected,
+        "{}",
+        minute
+      );
+    }
+  }
+
+  #[test]
+  fn restricted_day_fields_match_either_day() {
+    // The 13th, or any Friday, as in cron.
+    let cron = CronSchedule::parse("0 0 13 * 5").unwrap();
+    assert!(cron.matches(&at(2024, 2, 13, 0, 0)));
+    assert!(cron.matches(&at(2024, 2, 16, 0, 0)));
+    assert!(!cron.matches(&at(2024, 2, 14, 0, 0)));
+    // Sunday is 0 or 7.
+    let sunday = at(2024, 1, 7, 0, 0);
+    assert!(CronSchedule::parse("0 0 * * 7").unwrap().matches(&sunday));
+    assert!(CronSchedule::parse("0 0 * * 0").unwrap().matches(&sunday));
+  }
+
+  #[test]
+  fn bad_cron_expressions_are_refused() {
+    for expr in [
+      "* * * *",
+      "* * * * * *",
+      "60 * * * *",
+      "* 24 * * *",
+      "* * 0 * *",
+      "* * * 13 *",
+      "* * * * 8",
+      "*/0 * * * *",
+      "30-10 * * * *",
+      "a * * * *",
+    ] {
+      assert!(CronSchedule::parse(expr).is_err(), "{}", expr);
+    }
+  }
+
+  #[test]
+  fn tasks_run_on_their_interval() {
+    let fixture = Fixture::new(
+      r#"
+        schedule.every("1s", "tasks/tick.lua")
+        router.add("/ticks", "ticks.lua")
+      "#,
+      &[(
+        "ticks.lua",
+        r#"return { handler = function(request, response)
+          response.body = tostring(fyre.kv.get("ticks") or 0)
+        end }"#,
+      )],
+    );
+    std::fs::create_dir_all(fixture.path().join("scripts/tasks")).unwrap();
+    std::fs::write(
+      fixture.path().join("scripts/tasks/tick.lua"),
+      r#"return { run = function() fyre.kv.incr("ticks", 1) end }"#,
+    )
+    .unwrap();
+    let server = fixture
+      .builder()
+      .addr("127.0.0.1:0")
+      .workers(1)
+      .load()
+      .unwrap();
+    let addr = server.serve().unwrap().remove(0);
+    assert!(testing::get(&addr, "/ticks").ends_with("\r\n\r\n0"));
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while !testing::get(&addr, "/ticks").ends_with("\r\n\r\n2") {
+      assert!(Instant::now() < deadline, "the task didn't run twice");
+      thread::sleep(Duration::from_millis(50));
+    }
+    server.shutdown();
+  }
+}
@@ -0,0 +1,514 @@
+//! # Handler Bytecode Cache
+//!
+//! With `CONFIG.lua.bytecode_cache = true`, each handler script is compiled
+//! once and later requests load the compiled bytecode instead of parsing the
+//! source again. Entries are keyed by the SHA-256 of the script's source, which is
+//! read and hashed on every request, so an edited script is recompiled on
+//! its next request without a restart.
+//!
+//! The compiled scripts kept in memory are limited to
+//! `CONFIG.lua.cache_max_bytes` (64 MB by default); past that, the least
+//! recently used are dropped and compiled again when next requested. A request
+//! already running a script has its own copy of the bytecode, so evicting it is
+//! always safe. This is the only cache of scripts kept in memory: sources are
+//! read on every request, and there are no templates to cache. `fyre.cache`,
+//! which scripts fill themselves, is limited by `CONFIG.cache.max_entries`
+//! instead.
+//!
+//! Every handler script is also compiled once at startup by `check_all`, so
+//! a syntax error is reported before the server accepts requests, and with
+//! the cache enabled the first requests find their bytecode ready.
+//!
+//! With `CONFIG.lua.bytecode_cache_dir` also set, compiled scripts are written
+//! there and reused after a restart. Lua doesn't verify bytecode, and a crafted
+//! chunk can corrupt memory, so a cache file is only loaded when the source
+//! hash and the bytecode hash recorded in it both match. On Unix, files that
+//! other users can write are ignored as well. The directory should be as
+//! protected as the scripts themselves.
+
+use mlua::prelude::*;
+use mlua::ChunkMode;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+
+use crate::locks;
+
+/// The first bytes of a cache file, bumped when the layout changes.
+const FILE_MAGIC: &[u8] = b"FYREBC1\n";
+/// The length of a SHA-256 digest.
+const HASH_LEN: usize = 32;
+/// The bytecode kept in memory when `CONFIG.lua.cache_max_bytes` is not set.
+pub const DEFAULT_MAX_BYTES: usize = 64 * 1024 * 1024;
+
+/// A compiled script and the hash of the source it was compiled from.
+struct Entry {
+  source_hash: [u8; HASH_LEN],
+  bytecode: Arc<Vec<u8>>,
+  last_used: u64,
+}
+
+impl Entry {
+  /// The memory counted against the budget for an entry.
+  fn size(path: &str, bytecode: &[u8]) -> usize {
+    path.len() + bytecode.len()
+  }
+}
+
+#[derive(Default)]
+struct Entries {
+  /// Compiled scripts by path.
+  map: HashMap<String, Entry>,
+  /// The total size of the entries.
+  bytes: usize,
+  /// Incremented on every lookup, to order entries by last use.
+  clock: u64,
+}
+
+/// Counts reported by `fyre.metrics.render()`.
+pub struct Stats {
+  pub hits: u64,
+  pub misses: u64,
+  pub evictions: u64,
+  pub bytes: usize,
+}
+
+/// Loads handler scripts, from compiled bytecode when the cache is enabled.
+pub struct ScriptCache {
+  enabled: bool,
+  dir: Option<PathBuf>,
+  max_bytes: usize,
+  entries: Mutex<Entries>,
+  hits: AtomicU64,
+  misses: AtomicU64,
+  evictions: AtomicU64,
+}
+
+impl ScriptCache {
+  /// Creates a cache keeping up to `max_bytes` of bytecode in memory. When
+  /// `enabled` is false every load compiles the source; `dir` is only used
+  /// when `enabled` is true.
+  pub fn new(enabled: bool, dir: Option<String>, max_bytes: usize) -> Self {
+    ScriptCache {
+      enabled,
+      dir: dir.map(PathBuf::from),
+      max_bytes,
+      entries: Mutex::new(Entries::default()),
+      hits: AtomicU64::new(0),
+      misses: AtomicU64::new(0),
+      evictions: AtomicU64::new(0),
+    }
+  }
+
+  /// Returns the hit, miss, and eviction counts and the bytes cached.
+  pub fn stats(&self) -> Stats {
+    let bytes = locks::lock(&self.entries, "bytecode cache").bytes;
+    Stats {
+      hits: self.hits.load(Ordering::Relaxed),
+      misses: self.misses.load(Ordering::Relaxed),
+      evictions: self.evictions.load(Ordering::Relaxed),
+      bytes,
+    }
+  }
+
+  /// Drops the bytecode kept in memory, returning how many scripts it held.
+  /// Bytecode written to `CONFIG.lua.bytecode_cache_dir` is kept; it is checked
+  /// against the source before it is used.
+  pub fn clear(&self) -> usize {
+    let mut entries = locks::lock(&self.entries, "bytecode cache");
+    let scripts = entries.map.len();
+    entries.map.clear();
+    entries.bytes = 0;
+    scripts
+  }
+
+  /// Loads the script at `path`, whose contents are `source`, as a function
+  /// whose globals are `env`.
+  ///
+  /// # Errors
+  ///
+  /// This function will return a `LuaError` if the script doesn't compile.
+  pub fn load(
+    &self,
+    lua: &Lua,
+    path: &str,
+    source: &[u8],
+    env: LuaTable,
+  ) -> LuaResult<LuaFunction> {
+    if !self.enabled {
+      return lua
+        .load(source)
+        .set_name(path)
+        .set_mode(ChunkMode::Text)
+        .set_environment(env)
+        .into_function();
+    }
+
+    let source_hash: [u8; HASH_LEN] = Sha256::digest(source).into();
+    let bytecode = match self.cached(path, &source_hash) {
+      Some(bytecode) => {
+        self.hits.fetch_add(1, Ordering::Relaxed);
+        bytecode
+      }
+      None => {
+        self.misses.fetch_add(1, Ordering::Relaxed);
+        self.compile(lua, path, source, &source_hash)?
+      }
+    };
+    lua
+      .load(bytecode.as_slice())
+      .set_name(path)
+      .set_mode(ChunkMode::Binary)
+      .set_environment(env)
+      .into_function()
+  }
+
+  /// Compiles the scripts at `paths` without running them, spread over up
+  /// to `threads` threads, each with its own Lua state. With the cache
+  /// enabled the bytecode is kept, so the first requests don't compile.
+  ///
+  /// Returns the scripts that can't be read or don't compile, with the
+  /// error (which names the file and line), ordered by path.
+  pub fn check_all(&self, paths: &[String], threads: usize) -> Vec<(String, String)> {
+    let next = AtomicUsize::new(0);
+    let failures = Mutex::new(Vec::new());
+    std::thread::scope(|scope| {
+      for _ in 0..threads.clamp(1, paths.len().max(1)) {
+        scope.spawn(|| {
+          let lua = Lua::new();
+          while let Some(path) = paths.get(next.fetch_add(1, Ordering::Relaxed)) {
+            let checked = fs::read(path)
+              .map_err(|e| format!("{}: {}", path, e))
+              .and_then(|source| {
+                let env = lua.create_table().map_err(|e| e.to_string())?;
+                self
+                  .load(&lua, path, &source, env)
+                  .map_err(|e| e.to_string())
+              });
+            if let Err(e) = checked {
+              locks::lock(&failures, "script check").push((path.clone(), e));
+            }
+          }
+        });
+      }
+    });
+    let mut failures = failures.into_inner().unwrap_or_else(|e| e.into_inner());
+    failures.sort();
+    failures
+  }
+
+  /// Returns the in-memory bytecode for `path` if it was compiled from the
+  /// same source.
+  fn cached(&self, path: &str, source_hash: &[u8; HASH_LEN]) -> Option<Arc<Vec<u8>>> {
+    let mut entries = locks::lock(&self.entries, "bytecode cache");
+    entries.clock += 1;
+    let clock = entries.clock;
+    let entry = entries
+      .map
+      .get_mut(path)
+      .filter(|entry| entry.source_hash == *source_hash)?;
+    entry.last_used = clock;
+    Some(entry.bytecode.clone())
+  }
+
+  /// Keeps `bytecode` in memory, evicting the least recently used entries
+  /// to stay within the budget. Bytecode larger than the whole budget is not
+  /// kept.
+  fn insert(&self, path: &str, source_hash: &[u8; HASH_LEN], bytecode: &Arc<Vec<u8>>) {
+    let size = Entry::size(path, bytecode);
+    let mut entries = locks::lock(&self.entries, "bytecode cache");
+    if let Some(old) = entries.map.remove(path) {
+      entries.bytes -= Entry::size(path, &old.bytecode);
+    }
+    if size > self.max_bytes {
+      return;
+    }
+    while entries.bytes + size > self.max_bytes {
+      let Some(oldest) = entries
+        .map
+        .iter()
+        .min_by_key(|(_, entry)| entry.last_used)
+        .map(|(path, _)| path.clone())
+      else {
+        break;
+      };
+      if let Some(evicted) = entries.map.remove(&oldest) {
+        entries.bytes -= Entry::size(&oldest, &evicted.bytecode);
+        self.evictions.fetch_add(1, Ordering::Relaxed);
+      }
+    }
+    entries.clock += 1;
+    let last_used = entries.clock;
+    entries.bytes += size;
+    entries.map.insert(
+      path.to_string(),
+      Entry {
+        source_hash: *source_hash,
+        bytecode: bytecode.clone(),
+        last_used,
+      },
+    );
+  }
+
+  /// Gets the bytecode from the cache directory or by compiling `source`,
+  /// and keeps it in memory.
+  fn compile(
+    &self,
+    lua: &Lua,
+    path: &str,
+    source: &[u8],
+    source_hash: &[u8; HASH_LEN],
+  ) -> LuaResult<Arc<Vec<u8>>> {
+    let file = self
+      .dir
+      .as_ref()
+      .map(|dir| dir.join(format!("{}.luac", hex::encode(source_hash))));
+
+    // A file written by a build with a different Lua version fails to load
+    // and is replaced.
+    let from_disk = file
+      .as_deref()
+      .and_then(|file| read_file(file, source_hash))
+      .filter(|bytecode| {
+        lua
+          .load(bytecode.as_slice())
+          .set_mode(ChunkMode::Binary)
+          .into_function()
+          .is_ok()
+      });
+    let bytecode = match from_disk {
+      Some(bytecode) => bytecode,
+      None => {
+        let function = lua
+          .load(source)
+          .set_name(path)
+          .set_mode(ChunkMode::Text)
+          .into_function()?;
+        // Not stripped, so errors still report line numbers.
+        let bytecode = function.dump(false);
+        if let Some(file) = &file {
+          if let Err(e) = write_file(file, source_hash, &bytecode) {
+            warn!(
+              "Failed to write bytecode cache file {}: {}",
+              file.display(),
+              e
+            );
+          }
+        }
+        bytecode
+      }
+    };
+
+    let bytecode = Arc::new(bytecode);
+    self.insert(path, source_hash, &bytecode);
+    Ok(bytecode)
+  }
+}
+
+/// Reads a cache file, returning its bytecode only if the file is intact
+/// and was compiled from the source with `source_hash`.
+fn read_file(file: &Path, source_hash: &[u8; HASH_LEN]) -> Option<Vec<u8>> {
+  #[cfg(unix)]
+  {
+    use std::os::unix::fs::PermissionsExt;
+    let mode = fs::metadata(file).ok()?.permissions().mode();
+    if mode & 0o022 != 0 {
+      warn!(
+        "Ignoring bytecode cache file writable by other users: {}",
+        file.display()
+      );
+      return None;
+    }
+  }
+
+  let data = fs::read(file).ok()?;
+  let rest = data.strip_prefix(FILE_MAGIC)?;
+  if rest.len() < 2 * HASH_LEN {
+    return None;
+  }
+  let (stored_source_hash, rest) = rest.split_at(HASH_LEN);
+  let (stored_bytecode_hash, bytecode) = rest.split_at(HASH_LEN);
+  let bytecode_hash: [u8; HASH_LEN] = Sha256::digest(bytecode).into();
+  if stored_source_hash != source_hash || stored_bytecode_hash != bytecode_hash {
+    warn!(
+      "Ignoring bytecode cache file with a mismatched hash: {}",
+      file.display()
+    );
+    return None;
+  }
+  Some(bytecode.to_vec())
+}
+
+/// Writes a cache file through a temporary file, so a reader never sees a
+/// partial one.
+fn write_file(file: &Path, source_hash: &[u8; HASH_LEN], bytecode: &[u8]) -> io::Result<()> {
+  if let Some(dir) = file.parent() {
+    fs::create_dir_all(dir)?;
+  }
+  let bytecode_hash: [u8; HASH_LEN] = Sha256::digest(bytecode).into();
+  let mut data = Vec::with_capacity(FILE_MAGIC.len() + 2 * HASH_LEN + bytecode.len());
+  data.extend_from_slice(FILE_MAGIC);
+  data.extend_from_slice(source_hash);
+  data.extend_from_slice(&bytecode_hash);
+  data.extend_from_slice(bytecode);
+
+  // Workers can miss on the same script at once, so each write gets its own
+  // temporary file.
+  static NEXT_TMP: AtomicU64 = AtomicU64::new(0);
+  let tmp = file.with_extension(format!(
+    "tmp{}-{}",
+    std::process::id(),
+    NEXT_TMP.fetch_add(1, Ordering::Relaxed)
+  ));
+  fs::write(&tmp, &data)?;
+  #[cfg(unix)]
+  {
+    use std::os::unix::fs::PermissionsExt;
+    fs::set_permissions(&tmp, fs::Permissions::from_mode(0o600))?;
+  }
+  fs::rename(&tmp, file)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::testing::Fixture;
+
+  fn run(cache: &ScriptCache, lua: &Lua, path: &str, source: &str) -> LuaResult<i64> {
+    cache
+      .load(lua, path, source.as_bytes(), lua.create_table()?)?
+      .call(())
+  }
+
+  #[test]
+  fn scripts_are_compiled_once_per_source() {
+    let lua = Lua::new();
+    let cache = ScriptCache::new(true, None, DEFAULT_MAX_BYTES);
+    assert_eq!(run(&cache, &lua, "a.lua", "return 1").unwrap(), 1);
+    assert_eq!(run(&cache, &lua, "a.lua", "return 1").unwrap(), 1);
+    assert_eq!(run(&cache, &lua, "a.lua", "return 2").unwrap(), 2);
+    let stats = cache.stats();
+    assert_eq!((stats.hits, stats.misses), (1, 2));
+    assert!(stats.bytes > 0);
+
+    let error = run(&cache, &lua, "bad.lua", "return +").unwrap_err();
+    assert!(error.to_string().contains("\"bad.lua\"]:1:"), "{}", error);
+    assert_eq!(cache.clear(), 1);
+    assert_eq!(cache.stats().bytes, 0);
+
+    let off = ScriptCache::new(false, None, DEFAULT_MAX_BYTES);
+    assert_eq!(run(&off, &lua, "a.lua", "return 3").unwrap(), 3);
+    assert_eq!(off.stats().misses, 0);
+  }
+
+  #[test]
+  fn the_least_recently_used_are_evicted() {
+    let lua = Lua::new();
+    let size = |source: &str| {
+      let bytecode = lua
+        .load(source)
+        .set_name("a.lua")
+        .into_function()
+        .unwrap()
+        .dump(false);
+      Entry::size("a.lua", &bytecode)
+    };
+    // Room for two scripts of this size, but not three.
+    let cache = ScriptCache::new(true, None, size("return 1") * 5 / 2);
+    run(&cache, &lua, "a.lua", "return 1").unwrap();
+    run(&cache, &lua, "b.lua", "return 1").unwrap();
+    run(&cache, &lua, "a.lua", "return 1").unwrap();
+    run(&cache, &lua, "c.lua", "return 1").unwrap();
+    assert_eq!(cache.stats().evictions, 1);
+    run(&cache, &lua, "a.lua", "return 1").unwrap();
+    run(&cache, &lua, "b.lua", "return 1").unwrap();
+    let stats = cache.stats();
+    assert_eq!((stats.hits, stats.misses), (2, 4));
+
+    let tiny = ScriptCache::new(true, None, 8);
+    run(&tiny, &lua, "a.lua", "return 1").unwrap();
+    assert_eq!(tiny.stats().bytes, 0);
+  }
+
+  #[test]
+  fn cache_files_are_checked_before_they_are_loaded() {
+    let fixture = Fixture::new("", &[]);
+    let dir = fixture.path().join("bytecode");
+    let lua = Lua::new();
+    let source = "return 1";
+    let source_hash: [u8; HASH_LEN] = Sha256::digest(source).into();
+    let file = dir.join(format!("{}.luac", hex::encode(source_hash)));
+    let cache = || ScriptCache::new(true, Some(dir.display().to_string()), DEFAULT_MAX_BYTES);
+
+    assert_eq!(run(&cache(), &lua, "a.lua", source).unwrap(), 1);
+    let written = fs::read(&file).unwrap();
+    assert!(written.starts_with(FILE_MAGIC));
+    assert!(read_file(&file, &source_hash).is_some());
+    #[cfg(unix)]
+    {
+      use std::os::unix::fs::PermissionsExt;
+      assert_eq!(
+        fs::metadata(&file).unwrap().permissions().mode() & 0o777,
+        0o600
+      );
+    }
+
+    // Another script's bytecode under this source's hash is refused and
+    // replaced.
+    let other = lua.load("return 2").into_function().unwrap().dump(false);
+    let mut forged = written[..FILE_MAGIC.len() + 2 * HASH_LEN].to_vec();
+    forged.extend_from_slice(&other);
+    fs::write(&file, &forged).unwrap();
+    assert!(read_file(&file, &source_hash).is_none());
+    assert_eq!(run(&cache(), &lua, "a.lua", source).unwrap(), 1);
+    assert_eq!(fs::read(&file).unwrap(), written);
+
+    // So is a file the bytecode of another source was written to.
+    assert!(read_file(&file, &Sha256::digest("return 2").into()).is_none());
+
+    #[cfg(unix)]
+    {
+      use std::os::unix::fs::PermissionsExt;
+      fs::set_permissions(&file, fs::Permissions::from_mode(0o666)).unwrap();
+      assert!(read_file(&file, &source_hash).is_none());
+    }
+  }
+
+  #[test]
+  fn check_all_reports_each_broken_script() {
+    let fixture = Fixture::new(
+      "",
+      &[
+        ("ok.lua", "return {}"),
+        ("b.lua", "return {"),
+        ("a.lua", "x ="),
+      ],
+    );
+    let scripts = fixture.path().join("scripts");
+    let path = |name: &str| scripts.join(name).display().to_string();
+    let cache = ScriptCache::new(true, None, DEFAULT_MAX_BYTES);
+    let failures = cache.check_all(
+      &[
+        path("ok.lua"),
+        path("b.lua"),
+        path("missing.lua"),
+        path("a.lua"),
+      ],
+      2,
+    );
+    let failed: Vec<&str> = failures.iter().map(|(path, _)| path.as_str()).collect();
+    assert_eq!(failed, [path("a.lua"), path("b.lua"), path("missing.lua")]);
+    assert!(failures[0].1.contains("a.lua\"]:1:"), "{}", failures[0].1);
+    assert_eq!(
+      cache.stats().bytes,
+      locks::lock(&cache.entries, "test").bytes
+    );
+    assert!(cache
+      .cached(&path("ok.lua"), &Sha256::digest("return {}").into())
+      .is_some());
+  }
+}
@@ -0,0 +1,382 @@
+//! # Secrets
+//!
+//! Resolves named secrets so they never have to be written into
+//! `config.lua`. `fyre.secrets.get(name)` looks in, in order:
+//!
+//! 1. the environment variable `FYRE_SECRET_<NAME>`, with the name
+//!    uppercased and `-` and `.` turned into `_`;
+//! 2. the file `<name>` in the secrets directory, `FYRE_SECRETS_DIR` or
+//!    `/run/secrets` (where Docker and Kubernetes mount them), with
+//!    trailing newlines trimmed, unless other users can write to it;
+//! 3. the encrypted secrets file named by `FYRE_SECRETS_FILE`, unlocked
+//!    with the key in `FYRE_SECRETS_KEY`.
+//!
+//! Where to look is read from the environment rather than `CONFIG`, since
+//! `config.lua` itself reads secrets before its `CONFIG` table is applied.
+//! A value is cached once found, is never logged, and is replaced with
+//! `"[redacted]"` wherever it appears in `GET /admin/config`.
+//!
+//! The encrypted file is a JSON object of names to values, sealed with
+//! ChaCha20-Poly1305 under a 32-byte key given as 64 hex digits. `fyre
+//! secrets keygen` prints a new key, and `fyre secrets encrypt <file.json>
+//! <out>` seals a file with the key in `FYRE_SECRETS_KEY`.
+
+use base64::Engine;
+use chacha20poly1305::aead::{Aead, KeyInit};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use crate::locks;
+
+/// The secrets directory when `FYRE_SECRETS_DIR` is not set.
+pub const DEFAULT_DIR: &str = "/run/secrets";
+/// The first line of an encrypted secrets file.
+const FILE_HEADER: &str = "fyre-secrets v1";
+/// The length of the nonce stored ahead of the ciphertext.
+const NONCE_LEN: usize = 12;
+/// The longest secret name.
+const MAX_NAME_LEN: usize = 128;
+
+/// The `fyre secrets` command line.
+#[derive(Debug, clap::Args)]
+pub struct SecretsArgs {
+  #[command(subcommand)]
+  command: SecretsCommand,
+}
+
+#[derive(Debug, clap::Subcommand)]
+enum SecretsCommand {
+  /// Prints a new key for FYRE_SECRETS_KEY.
+  Keygen,
+  /// Seals a JSON object of secret names and values with the key in
+  /// FYRE_SECRETS_KEY, for FYRE_SECRETS_FILE.
+  Encrypt {
+    /// The JSON file to read.
+    input: PathBuf,
+    /// The encrypted file to write.
+    output: PathBuf,
+  },
+}
+
+/// Where secrets are looked up, and the ones found so far.
+#[derive(Default)]
+pub struct Secrets {
+  dir: Option<PathBuf>,
+  /// The decrypted contents of `FYRE_SECRETS_FILE`.
+  sealed: HashMap<String, String>,
+  cache: Mutex<HashMap<String, Arc<str>>>,
+}
+
+/// Shows where secrets come from, never their values.
+impl fmt::Debug for Secrets {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    f.debug_struct("Secrets")
+      .field("dir", &self.dir)
+      .field("sealed", &self.sealed.len())
+      .finish_non_exhaustive()
+  }
+}
+
+impl Secrets {
+  /// Reads `FYRE_SECRETS_DIR`, `FYRE_SECRETS_FILE`, and `FYRE_SECRETS_KEY`.
+  /// Relative paths are relative to `base`, the config file's directory.
+  ///
+  /// # Errors
+  ///
+  /// This function will return an error if `FYRE_SECRETS_FILE` is set and
+  /// can't be read, the key is missing or malformed, or the file doesn't
+  /// decrypt with it.
+  pub fn from_env(base: &Path) -> Result<Secrets, String> {
+    let dir = std::env::var_os("FYRE_SECRETS_DIR")
+      .map(|dir| base.join(dir))
+      .unwrap_or_else(|| PathBuf::from(DEFAULT_DIR));
+    let sealed = match std::env::var_os("FYRE_SECRETS_FILE") {
+      Some(file) => {
+        let file = base.join(file);
+        let key = key_from_env()?;
+        let contents = fs::read_to_string(&file)
+          .map_err(|e| format!("FYRE_SECRETS_FILE {}: {}", file.display(), e))?;
+        open(&contents, &key)
+          .map_err(|e| format!("FYRE_SECRETS_FILE {}: {}", file.display(), e))?
+      }
+      None => HashMap::new(),
+    };
+    Ok(Secrets {
+      dir: Some(dir),
+      sealed,
+      cache: Mutex::new(HashMap::new()),
+    })
+  }
+
+  /// Secrets read only from the files in `dir`.
+  #[cfg(test)]
+  pub(crate) fn in_dir(dir: &Path) -> Secrets {
+    Secrets {
+      dir: Some(dir.to_path_buf()),
+      ..Secrets::default()
+    }
+  }
+
+  /// Looks up `name`. Returns `None` if no source has it.
+  ///
+  /// # Errors
+  ///
+  /// This function will return an error if `name` is not 1 to 128 letters,
+  /// digits, `_`, `-`, or `.`, or starts with `.`.
+  pub fn get(&self, name: &str) -> Result<Option<Arc<str>>, String> {
+    check_name(name)?;
+    if let Some(value) = locks::lock(&self.cache, "secrets").get(name) {
+      return Ok(Some(value.clone()));
+    }
+
+    let var = format!("FYRE_SECRET_{}", name.to_ascii_uppercase().replace(['-', '.'], "_"));
+    let value = std::env::var(&var)
+      .ok()
+      .or_else(|| self.read_file(name))
+      .or_else(|| self.sealed.get(name).cloned());
+    let Some(value) = value else {
+      return Ok(None);
+    };
+    let value: Arc<str> = Arc::from(value);
+    locks::lock(&self.cache, "secrets").insert(name.to_string(), value.clone());
+    Ok(Some(value))
+  }
+
+  /// Every value looked up so far, for redacting them.
+  pub fn values(&self) -> Vec<Arc<str>> {
+    locks::lock(&self.cache, "secrets").values().cloned().collect()
+  }
+
+  fn read_file(&self, name: &str) -> Option<String> {
+    let path = self.dir.as_ref()?.join(name);
+    #[cfg(unix)]
+    {
+      use std::os::unix::fs::PermissionsExt;
+      let mode = fs::metadata(&path).ok()?.permissions().mode();
+      if mode & 0o022 != 0 {
+        warn!("Ignoring secret file writable by other users: {}", path.display());
+        return None;
+      }
+    }
+    match fs::read_to_string(&path) {
+      Ok(contents) => Some(contents.trim_end_matches(['\n', '\r']).to_string()),
+      Err(e) if e.kind() == io::ErrorKind::NotFound => None,
+      Err(e) => {
+        warn!("Failed to read secret {}: {}", path.display(), e);
+        None
+      }
+    }
+  }
+}
+
+/// Secret names are file names in the secrets directory, so anything that
+/// could leave it is refused.
+fn check_name(name: &str) -> Result<(), String> {
+  let valid = !name.is_empty()
+    && name.len() <= MAX_NAME_LEN
+    && !name.starts_with('.')
+    && name
+      .bytes()
+      .all(|b| b.is_ascii_alphanumeric() || b"_-.".contains(&b));
+  if valid {
+    Ok(())
+  } else {
+    Err(format!(
+      "'{}' is not a secret name (letters, digits, '_', '-', and '.', not starting with '.')",
+      name
+    ))
+  }
+}
+
+/// Reads the key from `FYRE_SECRETS_KEY`.
+fn key_from_env() -> Result<[u8; 32], String> {
+  let hex_key = std::env::var("FYRE_SECRETS_KEY")
+    .map_err(|_| "FYRE_SECRETS_KEY must be set to open FYRE_SECRETS_FILE".to_string())?;
+  hex::decode(hex_key.trim())
+    .ok()
+    .and_then(|key| <[u8; 32]>::try_from(key).ok())
+    .ok_or_else(|| "FYRE_SECRETS_KEY must be 64 hex digits".to_string())
+}
+
+/// Decrypts and parses an encrypted secrets file.
+fn open(contents: &str, key: &[u8; 32]) -> Result<HashMap<String, String>, String> {
+  let body = contents
+    .strip_prefix(FILE_HEADER)
+    .ok_or("not an encrypted secrets file")?;
+  let sealed = base64::engine::general_purpose::STANDARD
+    .decode(body.trim())
+    .map_err(|_| "the file is damaged")?;
+  if sealed.len() < NONCE_LEN {
+    return Err("the file is damaged".to_string());
+  }
+  let (nonce, ciphertext) = sealed.split_at(NONCE_LEN);
+  let plaintext = ChaCha20Poly1305::new(Key::from_slice(key))
+    .decrypt(Nonce::from_slice(nonce), ciphertext)
+    .map_err(|_| "the file doesn't decrypt with FYRE_SECRETS_KEY")?;
+  serde_json::from_slice(&plaintext)
+    .map_err(|_| "the file doesn't hold a JSON object of strings".to_string())
+}
+
+/// Encrypts a JSON object of secrets into the file format `open` reads.
+fn seal(json: &[u8], key: &[u8; 32]) -> Result<String, String> {
+  let secrets: HashMap<String, String> = serde_json::from_slice(json)
+    .map_err(|e| format!("must be a JSON object of strings: {}", e))?;
+  for name in secrets.keys() {
+    check_name(name)?;
+  }
+  let plaintext = serde_json::to_vec(&secrets).map_err(|e| e.to_string())?;
+  let nonce: [u8; NONCE_LEN] = rand::random();
+  let ciphertext = ChaCha20Poly1305::new(Key::from_slice(key))
+    .encrypt(Nonce::from_slice(&nonce), plaintext.as_slice())
+    .map_err(|_| "encryption failed".to_string())?;
+  let sealed = [nonce.as_slice(), &ciphertext].concat();
+  Ok(format!(
+    "{}\n{}\n",
+    FILE_HEADER,
+    base64::engine::general_purpose::STANDARD.encode(sealed)
+  ))
+}
+
+/// Runs `fyre secrets`.
+///
+/// # Errors
+///
+/// This function will return an error if the key is missing, or the input
+/// can't be read or isn't a JSON object of strings, or the output can't be
+/// written.
+pub fn run(args: SecretsArgs) -> Result<(), Box<dyn std::error::Error>> {
+  match args.command {
+    SecretsCommand::Keygen => {
+      println!("{}", hex::encode(rand::random::<[u8; 32]>()));
+    }
+    SecretsCommand::Encrypt { input, output } => {
+      let key = key_from_env()?;
+      let json =
+        fs::read(&input).map_err(|e| format!("Failed to read {}: {}", input.display(), e))?;
+      let sealed = seal(&json, &key).map_err(|e| format!("{}: {}", input.display(), e))?;
+      fs::write(&output, sealed)
+        .map_err(|e| format!("Failed to write {}: {}", output.display(), e))?;
+      info!("Wrote {}", output.display());
+    }
+  }
+  Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::testing::Fixture;
+
+  const KEY: [u8; 32] = [7; 32];
+
+  #[test]
+  fn files_are_read_with_trailing_newlines_trimmed() {
+    let fixture = Fixture::new("", &[]);
+    fs::write(fixture.path().join("db.password"), "hunter2\r\n\n").unwrap();
+    let secrets = Secrets::in_dir(fixture.path());
+    assert_eq!(
+      secrets.get("db.password").unwrap().as_deref(),
+      Some("hunter2")
+    );
+    assert_eq!(secrets.get("missing").unwrap(), None);
+    // Found values are cached, and listed for redaction.
+    fs::remove_file(fixture.path().join("db.password")).unwrap();
+    assert_eq!(
+      secrets.get("db.password").unwrap().as_deref(),
+      Some("hunter2")
+    );
+    assert_eq!(secrets.values(), vec![Arc::from("hunter2")]);
+  }
+
+  #[cfg(unix)]
+  #[test]
+  fn files_other_users_can_write_are_ignored() {
+    use std::os::unix::fs::PermissionsExt;
+    let fixture = Fixture::new("", &[]);
+    let secrets = Secrets::in_dir(fixture.path());
+    for (name, mode) in [("group", 0o620), ("world", 0o602)] {
+      let path = fixture.path().join(name);
+      fs::write(&path, "planted").unwrap();
+      fs::set_permissions(&path, fs::Permissions::from_mode(mode)).unwrap();
+      assert_eq!(secrets.get(name).unwrap(), None, "{:o}", mode);
+    }
+    let path = fixture.path().join("mounted");
+    fs::write(&path, "value").unwrap();
+    fs::set_permissions(&path, fs::Permissions::from_mode(0o644)).unwrap();
+    assert_eq!(secrets.get("mounted").unwrap().as_deref(), Some("value"));
+  }
+
+  #[test]
+  fn names_that_could_leave_the_directory_are_refused() {
+    let secrets = Secrets::default();
+    let long = "a".repeat(MAX_NAME_LEN + 1);
+    for name in [
+      "",
+      "../etc/passwd",
+      "a/b",
+      ".hidden",
+      "..",
+      "a b",
+      long.as_str(),
+    ] {
+      assert!(secrets.get(name).is_err(), "{:?}", name);
+    }
+    assert_eq!(secrets.get(&"a".repeat(MAX_NAME_LEN)).unwrap(), None);
+  }
+
+  #[test]
+  fn sealed_files_open_with_their_key() {
+    let sealed = seal(br#"{"stripe_key": "sk_test", "api.token": "t0k"}"#, &KEY).unwrap();
+    assert!(sealed.starts_with("fyre-secrets v1\n"));
+    let secrets = open(&sealed, &KEY).unwrap();
+    assert_eq!(secrets.len(), 2);
+    assert_eq!(secrets["stripe_key"], "sk_test");
+    // A fresh nonce each time.
+    assert_ne!(seal(br#"{"a": "b"}"#, &KEY), seal(br#"{"a": "b"}"#, &KEY));
+
+    let secrets = Secrets {
+      sealed: open(&sealed, &KEY).unwrap(),
+      ..Secrets::default()
+    };
+    assert_eq!(secrets.get("api.token").unwrap().as_deref(), Some("t0k"));
+  }
+
+  #[test]
+  fn damaged_files_and_wrong_keys_are_refused() {
+    let sealed = seal(br#"{"stripe_key": "sk_test"}"#, &KEY).unwrap();
+    assert_eq!(
+      open(&sealed, &[8; 32]).unwrap_err(),
+      "the file doesn't decrypt with FYRE_SECRETS_KEY"
+    );
+    let body = sealed.lines().nth(1).unwrap();
+    let mut bytes = base64::engine::general_purpose::STANDARD
+      .decode(body)
+      .unwrap();
+    *bytes.last_mut().unwrap() ^= 1;
+    let flipped = format!(
+      "{}\n{}\n",
+      FILE_HEADER,
+      base64::engine::general_purpose::STANDARD.encode(bytes)
+    );
+    assert_eq!(
+      open(&flipped, &KEY).unwrap_err(),
+      "the file doesn't decrypt with FYRE_SECRETS_KEY"
+    );
+    assert_eq!(
+      open("fyre-secrets v1\nAAAA\n", &KEY).unwrap_err(),
+      "the file is damaged"
+    );
+    assert_eq!(
+      open(r#"{"a": "b"}"#, &KEY).unwrap_err(),
+      "not an encrypted secrets file"
+    );
+
+    assert!(seal(br#"["not", "an", "object"]"#, &KEY).is_err());
+    assert!(seal(br#"{"../escape": "x"}"#, &KEY).is_err());
+  }
+}
@@ -0,0 +1,272 @@
+//! # Security Headers
+//!
+//! Adds the usual hardening headers to every handler response, so scripts
+//! don't each need a `response_hook` for them. With
+//! `CONFIG.security_headers` set, a response gets:
+//!
+//! - `X-Content-Type-Options: nosniff` (`content_type_options`, on by
+//!   default);
+//! - `X-Frame-Options` (`frame_options`, `"DENY"` by default, or
+//!   `"SAMEORIGIN"`);
+//! - `Referrer-Policy` (`referrer_policy`, by default
+//!   `"strict-origin-when-cross-origin"`);
+//! - `Content-Security-Policy` (`csp`, off unless set);
+//! - `Strict-Transport-Security` (`hsts = { max_age, include_subdomains,
+//!   preload }`, off unless set), only over HTTPS, since browsers ignore it
+//!   over plain HTTP.
+//!
+//! Setting any of them to `false` leaves that header out. A header the
+//! script set itself is left alone.
+
+use mlua::prelude::*;
+
+/// The `Strict-Transport-Security` max-age when `hsts` doesn't set one: a
+/// year.
+pub const DEFAULT_HSTS_MAX_AGE: u64 = 31_536_000;
+
+/// The headers to add, each already formatted.
+#[derive(Debug, Clone, Default)]
+pub struct SecurityHeaders {
+  content_type_options: bool,
+  frame_options: Option<String>,
+  referrer_policy: Option<String>,
+  csp: Option<String>,
+  hsts: Option<String>,
+}
+
+impl SecurityHeaders {
+  /// Reads the `CONFIG.security_headers` table.
+  ///
+  /// # Errors
+  ///
+  /// This function will return an error if a field has the wrong type,
+  /// `frame_options` is not `"DENY"` or `"SAMEORIGIN"`, `hsts.max_age` is
+  /// not a whole number, or a value is not printable ASCII.
+  pub fn from_lua(table: &LuaTable) -> Result<SecurityHeaders, String> {
+    let field = |name: &str, default: Option<&str>| -> Result<Option<String>, String> {
+      let value = match table.get::<LuaValue>(name).map_err(|e| e.to_string())? {
+        LuaValue::Nil => default.map(str::to_string),
+        LuaValue::Boolean(false) => None,
+        LuaValue::String(value) => Some(value.to_string_lossy()),
+        other => {
+          return Err(format!(
+            "security_headers.{} must be a string or false, got {}",
+            name,
+            other.type_name()
+          ))
+        }
+      };
+      match value {
+        Some(value) if !value.bytes().all(|b| (b' '..=b'~').contains(&b)) => Err(format!(
+          "security_headers.{} must be printable ASCII: {:?}",
+          name, value
+        )),
+        value => Ok(value),
+      }
+    };
+
+    let frame_options = field("frame_options", Some("DENY"))?;
+    if let Some(value) = &frame_options {
+      if !value.eq_ignore_ascii_case("DENY") && !value.eq_ignore_ascii_case("SAMEORIGIN") {
+        return Err(format!(
+          "security_headers.frame_options must be \"DENY\", \"SAMEORIGIN\", or false, got {:?}",
+          value
+        ));
+      }
+    }
+
+    // Matched rather than read as a `bool`, which any value converts to.
+    let content_type_options = match table
+      .get::<LuaValue>("content_type_options")
+      .map_err(|e| e.to_string())?
+    {
+      LuaValue::Nil => true,
+      LuaValue::Boolean(value) => value,
+      other => {
+        return Err(format!(
+          "security_headers.content_type_options must be a boolean, got {}",
+          other.type_name()
+        ))
+      }
+    };
+
+    let hsts = match table.get::<LuaValue>("hsts").map_err(|e| e.to_string())? {
+      LuaValue::Nil | LuaValue::Boolean(false) => None,
+      LuaValue::Boolean(true) => Some(format!("max-age={}", DEFAULT_HSTS_MAX_AGE)),
+      LuaValue::Table(hsts) => {
+        let bad = |e: LuaError| format!("security_headers.hsts: {}", e);
+        // Read as a value, since a `u64` would take 1.5 as 1.
+        let max_age = match hsts.get::<LuaValue>("max_age").map_err(bad)? {
+          LuaValue::Nil => DEFAULT_HSTS_MAX_AGE,
+          LuaValue::Integer(n) if n >= 0 => n as u64,
+          LuaValue::Number(n) if n >= 0.0 && n.fract() == 0.0 => n as u64,
+          other => {
+            return Err(format!(
+              "security_headers.hsts.max_age must be a whole number, got {}",
+              other.type_name()
+            ))
+          }
+        };
+        let mut value = format!("max-age={}", max_age);
+        if hsts
+          .get::<Option<bool>>("include_subdomains")
+          .map_err(bad)?
+          == Some(true)
+        {
+          value.push_str("; includeSubDomains");
+        }
+        if hsts.get::<Option<bool>>("preload").map_err(bad)? == Some(true) {
+          value.push_str("; preload");
+        }
+        Some(value)
+      }
+      other => {
+        return Err(format!(
+          "security_headers.hsts must be a table, true, or false, got {}",
+          other.type_name()
+        ))
+      }
+    };
+
+    Ok(SecurityHeaders {
+      content_type_options,
+      frame_options,
+      referrer_policy: field("referrer_policy", Some("strict-origin-when-cross-origin"))?,
+      csp: field("csp", None)?,
+      hsts,
+    })
+  }
+
+  /// The headers for a response, leaving out HSTS unless `https`.
+  pub fn headers(&self, https: bool) -> Vec<(&'static str, &str)> {
+    let mut headers = Vec::new();
+    if self.content_type_options {
+      headers.push(("X-Content-Type-Options", "nosniff"));
+    }
+    let optional = [
+      ("X-Frame-Options", &self.frame_options),
+      ("Referrer-Policy", &self.referrer_policy),
+      ("Content-Security-Policy", &self.csp),
+    ];
+    for (name, value) in optional {
+      if let Some(value) = value {
+        headers.push((name, value.as_str()));
+      }
+    }
+    if let Some(hsts) = self.hsts.as_deref().filter(|_| https) {
+      headers.push(("Strict-Transport-Security", hsts));
+    }
+    headers
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::testing::{self, Fixture};
+
+  fn parse(config: &str) -> Result<SecurityHeaders, String> {
+    let lua = Lua::new();
+    SecurityHeaders::from_lua(&lua.load(config).eval::<LuaTable>().unwrap())
+  }
+
+  #[test]
+  fn an_empty_table_adds_the_defaults() {
+    let headers = parse("{}").unwrap();
+    let expected = vec![
+      ("X-Content-Type-Options", "nosniff"),
+      ("X-Frame-Options", "DENY"),
+      ("Referrer-Policy", "strict-origin-when-cross-origin"),
+    ];
+    assert_eq!(headers.headers(false), expected);
+    assert_eq!(headers.headers(true), expected);
+  }
+
+  #[test]
+  fn headers_set_to_false_are_left_out() {
+    let headers =
+      parse("{ content_type_options = false, frame_options = false, referrer_policy = false }")
+        .unwrap();
+    assert!(headers.headers(true).is_empty());
+  }
+
+  #[test]
+  fn hsts_is_only_sent_over_https() {
+    let hsts = |config: &str, https: bool| {
+      let headers = parse(config).unwrap();
+      let value = headers
+        .headers(https)
+        .into_iter()
+        .find_map(|(name, value)| (name == "Strict-Transport-Security").then(|| value.to_string()));
+      value
+    };
+    let config = "{ hsts = { max_age = 60, include_subdomains = true, preload = true } }";
+    assert_eq!(
+      hsts(config, true).as_deref(),
+      Some("max-age=60; includeSubDomains; preload")
+    );
+    assert_eq!(hsts(config, false), None);
+    assert_eq!(
+      hsts("{ hsts = true }", true).as_deref(),
+      Some("max-age=31536000")
+    );
+    assert_eq!(hsts("{}", true), None);
+  }
+
+  #[test]
+  fn bad_values_are_refused() {
+    for config in [
+      r#"{ frame_options = "ALLOW-FROM x" }"#,
+      r#"{ csp = 1 }"#,
+      r#"{ csp = "default-src 'self'\r\nSet-Cookie: a=1" }"#,
+      r#"{ hsts = "yes" }"#,
+      r#"{ hsts = { max_age = 1.5 } }"#,
+      r#"{ hsts = { max_age = -1 } }"#,
+      r#"{ content_type_options = "no" }"#,
+    ] {
+      assert!(parse(config).is_err(), "{}", config);
+    }
+  }
+
+  #[test]
+  fn handler_responses_get_the_headers_the_script_didnt_set() {
+    let fixture = Fixture::new(
+      r#"
+        CONFIG = { security_headers = { csp = "default-src 'self'" } }
+        router.add("/page", "page.lua")
+      "#,
+      &[(
+        "page.lua",
+        r#"return {
+          handler = function(request, response)
+            response.headers["X-Frame-Options"] = "SAMEORIGIN"
+            response.body = "page"
+          end,
+        }"#,
+      )],
+    );
+    let server = fixture
+      .builder()
+      .addr("127.0.0.1:0")
+      .workers(1)
+      .load()
+      .unwrap();
+    let addr = server.serve().unwrap().remove(0);
+
+    let response = testing::get(&addr, "/page");
+    let (head, body) = response.split_once("\r\n\r\n").unwrap();
+    assert_eq!(body, "page");
+    let head = head.to_ascii_lowercase();
+    for line in [
+      "x-content-type-options: nosniff",
+      "x-frame-options: sameorigin",
+      "referrer-policy: strict-origin-when-cross-origin",
+      "content-security-policy: default-src 'self'",
+    ] {
+      assert_eq!(head.matches(line).count(), 1, "{}: {}", line, head);
+    }
+    assert_eq!(head.matches("x-frame-options").count(), 1, "{}", head);
+    assert!(!head.contains("strict-transport-security"), "{}", head);
+    server.shutdown();
+  }
+}
@@ -0,0 +1,682 @@
+//! # Async Connection Handling
+//!
+//! With the `async` feature, connections are accepted and read by hyper on
+//! a tokio runtime, so thousands of idle keep-alive or slow clients cost a
+//! task each instead of a thread. Each request is turned into the same
+//! `Request` the thread-per-connection code produces and queued for the
+//! worker threads, which run the Lua pipeline exactly as before:
+//!
+//! - The body is forwarded from hyper in chunks and read by the worker
+//!   through a channel, so large bodies still spill to disk as they arrive.
+//! - The worker's `Response` is read into memory and handed back to the
+//!   connection's task, so responses (static files included) are buffered
+//!   rather than streamed.
+//! - `CONFIG.limits.keep_alive_timeout_ms`, or
+//!   `CONFIG.timeouts.header_deadline_ms` if that is shorter, bounds the wait
+//!   for a request's headers, in place of `CONFIG.timeouts.header_read_ms`, and
+//!   those timeouts aren't counted. `CONFIG.timeouts.body_read_ms` bounds the
+//!   wait for each chunk of the body. `CONFIG.timeouts.write_ms` doesn't apply,
+//!   since hyper has no write timeout. `CONFIG.limits.connections` and
+//!   `CONFIG.limits.requests_per_connection` apply as usual.
+//! - The request head limits are checked once hyper has parsed the head, so
+//!   hyper buffers up to `CONFIG.limits.header_total_bytes` plus
+//!   `CONFIG.limits.url_bytes` (at least 8 KB) first. A head longer than that,
+//!   or with more than `CONFIG.limits.headers` headers, is answered with `431`
+//!   by hyper itself and isn't counted in `fyre_requests_oversized_total`.
+//! - `CONFIG.http.keep_alive`, `CONFIG.http.version_compat`, and a response's
+//!   own `Connection` header decide whether to send `Connection: close`, as
+//!   they do without the feature; hyper sends HTTP/1.0 responses with a
+//!   `Content-Length` itself.
+//! - A Unix socket listener is served the same way as a TCP one.
+//! - With `CONFIG.tls`, tokio-rustls does the handshake with the configuration
+//!   in place when the connection is accepted, within the same bound as the
+//!   wait for a request's headers. A connection turned away is closed without
+//!   an answer, as it is without the feature.
+
+use super::{
+  body_length, status_message, Body, ConnectionStats, Limits, Oversized, Phase, Queue,
+  RemoteAddr, Request, Responder, ACCEPT_RETRY_DELAY, REJECT_WRITE_TIMEOUT,
+};
+use crate::net::Listener;
+use crate::tls::{ClientCert, Negotiated, ServerTls};
+use http_body_util::{BodyExt, Full};
+use hyper::body::{Bytes, Incoming};
+use hyper::server::conn::http1;
+use hyper::service::service_fn;
+use hyper_util::rt::{TokioIo, TokioTimer};
+use std::convert::Infallible;
+use std::io::{self, Read};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tiny_http::{HTTPVersion, Header, Method, Response, StatusCode};
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
+use tokio::sync::{mpsc, oneshot};
+
+/// The body chunks buffered between hyper and the worker reading them.
+const BODY_CHANNEL_CHUNKS: usize = 8;
+
+/// Response headers hyper sets itself from the body and connection state.
+const HOP_HEADERS: [&str; 3] = ["Connection", "Content-Length", "Transfer-Encoding"];
+
+/// What a TLS connection's handshake settled, for each of its requests.
+#[derive(Clone)]
+struct Handshake {
+  client_cert: Option<Arc<ClientCert>>,
+  negotiated: Option<Negotiated>,
+}
+
+/// Starts a tokio runtime on an "accept" thread that serves connections on
+/// each of `listeners` and pushes their requests onto `queue`, over TLS on
+/// the TCP ones if `tls` is given.
+///
+/// # Errors
+///
+/// This function will return an error if the runtime or its thread can't be
+/// started.
+pub(super) fn start(
+  listeners: Vec<Listener>,
+  limits: Limits,
+  stats: Arc<ConnectionStats>,
+  queue: Arc<Queue>,
+  tls: Option<Arc<ServerTls>>,
+) -> io::Result<()> {
+  for listener in &listeners {
+    match listener {
+      Listener::Tcp(listener) => listener.set_nonblocking(true)?,
+      #[cfg(unix)]
+      Listener::Unix(listener) => listener.set_nonblocking(true)?,
+    }
+  }
+  let runtime = tokio::runtime::Builder::new_multi_thread()
+    .enable_all()
+    .thread_name("async-io")
+    .build()?;
+  let limits = Arc::new(limits);
+  std::thread::Builder::new()
+    .name("accept".to_string())
+    .spawn(move || {
+      runtime.block_on(async move {
+        let loops: Vec<_> = listeners
+          .into_iter()
+          .map(|listener| {
+            tokio::spawn(accept_loop(
+              listener,
+              limits.clone(),
+              stats.clone(),
+              queue.clone(),
+              tls.clone(),
+            ))
+          })
+          .collect();
+        for accept in loops {
+          let _ = accept.await;
+        }
+      })
+    })?;
+  Ok(())
+}
+
+async fn accept_loop(
+  listener: Listener,
+  limits: Arc<Limits>,
+  stats: Arc<ConnectionStats>,
+  queue: Arc<Queue>,
+  tls: Option<Arc<ServerTls>>,
+) {
+  match listener {
+    Listener::Tcp(listener) => {
+      let listener = match tokio::net::TcpListener::from_std(listener) {
+        Ok(listener) => listener,
+        Err(e) => {
+          error!("Failed to start the async listener: {}", e);
+          return;
+        }
+      };
+      loop {
+        match listener.accept().await {
+          Ok((stream, addr)) => admit(
+            stream,
+            RemoteAddr::Tcp(addr),
+            tls.as_ref(),
+            &limits,
+            &stats,
+            &queue,
+          ),
+          Err(e) => accept_failed(e).await,
+        }
+      }
+    }
+    #[cfg(unix)]
+    Listener::Unix(listener) => {
+      let listener = match tokio::net::UnixListener::from_std(listener) {
+        Ok(listener) => listener,
+        Err(e) => {
+          error!("Failed to start the async listener: {}", e);
+          return;
+        }
+      };
+      loop {
+        match listener.accept().await {
+          Ok((stream, _)) => {
+            let cred = stream.peer_cred().ok().and_then(|cred| {
+              Some(super::PeerCred {
+                pid: cred.pid()?,
+                uid: cred.uid(),
+                gid: cred.gid(),
+              })
+            });
+            admit(
+              stream,
+              RemoteAddr::Unix(cred),
+              None,
+              &limits,
+              &stats,
+              &queue,
+            );
+          }
+          Err(e) => accept_failed(e).await,
+        }
+      }
+    }
+  }
+}
+
+async fn accept_failed(e: io::Error) {
+  error!("Failed to accept connection: {}", e);
+  tokio::time::sleep(ACCEPT_RETRY_DELAY).await;
+}
+
+/// Starts serving an accepted connection, over TLS if `tls` is given, or
+/// rejects it when the server is stopping or at `CONFIG.limits.connections`.
+fn admit<S>(
+  stream: S,
+  remote_addr: RemoteAddr,
+  tls: Option<&Arc<ServerTls>>,
+  limits: &Arc<Limits>,
+  stats: &Arc<ConnectionStats>,
+  queue: &Arc<Queue>,
+) where
+  S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+  if queue.stopping() {
+    tokio::spawn(reject(stream, remote_addr, tls.is_some(), queue.clone()));
+    return;
+  }
+  let Some(guard) = stats.open(limits.max_connections) else {
+    stats.rejected.fetch_add(1, Ordering::Relaxed);
+    tokio::spawn(reject(stream, remote_addr, tls.is_some(), queue.clone()));
+    return;
+  };
+
+  let tls = tls.cloned();
+  let limits = limits.clone();
+  let stats = stats.clone();
+  let queue = queue.clone();
+  tokio::spawn(async move {
+    let _guard = guard;
+    let Some(tls) = tls else {
+      serve(stream, remote_addr, None, limits, stats, queue).await;
+      return;
+    };
+    let acceptor = tokio_rustls::TlsAcceptor::from(tls.config());
+    let wait = limits.keep_alive_timeout.min(limits.header_deadline);
+    let Ok(Ok(stream)) = tokio::time::timeout(wait, acceptor.accept(stream)).await else {
+      return;
+    };
+    let (_, conn) = stream.get_ref();
+    let handshake = Handshake {
+      client_cert: conn
+        .peer_certificates()
+        .and_then(|certs| certs.first())
+        .and_then(ClientCert::from_der)
+        .map(Arc::new),
+      negotiated: Negotiated::from_connection(conn),
+    };
+    serve(stream, remote_addr, Some(handshake), limits, stats, queue).await;
+  });
+}
+
+/// Answers a connection over the limit, or opened while the server is
+/// stopping, with `503` and closes it. A TLS connection is closed without
+/// an answer, since the handshake would cost more than it saves.
+async fn reject<S: AsyncWrite + Unpin>(
+  mut stream: S,
+  remote_addr: RemoteAddr,
+  tls: bool,
+  queue: Arc<Queue>,
+) {
+  if !tls {
+    let accepted = Instant::now();
+    let message = status_message(StatusCode(503));
+    let _ = tokio::time::timeout(REJECT_WRITE_TIMEOUT, stream.write_all(message.as_bytes())).await;
+    queue.log_refused(&remote_addr, StatusCode(503), accepted);
+  }
+  let _ = stream.shutdown().await;
+}
+
+/// Serves the requests of one connection until it closes. `handshake` is
+/// what its TLS handshake settled, over TLS.
+async fn serve<S>(
+  stream: S,
+  remote_addr: RemoteAddr,
+  handshake: Option<Handshake>,
+  limits: Arc<Limits>,
+  stats: Arc<ConnectionStats>,
+  queue: Arc<Queue>,
+) where
+  S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+  let served = AtomicU32::new(0);
+  let service_limits = limits.clone();
+  let service = service_fn(move |request| {
+    let served = served.fetch_add(1, Ordering::Relaxed).saturating_add(1);
+    let keep_alive = service_limits
+      .max_requests_per_connection
+      .is_none_or(|max| served < max);
+    handle(
+      request,
+      remote_addr.clone(),
+      handshake.clone(),
+      keep_alive,
+      service_limits.clone(),
+      stats.clone(),
+      queue.clone(),
+    )
+  });
+
+  let mut builder = http1::Builder::new();
+  builder
+    .timer(TokioTimer::new())
+    .header_read_timeout(limits.keep_alive_timeout.min(limits.header_deadline))
+    .keep_alive(true)
+    .max_headers(limits.max_headers)
+    // hyper requires at least 8 KB.
+    .max_buf_size((limits.max_header_total_bytes + limits.max_url_bytes).max(8192));
+  // Errors here are clients going away or timing out; there is no one to
+  // report them to.
+  let _ = builder
+    .serve_connection(TokioIo::new(stream), service)
+    .await;
+}
+
+/// Returns the limit the parsed request head goes past, if any.
+fn oversized(parts: &hyper::http::request::Parts, limits: &Limits) -> Option<Oversized> {
+  let url = parts.uri.path_and_query().map_or(0, |path| path.as_str().len());
+  if url > limits.max_url_bytes {
+    return Some(Oversized::Url);
+  }
+  if parts.headers.len() > limits.max_headers {
+    return Some(Oversized::HeaderCount);
+  }
+  let mut total = 0;
+  for (name, value) in &parts.headers {
+    // As the header line would be sent: `name: value\r\n`.
+    let line = name.as_str().len() + value.len() + 4;
+    if line > limits.max_header_bytes {
+      return Some(Oversized::HeaderSize);
+    }
+    total += line - 2;
+  }
+  (total > limits.max_header_total_bytes).then_some(Oversized::HeaderTotal)
+}
+
+/// Queues one request for the workers and waits for their response.
+async fn handle(
+  request: hyper::Request<Incoming>,
+  remote_addr: RemoteAddr,
+  handshake: Option<Handshake>,
+  keep_alive: bool,
+  limits: Arc<Limits>,
+  stats: Arc<ConnectionStats>,
+  queue: Arc<Queue>,
+) -> Result<hyper::Response<Full<Bytes>>, Infallible> {
+  let received = Instant::now();
+  // A request refused here is logged like one refused while its head was
+  // read without the feature.
+  let refuse = |status: StatusCode| {
+    queue.log_refused(&remote_addr, status, received);
+    status_response(status.0)
+  };
+  let (parts, incoming) = request.into_parts();
+  if let Some(limit) = oversized(&parts, &limits) {
+    return Ok(refuse(stats.refuse_oversized(limit)));
+  }
+  // hyper has framed the body already, but a request that fyre's own
+  // parser would refuse as a smuggling attempt is refused here too, and
+  // its connection closed so nothing after it is read. hyper drops the
+  // `Content-Length` of a chunked request before it gets here, and closes
+  // the connection after it itself.
+  let values = |name| {
+    parts
+      .headers
+      .get_all(name)
+      .into_iter()
+      .map(|value| value.to_str().unwrap_or(""))
+  };
+  if body_length(
+    values(hyper::header::TRANSFER_ENCODING),
+    values(hyper::header::CONTENT_LENGTH),
+  )
+  .is_none()
+  {
+    let mut response = refuse(StatusCode(400));
+    response.headers_mut().insert(
+      hyper::header::CONNECTION,
+      hyper::header::HeaderValue::from_static("close"),
+    );
+    return Ok(response);
+  }
+  let Ok(method) = Method::from_str(parts.method.as_str()) else {
+    return Ok(refuse(StatusCode(400)));
+  };
+  let url = parts
+    .uri
+    .path_and_query()
+    .map_or("/", |path| path.as_str())
+    .to_string();
+  let version = if parts.version == hyper::Version::HTTP_10 {
+    HTTPVersion(1, 0)
+  } else {
+    HTTPVersion(1, 1)
+  };
+  let mut headers = Vec::with_capacity(parts.headers.len());
+  for (name, value) in &parts.headers {
+    match Header::from_bytes(name.as_str().as_bytes(), value.as_bytes()) {
+      Ok(header) => headers.push(header),
+      Err(()) => return Ok(refuse(StatusCode(400))),
+    }
+  }
+
+  let (chunks, receiver) = mpsc::channel(BODY_CHANNEL_CHUNKS);
+  let forwarding = tokio::spawn(forward_body(
+    incoming,
+    chunks,
+    limits.body_read_timeout,
+    stats.clone(),
+  ));
+  let (reply, replied) = oneshot::channel();
+  queue.push(Request {
+    method,
+    url,
+    version: version.clone(),
+    headers,
+    remote_addr,
+    secure: handshake.is_some(),
+    client_cert: handshake.as_ref().and_then(|h| h.client_cert.clone()),
+    negotiated: handshake.and_then(|h| h.negotiated),
+    body: Body::Stream(BodyReader::new(receiver)).counted(None),
+    responder: Responder::Channel(reply),
+    server_header: None,
+    stats: None,
+    connection: None,
+    received,
+    request_log: None,
+    metrics: None,
+    span: None,
+  });
+
+  let (connection, mut response) = match replied.await {
+    Ok(reply) => (reply.connection, reply.into_response()),
+    // The worker failed to build a response.
+    Err(_) => (None, status_response(500)),
+  };
+  let compat = limits.version_compat && version == (1, 0);
+  let keep_alive = keep_alive && connection.unwrap_or(limits.keep_alive && !compat);
+  if !keep_alive || queue.stopping() {
+    // The worker has read all of the body it is going to, which may not be
+    // all of it, as for a body past `CONFIG.limits.body`. Dropping the
+    // rest lets hyper close the connection instead of waiting for it.
+    forwarding.abort();
+    // hyper adds `keep-alive` to a response an HTTP/1.0 client asked to keep
+    // the connection open for, unless the response is HTTP/1.0 itself.
+    if version == (1, 0) {
+      *response.version_mut() = hyper::Version::HTTP_10;
+    }
+    response.headers_mut().insert(
+      hyper::header::CONNECTION,
+      hyper::header::HeaderValue::from_static("close"),
+    );
+  }
+  Ok(response)
+}
+
+/// Passes the request body from hyper to the worker reading it, until it
+/// ends, the worker stops reading, or the client sends nothing for
+/// `timeout`, which is counted in `stats`.
+async fn forward_body(
+  mut incoming: Incoming,
+  chunks: mpsc::Sender<io::Result<Bytes>>,
+  timeout: Duration,
+  stats: Arc<ConnectionStats>,
+) {
+  loop {
+    let frame = match tokio::time::timeout(timeout, incoming.frame()).await {
+      Ok(Some(frame)) => frame,
+      Ok(None) => return,
+      Err(_) => {
+        stats.count_timeout(Phase::Body);
+        let _ = chunks
+          .send(Err(io::Error::from(io::ErrorKind::TimedOut)))
+          .await;
+        return;
+      }
+    };
+    let chunk = match frame {
+      Ok(frame) => match frame.into_data() {
+        Ok(data) => Ok(data),
+        // Trailers are ignored.
+        Err(_) => continue,
+      },
+      Err(e) => Err(io::Error::other(e)),
+    };
+    let failed = chunk.is_err();
+    if chunks.send(chunk).await.is_err() || failed {
+      return;
+    }
+  }
+}
+
+/// Reads a request body forwarded by `forward_body`, blocking the worker
+/// until the next chunk arrives.
+pub(super) struct BodyReader {
+  chunks: mpsc::Receiver<io::Result<Bytes>>,
+  chunk: Bytes,
+}
+
+impl BodyReader {
+  fn new(chunks: mpsc::Receiver<io::Result<Bytes>>) -> Self {
+    BodyReader {
+      chunks,
+      chunk: Bytes::new(),
+    }
+  }
+}
+
+impl Read for BodyReader {
+  fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+    while self.chunk.is_empty() {
+      match self.chunks.blocking_recv() {
+        Some(chunk) => self.chunk = chunk?,
+        None => return Ok(0),
+      }
+    }
+    let n = self.chunk.len().min(buf.len());
+    buf[..n].copy_from_slice(&self.chunk.split_to(n));
+    Ok(n)
+  }
+}
+
+/// A worker's response, handed back to the connection's task.
+pub(super) struct Reply {
+  status: u16,
+  headers: Vec<Header>,
+  body: Vec<u8>,
+  /// What the response's `Connection` header asked for (see
+  /// `Request::set_connection`).
+  connection: Option<bool>,
+}
+
+impl Reply {
+  fn into_response(self) -> hyper::Response<Full<Bytes>> {
+    let mut builder = hyper::Response::builder().status(self.status);
+    for header in &self.headers {
+      if HOP_HEADERS.iter().any(|name| header.field.equiv(name)) {
+        continue;
+      }
+      builder = builder.header(header.field.as_str().as_str(), header.value.as_str());
+    }
+    builder
+      .body(Full::new(Bytes::from(self.body)))
+      .unwrap_or_else(|e| {
+        error!("Invalid response: {}", e);
+        status_response(500)
+      })
+  }
+}
+
+/// Reads `response` into a `Reply` and sends it to the connection's task,
+/// with what its `Connection` header asked for.
+///
+/// # Errors
+///
+/// This function will return an error if the response body can't be read.
+pub(super) fn send<R: Read>(
+  reply: oneshot::Sender<Reply>,
+  response: Response<R>,
+  connection: Option<bool>,
+) -> io::Result<()> {
+  let status = response.status_code().0;
+  let headers = response.headers().to_vec();
+  let length = response.data_length();
+  let mut reader = response.into_reader();
+  let mut body = Vec::new();
+  match length {
+    Some(length) => reader.take(length as u64).read_to_end(&mut body)?,
+    None => reader.read_to_end(&mut body)?,
+  };
+  // The connection's task is gone if the client has disconnected.
+  let _ = reply.send(Reply {
+    status,
+    headers,
+    body,
+    connection,
+  });
+  Ok(())
+}
+
+fn status_response(status: u16) -> hyper::Response<Full<Bytes>> {
+  let reason = StatusCode(status).default_reason_phrase();
+  let mut response = hyper::Response::new(Full::new(Bytes::from(reason)));
+  *response.status_mut() =
+    hyper::StatusCode::from_u16(status).unwrap_or(hyper::StatusCode::INTERNAL_SERVER_ERROR);
+  response
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::testing::{self, Fixture};
+  use std::io::Write;
+  use std::net::TcpStream;
+
+  #[test]
+  fn bodies_are_read_across_chunks() {
+    let (sender, receiver) = mpsc::channel(BODY_CHANNEL_CHUNKS);
+    for chunk in ["hello ", "", "chunked world"] {
+      sender.try_send(Ok(Bytes::from(chunk))).unwrap();
+    }
+    drop(sender);
+    let mut reader = BodyReader::new(receiver);
+    let mut first = [0; 4];
+    assert_eq!(reader.read(&mut first).unwrap(), 4);
+    assert_eq!(&first, b"hell");
+    let mut rest = String::new();
+    reader.read_to_string(&mut rest).unwrap();
+    assert_eq!(rest, "o chunked world");
+
+    let (sender, receiver) = mpsc::channel(BODY_CHANNEL_CHUNKS);
+    sender
+      .try_send(Err(io::ErrorKind::TimedOut.into()))
+      .unwrap();
+    let error = BodyReader::new(receiver).read(&mut first).unwrap_err();
+    assert_eq!(error.kind(), io::ErrorKind::TimedOut);
+  }
+
+  #[test]
+  fn hyper_sets_the_hop_headers_itself() {
+    let header = |name: &str, value: &str| Header::from_bytes(name, value).unwrap();
+    let response = Reply {
+      status: 201,
+      headers: vec![
+        header("Content-Type", "text/plain"),
+        header("Content-Length", "999"),
+        header("Connection", "close"),
+        header("Transfer-Encoding", "chunked"),
+      ],
+      body: b"made".to_vec(),
+      connection: Some(false),
+    }
+    .into_response();
+    assert_eq!(response.status(), 201);
+    let names: Vec<&str> = response
+      .headers()
+      .keys()
+      .map(|name| name.as_str())
+      .collect();
+    assert_eq!(names, ["content-type"]);
+  }
+
+  #[test]
+  fn idle_connections_dont_hold_up_the_workers() {
+    let fixture = Fixture::new(
+      r#"router.add("/echo", "echo.lua")"#,
+      &[(
+        "echo.lua",
+        r#"return { handler = function(request, response)
+          response.body = request.method .. " " .. request.body
+        end }"#,
+      )],
+    );
+    let server = fixture
+      .builder()
+      .addr("127.0.0.1:0")
+      .workers(1)
+      .load()
+      .unwrap();
+    let addr = server.serve().unwrap().remove(0);
+
+    // More idle clients than workers, each partway through its head.
+    let idle: Vec<TcpStream> = (0..32)
+      .map(|_| {
+        let mut stream = TcpStream::connect(&addr).unwrap();
+        stream.write_all(b"GET /echo HTTP/1.1\r\n").unwrap();
+        stream
+      })
+      .collect();
+
+    // Two requests on one connection, the first with a chunked body.
+    let response = testing::send(
+      &addr,
+      "POST /echo HTTP/1.1\r\nHost: localhost\r\nTransfer-Encoding: chunked\r\n\r\n\
+       6\r\nhello \r\n5\r\nworld\r\n0\r\n\r\n\
+       GET /echo HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n",
+    );
+    assert_eq!(
+      response.matches("HTTP/1.1 200 OK\r\n").count(),
+      2,
+      "{}",
+      response
+    );
+    assert!(
+      response.contains("\r\n\r\nPOST hello world"),
+      "{}",
+      response
+    );
+    assert!(response.ends_with("\r\n\r\nGET "), "{}", response);
+
+    drop(idle);
+    server.shutdown();
+  }
+}
@@ -0,0 +1,1948 @@
+//! # Connection Handling
+//!
+//! Accepts connections on the listening socket, reads HTTP/1.x requests
+//! from them, and queues the requests for the worker threads, which answer
+//! with tiny_http `Response`s. tiny_http's own server can't limit its
+//! connections, so they are managed here:
+//!
+//! - At most `CONFIG.limits.connections` connections are open at once. Past
+//!   that, a new connection is answered with `503` and `Connection: close`
+//!   right away instead of waiting in the accept queue.
+//! - A connection that sends nothing for `CONFIG.limits.keep_alive_timeout_ms`
+//!   between requests is closed, as is one that stalls for
+//!   `CONFIG.timeouts.header_read_ms` in a request's head,
+//!   `CONFIG.timeouts.body_read_ms` in its body, or `CONFIG.timeouts.write_ms`
+//!   while its response is written. Each is counted by
+//!   `ConnectionStats::timed_out`.
+//! - However steadily a client trickles it in, a request's head must be
+//!   complete within `CONFIG.timeouts.header_deadline_ms`, counted from the
+//!   accept for the first request (so a TLS handshake counts) and from its
+//!   first byte for later ones. A connection past the deadline is answered with
+//!   `408`, closed, and counted separately from the stalls above.
+//! - A connection is closed after `CONFIG.limits.requests_per_connection`
+//!   requests.
+//! - A request a proxy in front might frame differently, so that a second
+//!   request could be smuggled in after it, is answered with `400` and its
+//!   connection closed (see `body_length`).
+//! - With `CONFIG.http.keep_alive = false`, every connection is closed after
+//!   one request. With `CONFIG.http.version_compat`, so is every HTTP/1.0
+//!   connection, and HTTP/1.0 clients aren't sent `100 Continue`. Either way a
+//!   handler can set `Connection: close` or `keep-alive` on its own response.
+//!
+//! The health probes (see `health`) are answered as they arrive instead of
+//! being queued, so they don't wait for a worker.
+//!
+//! Every response, from a worker or from the refusals above, is logged
+//! with `request_log` when it is on. A worker can also have its response
+//! counted in `request_metrics`, with `Request::count_in`, and its span
+//! exported with `Request::export_span` (see `span_export`).
+//!
+//! Once `Server::stop` is called the workers get no more requests: those
+//! still queued, and any that arrive afterwards on open or new connections,
+//! are answered with `503` and `Connection: close`.
+//!
+//! Each open connection has a thread that reads its requests one at a time:
+//! it reads a request's head, queues the request, and waits for the worker
+//! to respond before reading the next one.
+//!
+//! The listener is TCP, or a Unix domain socket for a `unix:` address (see
+//! `net::listen`). A request from a Unix socket reports its client as the
+//! process that connected, where the OS says which.
+//!
+//! With `CONFIG.tls` set in `config.lua`, each connection is wrapped in a
+//! rustls session (see `stream`) before its first request is read; everything
+//! after that is the same. Each connection takes the TLS configuration in place
+//! when it is accepted, so one reloaded on `SIGHUP` (see `tls`) applies to new
+//! connections while open ones carry on.
+//!
+//! Built with the `async` feature, connections are served by hyper on a
+//! tokio runtime instead (see `hyper_backend`), so an idle or slow client
+//! costs a task rather than a thread, and TLS is done by tokio-rustls with
+//! the same configuration. The requests go on the same queue
+//! and workers answer them through the same `Request` type, so the handler
+//! pipeline is identical on both.
+
+// The thread-per-connection code is unused when the `async` feature
+// replaces it.
+#![cfg_attr(feature = "async", allow(dead_code))]
+
+use chunked_transfer::Decoder;
+use std::collections::VecDeque;
+use std::io::{self, BufRead, BufReader, BufWriter, Cursor, Read, Write};
+use std::fmt;
+use std::net::SocketAddr;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{mpsc, Arc, Condvar, Mutex, PoisonError};
+use std::time::{Duration, Instant};
+use tiny_http::{HTTPVersion, Header, Method, Response, StatusCode};
+
+use crate::health::Health;
+use crate::locks;
+use crate::net::Listener;
+use crate::request_log::{Entry, RequestLog};
+use crate::request_metrics::RequestMetrics;
+use crate::span_export::ServerSpan;
+use crate::tls::{ClientCert, Negotiated, ServerTls};
+
+#[cfg(feature = "async")]
+mod hyper_backend;
+mod redirect;
+mod stream;
+
+pub use redirect::start_redirect;
+use stream::Stream;
+
+/// The open connections allowed when `CONFIG.limits.connections` is not set.
+pub const DEFAULT_MAX_CONNECTIONS: usize = 1024;
+/// How long a connection may stay silent when
+/// `CONFIG.limits.keep_alive_timeout_ms` is not set.
+pub const DEFAULT_KEEP_ALIVE_TIMEOUT: Duration = Duration::from_secs(5);
+/// How long a request's head may stall when `CONFIG.timeouts.header_read_ms` is
+/// not set.
+pub const DEFAULT_HEADER_READ_TIMEOUT: Duration = Duration::from_secs(10);
+/// How long a request's head may take in all when
+/// `CONFIG.timeouts.header_deadline_ms` is not set.
+pub const DEFAULT_HEADER_DEADLINE: Duration = Duration::from_secs(10);
+/// How long a request's body may stall when `CONFIG.timeouts.body_read_ms` is
+/// not set.
+pub const DEFAULT_BODY_READ_TIMEOUT: Duration = Duration::from_secs(30);
+/// How long writing a response may stall when `CONFIG.timeouts.write_ms` is not
+/// set.
+pub const DEFAULT_WRITE_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// The longest URL accepted when `CONFIG.limits.url_bytes` is not set.
+pub const DEFAULT_MAX_URL_BYTES: usize = 8 * 1024;
+/// The most headers accepted in one request when `CONFIG.limits.headers` is not
+/// set.
+pub const DEFAULT_MAX_HEADERS: usize = 100;
+/// The longest header line accepted when `CONFIG.limits.header_bytes` is not
+/// set.
+pub const DEFAULT_MAX_HEADER_BYTES: usize = 8 * 1024;
+/// The most header bytes accepted in one request when
+/// `CONFIG.limits.header_total_bytes` is not set.
+pub const DEFAULT_MAX_HEADER_TOTAL_BYTES: usize = 64 * 1024;
+/// Room in the request line for the method and version around the URL.
+const REQUEST_LINE_SLACK: usize = 64;
+/// The most unread request body discarded to keep a connection open; a
+/// connection with more left is closed instead.
+const MAX_DRAIN_BYTES: u64 = 64 * 1024;
+/// How long to wait before accepting again after `accept` fails (e.g. when
+/// the process is out of file descriptors).
+const ACCEPT_RETRY_DELAY: Duration = Duration::from_millis(100);
+/// How long writing a rejection may take.
+const REJECT_WRITE_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Connection limits from `config.lua`.
+#[derive(Debug, Clone)]
+pub struct Limits {
+  pub max_connections: usize,
+  /// How long a connection may wait for its next request.
+  pub keep_alive_timeout: Duration,
+  /// How long one read of a request's head or body, or one write of its
+  /// response, may wait.
+  pub header_read_timeout: Duration,
+  pub body_read_timeout: Duration,
+  pub write_timeout: Duration,
+  /// How long a request's head may take in all, however steadily it
+  /// arrives.
+  pub header_deadline: Duration,
+  /// `None` allows any number of requests per connection.
+  pub max_requests_per_connection: Option<u32>,
+  /// Whether connections are kept open between requests; a response can
+  /// still ask for either.
+  pub keep_alive: bool,
+  /// Whether HTTP/1.0 connections are closed after each response and their
+  /// clients never sent `100 Continue`, for clients that mishandle either.
+  /// Responses to them always carry a `Content-Length`, never chunks.
+  pub version_compat: bool,
+  /// The longest URL; a longer one is answered with `414`.
+  pub max_url_bytes: usize,
+  /// The most headers, the longest header line, and the most header bytes
+  /// in one request; past any of them the request is answered with `431`.
+  pub max_headers: usize,
+  pub max_header_bytes: usize,
+  pub max_header_total_bytes: usize,
+}
+
+impl Default for Limits {
+  fn default() -> Self {
+    Limits {
+      max_connections: DEFAULT_MAX_CONNECTIONS,
+      keep_alive_timeout: DEFAULT_KEEP_ALIVE_TIMEOUT,
+      header_read_timeout: DEFAULT_HEADER_READ_TIMEOUT,
+      body_read_timeout: DEFAULT_BODY_READ_TIMEOUT,
+      write_timeout: DEFAULT_WRITE_TIMEOUT,
+      header_deadline: DEFAULT_HEADER_DEADLINE,
+      max_requests_per_connection: None,
+      keep_alive: true,
+      version_compat: false,
+      max_url_bytes: DEFAULT_MAX_URL_BYTES,
+      max_headers: DEFAULT_MAX_HEADERS,
+      max_header_bytes: DEFAULT_MAX_HEADER_BYTES,
+      max_header_total_bytes: DEFAULT_MAX_HEADER_TOTAL_BYTES,
+    }
+  }
+}
+
+/// Which request limit a request went past.
+#[derive(Debug, Clone, Copy)]
+pub enum Oversized {
+  Url,
+  HeaderCount,
+  HeaderSize,
+  HeaderTotal,
+  /// `CONFIG.limits.body`, checked by the worker as it reads the body.
+  Body,
+}
+
+impl Oversized {
+  /// Every limit, in the order `ConnectionStats::oversized` reports them.
+  pub const ALL: [Oversized; 5] = [
+    Oversized::Url,
+    Oversized::HeaderCount,
+    Oversized::HeaderSize,
+    Oversized::HeaderTotal,
+    Oversized::Body,
+  ];
+
+  /// The limit's name, as the `limit` label of
+  /// `fyre_requests_oversized_total`.
+  pub fn name(self) -> &'static str {
+    match self {
+      Oversized::Url => "url",
+      Oversized::HeaderCount => "header_count",
+      Oversized::HeaderSize => "header_size",
+      Oversized::HeaderTotal => "header_total",
+      Oversized::Body => "body",
+    }
+  }
+
+  /// The status the request is answered with.
+  fn status(self) -> StatusCode {
+    match self {
+      Oversized::Url => StatusCode(414),
+      Oversized::Body => StatusCode(413),
+      _ => StatusCode(431),
+    }
+  }
+}
+
+/// Where a connection was when it timed out.
+#[derive(Debug, Clone, Copy)]
+pub enum Phase {
+  /// Waiting for the next request.
+  Idle,
+  Header,
+  /// Past `Limits::header_deadline` before the head was complete.
+  HeaderDeadline,
+  Body,
+  Write,
+}
+
+impl Phase {
+  /// Every phase, in the order `ConnectionStats::timed_out` reports them.
+  pub const ALL: [Phase; 5] = [
+    Phase::Idle,
+    Phase::Header,
+    Phase::HeaderDeadline,
+    Phase::Body,
+    Phase::Write,
+  ];
+
+  /// The phase's name, as the `phase` label of
+  /// `fyre_connections_timed_out_total`.
+  pub fn name(self) -> &'static str {
+    match self {
+      Phase::Idle => "idle",
+      Phase::Header => "header",
+      Phase::HeaderDeadline => "header_deadline",
+      Phase::Body => "body",
+      Phase::Write => "write",
+    }
+  }
+}
+
+/// Whether `e` is a socket read or write running out of time.
+fn is_timeout(e: &io::Error) -> bool {
+  matches!(e.kind(), io::ErrorKind::TimedOut | io::ErrorKind::WouldBlock)
+}
+
+/// Connection counts, reported by `fyre.metrics.render()`.
+#[derive(Default)]
+pub struct ConnectionStats {
+  active: AtomicUsize,
+  rejected: AtomicU64,
+  /// Requests refused for going past a limit, by `Oversized::ALL` index.
+  oversized: [AtomicU64; 5],
+  /// Connections closed for stalling, by `Phase::ALL` index.
+  timed_out: [AtomicU64; 5],
+}
+
+impl ConnectionStats {
+  /// Returns the number of open connections.
+  pub fn active(&self) -> usize {
+    self.active.load(Ordering::Relaxed)
+  }
+
+  /// Returns the number of connections turned away at the limit since
+  /// startup.
+  pub fn rejected(&self) -> u64 {
+    self.rejected.load(Ordering::Relaxed)
+  }
+
+  /// Returns the number of requests refused for going past `limit` since
+  /// startup.
+  pub fn oversized(&self, limit: Oversized) -> u64 {
+    self.oversized[limit as usize].load(Ordering::Relaxed)
+  }
+
+  /// Counts a request refused for going past `limit`, returning the status
+  /// to answer it with.
+  pub fn refuse_oversized(&self, limit: Oversized) -> StatusCode {
+    self.oversized[limit as usize].fetch_add(1, Ordering::Relaxed);
+    limit.status()
+  }
+
+  /// Returns the number of connections closed for stalling in `phase`
+  /// since startup.
+  pub fn timed_out(&self, phase: Phase) -> u64 {
+    self.timed_out[phase as usize].load(Ordering::Relaxed)
+  }
+
+  fn count_timeout(&self, phase: Phase) {
+    self.timed_out[phase as usize].fetch_add(1, Ordering::Relaxed);
+  }
+
+  /// Counts a new connection if fewer than `max` are open. The returned
+  /// guard uncounts it when the connection ends.
+  fn open(self: &Arc<Self>, max: usize) -> Option<ActiveGuard> {
+    self
+      .active
+      .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |n| {
+        (n < max).then_some(n + 1)
+      })
+      .ok()?;
+    Some(ActiveGuard(self.clone()))
+  }
+}
+
+/// Decrements the open connection count when a connection ends.
+struct ActiveGuard(Arc<ConnectionStats>);
+
+impl Drop for ActiveGuard {
+  fn drop(&mut self) {
+    self.0.active.fetch_sub(1, Ordering::Relaxed);
+  }
+}
+
+/// The client a request came from.
+#[derive(Debug, Clone)]
+pub enum RemoteAddr {
+  Tcp(SocketAddr),
+  /// A client of a Unix socket, with its credentials when the OS reports
+  /// them.
+  Unix(Option<PeerCred>),
+}
+
+/// The process on the other end of a Unix socket connection.
+#[derive(Debug, Clone)]
+pub struct PeerCred {
+  pub pid: i32,
+  pub uid: u32,
+  pub gid: u32,
+}
+
+/// Formats the address as `ip:port`, or as `unix:pid=1234,uid=33,gid=33`
+/// (just `unix` without credentials).
+impl fmt::Display for RemoteAddr {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      RemoteAddr::Tcp(addr) => addr.fmt(f),
+      RemoteAddr::Unix(Some(cred)) => write!(
+        f,
+        "unix:pid={},uid={},gid={}",
+        cred.pid, cred.uid, cred.gid
+      ),
+      RemoteAddr::Unix(None) => f.write_str("unix"),
+    }
+  }
+}
+
+/// Requests waiting for a worker.
+#[derive(Default)]
+struct Queue {
+  requests: Mutex<VecDeque<Request>>,
+  ready: Condvar,
+  /// Set by `Server::stop`, after which requests are refused.
+  stopping: AtomicBool,
+  /// Answers the health probes before they are queued.
+  health: Option<Arc<Health>>,
+  server_header: Option<Arc<str>>,
+  request_log: Option<Arc<RequestLog>>,
+}
+
+impl Queue {
+  fn push(&self, mut request: Request) {
+    request.server_header = self.server_header.clone();
+    request.request_log = self.request_log.clone();
+    let request = match &self.health {
+      Some(health) => match health.answer(request, self.stopping()) {
+        Some(request) => request,
+        None => return,
+      },
+      None => request,
+    };
+    let mut requests = locks::lock(&self.requests, "request queue");
+    // Checked under the lock, so no request is queued after `stop` has
+    // emptied the queue.
+    if self.stopping() {
+      drop(requests);
+      request.refuse();
+      return;
+    }
+    requests.push_back(request);
+    drop(requests);
+    self.ready.notify_one();
+  }
+
+  fn stopping(&self) -> bool {
+    self.stopping.load(Ordering::Relaxed)
+  }
+
+  /// Logs a request answered with `status` before its request line was
+  /// read.
+  fn log_refused(&self, remote_addr: &RemoteAddr, status: StatusCode, received: Instant) {
+    if let Some(log) = &self.request_log {
+      log.record(&Entry {
+        remote_addr,
+        request: None,
+        headers: &[],
+        status: status.0,
+        bytes: Some(status.default_reason_phrase().len()),
+        elapsed: received.elapsed(),
+      });
+    }
+  }
+}
+
+/// The HTTP server the worker threads take requests from.
+pub struct Server {
+  queue: Arc<Queue>,
+}
+
+impl Server {
+  /// Starts accepting connections on each of `listeners`, speaking HTTPS
+  /// if `tls` is given. Requests from all of them go on the one queue, and
+  /// `limits` applies to their connections together. `health` answers its
+  /// probes without queueing them. Responses without a `Server` header get
+  /// `server_header`, or none at all if it is `None`. Each response is
+  /// logged to `request_log`, if given.
+  ///
+  /// # Errors
+  ///
+  /// This function will return an error if an accept thread can't be
+  /// started, or if `tls` is given with a Unix socket, which doesn't
+  /// support it.
+  pub fn start(
+    listeners: Vec<Listener>,
+    limits: Limits,
+    stats: Arc<ConnectionStats>,
+    tls: Option<Arc<ServerTls>>,
+    health: Option<Arc<Health>>,
+    server_header: Option<String>,
+    request_log: Option<Arc<RequestLog>>,
+  ) -> io::Result<Server> {
+    #[cfg(unix)]
+    if tls.is_some() && listeners.iter().any(|l| matches!(l, Listener::Unix(_))) {
+      return Err(io::Error::other("TLS is not supported on a Unix socket"));
+    }
+    let queue = Arc::new(Queue {
+      health,
+      server_header: server_header.map(Arc::from),
+      request_log,
+      ..Queue::default()
+    });
+    #[cfg(not(feature = "async"))]
+    for listener in listeners {
+      let limits = limits.clone();
+      let stats = stats.clone();
+      let queue = queue.clone();
+      let tls = tls.clone();
+      std::thread::Builder::new()
+        .name("accept".to_string())
+        .spawn(move || accept_loop(listener, &limits, &stats, &queue, tls.as_ref()))?;
+    }
+    #[cfg(feature = "async")]
+    hyper_backend::start(listeners, limits, stats, queue.clone(), tls)?;
+    Ok(Server { queue })
+  }
+
+  /// Blocks until a request arrives and returns it. Returns `None` once the
+  /// server is stopped.
+  pub fn recv(&self) -> Option<Request> {
+    let mut requests = locks::lock(&self.queue.requests, "request queue");
+    loop {
+      if self.queue.stopping() {
+        return None;
+      }
+      if let Some(request) = requests.pop_front() {
+        return Some(request);
+      }
+      requests = self
+        .queue
+        .ready
+        .wait(requests)
+        .unwrap_or_else(PoisonError::into_inner);
+    }
+  }
+
+  /// Stops handing out requests: waiting workers are woken and `recv`
+  /// returns `None`, and queued and later requests are refused with `503`.
+  /// Requests the workers have already taken are unaffected.
+  pub fn stop(&self) {
+    let queued = {
+      let mut requests = locks::lock(&self.queue.requests, "request queue");
+      self.queue.stopping.store(true, Ordering::Relaxed);
+      std::mem::take(&mut *requests)
+    };
+    self.queue.ready.notify_all();
+    for request in queued {
+      request.refuse();
+    }
+  }
+}
+
+fn accept_loop(
+  listener: Listener,
+  limits: &Limits,
+  stats: &Arc<ConnectionStats>,
+  queue: &Arc<Queue>,
+  tls: Option<&Arc<ServerTls>>,
+) {
+  loop {
+    let (stream, remote_addr) = match accept(&listener) {
+      Ok(accepted) => accepted,
+      Err(e) => {
+        error!("Failed to accept connection: {}", e);
+        std::thread::sleep(ACCEPT_RETRY_DELAY);
+        continue;
+      }
+    };
+
+    if queue.stopping() {
+      reject(stream, &remote_addr, tls.is_some(), queue);
+      continue;
+    }
+    let Some(guard) = stats.open(limits.max_connections) else {
+      stats.rejected.fetch_add(1, Ordering::Relaxed);
+      reject(stream, &remote_addr, tls.is_some(), queue);
+      continue;
+    };
+
+    let limits = limits.clone();
+    let stats = stats.clone();
+    let queue = queue.clone();
+    let tls = tls.cloned();
+    let spawned = std::thread::Builder::new()
+      .name("connection".to_string())
+      .spawn(move || {
+        let _guard = guard;
+        serve(stream, remote_addr, &limits, &stats, &queue, tls.as_ref());
+      });
+    if let Err(e) = spawned {
+      error!("Failed to start connection thread: {}", e);
+    }
+  }
+}
+
+/// Accepts the next connection on `listener`.
+fn accept(listener: &Listener) -> io::Result<(Stream, RemoteAddr)> {
+  match listener {
+    Listener::Tcp(listener) => {
+      let (stream, addr) = listener.accept()?;
+      Ok((Stream::Plain(stream), RemoteAddr::Tcp(addr)))
+    }
+    #[cfg(unix)]
+    Listener::Unix(listener) => {
+      let (stream, _) = listener.accept()?;
+      let cred = stream::peer_cred(&stream);
+      Ok((Stream::Unix(stream), RemoteAddr::Unix(cred)))
+    }
+  }
+}
+
+/// Answers a connection over the limit, or opened while the server is
+/// stopping, with `503` and closes it. A TLS connection is closed without
+/// an answer, since the handshake would cost more than it saves.
+fn reject(mut stream: Stream, remote_addr: &RemoteAddr, tls: bool, queue: &Queue) {
+  if !tls {
+    let accepted = Instant::now();
+    let _ = stream.set_write_timeout(Some(REJECT_WRITE_TIMEOUT));
+    write_status(&mut stream, StatusCode(503));
+    queue.log_refused(remote_addr, StatusCode(503), accepted);
+  }
+  let _ = stream.shutdown();
+}
+
+/// Writes a plain response with `status` that closes the connection.
+fn write_status(stream: &mut impl Write, status: StatusCode) {
+  let _ = stream.write_all(status_message(status).as_bytes());
+}
+
+/// Formats a plain response with `status` that closes the connection.
+fn status_message(status: StatusCode) -> String {
+  let reason = status.default_reason_phrase();
+  format!(
+    "HTTP/1.1 {} {}\r\nConnection: close\r\nContent-Type: text/plain\r\n\
+     Content-Length: {}\r\n\r\n{}",
+    status.0,
+    reason,
+    reason.len(),
+    reason
+  )
+}
+
+/// The two halves of an open connection, passed from request to request.
+struct Connection {
+  reader: BufReader<Stream>,
+  writer: Stream,
+  /// The certificate the client presented in the TLS handshake.
+  client_cert: Option<Arc<ClientCert>>,
+  /// The TLS version and cipher suite the handshake settled on.
+  negotiated: Option<Negotiated>,
+}
+
+/// Reads and queues the requests of one connection until it closes.
+fn serve(
+  stream: Stream,
+  remote_addr: RemoteAddr,
+  limits: &Limits,
+  stats: &Arc<ConnectionStats>,
+  queue: &Queue,
+  tls: Option<&Arc<ServerTls>>,
+) {
+  let accepted = Instant::now();
+  if stream.set_write_timeout(Some(limits.write_timeout)).is_err() {
+    return;
+  }
+  let stream = match (stream, tls) {
+    (Stream::Plain(stream), Some(tls)) => match Stream::tls(stream, &tls.config()) {
+      Ok(stream) => stream,
+      Err(_) => return,
+    },
+    (stream, _) => stream,
+  };
+  let Ok(writer) = stream.try_clone() else {
+    return;
+  };
+  let mut conn = Connection {
+    reader: BufReader::new(stream),
+    writer,
+    client_cert: None,
+    negotiated: None,
+  };
+
+  let mut served: u32 = 0;
+  loop {
+    // Waiting for a request is bounded by the keep-alive timeout, and once
+    // it begins each read of its head by the header timeout. The TLS
+    // handshake happens on the first read, so it counts as waiting, and
+    // the first request's deadline runs from the accept.
+    let started = (served == 0).then_some(accepted);
+    let wait = match started {
+      Some(accepted) => limits
+        .keep_alive_timeout
+        .min(limits.header_deadline.saturating_sub(accepted.elapsed())),
+      None => limits.keep_alive_timeout,
+    };
+    if wait.is_zero() {
+      stats.count_timeout(Phase::HeaderDeadline);
+      return;
+    }
+    if conn.reader.get_ref().set_read_timeout(Some(wait)).is_err() {
+      return;
+    }
+    match conn.reader.fill_buf() {
+      Ok([]) => return,
+      Ok(_) => {}
+      Err(e) => {
+        if is_timeout(&e) {
+          let past_deadline =
+            started.is_some_and(|accepted| accepted.elapsed() >= limits.header_deadline);
+          stats.count_timeout(if past_deadline {
+            Phase::HeaderDeadline
+          } else {
+            Phase::Idle
+          });
+        }
+        return;
+      }
+    }
+    // The request's time, for the request log, runs from its first byte.
+    let received = Instant::now();
+    let deadline = HeadDeadline {
+      at: started.unwrap_or(received) + limits.header_deadline,
+      read_timeout: limits.header_read_timeout,
+    };
+    // The handshake is done once the first read returns.
+    if served == 0 {
+      conn.client_cert = conn.writer.client_cert().map(Arc::new);
+      conn.negotiated = conn.writer.negotiated();
+    }
+
+    let head = match read_head(&mut conn.reader, limits, deadline) {
+      Ok(head) => head,
+      Err(HeadError::Closed) => return,
+      Err(HeadError::TimedOut(phase)) => {
+        stats.count_timeout(phase);
+        write_status(&mut conn.writer, StatusCode(408));
+        queue.log_refused(&remote_addr, StatusCode(408), received);
+        return;
+      }
+      Err(HeadError::Status(status)) => {
+        write_status(&mut conn.writer, status);
+        queue.log_refused(&remote_addr, status, received);
+        return;
+      }
+      Err(HeadError::Oversized(limit)) => {
+        let status = stats.refuse_oversized(limit);
+        write_status(&mut conn.writer, status);
+        queue.log_refused(&remote_addr, status, received);
+        return;
+      }
+    };
+    served = served.saturating_add(1);
+
+    // The worker reads the body, a read at a time, within the body timeout.
+    let _ = conn.reader.get_ref().set_read_timeout(Some(limits.body_read_timeout));
+    // The worker sends the connection back once it has responded, if it
+    // can stay open.
+    let (done, returned) = mpsc::channel();
+    let mut request = Request::new(head, conn, remote_addr.clone(), limits, served, stats, done);
+    request.received = received;
+    queue.push(request);
+    match returned.recv() {
+      Ok(next) => conn = next,
+      Err(_) => return,
+    }
+  }
+}
+
+/// The request line and headers of a request.
+struct Head {
+  method: Method,
+  url: String,
+  version: HTTPVersion,
+  headers: Vec<Header>,
+  body: BodyLength,
+}
+
+enum BodyLength {
+  Fixed(u64),
+  Chunked,
+}
+
+enum HeadError {
+  /// The connection closed; nothing is sent.
+  Closed,
+  /// The client stalled partway through the head, or went past the
+  /// deadline for it; it is counted under the phase, and `408` sent before
+  /// closing.
+  TimedOut(Phase),
+  /// The request is invalid; the status is sent before closing.
+  Status(StatusCode),
+  /// The request goes past a limit; it is counted, and its status sent
+  /// before closing.
+  Oversized(Oversized),
+}
+
+impl Head {
+  fn header(&self, name: &'static str) -> Option<&str> {
+    self
+      .headers
+      .iter()
+      .find(|h| h.field.equiv(name))
+      .map(|h| h.value.as_str())
+  }
+
+  /// Whether the client wants the connection kept open after this request.
+  fn keep_alive(&self) -> bool {
+    let connection = self.header("Connection").unwrap_or("").to_ascii_lowercase();
+    if connection.contains("close") || connection.contains("upgrade") {
+      return false;
+    }
+    // HTTP/1.0 connections close unless the client asks otherwise.
+    self.version != (1, 0) || connection.contains("keep-alive")
+  }
+}
+
+/// When a request's head must be complete, and how long one read of it may
+/// stall before then.
+#[derive(Clone, Copy)]
+struct HeadDeadline {
+  at: Instant,
+  read_timeout: Duration,
+}
+
+impl HeadDeadline {
+  fn passed(&self) -> bool {
+    Instant::now() >= self.at
+  }
+
+  /// Sets the timeout of the next read on `stream`: the stall timeout, or
+  /// less if the deadline is nearer. Fails once the deadline has passed.
+  fn arm(&self, stream: &Stream) -> Result<(), HeadError> {
+    let left = self.at.saturating_duration_since(Instant::now());
+    if left.is_zero() {
+      return Err(HeadError::TimedOut(Phase::HeaderDeadline));
+    }
+    stream
+      .set_read_timeout(Some(left.min(self.read_timeout)))
+      .map_err(|_| HeadError::Closed)
+  }
+
+  /// The error for a read of the head that failed with `e`.
+  fn read_error(&self, e: &io::Error) -> HeadError {
+    if !is_timeout(e) {
+      HeadError::Closed
+    } else if self.passed() {
+      HeadError::TimedOut(Phase::HeaderDeadline)
+    } else {
+      HeadError::TimedOut(Phase::Header)
+    }
+  }
+}
+
+/// Reads one line of the request head, without its line ending, failing
+/// with `too_long` if it is longer than `max` bytes. Each read is armed
+/// against `deadline`, so a line trickled in a byte at a time still can't
+/// outlast it.
+fn read_line(
+  reader: &mut BufReader<Stream>,
+  max: usize,
+  too_long: Oversized,
+  deadline: HeadDeadline,
+) -> Result<String, HeadError> {
+  let mut line = Vec::new();
+  loop {
+    deadline.arm(reader.get_ref())?;
+    let available = match reader.fill_buf() {
+      Ok(available) => available,
+      Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+      Err(e) => return Err(deadline.read_error(&e)),
+    };
+    if available.is_empty() {
+      return Err(HeadError::Closed);
+    }
+    let window = &available[..available.len().min(max + 1 - line.len())];
+    if let Some(end) = window.iter().position(|&b| b == b'\n') {
+      line.extend_from_slice(&window[..=end]);
+      reader.consume(end + 1);
+      break;
+    }
+    let read = window.len();
+    line.extend_from_slice(window);
+    reader.consume(read);
+    if line.len() > max {
+      return Err(HeadError::Oversized(too_long));
+    }
+  }
+  line.pop();
+  if line.last() == Some(&b'\r') {
+    line.pop();
+  }
+  String::from_utf8(line).map_err(|_| HeadError::Status(StatusCode(400)))
+}
+
+/// Reads a request's head, refusing it as soon as it goes past one of
+/// `limits`, before the rest is read.
+fn read_head(
+  reader: &mut BufReader<Stream>,
+  limits: &Limits,
+  deadline: HeadDeadline,
+) -> Result<Head, HeadError> {
+  let bad_request = HeadError::Status(StatusCode(400));
+
+  // A client may send an empty line before the request line (RFC 9112,
+  // section 2.2).
+  let max_request_line = limits.max_url_bytes + REQUEST_LINE_SLACK;
+  let mut line = read_line(reader, max_request_line, Oversized::Url, deadline)?;
+  if line.is_empty() {
+    line = read_line(reader, max_request_line, Oversized::Url, deadline)?;
+  }
+
+  let mut parts = line.split(' ');
+  let (Some(method), Some(url), Some(version), None) =
+    (parts.next(), parts.next(), parts.next(), parts.next())
+  else {
+    return Err(bad_request);
+  };
+  if url.len() > limits.max_url_bytes {
+    return Err(HeadError::Oversized(Oversized::Url));
+  }
+  let method = Method::from_str(method).map_err(|_| HeadError::Status(StatusCode(400)))?;
+  if method.as_str().is_empty() || url.is_empty() {
+    return Err(bad_request);
+  }
+  let version = match version {
+    "HTTP/1.1" => HTTPVersion(1, 1),
+    "HTTP/1.0" => HTTPVersion(1, 0),
+    other if other.starts_with("HTTP/") => return Err(HeadError::Status(StatusCode(505))),
+    _ => return Err(bad_request),
+  };
+
+  let mut headers = Vec::new();
+  let mut total = 0;
+  loop {
+    // A line is cut off at whichever limit it would reach first. The blank
+    // line ending the head always fits.
+    let remaining = limits.max_header_total_bytes.saturating_sub(total).max(2);
+    let (max, too_long) = if remaining < limits.max_header_bytes {
+      (remaining, Oversized::HeaderTotal)
+    } else {
+      (limits.max_header_bytes, Oversized::HeaderSize)
+    };
+    let line = read_line(reader, max, too_long, deadline)?;
+    if line.is_empty() {
+      break;
+    }
+    // A line folded onto the one before (obs-fold) is refused rather than
+    // unfolded, as a proxy in front may not have read it that way (RFC 9112,
+    // section 5.2).
+    if line.starts_with([' ', '\t']) {
+      return Err(bad_request);
+    }
+    if headers.len() >= limits.max_headers {
+      return Err(HeadError::Oversized(Oversized::HeaderCount));
+    }
+    total += line.len();
+    let Some((name, value)) = line.split_once(':') else {
+      return Err(bad_request);
+    };
+    // Whitespace before the colon is rejected rather than trimmed, since
+    // proxies disagree on what it means (RFC 9112, section 5.1).
+    if name.is_empty() || name.contains(|c: char| c.is_ascii_whitespace()) {
+      return Err(bad_request);
+    }
+    let header =
+      Header::from_bytes(name, value.trim()).map_err(|_| HeadError::Status(StatusCode(400)))?;
+    headers.push(header);
+  }
+
+  let values = |name: &'static str| {
+    headers
+      .iter()
+      .filter(move |h| h.field.equiv(name))
+      .map(|h| h.value.as_str())
+  };
+  let body = body_length(values("Transfer-Encoding"), values("Content-Length"))
+    .ok_or(HeadError::Status(StatusCode(400)))?;
+  Ok(Head {
+    method,
+    url: url.to_string(),
+    version,
+    headers,
+    body,
+  })
+}
+
+/// How a request's body is framed, from all of its `Transfer-Encoding` and
+/// `Content-Length` values, or `None` if a proxy in front might frame it
+/// differently and so let a second request be smuggled in after it (RFC
+/// 9112, section 6.3): both headers at once, an encoding other than one
+/// `chunked`, or lengths that disagree or aren't plain digits. The request
+/// is then answered with `400` and the connection closed.
+fn body_length<'a>(
+  transfer_encodings: impl Iterator<Item = &'a str>,
+  content_lengths: impl Iterator<Item = &'a str>,
+) -> Option<BodyLength> {
+  let encodings: Vec<&str> = transfer_encodings.collect();
+  let mut length = None;
+  // Repeated lengths, in separate headers or one list, are allowed only if
+  // they are all the same.
+  for value in content_lengths.flat_map(|value| value.split(',')) {
+    let value = value.trim();
+    // `u64::from_str` would also take a leading `+`.
+    if value.is_empty() || !value.bytes().all(|b| b.is_ascii_digit()) {
+      return None;
+    }
+    let value: u64 = value.parse().ok()?;
+    if length.is_some_and(|length| length != value) {
+      return None;
+    }
+    length = Some(value);
+  }
+  match (encodings.as_slice(), length) {
+    ([], length) => Some(BodyLength::Fixed(length.unwrap_or(0))),
+    ([encoding], None) if encoding.trim().eq_ignore_ascii_case("chunked") => {
+      Some(BodyLength::Chunked)
+    }
+    _ => None,
+  }
+}
+
+/// A request body, read straight from the connection.
+enum Body {
+  Fixed(io::Take<BufReader<Stream>>),
+  Chunked {
+    decoder: Decoder<BufReader<Stream>>,
+    done: bool,
+  },
+  /// Passed on by hyper, with the `async` feature.
+  #[cfg(feature = "async")]
+  Stream(hyper_backend::BodyReader),
+  /// Given whole, for a request made with `Request::local`.
+  Memory(Cursor<Vec<u8>>),
+}
+
+impl Read for Body {
+  fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+    match self {
+      Body::Fixed(reader) => reader.read(buf),
+      Body::Chunked { done: true, .. } => Ok(0),
+      Body::Chunked { decoder, done } => {
+        let read = decoder.read(buf)?;
+        if read == 0 && !buf.is_empty() {
+          *done = true;
+        }
+        Ok(read)
+      }
+      #[cfg(feature = "async")]
+      Body::Stream(reader) => reader.read(buf),
+      Body::Memory(reader) => reader.read(buf),
+    }
+  }
+}
+
+impl Body {
+  /// Wraps the body so a read that times out is counted in `stats`.
+  fn counted(self, stats: Option<Arc<ConnectionStats>>) -> CountedBody {
+    CountedBody {
+      body: self,
+      stats,
+      capture: None,
+    }
+  }
+
+  /// Discards what is left of the body and returns the connection's reader,
+  /// positioned at the next request. Returns `None` if the body couldn't be
+  /// read to its end.
+  fn finish(mut self) -> Option<BufReader<Stream>> {
+    let drained = io::copy(&mut (&mut self).take(MAX_DRAIN_BYTES + 1), &mut io::sink()).ok()?;
+    if drained > MAX_DRAIN_BYTES {
+      return None;
+    }
+    match self {
+      Body::Fixed(reader) if reader.limit() == 0 => Some(reader.into_inner()),
+      Body::Chunked {
+        decoder,
+        done: true,
+      } => Some(decoder.into_inner()),
+      _ => None,
+    }
+  }
+}
+
+/// A request body that counts the first of its reads to time out, after
+/// which the connection is closed, and can keep its start for a debug dump
+/// (see `debug_dump`).
+struct CountedBody {
+  body: Body,
+  stats: Option<Arc<ConnectionStats>>,
+  /// The start of the body as it is read, with `Request::capture_body`.
+  capture: Option<Capture>,
+}
+
+/// The first `limit` bytes read from a body, and how many were read in all.
+struct Capture {
+  bytes: Vec<u8>,
+  limit: usize,
+  total: u64,
+}
+
+impl Read for CountedBody {
+  fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+    let result = self.body.read(buf);
+    match &result {
+      Ok(n) => {
+        if let Some(capture) = &mut self.capture {
+          let kept = (*n).min(capture.limit - capture.bytes.len());
+          capture.bytes.extend_from_slice(&buf[..kept]);
+          capture.total += *n as u64;
+        }
+      }
+      Err(e) if is_timeout(e) => {
+        if let Some(stats) = self.stats.take() {
+          stats.count_timeout(Phase::Body);
+        }
+      }
+      Err(_) => {}
+    }
+    result
+  }
+}
+
+/// An HTTP request waiting for a response.
+pub struct Request {
+  method: Method,
+  url: String,
+  version: HTTPVersion,
+  headers: Vec<Header>,
+  remote_addr: RemoteAddr,
+  /// Whether the request came over TLS.
+  secure: bool,
+  /// The certificate the client presented over TLS, if any.
+  client_cert: Option<Arc<ClientCert>>,
+  /// The TLS version and cipher suite of the connection, over TLS.
+  negotiated: Option<Negotiated>,
+  body: CountedBody,
+  responder: Responder,
+  /// The `Server` header added to a response that doesn't set one; `None`
+  /// sends none.
+  server_header: Option<Arc<str>>,
+  /// Counts the connection timing out while the response is written.
+  stats: Option<Arc<ConnectionStats>>,
+  /// Whether the response asked to keep the connection open (`true`) or
+  /// close it (`false`), with its `Connection` header.
+  connection: Option<bool>,
+  /// When the request's first byte arrived.
+  received: Instant,
+  /// Where the response is logged; `None` logs nothing.
+  request_log: Option<Arc<RequestLog>>,
+  /// Where the response is counted, and the route label it is counted
+  /// under; `None` counts nothing.
+  metrics: Option<(Arc<RequestMetrics>, String)>,
+  /// The request's span, sent once the response is written; `None` sends
+  /// none.
+  span: Option<ServerSpan>,
+}
+
+/// Where a request's response goes.
+enum Responder {
+  /// Written to the connection, which is then handed back to its thread.
+  Connection {
+    writer: Stream,
+    /// Whether the connection stays open unless the response says
+    /// otherwise.
+    keep_alive: bool,
+    /// Whether it can stay open at all: the client didn't ask to close it
+    /// and it hasn't reached `max_requests_per_connection`.
+    reusable: bool,
+    /// Whether the client sent `Expect: 100-continue` and hasn't been told
+    /// to go ahead yet.
+    expects_continue: bool,
+    done: mpsc::Sender<Connection>,
+  },
+  /// Sent to the hyper task serving the connection, with the `async`
+  /// feature.
+  #[cfg(feature = "async")]
+  Channel(tokio::sync::oneshot::Sender<hyper_backend::Reply>),
+  /// Sent back to the caller of `Request::local`.
+  Local(mpsc::Sender<LocalResponse>),
+}
+
+/// The response to a request made with `Request::local`.
+pub struct LocalResponse {
+  pub status: u16,
+  pub headers: Vec<Header>,
+  /// The body, which is empty for a `HEAD` request.
+  pub body: Vec<u8>,
+}
+
+impl Request {
+  /// Creates the `served`th request read from `conn`.
+  fn new(
+    head: Head,
+    conn: Connection,
+    remote_addr: RemoteAddr,
+    limits: &Limits,
+    served: u32,
+    stats: &Arc<ConnectionStats>,
+    done: mpsc::Sender<Connection>,
+  ) -> Request {
+    let compat = limits.version_compat && head.version == (1, 0);
+    let reusable = head.keep_alive()
+      && limits
+        .max_requests_per_connection
+        .is_none_or(|max| served < max);
+    let keep_alive = reusable && limits.keep_alive && !compat;
+    // HTTP/1.0 has no `100 Continue` (RFC 9110, section 10.1.1), and a
+    // client of it sends its body regardless.
+    let expects_continue = !compat
+      && head
+        .header("Expect")
+        .is_some_and(|value| value.eq_ignore_ascii_case("100-continue"));
+    let body = match head.body {
+      BodyLength::Fixed(length) => Body::Fixed(conn.reader.take(length)),
+      BodyLength::Chunked => Body::Chunked {
+        decoder: Decoder::new(conn.reader),
+        done: false,
+      },
+    }
+    .counted(Some(stats.clone()));
+    Request {
+      method: head.method,
+      url: head.url,
+      version: head.version,
+      headers: head.headers,
+      remote_addr,
+      secure: conn.writer.is_tls(),
+      client_cert: conn.client_cert,
+      negotiated: conn.negotiated,
+      body,
+      responder: Responder::Connection {
+        writer: conn.writer,
+        keep_alive,
+        reusable,
+        expects_continue,
+        done,
+      },
+      server_header: None,
+      stats: Some(stats.clone()),
+      connection: None,
+      received: Instant::now(),
+      request_log: None,
+      metrics: None,
+      span: None,
+    }
+  }
+
+  /// Creates a request that didn't arrive on a connection, for
+  /// `FyreServer::handle`. It comes from `127.0.0.1`, and its response is
+  /// sent to `reply` instead of being written anywhere.
+  pub fn local(
+    method: Method,
+    url: String,
+    headers: Vec<Header>,
+    body: Vec<u8>,
+    reply: mpsc::Sender<LocalResponse>,
+  ) -> Request {
+    Request {
+      method,
+      url,
+      version: HTTPVersion(1, 1),
+      headers,
+      remote_addr: RemoteAddr::Tcp(SocketAddr::from(([127, 0, 0, 1], 0))),
+      secure: false,
+      client_cert: None,
+      negotiated: None,
+      body: Body::Memory(Cursor::new(body)).counted(None),
+      responder: Responder::Local(reply),
+      server_header: None,
+      stats: None,
+      connection: None,
+      received: Instant::now(),
+      request_log: None,
+      metrics: None,
+      span: None,
+    }
+  }
+
+  /// Returns the request method.
+  pub fn method(&self) -> &Method {
+    &self.method
+  }
+
+  /// Returns the request target as sent, including any query string.
+  pub fn url(&self) -> &str {
+    &self.url
+  }
+
+  /// Returns the request headers in the order they were sent.
+  pub fn headers(&self) -> &[Header] {
+    &self.headers
+  }
+
+  /// Returns the HTTP version of the request.
+  pub fn http_version(&self) -> &HTTPVersion {
+    &self.version
+  }
+
+  /// Returns the client's address.
+  pub fn remote_addr(&self) -> &RemoteAddr {
+    &self.remote_addr
+  }
+
+  /// Returns `"https"` for a request that came over TLS, `"http"`
+  /// otherwise.
+  pub fn scheme(&self) -> &'static str {
+    if self.secure {
+      "https"
+    } else {
+      "http"
+    }
+  }
+
+  /// Returns the certificate the client presented over TLS, verified
+  /// against the CAs in `CONFIG.tls.client_ca`, if it presented one.
+  pub fn client_cert(&self) -> Option<&ClientCert> {
+    self.client_cert.as_deref()
+  }
+
+  /// Returns the TLS version and cipher suite the connection negotiated,
+  /// for a request that came over TLS.
+  pub fn tls_negotiated(&self) -> Option<Negotiated> {
+    self.negotiated
+  }
+
+  /// Returns a reader over the request body.
+  pub fn as_reader(&mut self) -> &mut dyn Read {
+    if let Responder::Connection {
+      writer,
+      expects_continue,
+      ..
+    } = &mut self.responder
+    {
+      if *expects_continue {
+        *expects_continue = false;
+        let _ = writer.write_all(b"HTTP/1.1 100 Continue\r\n\r\n");
+      }
+    }
+    &mut self.body
+  }
+
+  /// Sets what the response's `Connection` header asks for, since
+  /// tiny_http drops the header itself: `close` closes the connection, and
+  /// `keep-alive` keeps it open if the client and the limits allow.
+  /// Other values are ignored.
+  pub fn set_connection(&mut self, value: &str) {
+    let value = value.trim();
+    if value.eq_ignore_ascii_case("close") {
+      self.connection = Some(false);
+    } else if value.eq_ignore_ascii_case("keep-alive") {
+      self.connection = Some(true);
+    }
+  }
+
+  /// Counts the response in `metrics` under `route` once it is written.
+  pub fn count_in(&mut self, metrics: Arc<RequestMetrics>, route: String) {
+    self.metrics = Some((metrics, route));
+  }
+
+  /// Keeps the first `limit` bytes of the body as it is read, for
+  /// `captured_body`.
+  pub fn capture_body(&mut self, limit: usize) {
+    self.body.capture = Some(Capture {
+      bytes: Vec::new(),
+      limit,
+      total: 0,
+    });
+  }
+
+  /// Returns the body captured since `capture_body`, and how many bytes of
+  /// it were read in all.
+  pub fn captured_body(&mut self) -> Option<(Vec<u8>, u64)> {
+    self
+      .body
+      .capture
+      .take()
+      .map(|capture| (capture.bytes, capture.total))
+  }
+
+  /// Sends `span` for export, with the response's status, once it is
+  /// written.
+  pub fn export_span(&mut self, span: ServerSpan) {
+    self.span = Some(span);
+  }
+
+  /// Answers `503` and closes the connection, for a request that arrives
+  /// while the server is stopping.
+  fn refuse(mut self) {
+    if let Responder::Connection { reusable, .. } = &mut self.responder {
+      *reusable = false;
+    }
+    let status = StatusCode(503);
+    let response = Response::from_string(status.default_reason_phrase()).with_status_code(status);
+    let _ = self.respond(response);
+  }
+
+  /// Sends `response` and hands the connection back for its next request.
+  ///
+  /// # Errors
+  ///
+  /// This function will return an error if the response can't be written,
+  /// other than because the client has gone away.
+  pub fn respond<R: Read>(self, mut response: Response<R>) -> io::Result<()> {
+    let has_server = response
+      .headers()
+      .iter()
+      .any(|header| header.field.equiv("Server"));
+    if let (false, Some(value)) = (has_server, &self.server_header) {
+      if let Ok(header) = Header::from_bytes("Server", value.as_bytes()) {
+        response.add_header(header);
+      }
+    }
+    // tiny_http sends a `Server` header of its own when there is none.
+    let drop_server = !has_server && self.server_header.is_none();
+    let status = response.status_code().0;
+    let length = response.data_length();
+    let Request {
+      method,
+      url,
+      version,
+      headers,
+      remote_addr,
+      body,
+      responder,
+      stats,
+      client_cert,
+      negotiated,
+      connection,
+      received,
+      request_log,
+      metrics,
+      mut span,
+      ..
+    } = self;
+    let mut log = || {
+      if let Some((metrics, route)) = &metrics {
+        metrics.record(route, status, received.elapsed());
+      }
+      if let Some(span) = span.take() {
+        span.finish(status, received.elapsed());
+      }
+      if let Some(log) = &request_log {
+        log.record(&Entry {
+          remote_addr: &remote_addr,
+          request: Some((&method, url.as_str(), &version)),
+          headers: &headers,
+          status,
+          bytes: if method == Method::Head { Some(0) } else { length },
+          elapsed: received.elapsed(),
+        });
+      }
+    };
+    let (mut writer, keep_alive, expects_continue, done) = match responder {
+      Responder::Connection {
+        writer,
+        keep_alive,
+        reusable,
+        expects_continue,
+        done,
+      } => (
+        writer,
+        reusable && connection.unwrap_or(keep_alive),
+        expects_continue,
+        done,
+      ),
+      #[cfg(feature = "async")]
+      Responder::Channel(reply) => {
+        let result = hyper_backend::send(reply, response, connection);
+        log();
+        return result;
+      }
+      Responder::Local(reply) => {
+        let status = response.status_code().0;
+        let headers = response.headers().to_vec();
+        let mut body = Vec::new();
+        if method != Method::Head {
+          response.into_reader().read_to_end(&mut body)?;
+        }
+        let _ = reply.send(LocalResponse {
+          status,
+          headers,
+          body,
+        });
+        return Ok(());
+      }
+    };
+    // A client still waiting for `100 Continue` may or may not send its
+    // body, so the connection can't be reused.
+    let keep_alive = keep_alive && !expects_continue;
+    let connection = if !keep_alive {
+      "close"
+    } else if version == (1, 0) {
+      "keep-alive"
+    } else {
+      ""
+    };
+
+    let result = {
+      let mut out = ConnectionHeader::new(BufWriter::new(&mut writer), connection, drop_server);
+      response
+        .raw_print(&mut out, version.clone(), &headers, method == Method::Head, None)
+        .and_then(|()| out.flush())
+    };
+    // A client too slow to take the response is counted, and its
+    // connection closed, rather than reported as an error.
+    let timed_out = matches!(&result, Err(e) if is_timeout(e));
+    if let (true, Some(stats)) = (timed_out, &stats) {
+      stats.count_timeout(Phase::Write);
+    }
+    let result = result.or_else(|e| match e.kind() {
+      io::ErrorKind::BrokenPipe
+      | io::ErrorKind::ConnectionAborted
+      | io::ErrorKind::ConnectionReset => Ok(()),
+      _ if timed_out => Ok(()),
+      _ => Err(e),
+    });
+    log();
+
+    if result.is_ok() && keep_alive && !timed_out {
+      if let Some(reader) = body.body.finish() {
+        let _ = done.send(Connection {
+          reader,
+          writer,
+          client_cert,
+          negotiated,
+        });
+      }
+    }
+    result
+  }
+}
+
+/// Adds a `Connection` header to the response head written by
+/// `Response::raw_print`, since tiny_http drops one passed to `add_header`,
+/// and removes the `Server` header tiny_http adds when asked to.
+struct ConnectionHeader<W: Write> {
+  inner: W,
+  value: &'static str,
+  drop_server: bool,
+  /// The head written so far, until its blank line has been seen.
+  head: Option<Vec<u8>>,
+}
+
+impl<W: Write> ConnectionHeader<W> {
+  /// Wraps `inner`; an empty `value` adds no header.
+  fn new(inner: W, value: &'static str, drop_server: bool) -> Self {
+    ConnectionHeader {
+      inner,
+      value,
+      drop_server,
+      head: (!value.is_empty() || drop_server).then(Vec::new),
+    }
+  }
+}
+
+impl<W: Write> Write for ConnectionHeader<W> {
+  fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+    let Some(head) = &mut self.head else {
+      return self.inner.write(buf);
+    };
+    head.extend_from_slice(buf);
+    if let Some(end) = head.windows(4).position(|w| w == b"\r\n\r\n") {
+      let mut out = Vec::with_capacity(head.len() + 32);
+      for line in head[..end + 2].split_inclusive(|&b| b == b'\n') {
+        if self.drop_server && line.len() >= 7 && line[..7].eq_ignore_ascii_case(b"server:") {
+          continue;
+        }
+        out.extend_from_slice(line);
+      }
+      if !self.value.is_empty() {
+        out.extend_from_slice(format!("Connection: {}\r\n", self.value).as_bytes());
+      }
+      out.extend_from_slice(&head[end + 2..]);
+      self.head = None;
+      self.inner.write_all(&out)?;
+    }
+    Ok(buf.len())
+  }
+
+  fn flush(&mut self) -> io::Result<()> {
+    if let Some(head) = self.head.take() {
+      self.inner.write_all(&head)?;
+    }
+    self.inner.flush()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::testing::Fixture;
+  use std::net::TcpStream;
+
+  /// The value of the header `name` in a response head, whatever its case
+  /// (hyper writes them in lower case).
+  fn header<'a>(head: &'a str, name: &str) -> Option<&'a str> {
+    head.lines().find_map(|line| {
+      let (key, value) = line.split_once(':')?;
+      key.eq_ignore_ascii_case(name).then(|| value.trim())
+    })
+  }
+
+  /// Sends `request` on `stream` and returns the response's head and body,
+  /// read by its `Content-Length`.
+  fn exchange(stream: &mut TcpStream, request: &str) -> (String, String) {
+    stream.write_all(request.as_bytes()).unwrap();
+    let mut head = Vec::new();
+    let mut byte = [0];
+    while !head.ends_with(b"\r\n\r\n") {
+      stream.read_exact(&mut byte).unwrap();
+      head.push(byte[0]);
+    }
+    let head = String::from_utf8(head).unwrap();
+    let length =
+      header(&head, "Content-Length").unwrap_or_else(|| panic!("no Content-Length: {}", head));
+    let mut body = vec![0; length.parse().unwrap()];
+    stream.read_exact(&mut body).unwrap();
+    (head, String::from_utf8(body).unwrap())
+  }
+
+  /// Whether the server left `stream` open after its response.
+  fn is_open(stream: &mut TcpStream) -> bool {
+    stream
+      .set_read_timeout(Some(Duration::from_millis(200)))
+      .unwrap();
+    match stream.read(&mut [0]) {
+      Ok(0) => false,
+      Ok(_) => panic!("more than one response"),
+      Err(e) => {
+        assert!(is_timeout(&e), "{}", e);
+        true
+      }
+    }
+  }
+
+  const HELLO: &str =
+    r#"return { handler = function(request, response) response.body = "hello" end }"#;
+
+  /// A handler whose response sets `Connection` to its path, `/close` or
+  /// `/keep-alive`, without the slash.
+  const CONNECTION: &str = r#"
+    return {
+      handler = function(request, response)
+        response.headers["Connection"] = request.path:sub(2)
+        response.body = "hello"
+      end,
+    }
+  "#;
+
+  fn server(keep_alive: bool, version_compat: bool) -> (Fixture, crate::FyreServer, String) {
+    let fixture = Fixture::new(
+      &format!(
+        r#"
+          CONFIG = {{ http = {{ keep_alive = {}, version_compat = {} }} }}
+          router.add("/hello", "hello.lua")
+          router.add("/close", "connection.lua")
+          router.add("/keep-alive", "connection.lua")
+        "#,
+        keep_alive, version_compat
+      ),
+      &[("hello.lua", HELLO), ("connection.lua", CONNECTION)],
+    );
+    let server = fixture
+      .builder()
+      .addr("127.0.0.1:0")
+      .workers(1)
+      .load()
+      .unwrap();
+    let addr = server.serve().unwrap().remove(0);
+    (fixture, server, addr)
+  }
+
+  #[test]
+  fn keep_alive_and_version_compat_frame_each_version() {
+    const HTTP_10: &str = "GET /hello HTTP/1.0\r\nConnection: keep-alive\r\n\r\n";
+    const HTTP_11: &str = "GET /hello HTTP/1.1\r\nHost: localhost\r\n\r\n";
+    // (keep_alive, version_compat, request, kept open)
+    let cases = [
+      (true, false, HTTP_10, true),
+      (true, false, HTTP_11, true),
+      (true, true, HTTP_10, false),
+      (true, true, HTTP_11, true),
+      (false, false, HTTP_10, false),
+      (false, false, HTTP_11, false),
+      (false, true, HTTP_10, false),
+      (false, true, HTTP_11, false),
+    ];
+    for (keep_alive, version_compat, request, kept_open) in cases {
+      let case = format!(
+        "keep_alive = {}, version_compat = {}, {}",
+        keep_alive,
+        version_compat,
+        request.lines().next().unwrap()
+      );
+      let (_fixture, server, addr) = server(keep_alive, version_compat);
+      let mut stream = TcpStream::connect(&addr).unwrap();
+      let (head, body) = exchange(&mut stream, request);
+      assert_eq!(body, "hello", "{}", case);
+      assert_eq!(
+        header(&head, "Transfer-Encoding"),
+        None,
+        "{}: {}",
+        case,
+        head
+      );
+      let connection = header(&head, "Connection");
+      let expected = match (kept_open, request == HTTP_10) {
+        (false, _) => Some("close"),
+        (true, true) => Some("keep-alive"),
+        (true, false) => None,
+      };
+      assert_eq!(connection, expected, "{}: {}", case, head);
+      assert_eq!(is_open(&mut stream), kept_open, "{}", case);
+      server.shutdown();
+    }
+  }
+
+  #[test]
+  fn a_response_decides_its_own_connection() {
+    let (_fixture, server, addr) = server(false, false);
+    let mut stream = TcpStream::connect(&addr).unwrap();
+    let request = "GET /keep-alive HTTP/1.1\r\nHost: localhost\r\n\r\n";
+    let (head, _) = exchange(&mut stream, request);
+    assert_eq!(header(&head, "Connection"), None, "{}", head);
+    let request = "GET /close HTTP/1.1\r\nHost: localhost\r\n\r\n";
+    let (head, _) = exchange(&mut stream, request);
+    assert_eq!(header(&head, "Connection"), Some("close"), "{}", head);
+    assert!(!is_open(&mut stream));
+    server.shutdown();
+  }
+
+  #[test]
+  fn a_head_sent_too_slowly_is_cut_off_but_a_slow_body_is_not() {
+    let fixture = Fixture::new(
+      r#"
+        CONFIG = { timeouts = { header_deadline_ms = 500, body_read_ms = 2000 } }
+        router.add("/upload", "upload.lua")
+      "#,
+      &[(
+        "upload.lua",
+        r#"return { handler = function(request, response) response.body = request.body end }"#,
+      )],
+    );
+    let server = fixture
+      .builder()
+      .addr("127.0.0.1:0")
+      .workers(1)
+      .load()
+      .unwrap();
+    let addr = server.serve().unwrap().remove(0);
+
+    // Sends a header a byte every 100 ms, each well within the header read
+    // timeout, until the server stops taking them.
+    let mut slow = TcpStream::connect(&addr).unwrap();
+    let started = Instant::now();
+    let mut dribble = slow.try_clone().unwrap();
+    let dribbler = std::thread::spawn(move || {
+      dribble
+        .write_all(b"GET /upload HTTP/1.1\r\nX-Slow: ")
+        .unwrap();
+      while started.elapsed() < Duration::from_secs(5) && dribble.write_all(b"a").is_ok() {
+        std::thread::sleep(Duration::from_millis(100));
+      }
+    });
+    slow.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    let mut response = Vec::new();
+    // The server may reset the connection, having left bytes unread. With
+    // the `async` feature, hyper closes it without an answer or a count.
+    match slow.read_to_end(&mut response) {
+      Ok(_) if cfg!(feature = "async") => assert!(response.is_empty(), "{:?}", response),
+      Ok(_) => assert!(response.starts_with(b"HTTP/1.1 408"), "{:?}", response),
+      Err(e) => assert_eq!(e.kind(), io::ErrorKind::ConnectionReset, "{}", e),
+    }
+    let cut_off = started.elapsed();
+    assert!(cut_off < Duration::from_secs(2), "{:?}", cut_off);
+    if !cfg!(feature = "async") {
+      assert_eq!(server.state.connections.timed_out(Phase::HeaderDeadline), 1);
+    }
+
+    // The deadline is for the head only: a body may take longer.
+    let mut upload = TcpStream::connect(&addr).unwrap();
+    upload
+      .write_all(b"POST /upload HTTP/1.1\r\nHost: localhost\r\nContent-Length: 10\r\n\r\n")
+      .unwrap();
+    for byte in b"0123456789" {
+      std::thread::sleep(Duration::from_millis(100));
+      upload.write_all(&[*byte]).unwrap();
+    }
+    let (head, body) = exchange(&mut upload, "");
+    assert!(head.starts_with("HTTP/1.1 200"), "{}", head);
+    assert_eq!(body, "0123456789");
+    server.shutdown();
+    dribbler.join().unwrap();
+  }
+
+  /// `body_length` of the given `Transfer-Encoding` and `Content-Length`
+  /// header values.
+  fn length(encodings: &[&str], lengths: &[&str]) -> Option<BodyLength> {
+    body_length(encodings.iter().copied(), lengths.iter().copied())
+  }
+
+  #[test]
+  fn body_length_takes_one_unambiguous_framing() {
+    assert!(matches!(length(&[], &[]), Some(BodyLength::Fixed(0))));
+    assert!(matches!(length(&[], &["42"]), Some(BodyLength::Fixed(42))));
+    assert!(matches!(
+      length(&["chunked"], &[]),
+      Some(BodyLength::Chunked)
+    ));
+    assert!(matches!(
+      length(&[" Chunked "], &[]),
+      Some(BodyLength::Chunked)
+    ));
+    // Repeated lengths that agree, in separate headers or one list.
+    assert!(matches!(
+      length(&[], &["42", "42"]),
+      Some(BodyLength::Fixed(42))
+    ));
+    assert!(matches!(
+      length(&[], &["42, 42"]),
+      Some(BodyLength::Fixed(42))
+    ));
+    let largest = u64::MAX.to_string();
+    assert!(matches!(
+      length(&[], &[&largest]),
+      Some(BodyLength::Fixed(u64::MAX))
+    ));
+  }
+
+  #[test]
+  fn body_length_refuses_ambiguous_framing() {
+    let refused: &[(&[&str], &[&str])] = &[
+      // Conflicting lengths.
+      (&[], &["42", "43"]),
+      (&[], &["42, 43"]),
+      (&[], &["42", ""]),
+      // Lengths that aren't plain digits.
+      (&[], &["+42"]),
+      (&[], &["-1"]),
+      (&[], &["0x2a"]),
+      (&[], &["4 2"]),
+      (&[], &[""]),
+      // Past the largest length there can be.
+      (&[], &["18446744073709551616"]),
+      // Both headers at once.
+      (&["chunked"], &["42"]),
+      (&["chunked"], &["0"]),
+      // Any encoding but one `chunked`.
+      (&["gzip"], &[]),
+      (&["gzip, chunked"], &[]),
+      (&["chunked", "chunked"], &[]),
+      (&["chunked, identity"], &[]),
+      (&[""], &[]),
+    ];
+    for (encodings, lengths) in refused {
+      assert!(
+        length(encodings, lengths).is_none(),
+        "Transfer-Encoding {:?}, Content-Length {:?}",
+        encodings,
+        lengths
+      );
+    }
+  }
+
+  #[test]
+  fn ambiguous_framing_is_answered_with_400_and_closed() {
+    let (_fixture, server, addr) = server(true, false);
+    let mut framings = vec![
+      "Content-Length: 5\r\nContent-Length: 6",
+      "Transfer-Encoding: gzip, chunked",
+      "X-Folded: a\r\n b",
+    ];
+    if cfg!(feature = "async") {
+      // hyper drops the `Content-Length`, reads the body as chunked, and
+      // closes the connection after it.
+      let mut stream = TcpStream::connect(&addr).unwrap();
+      let request = "POST /hello HTTP/1.1\r\nHost: localhost\r\n\
+        Content-Length: 5\r\nTransfer-Encoding: chunked\r\n\r\n0\r\n\r\n";
+      let (head, body) = exchange(&mut stream, request);
+      assert_eq!(body, "hello", "{}", head);
+      assert!(!is_open(&mut stream));
+    } else {
+      framings.push("Content-Length: 5\r\nTransfer-Encoding: chunked");
+    }
+    for framing in framings {
+      let mut stream = TcpStream::connect(&addr).unwrap();
+      let request = format!(
+        "POST /hello HTTP/1.1\r\nHost: localhost\r\n{}\r\n\r\n",
+        framing
+      );
+      stream.write_all(request.as_bytes()).unwrap();
+      let mut response = String::new();
+      stream.read_to_string(&mut response).unwrap();
+      assert!(
+        response.starts_with("HTTP/1.1 400"),
+        "{:?}: {}",
+        framing,
+        response
+      );
+      assert!(!is_open(&mut stream), "{:?}", framing);
+    }
+    server.shutdown();
+  }
+
+  /// Connects from 127.0.0.2 as well, which only Linux routes to itself
+  /// without setup.
+  #[test]
+  #[cfg(target_os = "linux")]
+  fn clients_outside_the_access_lists_are_answered_403() {
+    let fixture = Fixture::new(
+      r#"
+        CONFIG = { access = { allow = { "127.0.0.0/8", "::1" }, deny = { "127.0.0.2" } } }
+        router.add("/hello", "hello.lua")
+        router.add("/internal", "hello.lua", { allow = { "10.0.0.0/8" } })
+        router.add("/local", "hello.lua", { deny = { "2001:db8::/32" } })
+      "#,
+      &[("hello.lua", HELLO)],
+    );
+    let server = fixture
+      .builder()
+      .addr("127.0.0.1:0")
+      .workers(1)
+      .load()
+      .unwrap();
+    let port = server
+      .serve()
+      .unwrap()
+      .remove(0)
+      .rsplit_once(':')
+      .unwrap()
+      .1
+      .to_string();
+    let get = |from: &str, path: &str| {
+      let stream =
+        socket2::Socket::new(socket2::Domain::IPV4, socket2::Type::STREAM, None).unwrap();
+      stream
+        .bind(
+          &format!("{}:0", from)
+            .parse::<std::net::SocketAddr>()
+            .unwrap()
+            .into(),
+        )
+        .unwrap();
+      stream
+        .connect(
+          &format!("127.0.0.1:{}", port)
+            .parse::<std::net::SocketAddr>()
+            .unwrap()
+            .into(),
+        )
+        .unwrap();
+      let mut stream = TcpStream::from(stream);
+      let request = format!(
+        "GET {} HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n",
+        path
+      );
+      stream.write_all(request.as_bytes()).unwrap();
+      let mut response = String::new();
+      stream.read_to_string(&mut response).unwrap();
+      response
+    };
+
+    for (from, path, status) in [
+      ("127.0.0.1", "/hello", "200"),
+      ("127.0.0.1", "/local", "200"),
+      // Denied by CONFIG.access, though its allow list covers it.
+      ("127.0.0.2", "/hello", "403"),
+      // Allowed by CONFIG.access but not by the route's own list.
+      ("127.0.0.1", "/internal", "403"),
+    ] {
+      let response = get(from, path);
+      assert!(
+        response.starts_with(&format!("HTTP/1.1 {}", status)),
+        "{} {}: {}",
+        from,
+        path,
+        response
+      );
+      if status == "403" {
+        assert!(response.ends_with("\r\n\r\n403 Forbidden"), "{}", response);
+      }
+    }
+    server.shutdown();
+  }
+}
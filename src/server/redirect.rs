@@ -0,0 +1,164 @@
+//! Redirects plain HTTP requests to HTTPS, for the listener started by
+//! `CONFIG.tls.redirect_http`.
+
+use super::{
+  read_head, write_status, ConnectionStats, HeadDeadline, HeadError, Limits, Stream,
+  ACCEPT_RETRY_DELAY, REJECT_WRITE_TIMEOUT,
+};
+use std::io::{self, BufReader, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::Arc;
+use std::time::Instant;
+use tiny_http::StatusCode;
+
+/// Starts a thread answering every request on `listener` with a redirect to
+/// the same URL over HTTPS on `https_port`. Each connection gets one answer
+/// and is closed.
+///
+/// # Errors
+///
+/// This function will return an error if the thread can't be started.
+pub fn start_redirect(listener: TcpListener, https_port: u16, limits: Limits) -> io::Result<()> {
+  std::thread::Builder::new()
+    .name("redirect".to_string())
+    .spawn(move || accept_loop(listener, https_port, &limits))?;
+  Ok(())
+}
+
+fn accept_loop(listener: TcpListener, https_port: u16, limits: &Limits) {
+  let stats = Arc::new(ConnectionStats::default());
+  for stream in listener.incoming() {
+    let stream = match stream {
+      Ok(stream) => stream,
+      Err(e) => {
+        error!("Failed to accept connection: {}", e);
+        std::thread::sleep(ACCEPT_RETRY_DELAY);
+        continue;
+      }
+    };
+    let Some(guard) = stats.open(limits.max_connections) else {
+      // `reject` logs to the request log, which this listener doesn't have.
+      let mut stream = stream;
+      let _ = stream.set_write_timeout(Some(REJECT_WRITE_TIMEOUT));
+      write_status(&mut stream, StatusCode(503));
+      continue;
+    };
+    let accepted = Instant::now();
+    let (limits, stats) = (limits.clone(), stats.clone());
+    let spawned = std::thread::Builder::new()
+      .name("redirect-connection".to_string())
+      .spawn(move || {
+        let _guard = guard;
+        let timeout = limits.keep_alive_timeout;
+        if stream.set_read_timeout(Some(timeout)).is_ok() {
+          redirect(stream, https_port, &limits, &stats, accepted);
+        }
+      });
+    if let Err(e) = spawned {
+      error!("Failed to start connection thread: {}", e);
+    }
+  }
+}
+
+/// Reads one request, within the head deadline from when the connection
+/// was `accepted`, and answers it with a `301` to its HTTPS URL.
+fn redirect(
+  stream: TcpStream,
+  https_port: u16,
+  limits: &Limits,
+  stats: &ConnectionStats,
+  accepted: Instant,
+) {
+  let Ok(mut writer) = stream.try_clone() else {
+    return;
+  };
+  let mut reader = BufReader::new(Stream::Plain(stream));
+  let deadline = HeadDeadline {
+    at: accepted + limits.header_deadline,
+    read_timeout: limits.header_read_timeout,
+  };
+  let head = match read_head(&mut reader, limits, deadline) {
+    Ok(head) => head,
+    Err(HeadError::Closed) => return,
+    Err(HeadError::TimedOut(phase)) => {
+      stats.count_timeout(phase);
+      return write_status(&mut writer, StatusCode(408));
+    }
+    Err(HeadError::Status(status)) => return write_status(&mut writer, status),
+    Err(HeadError::Oversized(limit)) => {
+      return write_status(&mut writer, stats.refuse_oversized(limit))
+    }
+  };
+  let Some(host) = head.header("Host").map(host_name) else {
+    return write_status(&mut writer, StatusCode(400));
+  };
+  let location = if https_port == 443 {
+    format!("https://{}{}", host, head.url)
+  } else {
+    format!("https://{}:{}{}", host, https_port, head.url)
+  };
+  // The parser leaves a lone CR or a NUL in the `Host` or the URL, which
+  // a client behind a proxy could use to split the response.
+  if crate::fyre::check_header(b"Location", location.as_bytes()).is_err() {
+    return write_status(&mut writer, StatusCode(400));
+  }
+  let _ = writer.write_all(
+    format!(
+      "HTTP/1.1 301 Moved Permanently\r\nLocation: {}\r\nConnection: close\r\n\
+       Content-Length: 0\r\n\r\n",
+      location
+    )
+    .as_bytes(),
+  );
+}
+
+/// Returns the `Host` header without its port, keeping the brackets of an
+/// IPv6 address.
+fn host_name(host: &str) -> &str {
+  match host.rfind(':') {
+    Some(colon) if !host[colon..].contains(']') => &host[..colon],
+    _ => host,
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::io::Read;
+
+  #[test]
+  fn a_redirect_cannot_be_split_by_the_request() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    start_redirect(listener, 8443, Limits::default()).unwrap();
+    let send = |request: &[u8]| {
+      let mut stream = TcpStream::connect(addr).unwrap();
+      stream.write_all(request).unwrap();
+      let mut response = String::new();
+      stream.read_to_string(&mut response).unwrap();
+      response
+    };
+
+    let response = send(b"GET /login?next=%2F HTTP/1.1\r\nHost: example.com:8080\r\n\r\n");
+    assert!(response.starts_with("HTTP/1.1 301"), "{}", response);
+    assert!(
+      response.contains("\r\nLocation: https://example.com:8443/login?next=%2F\r\n"),
+      "{}",
+      response
+    );
+    for request in [
+      &b"GET / HTTP/1.1\r\nHost: example.com\rSet-Cookie: admin=1\r\n\r\n"[..],
+      b"GET / HTTP/1.1\r\nHost: example.com\0\r\n\r\n",
+      b"GET /\rSet-Cookie:admin=1 HTTP/1.1\r\nHost: example.com\r\n\r\n",
+    ] {
+      let response = send(request);
+      assert!(
+        response.starts_with("HTTP/1.1 400"),
+        "{:?}: {:?}",
+        String::from_utf8_lossy(request),
+        response
+      );
+      assert!(!response.contains("admin"), "{:?}", response);
+    }
+  }
+}
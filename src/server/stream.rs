@@ -0,0 +1,173 @@
+//! Plain, Unix socket, and TLS connections behind one type, so reading
+//! requests and writing responses works the same on all of them.
+
+use rustls::{ServerConnection, StreamOwned};
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, TcpStream};
+#[cfg(unix)]
+use std::os::unix::net::UnixStream;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use crate::locks;
+use crate::tls::{ClientCert, Negotiated};
+
+type TlsStream = StreamOwned<ServerConnection, TcpStream>;
+
+/// An accepted connection.
+///
+/// Cloning shares the connection like `TcpStream::try_clone`. The halves of
+/// a TLS connection take turns on a lock, which is never contended: a
+/// connection's request is read and its response written one after the
+/// other.
+pub(super) enum Stream {
+  Plain(TcpStream),
+  #[cfg(unix)]
+  Unix(UnixStream),
+  Tls(Arc<Mutex<TlsStream>>),
+}
+
+impl Stream {
+  /// Wraps `stream` in a TLS session; the handshake happens on the first
+  /// read.
+  pub(super) fn tls(stream: TcpStream, config: &Arc<rustls::ServerConfig>) -> io::Result<Stream> {
+    let conn = ServerConnection::new(config.clone()).map_err(io::Error::other)?;
+    Ok(Stream::Tls(Arc::new(Mutex::new(StreamOwned::new(conn, stream)))))
+  }
+
+  pub(super) fn try_clone(&self) -> io::Result<Stream> {
+    match self {
+      Stream::Plain(stream) => Ok(Stream::Plain(stream.try_clone()?)),
+      #[cfg(unix)]
+      Stream::Unix(stream) => Ok(Stream::Unix(stream.try_clone()?)),
+      Stream::Tls(stream) => Ok(Stream::Tls(stream.clone())),
+    }
+  }
+
+  /// Whether the connection is encrypted.
+  pub(super) fn is_tls(&self) -> bool {
+    matches!(self, Stream::Tls(_))
+  }
+
+  /// Returns the certificate the client presented, once the handshake is
+  /// done, if it sent one.
+  pub(super) fn client_cert(&self) -> Option<ClientCert> {
+    match self {
+      Stream::Tls(stream) => {
+        let stream = locks::lock(stream, "TLS connection");
+        ClientCert::from_der(stream.conn.peer_certificates()?.first()?)
+      }
+      _ => None,
+    }
+  }
+
+  /// Returns the protocol version and cipher suite, once the handshake is
+  /// done.
+  pub(super) fn negotiated(&self) -> Option<Negotiated> {
+    match self {
+      Stream::Tls(stream) => {
+        Negotiated::from_connection(&locks::lock(stream, "TLS connection").conn)
+      }
+      _ => None,
+    }
+  }
+
+  pub(super) fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+    match self {
+      Stream::Plain(stream) => stream.set_read_timeout(timeout),
+      #[cfg(unix)]
+      Stream::Unix(stream) => stream.set_read_timeout(timeout),
+      Stream::Tls(stream) => locks::lock(stream, "TLS connection")
+        .get_ref()
+        .set_read_timeout(timeout),
+    }
+  }
+
+  pub(super) fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+    match self {
+      Stream::Plain(stream) => stream.set_write_timeout(timeout),
+      #[cfg(unix)]
+      Stream::Unix(stream) => stream.set_write_timeout(timeout),
+      Stream::Tls(stream) => locks::lock(stream, "TLS connection")
+        .get_ref()
+        .set_write_timeout(timeout),
+    }
+  }
+
+  pub(super) fn shutdown(&self) -> io::Result<()> {
+    match self {
+      Stream::Plain(stream) => stream.shutdown(Shutdown::Both),
+      #[cfg(unix)]
+      Stream::Unix(stream) => stream.shutdown(Shutdown::Both),
+      Stream::Tls(stream) => locks::lock(stream, "TLS connection")
+        .get_ref()
+        .shutdown(Shutdown::Both),
+    }
+  }
+}
+
+impl Read for Stream {
+  fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+    match self {
+      Stream::Plain(stream) => stream.read(buf),
+      #[cfg(unix)]
+      Stream::Unix(stream) => stream.read(buf),
+      Stream::Tls(stream) => locks::lock(stream, "TLS connection").read(buf),
+    }
+  }
+}
+
+impl Write for Stream {
+  fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+    match self {
+      Stream::Plain(stream) => stream.write(buf),
+      #[cfg(unix)]
+      Stream::Unix(stream) => stream.write(buf),
+      Stream::Tls(stream) => locks::lock(stream, "TLS connection").write(buf),
+    }
+  }
+
+  fn flush(&mut self) -> io::Result<()> {
+    match self {
+      Stream::Plain(stream) => stream.flush(),
+      #[cfg(unix)]
+      Stream::Unix(stream) => stream.flush(),
+      Stream::Tls(stream) => locks::lock(stream, "TLS connection").flush(),
+    }
+  }
+}
+
+/// Returns the process on the other end of a Unix socket connection, where
+/// the OS reports it.
+#[cfg(target_os = "linux")]
+pub(super) fn peer_cred(stream: &UnixStream) -> Option<super::PeerCred> {
+  use std::os::fd::AsRawFd;
+
+  let mut cred = libc::ucred {
+    pid: 0,
+    uid: 0,
+    gid: 0,
+  };
+  let mut len = std::mem::size_of::<libc::ucred>() as libc::socklen_t;
+  // SAFETY: `cred` and `len` describe a writable `ucred`, which is what
+  // SO_PEERCRED fills in.
+  let result = unsafe {
+    libc::getsockopt(
+      stream.as_raw_fd(),
+      libc::SOL_SOCKET,
+      libc::SO_PEERCRED,
+      (&mut cred as *mut libc::ucred).cast(),
+      &mut len,
+    )
+  };
+  (result == 0).then_some(super::PeerCred {
+    pid: cred.pid,
+    uid: cred.uid,
+    gid: cred.gid,
+  })
+}
+
+#[cfg(all(unix, not(target_os = "linux")))]
+pub(super) fn peer_cred(_stream: &UnixStream) -> Option<super::PeerCred> {
+  None
+}
@@ -0,0 +1,685 @@
+//! # The `CONFIG` Table
+//!
+//! `config.lua` sets the server's options in one `CONFIG` table, grouped by
+//! area, instead of loose globals:
+//!
+//! ```lua
+//! CONFIG = {
+//!   addr = "0.0.0.0:8000",
+//!   workers = 8,
+//!   limits = { connections = 2048, in_flight = 64 },
+//!   sandbox = { fs_allow = { "data/" } },
+//! }
+//! ```
+//!
+//! `SETTINGS` lists every key, its type, and the global it replaces. Once
+//! the script has run, `apply` checks the table against it, so a misspelt
+//! key or a value of the wrong type is reported by its full name (e.g.
+//! `CONFIG.limits.conections`), and copies each value to its global, where
+//! `load_lua_config` reads it. The globals still work on their own, with a
+//! deprecation warning; setting both a key and its global is an error. New
+//! settings get a key here rather than a global of their own.
+
+use mlua::prelude::*;
+use std::sync::Arc;
+
+/// The type a setting's value must have.
+#[derive(Debug, Clone, Copy)]
+enum Kind {
+  String,
+  Boolean,
+  /// An integer no smaller than the bound.
+  Integer(i64),
+  /// A number from 0 to 1.
+  Ratio,
+  /// A list of strings.
+  List,
+  /// A table of its own, checked where it is read (e.g. `tls`).
+  Table,
+  /// One of the given strings.
+  OneOf(&'static [&'static str]),
+  /// A string, or `false`.
+  StringOrFalse,
+  /// A path starting with `/`, or `false`.
+  PathOrFalse,
+  /// A Lua function.
+  Function,
+}
+
+/// One `CONFIG` key.
+struct Setting {
+  /// The key's path under `CONFIG`, with `.` between table levels.
+  key: &'static str,
+  /// The global the value is read from.
+  global: &'static str,
+  kind: Kind,
+}
+
+const fn setting(key: &'static str, global: &'static str, kind: Kind) -> Setting {
+  Setting { key, global, kind }
+}
+
+const POSITIVE: Kind = Kind::Integer(1);
+const NON_NEGATIVE: Kind = Kind::Integer(0);
+
+/// Every `CONFIG` key.
+const SETTINGS: &[Setting] = &[
+  setting("addr", "SERVER_ADDR", Kind::String),
+  setting("addrs", "SERVER_ADDRS", Kind::List),
+  setting(
+    "bind_check",
+    "BIND_CHECK",
+    Kind::OneOf(&["strict", "lenient"]),
+  ),
+  setting("workers", "WORKERS", POSITIVE),
+  setting("pid_file", "PID_FILE", Kind::String),
+  setting("run_as", "RUN_AS", Kind::Table),
+  setting("server_header", "SERVER_HEADER", Kind::StringOrFalse),
+  setting("security_headers", "SECURITY_HEADERS", Kind::Table),
+  setting("tls", "TLS", Kind::Table),
+  setting("log.file", "LOG_FILE", Kind::String),
+  setting("log.rotate", "LOG_ROTATE", Kind::Table),
+  setting("log.format", "LOG_FORMAT", Kind::OneOf(&["plain", "json"])),
+  setting("log.level", "LOG_LEVEL", Kind::String),
+  setting("log.slow_request_ms", "SLOW_REQUEST_MS", NON_NEGATIVE),
+  setting("log.requests", "REQUEST_LOG", Kind::StringOrFalse),
+  setting("log.requests_exclude", "REQUEST_LOG_EXCLUDE", Kind::List),
+  setting("socket.nodelay", "TCP_NODELAY", Kind::Boolean),
+  setting("socket.backlog", "LISTEN_BACKLOG", POSITIVE),
+  setting("socket.recv_buffer", "SO_RCVBUF", POSITIVE),
+  setting("socket.send_buffer", "SO_SNDBUF", POSITIVE),
+  setting("socket.unix_mode", "UNIX_SOCKET_MODE", Kind::String),
+  setting("bind.reuse_addr", "SO_REUSEADDR", Kind::Boolean),
+  setting("bind.reuse_port", "SO_REUSEPORT", Kind::Boolean),
+  setting("bind.retry", "BIND_RETRY", Kind::Table),
+  setting("limits.connections", "MAX_CONNECTIONS", POSITIVE),
+  setting(
+    "limits.keep_alive_timeout_ms",
+    "KEEP_ALIVE_TIMEOUT_MS",
+    POSITIVE,
+  ),
+  setting(
+    "limits.requests_per_connection",
+    "MAX_REQUESTS_PER_CONNECTION",
+    POSITIVE,
+  ),
+  setting("limits.url_bytes", "MAX_URL_BYTES", POSITIVE),
+  setting("limits.headers", "MAX_HEADERS", POSITIVE),
+  setting("limits.header_bytes", "MAX_HEADER_BYTES", POSITIVE),
+  setting("limits.header_total_bytes", "MAX_HEADER_TOTAL_BYTES", POSITIVE),
+  setting("limits.body", "MAX_BODY_BYTES", POSITIVE),
+  setting("http.keep_alive", "HTTP_KEEP_ALIVE", Kind::Boolean),
+  setting("http.version_compat", "HTTP_VERSION_COMPAT", Kind::Boolean),
+  setting("timeouts.header_read_ms", "HEADER_READ_TIMEOUT_MS", POSITIVE),
+  setting("timeouts.header_deadline_ms", "HEADER_DEADLINE_MS", POSITIVE),
+  setting("timeouts.body_read_ms", "BODY_READ_TIMEOUT_MS", POSITIVE),
+  setting("timeouts.write_ms", "WRITE_TIMEOUT_MS", POSITIVE),
+  // The same global as `limits.keep_alive_timeout_ms`, kept with the other
+  // timeouts.
+  setting("timeouts.keep_alive_idle_ms", "KEEP_ALIVE_TIMEOUT_MS", POSITIVE),
+  setting("limits.in_flight", "MAX_IN_FLIGHT", POSITIVE),
+  setting("limits.in_flight_queue", "IN_FLIGHT_QUEUE", NON_NEGATIVE),
+  setting(
+    "limits.in_flight_queue_timeout_ms",
+    "IN_FLIGHT_QUEUE_TIMEOUT_MS",
+    POSITIVE,
+  ),
+  setting("access.allow", "ACCESS_ALLOW", Kind::List),
+  setting("access.deny", "ACCESS_DENY", Kind::List),
+  setting("access.log", "ACCESS_LOG", Kind::Boolean),
+  setting("allowed_hosts", "ALLOWED_HOSTS", Kind::List),
+  setting("auth", "AUTH", Kind::Table),
+  setting("api_keys", "API_KEYS", Kind::Table),
+  setting("rate_limit", "RATE_LIMIT", Kind::Table),
+  setting("body.spill_bytes", "BODY_SPILL_BYTES", NON_NEGATIVE),
+  setting("body.spill_dir", "BODY_SPILL_DIR", Kind::String),
+  setting("lua.state_max_uses", "LUA_STATE_MAX_USES", POSITIVE),
+  setting("lua.instruction_limit", "LUA_INSTRUCTION_LIMIT", POSITIVE),
+  setting(
+    "lua.script_check",
+    "SCRIPT_CHECK",
+    Kind::OneOf(&["strict", "lenient"]),
+  ),
+  setting(
+    "lua.header_check",
+    "HEADER_CHECK",
+    Kind::OneOf(&["strict", "lenient"]),
+  ),
+  setting("lua.bytecode_cache", "BYTECODE_CACHE", Kind::Boolean),
+  setting("lua.bytecode_cache_dir", "BYTECODE_CACHE_DIR", Kind::String),
+  setting("lua.cache_max_bytes", "CACHE_MAX_BYTES", POSITIVE),
+  // The key the budget had when it only covered bytecode.
+  setting("lua.bytecode_cache_max_bytes", "CACHE_MAX_BYTES", POSITIVE),
+  setting("static.mmap_entries", "STATIC_MMAP_ENTRIES", POSITIVE),
+  setting("static.mmap_max_bytes", "STATIC_MMAP_MAX_BYTES", POSITIVE),
+  setting("shutdown.grace_ms", "SHUTDOWN_GRACE_MS", NON_NEGATIVE),
+  setting("shutdown.script", "ON_SHUTDOWN", Kind::String),
+  setting("sandbox.http_allow", "HTTP_ALLOW", Kind::List),
+  setting("sandbox.http_allow_private", "HTTP_ALLOW_PRIVATE", Kind::Boolean),
+  setting("sandbox.env_allow", "ENV_ALLOWLIST", Kind::List),
+  setting("sandbox.fs_allow", "FS_ALLOW", Kind::List),
+  setting("sandbox.fs_max_read_bytes", "FS_MAX_READ_BYTES", POSITIVE),
+  setting("sandbox.exec_allow", "EXEC_ALLOW", Kind::List),
+  setting("kv.max_entries", "KV_MAX_ENTRIES", POSITIVE),
+  setting("cache.max_entries", "CACHE_MAX_ENTRIES", POSITIVE),
+  setting("sqlite.dir", "SQLITE_DIR", Kind::String),
+  setting("queue.dir", "QUEUE_DIR", Kind::String),
+  setting("metrics.max_series", "METRICS_MAX_SERIES", POSITIVE),
+  setting("metrics.path", "METRICS_PATH", Kind::StringOrFalse),
+  setting("metrics.listener", "METRICS_LISTENER", Kind::OneOf(&["main", "admin"])),
+  setting("metrics.count_self", "METRICS_COUNT_SELF", Kind::Boolean),
+  setting("tracing.endpoint", "TRACING_ENDPOINT", Kind::String),
+  setting(
+    "tracing.protocol",
+    "TRACING_PROTOCOL",
+    Kind::OneOf(&["http/protobuf", "http/json"]),
+  ),
+  setting("tracing.sample_ratio", "TRACING_SAMPLE_RATIO", Kind::Ratio),
+  setting("tracing.resource", "TRACING_RESOURCE", Kind::Table),
+  setting("error_reporting.webhook", "ERROR_WEBHOOK", Kind::String),
+  setting(
+    "error_reporting.min_level",
+    "ERROR_REPORT_LEVEL",
+    Kind::OneOf(&["error", "warn"]),
+  ),
+  setting("error_reporting.throttle", "ERROR_REPORT_THROTTLE", Kind::String),
+  setting("debug.dump_routes", "DEBUG_DUMP_ROUTES", Kind::List),
+  setting("debug.dump_dir", "DEBUG_DUMP_DIR", Kind::String),
+  setting("redis.url", "REDIS_URL", Kind::String),
+  setting("redis.pool_size", "REDIS_POOL_SIZE", POSITIVE),
+  setting("redis.timeout_ms", "REDIS_TIMEOUT_MS", POSITIVE),
+  setting("session.secret", "SESSION_SECRET", Kind::String),
+  setting(
+    "session.store",
+    "SESSION_STORE",
+    Kind::OneOf(&["cookie", "kv"]),
+  ),
+  setting("session.ttl", "SESSION_TTL", POSITIVE),
+  setting("session.cookie", "SESSION_COOKIE", Kind::String),
+  setting("session.secure", "SESSION_SECURE", Kind::Boolean),
+  setting("keys", "KEYS", Kind::Table),
+  setting("smtp.host", "SMTP_HOST", Kind::String),
+  setting("smtp.port", "SMTP_PORT", POSITIVE),
+  setting(
+    "smtp.security",
+    "SMTP_SECURITY",
+    Kind::OneOf(&["starttls", "tls", "none"]),
+  ),
+  setting("smtp.username", "SMTP_USERNAME", Kind::String),
+  setting("smtp.password", "SMTP_PASSWORD", Kind::String),
+  setting("smtp.from", "SMTP_FROM", Kind::String),
+  setting("smtp.timeout_ms", "SMTP_TIMEOUT_MS", POSITIVE),
+  setting("smtp.queue", "SMTP_QUEUE", Kind::String),
+  setting("admin.token", "ADMIN_TOKEN", Kind::String),
+  setting("admin.addr", "ADMIN_ADDR", Kind::String),
+  setting("audit.file", "AUDIT_FILE", Kind::String),
+  setting("audit.syslog", "AUDIT_SYSLOG", Kind::String),
+  setting("health.live_path", "HEALTH_LIVE_PATH", Kind::PathOrFalse),
+  setting("health.ready_path", "HEALTH_READY_PATH", Kind::PathOrFalse),
+  setting("health.readiness", "HEALTH_READINESS", Kind::Function),
+  setting(
+    "health.readiness_interval_ms",
+    "HEALTH_READINESS_INTERVAL_MS",
+    POSITIVE,
+  ),
+  setting("health.log", "HEALTH_LOG", Kind::Boolean),
+];
+
+/// Globals that were renamed, by their old name, with the new one. The old
+/// name is still read, with the deprecation warning.
+const RENAMED: &[(&str, &str)] = &[("BYTECODE_CACHE_MAX_BYTES", "CACHE_MAX_BYTES")];
+
+/// The keys whose values `effective` leaves out. `redis.url` may carry a
+/// password, and a webhook URL usually holds its token.
+const SECRETS: &[&str] = &[
+  "session.secret",
+  "keys",
+  "auth",
+  "api_keys",
+  "smtp.password",
+  "redis.url",
+  "admin.token",
+  "error_reporting.webhook",
+];
+
+/// Checks the `CONFIG` table, if the script set one, and copies its values
+/// to the globals `load_lua_config` reads.
+///
+/// Returns a deprecation warning if settings were given as globals.
+///
+/// # Errors
+///
+/// Returns an error message naming the key if `CONFIG` has a key that
+/// isn't a setting or a value of the wrong type, sets a setting whose
+/// global is also set or both keys of one setting, or if a renamed global
+/// is set under both names.
+pub fn apply(globals: &LuaTable) -> Result<Option<String>, String> {
+  let mut deprecated = Vec::new();
+  for (i, setting) in SETTINGS.iter().enumerate() {
+    // A global with two keys is listed under the first.
+    if SETTINGS[..i].iter().any(|earlier| earlier.global == setting.global) {
+      continue;
+    }
+    let value: LuaValue = globals.raw_get(setting.global).map_err(|e| e.to_string())?;
+    if !value.is_nil() {
+      deprecated.push(format!("{} (CONFIG.{})", setting.global, setting.key));
+    }
+  }
+  for (old, new) in RENAMED {
+    let value: LuaValue = globals.raw_get(*old).map_err(|e| e.to_string())?;
+    if value.is_nil() {
+      continue;
+    }
+    let current: LuaValue = globals.raw_get(*new).map_err(|e| e.to_string())?;
+    if !current.is_nil() {
+      return Err(format!("Set {} or {}, not both", new, old));
+    }
+    globals.raw_set(*new, value).map_err(|e| e.to_string())?;
+    globals.raw_set(*old, LuaValue::Nil).map_err(|e| e.to_string())?;
+    if let Some(setting) = SETTINGS.iter().find(|setting| setting.global == *new) {
+      deprecated.push(format!("{} (CONFIG.{})", old, setting.key));
+    }
+  }
+
+  match globals
+    .raw_get::<LuaValue>("CONFIG")
+    .map_err(|e| e.to_string())?
+  {
+    LuaValue::Nil => {}
+    LuaValue::Table(table) => apply_table(globals, &table, "")?,
+    other => return Err(format!("CONFIG must be a table, got {}", other.type_name())),
+  }
+
+  Ok((!deprecated.is_empty()).then(|| {
+    format!(
+      "Settings as globals are deprecated; move them into CONFIG: {}",
+      deprecated.join(", ")
+    )
+  }))
+}
+
+/// Returns how errors name the setting read from `global`: its first
+/// `CONFIG` key, or the global itself if it has none.
+pub fn key(global: &str) -> String {
+  SETTINGS
+    .iter()
+    .find(|setting| setting.global == global)
+    .map_or_else(|| global.to_string(), |setting| format!("CONFIG.{}", setting.key))
+}
+
+/// Returns the settings in effect once `apply` has run, as a JSON object
+/// laid out like `CONFIG`, for `GET /admin/config`. Settings left unset
+/// are omitted, and secrets, along with any string holding one of the
+/// `fyre.secrets` values in `secrets`, are replaced with `"[redacted]"`.
+///
+/// # Errors
+///
+/// Returns an error message if a global can't be read.
+pub fn effective(globals: &LuaTable, secrets: &[Arc<str>]) -> Result<serde_json::Value, String> {
+  let mut root = serde_json::Map::new();
+  for setting in SETTINGS {
+    let value: LuaValue = globals.raw_get(setting.global).map_err(|e| e.to_string())?;
+    if value.is_nil() {
+      continue;
+    }
+    let value = if SECRETS.contains(&setting.key) {
+      serde_json::Value::from("[redacted]")
+    } else if value.is_function() {
+      serde_json::Value::from("[function]")
+    } else {
+      let mut value =
+        crate::fyre::json::to_json(&value).map_err(|e| format!("{}: {}", setting.global, e))?;
+      redact(&mut value, secrets);
+      value
+    };
+
+    // Keys are at most one section deep.
+    match setting.key.split_once('.') {
+      None => {
+        root.insert(setting.key.to_string(), value);
+      }
+      Some((section, key)) => {
+        let section = root
+          .entry(section)
+          .or_insert_with(|| serde_json::Value::Object(serde_json::Map::new()));
+        if let serde_json::Value::Object(section) = section {
+          section.insert(key.to_string(), value);
+        }
+      }
+    }
+  }
+  Ok(serde_json::Value::Object(root))
+}
+
+/// Replaces the strings in `value` that hold one of `secrets`.
+fn redact(value: &mut serde_json::Value, secrets: &[Arc<str>]) {
+  match value {
+    serde_json::Value::String(s)
+      if secrets
+        .iter()
+        .any(|secret| !secret.is_empty() && s.contains(&**secret)) =>
+    {
+      *value = serde_json::Value::from("[redacted]");
+    }
+    serde_json::Value::Array(items) => items.iter_mut().for_each(|item| redact(item, secrets)),
+    serde_json::Value::Object(fields) => fields.values_mut().for_each(|item| redact(item, secrets)),
+    _ => {}
+  }
+}
+
+/// Applies the keys of `table`, which is `CONFIG` itself when `section`
+/// is empty and `CONFIG.<section>` otherwise.
+fn apply_table(globals: &LuaTable, table: &LuaTable, section: &str) -> Result<(), String> {
+  for pair in table.pairs::<LuaValue, LuaValue>() {
+    let (key, value) = pair.map_err(|e| e.to_string())?;
+    let key = match key {
+      LuaValue::String(key) => key.to_string_lossy(),
+      other => {
+        let table = match section {
+          "" => "CONFIG".to_string(),
+          section => format!("CONFIG.{}", section),
+        };
+        return Err(format!(
+          "{} has a {} key; keys must be names",
+          table,
+          other.type_name()
+        ));
+      }
+    };
+    let path = match section {
+      "" => key,
+      section => format!("{}.{}", section, key),
+    };
+
+    if let Some(setting) = SETTINGS.iter().find(|setting| setting.key == path) {
+      check(&value, setting.kind).map_err(|expected| {
+        format!(
+          "CONFIG.{} must be {}, got {}",
+          path,
+          expected,
+          describe(&value)
+        )
+      })?;
+      let global: LuaValue = globals.raw_get(setting.global).map_err(|e| e.to_string())?;
+      if !global.is_nil() {
+        // With two keys for the global, the other may be what set it.
+        let keys: Vec<&str> = SETTINGS
+          .iter()
+          .filter(|alias| alias.global == setting.global)
+          .map(|alias| alias.key)
+          .collect();
+        if let [first, second] = keys[..] {
+          let other = if first == path { second } else { first };
+          if !config_value(globals, other)?.is_nil() {
+            return Err(format!("Set CONFIG.{} or CONFIG.{}, not both", first, second));
+          }
+        }
+        return Err(format!(
+          "Set CONFIG.{} or {}, not both",
+          path, setting.global
+        ));
+      }
+      globals
+        .raw_set(setting.global, value)
+        .map_err(|e| e.to_string())?;
+      continue;
+    }
+
+    if !is_section(&path) {
+      return Err(format!("CONFIG.{} is not a setting", path));
+    }
+    match value {
+      LuaValue::Table(table) => apply_table(globals, &table, &path)?,
+      other => {
+        return Err(format!(
+          "CONFIG.{} must be a table, got {}",
+          path,
+          other.type_name()
+        ))
+      }
+    }
+  }
+  Ok(())
+}
+
+/// Returns the value at `key`, a path such as `lua.cache_max_bytes`, in
+/// the `CONFIG` table, or nil if a table on the way is missing.
+fn config_value(globals: &LuaTable, key: &str) -> Result<LuaValue, String> {
+  let mut value: LuaValue = globals.raw_get("CONFIG").map_err(|e| e.to_string())?;
+  for part in key.split('.') {
+    value = match value {
+      LuaValue::Table(table) => table.raw_get(part).map_err(|e| e.to_string())?,
+      _ => return Ok(LuaValue::Nil),
+    };
+  }
+  Ok(value)
+}
+
+/// Merges `from`, a `CONFIG` table assigned by an included file, into
+/// `into`, the one set before it, so the file only overrides the keys it
+/// sets. Sections are merged key by key; any other value, including a list
+/// or the `tls` table, replaces the earlier one.
+///
+/// # Errors
+///
+/// Returns a `LuaError` if a table can't be read or written.
+pub fn merge(into: &LuaTable, from: &LuaTable) -> LuaResult<()> {
+  merge_section(into, from, "")
+}
+
+fn merge_section(into: &LuaTable, from: &LuaTable, section: &str) -> LuaResult<()> {
+  for pair in from.pairs::<LuaValue, LuaValue>() {
+    let (key, value) = pair?;
+    if let (LuaValue::String(name), LuaValue::Table(table)) = (&key, &value) {
+      let path = match section {
+        "" => name.to_string_lossy(),
+        section => format!("{}.{}", section, name.to_string_lossy()),
+      };
+      if is_section(&path) {
+        if let LuaValue::Table(existing) = into.raw_get::<LuaValue>(&key)? {
+          merge_section(&existing, table, &path)?;
+          continue;
+        }
+      }
+    }
+    into.raw_set(key, value)?;
+  }
+  Ok(())
+}
+
+/// Whether `path` is a table of settings, such as `limits`.
+fn is_section(path: &str) -> bool {
+  let prefix = format!("{}.", path);
+  SETTINGS
+    .iter()
+    .any(|setting| setting.key.starts_with(&prefix))
+}
+
+/// Checks `value` against `kind`, returning what was expected if it
+/// doesn't match.
+fn check(value: &LuaValue, kind: Kind) -> Result<(), String> {
+  let matches = match kind {
+    Kind::String => value.is_string(),
+    Kind::Boolean => value.is_boolean(),
+    Kind::Integer(min) => integer(value).is_some_and(|n| n >= min),
+    Kind::Ratio => match value {
+      LuaValue::Integer(n) => (0..=1).contains(n),
+      LuaValue::Number(n) => (0.0..=1.0).contains(n),
+      _ => false,
+    },
+    Kind::Table => value.is_table(),
+    Kind::List => match value {
+      LuaValue::Table(table) => {
+        table.raw_len() == table.pairs::<LuaValue, LuaValue>().count()
+          && table
+            .sequence_values::<LuaValue>()
+            .all(|item| item.is_ok_and(|item| item.is_string()))
+      }
+      _ => false,
+    },
+    Kind::OneOf(options) => value
+      .as_string()
+      .is_some_and(|s| options.iter().any(|option| s == *option)),
+    Kind::StringOrFalse => matches!(value, LuaValue::String(_) | LuaValue::Boolean(false)),
+    Kind::PathOrFalse => match value {
+      LuaValue::String(path) => path.as_bytes().starts_with(b"/"),
+      LuaValue::Boolean(enabled) => !enabled,
+      _ => false,
+    },
+    Kind::Function => value.is_function(),
+  };
+  if matches {
+    return Ok(());
+  }
+  Err(match kind {
+    Kind::String => "a string".to_string(),
+    Kind::Boolean => "true or false".to_string(),
+    Kind::Integer(0) => "a whole number".to_string(),
+    Kind::Integer(1) => "a positive whole number".to_string(),
+    Kind::Integer(min) => format!("a whole number of at least {}", min),
+    Kind::Ratio => "a number from 0 to 1".to_string(),
+    Kind::Table => "a table".to_string(),
+    Kind::List => "a list of strings".to_string(),
+    Kind::OneOf(options) => {
+      let options: Vec<String> = options.iter().map(|o| format!("{:?}", o)).collect();
+      format!("one of {}", options.join(", "))
+    }
+    Kind::StringOrFalse => "a string or false".to_string(),
+    Kind::PathOrFalse => "a path starting with / or false".to_string(),
+    Kind::Function => "a function".to_string(),
+  })
+}
+
+fn integer(value: &LuaValue) -> Option<i64> {
+  match value {
+    LuaValue::Integer(n) => Some(*n),
+    LuaValue::Number(n) if n.fract() == 0.0 => Some(*n as i64),
+    _ => None,
+  }
+}
+
+/// Describes a wrong value for an error message.
+fn describe(value: &LuaValue) -> String {
+  match value {
+    LuaValue::String(s) => format!("{:?}", s.to_string_lossy()),
+    LuaValue::Integer(n) => n.to_string(),
+    LuaValue::Number(n) => n.to_string(),
+    LuaValue::Boolean(b) => b.to_string(),
+    other => other.type_name().to_string(),
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  /// Runs `script` and applies its `CONFIG`, returning the globals.
+  fn applied(script: &str) -> (Lua, Result<Option<String>, String>) {
+    let lua = Lua::new();
+    lua.load(script).exec().unwrap();
+    let result = apply(&lua.globals());
+    (lua, result)
+  }
+
+  #[test]
+  fn a_renamed_global_is_read_under_its_new_name() {
+    let (lua, result) = applied("BYTECODE_CACHE_MAX_BYTES = 4096");
+    let warning = result.unwrap().unwrap();
+    assert!(warning.contains("BYTECODE_CACHE_MAX_BYTES (CONFIG.lua.cache_max_bytes)"));
+    let globals = lua.globals();
+    assert_eq!(
+      globals.get::<Option<i64>>("CACHE_MAX_BYTES").unwrap(),
+      Some(4096)
+    );
+    assert_eq!(
+      globals
+        .get::<Option<i64>>("BYTECODE_CACHE_MAX_BYTES")
+        .unwrap(),
+      None
+    );
+
+    let (_, result) = applied("BYTECODE_CACHE_MAX_BYTES = 1 CACHE_MAX_BYTES = 2");
+    assert_eq!(
+      result.unwrap_err(),
+      "Set CACHE_MAX_BYTES or BYTECODE_CACHE_MAX_BYTES, not both"
+    );
+  }
+
+  /// Every global the loading code reads has a `CONFIG` key, so no setting
+  /// is left that can only be set as a global of its own.
+  #[test]
+  fn every_global_read_has_a_key() {
+    let lib = include_str!("lib.rs");
+    let readers = [
+      &lib[lib.find("fn load_lua_config(").unwrap()..],
+      include_str!("audit.rs"),
+      include_str!("debug_dump.rs"),
+      include_str!("error_reports.rs"),
+      include_str!("health.rs"),
+      include_str!("request_log.rs"),
+      include_str!("request_metrics.rs"),
+      include_str!("span_export.rs"),
+    ];
+    let is_global = |literal: &str| {
+      literal.len() > 2
+        && literal.starts_with(|c: char| c.is_ascii_uppercase())
+        && literal
+          .chars()
+          .all(|c| c.is_ascii_uppercase() || c.is_ascii_digit() || c == '_')
+    };
+    for source in readers {
+      // Tests send requests and such, which aren't settings.
+      let source = source.split("\n#[cfg(test)]").next().unwrap();
+      let code = source
+        .lines()
+        .filter(|line| !line.trim_start().starts_with("//"));
+      for line in code {
+        for literal in line.split('"').skip(1).step_by(2).filter(|l| is_global(l)) {
+          assert!(
+            SETTINGS.iter().any(|setting| setting.global == literal),
+            "{} is read but has no CONFIG key",
+            literal
+          );
+        }
+      }
+    }
+  }
+
+  #[test]
+  fn both_keys_set_the_cache_budget() {
+    for key in ["cache_max_bytes", "bytecode_cache_max_bytes"] {
+      let (lua, result) = applied(&format!("CONFIG = {{ lua = {{ {} = 4096 }} }}", key));
+      assert_eq!(result.unwrap(), None);
+      let budget: Option<i64> = lua.globals().get("CACHE_MAX_BYTES").unwrap();
+      assert_eq!(budget, Some(4096), "lua.{}", key);
+    }
+    let (_, result) =
+      applied("CONFIG = { lua = { bytecode_cache_max_bytes = 1, cache_max_bytes = 2 } }");
+    assert_eq!(
+      result.unwrap_err(),
+      "Set CONFIG.lua.cache_max_bytes or CONFIG.lua.bytecode_cache_max_bytes, not both"
+    );
+    let (_, result) = applied("CONFIG = { lua = { cache_max_bytes = 0 } }");
+    assert_eq!(
+      result.unwrap_err(),
+      "CONFIG.lua.cache_max_bytes must be a positive whole number, got 0"
+    );
+  }
+
+  #[test]
+  fn both_keys_set_the_keep_alive_timeout() {
+    let (_, result) = applied(
+      "CONFIG = { limits = { keep_alive_timeout_ms = 1 }, timeouts = { keep_alive_idle_ms = 2 } }",
+    );
+    assert_eq!(
+      result.unwrap_err(),
+      "Set CONFIG.limits.keep_alive_timeout_ms or CONFIG.timeouts.keep_alive_idle_ms, not both"
+    );
+    let (_, result) =
+      applied("KEEP_ALIVE_TIMEOUT_MS = 1 CONFIG = { timeouts = { keep_alive_idle_ms = 2 } }");
+    assert_eq!(
+      result.unwrap_err(),
+      "Set CONFIG.timeouts.keep_alive_idle_ms or KEEP_ALIVE_TIMEOUT_MS, not both"
+    );
+  }
+}
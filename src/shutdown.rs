@@ -0,0 +1,166 @@
+//! # Graceful Shutdown
+//!
+//! On SIGINT or SIGTERM (Ctrl-C on Windows) the server stops handing requests
+//! to the workers and lets the ones already running finish, for up to
+//! `CONFIG.shutdown.grace_ms` (30 seconds by default). Requests arriving in the
+//! meantime are answered with `503` and `Connection: close`. The
+//! `CONFIG.shutdown.script` script then runs, the background queues stop, and
+//! the process exits with `0`, or with `FORCED_EXIT_CODE` if requests were
+//! still running when the grace period ran out. A second signal exits right
+//! away with `FORCED_EXIT_CODE`.
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc;
+use std::time::{Duration, Instant};
+
+/// How long running requests may take to finish when `CONFIG.shutdown.grace_ms`
+/// is not set.
+pub const DEFAULT_GRACE_PERIOD: Duration = Duration::from_secs(30);
+/// The exit code when the grace period ran out or a second signal arrived.
+pub const FORCED_EXIT_CODE: i32 = 2;
+
+/// Installs the signal handler. The returned channel receives a message on
+/// the first SIGINT or SIGTERM.
+///
+/// # Errors
+///
+/// Returns an error message if the handler can't be installed.
+pub fn signals() -> Result<mpsc::Receiver<()>, String> {
+  let (sender, receiver) = mpsc::channel();
+  let received = AtomicBool::new(false);
+  ctrlc::set_handler(move || {
+    if received.swap(true, Ordering::Relaxed) {
+      warn!("Second shutdown signal received, exiting now");
+      std::process::exit(FORCED_EXIT_CODE);
+    }
+    let _ = sender.send(());
+  })
+  .map_err(|e| format!("failed to install the signal handler: {}", e))?;
+  Ok(receiver)
+}
+
+/// Reports on `exited` when dropped, so a worker is counted as finished
+/// even if it panics.
+pub struct ExitGuard(pub mpsc::Sender<()>);
+
+impl Drop for ExitGuard {
+  fn drop(&mut self) {
+    let _ = self.0.send(());
+  }
+}
+
+/// Waits for `running` workers to report on `exited`, for at most `grace`.
+/// Returns the number still running.
+pub fn drain(exited: &mpsc::Receiver<()>, mut running: usize, grace: Duration) -> usize {
+  let deadline = Instant::now() + grace;
+  while running > 0 {
+    let left = deadline.saturating_duration_since(Instant::now());
+    if exited.recv_timeout(left).is_err() {
+      break;
+    }
+    running -= 1;
+  }
+  running
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::testing::{self, Fixture};
+  use std::thread;
+
+  #[test]
+  fn drain_waits_for_every_worker() {
+    let (sender, exited) = mpsc::channel();
+    let workers: Vec<_> = (0..3)
+      .map(|i| {
+        let guard = ExitGuard(sender.clone());
+        thread::spawn(move || {
+          thread::sleep(Duration::from_millis(20 * i));
+          drop(guard);
+        })
+      })
+      .collect();
+    assert_eq!(drain(&exited, 3, Duration::from_secs(5)), 0);
+    for worker in workers {
+      worker.join().unwrap();
+    }
+  }
+
+  #[test]
+  fn drain_gives_up_after_the_grace_period() {
+    let (sender, exited) = mpsc::channel();
+    drop(ExitGuard(sender.clone()));
+    let started = Instant::now();
+    assert_eq!(drain(&exited, 3, Duration::from_millis(100)), 2);
+    assert!(started.elapsed() >= Duration::from_millis(100));
+    drop(sender);
+  }
+
+  #[test]
+  fn a_panicking_worker_still_counts_as_exited() {
+    let (sender, exited) = mpsc::channel();
+    let guard = ExitGuard(sender);
+    let worker = thread::spawn(move || {
+      let _guard = guard;
+      panic!("handler failed");
+    });
+    assert!(worker.join().is_err());
+    assert_eq!(drain(&exited, 1, Duration::from_secs(5)), 0);
+  }
+
+  /// A server whose `/slow` handler runs for `busy_ms`, with a grace period
+  /// of `grace_ms`.
+  fn slow_server(busy_ms: u64, grace_ms: u64) -> (Fixture, crate::FyreServer, String) {
+    let fixture = Fixture::new(
+      &format!(
+        r#"
+          CONFIG = {{ shutdown = {{ grace_ms = {} }} }}
+          router.add("/slow", "slow.lua")
+        "#,
+        grace_ms
+      ),
+      &[(
+        "slow.lua",
+        &format!(
+          r#"return {{ handler = function(request, response)
+            local done_at = os.clock() + {} / 1000
+            while os.clock() < done_at do end
+            response.body = "finished"
+          end }}"#,
+          busy_ms
+        ),
+      )],
+    );
+    let server = fixture
+      .builder()
+      .addr("127.0.0.1:0")
+      .workers(1)
+      .load()
+      .unwrap();
+    let addr = server.serve().unwrap().remove(0);
+    (fixture, server, addr)
+  }
+
+  #[test]
+  fn requests_in_flight_finish_before_shutdown_returns() {
+    let (_fixture, server, addr) = slow_server(500, 5_000);
+    let client = thread::spawn(move || testing::get(&addr, "/slow"));
+    thread::sleep(Duration::from_millis(200));
+    assert_eq!(server.shutdown(), 0);
+    let response = client.join().unwrap();
+    assert!(response.starts_with("HTTP/1.1 200"), "{}", response);
+    assert!(response.ends_with("finished"), "{}", response);
+  }
+
+  #[test]
+  fn shutdown_reports_requests_outlasting_the_grace_period() {
+    let (_fixture, server, addr) = slow_server(1_500, 100);
+    let client = thread::spawn(move || testing::get(&addr, "/slow"));
+    thread::sleep(Duration::from_millis(200));
+    let started = Instant::now();
+    assert_eq!(server.shutdown(), 1);
+    assert!(started.elapsed() < Duration::from_secs(1));
+    client.join().unwrap();
+  }
+}
@@ -0,0 +1,179 @@
+//! # Slow Request Log
+//!
+//! With `CONFIG.log.slow_request_ms` set, a handler request taking longer than
+//! that gets one `WARN` line with its route, script, status, and sizes, and the
+//! time split into phases: waiting for a worker slot, reading the request body,
+//! running the Lua pipeline, and writing the response. A slow client shows up
+//! as `read` or `write` time, a slow handler as `lua`. The lines are logged
+//! whether or not anything else is, and `fyre.metrics.render()` counts them as
+//! `fyre_slow_requests_total`.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+use serde_json::Value;
+
+use crate::logger::{self, Level};
+
+/// Where a request's time went.
+#[derive(Debug, Default)]
+pub struct Phases {
+  /// Reading the request body.
+  pub read: Duration,
+  /// Loading the script and running the pipeline.
+  pub lua: Duration,
+  /// Sending the response.
+  pub write: Duration,
+  pub request_bytes: u64,
+  /// The response body's length, if it was known up front.
+  pub response_bytes: Option<usize>,
+}
+
+/// Logs and counts the requests slower than `CONFIG.log.slow_request_ms`.
+pub struct SlowLog {
+  /// `None` when `CONFIG.log.slow_request_ms` is unset or 0.
+  threshold: Option<Duration>,
+  count: AtomicU64,
+}
+
+impl SlowLog {
+  /// Creates a log for requests slower than `threshold_ms`; 0 disables it.
+  pub fn new(threshold_ms: u64) -> Self {
+    SlowLog {
+      threshold: (threshold_ms > 0).then_some(Duration::from_millis(threshold_ms)),
+      count: AtomicU64::new(0),
+    }
+  }
+
+  /// Logs the request if it took longer than the threshold. Time not spent
+  /// in a phase was spent waiting: for the concurrency limits and a Lua
+  /// state.
+  pub fn record(
+    &self,
+    worker: usize,
+    route: &str,
+    script: &str,
+    status: u16,
+    elapsed: Duration,
+    phases: &Phases,
+  ) {
+    let Some(threshold) = self.threshold else {
+      return;
+    };
+    if elapsed <= threshold {
+      return;
+    }
+    self.count.fetch_add(1, Ordering::Relaxed);
+    let wait = elapsed.saturating_sub(phases.read + phases.lua + phases.write);
+    let fields = [
+      ("status", Value::from(status)),
+      ("duration_ms", Value::from(elapsed.as_millis() as u64)),
+    ];
+    logger::log_fields(
+      Level::Warn,
+      module_path!(),
+      format_args!(
+        "[worker {}] Slow request: {} -> {} status={} elapsed={}ms wait={}ms read={}ms \
+         lua={}ms write={}ms request_bytes={} response_bytes={}",
+        worker,
+        route,
+        script,
+        status,
+        elapsed.as_millis(),
+        wait.as_millis(),
+        phases.read.as_millis(),
+        phases.lua.as_millis(),
+        phases.write.as_millis(),
+        phases.request_bytes,
+        phases
+          .response_bytes
+          .map_or_else(|| "unknown".to_string(), |n| n.to_string())
+      ),
+      &fields,
+    );
+  }
+
+  /// Returns the number of slow requests since startup.
+  pub fn count(&self) -> u64 {
+    self.count.load(Ordering::Relaxed)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::testing::{self, Fixture};
+
+  #[test]
+  fn only_requests_past_the_threshold_count() {
+    let log = SlowLog::new(100);
+    let phases = Phases::default();
+    log.record(
+      0,
+      "/",
+      "index.lua",
+      200,
+      Duration::from_millis(100),
+      &phases,
+    );
+    assert_eq!(log.count(), 0);
+    log.record(
+      0,
+      "/",
+      "index.lua",
+      200,
+      Duration::from_millis(101),
+      &phases,
+    );
+    assert_eq!(log.count(), 1);
+
+    let off = SlowLog::new(0);
+    off.record(0, "/", "index.lua", 200, Duration::from_secs(60), &phases);
+    assert_eq!(off.count(), 0);
+  }
+
+  #[test]
+  fn slow_handlers_show_up_in_the_metrics() {
+    let fixture = Fixture::new(
+      r#"
+        CONFIG = { log = { slow_request_ms = 30 } }
+        router.add("/fast", "fast.lua")
+        router.add("/slow", "slow.lua")
+        router.add("/metrics", "metrics.lua")
+      "#,
+      &[
+        ("fast.lua", "return { handler = function() end }"),
+        (
+          "slow.lua",
+          r#"return { handler = function()
+            local done_at = os.clock() + 0.1
+            while os.clock() < done_at do end
+          end }"#,
+        ),
+        (
+          "metrics.lua",
+          r#"return { handler = function(request, response)
+            response.body = fyre.metrics.render()
+          end }"#,
+        ),
+      ],
+    );
+    let server = fixture
+      .builder()
+      .addr("127.0.0.1:0")
+      .workers(1)
+      .load()
+      .unwrap();
+    let addr = server.serve().unwrap().remove(0);
+    testing::get(&addr, "/fast");
+    testing::get(&addr, "/slow");
+    testing::get(&addr, "/slow");
+    let metrics = testing::get(&addr, "/metrics");
+    assert!(
+      metrics.contains("\nfyre_slow_requests_total 2\n"),
+      "{}",
+      metrics
+    );
+    server.shutdown();
+  }
+}
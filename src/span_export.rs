@@ -0,0 +1,677 @@
+//! # Span Export
+//!
+//! With `CONFIG.tracing.endpoint` set, every request a worker sees is sent
+//! to an OpenTelemetry collector as a server span, named for its method and
+//! route, with these children:
+//!
+//! - `read body`, reading the request body of a handler request.
+//! - `middleware`, `handler`, and `response_hook`, each pipeline stage that
+//!   ran, with its error if it failed.
+//! - A client span for each `fyre.http` call, whose own span id is the
+//!   parent in the `traceparent` sent with it.
+//!
+//! ```lua
+//! CONFIG.tracing = {
+//!   endpoint = "http://otel-collector:4318/v1/traces",
+//!   protocol = "http/protobuf", -- or "http/json"
+//!   sample_ratio = 0.25,
+//!   resource = { ["service.name"] = "checkout", ["deployment.environment"] = "prod" },
+//! }
+//! ```
+//!
+//! A request that continues a trace is exported if its caller sampled it; a
+//! new trace is sampled with the probability `sample_ratio` (1 by default),
+//! and the decision goes downstream in the `traceparent` flags (see
+//! `trace`). `service.name` is `"fyre"` unless `resource` sets it.
+//!
+//! Finished spans are queued for one exporter thread, which posts them in
+//! batches of up to `MAX_BATCH`, at least every `FLUSH_INTERVAL`, so a
+//! slow or unreachable collector never holds up a request. When the queue
+//! is full, spans are dropped and counted, and a batch the collector
+//! refuses is not retried. Without an endpoint nothing is timed or queued.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use mlua::prelude::*;
+use serde_json::{json, Value};
+
+use crate::settings;
+use crate::trace::{self, TraceContext};
+
+/// The spans waiting to be exported, past which new ones are dropped.
+const QUEUE_SPANS: usize = 2048;
+/// The most spans posted at once.
+const MAX_BATCH: usize = 512;
+/// How long a span waits for its batch to fill before it is posted anyway.
+const FLUSH_INTERVAL: Duration = Duration::from_secs(5);
+/// How long the collector has to accept a batch.
+const EXPORT_TIMEOUT: Duration = Duration::from_secs(10);
+/// How long `flush` waits for the exporter thread.
+const FLUSH_TIMEOUT: Duration = Duration::from_secs(15);
+/// The `service.name` when `CONFIG.tracing.resource` doesn't set one.
+const DEFAULT_SERVICE_NAME: &str = "fyre";
+
+/// How spans are encoded for the collector.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Protocol {
+  /// `http/protobuf`, the OTLP default.
+  Protobuf,
+  /// `http/json`.
+  Json,
+}
+
+/// The collector and what is sent to it, from `CONFIG.tracing`.
+#[derive(Debug, Clone)]
+pub struct Settings {
+  pub endpoint: String,
+  pub protocol: Protocol,
+  /// The probability that a new trace is sampled, from 0 to 1.
+  pub sample_ratio: f64,
+  /// The resource attributes, `service.name` among them, sorted by key.
+  pub resource: Vec<(String, Attribute)>,
+}
+
+impl Settings {
+  /// Reads `CONFIG.tracing.endpoint`, `CONFIG.tracing.protocol`,
+  /// `CONFIG.tracing.sample_ratio`, and `CONFIG.tracing.resource`. Returns
+  /// `None` if no endpoint is set.
+  ///
+  /// # Errors
+  ///
+  /// Returns an error message if one has the wrong type or value, or one is
+  /// set without the endpoint.
+  pub fn from_globals(globals: &LuaTable) -> Result<Option<Settings>, String> {
+    let endpoint = globals
+      .get::<Option<String>>("TRACING_ENDPOINT")
+      .map_err(|e| format!("CONFIG.tracing.endpoint must be a URL: {}", e))?;
+    let Some(endpoint) = endpoint else {
+      for global in [
+        "TRACING_PROTOCOL",
+        "TRACING_SAMPLE_RATIO",
+        "TRACING_RESOURCE",
+      ] {
+        if !globals
+          .get::<LuaValue>(global)
+          .is_ok_and(|value| value.is_nil())
+        {
+          return Err(format!("{} is set but CONFIG.tracing.endpoint isn't", settings::key(global)));
+        }
+      }
+      return Ok(None);
+    };
+    match url::Url::parse(&endpoint) {
+      Ok(url) if matches!(url.scheme(), "http" | "https") => {}
+      Ok(_) => {
+        return Err(format!(
+          "CONFIG.tracing.endpoint {:?} must be an http or https URL",
+          endpoint
+        ))
+      }
+      Err(e) => return Err(format!("Invalid CONFIG.tracing.endpoint {:?}: {}", endpoint, e)),
+    }
+
+    let protocol = match globals
+      .get::<Option<String>>("TRACING_PROTOCOL")
+      .map_err(|e| {
+        format!(
+          "CONFIG.tracing.protocol must be \"http/protobuf\" or \"http/json\": {}",
+          e
+        )
+      })?
+      .as_deref()
+    {
+      None | Some("http/protobuf") => Protocol::Protobuf,
+      Some("http/json") => Protocol::Json,
+      Some(other) => {
+        return Err(format!(
+          "CONFIG.tracing.protocol must be \"http/protobuf\" or \"http/json\", got {:?}",
+          other
+        ))
+      }
+    };
+
+    let sample_ratio = globals
+      .get::<Option<f64>>("TRACING_SAMPLE_RATIO")
+      .map_err(|e| format!("CONFIG.tracing.sample_ratio must be a number: {}", e))?
+      .unwrap_or(1.0);
+    if !(0.0..=1.0).contains(&sample_ratio) {
+      return Err(format!(
+        "CONFIG.tracing.sample_ratio must be from 0 to 1, got {}",
+        sample_ratio
+      ));
+    }
+
+    let mut resource = Vec::new();
+    if let Some(table) = globals
+      .get::<Option<LuaTable>>("TRACING_RESOURCE")
+      .map_err(|e| format!("CONFIG.tracing.resource must be a table: {}", e))?
+    {
+      for pair in table.pairs::<String, LuaValue>() {
+        let (key, value) =
+          pair.map_err(|e| format!("CONFIG.tracing.resource keys must be strings: {}", e))?;
+        let value = match value {
+          LuaValue::String(s) => Attribute::String(s.to_string_lossy()),
+          LuaValue::Integer(n) => Attribute::Int(n),
+          LuaValue::Number(n) => Attribute::Double(n),
+          LuaValue::Boolean(b) => Attribute::Bool(b),
+          other => {
+            return Err(format!(
+              "CONFIG.tracing.resource.{} must be a string, number, or boolean, not a {}",
+              key,
+              other.type_name()
+            ))
+          }
+        };
+        resource.push((key, value));
+      }
+    }
+    if !resource.iter().any(|(key, _)| key == "service.name") {
+      resource.push(("service.name".to_string(), DEFAULT_SERVICE_NAME.into()));
+    }
+    resource.sort_by(|(a, _), (b, _)| a.cmp(b));
+
+    Ok(Some(Settings {
+      endpoint,
+      protocol,
+      sample_ratio,
+      resource,
+    }))
+  }
+}
+
+/// A span or resource attribute's value.
+#[derive(Debug, Clone)]
+pub enum Attribute {
+  String(String),
+  Bool(bool),
+  Int(i64),
+  Double(f64),
+}
+
+impl From<&str> for Attribute {
+  fn from(value: &str) -> Self {
+    Attribute::String(value.to_string())
+  }
+}
+
+impl From<String> for Attribute {
+  fn from(value: String) -> Self {
+    Attribute::String(value)
+  }
+}
+
+impl From<i64> for Attribute {
+  fn from(value: i64) -> Self {
+    Attribute::Int(value)
+  }
+}
+
+/// The OTLP `SpanKind`s used.
+#[derive(Debug, Clone, Copy)]
+pub enum SpanKind {
+  Internal = 1,
+  Server = 2,
+  Client = 3,
+}
+
+/// A finished span.
+struct Span {
+  trace_id: String,
+  span_id: String,
+  parent_span_id: Option<String>,
+  tracestate: Option<String>,
+  name: String,
+  kind: SpanKind,
+  start: SystemTime,
+  end: SystemTime,
+  attributes: Vec<(&'static str, Attribute)>,
+  /// Why the operation failed, if it did.
+  error: Option<String>,
+}
+
+impl Span {
+  /// A span of `trace`, ending now after `elapsed`.
+  fn ended(
+    trace: &TraceContext,
+    span_id: String,
+    parent_span_id: Option<String>,
+    name: String,
+    kind: SpanKind,
+    elapsed: Duration,
+  ) -> Span {
+    let end = SystemTime::now();
+    Span {
+      trace_id: trace.trace_id.clone(),
+      span_id,
+      parent_span_id,
+      tracestate: trace.tracestate.clone(),
+      name,
+      kind,
+      start: end.checked_sub(elapsed).unwrap_or(end),
+      end,
+      attributes: Vec::new(),
+      error: None,
+    }
+  }
+}
+
+enum Message {
+  Span(Span),
+  Flush(mpsc::Sender<()>),
+}
+
+/// The span queue's sending end, in `AppState`.
+pub struct Exporter {
+  sender: mpsc::SyncSender<Message>,
+  sample_ratio: f64,
+  /// Spans dropped because the queue was full, since the exporter thread
+  /// last reported them.
+  dropped: Arc<AtomicU64>,
+}
+
+impl Exporter {
+  /// Starts the exporter thread.
+  ///
+  /// # Errors
+  ///
+  /// Returns an error message if the thread can't be started.
+  pub fn start(settings: Settings) -> Result<Exporter, String> {
+    let (sender, receiver) = mpsc::sync_channel(QUEUE_SPANS);
+    let dropped = Arc::new(AtomicU64::new(0));
+    let sample_ratio = settings.sample_ratio;
+    let thread_dropped = dropped.clone();
+    std::thread::Builder::new()
+      .name("span-export".to_string())
+      .spawn(move || run(settings, receiver, &thread_dropped))
+      .map_err(|e| format!("Could not start the span export thread: {}", e))?;
+    Ok(Exporter {
+      sender,
+      sample_ratio,
+      dropped,
+    })
+  }
+
+  /// Decides whether a new trace is sampled. A continued trace keeps its
+  /// caller's decision.
+  pub fn sample(&self, trace: &mut TraceContext) {
+    if trace.parent_span_id.is_none() {
+      trace.set_sampled(rand::random::<f64>() < self.sample_ratio);
+    }
+  }
+
+  /// Queues `span`, or drops it if the queue is full.
+  fn send(&self, span: Span) {
+    if let Err(mpsc::TrySendError::Full(_)) = self.sender.try_send(Message::Span(span)) {
+      self.dropped.fetch_add(1, Ordering::Relaxed);
+    }
+  }
+
+  /// Waits until the spans queued so far are exported, for up to
+  /// `FLUSH_TIMEOUT`.
+  pub fn flush(&self) {
+    let (done, exported) = mpsc::channel();
+    if self.sender.send(Message::Flush(done)).is_ok()
+      && exported.recv_timeout(FLUSH_TIMEOUT).is_err()
+    {
+      warn!("Timed out waiting for spans to be exported");
+    }
+  }
+}
+
+/// A request's server span, sent once its response is written (see
+/// `server::Request::export_span`).
+pub struct ServerSpan {
+  exporter: Arc<Exporter>,
+  trace: TraceContext,
+  name: String,
+  attributes: Vec<(&'static str, Attribute)>,
+}
+
+impl ServerSpan {
+  pub fn new(
+    exporter: Arc<Exporter>,
+    trace: &TraceContext,
+    name: String,
+    attributes: Vec<(&'static str, Attribute)>,
+  ) -> ServerSpan {
+    ServerSpan {
+      exporter,
+      trace: trace.clone(),
+      name,
+      attributes,
+    }
+  }
+
+  /// Sends the span for a response with `status`, `elapsed` after the
+  /// request arrived.
+  pub fn finish(self, status: u16, elapsed: Duration) {
+    let mut span = Span::ended(
+      &self.trace,
+      self.trace.span_id.clone(),
+      self.trace.parent_span_id.clone(),
+      self.name,
+      SpanKind::Server,
+      elapsed,
+    );
+    span.attributes = self.attributes;
+    span
+      .attributes
+      .push(("http.response.status_code", i64::from(status).into()));
+    if status >= 500 {
+      span.error = Some(format!("status {}", status));
+    }
+    self.exporter.send(span);
+  }
+}
+
+/// Puts the children of a request's span in a Lua state's app data, while
+/// its pipeline runs, for `start` to find.
+pub struct Recorder {
+  exporter: Arc<Exporter>,
+  trace: TraceContext,
+}
+
+impl Recorder {
+  pub fn new(exporter: Arc<Exporter>, trace: &TraceContext) -> Recorder {
+    Recorder {
+      exporter,
+      trace: trace.clone(),
+    }
+  }
+}
+
+/// A child span being timed.
+pub struct Timing {
+  span_id: String,
+  name: String,
+  kind: SpanKind,
+  started: Instant,
+}
+
+/// Starts timing a child of the request's span, or returns `None` if the
+/// request running in `lua` isn't exported.
+pub fn start(lua: &Lua, name: &str, kind: SpanKind) -> Option<Timing> {
+  lua.app_data_ref::<Recorder>()?;
+  Some(Timing {
+    span_id: trace::random_id(8),
+    name: name.to_string(),
+    kind,
+    started: Instant::now(),
+  })
+}
+
+impl Timing {
+  /// The span's id, for a `traceparent` sent from it.
+  pub fn span_id(&self) -> &str {
+    &self.span_id
+  }
+
+  /// Ends the span and queues it, with `error` if the operation failed.
+  pub fn end(self, lua: &Lua, attributes: Vec<(&'static str, Attribute)>, error: Option<String>) {
+    let Some(recorder) = lua.app_data_ref::<Recorder>() else {
+      return;
+    };
+    let mut span = Span::ended(
+      &recorder.trace,
+      self.span_id,
+      Some(recorder.trace.span_id.clone()),
+      self.name,
+      self.kind,
+      self.started.elapsed(),
+    );
+    span.attributes = attributes;
+    span.error = error;
+    recorder.exporter.send(span);
+  }
+}
+
+/// The exporter thread: gathers spans into batches and posts each one.
+fn run(settings: Settings, receiver: mpsc::Receiver<Message>, dropped: &AtomicU64) {
+  let agent = ureq::AgentBuilder::new().timeout(EXPORT_TIMEOUT).build();
+  let mut batch = Vec::new();
+  // When the oldest span in the batch has to be posted.
+  let mut deadline: Option<Instant> = None;
+  // Whether the last post failed, so a collector that is down is reported
+  // once rather than for every batch.
+  let mut failing = false;
+  loop {
+    let message = match deadline {
+      None => receiver.recv().map_err(|_| RecvTimeoutError::Disconnected),
+      Some(deadline) => receiver.recv_timeout(deadline.saturating_duration_since(Instant::now())),
+    };
+    let done = match message {
+      Ok(Message::Span(span)) => {
+        batch.push(span);
+        deadline.get_or_insert_with(|| Instant::now() + FLUSH_INTERVAL);
+        if batch.len() < MAX_BATCH {
+          continue;
+        }
+        None
+      }
+      Ok(Message::Flush(done)) => Some(done),
+      Err(RecvTimeoutError::Timeout) => None,
+      Err(RecvTimeoutError::Disconnected) => {
+        post(&agent, &settings, &mut batch, &mut failing);
+        return;
+      }
+    };
+    post(&agent, &settings, &mut batch, &mut failing);
+    deadline = None;
+    let count = dropped.swap(0, Ordering::Relaxed);
+    if count > 0 {
+      warn!("Dropped {} spans: the export queue was full", count);
+    }
+    if let Some(done) = done {
+      let _ = done.send(());
+    }
+  }
+}
+
+/// Posts `batch` to the collector and empties it.
+fn post(agent: &ureq::Agent, settings: &Settings, batch: &mut Vec<Span>, failing: &mut bool) {
+  if batch.is_empty() {
+    return;
+  }
+  let (content_type, body) = match settings.protocol {
+    Protocol::Protobuf => (
+      "application/x-protobuf",
+      encode_protobuf(&settings.resource, batch),
+    ),
+    Protocol::Json => (
+      "application/json",
+      encode_json(&settings.resource, batch)
+        .to_string()
+        .into_bytes(),
+    ),
+  };
+  let result = agent
+    .post(&settings.endpoint)
+    .set("Content-Type", content_type)
+    .send_bytes(&body);
+  match result {
+    Ok(_) if *failing => {
+      info!("Exporting spans to {} again", settings.endpoint);
+      *failing = false;
+    }
+    Ok(_) => {}
+    Err(e) if !*failing => {
+      warn!(
+        "Failed to export {} spans to {}: {}",
+        batch.len(),
+        settings.endpoint,
+        e
+      );
+      *failing = true;
+    }
+    Err(_) => {}
+  }
+  batch.clear();
+}
+
+fn unix_nanos(time: SystemTime) -> u64 {
+  time
+    .duration_since(UNIX_EPOCH)
+    .map_or(0, |since| since.as_nanos() as u64)
+}
+
+/// The OTLP `Status` code of a failed span.
+const STATUS_ERROR: u64 = 2;
+
+/// Encodes an `ExportTraceServiceRequest` in the protobuf wire format.
+fn encode_protobuf(resource: &[(String, Attribute)], spans: &[Span]) -> Vec<u8> {
+  let mut out = Vec::new();
+  // ExportTraceServiceRequest.resource_spans
+  proto::message(&mut out, 1, |resource_spans| {
+    // ResourceSpans.resource
+    proto::message(resource_spans, 1, |out| {
+      for (key, value) in resource {
+        proto::key_value(out, 1, key, value);
+      }
+    });
+    // ResourceSpans.scope_spans
+    proto::message(resource_spans, 2, |scope_spans| {
+      proto::message(scope_spans, 1, |scope| {
+        proto::bytes(scope, 1, b"fyre");
+        proto::bytes(scope, 2, crate::VERSION.as_bytes());
+      });
+      for span in spans {
+        proto::message(scope_spans, 2, |out| {
+          proto::bytes(out, 1, &hex::decode(&span.trace_id).unwrap_or_default());
+          proto::bytes(out, 2, &hex::decode(&span.span_id).unwrap_or_default());
+          if let Some(tracestate) = &span.tracestate {
+            proto::bytes(out, 3, tracestate.as_bytes());
+          }
+          if let Some(parent) = &span.parent_span_id {
+            proto::bytes(out, 4, &hex::decode(parent).unwrap_or_default());
+          }
+          proto::bytes(out, 5, span.name.as_bytes());
+          proto::varint_field(out, 6, span.kind as u64);
+          proto::fixed64(out, 7, unix_nanos(span.start));
+          proto::fixed64(out, 8, unix_nanos(span.end));
+          for (key, value) in &span.attributes {
+            proto::key_value(out, 9, key, value);
+          }
+          if let Some(error) = &span.error {
+            proto::message(out, 15, |status| {
+              proto::bytes(status, 2, error.as_bytes());
+              proto::varint_field(status, 3, STATUS_ERROR);
+            });
+          }
+        });
+      }
+    });
+  });
+  out
+}
+
+/// The protobuf wire format, as far as OTLP needs it.
+mod proto {
+  use super::Attribute;
+
+  fn varint(out: &mut Vec<u8>, mut n: u64) {
+    while n >= 0x80 {
+      out.push((n as u8) | 0x80);
+      n >>= 7;
+    }
+    out.push(n as u8);
+  }
+
+  fn tag(out: &mut Vec<u8>, field: u64, wire_type: u64) {
+    varint(out, (field << 3) | wire_type);
+  }
+
+  pub fn varint_field(out: &mut Vec<u8>, field: u64, n: u64) {
+    tag(out, field, 0);
+    varint(out, n);
+  }
+
+  pub fn fixed64(out: &mut Vec<u8>, field: u64, n: u64) {
+    tag(out, field, 1);
+    out.extend_from_slice(&n.to_le_bytes());
+  }
+
+  /// A `bytes` or `string` field.
+  pub fn bytes(out: &mut Vec<u8>, field: u64, data: &[u8]) {
+    tag(out, field, 2);
+    varint(out, data.len() as u64);
+    out.extend_from_slice(data);
+  }
+
+  /// An embedded message, written by `build`.
+  pub fn message(out: &mut Vec<u8>, field: u64, build: impl FnOnce(&mut Vec<u8>)) {
+    let mut inner = Vec::new();
+    build(&mut inner);
+    bytes(out, field, &inner);
+  }
+
+  /// A `KeyValue` with its `AnyValue`.
+  pub fn key_value(out: &mut Vec<u8>, field: u64, key: &str, value: &Attribute) {
+    message(out, field, |out| {
+      bytes(out, 1, key.as_bytes());
+      message(out, 2, |out| match value {
+        Attribute::String(s) => bytes(out, 1, s.as_bytes()),
+        Attribute::Bool(b) => varint_field(out, 2, u64::from(*b)),
+        Attribute::Int(n) => varint_field(out, 3, *n as u64),
+        Attribute::Double(n) => fixed64(out, 4, n.to_bits()),
+      });
+    });
+  }
+}
+
+/// Encodes an `ExportTraceServiceRequest` in the OTLP JSON encoding, where
+/// ids are hex and 64-bit integers are strings.
+fn encode_json(resource: &[(String, Attribute)], spans: &[Span]) -> Value {
+  let spans: Vec<Value> = spans
+    .iter()
+    .map(|span| {
+      let mut out = json!({
+        "traceId": span.trace_id,
+        "spanId": span.span_id,
+        "name": span.name,
+        "kind": span.kind as u8,
+        "startTimeUnixNano": unix_nanos(span.start).to_string(),
+        "endTimeUnixNano": unix_nanos(span.end).to_string(),
+        "attributes": json_attributes(span.attributes.iter().map(|(key, value)| (*key, value))),
+      });
+      if let Some(parent) = &span.parent_span_id {
+        out["parentSpanId"] = json!(parent);
+      }
+      if let Some(tracestate) = &span.tracestate {
+        out["traceState"] = json!(tracestate);
+      }
+      if let Some(error) = &span.error {
+        out["status"] = json!({ "code": STATUS_ERROR, "message": error });
+      }
+      out
+    })
+    .collect();
+  json!({
+    "resourceSpans": [{
+      "resource": {
+        "attributes": json_attributes(resource.iter().map(|(key, value)| (key.as_str(), value))),
+      },
+      "scopeSpans": [{
+        "scope": { "name": "fyre", "version": crate::VERSION },
+        "spans": spans,
+      }],
+    }],
+  })
+}
+
+/// OTLP JSON `KeyValue`s.
+fn json_attributes<'a>(attributes: impl Iterator<Item = (&'a str, &'a Attribute)>) -> Vec<Value> {
+  attributes
+    .map(|(key, value)| {
+      let value = match value {
+        Attribute::String(s) => json!({ "stringValue": s }),
+        Attribute::Bool(b) => json!({ "boolValue": b }),
+        Attribute::Int(n) => json!({ "intValue": n.to_string() }),
+        Attribute::Double(n) => json!({ "doubleValue": n }),
+      };
+      json!({ "key": key, "value": value })
+    })
+    .collect()
+}
@@ -0,0 +1,604 @@
+//! # Static Files
+//!
+//! Serves the directories mounted with `router.static(prefix, dir)` and the
+//! files handlers send with `response.file(path)`. A file is handed to the
+//! response as the open `File` with its length, so it is sent in chunks as
+//! it is read and never held in memory whole, however large it is.
+//!
+//! A mount declared with `{ mmap = true }` serves files up to
+//! `CONFIG.static.mmap_max_bytes` from memory maps instead, kept for the
+//! `CONFIG.static.mmap_entries` most recently used files. Concurrent requests
+//! for a file share its one mapping, and a file whose size or modification time
+//! has changed is mapped again. A mapped file must be replaced (e.g. by
+//! renaming a new file over it) rather than rewritten in place, since reading a
+//! mapping whose file has shrunk crashes the process.
+//!
+//! A mount only serves files inside its directory. Besides refusing `..`,
+//! the path a request resolves to is canonicalized and must still be under
+//! the canonical directory, so a symlink pointing out of it (at `/etc`,
+//! say) is answered with `404`. The directory itself may be a symlink, and
+//! is resolved again on every request, so switching a `current` link to a
+//! new release takes effect at once. A mount declared with
+//! `{ follow_symlinks = true }` serves whatever its symlinks point at, for
+//! build outputs linked in on purpose. Sockets, devices, and other special
+//! files are never served, and neither are dotfiles (any path segment
+//! starting with `.`) unless the mount sets `serve_hidden = true`.
+
+use memmap2::Mmap;
+use mlua::prelude::*;
+use std::collections::HashMap;
+use std::fs::{self, File, Metadata};
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+use tiny_http::{Header, Method, Response, ResponseBox, StatusCode};
+
+use crate::locks;
+
+/// The number of files kept mapped when `CONFIG.static.mmap_entries` is not
+/// set.
+pub const DEFAULT_MMAP_ENTRIES: usize = 256;
+/// The largest file served from a mapping when `CONFIG.static.mmap_max_bytes`
+/// is not set.
+pub const DEFAULT_MMAP_MAX_BYTES: u64 = 1024 * 1024;
+
+/// A directory mounted with `router.static`.
+#[derive(Debug, Clone)]
+pub struct Mount {
+  /// The URL prefix, without a trailing slash (`""` for the root).
+  pub prefix: String,
+  pub dir: PathBuf,
+  /// Whether small files are served from memory maps.
+  pub mmap: bool,
+  /// Whether symlinks leading out of `dir` are followed.
+  pub follow_symlinks: bool,
+  /// Whether paths with a segment starting with `.` are served.
+  pub serve_hidden: bool,
+}
+
+impl Mount {
+  /// Reads a `router.static(prefix, dir, opts)` declaration.
+  ///
+  /// # Errors
+  ///
+  /// This function will return a `LuaError` if `prefix` doesn't start with
+  /// `/`, `dir` is not a directory, or an option has the wrong type.
+  pub fn from_lua(prefix: String, dir: String, opts: Option<LuaTable>) -> LuaResult<Mount> {
+    if !prefix.starts_with('/') {
+      return Err(LuaError::external(format!(
+        "router.static: prefix must start with '/', got '{}'",
+        prefix
+      )));
+    }
+    if !Path::new(&dir).is_dir() {
+      return Err(LuaError::external(format!(
+        "router.static: directory not found: {}",
+        dir
+      )));
+    }
+    let flag = |name: &str| match &opts {
+      Some(opts) => opts
+        .get::<Option<bool>>(name)
+        .map(|value| value.unwrap_or(false)),
+      None => Ok(false),
+    };
+    Ok(Mount {
+      prefix: prefix.trim_end_matches('/').to_string(),
+      dir: PathBuf::from(dir),
+      mmap: flag("mmap")?,
+      follow_symlinks: flag("follow_symlinks")?,
+      serve_hidden: flag("serve_hidden")?,
+    })
+  }
+}
+
+/// Maps the path below a mount to a file in its directory. Returns `None`
+/// for a path that isn't valid UTF-8 once decoded, that contains `..`, a
+/// backslash, or a NUL byte, or, unless the mount serves hidden files, that
+/// has a segment starting with `.`.
+fn resolve(mount: &Mount, rest: &str) -> Option<PathBuf> {
+  let decoded = percent_encoding::percent_decode_str(rest)
+    .decode_utf8()
+    .ok()?;
+  let mut path = mount.dir.clone();
+  for segment in decoded.split('/') {
+    match segment {
+      "" | "." => {}
+      ".." => return None,
+      _ if segment.contains(['\\', '\0']) => return None,
+      _ if segment.starts_with('.') && !mount.serve_hidden => return None,
+      _ => path.push(segment),
+    }
+  }
+  if path.is_dir() {
+    path.push("index.html");
+  }
+  Some(path)
+}
+
+/// Resolves the symlinks in `path`, returning the real path if it is still
+/// under the mount's directory, also resolved. Returns `None` for one
+/// leading out of it, or one that can't be resolved, e.g. because the file
+/// doesn't exist.
+fn contain(mount: &Mount, path: &Path) -> Option<PathBuf> {
+  let root = fs::canonicalize(&mount.dir).ok()?;
+  let real = fs::canonicalize(path).ok()?;
+  if real.starts_with(&root) {
+    return Some(real);
+  }
+  warn!(
+    "Refused {}: it resolves to {}, outside the static directory {}",
+    path.display(),
+    real.display(),
+    root.display()
+  );
+  None
+}
+
+/// Serves a request for `rest` under `mount`.
+pub fn serve(method: &Method, mount: &Mount, rest: &str, cache: &FileCache) -> ResponseBox {
+  if !matches!(method, Method::Get | Method::Head) {
+    let mut response = status_response(405);
+    if let Ok(allow) = Header::from_bytes("Allow", "GET, HEAD") {
+      response.add_header(allow);
+    }
+    return response;
+  }
+  let Some(mut path) = resolve(mount, rest) else {
+    return status_response(404);
+  };
+  if !mount.follow_symlinks {
+    // The file opened is the one checked, so a link swapped in between
+    // can't lead out.
+    match contain(mount, &path) {
+      Some(real) => path = real,
+      None => return status_response(404),
+    }
+  }
+  match open(&path, mount.mmap.then_some(cache)) {
+    Ok(file) => file.into_response(StatusCode(200)),
+    Err(e) => match e.kind() {
+      io::ErrorKind::NotFound => status_response(404),
+      io::ErrorKind::PermissionDenied => status_response(403),
+      _ => {
+        error!("Failed to serve {}: {}", path.display(), e);
+        status_response(500)
+      }
+    },
+  }
+}
+
+fn status_response(status: u16) -> ResponseBox {
+  let status = StatusCode(status);
+  Response::from_string(format!("{} {}", status.0, status.default_reason_phrase()))
+    .with_status_code(status)
+    .boxed()
+}
+
+/// An opened file, ready to be sent.
+pub struct OpenFile {
+  reader: Box<dyn Read + Send>,
+  len: u64,
+  content_type: &'static str,
+}
+
+impl OpenFile {
+  /// Builds a response streaming the file, with its `Content-Type` and
+  /// `Content-Length`.
+  pub fn into_response(self, status: StatusCode) -> ResponseBox {
+    let mut response = Response::new(
+      status,
+      Vec::new(),
+      self.reader,
+      Some(self.len as usize),
+      None,
+    );
+    if let Ok(header) = Header::from_bytes("Content-Type", self.content_type) {
+      response.add_header(header);
+    }
+    response
+  }
+}
+
+/// Opens the regular file at `path`, from a mapping in `cache` if one is
+/// given and the file is small enough.
+///
+/// # Errors
+///
+/// This function will return an error if the file can't be opened or
+/// mapped, or (as `NotFound`) if it isn't a regular file.
+pub fn open(path: &Path, cache: Option<&FileCache>) -> io::Result<OpenFile> {
+  // Checked before opening too, since opening a FIFO blocks until a writer
+  // shows up and opening a device can have side effects.
+  if !fs::metadata(path)?.is_file() {
+    return Err(io::Error::new(
+      io::ErrorKind::NotFound,
+      "not a regular file",
+    ));
+  }
+  let file = File::open(path)?;
+  let metadata = file.metadata()?;
+  if !metadata.is_file() {
+    return Err(io::Error::new(
+      io::ErrorKind::NotFound,
+      "not a regular file",
+    ));
+  }
+  let len = metadata.len();
+  let reader: Box<dyn Read + Send> = match cache {
+    Some(cache) if len <= cache.max_bytes => Box::new(cache.get(path, &file, &metadata)?),
+    _ => Box::new(file),
+  };
+  Ok(OpenFile {
+    reader,
+    len,
+    content_type: content_type(path),
+  })
+}
+
+/// Guesses a `Content-Type` from the file extension.
+pub fn content_type(path: &Path) -> &'static str {
+  let extension = path
+    .extension()
+    .and_then(|e| e.to_str())
+    .map(str::to_ascii_lowercase);
+  match extension.as_deref() {
+    Some("html" | "htm") => "text/html; charset=utf-8",
+    Some("css") => "text/css; charset=utf-8",
+    Some("js" | "mjs") => "text/javascript; charset=utf-8",
+    Some("json" | "map") => "application/json",
+    Some("txt") => "text/plain; charset=utf-8",
+    Some("csv") => "text/csv; charset=utf-8",
+    Some("xml") => "application/xml",
+    Some("svg") => "image/svg+xml",
+    Some("png") => "image/png",
+    Some("jpg" | "jpeg") => "image/jpeg",
+    Some("gif") => "image/gif",
+    Some("webp") => "image/webp",
+    Some("ico") => "image/x-icon",
+    Some("wasm") => "application/wasm",
+    Some("pdf") => "application/pdf",
+    Some("woff") => "font/woff",
+    Some("woff2") => "font/woff2",
+    _ => "application/octet-stream",
+  }
+}
+
+/// A mapped file and what it looked like when it was mapped.
+struct Mapping {
+  map: Arc<Mmap>,
+  len: u64,
+  modified: Option<SystemTime>,
+  last_used: u64,
+}
+
+struct CacheInner {
+  mappings: HashMap<PathBuf, Mapping>,
+  /// Incremented on every lookup, to order entries by last use.
+  clock: u64,
+}
+
+/// The memory maps shared by the `mmap` mounts.
+pub struct FileCache {
+  inner: Mutex<CacheInner>,
+  max_entries: usize,
+  max_bytes: u64,
+}
+
+impl FileCache {
+  /// Creates a cache keeping up to `max_entries` files of at most
+  /// `max_bytes` each.
+  pub fn new(max_entries: usize, max_bytes: u64) -> Self {
+    FileCache {
+      inner: Mutex::new(CacheInner {
+        mappings: HashMap::new(),
+        clock: 0,
+      }),
+      max_entries,
+      max_bytes,
+    }
+  }
+
+  /// Drops every mapping, returning how many there were. Responses still
+  /// being sent from one keep it until they finish.
+  pub fn clear(&self) -> usize {
+    let mut inner = locks::lock(&self.inner, "static file cache");
+    let mapped = inner.mappings.len();
+    inner.mappings.clear();
+    mapped
+  }
+
+  /// Returns a reader over the mapping of `file`, mapping it if it isn't
+  /// mapped yet or has changed since.
+  fn get(&self, path: &Path, file: &File, metadata: &Metadata) -> io::Result<MappedReader> {
+    let modified = metadata.modified().ok();
+    let mut inner = locks::lock(&self.inner, "static file cache");
+    inner.clock += 1;
+    let clock = inner.clock;
+
+    if let Some(mapping) = inner.mappings.get_mut(path) {
+      if mapping.len == metadata.len() && mapping.modified == modified {
+        mapping.last_used = clock;
+        return Ok(MappedReader::new(mapping.map.clone()));
+      }
+    }
+
+    // SAFETY: the map is read-only, and the module documentation requires
+    // mapped files to be replaced rather than modified in place.
+    let map = Arc::new(unsafe { Mmap::map(file)? });
+    if !inner.mappings.contains_key(path) && inner.mappings.len() >= self.max_entries {
+      let oldest = inner
+        .mappings
+        .iter()
+        .min_by_key(|(_, mapping)| mapping.last_used)
+        .map(|(path, _)| path.clone());
+      if let Some(oldest) = oldest {
+        inner.mappings.remove(&oldest);
+      }
+    }
+    inner.mappings.insert(
+      path.to_path_buf(),
+      Mapping {
+        map: map.clone(),
+        len: metadata.len(),
+        modified,
+        last_used: clock,
+      },
+    );
+    Ok(MappedReader::new(map))
+  }
+}
+
+/// Reads a shared mapping from the start.
+struct MappedReader {
+  map: Arc<Mmap>,
+  pos: usize,
+}
+
+impl MappedReader {
+  fn new(map: Arc<Mmap>) -> Self {
+    MappedReader { map, pos: 0 }
+  }
+}
+
+impl Read for MappedReader {
+  fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+    let remaining = &self.map[self.pos..];
+    let n = remaining.len().min(buf.len());
+    buf[..n].copy_from_slice(&remaining[..n]);
+    self.pos += n;
+    Ok(n)
+  }
+}
+
+/// Describes a mount for the startup log, with its directory's full path.
+pub fn describe(mount: &Mount) -> String {
+  let dir = fs::canonicalize(&mount.dir).unwrap_or_else(|_| mount.dir.clone());
+  let mut options = Vec::new();
+  if mount.mmap {
+    options.push("mmap");
+  }
+  if mount.follow_symlinks {
+    options.push("follow_symlinks");
+  }
+  if mount.serve_hidden {
+    options.push("serve_hidden");
+  }
+  if options.is_empty() {
+    format!("{}/ -> {}", mount.prefix, dir.display())
+  } else {
+    format!("{}/ -> {} ({})", mount.prefix, dir.display(), options.join(", "))
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::testing::Fixture;
+
+  fn mount(dir: &Path) -> Mount {
+    Mount {
+      prefix: "/assets".to_string(),
+      dir: dir.to_path_buf(),
+      mmap: false,
+      follow_symlinks: false,
+      serve_hidden: false,
+    }
+  }
+
+  #[test]
+  fn paths_outside_the_mount_are_refused() {
+    let fixture = Fixture::new("", &[]);
+    let mount = mount(fixture.path());
+    assert_eq!(
+      resolve(&mount, "/a/./b.txt"),
+      Some(fixture.path().join("a/b.txt"))
+    );
+    assert_eq!(
+      resolve(&mount, "/scripts"),
+      Some(fixture.path().join("scripts/index.html"))
+    );
+    for rest in [
+      "/../etc/passwd",
+      "/a/%2e%2e/b",
+      "/a\\b",
+      "/a%00b",
+      "/.env",
+      "/%ff",
+    ] {
+      assert_eq!(resolve(&mount, rest), None, "{}", rest);
+    }
+    let hidden = Mount {
+      serve_hidden: true,
+      ..mount
+    };
+    assert_eq!(
+      resolve(&hidden, "/.well-known/x"),
+      Some(fixture.path().join(".well-known/x"))
+    );
+  }
+
+  #[test]
+  fn special_files_are_not_served() {
+    let fixture = Fixture::new("", &[]);
+    let mount = mount(fixture.path());
+    let cache = FileCache::new(1, 1024);
+    let response = serve(&Method::Get, &mount, "/missing.txt", &cache);
+    assert_eq!(response.status_code(), StatusCode(404));
+    let response = serve(&Method::Post, &mount, "/config.lua", &cache);
+    assert_eq!(response.status_code(), StatusCode(405));
+    assert_eq!(
+      open(Path::new("/dev/null"), None).err().map(|e| e.kind()),
+      Some(io::ErrorKind::NotFound)
+    );
+  }
+
+  #[cfg(unix)]
+  #[test]
+  fn symlinks_out_of_the_mount_are_refused() {
+    use std::os::unix::fs::symlink;
+
+    let fixture = Fixture::new("", &[]);
+    let site = fixture.path().join("site");
+    let secret = fixture.path().join("secret");
+    fs::create_dir_all(site.join("css")).unwrap();
+    fs::create_dir_all(&secret).unwrap();
+    fs::write(site.join("index.html"), "home").unwrap();
+    fs::write(site.join("css/app.css"), "body {}").unwrap();
+    fs::write(secret.join("passwd"), "root:x:0:0").unwrap();
+    // A symlinked file and a symlinked directory leading out, and a link
+    // to a file inside.
+    symlink(secret.join("passwd"), site.join("leak.txt")).unwrap();
+    symlink(&secret, site.join("private")).unwrap();
+    symlink(site.join("css/app.css"), site.join("app.css")).unwrap();
+    // A symlinked root, as a deploy's `current` link would be.
+    let current = fixture.path().join("current");
+    symlink(&site, &current).unwrap();
+
+    let cache = FileCache::new(1, 1024);
+    let status = |mount: &Mount, rest: &str| serve(&Method::Get, mount, rest, &cache).status_code();
+    for dir in [&site, &current] {
+      let mount = mount(dir);
+      assert_eq!(status(&mount, "/"), StatusCode(200), "{}", dir.display());
+      assert_eq!(status(&mount, "/css/app.css"), StatusCode(200));
+      assert_eq!(status(&mount, "/app.css"), StatusCode(200));
+      assert_eq!(status(&mount, "/leak.txt"), StatusCode(404));
+      assert_eq!(status(&mount, "/private/passwd"), StatusCode(404));
+    }
+
+    let follow = Mount {
+      follow_symlinks: true,
+      ..mount(&site)
+    };
+    assert_eq!(status(&follow, "/leak.txt"), StatusCode(200));
+    assert_eq!(status(&follow, "/private/passwd"), StatusCode(200));
+  }
+
+  #[test]
+  fn dotfiles_are_served_only_when_asked_for() {
+    let fixture = Fixture::new("", &[]);
+    fs::write(fixture.path().join(".env"), "SECRET=1").unwrap();
+    fs::create_dir_all(fixture.path().join(".well-known")).unwrap();
+    fs::write(
+      fixture.path().join(".well-known/security.txt"),
+      "Contact: x",
+    )
+    .unwrap();
+    let cache = FileCache::new(1, 1024);
+    let hidden = Mount {
+      serve_hidden: true,
+      ..mount(fixture.path())
+    };
+    for (rest, default, with_hidden) in [
+      ("/.env", 404, 200),
+      ("/.well-known/security.txt", 404, 200),
+      ("/%2eenv", 404, 200),
+      ("/config.lua", 200, 200),
+    ] {
+      let default_status = serve(&Method::Get, &mount(fixture.path()), rest, &cache).status_code();
+      assert_eq!(default_status, StatusCode(default), "{}", rest);
+      let hidden_status = serve(&Method::Get, &hidden, rest, &cache).status_code();
+      assert_eq!(hidden_status, StatusCode(with_hidden), "{}", rest);
+    }
+  }
+
+  /// The resident set size of this process, in bytes.
+  #[cfg(target_os = "linux")]
+  fn rss() -> u64 {
+    let status = fs::read_to_string("/proc/self/status").unwrap();
+    let line = status
+      .lines()
+      .find(|line| line.starts_with("VmRSS:"))
+      .unwrap();
+    let kb: u64 = line.split_whitespace().nth(1).unwrap().parse().unwrap();
+    kb * 1024
+  }
+
+  #[test]
+  #[cfg(target_os = "linux")]
+  fn a_large_file_is_streamed() {
+    const SIZE: u64 = 1 << 30;
+    let fixture = Fixture::new("", &[]);
+    File::create(fixture.path().join("large.bin"))
+      .unwrap()
+      .set_len(SIZE)
+      .unwrap();
+    let mount = mount(fixture.path());
+    let cache = FileCache::new(1, 1024);
+
+    let before = rss();
+    let response = serve(&Method::Get, &mount, "/large.bin", &cache);
+    assert_eq!(response.status_code(), StatusCode(200));
+    let mut sent = Counter(0);
+    response
+      .raw_print(&mut sent, tiny_http::HTTPVersion(1, 1), &[], false, None)
+      .unwrap();
+    assert!(sent.0 > SIZE);
+    let grown = rss().saturating_sub(before);
+    assert!(grown < 64 * 1024 * 1024, "RSS grew by {} bytes", grown);
+  }
+
+  /// Counts the bytes written to it.
+  struct Counter(u64);
+
+  impl io::Write for Counter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+      self.0 += buf.len() as u64;
+      Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+      Ok(())
+    }
+  }
+
+  #[test]
+  fn mapped_files_are_shared_and_remapped_when_changed() {
+    let fixture = Fixture::new("", &[]);
+    let path = fixture.path().join("small.txt");
+    fs::write(&path, "one").unwrap();
+    let cache = FileCache::new(1, 1024);
+    let map = |path: &Path| {
+      let file = File::open(path).unwrap();
+      cache
+        .get(path, &file, &file.metadata().unwrap())
+        .unwrap()
+        .map
+    };
+
+    let first = map(&path);
+    assert!(Arc::ptr_eq(&first, &map(&path)));
+    fs::write(&path, "three").unwrap();
+    let changed = map(&path);
+    assert!(!Arc::ptr_eq(&first, &changed));
+    assert_eq!(&changed[..], b"three");
+
+    // Past `max_entries`, the least recently used mapping is dropped.
+    let other = fixture.path().join("other.txt");
+    fs::write(&other, "other").unwrap();
+    map(&other);
+    assert!(!Arc::ptr_eq(&changed, &map(&path)));
+    assert_eq!(cache.clear(), 1);
+  }
+}
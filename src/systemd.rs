@@ -0,0 +1,174 @@
+//! # systemd Integration
+//!
+//! Under systemd the server can run as a `Type=notify` service with socket
+//! activation, so a restart doesn't refuse connections:
+//!
+//! - Socket activation: when systemd passes listening sockets
+//!   (`LISTEN_PID` and `LISTEN_FDS`), they are used instead of binding the
+//!   configured addresses. Their options come from the `.socket` unit, and
+//!   a Unix socket's file is left for systemd to remove.
+//! - Readiness: `READY=1` is sent to `NOTIFY_SOCKET` once the configuration
+//!   has loaded, every handler script has compiled, and the workers are
+//!   running, and `STOPPING=1` when a graceful shutdown begins.
+//! - Watchdog: with `WatchdogSec=` set, `WATCHDOG=1` is sent at half the
+//!   interval from the main thread, so systemd restarts a server that stops
+//!   answering it.
+//!
+//! Without these variables (or on systems other than Unix) every function
+//! here does nothing.
+
+use std::io;
+use std::time::Duration;
+
+use crate::net::Listener;
+
+/// The first descriptor systemd passes, `SD_LISTEN_FDS_START`.
+#[cfg(unix)]
+const LISTEN_FDS_START: i32 = 3;
+
+/// Takes the listening sockets systemd passed to this process, if any. The
+/// variables are removed, so programs run with `fyre.exec` don't see them.
+///
+/// It must be called before any thread is started.
+///
+/// # Errors
+///
+/// This function will return an error if a passed descriptor isn't a
+/// listening stream socket.
+#[cfg(unix)]
+pub fn listen_fds() -> io::Result<Vec<Listener>> {
+  use std::os::fd::FromRawFd;
+  use std::os::unix::net::UnixListener;
+
+  let pid = std::env::var("LISTEN_PID").ok();
+  let fds = std::env::var("LISTEN_FDS").ok();
+  for name in ["LISTEN_PID", "LISTEN_FDS", "LISTEN_FDNAMES"] {
+    std::env::remove_var(name);
+  }
+  let for_us = pid.and_then(|pid| pid.parse::<u32>().ok()) == Some(std::process::id());
+  let Some(count) = fds
+    .and_then(|fds| fds.parse::<i32>().ok())
+    .filter(|_| for_us)
+  else {
+    return Ok(Vec::new());
+  };
+
+  let mut listeners = Vec::new();
+  for fd in LISTEN_FDS_START..LISTEN_FDS_START.saturating_add(count) {
+    // SAFETY: sets a flag on a descriptor systemd passed to this process.
+    unsafe { libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC) };
+    if socket_option(fd, libc::SO_TYPE)? != libc::SOCK_STREAM
+      || socket_option(fd, libc::SO_ACCEPTCONN)? == 0
+    {
+      return Err(io::Error::other(format!(
+        "descriptor {} from systemd is not a listening stream socket",
+        fd
+      )));
+    }
+    // SAFETY: the descriptor is a socket passed to this process, and is
+    // owned by the listener from here on.
+    let listener = if socket_family(fd)? == libc::AF_UNIX {
+      Listener::Unix(unsafe { UnixListener::from_raw_fd(fd) })
+    } else {
+      Listener::Tcp(unsafe { std::net::TcpListener::from_raw_fd(fd) })
+    };
+    listeners.push(listener);
+  }
+  Ok(listeners)
+}
+
+#[cfg(not(unix))]
+pub fn listen_fds() -> io::Result<Vec<Listener>> {
+  Ok(Vec::new())
+}
+
+#[cfg(unix)]
+fn socket_option(fd: libc::c_int, option: libc::c_int) -> io::Result<libc::c_int> {
+  let mut value: libc::c_int = 0;
+  let mut len = std::mem::size_of::<libc::c_int>() as libc::socklen_t;
+  // SAFETY: `value` and `len` describe a buffer the size of the option.
+  let result = unsafe {
+    libc::getsockopt(
+      fd,
+      libc::SOL_SOCKET,
+      option,
+      (&mut value as *mut libc::c_int).cast(),
+      &mut len,
+    )
+  };
+  if result < 0 {
+    return Err(io::Error::last_os_error());
+  }
+  Ok(value)
+}
+
+#[cfg(unix)]
+fn socket_family(fd: libc::c_int) -> io::Result<libc::c_int> {
+  // SAFETY: an all-zero `sockaddr_storage` is valid, and `len` is its size.
+  let mut addr: libc::sockaddr_storage = unsafe { std::mem::zeroed() };
+  let mut len = std::mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+  // SAFETY: `addr` has room for any socket address.
+  let result = unsafe {
+    libc::getsockname(
+      fd,
+      (&mut addr as *mut libc::sockaddr_storage).cast(),
+      &mut len,
+    )
+  };
+  if result < 0 {
+    return Err(io::Error::last_os_error());
+  }
+  Ok(libc::c_int::from(addr.ss_family))
+}
+
+/// Sends `state` (e.g. `"READY=1"`) to the service manager, if it asked for
+/// notifications. A failure is logged, since the server works without.
+pub fn notify(state: &str) {
+  let Some(socket) = std::env::var_os("NOTIFY_SOCKET") else {
+    return;
+  };
+  if let Err(e) = send(&socket, state) {
+    warn!("Failed to notify systemd ({}): {}", state, e);
+  }
+}
+
+#[cfg(unix)]
+fn send(socket: &std::ffi::OsStr, state: &str) -> io::Result<()> {
+  use std::os::unix::ffi::OsStrExt;
+  use std::os::unix::net::UnixDatagram;
+
+  let datagram = UnixDatagram::unbound()?;
+  let path = socket.as_bytes();
+  match path.strip_prefix(b"@") {
+    // An abstract socket, which only Linux has.
+    #[cfg(target_os = "linux")]
+    Some(name) => {
+      use std::os::linux::net::SocketAddrExt;
+      let addr = std::os::unix::net::SocketAddr::from_abstract_name(name)?;
+      datagram.send_to_addr(state.as_bytes(), &addr)?;
+    }
+    #[cfg(not(target_os = "linux"))]
+    Some(_) => return Err(io::Error::other("abstract sockets are not supported")),
+    None => {
+      datagram.send_to(state.as_bytes(), socket)?;
+    }
+  }
+  Ok(())
+}
+
+#[cfg(not(unix))]
+fn send(_socket: &std::ffi::OsStr, _state: &str) -> io::Result<()> {
+  Ok(())
+}
+
+/// How often to send `WATCHDOG=1`: half the interval systemd asked for
+/// with `WATCHDOG_USEC`, or `None` if it didn't ask this process.
+pub fn watchdog_interval() -> Option<Duration> {
+  if let Ok(pid) = std::env::var("WATCHDOG_PID") {
+    if pid.parse::<u32>().ok() != Some(std::process::id()) {
+      return None;
+    }
+  }
+  let usec: u64 = std::env::var("WATCHDOG_USEC").ok()?.parse().ok()?;
+  (usec > 0).then(|| Duration::from_micros(usec / 2))
+}
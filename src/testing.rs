@@ -0,0 +1,97 @@
+//! # Test Fixtures
+//!
+//! A configuration and its handler scripts written to a directory of their
+//! own, for tests that load a whole server. The directory is removed when
+//! the fixture is dropped.
+
+use std::fs;
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+#[cfg(unix)]
+use std::sync::Mutex;
+
+#[cfg(unix)]
+use crate::logger;
+use crate::{Builder, FyreServer};
+
+/// A temporary directory holding `config.lua` and `scripts/`.
+pub struct Fixture {
+  dir: PathBuf,
+}
+
+impl Fixture {
+  /// Writes `config` as `config.lua` and each `(name, source)` in `scripts`
+  /// under `scripts/`.
+  pub fn new(config: &str, scripts: &[(&str, &str)]) -> Fixture {
+    static NEXT: AtomicUsize = AtomicUsize::new(0);
+    let dir = std::env::temp_dir().join(format!(
+      "fyre-test-{}-{}",
+      std::process::id(),
+      NEXT.fetch_add(1, Ordering::Relaxed)
+    ));
+    fs::create_dir_all(dir.join("scripts")).unwrap();
+    fs::write(dir.join("config.lua"), config).unwrap();
+    for (name, source) in scripts {
+      fs::write(dir.join("scripts").join(name), source).unwrap();
+    }
+    Fixture { dir }
+  }
+
+  pub fn path(&self) -> &Path {
+    &self.dir
+  }
+
+  /// A builder for the fixture's configuration, without the startup banner.
+  pub fn builder(&self) -> Builder {
+    FyreServer::builder()
+      .config_file(self.dir.join("config.lua"))
+      .quiet(true)
+  }
+
+  /// Loads the fixture's configuration.
+  pub fn server(&self) -> FyreServer {
+    self.builder().load().unwrap()
+  }
+}
+
+impl Drop for Fixture {
+  fn drop(&mut self) {
+    let _ = fs::remove_dir_all(&self.dir);
+  }
+}
+
+/// Sends `GET path` to `addr` over a connection of its own and returns the
+/// whole response, head and body.
+pub fn get(addr: &str, path: &str) -> String {
+  send(
+    addr,
+    &format!("GET {} HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n", path),
+  )
+}
+
+/// Sends `request`, which should ask for `Connection: close`, to `addr` over
+/// a connection of its own and returns the whole response.
+pub fn send(addr: &str, request: &str) -> String {
+  let mut stream = TcpStream::connect(addr).unwrap();
+  stream.write_all(request.as_bytes()).unwrap();
+  let mut response = String::new();
+  stream.read_to_string(&mut response).unwrap();
+  response
+}
+
+/// Held by the tests that send `SIGHUP`, which reloads every TLS
+/// configuration and htpasswd file in the process.
+#[cfg(unix)]
+pub static HANGING_UP: Mutex<()> = Mutex::new(());
+
+/// Sends the process `SIGHUP`, as `kill -HUP` would.
+#[cfg(unix)]
+pub fn hang_up() {
+  logger::handle_sighup().unwrap();
+  let before = logger::hangups();
+  // SAFETY: the handler `handle_sighup` installed only updates atomics.
+  unsafe { libc::raise(libc::SIGHUP) };
+  assert!(logger::hangups() > before);
+}
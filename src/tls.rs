@@ -0,0 +1,560 @@
+//! # HTTPS
+//!
+//! Loads the certificate and key named by `CONFIG.tls.cert` and `CONFIG.tls.key`
+//! in `config.lua` and builds the rustls configuration the server
+//! terminates TLS with. Problems are reported at startup and name the file:
+//! one that can't be read or holds no PEM certificate or key, a certificate
+//! that has expired, or a key that doesn't belong to the certificate. A
+//! certificate expiring within `EXPIRY_WARNING_DAYS` is logged as a warning.
+//!
+//! With `client_ca` set, clients may present a certificate signed by one of
+//! the CAs in that file, and handlers see it as `request.tls.client_cert`.
+//! With `require_client_cert = true` too, a handshake without a valid one
+//! fails. A route added with `require_client_cert = true` answers `403` to
+//! a request without one, so one listener can serve both public and
+//! internal paths.
+//!
+//! `min_version` and `max_version` (`"1.2"` or `"1.3"`) limit the protocol
+//! versions offered, and `cipher_suites` lists the only suites allowed, by
+//! their IANA names (e.g. `"TLS_ECDHE_RSA_WITH_AES_128_GCM_SHA256"`, or
+//! `"TLS13_AES_256_GCM_SHA384"` for TLS 1.3), in order of preference. An
+//! unknown suite fails startup, naming it. Handlers see what a connection
+//! negotiated as `request.tls.version` and `request.tls.cipher`.
+//!
+//! On `SIGHUP` the files are read again and the new configuration is
+//! swapped in for the connections accepted after it (see `ServerTls`), so a
+//! renewed certificate is picked up without a restart. Connections already
+//! open keep the one they started with, so none are dropped. If the new
+//! files can't be used, that is logged and the old certificate stays in
+//! use; with `run_as`, the files must be readable by that user.
+
+use arc_swap::ArcSwap;
+use mlua::prelude::*;
+use rustls::pki_types::pem::{self, PemObject};
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use rustls::server::WebPkiClientVerifier;
+use rustls::{ProtocolVersion, ServerConnection, SupportedProtocolVersion};
+use sha2::{Digest, Sha256};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+use crate::logger;
+
+/// How close to its expiry a certificate is warned about at startup.
+pub const EXPIRY_WARNING_DAYS: i64 = 14;
+
+/// A TLS protocol version `min_version` or `max_version` may name.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum TlsVersion {
+  V1_2,
+  V1_3,
+}
+
+impl TlsVersion {
+  fn parse(field: &str, value: &str) -> Result<TlsVersion, String> {
+    match value {
+      "1.2" => Ok(TlsVersion::V1_2),
+      "1.3" => Ok(TlsVersion::V1_3),
+      other => Err(format!(
+        "CONFIG.tls.{} must be \"1.2\" or \"1.3\", got \"{}\"",
+        field, other
+      )),
+    }
+  }
+
+  fn protocol(self) -> &'static SupportedProtocolVersion {
+    match self {
+      TlsVersion::V1_2 => &rustls::version::TLS12,
+      TlsVersion::V1_3 => &rustls::version::TLS13,
+    }
+  }
+}
+
+/// The `CONFIG.tls` table from `config.lua`.
+#[derive(Debug, Clone)]
+pub struct TlsSettings {
+  /// The PEM file with the certificate chain, server certificate first.
+  pub cert: PathBuf,
+  /// The PEM file with the private key.
+  pub key: PathBuf,
+  /// The address of a plain HTTP listener that redirects to HTTPS.
+  pub redirect_http: Option<String>,
+  /// The PEM file with the CAs client certificates are checked against.
+  pub client_ca: Option<PathBuf>,
+  /// Whether a handshake without a valid client certificate fails.
+  pub require_client_cert: bool,
+  /// The oldest and newest protocol versions offered.
+  pub min_version: TlsVersion,
+  pub max_version: TlsVersion,
+  /// The cipher suites allowed, most preferred first; `None` allows the
+  /// rustls defaults.
+  pub cipher_suites: Option<Vec<String>>,
+}
+
+impl TlsSettings {
+  /// Reads the `CONFIG.tls` table.
+  ///
+  /// # Errors
+  ///
+  /// Returns an error message if `cert` or `key` is missing, an entry is
+  /// not a string, a version is not `"1.2"` or `"1.3"`, `min_version` is
+  /// newer than `max_version`, or `cipher_suites` is empty.
+  pub fn from_lua(table: &LuaTable) -> Result<TlsSettings, String> {
+    let get = |name: &str| {
+      table
+        .get::<Option<String>>(name)
+        .map_err(|e| format!("CONFIG.tls.{} must be a string: {}", name, e))
+    };
+    let path = |name: &str| {
+      get(name)?
+        .map(PathBuf::from)
+        .ok_or_else(|| format!("CONFIG.tls.{} is required", name))
+    };
+    let require_client_cert = table
+      .get::<Option<bool>>("require_client_cert")
+      .map_err(|e| format!("CONFIG.tls.require_client_cert must be a boolean: {}", e))?
+      .unwrap_or(false);
+    let client_ca = get("client_ca")?.map(PathBuf::from);
+    if require_client_cert && client_ca.is_none() {
+      return Err("CONFIG.tls.require_client_cert needs CONFIG.tls.client_ca".to_string());
+    }
+    let version = |name: &str, default: TlsVersion| match get(name)? {
+      Some(value) => TlsVersion::parse(name, &value),
+      None => Ok(default),
+    };
+    let min_version = version("min_version", TlsVersion::V1_2)?;
+    let max_version = version("max_version", TlsVersion::V1_3)?;
+    if min_version > max_version {
+      return Err("CONFIG.tls.min_version is newer than CONFIG.tls.max_version".to_string());
+    }
+    let cipher_suites = table
+      .get::<Option<Vec<String>>>("cipher_suites")
+      .map_err(|e| format!("CONFIG.tls.cipher_suites must be a list of strings: {}", e))?;
+    if cipher_suites.as_ref().is_some_and(Vec::is_empty) {
+      return Err("CONFIG.tls.cipher_suites can't be empty".to_string());
+    }
+    Ok(TlsSettings {
+      cert: path("cert")?,
+      key: path("key")?,
+      redirect_http: get("redirect_http")?,
+      client_ca,
+      require_client_cert,
+      min_version,
+      max_version,
+      cipher_suites,
+    })
+  }
+}
+
+/// A certificate a client presented and the CA verified, as
+/// `request.tls.client_cert`. The issuer and serial number identify it in a
+/// revocation list, and the fingerprint pins it.
+#[derive(Debug, Clone)]
+pub struct ClientCert {
+  /// The subject's distinguished name, e.g. `CN=billing, O=Example`.
+  pub subject: String,
+  pub issuer: String,
+  /// The serial number, in lowercase hex.
+  pub serial: String,
+  /// The SHA-256 of the DER certificate, in lowercase hex.
+  pub fingerprint: String,
+  /// The validity period, as Unix timestamps.
+  pub not_before: i64,
+  pub not_after: i64,
+}
+
+impl ClientCert {
+  /// Reads the fields of `cert`, or returns `None` if it can't be parsed,
+  /// which a certificate the verifier accepted always can.
+  pub fn from_der(cert: &CertificateDer) -> Option<ClientCert> {
+    let (_, parsed) = x509_parser::parse_x509_certificate(cert).ok()?;
+    let validity = parsed.validity();
+    Some(ClientCert {
+      subject: parsed.subject().to_string(),
+      issuer: parsed.issuer().to_string(),
+      serial: parsed.raw_serial_as_string().replace(':', ""),
+      fingerprint: hex::encode(Sha256::digest(cert.as_ref())),
+      not_before: validity.not_before.timestamp(),
+      not_after: validity.not_after.timestamp(),
+    })
+  }
+
+  /// Converts the certificate to a Lua table with the same fields.
+  pub fn to_lua(&self, lua: &Lua) -> LuaResult<LuaTable> {
+    let table = lua.create_table()?;
+    table.set("subject", self.subject.as_str())?;
+    table.set("issuer", self.issuer.as_str())?;
+    table.set("serial", self.serial.as_str())?;
+    table.set("fingerprint", self.fingerprint.as_str())?;
+    table.set("not_before", self.not_before)?;
+    table.set("not_after", self.not_after)?;
+    Ok(table)
+  }
+}
+
+/// The protocol version and cipher suite a connection negotiated, as
+/// `request.tls.version` (e.g. `"TLSv1.3"`) and `request.tls.cipher`.
+#[derive(Debug, Clone, Copy)]
+pub struct Negotiated {
+  pub version: &'static str,
+  pub cipher: &'static str,
+}
+
+impl Negotiated {
+  /// Reads what `conn` negotiated, once its handshake is done.
+  pub fn from_connection(conn: &ServerConnection) -> Option<Negotiated> {
+    let version = match conn.protocol_version()? {
+      ProtocolVersion::TLSv1_2 => "TLSv1.2",
+      ProtocolVersion::TLSv1_3 => "TLSv1.3",
+      _ => "unknown",
+    };
+    let cipher = conn
+      .negotiated_cipher_suite()?
+      .suite()
+      .as_str()
+      .unwrap_or("unknown");
+    Some(Negotiated { version, cipher })
+  }
+
+  /// Adds `version` and `cipher` to a `request.tls` table.
+  pub fn to_lua(self, table: &LuaTable) -> LuaResult<()> {
+    table.set("version", self.version)?;
+    table.set("cipher", self.cipher)
+  }
+}
+
+/// The server's TLS configuration, built again from the same files when
+/// the server gets `SIGHUP`.
+pub struct ServerTls {
+  settings: TlsSettings,
+  config: ArcSwap<rustls::ServerConfig>,
+  /// The `logger::hangups` count when the files were last read.
+  hangups: AtomicU64,
+}
+
+impl ServerTls {
+  /// Builds the configuration from `settings`.
+  ///
+  /// # Errors
+  ///
+  /// Returns an error message as `server_config` does.
+  pub fn new(settings: TlsSettings) -> Result<ServerTls, String> {
+    let hangups = logger::hangups();
+    let config = server_config(&settings)?;
+    Ok(ServerTls {
+      settings,
+      config: ArcSwap::new(config),
+      hangups: AtomicU64::new(hangups),
+    })
+  }
+
+  /// Returns the configuration for a new connection, reading the files
+  /// again first if the server got `SIGHUP` since they were last read.
+  pub fn config(&self) -> Arc<rustls::ServerConfig> {
+    let hangups = logger::hangups();
+    // Only the connection that sees the new count first reads the files;
+    // the others carry on with the configuration in place.
+    if self.hangups.swap(hangups, Ordering::Relaxed) != hangups {
+      match server_config(&self.settings) {
+        Ok(config) => {
+          info!("Reloaded TLS certificate {}", self.settings.cert.display());
+          self.config.store(config);
+        }
+        Err(e) => warn!("{}; keeping the TLS certificate loaded before", e),
+      }
+    }
+    self.config.load_full()
+  }
+}
+
+/// Builds the server's TLS configuration from `settings`, offering HTTP/1.1
+/// over ALPN and asking for client certificates if there is a `client_ca`.
+///
+/// # Errors
+///
+/// Returns an error message if a file can't be read or parsed, the
+/// certificate has expired, the key doesn't match the certificate, the
+/// client CA file holds no usable CA, a cipher suite is unknown, or none of
+/// the cipher suites works with the versions allowed.
+pub fn server_config(settings: &TlsSettings) -> Result<Arc<rustls::ServerConfig>, String> {
+  let certs = load_certs(&settings.cert)?;
+  check_expiry(&settings.cert, &certs[0])?;
+  let key = load_key(&settings.key)?;
+
+  let mut provider = rustls::crypto::ring::default_provider();
+  if let Some(names) = &settings.cipher_suites {
+    provider.cipher_suites = cipher_suites(&provider.cipher_suites, names)?;
+  }
+  let versions: Vec<&'static SupportedProtocolVersion> = [TlsVersion::V1_2, TlsVersion::V1_3]
+    .into_iter()
+    .filter(|version| (settings.min_version..=settings.max_version).contains(version))
+    .map(TlsVersion::protocol)
+    .collect();
+  let provider = Arc::new(provider);
+  let builder = rustls::ServerConfig::builder_with_provider(provider.clone())
+    .with_protocol_versions(&versions)
+    .map_err(|e| {
+      format!(
+        "failed to configure TLS (does CONFIG.tls.cipher_suites include one for each version \
+         allowed?): {}",
+        e
+      )
+    })?;
+  let builder = match &settings.client_ca {
+    Some(path) => {
+      let mut roots = rustls::RootCertStore::empty();
+      for cert in load_certs(path)? {
+        roots
+          .add(cert)
+          .map_err(|e| format!("invalid TLS client CA {}: {}", path.display(), e))?;
+      }
+      let verifier = WebPkiClientVerifier::builder_with_provider(Arc::new(roots), provider);
+      let verifier = if settings.require_client_cert {
+        verifier
+      } else {
+        verifier.allow_unauthenticated()
+      };
+      let verifier = verifier
+        .build()
+        .map_err(|e| format!("invalid TLS client CA {}: {}", path.display(), e))?;
+      builder.with_client_cert_verifier(verifier)
+    }
+    None => builder.with_no_client_auth(),
+  };
+  let mut config = builder.with_single_cert(certs, key).map_err(|e| {
+    format!(
+      "TLS certificate {} and key {} can't be used together: {}",
+      settings.cert.display(),
+      settings.key.display(),
+      e
+    )
+  })?;
+  config.alpn_protocols = vec![b"http/1.1".to_vec()];
+  Ok(Arc::new(config))
+}
+
+/// Picks the suites named in `names` out of `available`, in that order.
+fn cipher_suites(
+  available: &[rustls::SupportedCipherSuite],
+  names: &[String],
+) -> Result<Vec<rustls::SupportedCipherSuite>, String> {
+  let name = |suite: &rustls::SupportedCipherSuite| suite.suite().as_str().unwrap_or("");
+  names
+    .iter()
+    .map(|wanted| {
+      available
+        .iter()
+        .find(|suite| name(suite).eq_ignore_ascii_case(wanted))
+        .copied()
+        .ok_or_else(|| {
+          format!(
+            "unknown TLS cipher suite \"{}\" in CONFIG.tls.cipher_suites; the supported ones \
+             are {}",
+            wanted,
+            available.iter().map(name).collect::<Vec<_>>().join(", ")
+          )
+        })
+    })
+    .collect()
+}
+
+fn read(path: &Path, what: &str) -> Result<Vec<u8>, String> {
+  fs::read(path).map_err(|e| format!("failed to read TLS {} {}: {}", what, path.display(), e))
+}
+
+/// Reads the certificate chain in `path`, which must hold at least one.
+fn load_certs(path: &Path) -> Result<Vec<CertificateDer<'static>>, String> {
+  let pem = read(path, "certificate")?;
+  let certs = CertificateDer::pem_slice_iter(&pem)
+    .collect::<Result<Vec<_>, _>>()
+    .map_err(|e| format!("invalid TLS certificate {}: {:?}", path.display(), e))?;
+  if certs.is_empty() {
+    return Err(format!("no PEM certificate found in {}", path.display()));
+  }
+  Ok(certs)
+}
+
+/// Reads the first private key in `path`.
+fn load_key(path: &Path) -> Result<PrivateKeyDer<'static>, String> {
+  let pem = read(path, "key")?;
+  PrivateKeyDer::from_pem_slice(&pem).map_err(|e| match e {
+    pem::Error::NoItemsFound => format!("no PEM private key found in {}", path.display()),
+    e => format!("invalid TLS key {}: {:?}", path.display(), e),
+  })
+}
+
+/// Fails for an expired certificate and warns about one expiring soon.
+fn check_expiry(path: &Path, cert: &CertificateDer) -> Result<(), String> {
+  let (_, parsed) = x509_parser::parse_x509_certificate(cert)
+    .map_err(|e| format!("invalid TLS certificate {}: {}", path.display(), e))?;
+  let validity = parsed.validity();
+  match validity.time_to_expiration() {
+    None => Err(format!(
+      "TLS certificate {} expired on {}",
+      path.display(),
+      validity.not_after
+    )),
+    Some(left) if left.whole_days() < EXPIRY_WARNING_DAYS => {
+      warn!(
+        "TLS certificate {} expires on {}",
+        path.display(),
+        validity.not_after
+      );
+      Ok(())
+    }
+    Some(_) => Ok(()),
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::locks;
+  use crate::testing::{self, Fixture};
+  use rustls::pki_types::ServerName;
+  use rustls::{ClientConnection, StreamOwned};
+  use std::io::{Read, Write};
+  use std::net::TcpStream;
+
+  const CA: &[u8] = include_bytes!("../testdata/tls/ca.pem");
+  const CERT_A: &[u8] = include_bytes!("../testdata/tls/a.pem");
+  const KEY_A: &[u8] = include_bytes!("../testdata/tls/a.key");
+  const CERT_B: &[u8] = include_bytes!("../testdata/tls/b.pem");
+  const KEY_B: &[u8] = include_bytes!("../testdata/tls/b.key");
+
+  /// Returns the `CONFIG.tls` table's paths in `dir`, holding `cert` and `key`.
+  fn settings(dir: &Path, cert: &[u8], key: &[u8]) -> TlsSettings {
+    fs::write(dir.join("cert.pem"), cert).unwrap();
+    fs::write(dir.join("key.pem"), key).unwrap();
+    TlsSettings {
+      cert: dir.join("cert.pem"),
+      key: dir.join("key.pem"),
+      redirect_http: None,
+      client_ca: None,
+      require_client_cert: false,
+      min_version: TlsVersion::V1_2,
+      max_version: TlsVersion::V1_3,
+      cipher_suites: None,
+    }
+  }
+
+  #[test]
+  fn a_key_for_another_certificate_is_refused() {
+    let fixture = Fixture::new("", &[]);
+    let error = server_config(&settings(fixture.path(), CERT_A, KEY_B)).unwrap_err();
+    assert!(error.contains("can't be used together"), "{}", error);
+    let error = server_config(&settings(fixture.path(), b"", KEY_A)).unwrap_err();
+    assert!(
+      error.starts_with("no PEM certificate found in"),
+      "{}",
+      error
+    );
+  }
+
+  #[test]
+  #[cfg(unix)]
+  fn a_failed_reload_keeps_the_certificate() {
+    let _hanging_up = locks::lock(&testing::HANGING_UP, "test");
+    let fixture = Fixture::new("", &[]);
+    let tls = ServerTls::new(settings(fixture.path(), CERT_A, KEY_A)).unwrap();
+    let before = tls.config();
+    assert!(Arc::ptr_eq(&before, &tls.config()));
+
+    settings(fixture.path(), CERT_A, KEY_B);
+    testing::hang_up();
+    assert!(Arc::ptr_eq(&before, &tls.config()));
+
+    settings(fixture.path(), CERT_B, KEY_B);
+    testing::hang_up();
+    assert!(!Arc::ptr_eq(&before, &tls.config()));
+  }
+
+  /// A TLS client trusting the test CA.
+  fn connect(addr: &str) -> StreamOwned<ClientConnection, TcpStream> {
+    let mut roots = rustls::RootCertStore::empty();
+    roots
+      .add(CertificateDer::from_pem_slice(CA).unwrap())
+      .unwrap();
+    let provider = Arc::new(rustls::crypto::ring::default_provider());
+    let config = rustls::ClientConfig::builder_with_provider(provider)
+      .with_safe_default_protocol_versions()
+      .unwrap()
+      .with_root_certificates(roots)
+      .with_no_client_auth();
+    let name = ServerName::try_from("localhost").unwrap();
+    let conn = ClientConnection::new(Arc::new(config), name).unwrap();
+    StreamOwned::new(conn, TcpStream::connect(addr).unwrap())
+  }
+
+  /// Sends `GET path` on a kept-alive connection and returns the body.
+  fn get(stream: &mut StreamOwned<ClientConnection, TcpStream>, path: &str) -> String {
+    write!(stream, "GET {} HTTP/1.1\r\nHost: localhost\r\n\r\n", path).unwrap();
+    let mut response = Vec::new();
+    let mut buf = [0; 1024];
+    let (head_end, length) = loop {
+      let n = stream.read(&mut buf).unwrap();
+      assert!(n > 0, "connection closed");
+      response.extend_from_slice(&buf[..n]);
+      let text = String::from_utf8_lossy(&response);
+      if let Some(end) = text.find("\r\n\r\n") {
+        let length: usize = text[..end]
+          .lines()
+          .find_map(|line| {
+            let (name, value) = line.split_once(':')?;
+            name
+              .eq_ignore_ascii_case("content-length")
+              .then(|| value.trim().parse().unwrap())
+          })
+          .unwrap();
+        break (end + 4, length);
+      }
+    };
+    while response.len() < head_end + length {
+      let n = stream.read(&mut buf).unwrap();
+      assert!(n > 0, "connection closed");
+      response.extend_from_slice(&buf[..n]);
+    }
+    String::from_utf8(response[head_end..].to_vec()).unwrap()
+  }
+
+  fn certificate(stream: &StreamOwned<ClientConnection, TcpStream>) -> Vec<u8> {
+    stream.conn.peer_certificates().unwrap()[0].to_vec()
+  }
+
+  #[test]
+  #[cfg(unix)]
+  fn new_connections_get_the_reloaded_certificate() {
+    let _hanging_up = locks::lock(&testing::HANGING_UP, "test");
+    let fixture = Fixture::new(
+      r#"
+        CONFIG = { tls = { cert = "cert.pem", key = "key.pem" } }
+        router.add("/scheme", "scheme.lua")
+      "#,
+      &[(
+        "scheme.lua",
+        "return { handler = function(request, response) response.body = request.scheme end }",
+      )],
+    );
+    settings(fixture.path(), CERT_A, KEY_A);
+    let server = fixture.builder().addr("127.0.0.1:0").load().unwrap();
+    let addr = server.serve().unwrap().remove(0);
+    let cert_a = CertificateDer::from_pem_slice(CERT_A).unwrap().to_vec();
+    let cert_b = CertificateDer::from_pem_slice(CERT_B).unwrap().to_vec();
+
+    let mut open = connect(&addr);
+    assert_eq!(get(&mut open, "/scheme"), "https");
+    assert_eq!(certificate(&open), cert_a);
+
+    settings(fixture.path(), CERT_B, KEY_B);
+    testing::hang_up();
+    let mut renewed = connect(&addr);
+    assert_eq!(get(&mut renewed, "/scheme"), "https");
+    assert_eq!(certificate(&renewed), cert_b);
+    // The connection opened before the reload carries on.
+    assert_eq!(get(&mut open, "/scheme"), "https");
+    assert_eq!(certificate(&open), cert_a);
+    server.shutdown();
+  }
+}
@@ -0,0 +1,166 @@
+//! # Trace Context
+//!
+//! Requests carry their place in a distributed trace in the W3C
+//! `traceparent` header (`00-<trace id>-<parent span id>-<flags>`), with
+//! vendor data in `tracestate`. Each request a worker handles is a span of
+//! its own: it keeps the caller's trace id, gets a new span id, and the
+//! caller's span becomes its parent. A request without a valid
+//! `traceparent` starts a new trace, sampled unless
+//! `CONFIG.tracing.sample_ratio` says otherwise (see `span_export`), and
+//! its `tracestate` is ignored.
+//!
+//! Handlers see the context as `request.trace`:
+//!
+//! ```lua
+//! -- { trace_id = "4bf92f3577b34da6a3ce929d0e0e4736", span_id = "00f067aa0ba902b7",
+//! --   parent_span_id = "b7ad6b7169203331", sampled = true,
+//! --   traceparent = "00-4bf92f...-00f067aa0ba902b7-01", tracestate = "congo=t61rcWkgMzE" }
+//! ```
+//!
+//! `fyre.http` sends `traceparent` and `tracestate` on every outbound call
+//! made while handling the request, with the request's span as the parent
+//! (or the call's own span, when spans are exported), unless the call sets
+//! `traceparent` itself or passes `trace = false`. With
+//! `CONFIG.log.format = "json"`, the lines written while the request is
+//! handled, its request log line included, have its `trace_id` and
+//! `span_id`.
+
+use mlua::prelude::*;
+use rand::RngCore;
+use tiny_http::Header;
+
+use crate::fyre::encoding::hex_encode;
+
+/// The longest `tracestate` passed on; the W3C recommendation asks for at
+/// least this much to be kept.
+const MAX_TRACESTATE_BYTES: usize = 512;
+/// The `sampled` bit of the trace flags.
+const SAMPLED: u8 = 0x01;
+
+/// A request's span and the trace it belongs to.
+#[derive(Debug, Clone)]
+pub struct TraceContext {
+  /// 32 lowercase hex digits.
+  pub trace_id: String,
+  /// This request's span: 16 lowercase hex digits.
+  pub span_id: String,
+  /// The caller's span, if the request came with a `traceparent`.
+  pub parent_span_id: Option<String>,
+  pub flags: u8,
+  pub tracestate: Option<String>,
+}
+
+impl TraceContext {
+  /// Continues the trace in the request's `traceparent` header, or starts a
+  /// new one if it has none or it is malformed.
+  pub fn from_headers(headers: &[Header]) -> TraceContext {
+    let traceparent = headers
+      .iter()
+      .find(|header| header.field.equiv("traceparent"))
+      .and_then(|header| parse_traceparent(header.value.as_str()));
+    let Some((trace_id, parent_span_id, flags)) = traceparent else {
+      return TraceContext {
+        trace_id: random_id(16),
+        span_id: random_id(8),
+        parent_span_id: None,
+        flags: SAMPLED,
+        tracestate: None,
+      };
+    };
+    // Repeated `tracestate` headers are one list.
+    let tracestate: Vec<&str> = headers
+      .iter()
+      .filter(|header| header.field.equiv("tracestate"))
+      .map(|header| header.value.as_str().trim())
+      .filter(|value| !value.is_empty())
+      .collect();
+    let tracestate = tracestate.join(",");
+    TraceContext {
+      trace_id,
+      span_id: random_id(8),
+      parent_span_id: Some(parent_span_id),
+      flags,
+      tracestate: (!tracestate.is_empty() && tracestate.len() <= MAX_TRACESTATE_BYTES)
+        .then_some(tracestate),
+    }
+  }
+
+  pub fn sampled(&self) -> bool {
+    self.flags & SAMPLED != 0
+  }
+
+  pub fn set_sampled(&mut self, sampled: bool) {
+    self.flags = if sampled {
+      self.flags | SAMPLED
+    } else {
+      self.flags & !SAMPLED
+    };
+  }
+
+  /// The `traceparent` for a call made from this span.
+  pub fn traceparent(&self) -> String {
+    self.traceparent_from(&self.span_id)
+  }
+
+  /// The `traceparent` for a call made from `span_id`, a child of this
+  /// span. Only the `sampled` flag is passed on, the one version `00`
+  /// defines.
+  pub fn traceparent_from(&self, span_id: &str) -> String {
+    format!(
+      "00-{}-{}-{:02x}",
+      self.trace_id,
+      span_id,
+      self.flags & SAMPLED
+    )
+  }
+
+  /// Builds `request.trace`.
+  ///
+  /// # Errors
+  ///
+  /// This function will return a `LuaError` if the table cannot be created.
+  pub fn to_lua(&self, lua: &Lua) -> LuaResult<LuaTable> {
+    let table = lua.create_table()?;
+    table.set("trace_id", self.trace_id.as_str())?;
+    table.set("span_id", self.span_id.as_str())?;
+    table.set("parent_span_id", self.parent_span_id.as_deref())?;
+    table.set("sampled", self.sampled())?;
+    table.set("traceparent", self.traceparent())?;
+    table.set("tracestate", self.tracestate.as_deref())?;
+    Ok(table)
+  }
+}
+
+/// Splits a `traceparent` into its trace id, parent span id, and flags.
+/// Returns `None` if it is malformed, its version is `ff`, or either id is
+/// all zeros. A version after `00` may add fields, which are ignored.
+fn parse_traceparent(value: &str) -> Option<(String, String, u8)> {
+  let value = value.trim();
+  let mut fields = value.split('-');
+  let version = fields.next()?;
+  let trace_id = fields.next()?;
+  let parent_id = fields.next()?;
+  let flags = fields.next()?;
+  let hex = |field: &str, len: usize| {
+    field.len() == len && field.bytes().all(|b| matches!(b, b'0'..=b'9' | b'a'..=b'f'))
+  };
+  if !hex(version, 2) || version == "ff" || (version == "00" && fields.next().is_some()) {
+    return None;
+  }
+  if !hex(trace_id, 32) || !hex(parent_id, 16) || !hex(flags, 2) {
+    return None;
+  }
+  let zero = |id: &str| id.bytes().all(|b| b == b'0');
+  if zero(trace_id) || zero(parent_id) {
+    return None;
+  }
+  let flags = u8::from_str_radix(flags, 16).ok()?;
+  Some((trace_id.to_string(), parent_id.to_string(), flags))
+}
+
+/// Returns `bytes` random bytes as lowercase hex.
+pub fn random_id(bytes: usize) -> String {
+  let mut id = vec![0u8; bytes];
+  rand::thread_rng().fill_bytes(&mut id);
+  hex_encode(&id)
+}
@@ -0,0 +1,156 @@
+//! # Worker Diagnostics
+//!
+//! Tracks what each request worker is doing: the requests it has handled,
+//! the route it is running now, and the share of its time spent busy.
+//! `fyre.metrics.workers()` returns these for a status page, and
+//! `fyre.metrics.render()` reports them as `fyre_worker_*` metrics.
+//!
+//! A panic while a worker runs a handler is caught: the request gets a
+//! `500`, the worker's Lua state is dropped, and the worker carries on with
+//! a new one, counted in `restarts` (see `panics`).
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::locks;
+
+/// The most worker threads `CONFIG.workers` or `--workers` may ask for.
+pub const MAX_WORKERS: usize = 1024;
+
+#[derive(Default)]
+struct Slot {
+  requests: AtomicU64,
+  busy_micros: AtomicU64,
+  restarts: AtomicU64,
+  /// The route being handled and when it started.
+  current: Mutex<Option<(String, Instant)>>,
+}
+
+/// What one worker has done, as returned by `WorkerStats::snapshot`.
+pub struct Snapshot {
+  pub id: usize,
+  pub requests: u64,
+  /// The route being handled now, if the worker is busy.
+  pub route: Option<String>,
+  /// The share of the time since startup spent handling requests, 0-100.
+  pub busy_percent: f64,
+  pub restarts: u64,
+}
+
+/// The counters of every request worker.
+pub struct WorkerStats {
+  slots: Vec<Slot>,
+  started: Instant,
+}
+
+impl WorkerStats {
+  /// Creates the counters for `workers` workers, numbered from 0.
+  pub fn new(workers: usize) -> Self {
+    WorkerStats {
+      slots: (0..workers).map(|_| Slot::default()).collect(),
+      started: Instant::now(),
+    }
+  }
+
+  /// Records that worker `id` started handling `route`. The request is
+  /// counted and its time added when the returned `Busy` is dropped.
+  pub fn begin(&self, id: usize, route: &str) -> Option<Busy<'_>> {
+    let slot = self.slots.get(id)?;
+    let started = Instant::now();
+    *locks::lock(&slot.current, "worker stats") = Some((route.to_string(), started));
+    Some(Busy { slot, started })
+  }
+
+  /// Counts a panic that cost worker `id` its Lua state.
+  pub fn restarted(&self, id: usize) {
+    if let Some(slot) = self.slots.get(id) {
+      slot.restarts.fetch_add(1, Ordering::Relaxed);
+    }
+  }
+
+  /// Whether every worker has been handling one request for longer than
+  /// `limit`, so no new request can be answered.
+  pub fn all_stuck(&self, limit: Duration) -> bool {
+    self.slots.iter().all(|slot| {
+      locks::lock(&slot.current, "worker stats")
+        .as_ref()
+        .is_some_and(|(_, started)| started.elapsed() > limit)
+    })
+  }
+
+  /// Returns each worker's counters, in order of id.
+  pub fn snapshot(&self) -> Vec<Snapshot> {
+    let now = Instant::now();
+    let uptime = now.duration_since(self.started).as_micros().max(1) as f64;
+    self
+      .slots
+      .iter()
+      .enumerate()
+      .map(|(id, slot)| {
+        let current = locks::lock(&slot.current, "worker stats").clone();
+        let mut busy = slot.busy_micros.load(Ordering::Relaxed) as f64;
+        if let Some((_, started)) = &current {
+          busy += now.duration_since(*started).as_micros() as f64;
+        }
+        Snapshot {
+          id,
+          requests: slot.requests.load(Ordering::Relaxed),
+          route: current.map(|(route, _)| route),
+          busy_percent: (busy / uptime * 100.0).min(100.0),
+          restarts: slot.restarts.load(Ordering::Relaxed),
+        }
+      })
+      .collect()
+  }
+}
+
+/// A request being handled, recorded when dropped.
+pub struct Busy<'a> {
+  slot: &'a Slot,
+  started: Instant,
+}
+
+impl Drop for Busy<'_> {
+  fn drop(&mut self) {
+    let elapsed = self.started.elapsed().as_micros() as u64;
+    self.slot.busy_micros.fetch_add(elapsed, Ordering::Relaxed);
+    self.slot.requests.fetch_add(1, Ordering::Relaxed);
+    *locks::lock(&self.slot.current, "worker stats") = None;
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn busy_workers_are_counted_when_done() {
+    let stats = WorkerStats::new(2);
+    let busy = stats.begin(1, "/slow").unwrap();
+    assert!(stats.begin(2, "/missing").is_none());
+    let snapshot = stats.snapshot();
+    assert_eq!(snapshot[1].route.as_deref(), Some("/slow"));
+    assert_eq!(snapshot[1].requests, 0);
+    assert_eq!(snapshot[0].route, None);
+
+    drop(busy);
+    stats.restarted(1);
+    let snapshot = stats.snapshot();
+    assert_eq!(snapshot[1].route, None);
+    assert_eq!(snapshot[1].requests, 1);
+    assert_eq!(snapshot[1].restarts, 1);
+    assert_eq!(snapshot[0].requests, 0);
+  }
+
+  #[test]
+  fn stuck_only_when_every_worker_is() {
+    let stats = WorkerStats::new(2);
+    let _first = stats.begin(0, "/a");
+    assert!(!stats.all_stuck(Duration::ZERO));
+    let _second = stats.begin(1, "/b");
+    std::thread::sleep(Duration::from_millis(5));
+    assert!(stats.all_stuck(Duration::ZERO));
+    assert!(!stats.all_stuck(Duration::from_secs(60)));
+  }
+}
@@ -0,0 +1,16 @@
+-- Fyre configuration. Relative paths are resolved against this file's directory.
+CONFIG = {
+  addr = "localhost:9000",
+  -- Threads handling requests (default: one per CPU).
+  -- workers = 4,
+}
+
+-- Maps a URL path to a handler script in scripts/:
+-- router.add(path, handler_script [, options])
+router.add("/hello", "hello.lua")
+
+-- Limit a heavy route to a few requests at once:
+-- router.add("/export", "export.lua", { max_concurrent = 2, queue = 10 })
+
+-- Serves the files in static/ for paths under /static no route matches.
+router.static("/static", "static")
@@ -0,0 +1,35 @@
+-- GET /hello answers with a JSON greeting.
+--
+-- Each request runs up to three stages, in order. Only `handler` is
+-- required.
+
+-- 1. middleware runs first. Setting a status other than 200 stops the
+--    handler from running, e.g. to reject a request.
+local function middleware(request, response)
+  if request.method ~= "GET" then
+    response.status = 405
+    response.headers["Allow"] = "GET"
+    response.headers["Content-Type"] = "application/json"
+    response.body = '{"error": "method not allowed"}'
+  end
+end
+
+-- 2. handler builds the response.
+local function handler(request, response)
+  response.status = 200
+  response.headers["Content-Type"] = "application/json"
+  response.body = string.format('{"message": "Hello, world!", "scheme": "%s"}', request.scheme)
+end
+
+-- 3. response_hook runs last, whatever happened before: a good place for
+--    final headers and logging.
+local function response_hook(request, response)
+  response.headers["Cache-Control"] = "no-store"
+  print(string.format("%s %s -> %d", request.method, request.path, response.status))
+end
+
+return {
+  middleware = middleware,
+  handler = handler,
+  response_hook = response_hook,
+}
@@ -0,0 +1,14 @@
+-- Fyre configuration. Relative paths are resolved against this file's directory.
+CONFIG = {
+  addr = "localhost:9000",
+  -- Threads handling requests (default: one per CPU).
+  -- workers = 4,
+}
+
+-- Maps a URL path to a handler script in scripts/:
+-- router.add(path, handler_script [, options])
+router.add("/", "hello.lua")
+
+-- Serves the files in static/ (stylesheets, images) under /assets, for
+-- paths no route matches.
+router.static("/assets", "static")
@@ -0,0 +1,55 @@
+-- GET / answers with an HTML page.
+--
+-- Each request runs up to three stages, in order. Only `handler` is
+-- required.
+
+local function escape(text)
+  return (text:gsub("[&<>\"']", {
+    ["&"] = "&amp;", ["<"] = "&lt;", [">"] = "&gt;", ['"'] = "&quot;", ["'"] = "&#39;",
+  }))
+end
+
+-- 1. middleware runs first. Setting a status other than 200 stops the
+--    handler from running, e.g. to reject a request.
+local function middleware(request, response)
+  if request.method ~= "GET" and request.method ~= "HEAD" then
+    response.status = 405
+    response.headers["Allow"] = "GET, HEAD"
+    response.body = "Method Not Allowed"
+  end
+end
+
+-- 2. handler builds the response.
+local function handler(request, response)
+  local agent = escape(request.headers["User-Agent"] or "stranger")
+  response.status = 200
+  response.headers["Content-Type"] = "text/html; charset=utf-8"
+  response.body = [[
+<!doctype html>
+<html>
+<head>
+  <meta charset="utf-8">
+  <title>Hello</title>
+  <link rel="stylesheet" href="/assets/style.css">
+</head>
+<body>
+  <h1>Hello, world!</h1>
+  <p>You are using ]] .. agent .. [[.</p>
+  <p>Edit <code>scripts/hello.lua</code> to change this page.</p>
+</body>
+</html>
+]]
+end
+
+-- 3. response_hook runs last, whatever happened before: a good place for
+--    final headers and logging.
+local function response_hook(request, response)
+  response.headers["X-Content-Type-Options"] = "nosniff"
+  print(string.format("%s %s -> %d", request.method, request.path, response.status))
+end
+
+return {
+  middleware = middleware,
+  handler = handler,
+  response_hook = response_hook,
+}